@@ -0,0 +1,45 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Incoming stanzas go through quarantine::ingest before reaching mods, which rely on every
+//! element left in them parsing. Run with `cargo +nightly fuzz run ingest`.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::str::FromStr;
+use xmpp_parsers::Element;
+
+// aparte is a binary crate, the module is built on its own
+#[path = "../../src/quarantine.rs"]
+mod quarantine;
+
+fn assert_sane(element: &Element) {
+    assert_eq!(
+        quarantine::check(element),
+        Ok(()),
+        "{}",
+        String::from(element)
+    );
+    for child in element.children() {
+        assert_sane(child);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let stanza = match std::str::from_utf8(data).map(Element::from_str) {
+        Ok(Ok(stanza)) => stanza,
+        _ => return,
+    };
+
+    let (ingested, quarantined) = quarantine::ingest(stanza);
+    if let Some(ingested) = ingested {
+        assert_sane(&ingested);
+    }
+    for quarantined in quarantined {
+        assert!(
+            quarantine::check(&quarantined.element).is_err(),
+            "{} quarantined: {}",
+            String::from(&quarantined.element),
+            quarantined.reason
+        );
+    }
+});
@@ -13,4 +13,55 @@ pub struct ConnectionInfo {
     pub server: Option<String>,
     pub port: Option<u16>,
     pub autoconnect: bool,
+    /// Connect as an external component (XEP-0114) instead of a client
+    #[serde(default)]
+    pub component: bool,
+    /// Interval in seconds between whitespace keepalives, 0 to disable them, for components too
+    ///
+    /// Keepalives keep NAT mappings alive and let a dead connection be noticed (and restored).
+    /// TCP keepalive probes are also enabled on the socket, sent after as many idle seconds.
+    #[serde(default = "default_keepalive")]
+    pub keepalive: u64,
+    /// Negotiate stream compression (XEP-0138) when the server offers it
+    ///
+    /// Disabled by default as compressing encrypted streams leaks information about their
+    /// content (CRIME/BREACH like attacks). Only worth it on very slow links.
+    #[serde(default)]
+    pub compression: bool,
+    /// Fingerprint of the OpenPGP key used for XEP-0373, looked up by its xmpp: user id otherwise
+    #[serde(default)]
+    pub openpgp_key: Option<String>,
+    /// Presence sent once connected
+    #[serde(default)]
+    pub initial_presence: InitialPresence,
+    /// Nick used in channels joined without one, before the global one
+    #[serde(default)]
+    pub nick: Option<String>,
+    /// Enable stream management (XEP-0198), servers not supporting it close the stream
+    #[serde(default = "default_stream_management")]
+    pub stream_management: bool,
+    /// Times the account is connected, like "mon-fri 09:00-18:00", always when empty
+    #[serde(default)]
+    pub schedule: Vec<String>,
+}
+
+/// Presence sent once connected, see /online
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InitialPresence {
+    /// Appear online to contacts
+    #[default]
+    Online,
+    /// Send no presence: contacts see the account offline and it receives no presences
+    None,
+    /// Appear offline while receiving presences, where the server supports it (XEP-0186)
+    Invisible,
+}
+
+pub fn default_keepalive() -> u64 {
+    60
+}
+
+pub fn default_stream_management() -> bool {
+    true
 }
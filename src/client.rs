@@ -0,0 +1,1592 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! XMPP client connection, negotiated like tokio-xmpp does but opened to the addresses found by
+//! the shared resolver, as tokio-xmpp looks the server up with a resolver of its own.
+//!
+//! The stream is secured with STARTTLS, authenticated with SASL and bound to a resource, then
+//! reopened the same way whenever it is lost until reconnection is disabled. Once stream
+//! management (XEP-0198) is enabled with resumption, a lost stream is resumed instead of bound
+//! again, the stanzas the server didn't receive being written again, and its loss is only
+//! reported if it can't be resumed.
+//!
+//! Servers offering SASL2 (XEP-0388) are authenticated with it, the resource being bound
+//! (XEP-0386) or the session resumed in the same round trip, and without restarting the stream.
+//! They are also asked for a FAST token (XEP-0484), used instead of the password to reconnect
+//! until refused. Other servers are authenticated and bound the classic way.
+//!
+//! The socket is kept alive with TCP keepalive probes, sent after as many idle seconds as the
+//! whitespace keepalives of the account so that a dead connection is noticed by the kernel too.
+//!
+//! When enabled for the account, the stream is compressed with zlib (XEP-0138) before being
+//! bound, if the server offers it.
+//!
+//! External components (XEP-0114) are connected the way tokio-xmpp does too, their socket being
+//! kept alive the same way.
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256 as HmacSha256;
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use futures::task::{Context, Poll, Waker};
+use futures::{Future, Sink, Stream};
+use native_tls::TlsConnector as NativeTlsConnector;
+use sasl::client::mechanisms::{Anonymous, Plain, Scram};
+use sasl::client::Mechanism;
+use sasl::client::MechanismError;
+use sasl::common::scram::{Sha1, Sha256};
+use sasl::common::{ChannelBinding, Credentials, Identity, Password, Secret};
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::pin::Pin;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_native_tls::{TlsConnector, TlsStream};
+use tokio_xmpp::stream_features::StreamFeatures;
+use tokio_xmpp::xmpp_stream::XMPPStream;
+use tokio_xmpp::{AuthError, Error, Event, Packet, ProtocolError};
+use uuid::Uuid;
+use xmpp_parsers::bind::{BindQuery, BindResponse};
+use xmpp_parsers::component::Handshake;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::sasl::{
+    Auth, Challenge, DefinedCondition, Failure, Mechanism as XmppMechanism, Response, Success,
+};
+use xmpp_parsers::{ns, Element, Jid};
+use zeroize::Zeroize;
+
+use crate::dns;
+use crate::mods::sm::{is_stanza, SM};
+use crate::zlib::ZlibStream;
+
+/// Port of client connections when the server has no SRV record
+const DEFAULT_PORT: u16 = 5222;
+/// Option setting the idle time before the first keepalive probe
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const TCP_KEEPIDLE: libc::c_int = libc::TCP_KEEPALIVE;
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+const TCP_KEEPIDLE: libc::c_int = libc::TCP_KEEPIDLE;
+const BIND_ID: &str = "resource-bind";
+const SASL2: &str = "urn:xmpp:sasl:2";
+const BIND2: &str = "urn:xmpp:bind:0";
+const FAST: &str = "urn:xmpp:fast:0";
+/// FAST mechanism, the token being hashed with HMAC-SHA-256 without channel binding
+const FAST_MECHANISM: &str = "HT-SHA-256-NONE";
+/// Start of the resources bound with Bind 2, the server choosing the rest
+const BIND_TAG: &str = "aparte";
+const COMPRESS: &str = "http://jabber.org/protocol/compress";
+const COMPRESS_FEATURE: &str = "http://jabber.org/features/compress";
+const CSI: &str = "urn:xmpp:csi:0";
+
+/// TLS stream, compressed once negotiated
+type Transport = ZlibStream<TlsStream<TcpStream>>;
+type XmppStream = XMPPStream<Transport>;
+type Connection = Result<Connected, Error>;
+/// Stream of an external component, authenticated with its secret
+pub type ComponentStream = XMPPStream<TcpStream>;
+
+/// Stream connected and bound, or resumed
+struct Connected {
+    stream: XmppStream,
+    /// Count of stanzas acknowledged by the server, if the stream was resumed
+    resumed: Option<u32>,
+    /// Token to authenticate with next time, if the server gave one
+    token: Option<Token>,
+    /// Whether the server accepts Client State Indication (XEP-0352)
+    csi: bool,
+}
+
+/// FAST token (XEP-0484), standing for the password until the server refuses it
+#[derive(Clone)]
+struct Token {
+    secret: String,
+    /// Authentications with the token so far, letting the server detect replays
+    count: u32,
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+enum State {
+    Disconnected,
+    Connecting(Pin<Box<dyn Future<Output = Connection>>>),
+    Connected(Box<XmppStream>),
+}
+
+/// Stream management session, kept to resume the stream once lost
+struct Session {
+    /// Jid the stream was bound to
+    jid: Jid,
+    /// Id of the session, once the server enabled it with resumption
+    id: Option<String>,
+    /// Stanzas received since enabled
+    inbound: u32,
+    /// Stanzas acknowledged by the server
+    acked: u32,
+    /// Stanzas written and not acknowledged yet, oldest first
+    unacked: VecDeque<Element>,
+}
+
+impl Session {
+    fn new(jid: Jid) -> Self {
+        Self {
+            jid,
+            id: None,
+            inbound: 0,
+            acked: 0,
+            unacked: VecDeque::new(),
+        }
+    }
+
+    fn written(&mut self, stanza: &Element) {
+        if is_stanza(stanza) {
+            self.unacked.push_back(stanza.clone());
+        }
+    }
+
+    /// Keep track of a received element, false once the session can't be resumed
+    fn received(&mut self, element: &Element) -> bool {
+        if is_stanza(element) {
+            self.inbound = self.inbound.wrapping_add(1);
+            return true;
+        }
+        if element.ns() != SM {
+            return true;
+        }
+        match element.name() {
+            "enabled" => match (element.attr("resume"), element.attr("id")) {
+                (Some("true"), Some(id)) | (Some("1"), Some(id)) => {
+                    self.id = Some(id.to_string());
+                    self.inbound = 0;
+                    true
+                }
+                _ => false,
+            },
+            "failed" => false,
+            "a" => {
+                if let Some(Ok(h)) = element.attr("h").map(u32::from_str) {
+                    self.acknowledged(h);
+                }
+                true
+            }
+            _ => true,
+        }
+    }
+
+    fn acknowledged(&mut self, h: u32) {
+        let count = h.wrapping_sub(self.acked) as usize;
+        self.unacked.drain(..count.min(self.unacked.len()));
+        self.acked = h;
+    }
+
+    /// What is needed to resume the session, if the server accepted to
+    fn resumption(&self) -> Option<Resumption> {
+        Some(Resumption {
+            id: self.id.clone()?,
+            jid: self.jid.clone(),
+            h: self.inbound,
+            acked: self.acked,
+            unacked: self.unacked.iter().cloned().collect(),
+        })
+    }
+}
+
+/// Session to resume on a new stream
+struct Resumption {
+    id: String,
+    jid: Jid,
+    /// Stanzas received on the lost stream
+    h: u32,
+    acked: u32,
+    unacked: Vec<Element>,
+}
+
+/// Settings of the account for the streams it opens
+#[derive(Clone, Copy, Default)]
+struct Options {
+    /// Whether to compress the stream when the server offers it
+    compression: bool,
+    /// Idle seconds before TCP keepalive probes, 0 to disable them
+    keepalive: u64,
+}
+
+/// Connection of an account, yielding tokio-xmpp events and accepting its packets
+pub struct Client {
+    jid: Jid,
+    password: String,
+    resolver: dns::Resolver,
+    reconnect: bool,
+    state: State,
+    /// Sender waiting for the stream to be connected
+    sender: Option<Waker>,
+    /// Jid the current stream is bound to
+    bound: Option<Jid>,
+    session: Option<Session>,
+    /// Loss of a stream being resumed, reported if it can't be
+    lost: Option<Error>,
+    /// Events to yield before polling the stream again
+    events: VecDeque<Event>,
+    /// Identifies this client to servers offering SASL2, which tie FAST tokens to it
+    user_agent: String,
+    token: Option<Token>,
+    options: Options,
+    /// Whether the server of the current stream accepts Client State Indication (XEP-0352)
+    csi: bool,
+}
+
+impl Client {
+    pub fn new(jid: Jid, password: &str, resolver: dns::Resolver) -> Self {
+        let mut client = Self {
+            jid,
+            password: password.to_string(),
+            resolver,
+            reconnect: false,
+            state: State::Disconnected,
+            sender: None,
+            bound: None,
+            session: None,
+            lost: None,
+            events: VecDeque::new(),
+            user_agent: Uuid::new_v4().to_hyphenated().to_string(),
+            token: None,
+            options: Options::default(),
+            csi: false,
+        };
+        client.state = client.connecting();
+        client
+    }
+
+    /// Whether to connect again when the stream is lost
+    pub fn set_reconnect(&mut self, reconnect: bool) -> &mut Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// Whether to compress the stream (XEP-0138) when the server offers it
+    pub fn set_compression(&mut self, compression: bool) -> &mut Self {
+        self.options.compression = compression;
+        self
+    }
+
+    /// Idle seconds before probing the connection with TCP keepalive, 0 to disable it
+    pub fn set_keepalive(&mut self, keepalive: u64) -> &mut Self {
+        self.options.keepalive = keepalive;
+        self
+    }
+
+    fn connecting(&self) -> State {
+        State::Connecting(Box::pin(connect(
+            self.resolver.clone(),
+            self.jid.clone(),
+            self.password.clone(),
+            self.session.as_ref().and_then(Session::resumption),
+            self.user_agent.clone(),
+            self.token.clone(),
+            self.options,
+        )))
+    }
+
+    /// Report the loss of the stream, unless it can be resumed
+    fn disconnected(&mut self, err: Error) -> Option<Event> {
+        match self
+            .session
+            .as_ref()
+            .and_then(|session| session.id.as_ref())
+        {
+            Some(_) if self.reconnect => {
+                debug!("Resuming stream of {}: {}", self.jid, err);
+                self.lost = Some(err);
+                self.state = self.connecting();
+                None
+            }
+            _ => {
+                self.session = None;
+                Some(Event::Disconnected(err))
+            }
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.password.zeroize();
+    }
+}
+
+/// Probe the connection after `keepalive` idle seconds, then every `keepalive` seconds
+fn set_tcp_keepalive(stream: &TcpStream, keepalive: u64) -> io::Result<()> {
+    let fd = stream.as_raw_fd();
+    let seconds = keepalive.min(libc::c_int::MAX as u64) as libc::c_int;
+    for (level, name, value) in [
+        (libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1),
+        (libc::IPPROTO_TCP, TCP_KEEPIDLE, seconds),
+        (libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, seconds),
+    ] {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Enable TCP keepalive on a new connection, unless disabled with 0
+fn keep_alive(stream: &TcpStream, keepalive: u64) {
+    if keepalive > 0 {
+        if let Err(err) = set_tcp_keepalive(stream, keepalive) {
+            warn!("Cannot enable TCP keepalive: {}", err);
+        }
+    }
+}
+
+/// Open a TCP connection to the first address of the server accepting it
+async fn open(resolver: &dns::Resolver, domain: &str, keepalive: u64) -> Result<TcpStream, Error> {
+    let addresses = resolver
+        .lookup_service(domain, "_xmpp-client._tcp", DEFAULT_PORT)
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::NotFound, err))?;
+    let mut error = io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} has no address", domain),
+    );
+    for address in addresses {
+        match TcpStream::connect(address).await {
+            Ok(stream) => {
+                keep_alive(&stream, keepalive);
+                return Ok(stream);
+            }
+            Err(err) => {
+                debug!("Cannot connect to {}: {}", address, err);
+                error = err;
+            }
+        }
+    }
+    Err(error.into())
+}
+
+/// Connect an external component (XEP-0114) to an address of the server and authenticate it with
+/// its secret
+pub async fn connect_component(
+    domain: &str,
+    secret: &str,
+    address: SocketAddr,
+    keepalive: u64,
+) -> Result<ComponentStream, Error> {
+    let tcp = TcpStream::connect(address).await?;
+    keep_alive(&tcp, keepalive);
+    let jid = Jid::from_str(domain)?;
+    let mut stream = XMPPStream::start(tcp, jid, ns::COMPONENT_ACCEPT.to_owned()).await?;
+
+    let handshake = Handshake::from_password_and_stream_id(secret, &stream.id);
+    stream.send_stanza(handshake).await?;
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) if stanza.is("handshake", ns::COMPONENT_ACCEPT) => {
+                return Ok(stream);
+            }
+            Some(Ok(Packet::Stanza(stanza))) if stanza.is("error", ns::STREAM) => {
+                return Err(AuthError::ComponentFail.into());
+            }
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(err),
+            None => return Err(Error::Disconnected),
+        }
+    }
+}
+
+async fn connect(
+    resolver: dns::Resolver,
+    jid: Jid,
+    password: String,
+    resumption: Option<Resumption>,
+    user_agent: String,
+    token: Option<Token>,
+    options: Options,
+) -> Connection {
+    let domain = jid.clone().domain();
+    let tcp = open(&resolver, &domain, options.keepalive).await?;
+
+    let stream = XMPPStream::start(tcp, jid.clone(), ns::JABBER_CLIENT.to_owned()).await?;
+    if !stream.stream_features.can_starttls() {
+        return Err(ProtocolError::NoTls.into());
+    }
+    let tls = ZlibStream::new(starttls(stream, &domain).await?);
+    let stream = XMPPStream::start(tls, jid.clone(), ns::JABBER_CLIENT.to_owned()).await?;
+
+    let username = jid.clone().node().unwrap_or_default();
+    let credentials = Credentials::default()
+        .with_username(username)
+        .with_password(password)
+        .with_channel_binding(ChannelBinding::None);
+    let offered = match Sasl2::offered(&stream.stream_features.0) {
+        Some(offered) => offered,
+        None => {
+            let tls = auth(stream, credentials).await?;
+            let stream = XMPPStream::start(tls, jid, ns::JABBER_CLIENT.to_owned()).await?;
+            let csi = stream.stream_features.0.has_child("csi", CSI);
+            let (stream, resumed) = establish(stream, resumption, options.compression).await?;
+            return Ok(Connected {
+                stream,
+                resumed,
+                token: None,
+                csi,
+            });
+        }
+    };
+
+    let mut stream = stream;
+    let authenticated = sasl2(
+        &mut stream,
+        &offered,
+        credentials,
+        token,
+        resumption.as_ref(),
+        &user_agent,
+    )
+    .await?;
+    if let Some(jid) = authenticated.jid {
+        stream.jid = jid;
+    }
+    let token = authenticated.token;
+    if let (Some(h), Some(resumption)) = (authenticated.resumed, &resumption) {
+        stream.jid = resumption.jid.clone();
+        rewrite(&mut stream, resumption, h).await?;
+        return Ok(Connected {
+            stream,
+            resumed: Some(h),
+            token,
+            csi: offered.csi,
+        });
+    }
+    if authenticated.bound {
+        return Ok(Connected {
+            stream,
+            resumed: None,
+            token,
+            csi: offered.csi,
+        });
+    }
+
+    // Not bound along, the stream goes on with the features of an authenticated one
+    stream.stream_features = StreamFeatures::new(features(&mut stream).await?);
+    let csi = stream.stream_features.0.has_child("csi", CSI);
+    let (stream, resumed) = establish(stream, resumption, options.compression).await?;
+    Ok(Connected {
+        stream,
+        resumed,
+        token,
+        csi,
+    })
+}
+
+/// Resume the session on an authenticated stream if possible, bind it otherwise
+async fn establish(
+    mut stream: XmppStream,
+    resumption: Option<Resumption>,
+    compression: bool,
+) -> Result<(XmppStream, Option<u32>), Error> {
+    if compression {
+        stream = compress(stream).await?;
+    }
+    if let Some(resumption) = resumption {
+        if stream.stream_features.0.has_child("sm", SM) {
+            if let Some(h) = resume(&mut stream, &resumption).await? {
+                stream.jid = resumption.jid;
+                return Ok((stream, Some(h)));
+            }
+        }
+    }
+    Ok((bind(stream).await?, None))
+}
+
+/// Compress an authenticated stream with zlib (XEP-0138), left uncompressed if the server
+/// doesn't offer it or fails to
+async fn compress<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: XMPPStream<ZlibStream<S>>,
+) -> Result<XMPPStream<ZlibStream<S>>, Error> {
+    let zlib = stream
+        .stream_features
+        .0
+        .get_child("compression", COMPRESS_FEATURE)
+        .is_some_and(|compression| {
+            compression
+                .children()
+                .any(|method| method.is("method", COMPRESS_FEATURE) && method.text() == "zlib")
+        });
+    if !zlib {
+        debug!("{} doesn't offer zlib compression", stream.jid);
+        return Ok(stream);
+    }
+
+    let method = Element::builder("method", COMPRESS).append("zlib").build();
+    stream
+        .send_stanza(
+            Element::builder("compress", COMPRESS)
+                .append(method)
+                .build(),
+        )
+        .await?;
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) if stanza.is("compressed", COMPRESS) => break,
+            Some(Ok(Packet::Stanza(stanza))) if stanza.is("failure", COMPRESS) => {
+                debug!("Cannot compress stream: {}", String::from(&stanza));
+                return Ok(stream);
+            }
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(err),
+            None => return Err(Error::Disconnected),
+        }
+    }
+
+    let jid = stream.jid.clone();
+    let mut transport = stream.into_inner();
+    transport.compress();
+    XMPPStream::start(transport, jid, ns::JABBER_CLIENT.to_owned()).await
+}
+
+/// Wait for the features of the stream
+async fn features<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut XMPPStream<S>,
+) -> Result<Element, Error> {
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) if stanza.is("features", ns::STREAM) => {
+                return Ok(stanza)
+            }
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(err),
+            None => return Err(Error::Disconnected),
+        }
+    }
+}
+
+/// Resume a stream management session before binding, writing again what the server didn't
+/// receive. Returns the count of stanzas it acknowledged, None if the session can't be resumed.
+async fn resume(stream: &mut XmppStream, resumption: &Resumption) -> Result<Option<u32>, Error> {
+    stream
+        .send_stanza(
+            Element::builder("resume", SM)
+                .attr("previd", resumption.id.as_str())
+                .attr("h", resumption.h.to_string())
+                .build(),
+        )
+        .await?;
+    let h = loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) if stanza.is("resumed", SM) => {
+                match stanza.attr("h").map(u32::from_str) {
+                    Some(Ok(h)) => break h,
+                    _ => {
+                        let err = xmpp_parsers::Error::ParseError("Invalid h in resumed.");
+                        return Err(ProtocolError::Parsers(err).into());
+                    }
+                }
+            }
+            Some(Ok(Packet::Stanza(stanza))) if stanza.is("failed", SM) => {
+                debug!("Cannot resume stream: {}", String::from(&stanza));
+                return Ok(None);
+            }
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(err),
+            None => return Err(Error::Disconnected),
+        }
+    };
+
+    rewrite(stream, resumption, h).await?;
+    Ok(Some(h))
+}
+
+/// Write again the stanzas a resumed stream lost, given the count the server acknowledged
+async fn rewrite(stream: &mut XmppStream, resumption: &Resumption, h: u32) -> Result<(), Error> {
+    let received = h.wrapping_sub(resumption.acked) as usize;
+    for stanza in resumption.unacked.iter().skip(received) {
+        stream.send_stanza(stanza.clone()).await?;
+    }
+    Ok(())
+}
+
+/// Secure the stream with TLS, the certificate being checked against the domain of the account
+async fn starttls(
+    mut stream: XMPPStream<TcpStream>,
+    domain: &str,
+) -> Result<TlsStream<TcpStream>, Error> {
+    stream
+        .send(Packet::Stanza(
+            Element::builder("starttls", ns::TLS).build(),
+        ))
+        .await?;
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) if stanza.name() == "proceed" => break,
+            Some(Ok(Packet::Text(_))) => {}
+            Some(Err(err)) => return Err(err),
+            _ => return Err(ProtocolError::NoTls.into()),
+        }
+    }
+
+    let connector = TlsConnector::from(NativeTlsConnector::builder().build()?);
+    Ok(connector.connect(domain, stream.into_inner()).await?)
+}
+
+/// Authenticate with the strongest SASL mechanism offered by the server
+async fn auth(mut stream: XmppStream, credentials: Credentials) -> Result<Transport, Error> {
+    let offered: HashSet<String> = stream.stream_features.sasl_mechanisms()?.collect();
+    let mechanisms: Vec<Box<dyn Mechanism>> = vec![
+        Box::new(Scram::<Sha256>::from_credentials(credentials.clone()).map_err(AuthError::Sasl)?),
+        Box::new(Scram::<Sha1>::from_credentials(credentials.clone()).map_err(AuthError::Sasl)?),
+        Box::new(Plain::from_credentials(credentials).map_err(AuthError::Sasl)?),
+        Box::new(Anonymous::new()),
+    ];
+    let mut mechanism = match mechanisms
+        .into_iter()
+        .find(|mechanism| offered.contains(mechanism.name()))
+    {
+        Some(mechanism) => mechanism,
+        None => return Err(AuthError::NoMechanism.into()),
+    };
+
+    let name = XmppMechanism::from_str(mechanism.name()).map_err(ProtocolError::Parsers)?;
+    stream
+        .send_stanza(Auth {
+            mechanism: name,
+            data: mechanism.initial(),
+        })
+        .await?;
+    loop {
+        let stanza = match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => stanza,
+            Some(Ok(_)) => continue,
+            Some(Err(err)) => return Err(err),
+            None => return Err(Error::Disconnected),
+        };
+        if let Ok(challenge) = Challenge::try_from(stanza.clone()) {
+            let data = mechanism
+                .response(&challenge.data)
+                .map_err(AuthError::Sasl)?;
+            stream.send_stanza(Response { data }).await?;
+        } else if let Ok(success) = Success::try_from(stanza.clone()) {
+            // Checks the server knows the password too, with SCRAM
+            mechanism.success(&success.data).map_err(AuthError::Sasl)?;
+            return Ok(stream.into_inner());
+        } else if let Ok(failure) = Failure::try_from(stanza) {
+            return Err(AuthError::Fail(failure.defined_condition).into());
+        }
+    }
+}
+
+/// What a server offers with SASL2 (XEP-0388), in its stream features
+struct Sasl2 {
+    mechanisms: HashSet<String>,
+    /// Whether the resource can be bound along (XEP-0386)
+    bind: bool,
+    /// Whether the session can be resumed along
+    resume: bool,
+    /// Whether FAST tokens can be requested and used (XEP-0484)
+    fast: bool,
+    /// Whether Client State Indication (XEP-0352) is offered once bound along
+    csi: bool,
+}
+
+impl Sasl2 {
+    fn offered(features: &Element) -> Option<Self> {
+        let authentication = features.get_child("authentication", SASL2)?;
+        let mechanisms = authentication
+            .children()
+            .filter(|child| child.is("mechanism", SASL2))
+            .map(Element::text)
+            .collect();
+        let inline = authentication.get_child("inline", SASL2);
+        let fast = inline
+            .and_then(|inline| inline.get_child("fast", FAST))
+            .map(|fast| {
+                fast.children().any(|mechanism| {
+                    mechanism.is("mechanism", FAST) && mechanism.text() == FAST_MECHANISM
+                })
+            })
+            .unwrap_or(false);
+        let csi = inline
+            .and_then(|inline| inline.get_child("bind", BIND2))
+            .and_then(|bind| bind.get_child("inline", BIND2))
+            .is_some_and(|features| {
+                features
+                    .children()
+                    .any(|feature| feature.is("feature", BIND2) && feature.attr("var") == Some(CSI))
+            });
+        Some(Self {
+            mechanisms,
+            bind: inline.is_some_and(|inline| inline.has_child("bind", BIND2)),
+            resume: inline.is_some_and(|inline| inline.has_child("sm", SM)),
+            fast,
+            csi,
+        })
+    }
+}
+
+/// Outcome of a SASL2 authentication
+struct Authenticated {
+    /// Jid the stream was authenticated as, the full one once bound
+    jid: Option<Jid>,
+    bound: bool,
+    /// Count of stanzas acknowledged by the server, if the session was resumed
+    resumed: Option<u32>,
+    token: Option<Token>,
+}
+
+impl Authenticated {
+    fn from_success(success: &Element) -> Self {
+        let jid = success
+            .get_child("authorization-identifier", SASL2)
+            .and_then(|jid| Jid::from_str(&jid.text()).ok());
+        let resumed = success
+            .get_child("resumed", SM)
+            .and_then(|resumed| resumed.attr("h"))
+            .and_then(|h| u32::from_str(h).ok());
+        let token = success
+            .get_child("token", FAST)
+            .and_then(|token| token.attr("token"))
+            .map(|secret| Token {
+                secret: secret.to_string(),
+                count: 0,
+            });
+        Self {
+            jid,
+            bound: success.has_child("bound", BIND2),
+            resumed,
+            token,
+        }
+    }
+}
+
+/// HT-SHA-256-NONE mechanism, proving the knowledge of a FAST token
+struct HtSha256 {
+    username: String,
+    token: String,
+}
+
+impl HtSha256 {
+    fn hash(&self, label: &str) -> Vec<u8> {
+        let mut hmac = Hmac::new(HmacSha256::new(), self.token.as_bytes());
+        hmac.input(label.as_bytes());
+        hmac.result().code().to_vec()
+    }
+}
+
+impl Drop for HtSha256 {
+    fn drop(&mut self) {
+        self.token.zeroize();
+    }
+}
+
+impl Mechanism for HtSha256 {
+    fn name(&self) -> &str {
+        FAST_MECHANISM
+    }
+
+    fn from_credentials(credentials: Credentials) -> Result<Self, MechanismError> {
+        match (credentials.identity, credentials.secret) {
+            (Identity::Username(username), Secret::Password(Password::Plain(token))) => {
+                Ok(Self { username, token })
+            }
+            (Identity::None, _) => Err(MechanismError::PlainRequiresUsername),
+            _ => Err(MechanismError::PlainRequiresPlaintextPassword),
+        }
+    }
+
+    fn initial(&mut self) -> Vec<u8> {
+        let mut initial = self.username.as_bytes().to_vec();
+        initial.push(0);
+        initial.extend(self.hash("Initiator"));
+        initial
+    }
+
+    fn success(&mut self, data: &[u8]) -> Result<(), MechanismError> {
+        // The server proves it knows the token too
+        match data == self.hash("Responder").as_slice() {
+            true => Ok(()),
+            false => Err(MechanismError::InvalidSignatureInSuccessResponse),
+        }
+    }
+}
+
+/// Authenticate with SASL2 (XEP-0388), with the FAST token if any and the password otherwise,
+/// binding the resource or resuming the session along when offered
+async fn sasl2<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut XMPPStream<S>,
+    offered: &Sasl2,
+    credentials: Credentials,
+    token: Option<Token>,
+    resumption: Option<&Resumption>,
+    user_agent: &str,
+) -> Result<Authenticated, Error> {
+    let mut inline = vec![Element::builder("user-agent", SASL2)
+        .attr("id", user_agent)
+        .append(Element::builder("software", SASL2).append("Aparté").build())
+        .build()];
+    if let (true, Some(resumption)) = (offered.resume, resumption) {
+        inline.push(
+            Element::builder("resume", SM)
+                .attr("previd", resumption.id.as_str())
+                .attr("h", resumption.h.to_string())
+                .build(),
+        );
+    }
+    if offered.bind {
+        inline.push(
+            Element::builder("bind", BIND2)
+                .append(Element::builder("tag", BIND2).append(BIND_TAG).build())
+                .build(),
+        );
+    }
+
+    if let (true, Some(token)) = (offered.fast, token) {
+        let username = match &credentials.identity {
+            Identity::Username(username) => username.clone(),
+            Identity::None => String::new(),
+        };
+        let fast = Credentials::default()
+            .with_username(username)
+            .with_password(token.secret.clone());
+        let mut mechanism = HtSha256::from_credentials(fast).map_err(AuthError::Sasl)?;
+        let mut payloads = inline.clone();
+        payloads.push(
+            Element::builder("fast", FAST)
+                .attr("count", token.count.to_string())
+                .build(),
+        );
+        match authenticate(stream, &mut mechanism, payloads).await {
+            Ok(success) => {
+                let mut authenticated = Authenticated::from_success(&success);
+                // Kept until the server rotates it
+                if authenticated.token.is_none() {
+                    let mut token = token.clone();
+                    token.count += 1;
+                    authenticated.token = Some(token);
+                }
+                return Ok(authenticated);
+            }
+            Err(Error::Auth(AuthError::Fail(condition))) => {
+                debug!("FAST token refused ({:?}), using the password", condition);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mechanisms: Vec<Box<dyn Mechanism>> = vec![
+        Box::new(Scram::<Sha256>::from_credentials(credentials.clone()).map_err(AuthError::Sasl)?),
+        Box::new(Scram::<Sha1>::from_credentials(credentials.clone()).map_err(AuthError::Sasl)?),
+        Box::new(Plain::from_credentials(credentials).map_err(AuthError::Sasl)?),
+        Box::new(Anonymous::new()),
+    ];
+    let mut mechanism = match mechanisms
+        .into_iter()
+        .find(|mechanism| offered.mechanisms.contains(mechanism.name()))
+    {
+        Some(mechanism) => mechanism,
+        None => return Err(AuthError::NoMechanism.into()),
+    };
+    if offered.fast {
+        inline.push(
+            Element::builder("request-token", FAST)
+                .attr("mechanism", FAST_MECHANISM)
+                .build(),
+        );
+    }
+    let success = authenticate(stream, mechanism.as_mut(), inline).await?;
+    Ok(Authenticated::from_success(&success))
+}
+
+/// Run a SASL2 exchange with a mechanism, returning the success of the server
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut XMPPStream<S>,
+    mechanism: &mut dyn Mechanism,
+    payloads: Vec<Element>,
+) -> Result<Element, Error> {
+    let initial = mechanism.initial();
+    let mut authenticate =
+        Element::builder("authenticate", SASL2).attr("mechanism", mechanism.name());
+    if !initial.is_empty() {
+        authenticate = authenticate.append(
+            Element::builder("initial-response", SASL2)
+                .append(base64::encode(&initial))
+                .build(),
+        );
+    }
+    stream
+        .send_stanza(authenticate.append_all(payloads).build())
+        .await?;
+
+    loop {
+        let stanza = match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => stanza,
+            Some(Ok(_)) => continue,
+            Some(Err(err)) => return Err(err),
+            None => return Err(Error::Disconnected),
+        };
+        if !stanza.has_ns(SASL2) {
+            continue;
+        }
+        let data = |element: &Element| {
+            base64::decode(element.text().trim())
+                .map_err(|_| AuthError::Sasl(MechanismError::CannotDecodeChallenge))
+        };
+        match stanza.name() {
+            "challenge" => {
+                let response = mechanism
+                    .response(&data(&stanza)?)
+                    .map_err(AuthError::Sasl)?;
+                let response = Element::builder("response", SASL2)
+                    .append(base64::encode(&response))
+                    .build();
+                stream.send_stanza(response).await?;
+            }
+            "success" => {
+                // Checks the server knows the password too, with SCRAM
+                let additional = match stanza.get_child("additional-data", SASL2) {
+                    Some(additional) => data(additional)?,
+                    None => Vec::new(),
+                };
+                mechanism.success(&additional).map_err(AuthError::Sasl)?;
+                return Ok(stanza);
+            }
+            "failure" => {
+                let condition = stanza
+                    .children()
+                    .find_map(|child| DefinedCondition::try_from(child.clone()).ok())
+                    .unwrap_or(DefinedCondition::NotAuthorized);
+                return Err(AuthError::Fail(condition).into());
+            }
+            // Tasks such as a second factor aren't supported
+            _ => {
+                let _ = stream
+                    .send_stanza(Element::builder("abort", SASL2).build())
+                    .await;
+                let err = xmpp_parsers::Error::ParseError("Unsupported SASL2 task.");
+                return Err(ProtocolError::Parsers(err).into());
+            }
+        }
+    }
+}
+
+/// Bind the resource of the account, or the one the server assigns
+async fn bind(mut stream: XmppStream) -> Result<XmppStream, Error> {
+    if !stream.stream_features.can_bind() {
+        return Ok(stream);
+    }
+
+    let resource = match stream.jid.clone() {
+        Jid::Full(jid) => Some(jid.resource),
+        Jid::Bare(_) => None,
+    };
+    stream
+        .send_stanza(Iq::from_set(BIND_ID, BindQuery::new(resource)))
+        .await?;
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) => match Iq::try_from(stanza) {
+                Ok(iq) if iq.id == BIND_ID => match iq.payload {
+                    IqType::Result(payload) => {
+                        if let Some(bind) =
+                            payload.and_then(|payload| BindResponse::try_from(payload).ok())
+                        {
+                            stream.jid = bind.into();
+                        }
+                        return Ok(stream);
+                    }
+                    _ => return Err(ProtocolError::InvalidBindResponse.into()),
+                },
+                _ => {}
+            },
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(err),
+            None => return Err(Error::Disconnected),
+        }
+    }
+}
+
+impl Stream for Client {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+            match mem::replace(&mut self.state, State::Disconnected) {
+                State::Disconnected if self.reconnect => self.state = self.connecting(),
+                State::Disconnected => return Poll::Ready(None),
+                State::Connecting(mut connect) => match connect.as_mut().poll(cx) {
+                    Poll::Ready(Ok(Connected {
+                        stream,
+                        resumed,
+                        token,
+                        csi,
+                    })) => {
+                        self.token = token;
+                        self.csi = csi;
+                        let bound_jid = stream.jid.clone();
+                        self.bound = Some(bound_jid.clone());
+                        self.state = State::Connected(Box::new(stream));
+                        if let Some(sender) = self.sender.take() {
+                            sender.wake();
+                        }
+                        match (resumed, &mut self.session) {
+                            (Some(h), Some(session)) => {
+                                session.acknowledged(h);
+                                self.lost = None;
+                            }
+                            // The previous session is gone along with what it held
+                            _ => {
+                                self.session = None;
+                                if let Some(lost) = self.lost.take() {
+                                    self.events.push_back(Event::Disconnected(lost));
+                                }
+                            }
+                        }
+                        self.events.push_back(Event::Online {
+                            bound_jid,
+                            resumed: resumed.is_some(),
+                        });
+                    }
+                    Poll::Ready(Err(err)) => {
+                        let lost = match self.lost.take() {
+                            Some(lost) => {
+                                debug!("Cannot resume stream of {}: {}", self.jid, err);
+                                self.session = None;
+                                lost
+                            }
+                            None => err,
+                        };
+                        return Poll::Ready(Some(Event::Disconnected(lost)));
+                    }
+                    Poll::Pending => {
+                        self.state = State::Connecting(connect);
+                        return Poll::Pending;
+                    }
+                },
+                State::Connected(mut stream) => match Pin::new(&mut stream).poll_next(cx) {
+                    Poll::Ready(Some(Ok(Packet::Stanza(stanza)))) => {
+                        self.state = State::Connected(stream);
+                        if let Some(session) = &mut self.session {
+                            if !session.received(&stanza) {
+                                self.session = None;
+                            }
+                        }
+                        return Poll::Ready(Some(Event::Stanza(stanza)));
+                    }
+                    // Whitespace between stanzas
+                    Poll::Ready(Some(Ok(Packet::Text(_)))) => {
+                        self.state = State::Connected(stream);
+                    }
+                    Poll::Ready(Some(Ok(Packet::StreamStart(_)))) => {
+                        if let Some(event) =
+                            self.disconnected(ProtocolError::InvalidStreamStart.into())
+                        {
+                            return Poll::Ready(Some(event));
+                        }
+                    }
+                    Poll::Ready(Some(Ok(Packet::StreamEnd))) | Poll::Ready(None) => {
+                        if let Some(event) = self.disconnected(Error::Disconnected) {
+                            return Poll::Ready(Some(event));
+                        }
+                    }
+                    Poll::Ready(Some(Err(err))) => {
+                        if let Some(event) = self.disconnected(err) {
+                            return Poll::Ready(Some(event));
+                        }
+                    }
+                    Poll::Pending => {
+                        self.state = State::Connected(stream);
+                        return Poll::Pending;
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl Sink<Packet> for Client {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        match self.state {
+            State::Connected(ref mut stream) => Pin::new(stream).poll_ready(cx),
+            _ => {
+                self.sender = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, packet: Packet) -> Result<(), Self::Error> {
+        match &packet {
+            // Servers not offering it may close the stream
+            Packet::Stanza(stanza) if stanza.ns() == CSI && !self.csi => {
+                debug!("{} doesn't offer client state indication", self.jid);
+                return Ok(());
+            }
+            Packet::Stanza(stanza) if stanza.is("enable", SM) => {
+                // Stanzas are counted by the server from there
+                self.session = self.bound.clone().map(Session::new);
+            }
+            Packet::Stanza(stanza) => {
+                if let Some(session) = &mut self.session {
+                    session.written(stanza);
+                }
+            }
+            // Closed on purpose, neither resumed nor reconnected
+            Packet::StreamEnd => {
+                self.reconnect = false;
+                self.session = None;
+            }
+            _ => {}
+        }
+        match self.state {
+            State::Connected(ref mut stream) => Pin::new(stream).start_send(packet),
+            _ => Err(Error::InvalidState),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        match self.state {
+            State::Connected(ref mut stream) => Pin::new(stream).poll_flush(cx),
+            _ => {
+                self.sender = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        match self.state {
+            State::Connected(ref mut stream) => Pin::new(stream).poll_close(cx),
+            _ => {
+                self.sender = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    const TOKEN: &str = "WXZzciBwYmFmdmZnZiBqdmd1IGp2eXFiYXJm";
+
+    fn message(id: &str) -> Element {
+        Element::builder("message", ns::DEFAULT_NS)
+            .attr("id", id)
+            .build()
+    }
+
+    fn socket_option(stream: &TcpStream, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        value
+    }
+
+    #[test]
+    fn test_tcp_keepalive_is_enabled_with_account_delay() {
+        testing::run(async {
+            // Given
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+
+            // When
+            set_tcp_keepalive(&stream, 42).unwrap();
+
+            // Then
+            assert_ne!(
+                socket_option(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE),
+                0
+            );
+            assert_eq!(socket_option(&stream, libc::IPPROTO_TCP, TCP_KEEPIDLE), 42);
+            assert_eq!(
+                socket_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL),
+                42
+            );
+        });
+    }
+
+    #[test]
+    fn test_component_is_authenticated_and_kept_alive() {
+        testing::run(async {
+            // Given
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = tokio::task::spawn_local(async move {
+                let (mut server, _) = listener.accept().await.unwrap();
+                let mut written = String::new();
+                let mut answered = 0;
+                for (end, answer) in [
+                    (
+                        "<stream:stream",
+                        "<?xml version='1.0'?><stream:stream xmlns='jabber:component:accept'
+                            xmlns:stream='http://etherx.jabber.org/streams' id='s1'
+                            from='gateway.example.org'>",
+                    ),
+                    ("</handshake>", "<handshake/>"),
+                ] {
+                    loop {
+                        if let Some(position) = written[answered..].find(end) {
+                            answered += position + end.len();
+                            break;
+                        }
+                        let mut buf = [0u8; 4096];
+                        let read = server.read(&mut buf).await.unwrap();
+                        written.push_str(&String::from_utf8_lossy(&buf[..read]));
+                    }
+                    server.write_all(answer.as_bytes()).await.unwrap();
+                }
+                (server, written)
+            });
+
+            // When
+            let stream = connect_component("gateway.example.org", "secret", address, 42)
+                .await
+                .unwrap();
+
+            // Then
+            let (_server, written) = server.await.unwrap();
+            assert!(written.contains(
+                &Handshake::from_password_and_stream_id("secret", "s1")
+                    .data
+                    .unwrap()
+            ));
+            let stream = stream.into_inner();
+            assert_ne!(
+                socket_option(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE),
+                0
+            );
+            assert_eq!(socket_option(&stream, libc::IPPROTO_TCP, TCP_KEEPIDLE), 42);
+        });
+    }
+
+    #[test]
+    fn test_session_is_resumed_with_what_the_server_missed() {
+        // Given
+        let mut session = Session::new(Jid::from_str("romeo@example.org/aparte").unwrap());
+        session.written(&message("m1"));
+        session.written(&message("m2"));
+
+        // When
+        let enabled = Element::from_str("<enabled xmlns='urn:xmpp:sm:3' resume='true' id='s1'/>");
+        assert!(session.received(&enabled.unwrap()));
+        assert!(session.received(&message("m3")));
+        assert!(session.received(&Element::from_str("<a xmlns='urn:xmpp:sm:3' h='1'/>").unwrap()));
+
+        // Then
+        let resumption = session.resumption().unwrap();
+        assert_eq!(resumption.id, "s1");
+        assert_eq!(resumption.h, 1);
+        assert_eq!(resumption.acked, 1);
+        assert_eq!(resumption.unacked, vec![message("m2")]);
+    }
+
+    #[test]
+    fn test_session_not_resumable_is_given_up() {
+        // Given
+        let mut session = Session::new(Jid::from_str("romeo@example.org/aparte").unwrap());
+
+        // When
+        let enabled = Element::from_str("<enabled xmlns='urn:xmpp:sm:3'/>").unwrap();
+
+        // Then
+        assert!(!session.received(&enabled));
+        assert!(session.resumption().is_none());
+    }
+
+    /// Fake server offering SASL2 with the given inline features, answering each element the
+    /// client ends with the given tag, and giving back everything the client wrote
+    async fn serve(
+        mut server: DuplexStream,
+        inline: &'static str,
+        answers: Vec<(&'static str, &'static str)>,
+    ) -> String {
+        let features = format!(
+            "<?xml version='1.0'?><stream:stream xmlns='jabber:client'
+                xmlns:stream='http://etherx.jabber.org/streams' id='s1' from='example.org'
+                version='1.0'>
+            <stream:features>
+                <authentication xmlns='urn:xmpp:sasl:2'>
+                    <mechanism>PLAIN</mechanism>
+                    <inline>{}</inline>
+                </authentication>
+            </stream:features>",
+            inline
+        );
+        server.write_all(features.as_bytes()).await.unwrap();
+        let mut written = String::new();
+        let mut answered = 0;
+        for (end, answer) in answers {
+            loop {
+                if let Some(position) = written[answered..].find(end) {
+                    answered += position + end.len();
+                    break;
+                }
+                let mut buf = [0u8; 4096];
+                let read = server.read(&mut buf).await.unwrap();
+                written.push_str(&String::from_utf8_lossy(&buf[..read]));
+            }
+            server.write_all(answer.as_bytes()).await.unwrap();
+        }
+        written
+    }
+
+    async fn start(client: DuplexStream) -> (XMPPStream<DuplexStream>, Sasl2, Credentials) {
+        let jid = Jid::from_str("romeo@example.org").unwrap();
+        let stream = XMPPStream::start(client, jid, ns::JABBER_CLIENT.to_owned())
+            .await
+            .unwrap();
+        let offered = Sasl2::offered(&stream.stream_features.0).unwrap();
+        let credentials = Credentials::default()
+            .with_username("romeo")
+            .with_password("password")
+            .with_channel_binding(ChannelBinding::None);
+        (stream, offered, credentials)
+    }
+
+    #[test]
+    fn test_sasl2_binds_along_and_requests_a_fast_token() {
+        testing::run(async {
+            // Given
+            let (client, server) = tokio::io::duplex(4096);
+            let server = tokio::task::spawn_local(serve(
+                server,
+                "<bind xmlns='urn:xmpp:bind:0'/>
+                <fast xmlns='urn:xmpp:fast:0'><mechanism>HT-SHA-256-NONE</mechanism></fast>",
+                vec![(
+                    "</authenticate>",
+                    "<success xmlns='urn:xmpp:sasl:2'>
+                        <authorization-identifier>romeo@example.org/aparte.x1</authorization-identifier>
+                        <bound xmlns='urn:xmpp:bind:0'/>
+                        <token xmlns='urn:xmpp:fast:0' expiry='2030-01-01T00:00:00Z'
+                            token='WXZzciBwYmFmdmZnZiBqdmd1IGp2eXFiYXJm'/>
+                    </success>",
+                )],
+            ));
+            let (mut stream, offered, credentials) = start(client).await;
+
+            // When
+            let authenticated = sasl2(&mut stream, &offered, credentials, None, None, "u1")
+                .await
+                .unwrap();
+
+            // Then
+            assert_eq!(
+                authenticated.jid,
+                Some(Jid::from_str("romeo@example.org/aparte.x1").unwrap())
+            );
+            assert!(authenticated.bound);
+            assert_eq!(authenticated.token.unwrap().secret, TOKEN);
+            let written = server.await.unwrap();
+            assert!(written.contains("mechanism=\"PLAIN\""));
+            assert!(written.contains("AHJvbWVvAHBhc3N3b3Jk"));
+            assert!(written.contains("<tag>aparte</tag>"));
+            assert!(written.contains("<request-token xmlns=\"urn:xmpp:fast:0\""));
+        });
+    }
+
+    #[test]
+    fn test_client_state_indication_offered_with_bind2() {
+        // Given
+        let offered = |inline: &str| {
+            let features = Element::from_str(&format!(
+                "<features xmlns='http://etherx.jabber.org/streams'>
+                    <authentication xmlns='urn:xmpp:sasl:2'>
+                        <mechanism>PLAIN</mechanism>
+                        <inline>{}</inline>
+                    </authentication>
+                </features>",
+                inline
+            ))
+            .unwrap();
+            Sasl2::offered(&features).unwrap()
+        };
+
+        // When
+        let csi = offered(
+            "<bind xmlns='urn:xmpp:bind:0'>
+                <inline><feature var='urn:xmpp:csi:0'/></inline>
+            </bind>",
+        );
+        let none = offered("<bind xmlns='urn:xmpp:bind:0'/>");
+
+        // Then
+        assert!(csi.bind && csi.csi);
+        assert!(none.bind && !none.csi);
+    }
+
+    #[test]
+    fn test_fast_token_is_used_instead_of_the_password() {
+        testing::run(async {
+            // Given
+            let (client, server) = tokio::io::duplex(4096);
+            let server = tokio::task::spawn_local(serve(
+                server,
+                "<fast xmlns='urn:xmpp:fast:0'><mechanism>HT-SHA-256-NONE</mechanism></fast>",
+                vec![(
+                    "</authenticate>",
+                    "<success xmlns='urn:xmpp:sasl:2'>
+                        <additional-data>2mOhooQm0WWcjhOleplSySBeMoFbqwGB+t2/QQf7ciU=</additional-data>
+                        <authorization-identifier>romeo@example.org</authorization-identifier>
+                    </success>",
+                )],
+            ));
+            let (mut stream, offered, credentials) = start(client).await;
+            let token = Token {
+                secret: TOKEN.to_string(),
+                count: 3,
+            };
+
+            // When
+            let authenticated = sasl2(&mut stream, &offered, credentials, Some(token), None, "u1")
+                .await
+                .unwrap();
+
+            // Then
+            assert!(!authenticated.bound);
+            assert_eq!(authenticated.token.unwrap().count, 4);
+            let written = server.await.unwrap();
+            assert!(written.contains("mechanism=\"HT-SHA-256-NONE\""));
+            assert!(written.contains("cm9tZW8AAXCOmOKcpfnrMszT/3mGldinvEK2P3i5joEberxnyPI="));
+            assert!(written.contains("count=\"3\""));
+            assert!(!written.contains("AHJvbWVvAHBhc3N3b3Jk"));
+        });
+    }
+
+    #[test]
+    fn test_refused_fast_token_falls_back_to_the_password() {
+        testing::run(async {
+            // Given
+            let (client, server) = tokio::io::duplex(4096);
+            let server = tokio::task::spawn_local(serve(
+                server,
+                "<fast xmlns='urn:xmpp:fast:0'><mechanism>HT-SHA-256-NONE</mechanism></fast>",
+                vec![
+                    (
+                        "</authenticate>",
+                        "<failure xmlns='urn:xmpp:sasl:2'>
+                            <credentials-expired xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>
+                        </failure>",
+                    ),
+                    (
+                        "</authenticate>",
+                        "<success xmlns='urn:xmpp:sasl:2'>
+                            <authorization-identifier>romeo@example.org</authorization-identifier>
+                            <token xmlns='urn:xmpp:fast:0' expiry='2030-01-01T00:00:00Z'
+                                token='bmV3'/>
+                        </success>",
+                    ),
+                ],
+            ));
+            let (mut stream, offered, credentials) = start(client).await;
+            let token = Token {
+                secret: TOKEN.to_string(),
+                count: 3,
+            };
+
+            // When
+            let authenticated = sasl2(&mut stream, &offered, credentials, Some(token), None, "u1")
+                .await
+                .unwrap();
+
+            // Then
+            let token = authenticated.token.unwrap();
+            assert_eq!((token.secret.as_str(), token.count), ("bmV3", 0));
+            let written = server.await.unwrap();
+            assert!(written.contains("AHJvbWVvAHBhc3N3b3Jk"));
+        });
+    }
+
+    #[test]
+    fn test_stream_is_compressed_when_offered() {
+        testing::run(async {
+            // Given
+            let (client, server) = tokio::io::duplex(4096);
+            let server = tokio::task::spawn_local(async move {
+                let mut server = ZlibStream::new(server);
+                let header = "<?xml version='1.0'?><stream:stream xmlns='jabber:client'
+                    xmlns:stream='http://etherx.jabber.org/streams' id='s1' from='example.org'
+                    version='1.0'>";
+                let features = format!(
+                    "{}<stream:features>
+                        <compression xmlns='http://jabber.org/features/compress'>
+                            <method>zlib</method>
+                        </compression>
+                    </stream:features>",
+                    header
+                );
+                server.write_all(features.as_bytes()).await.unwrap();
+                let mut written = String::new();
+                while !written.contains("</compress>") {
+                    let mut buf = [0u8; 4096];
+                    let read = server.read(&mut buf).await.unwrap();
+                    written.push_str(&String::from_utf8_lossy(&buf[..read]));
+                }
+                let compressed = "<compressed xmlns='http://jabber.org/protocol/compress'/>";
+                server.write_all(compressed.as_bytes()).await.unwrap();
+                server.compress();
+                let mut restarted = String::new();
+                while !restarted.contains("<stream:stream") {
+                    let mut buf = [0u8; 4096];
+                    let read = server.read(&mut buf).await.unwrap();
+                    restarted.push_str(&String::from_utf8_lossy(&buf[..read]));
+                }
+                let features = format!(
+                    "{}<stream:features>
+                        <bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/>
+                    </stream:features>",
+                    header
+                );
+                server.write_all(features.as_bytes()).await.unwrap();
+                server.flush().await.unwrap();
+                written
+            });
+            let jid = Jid::from_str("romeo@example.org").unwrap();
+            let stream =
+                XMPPStream::start(ZlibStream::new(client), jid, ns::JABBER_CLIENT.to_owned())
+                    .await
+                    .unwrap();
+
+            // When
+            let stream = compress(stream).await.unwrap();
+
+            // Then
+            assert!(stream.stream_features.0.has_child("bind", ns::BIND));
+            let written = server.await.unwrap();
+            assert!(written.contains("<method>zlib</method>"));
+        });
+    }
+}
@@ -70,3 +70,13 @@ pub fn rainbow(input: &str) -> String {
 
     output
 }
+
+/// Grey out text meant to be displayed as unavailable
+pub fn dimmed(input: &str) -> String {
+    format!(
+        "{}{}{}",
+        color::Fg(color::LightBlack),
+        input,
+        color::Fg(color::White)
+    )
+}
@@ -1,9 +1,14 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+use std::str::FromStr;
 #[allow(unused_imports)]
 use textwrap;
 use unicode_segmentation::UnicodeSegmentation;
+use xmpp_parsers::Jid;
 
 use crate::account::Account;
 use crate::core::Aparte;
@@ -252,12 +257,211 @@ impl Command {
     }
 }
 
+pub type Completion = Box<dyn Fn(&mut Aparte, Command) -> Vec<String>>;
+
 pub struct CommandParser {
     pub name: &'static str,
     pub help: String,
     pub parse: fn(&Option<Account>, &str, &str) -> Result<Command, String>,
     pub exec: fn(&mut Aparte, Command) -> Result<(), String>,
-    pub autocompletions: Vec<Option<Box<dyn Fn(&mut Aparte, Command) -> Vec<String>>>>,
+    pub autocompletions: Vec<Option<Completion>>,
+    pub subcommands: HashMap<String, CommandParser>,
+}
+
+impl CommandParser {
+    /// Parser of the deepest subcommand given in the arguments, along with the index of its
+    /// name in the arguments. Only subcommands before the cursor are followed.
+    pub fn resolve<'a>(&'a self, command: &Command) -> (&'a CommandParser, usize) {
+        let mut parser = self;
+        let mut depth = 0;
+        while command.cursor > depth + 1 {
+            match parser.subcommands.get(&command.args[depth + 1]) {
+                Some(subcommand) => {
+                    parser = subcommand;
+                    depth += 1;
+                }
+                None => break,
+            }
+        }
+        (parser, depth)
+    }
+}
+
+/// Kind of JID an argument accepts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JidKind {
+    /// Without resource, like a contact or a channel
+    Bare,
+    /// With a resource, like a client or a channel occupant
+    Full,
+}
+
+/// Error in the arguments given to a command
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArgError {
+    Missing {
+        arg: &'static str,
+    },
+    Format {
+        arg: &'static str,
+        reason: String,
+    },
+    NotAllowed {
+        arg: &'static str,
+        value: String,
+        allowed: Vec<&'static str>,
+    },
+    OutOfRange {
+        arg: &'static str,
+        value: String,
+        expected: String,
+    },
+    Jid {
+        arg: &'static str,
+        value: String,
+        expected: JidKind,
+    },
+    Duplicate {
+        arg: &'static str,
+    },
+    Subcommand {
+        value: String,
+        allowed: Vec<String>,
+    },
+}
+
+/// `a`, `a or b`, `a, b or c`
+fn enumerate<S: AsRef<str>>(values: &[S]) -> String {
+    match values {
+        [] => String::new(),
+        [value] => value.as_ref().to_string(),
+        [head @ .., last] => format!(
+            "{} or {}",
+            head.iter()
+                .map(|value| value.as_ref())
+                .collect::<Vec<_>>()
+                .join(", "),
+            last.as_ref()
+        ),
+    }
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgError::Missing { arg } => write!(f, "Missing {} argument", arg),
+            ArgError::Format { arg, reason } => {
+                write!(f, "Invalid format for {} argument: {}", arg, reason)
+            }
+            ArgError::NotAllowed {
+                arg,
+                value,
+                allowed,
+            } => write!(
+                f,
+                "Invalid {} argument {}, expected {}",
+                arg,
+                value,
+                enumerate(allowed)
+            ),
+            ArgError::OutOfRange {
+                arg,
+                value,
+                expected,
+            } => write!(f, "Invalid {} argument {}, expected {}", arg, value, expected),
+            ArgError::Jid {
+                arg,
+                value,
+                expected: JidKind::Bare,
+            } => write!(
+                f,
+                "Invalid {} argument {}, expected a JID without resource like user@server.tld",
+                arg, value
+            ),
+            ArgError::Jid {
+                arg,
+                value,
+                expected: JidKind::Full,
+            } => write!(
+                f,
+                "Invalid {} argument {}, expected a JID with a resource like user@server.tld/resource",
+                arg, value
+            ),
+            ArgError::Duplicate { arg } => write!(f, "Multiple occurrences of {} argument", arg),
+            ArgError::Subcommand { value, allowed } => write!(
+                f,
+                "Invalid subcommand {}, expected {}",
+                value,
+                enumerate(allowed)
+            ),
+        }
+    }
+}
+
+impl From<ArgError> for String {
+    fn from(err: ArgError) -> Self {
+        err.to_string()
+    }
+}
+
+/// Check an argument against the values it's allowed to take
+pub fn check_values(
+    arg: &'static str,
+    value: &str,
+    allowed: &[&'static str],
+) -> Result<(), ArgError> {
+    match allowed.contains(&value) {
+        true => Ok(()),
+        false => Err(ArgError::NotAllowed {
+            arg,
+            value: value.to_string(),
+            allowed: allowed.to_vec(),
+        }),
+    }
+}
+
+/// Check a numeric argument is in its range
+pub fn check_range<T: PartialOrd + fmt::Display, R: RangeBounds<T>>(
+    arg: &'static str,
+    value: &T,
+    range: R,
+) -> Result<(), ArgError> {
+    if range.contains(value) {
+        return Ok(());
+    }
+
+    let expected = match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => {
+            format!("between {} and {}", start, end)
+        }
+        (Bound::Included(start), Bound::Excluded(end)) => {
+            format!("at least {} and less than {}", start, end)
+        }
+        (Bound::Included(start), Bound::Unbounded) => format!("at least {}", start),
+        (Bound::Unbounded, Bound::Included(end)) => format!("at most {}", end),
+        (Bound::Unbounded, Bound::Excluded(end)) => format!("less than {}", end),
+        (Bound::Excluded(start), _) => format!("more than {}", start),
+        (Bound::Unbounded, Bound::Unbounded) => unreachable!(),
+    };
+    Err(ArgError::OutOfRange {
+        arg,
+        value: value.to_string(),
+        expected,
+    })
+}
+
+/// Check an argument is the expected kind of JID, BareJid would silently drop a resource
+pub fn check_jid(arg: &'static str, value: &str, expected: JidKind) -> Result<(), ArgError> {
+    match (Jid::from_str(value), expected) {
+        (Ok(Jid::Bare(_)), JidKind::Bare) | (Ok(Jid::Full(_)), JidKind::Full) => Ok(()),
+        // Format errors are reported when parsing the argument
+        (Err(_), _) => Ok(()),
+        _ => Err(ArgError::Jid {
+            arg,
+            value: value.to_string(),
+            expected,
+        }),
+    }
 }
 
 #[macro_export]
@@ -265,8 +469,11 @@ macro_rules! parse_subcommand_attrs(
     ($map:ident, {}) => ();
     ($map:ident, { children: $subcommands:tt $(, $($tail:tt)*)? }) => (
         build_subcommand_map!($map, $subcommands);
+        parse_subcommand_attrs!($map, { $($($tail)*)? });
+    );
+    ($map:ident, { $attr:ident: $value:tt $(, $($tail:tt)*)? }) => (
+        parse_subcommand_attrs!($map, { $($($tail)*)? });
     );
-    ($map:ident, { completion: (|$aparte:ident, $command:ident| $completion:block) $(, $($tail:tt)*)? }) => ();
 );
 
 #[macro_export]
@@ -279,6 +486,44 @@ macro_rules! build_subcommand_map(
     ($map:ident, { completion: (|$aparte:ident, $command:ident| $completion:block) $(, $($tail:tt)*)? }) => ();
 );
 
+#[macro_export]
+macro_rules! generate_subcommands(
+    ($map:ident, {}) => ();
+    ($map:ident, { $arg:ident: Command = $attr:tt $(, $($tail:tt)*)? }) => (
+        parse_subcommand_attrs!($map, $attr);
+        generate_subcommands!($map, { $($($tail)*)? });
+    );
+    ($map:ident, { $arg:ident: $type:ty $(= $attr:tt)? $(, $($tail:tt)*)? }) => (
+        generate_subcommands!($map, { $($($tail)*)? });
+    );
+);
+
+/// Check the constraints of an argument: `values: ["on", "off"]`, `range: (1..=100)` or
+/// `jid: bare` and `jid: full`
+#[macro_export]
+macro_rules! check_command_arg(
+    ($arg:ident, $raw:expr, $value:expr, {}) => ();
+    ($arg:ident, $raw:expr, $value:expr, { values: [$($allowed:expr),* $(,)?] $(, $($tail:tt)*)? }) => (
+        $crate::command::check_values(stringify!($arg), $raw, &[$($allowed),*])?;
+        check_command_arg!($arg, $raw, $value, { $($($tail)*)? });
+    );
+    ($arg:ident, $raw:expr, $value:expr, { range: ($range:expr) $(, $($tail:tt)*)? }) => (
+        $crate::command::check_range(stringify!($arg), $value, $range)?;
+        check_command_arg!($arg, $raw, $value, { $($($tail)*)? });
+    );
+    ($arg:ident, $raw:expr, $value:expr, { jid: bare $(, $($tail:tt)*)? }) => (
+        $crate::command::check_jid(stringify!($arg), $raw, $crate::command::JidKind::Bare)?;
+        check_command_arg!($arg, $raw, $value, { $($($tail)*)? });
+    );
+    ($arg:ident, $raw:expr, $value:expr, { jid: full $(, $($tail:tt)*)? }) => (
+        $crate::command::check_jid(stringify!($arg), $raw, $crate::command::JidKind::Full)?;
+        check_command_arg!($arg, $raw, $value, { $($($tail)*)? });
+    );
+    ($arg:ident, $raw:expr, $value:expr, { $attr:ident: $other:tt $(, $($tail:tt)*)? }) => (
+        check_command_arg!($arg, $raw, $value, { $($($tail)*)? });
+    );
+);
+
 #[macro_export]
 macro_rules! parse_command_args(
     ($aparte:ident, $command:ident, $index:ident, {}) => ();
@@ -290,8 +535,9 @@ macro_rules! parse_command_args(
 
         let $arg: Password<$type> = match Password::from_str(&$command.args[$index]) {
             Ok(arg) => arg,
-            Err(e) => return Err(format!("Invalid format for {} argument: {}", stringify!($arg), e)),
+            Err(e) => return Err($crate::command::ArgError::Format { arg: stringify!($arg), reason: e.to_string() }.into()),
         };
+        zeroize::Zeroize::zeroize(&mut $command.args[$index]);
 
         $index += 1;
     );
@@ -299,8 +545,11 @@ macro_rules! parse_command_args(
         let $arg: Option<$type> = {
             if $command.args.len() > $index {
                 match <$type>::from_str(&$command.args[$index]) {
-                    Ok(arg) => Some(arg),
-                    Err(e) => return Err(format!("Invalid format for {} argument: {}", stringify!($arg), e)),
+                    Ok(arg) => {
+                        $(check_command_arg!($arg, &$command.args[$index], &arg, $attr);)?
+                        Some(arg)
+                    }
+                    Err(e) => return Err($crate::command::ArgError::Format { arg: stringify!($arg), reason: e.to_string() }.into()),
                 }
             } else {
                 None
@@ -317,7 +566,7 @@ macro_rules! parse_command_args(
             let mut matching = Vec::new();
             let mut i = 0;
             while i != $command.args.len() {
-                if $command.args[i].starts_with(stringify!($arg)) {
+                if $command.args[i].starts_with(concat!(stringify!($arg), "=")) {
                     matching.push($command.args.remove(i));
                 } else {
                     i += 1;
@@ -326,13 +575,16 @@ macro_rules! parse_command_args(
             match matching.as_slice() {
                 [] => None,
                 [named] => {
-                    let arg = named.splitn(2, "=").collect::<Vec<&str>>()[1];
-                    match <$type>::from_str(&arg) {
-                        Ok(arg) => Some(arg),
-                        Err(e) => return Err(format!("Invalid format for {} argument: {}", stringify!($arg), e)),
+                    let raw = &named[concat!(stringify!($arg), "=").len()..];
+                    match <$type>::from_str(raw) {
+                        Ok(arg) => {
+                            $(check_command_arg!($arg, raw, &arg, $attr);)?
+                            Some(arg)
+                        }
+                        Err(e) => return Err($crate::command::ArgError::Format { arg: stringify!($arg), reason: e.to_string() }.into()),
                     }
                 }
-                _ => return Err(format!("Multiple occurance of {} argument", stringify!($arg))),
+                _ => return Err($crate::command::ArgError::Duplicate { arg: stringify!($arg) }.into()),
             }
         };
 
@@ -340,10 +592,10 @@ macro_rules! parse_command_args(
     );
     ($aparte:ident, $command:ident, $index:ident, { $arg:ident: Command = $attr:tt $(, $($tail:tt)*)? }) => (
         if $command.args.len() <= $index {
-            return Err(format!("Missing {} argument", stringify!($arg)))
+            return Err($crate::command::ArgError::Missing { arg: stringify!($arg) }.into())
         }
 
-        let mut sub_commands: HashMap<String, CommandParser> = HashMap::new();
+        let mut sub_commands: std::collections::HashMap<String, CommandParser> = std::collections::HashMap::new();
         parse_subcommand_attrs!(sub_commands, $attr);
 
         return match sub_commands.get(&$command.args[$index]) {
@@ -354,18 +606,23 @@ macro_rules! parse_command_args(
                 };
                 (sub_parser.exec)($aparte, sub_command)
             },
-            None => Err(format!("Invalid subcommand {}", $command.args[$index])),
+            None => {
+                let mut allowed = sub_commands.keys().cloned().collect::<Vec<String>>();
+                allowed.sort();
+                Err($crate::command::ArgError::Subcommand { value: $command.args[$index].clone(), allowed }.into())
+            }
         };
     );
     ($aparte:ident, $command:ident, $index:ident, { $arg:ident: $type:ty $(= $attr:tt)? $(, $($tail:tt)*)? }) => (
         if $command.args.len() <= $index {
-            return Err(format!("Missing {} argument", stringify!($arg)))
+            return Err($crate::command::ArgError::Missing { arg: stringify!($arg) }.into())
         }
 
         let $arg: $type = match <$type>::from_str(&$command.args[$index]) {
             Ok(arg) => arg,
-            Err(e) => return Err(format!("Invalid format for {} argument: {}", stringify!($arg), e)),
+            Err(e) => return Err($crate::command::ArgError::Format { arg: stringify!($arg), reason: e.to_string() }.into()),
         };
+        $(check_command_arg!($arg, &$command.args[$index], &$arg, $attr);)?
 
         $index += 1;
 
@@ -376,9 +633,18 @@ macro_rules! parse_command_args(
 #[macro_export]
 macro_rules! generate_command_autocompletions(
     ($autocompletions:ident, {}) => ();
+    ($autocompletions:ident, { $argname:ident: Named<$type:ty> = $attrs:tt $(, $($tail:tt)*)? }) => (
+        let count = $autocompletions.len();
+        generate_arg_autocompletion!($autocompletions, concat!(stringify!($argname), "="), $attrs);
+        if count == $autocompletions.len() {
+            $autocompletions.push(None);
+        }
+        assert!($autocompletions.len() == count + 1, "Two completion pushed for the argument {}", stringify!($argname));
+        generate_command_autocompletions!($autocompletions, { $($($tail)*)? });
+    );
     ($autocompletions:ident, { $argname:ident: $type:ty = $attrs:tt $(, $($tail:tt)*)? }) => (
         let count = $autocompletions.len();
-        generate_arg_autocompletion!($autocompletions, $type, $attrs);
+        generate_arg_autocompletion!($autocompletions, "", $attrs);
         if count == $autocompletions.len() {
             $autocompletions.push(None);
         }
@@ -400,18 +666,28 @@ macro_rules! generate_sub_autocompletion(
     );
 );
 
+/// Completion of an argument, `prefix` is prepended to allowed values of named arguments
 #[macro_export]
 macro_rules! generate_arg_autocompletion(
-    ($autocompletions:ident, $type:ty, {}) => ();
-    ($autocompletions:ident, $type:ty, { children: $subs:tt $(, $($tail:tt)*)? }) => (
+    ($autocompletions:ident, $prefix:expr, {}) => ();
+    ($autocompletions:ident, $prefix:expr, { children: $subs:tt $(, $($tail:tt)*)? }) => (
         let mut sub = vec![];
         generate_sub_autocompletion!(sub, $subs);
         $autocompletions.push(Some(Box::new(move |_: &mut Aparte, _: Command| -> Vec<String> { sub.clone() })));
-        generate_arg_autocompletion!($autocompletions, $type, { $($($tail)*)? });
+        generate_arg_autocompletion!($autocompletions, $prefix, { $($($tail)*)? });
     );
-    ($autocompletions:ident, $type:ty, { completion: (|$aparte:ident, $command:ident| $completion:block) $(, $($tail:tt)*)? }) => (
+    ($autocompletions:ident, $prefix:expr, { completion: (|$aparte:ident, $command:ident| $completion:block) $(, $($tail:tt)*)? }) => (
         $autocompletions.push(Some(Box::new(|$aparte: &mut Aparte, $command: Command| -> Vec<String> { $completion })));
-        generate_arg_autocompletion!($autocompletions, $type, { $($($tail)*)? });
+        generate_arg_autocompletion!($autocompletions, $prefix, { $($($tail)*)? });
+    );
+    ($autocompletions:ident, $prefix:expr, { values: [$($allowed:expr),* $(,)?] $(, $($tail:tt)*)? }) => (
+        $autocompletions.push(Some(Box::new(|_: &mut Aparte, _: Command| -> Vec<String> {
+            vec![$(format!("{}{}", $prefix, $allowed)),*]
+        })));
+        generate_arg_autocompletion!($autocompletions, $prefix, { $($($tail)*)? });
+    );
+    ($autocompletions:ident, $prefix:expr, { $attr:ident: $value:tt $(, $($tail:tt)*)? }) => (
+        generate_arg_autocompletion!($autocompletions, $prefix, { $($($tail)*)? });
     );
 );
 
@@ -469,15 +745,20 @@ macro_rules! command_def (
             }
 
             pub fn new() -> CommandParser {
-                let mut autocompletions = Vec::<Option<Box<dyn Fn(&mut Aparte, Command) -> Vec<String>>>>::new();
+                let mut autocompletions = Vec::<Option<$crate::command::Completion>>::new();
                 generate_command_autocompletions!(autocompletions, $args);
 
+                #[allow(unused_mut)]
+                let mut subcommands = std::collections::HashMap::new();
+                generate_subcommands!(subcommands, $args);
+
                 CommandParser {
                     name: stringify!($name),
                     help: help(),
                     parse,
                     exec,
-                    autocompletions: autocompletions,
+                    autocompletions,
+                    subcommands,
                 }
             }
         }
@@ -497,7 +778,10 @@ macro_rules! command_def (
                 Command::new(account.clone(), context.to_string(), buf.to_string())
             }
 
-            fn exec($aparte: &mut Aparte, mut $command: Command) -> Result<(), String> {
+            fn exec($aparte: &mut Aparte, $command: Command) -> Result<(), String> {
+                // Only mutated by arguments consuming the raw ones, like Named and Password
+                #[allow(unused_mut)]
+                let mut $command = $command;
                 #[allow(unused_variables, unused_mut)]
                 let mut index = 1;
                 parse_command_args!($aparte, $command, index, $args);
@@ -510,16 +794,21 @@ macro_rules! command_def (
 
             pub fn new() -> CommandParser {
                 #[allow(unused_mut)]
-                let mut autocompletions = Vec::<Option<Box<dyn Fn(&mut Aparte, Command) -> Vec<String>>>>::new();
+                let mut autocompletions = Vec::<Option<$crate::command::Completion>>::new();
 
                 generate_command_autocompletions!(autocompletions, $args);
 
+                #[allow(unused_mut)]
+                let mut subcommands = std::collections::HashMap::new();
+                generate_subcommands!(subcommands, $args);
+
                 CommandParser {
                     name: stringify!($name),
                     help: help(),
                     parse,
                     exec,
-                    autocompletions: autocompletions,
+                    autocompletions,
+                    subcommands,
                 }
             }
         }
@@ -529,7 +818,10 @@ macro_rules! command_def (
 #[cfg(test)]
 mod tests_command_macro {
     use super::*;
+    use crate::core::{Event, Password};
+    use crate::testing;
     use std::str::FromStr;
+    use xmpp_parsers::BareJid;
 
     command_def!(no_args, "help", {}, |_aparte, _command| { Ok(()) });
 
@@ -601,6 +893,164 @@ mod tests_command_macro {
         assert_eq!(cmd.help, "help");
         assert_eq!(cmd.autocompletions.len(), 2);
     }
+
+    command_def!(constrained, "help", {
+        state: String = {
+            values: ["on", "off"]
+        },
+        contact: Named<BareJid> = {
+            jid: bare
+        },
+        autojoin: Named<String> = {
+            values: ["on", "off"]
+        },
+        count: Option<usize> = {
+            range: (1..=10)
+        }
+    }, |_aparte, _command| {
+        let _ = (state, count, contact, autojoin);
+        Ok(())
+    });
+
+    command_def!(parent, "help", {
+        action: Command = {
+            children: {
+                "constrained": constrained,
+                "no_args": no_args,
+            }
+        }
+    });
+
+    command_def!(login, "help", {
+        user: String,
+        password: Password<String>
+    }, |_aparte, _command| {
+        // Only the password is wiped once parsed
+        assert_eq!(_command.args[1], "romeo");
+        assert!(_command.args[2].is_empty());
+        assert_eq!(user, "romeo");
+        assert_eq!(password.0, "secret");
+        Ok(())
+    });
+
+    fn exec(parser: &CommandParser, buf: &str) -> Result<(), String> {
+        let mut harness = testing::Harness::new();
+        let command = Command::new(None, "console".to_string(), buf.to_string())?;
+        (parser.exec)(&mut harness.aparte, command)
+    }
+
+    #[test]
+    fn test_command_wipes_password_args_only() {
+        testing::run(async {
+            assert_eq!(exec(&login::new(), "/login romeo secret"), Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_command_with_constraints() {
+        // Given
+        let cmd = constrained::new();
+
+        // When
+        let valid = exec(
+            &cmd,
+            "/constrained on 10 contact=juliet@example.org autojoin=off",
+        );
+
+        // Then
+        assert_eq!(valid, Ok(()));
+    }
+
+    #[test]
+    fn test_command_constraints_errors() {
+        // Given
+        let cmd = constrained::new();
+
+        // When
+        let values = exec(&cmd, "/constrained maybe");
+        let range = exec(&cmd, "/constrained on 0");
+        let jid = exec(&cmd, "/constrained on contact=juliet@example.org/balcony");
+        let named = exec(&cmd, "/constrained on autojoin=yes");
+        let format = exec(&cmd, "/constrained on many");
+        let missing = exec(&cmd, "/constrained");
+
+        // Then
+        assert_eq!(
+            values,
+            Err("Invalid state argument maybe, expected on or off".to_string())
+        );
+        assert_eq!(
+            range,
+            Err("Invalid count argument 0, expected between 1 and 10".to_string())
+        );
+        assert_eq!(
+            jid,
+            Err("Invalid contact argument juliet@example.org/balcony, expected a JID without resource like user@server.tld".to_string())
+        );
+        assert_eq!(
+            named,
+            Err("Invalid autojoin argument yes, expected on or off".to_string())
+        );
+        assert_eq!(
+            format,
+            Err("Invalid format for count argument: invalid digit found in string".to_string())
+        );
+        assert_eq!(missing, Err("Missing state argument".to_string()));
+    }
+
+    #[test]
+    fn test_command_constraints_completion() {
+        // Given
+        let mut harness = testing::Harness::new();
+        let cmd = constrained::new();
+        let command =
+            Command::new(None, "console".to_string(), "/constrained ".to_string()).unwrap();
+
+        // When
+        let completions = cmd
+            .autocompletions
+            .iter()
+            .map(|completion| match completion {
+                Some(completion) => completion(&mut harness.aparte, command.clone()),
+                None => Vec::new(),
+            })
+            .collect::<Vec<_>>();
+
+        // Then
+        assert_eq!(
+            completions,
+            vec![
+                vec!["on".to_string(), "off".to_string()],
+                vec![],
+                vec!["autojoin=on".to_string(), "autojoin=off".to_string()],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subcommand_resolution() {
+        // Given
+        let cmd = parent::new();
+        let command = Command::new(
+            None,
+            "console".to_string(),
+            "/parent constrained on".to_string(),
+        )
+        .unwrap();
+
+        // When
+        let (parser, depth) = cmd.resolve(&command);
+        let invalid = exec(&cmd, "/parent unknown");
+
+        // Then
+        assert_eq!(parser.name, "constrained");
+        assert_eq!(depth, 1);
+        assert_eq!(
+            invalid,
+            Err("Invalid subcommand unknown, expected constrained or no_args".to_string())
+        );
+    }
 }
 
 #[cfg(test)]
@@ -3,10 +3,639 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use xmpp_parsers::BareJid;
 
 use crate::account::ConnectionInfo;
+use crate::graphics::Protocol;
+use crate::mods::filter::FilterAction;
+use crate::mods::requests::StrangersPolicy;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Config {
     pub accounts: HashMap<String, ConnectionInfo>,
+    /// Nick used in channels joined without one, when the account has none either
+    #[serde(default)]
+    pub nick: Option<String>,
+    #[serde(default)]
+    pub format: FormatConfig,
+    #[serde(default)]
+    pub paste: PasteConfig,
+    #[serde(default)]
+    pub privacy: PrivacyConfig,
+    #[serde(default)]
+    pub upload: UploadConfig,
+    #[serde(default)]
+    pub transfer: TransferConfig,
+    #[serde(default)]
+    pub stats: StatsConfig,
+    #[serde(default)]
+    pub iq: IqConfig,
+    #[serde(default)]
+    pub session: SessionConfig,
+    /// Rules applied to incoming messages, in order
+    #[serde(default)]
+    pub filters: Vec<FilterConfig>,
+    /// Protocol used to display images, detected when not set
+    #[serde(default)]
+    pub graphics: Option<Protocol>,
+    /// Command opening images the terminal can't display, given the path of the image
+    #[serde(default)]
+    pub image_viewer: Option<String>,
+    /// Start in low bandwidth mode, see /lowbandwidth
+    #[serde(default)]
+    pub low_bandwidth: bool,
+    /// What to do with messages of people who aren't in the roster
+    #[serde(default)]
+    pub strangers: StrangersPolicy,
+    #[serde(default)]
+    pub announcements: AnnouncementsConfig,
+    #[serde(default)]
+    pub previews: PreviewsConfig,
+    #[serde(default)]
+    pub birthdays: BirthdaysConfig,
+    #[serde(default)]
+    pub idle: IdleConfig,
+    #[serde(default)]
+    pub links: LinksConfig,
+    #[serde(default)]
+    pub layout: LayoutConfig,
+    #[serde(default)]
+    pub keys: KeysConfig,
+    #[serde(default)]
+    pub plugins: PluginsConfig,
+    /// Custom emoji of conversations by bare jid, mapping shortnames to `cid:` or HTTP URIs
+    #[serde(default)]
+    pub emoji: HashMap<String, HashMap<String, String>>,
+    /// Profile used, overridden by `--profile`, see /profile
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, ProfileConfig>,
+    /// Conversations opened together by name, see /workspace
+    #[serde(default)]
+    pub workspaces: HashMap<String, WorkspaceConfig>,
+}
+
+impl Config {
+    /// Configuration restricted to the accounts of a profile, with its overrides
+    pub fn with_profile(&self, name: &str) -> Result<Config, String> {
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or(format!("Unknown profile {}", name))?;
+        let mut accounts = HashMap::new();
+        for account in profile.accounts.iter() {
+            let info = self
+                .accounts
+                .get(account)
+                .ok_or(format!("Unknown account {} in profile {}", account, name))?;
+            accounts.insert(account.clone(), info.clone());
+        }
+        Ok(Config {
+            accounts,
+            format: profile
+                .format
+                .clone()
+                .unwrap_or_else(|| self.format.clone()),
+            filters: profile
+                .filters
+                .clone()
+                .unwrap_or_else(|| self.filters.clone()),
+            profile: Some(name.to_string()),
+            ..self.clone()
+        })
+    }
+}
+
+/// Accounts, rooms, look and notification rules used together, separately from other profiles
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ProfileConfig {
+    /// Names of the accounts of the profile, the only ones connected and listed
+    pub accounts: Vec<String>,
+    /// Rooms joined once the first account of the profile is connected
+    pub rooms: Vec<String>,
+    /// Replaces the global format
+    pub format: Option<FormatConfig>,
+    /// Replaces the global filters
+    pub filters: Option<Vec<FilterConfig>>,
+}
+
+/// Related channels and chats opened together, like the ones of a recurring meeting
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct WorkspaceConfig {
+    /// Name of the account opening the conversations, the current one if omitted
+    pub account: Option<String>,
+    /// Channels joined, as `room@server` or `room@server/nick`
+    pub channels: Vec<String>,
+    /// Contacts whose chat window is opened
+    pub chats: Vec<String>,
+}
+
+/// Buffer lines formatting, see `template` for the templates syntax
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FormatConfig {
+    /// strftime like format of `{time}`
+    pub timestamp: String,
+    /// Prefix of messages, accepts `{time}`, `{attributes}` and `{nick}`
+    pub message: String,
+    /// Prefix of /me messages, accepts `{time}`, `{attributes}` and `{nick}`
+    pub me: String,
+    /// Prefix of log lines, accepts `{time}`
+    pub log: String,
+    /// Nicks longer than this are truncated
+    pub nick_max_width: Option<usize>,
+    /// Right align nicks in a column of the given width
+    pub nick_column: Option<usize>,
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        Self {
+            timestamp: "%T".to_string(),
+            message: "{time} - {attributes}{nick}: ".to_string(),
+            me: "{time} - {attributes}* {nick}".to_string(),
+            log: "{time} - ".to_string(),
+            nick_max_width: None,
+            nick_column: None,
+        }
+    }
+}
+
+/// Long messages handling
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PasteConfig {
+    /// Messages with more lines must be confirmed before being sent
+    pub max_lines: usize,
+    /// Shell command reading a message on its stdin and printing a link to it on its stdout
+    pub command: Option<String>,
+    /// Share long messages as text files through the HTTP upload service of the server instead
+    pub upload: bool,
+}
+
+impl Default for PasteConfig {
+    fn default() -> Self {
+        Self {
+            max_lines: 5,
+            command: None,
+            upload: false,
+        }
+    }
+}
+
+/// File sharing through HTTP upload
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct UploadConfig {
+    /// Shell command shrinking images too large for the upload service, accepts `{input}` and
+    /// `{output}` paths, see `template` for the syntax
+    pub resize_command: Option<String>,
+}
+
+/// Files sent peer to peer by contacts
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TransferConfig {
+    /// Directory received files are saved in, the download directory of the user by default
+    pub directory: Option<String>,
+}
+
+/// Incoming messages filter
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterConfig {
+    /// Bare jid of a contact or channel, `*` for all conversations
+    pub conversation: String,
+    /// Regular expression matched against message bodies
+    pub regex: String,
+    pub action: FilterAction,
+}
+
+/// Connection metrics
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StatsConfig {
+    /// Seconds between two pings of the server
+    pub ping_interval: u64,
+    /// Show the latency of the current account in the status bar
+    pub status_bar: bool,
+    /// Seconds of silence in a channel after which we ping ourselves in it to check we are still
+    /// joined, 0 never checks
+    pub channel_ping_interval: u64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: 300,
+            status_bar: false,
+            channel_ping_interval: 900,
+        }
+    }
+}
+
+/// Requests sent to servers and contacts
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct IqConfig {
+    /// Seconds to wait for an answer, 0 waits forever
+    pub timeout: u64,
+    /// Times a query left unanswered is sent again before giving up
+    pub retries: u32,
+}
+
+impl Default for IqConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 60,
+            retries: 1,
+        }
+    }
+}
+
+/// Built-in plugins, see /plugin
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PluginsConfig {
+    /// Plugins not given any event, as named by /plugin list
+    pub disabled: Vec<String>,
+}
+
+/// Headlines and server messages, gathered in the announcements window
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AnnouncementsConfig {
+    /// Ring like for a message instead of only marking the window unread
+    pub notify: bool,
+}
+
+/// Reminders of the birthdays and anniversaries of contacts, read from their vCard
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BirthdaysConfig {
+    /// Ring when reminding instead of only logging in the console
+    pub notify: bool,
+}
+
+/// Last interaction with Aparté, published in presence (XEP-0319)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct IdleConfig {
+    /// Tell contacts since when you are idle
+    pub publish: bool,
+    /// Seconds without typing after which you are idle
+    pub after: u64,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        Self {
+            publish: false,
+            after: 600,
+        }
+    }
+}
+
+/// Links received in some channels handed to a command, e.g. to save them to a read-it-later
+/// service
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LinksConfig {
+    /// Shell command given the link as argument, succeeding once it is archived
+    pub command: Option<String>,
+    /// Channels whose links are archived
+    pub rooms: Vec<String>,
+}
+
+/// Sidebars hidden on narrow terminals, Alt-r and Alt-o toggling them whatever the width
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LayoutConfig {
+    /// Columns under which the roster is hidden
+    pub roster_min_width: u16,
+    /// Columns under which the occupants of channels are hidden
+    pub occupants_min_width: u16,
+    /// Width of the occupants of channels, the one of the longest nick by default
+    pub occupants_width: Option<PanelWidth>,
+    /// Side of channels the occupants are shown on
+    pub occupants_position: PanelPosition,
+    /// Channels whose occupants are hidden
+    pub hidden_occupants: Vec<String>,
+    /// Draw a scrollbar on the right of conversations
+    pub scrollbar: bool,
+}
+
+impl Default for LayoutConfig {
+    fn default() -> Self {
+        Self {
+            roster_min_width: 100,
+            occupants_min_width: 80,
+            occupants_width: None,
+            occupants_position: PanelPosition::Right,
+            hidden_occupants: Vec::new(),
+            scrollbar: false,
+        }
+    }
+}
+
+/// Key sequences bound to actions or commands, like `"C-b n" = "next-window"`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct KeysConfig {
+    /// Key standing for `<leader>` in bindings, like `C-b` or `M-space`
+    pub leader: Option<String>,
+    /// Milliseconds waited for the next key of a sequence
+    pub timeout: u64,
+    /// Actions or commands by key sequence, the keys being separated by spaces
+    pub bindings: HashMap<String, String>,
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        Self {
+            leader: None,
+            timeout: 1000,
+            bindings: HashMap::new(),
+        }
+    }
+}
+
+/// Width of a sidebar, in columns like `20` or in percent of the terminal like `"25%"`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "RawPanelWidth")]
+pub enum PanelWidth {
+    Columns(u16),
+    Percent(u16),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPanelWidth {
+    Columns(u16),
+    Text(String),
+}
+
+impl TryFrom<RawPanelWidth> for PanelWidth {
+    type Error = String;
+
+    fn try_from(raw: RawPanelWidth) -> Result<Self, Self::Error> {
+        match raw {
+            RawPanelWidth::Columns(columns) => Ok(PanelWidth::Columns(columns)),
+            RawPanelWidth::Text(text) => PanelWidth::from_str(&text),
+        }
+    }
+}
+
+impl FromStr for PanelWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |_| format!("Invalid width {}, expected columns or a percentage", s);
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.trim().parse().map_err(invalid)? {
+                percent if percent <= 100 => Ok(PanelWidth::Percent(percent)),
+                _ => Err(format!("Invalid width {}, more than 100%", s)),
+            },
+            None => Ok(PanelWidth::Columns(s.trim().parse().map_err(invalid)?)),
+        }
+    }
+}
+
+impl fmt::Display for PanelWidth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PanelWidth::Columns(columns) => write!(f, "{} columns", columns),
+            PanelWidth::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PanelPosition {
+    Left,
+    Right,
+}
+
+/// Titles of the web pages linked in incoming messages
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PreviewsConfig {
+    /// Fetch the pages, which tells their server that the link is looked at
+    pub enabled: bool,
+    /// Bytes read at most from a page to find its title
+    pub max_size: u64,
+    /// Seconds after which a page is given up
+    pub timeout: u64,
+    /// Proxy the pages are fetched through, curl's environment variables apply otherwise
+    pub proxy: Option<String>,
+}
+
+impl Default for PreviewsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_size: 64 * 1024,
+            timeout: 10,
+            proxy: None,
+        }
+    }
+}
+
+/// Windows restored on next start
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Save open windows on exit and restore them on next start
+    pub restore: bool,
+    /// Seconds between two saves, 0 to only save on exit
+    pub autosave: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            restore: true,
+            autosave: 60,
+        }
+    }
+}
+
+/// Read activity disclosed to contacts and rooms, nothing by default
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PrivacyConfig {
+    /// Send chat states (XEP-0085)
+    pub chat_states: bool,
+    /// Send delivery receipts (XEP-0184)
+    pub receipts: bool,
+    /// Send displayed chat markers (XEP-0333)
+    pub markers: bool,
+    /// Per contact overrides
+    pub contacts: HashMap<String, PrivacyOverride>,
+    /// Per room overrides
+    pub rooms: HashMap<String, PrivacyOverride>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PrivacyOverride {
+    pub chat_states: Option<bool>,
+    pub receipts: Option<bool>,
+    pub markers: Option<bool>,
+}
+
+impl PrivacyConfig {
+    pub fn chat_states(&self, jid: &BareJid) -> bool {
+        self.get(jid, |o| o.chat_states).unwrap_or(self.chat_states)
+    }
+
+    pub fn receipts(&self, jid: &BareJid) -> bool {
+        self.get(jid, |o| o.receipts).unwrap_or(self.receipts)
+    }
+
+    pub fn markers(&self, jid: &BareJid) -> bool {
+        self.get(jid, |o| o.markers).unwrap_or(self.markers)
+    }
+
+    /// Whether a setting is enabled globally or for some contact or room, the features it
+    /// relies on being advertised to everyone alike
+    pub fn anywhere<F>(&self, global: bool, setting: F) -> bool
+    where
+        F: Fn(&PrivacyOverride) -> Option<bool>,
+    {
+        global
+            || self
+                .contacts
+                .values()
+                .chain(self.rooms.values())
+                .any(|o| setting(o) == Some(true))
+    }
+
+    fn get<F>(&self, jid: &BareJid, setting: F) -> Option<bool>
+    where
+        F: Fn(&PrivacyOverride) -> Option<bool>,
+    {
+        let jid = jid.to_string();
+        self.contacts
+            .get(&jid)
+            .and_then(&setting)
+            .or_else(|| self.rooms.get(&jid).and_then(&setting))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_privacy_overrides() {
+        // Given
+        let config: Config = toml::from_str(
+            r#"
+            [accounts]
+
+            [privacy]
+            chat_states = true
+
+            [privacy.contacts."friend@example.org"]
+            markers = true
+
+            [privacy.rooms."room@conference.example.org"]
+            chat_states = false
+            "#,
+        )
+        .unwrap();
+        let friend = BareJid::from_str("friend@example.org").unwrap();
+        let room = BareJid::from_str("room@conference.example.org").unwrap();
+        let other = BareJid::from_str("other@example.org").unwrap();
+
+        // Then
+        assert!(config.privacy.markers(&friend));
+        assert!(!config.privacy.markers(&other));
+        assert!(!config.privacy.chat_states(&room));
+        assert!(config.privacy.chat_states(&other));
+        assert!(!config.privacy.receipts(&room));
+        assert!(config
+            .privacy
+            .anywhere(config.privacy.markers, |o| o.markers));
+        assert!(!config
+            .privacy
+            .anywhere(config.privacy.receipts, |o| o.receipts));
+    }
+
+    #[test]
+    fn test_profiles() {
+        // Given
+        let config: Config = toml::from_str(
+            r#"
+            [accounts.work]
+            jid = "romeo@corp.example"
+            autoconnect = true
+
+            [accounts.personal]
+            jid = "romeo@example.org"
+            autoconnect = true
+
+            [[filters]]
+            conversation = "*"
+            regex = "juliet"
+            action = "notify"
+
+            [profiles.work]
+            accounts = ["work"]
+            rooms = ["team@conference.corp.example"]
+            filters = []
+
+            [profiles.work.format]
+            timestamp = "%R"
+
+            [profiles.broken]
+            accounts = ["school"]
+            "#,
+        )
+        .unwrap();
+
+        // When
+        let work = config.with_profile("work").unwrap();
+
+        // Then
+        assert_eq!(work.accounts.keys().collect::<Vec<_>>(), vec!["work"]);
+        assert!(work.filters.is_empty());
+        assert_eq!(work.format.timestamp, "%R");
+        assert_eq!(work.profile.as_deref(), Some("work"));
+        assert_eq!(config.filters.len(), 1);
+        assert_eq!(
+            config.with_profile("broken").unwrap_err(),
+            "Unknown account school in profile broken"
+        );
+        assert!(config.with_profile("school").is_err());
+    }
+
+    #[test]
+    fn test_panel_widths() {
+        // Given
+        let config: Config = toml::from_str(
+            r#"
+            [accounts]
+
+            [layout]
+            occupants_width = "25%"
+            occupants_position = "left"
+            "#,
+        )
+        .unwrap();
+
+        // Then
+        assert_eq!(config.layout.occupants_width, Some(PanelWidth::Percent(25)));
+        assert_eq!(config.layout.occupants_position, PanelPosition::Left);
+        assert_eq!(PanelWidth::from_str("20"), Ok(PanelWidth::Columns(20)));
+        assert!(PanelWidth::from_str("120%").is_err());
+        assert!(toml::from_str::<LayoutConfig>("occupants_width = 20").is_ok());
+    }
 }
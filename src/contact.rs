@@ -1,6 +1,7 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use chrono::{DateTime, FixedOffset};
 use std::cmp;
 use std::hash::{Hash, Hasher};
 use xmpp_parsers::roster::Subscription;
@@ -39,6 +40,10 @@ pub struct Contact {
     pub name: Option<String>,
     pub subscription: Subscription,
     pub presence: Presence,
+    /// Status text of the last presence received
+    pub status: Option<String>,
+    /// Last interaction of the contact with their client, when idle (XEP-0319)
+    pub idle: Option<DateTime<FixedOffset>>,
     pub groups: Vec<Group>,
 }
 
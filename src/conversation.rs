@@ -1,9 +1,12 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use chrono::{DateTime, FixedOffset};
 use std::cmp;
 use std::collections::HashMap;
+use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::str::FromStr;
 use xmpp_parsers::BareJid;
 
 use crate::account::Account;
@@ -17,6 +20,33 @@ pub enum Affiliation {
     None,
 }
 
+impl fmt::Display for Affiliation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Affiliation::Owner => write!(f, "owner"),
+            Affiliation::Admin => write!(f, "admin"),
+            Affiliation::Member => write!(f, "member"),
+            Affiliation::Outcast => write!(f, "outcast"),
+            Affiliation::None => write!(f, "none"),
+        }
+    }
+}
+
+impl FromStr for Affiliation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "owner" => Ok(Affiliation::Owner),
+            "admin" => Ok(Affiliation::Admin),
+            "member" => Ok(Affiliation::Member),
+            "outcast" => Ok(Affiliation::Outcast),
+            "none" => Ok(Affiliation::None),
+            _ => Err(format!("Unknown affiliation {}", s)),
+        }
+    }
+}
+
 #[derive(Hash, Eq, PartialEq, Clone, Debug, Copy)]
 pub enum Role {
     Visitor,
@@ -31,6 +61,8 @@ pub struct Occupant {
     pub jid: Option<BareJid>,
     pub affiliation: Affiliation,
     pub role: Role,
+    /// When the occupant was first seen, people already in the room are seen when we join it
+    pub joined: DateTime<FixedOffset>,
 }
 
 impl Ord for Occupant {
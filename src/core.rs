@@ -7,44 +7,55 @@ use futures::sink::SinkExt;
 use futures::stream::StreamExt;
 use rand::{self, Rng};
 use std::any::TypeId;
-use std::cell::{Ref, RefCell, RefMut};
-use std::collections::{HashMap, VecDeque};
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::fmt;
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
 use std::io::Read;
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::str::FromStr;
+use std::time::Duration;
 use termion::event::Key;
 use tokio::runtime::Runtime as TokioRuntime;
 use tokio::signal::unix;
 use tokio::sync::mpsc;
 use tokio::task;
-use tokio_xmpp::{
-    AsyncClient as TokioXmppClient, Error as XmppError, Event as XmppEvent, Packet as XmppPacket,
-};
+use tokio::time;
+use tokio_xmpp::{Error as XmppError, Event as XmppEvent, Packet as XmppPacket};
 use uuid::Uuid;
 use xmpp_parsers;
+use xmpp_parsers::chatstates::ChatState;
 use xmpp_parsers::delay::Delay;
 use xmpp_parsers::iq::{Iq, IqType};
 use xmpp_parsers::message::Message as XmppParsersMessage;
 use xmpp_parsers::muc::Muc;
 use xmpp_parsers::presence::{Presence, Show as PresenceShow, Type as PresenceType};
 use xmpp_parsers::pubsub::event::PubSubEvent;
-use xmpp_parsers::{iq, presence, BareJid, Element, FullJid, Jid};
+use xmpp_parsers::stanza_error::StanzaError;
+use xmpp_parsers::{ibr, iq, ns, presence, BareJid, Element, FullJid, Jid};
+use zeroize::Zeroize;
 
-use crate::account::{Account, ConnectionInfo};
+use crate::account::{self, Account, ConnectionInfo, InitialPresence};
+use crate::client::{self, Client, ComponentStream};
 use crate::color;
 use crate::command::{Command, CommandParser};
 use crate::config::Config;
 use crate::conversation::{Channel, Conversation};
 use crate::cursor::Cursor;
+use crate::dns;
 use crate::message::Message;
 use crate::mods;
+use crate::quarantine;
+use crate::remote;
 use crate::{
-    command_def, generate_arg_autocompletion, generate_command_autocompletions, generate_help,
-    parse_command_args,
+    build_subcommand_map, check_command_arg, command_def, generate_arg_autocompletion,
+    generate_command_autocompletions, generate_help, generate_sub_autocompletion,
+    generate_sub_help, generate_subcommands, generate_subs_help, parse_command_args,
+    parse_subcommand_attrs,
 };
 use crate::{contact, conversation};
 
@@ -61,6 +72,7 @@ pub enum Event {
     Start,
     Connect(ConnectionInfo, Password<String>),
     Connected(Account, Jid),
+    ComponentConnected(Account),
     Disconnected(Account, String),
     AuthError(Account, String),
     Stanza(Account, Element),
@@ -73,6 +85,12 @@ pub enum Event {
         account: Account,
         contact: BareJid,
     },
+    /// A contact moved from old to new, the two conversations are now one
+    Merge {
+        account: Account,
+        old: BareJid,
+        new: BareJid,
+    },
     Join {
         account: FullJid,
         channel: Jid,
@@ -86,9 +104,40 @@ pub enum Event {
     Leave(Channel),
     Iq(Account, iq::Iq),
     Disco(Account),
+    /// Identity and features of a service of the server of an account, found once connected
+    Service(Account, Jid, xmpp_parsers::disco::DiscoInfoResult),
+    /// A SOCKS5 proxy (XEP-0065) of the server of an account was found, its host resolved
+    Proxy(Account, mods::bytestreams::Proxy),
+    /// A SOCKS5 connection for the bytestream with the given sid was established through the
+    /// candidate with the given id, or none could be
+    Socks5 {
+        account: Account,
+        sid: String,
+        result: Result<String, String>,
+    },
+    /// The bytestream with the given sid ended, along with the size of the data it carried
+    Transferred {
+        account: Account,
+        sid: String,
+        result: Result<u64, String>,
+    },
     PubSub(Account, PubSubEvent),
     Presence(Account, presence::Presence),
     ReadPassword(Command),
+    /// Ask a value appended to the command once validated, like ReadPassword but shown
+    ReadInput(Command),
+    /// A file to share in a conversation was resized if needed, ready to be uploaded
+    UploadPrepared {
+        account: Account,
+        conversation: BareJid,
+        result: Result<mods::upload::Prepared, String>,
+    },
+    /// A long message typed in a window went through the paste command, giving its link
+    Pasted {
+        window: String,
+        raw_buf: String,
+        result: Result<String, String>,
+    },
     Win(String),
     Close(String),
     Contact(Account, contact::Contact),
@@ -100,7 +149,15 @@ pub enum Event {
         conversation: BareJid,
         occupant: conversation::Occupant,
     },
+    /// A message was selected in a conversation window, None when the selection is cleared
+    MessageSelected {
+        account: Account,
+        conversation: BareJid,
+        message: Option<Message>,
+    },
     WindowChange,
+    /// Time to save the state of the UI
+    AutoSave,
     LoadChannelHistory {
         account: Account,
         jid: BareJid,
@@ -113,6 +170,8 @@ pub enum Event {
     },
     Quit,
     Key(Key),
+    /// Special key like an arrow pressed with a modifier, which termion doesn't report
+    ModifiedKey(mods::ui::Modifier, Key),
     AutoComplete {
         account: Option<Account>,
         context: String,
@@ -123,7 +182,160 @@ pub enum Event {
     Completed(String, Cursor),
     ChangeWindow(String),
     Notification(String),
+    /// Headline or server message (MOTD…), shown in the announcements window
+    Announcement(Account, Message),
+    /// The terminal gained (true) or lost (false) the focus
+    Focus(bool),
+    /// The low bandwidth mode was turned on (true) or off (false)
+    LowBandwidth(bool),
     Subject(Account, Jid, HashMap<String, String>),
+    /// Time to measure the round trip to the server of an account
+    Ping(Account),
+    /// Round trip to the server of an account
+    Latency(Account, Duration),
+    /// Time to check silent channels still count us among their occupants
+    SelfPing,
+    /// Time for an iq sent with send_iq to be answered, by id and attempt
+    IqTimeout(Account, String, u32),
+    /// Bits of binary with the given content id were received
+    Bob(String),
+    /// Load the messages of a conversation around a date and scroll to them
+    GoTo {
+        account: Account,
+        conversation: BareJid,
+        date: DateTime<FixedOffset>,
+    },
+    /// A message was encrypted, along with the number of keys of its recipient
+    Encrypted {
+        account: Account,
+        conversation: BareJid,
+        id: String,
+        result: Result<(Element, usize), String>,
+    },
+    /// How an outgoing message is protected changed, or a decrypted one can't be trusted
+    Protection {
+        conversation: BareJid,
+        id: String,
+        protection: mods::openpgp::Protection,
+    },
+    /// A message was decrypted, its signature checked by gpg
+    Decrypted {
+        account: Account,
+        message: XmppParsersMessage,
+        delay: Option<Delay>,
+        signature: mods::openpgp::Signature,
+        /// Key of the account, looked up when the message was sent by it
+        own_key: Option<String>,
+    },
+    /// The OpenPGP key of an account was looked up in the gpg keyring
+    OwnKey {
+        account: Account,
+        purpose: mods::openpgp::KeyUse,
+        result: Result<String, String>,
+    },
+    /// The public key of an account was exported from the gpg keyring to be published
+    PublicKeyExported {
+        account: Account,
+        fingerprint: String,
+        result: Result<Vec<u8>, String>,
+    },
+    /// A key announced by a contact was imported in the gpg keyring, along with its status lines
+    KeyImported {
+        contact: BareJid,
+        fingerprint: String,
+        result: Result<String, String>,
+    },
+    /// Time of the reminder with the given id has come
+    Reminder(usize),
+    /// Fetch archived messages of a conversation preceding the oldest one known
+    History {
+        account: Account,
+        conversation: BareJid,
+        count: usize,
+    },
+    /// Fetch the archived messages missing where a gap placeholder was activated, see MamMod
+    FetchGap(String),
+    /// Log line shown in a channel window rather than in the console, like the result of a
+    /// command acting on the channel
+    Notice {
+        account: Account,
+        conversation: BareJid,
+        message: Message,
+    },
+    /// Show a message in its conversation window
+    ShowMessage {
+        account: Account,
+        conversation: BareJid,
+        message: Message,
+    },
+    /// Image of a custom emoji of a conversation is available
+    CustomEmoji {
+        conversation: BareJid,
+        shortname: String,
+        image: mods::bob::Bob,
+    },
+    /// A contact requests our attention (XEP-0224)
+    Attention {
+        account: Account,
+        contact: BareJid,
+    },
+    /// End of the screen flash of an attention request
+    FlashEnd,
+    /// Time to press the next key of a sequence is over, for the sequence with this number
+    ChordTimeout(u64),
+    /// Avatar of a contact changed, None when it has none anymore
+    Avatar {
+        contact: BareJid,
+        avatar: Option<mods::avatar::Avatar>,
+    },
+    /// Mood of a contact changed, None when they stopped publishing it
+    Mood {
+        contact: BareJid,
+        mood: Option<mods::mood::Mood>,
+    },
+    /// Activity of a contact changed, None when they stopped publishing it
+    Activity {
+        contact: BareJid,
+        activity: Option<mods::mood::Activity>,
+    },
+    /// Title of a web page linked in a conversation was fetched, None if it has none
+    LinkTitle {
+        conversation: BareJid,
+        url: String,
+        title: Option<String>,
+    },
+    /// The archive command ran for a link, see LinksConfig
+    LinkArchived {
+        url: String,
+        result: Result<(), String>,
+    },
+    /// Track played by the media player, None when nothing is played
+    NowPlaying(Option<mods::tune::Track>),
+    /// Local midnight passed
+    DayChanged,
+    /// Time to check whether the user is idle
+    IdleCheck,
+    /// Configuration of a profile is now used, accounts of the previous one are disconnected
+    Profile(String),
+    /// Conversations the window bar is restricted to, None to show them all, see /workspace
+    Workspace(Option<mods::workspace::Workspace>),
+    /// Time to connect or disconnect the accounts following their schedule
+    AccountSchedule,
+    /// Change the presence of every connected account, see PresenceMod
+    SetPresence {
+        show: Option<PresenceShow>,
+        status: Option<String>,
+    },
+    /// Registration form of a server fetched, see /register
+    RegistrationForm {
+        server: String,
+        result: Result<ibr::Query, String>,
+    },
+    /// Registration form of a server submitted
+    Registered {
+        server: String,
+        result: Result<(), String>,
+    },
 }
 
 pub enum Mod {
@@ -137,6 +349,47 @@ pub enum Mod {
     UI(mods::ui::UIMod),
     Mam(mods::mam::MamMod),
     Correction(mods::correction::CorrectionMod),
+    Receipts(mods::receipts::ReceiptsMod),
+    Rosterx(mods::rosterx::RosterxMod),
+    Moved(mods::moved::MovedMod),
+    Room(mods::room::RoomMod),
+    Upload(mods::upload::UploadMod),
+    Stats(mods::stats::StatsMod),
+    Filter(mods::filter::FilterMod),
+    Spoiler(mods::spoiler::SpoilerMod),
+    Requests(mods::requests::RequestsMod),
+    #[cfg(feature = "dbus")]
+    DBus(mods::dbus::DBusMod),
+    Captcha(mods::captcha::CaptchaMod),
+    Bob(mods::bob::BobMod),
+    Emoji(mods::emoji::EmojiMod),
+    Search(mods::search::SearchMod),
+    Remind(mods::remind::RemindMod),
+    OpenPgp(mods::openpgp::OpenPgpMod),
+    Avatar(mods::avatar::AvatarMod),
+    Attention(mods::attention::AttentionMod),
+    VCard(mods::vcard::VCardMod),
+    Presence(mods::presence::PresenceMod),
+    Blocking(mods::blocking::BlockingMod),
+    Preview(mods::preview::PreviewMod),
+    StreamManagement(mods::sm::StreamManagementMod),
+    Tune(mods::tune::TuneMod),
+    Profile(mods::profile::ProfileMod),
+    Idle(mods::idle::IdleMod),
+    LinkArchive(mods::links::LinkArchiveMod),
+    Mood(mods::mood::MoodMod),
+    Switch(mods::switch::SwitchMod),
+    Register(mods::register::RegisterMod),
+    Adhoc(mods::adhoc::AdhocMod),
+    Browse(mods::browse::BrowseMod),
+    Invite(mods::invite::InviteMod),
+    SelfPing(mods::selfping::SelfPingMod),
+    Workspace(mods::workspace::WorkspaceMod),
+    Accounts(mods::accounts::AccountsMod),
+    Bytestreams(mods::bytestreams::BytestreamsMod),
+    Ibb(mods::ibb::IbbMod),
+    FileTransfer(mods::filetransfer::FileTransferMod),
+    ClientState(mods::csi::ClientStateMod),
 }
 
 macro_rules! from_mod {
@@ -171,6 +424,47 @@ from_mod!(UI, mods::ui::UIMod);
 from_mod!(Mam, mods::mam::MamMod);
 from_mod!(Messages, mods::messages::MessagesMod);
 from_mod!(Correction, mods::correction::CorrectionMod);
+from_mod!(Receipts, mods::receipts::ReceiptsMod);
+from_mod!(Rosterx, mods::rosterx::RosterxMod);
+from_mod!(Moved, mods::moved::MovedMod);
+from_mod!(Room, mods::room::RoomMod);
+from_mod!(Upload, mods::upload::UploadMod);
+from_mod!(Stats, mods::stats::StatsMod);
+from_mod!(Filter, mods::filter::FilterMod);
+from_mod!(Spoiler, mods::spoiler::SpoilerMod);
+from_mod!(Requests, mods::requests::RequestsMod);
+#[cfg(feature = "dbus")]
+from_mod!(DBus, mods::dbus::DBusMod);
+from_mod!(Captcha, mods::captcha::CaptchaMod);
+from_mod!(Bob, mods::bob::BobMod);
+from_mod!(Emoji, mods::emoji::EmojiMod);
+from_mod!(Search, mods::search::SearchMod);
+from_mod!(Remind, mods::remind::RemindMod);
+from_mod!(OpenPgp, mods::openpgp::OpenPgpMod);
+from_mod!(Avatar, mods::avatar::AvatarMod);
+from_mod!(Attention, mods::attention::AttentionMod);
+from_mod!(VCard, mods::vcard::VCardMod);
+from_mod!(Presence, mods::presence::PresenceMod);
+from_mod!(Blocking, mods::blocking::BlockingMod);
+from_mod!(Preview, mods::preview::PreviewMod);
+from_mod!(StreamManagement, mods::sm::StreamManagementMod);
+from_mod!(Tune, mods::tune::TuneMod);
+from_mod!(Profile, mods::profile::ProfileMod);
+from_mod!(Idle, mods::idle::IdleMod);
+from_mod!(LinkArchive, mods::links::LinkArchiveMod);
+from_mod!(Mood, mods::mood::MoodMod);
+from_mod!(Switch, mods::switch::SwitchMod);
+from_mod!(Register, mods::register::RegisterMod);
+from_mod!(Adhoc, mods::adhoc::AdhocMod);
+from_mod!(Browse, mods::browse::BrowseMod);
+from_mod!(Invite, mods::invite::InviteMod);
+from_mod!(SelfPing, mods::selfping::SelfPingMod);
+from_mod!(Workspace, mods::workspace::WorkspaceMod);
+from_mod!(Accounts, mods::accounts::AccountsMod);
+from_mod!(Bytestreams, mods::bytestreams::BytestreamsMod);
+from_mod!(Ibb, mods::ibb::IbbMod);
+from_mod!(FileTransfer, mods::filetransfer::FileTransferMod);
+from_mod!(ClientState, mods::csi::ClientStateMod);
 
 pub trait ModTrait: fmt::Display {
     fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()>;
@@ -211,6 +505,47 @@ impl ModTrait for Mod {
             Mod::Mam(r#mod) => r#mod.init(aparte),
             Mod::Messages(r#mod) => r#mod.init(aparte),
             Mod::Correction(r#mod) => r#mod.init(aparte),
+            Mod::Receipts(r#mod) => r#mod.init(aparte),
+            Mod::Rosterx(r#mod) => r#mod.init(aparte),
+            Mod::Moved(r#mod) => r#mod.init(aparte),
+            Mod::Room(r#mod) => r#mod.init(aparte),
+            Mod::Upload(r#mod) => r#mod.init(aparte),
+            Mod::Stats(r#mod) => r#mod.init(aparte),
+            Mod::Filter(r#mod) => r#mod.init(aparte),
+            Mod::Spoiler(r#mod) => r#mod.init(aparte),
+            Mod::Requests(r#mod) => r#mod.init(aparte),
+            #[cfg(feature = "dbus")]
+            Mod::DBus(r#mod) => r#mod.init(aparte),
+            Mod::Captcha(r#mod) => r#mod.init(aparte),
+            Mod::Bob(r#mod) => r#mod.init(aparte),
+            Mod::Emoji(r#mod) => r#mod.init(aparte),
+            Mod::Search(r#mod) => r#mod.init(aparte),
+            Mod::Remind(r#mod) => r#mod.init(aparte),
+            Mod::OpenPgp(r#mod) => r#mod.init(aparte),
+            Mod::Avatar(r#mod) => r#mod.init(aparte),
+            Mod::Attention(r#mod) => r#mod.init(aparte),
+            Mod::VCard(r#mod) => r#mod.init(aparte),
+            Mod::Presence(r#mod) => r#mod.init(aparte),
+            Mod::Blocking(r#mod) => r#mod.init(aparte),
+            Mod::Preview(r#mod) => r#mod.init(aparte),
+            Mod::StreamManagement(r#mod) => r#mod.init(aparte),
+            Mod::Tune(r#mod) => r#mod.init(aparte),
+            Mod::Profile(r#mod) => r#mod.init(aparte),
+            Mod::Idle(r#mod) => r#mod.init(aparte),
+            Mod::LinkArchive(r#mod) => r#mod.init(aparte),
+            Mod::Mood(r#mod) => r#mod.init(aparte),
+            Mod::Switch(r#mod) => r#mod.init(aparte),
+            Mod::Register(r#mod) => r#mod.init(aparte),
+            Mod::Adhoc(r#mod) => r#mod.init(aparte),
+            Mod::Browse(r#mod) => r#mod.init(aparte),
+            Mod::Invite(r#mod) => r#mod.init(aparte),
+            Mod::SelfPing(r#mod) => r#mod.init(aparte),
+            Mod::Workspace(r#mod) => r#mod.init(aparte),
+            Mod::Accounts(r#mod) => r#mod.init(aparte),
+            Mod::Bytestreams(r#mod) => r#mod.init(aparte),
+            Mod::Ibb(r#mod) => r#mod.init(aparte),
+            Mod::FileTransfer(r#mod) => r#mod.init(aparte),
+            Mod::ClientState(r#mod) => r#mod.init(aparte),
         }
     }
 
@@ -226,6 +561,47 @@ impl ModTrait for Mod {
             Mod::Mam(r#mod) => r#mod.on_event(aparte, event),
             Mod::Messages(r#mod) => r#mod.on_event(aparte, event),
             Mod::Correction(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Receipts(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Rosterx(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Moved(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Room(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Upload(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Stats(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Filter(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Spoiler(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Requests(r#mod) => r#mod.on_event(aparte, event),
+            #[cfg(feature = "dbus")]
+            Mod::DBus(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Captcha(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Bob(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Emoji(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Search(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Remind(r#mod) => r#mod.on_event(aparte, event),
+            Mod::OpenPgp(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Avatar(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Attention(r#mod) => r#mod.on_event(aparte, event),
+            Mod::VCard(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Presence(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Blocking(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Preview(r#mod) => r#mod.on_event(aparte, event),
+            Mod::StreamManagement(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Tune(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Profile(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Idle(r#mod) => r#mod.on_event(aparte, event),
+            Mod::LinkArchive(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Mood(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Switch(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Register(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Adhoc(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Browse(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Invite(r#mod) => r#mod.on_event(aparte, event),
+            Mod::SelfPing(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Workspace(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Accounts(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Bytestreams(r#mod) => r#mod.on_event(aparte, event),
+            Mod::Ibb(r#mod) => r#mod.on_event(aparte, event),
+            Mod::FileTransfer(r#mod) => r#mod.on_event(aparte, event),
+            Mod::ClientState(r#mod) => r#mod.on_event(aparte, event),
         }
     }
 
@@ -253,6 +629,57 @@ impl ModTrait for Mod {
             Mod::Correction(r#mod) => {
                 r#mod.can_handle_xmpp_message(aparte, account, message, delay)
             }
+            Mod::Receipts(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Rosterx(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Moved(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Room(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Upload(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Stats(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Filter(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Spoiler(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Requests(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            #[cfg(feature = "dbus")]
+            Mod::DBus(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Captcha(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Bob(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Emoji(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Search(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Remind(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::OpenPgp(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Avatar(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Attention(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::VCard(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Presence(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Blocking(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Preview(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::StreamManagement(r#mod) => {
+                r#mod.can_handle_xmpp_message(aparte, account, message, delay)
+            }
+            Mod::Tune(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Profile(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Idle(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::LinkArchive(r#mod) => {
+                r#mod.can_handle_xmpp_message(aparte, account, message, delay)
+            }
+            Mod::Mood(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Switch(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Register(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Adhoc(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Browse(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Invite(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::SelfPing(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Workspace(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Accounts(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::Bytestreams(r#mod) => {
+                r#mod.can_handle_xmpp_message(aparte, account, message, delay)
+            }
+            Mod::Ibb(r#mod) => r#mod.can_handle_xmpp_message(aparte, account, message, delay),
+            Mod::FileTransfer(r#mod) => {
+                r#mod.can_handle_xmpp_message(aparte, account, message, delay)
+            }
+            Mod::ClientState(r#mod) => {
+                r#mod.can_handle_xmpp_message(aparte, account, message, delay)
+            }
         }
     }
 
@@ -274,10 +701,62 @@ impl ModTrait for Mod {
             Mod::Mam(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
             Mod::Messages(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
             Mod::Correction(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Receipts(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Rosterx(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Moved(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Room(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Upload(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Stats(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Filter(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Spoiler(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Requests(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            #[cfg(feature = "dbus")]
+            Mod::DBus(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Captcha(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Bob(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Emoji(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Search(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Remind(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::OpenPgp(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Avatar(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Attention(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::VCard(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Presence(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Blocking(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Preview(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::StreamManagement(r#mod) => {
+                r#mod.handle_xmpp_message(aparte, account, message, delay)
+            }
+            Mod::Tune(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Profile(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Idle(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::LinkArchive(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Mood(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Switch(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Register(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Adhoc(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Browse(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Invite(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::SelfPing(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Workspace(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Accounts(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Bytestreams(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::Ibb(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::FileTransfer(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
+            Mod::ClientState(r#mod) => r#mod.handle_xmpp_message(aparte, account, message, delay),
         }
     }
 }
 
+impl Mod {
+    /// Name of the mod given to /plugin, its variant in lowercase
+    pub fn name(&self) -> String {
+        format!("{:?}", self)
+            .trim_start_matches("Mod::")
+            .to_lowercase()
+    }
+}
+
 impl fmt::Debug for Mod {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -291,6 +770,47 @@ impl fmt::Debug for Mod {
             Mod::Mam(_) => f.write_str("Mod::Mam"),
             Mod::Messages(_) => f.write_str("Mod::Messages"),
             Mod::Correction(_) => f.write_str("Mod::Correction"),
+            Mod::Receipts(_) => f.write_str("Mod::Receipts"),
+            Mod::Rosterx(_) => f.write_str("Mod::Rosterx"),
+            Mod::Moved(_) => f.write_str("Mod::Moved"),
+            Mod::Room(_) => f.write_str("Mod::Room"),
+            Mod::Upload(_) => f.write_str("Mod::Upload"),
+            Mod::Stats(_) => f.write_str("Mod::Stats"),
+            Mod::Filter(_) => f.write_str("Mod::Filter"),
+            Mod::Spoiler(_) => f.write_str("Mod::Spoiler"),
+            Mod::Requests(_) => f.write_str("Mod::Requests"),
+            #[cfg(feature = "dbus")]
+            Mod::DBus(_) => f.write_str("Mod::DBus"),
+            Mod::Captcha(_) => f.write_str("Mod::Captcha"),
+            Mod::Bob(_) => f.write_str("Mod::Bob"),
+            Mod::Emoji(_) => f.write_str("Mod::Emoji"),
+            Mod::Search(_) => f.write_str("Mod::Search"),
+            Mod::Remind(_) => f.write_str("Mod::Remind"),
+            Mod::OpenPgp(_) => f.write_str("Mod::OpenPgp"),
+            Mod::Avatar(_) => f.write_str("Mod::Avatar"),
+            Mod::Attention(_) => f.write_str("Mod::Attention"),
+            Mod::VCard(_) => f.write_str("Mod::VCard"),
+            Mod::Presence(_) => f.write_str("Mod::Presence"),
+            Mod::Blocking(_) => f.write_str("Mod::Blocking"),
+            Mod::Preview(_) => f.write_str("Mod::Preview"),
+            Mod::StreamManagement(_) => f.write_str("Mod::StreamManagement"),
+            Mod::Tune(_) => f.write_str("Mod::Tune"),
+            Mod::Profile(_) => f.write_str("Mod::Profile"),
+            Mod::Idle(_) => f.write_str("Mod::Idle"),
+            Mod::LinkArchive(_) => f.write_str("Mod::LinkArchive"),
+            Mod::Mood(_) => f.write_str("Mod::Mood"),
+            Mod::Switch(_) => f.write_str("Mod::Switch"),
+            Mod::Register(_) => f.write_str("Mod::Register"),
+            Mod::Adhoc(_) => f.write_str("Mod::Adhoc"),
+            Mod::Browse(_) => f.write_str("Mod::Browse"),
+            Mod::Invite(_) => f.write_str("Mod::Invite"),
+            Mod::SelfPing(_) => f.write_str("Mod::SelfPing"),
+            Mod::Workspace(_) => f.write_str("Mod::Workspace"),
+            Mod::Accounts(_) => f.write_str("Mod::Accounts"),
+            Mod::Bytestreams(_) => f.write_str("Mod::Bytestreams"),
+            Mod::Ibb(_) => f.write_str("Mod::Ibb"),
+            Mod::FileTransfer(_) => f.write_str("Mod::FileTransfer"),
+            Mod::ClientState(_) => f.write_str("Mod::ClientState"),
         }
     }
 }
@@ -308,14 +828,94 @@ impl fmt::Display for Mod {
             Mod::Mam(r#mod) => r#mod.fmt(f),
             Mod::Messages(r#mod) => r#mod.fmt(f),
             Mod::Correction(r#mod) => r#mod.fmt(f),
+            Mod::Receipts(r#mod) => r#mod.fmt(f),
+            Mod::Rosterx(r#mod) => r#mod.fmt(f),
+            Mod::Moved(r#mod) => r#mod.fmt(f),
+            Mod::Room(r#mod) => r#mod.fmt(f),
+            Mod::Upload(r#mod) => r#mod.fmt(f),
+            Mod::Stats(r#mod) => r#mod.fmt(f),
+            Mod::Filter(r#mod) => r#mod.fmt(f),
+            Mod::Spoiler(r#mod) => r#mod.fmt(f),
+            Mod::Requests(r#mod) => r#mod.fmt(f),
+            #[cfg(feature = "dbus")]
+            Mod::DBus(r#mod) => r#mod.fmt(f),
+            Mod::Captcha(r#mod) => r#mod.fmt(f),
+            Mod::Bob(r#mod) => r#mod.fmt(f),
+            Mod::Emoji(r#mod) => r#mod.fmt(f),
+            Mod::Search(r#mod) => r#mod.fmt(f),
+            Mod::Remind(r#mod) => r#mod.fmt(f),
+            Mod::OpenPgp(r#mod) => r#mod.fmt(f),
+            Mod::Avatar(r#mod) => r#mod.fmt(f),
+            Mod::Attention(r#mod) => r#mod.fmt(f),
+            Mod::VCard(r#mod) => r#mod.fmt(f),
+            Mod::Presence(r#mod) => r#mod.fmt(f),
+            Mod::Blocking(r#mod) => r#mod.fmt(f),
+            Mod::Preview(r#mod) => r#mod.fmt(f),
+            Mod::StreamManagement(r#mod) => r#mod.fmt(f),
+            Mod::Tune(r#mod) => r#mod.fmt(f),
+            Mod::Profile(r#mod) => r#mod.fmt(f),
+            Mod::Idle(r#mod) => r#mod.fmt(f),
+            Mod::LinkArchive(r#mod) => r#mod.fmt(f),
+            Mod::Mood(r#mod) => r#mod.fmt(f),
+            Mod::Switch(r#mod) => r#mod.fmt(f),
+            Mod::Register(r#mod) => r#mod.fmt(f),
+            Mod::Adhoc(r#mod) => r#mod.fmt(f),
+            Mod::Browse(r#mod) => r#mod.fmt(f),
+            Mod::Invite(r#mod) => r#mod.fmt(f),
+            Mod::SelfPing(r#mod) => r#mod.fmt(f),
+            Mod::Workspace(r#mod) => r#mod.fmt(f),
+            Mod::Accounts(r#mod) => r#mod.fmt(f),
+            Mod::Bytestreams(r#mod) => r#mod.fmt(f),
+            Mod::Ibb(r#mod) => r#mod.fmt(f),
+            Mod::FileTransfer(r#mod) => r#mod.fmt(f),
+            Mod::ClientState(r#mod) => r#mod.fmt(f),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Password<T: FromStr>(pub T);
+/// Secret wiped from memory once dropped and never shown in logs
+#[derive(Clone)]
+pub struct Password<T: FromStr + Zeroize>(pub T);
+
+impl Password<String> {
+    /// Read a password from the first line of an inherited file descriptor
+    pub fn from_fd(fd: RawFd) -> Result<Self, String> {
+        // Taking a standard stream would close it once read
+        if fd <= 2 {
+            return Err(format!("File descriptor {} is a standard stream", fd));
+        }
+        // Safety: fcntl only queries the flags of the descriptor
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            return Err(format!("File descriptor {} is not open", fd));
+        }
+        // Safety: the descriptor is open and was handed to us on the command line for this sole
+        // purpose, it is closed once read
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        let mut content = String::new();
+        let result = file.read_to_string(&mut content);
+        let password = content.lines().next().unwrap_or("").to_string();
+        content.zeroize();
+        result.map_err(|err| err.to_string())?;
+        match password.is_empty() {
+            true => Err(format!("No password to read on file descriptor {}", fd)),
+            false => Ok(Password(password)),
+        }
+    }
+}
 
-impl<T: FromStr> FromStr for Password<T> {
+impl<T: FromStr + Zeroize> fmt::Debug for Password<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Password(***)")
+    }
+}
+
+impl<T: FromStr + Zeroize> Drop for Password<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: FromStr + Zeroize> FromStr for Password<T> {
     type Err = T::Err;
 
     fn from_str(s: &str) -> Result<Self, T::Err> {
@@ -328,7 +928,48 @@ impl<T: FromStr> FromStr for Password<T> {
 
 pub struct Connection {
     pub sink: mpsc::Sender<Element>,
+    #[allow(dead_code)]
     pub account: FullJid,
+    /// Connected as an external component (XEP-0114), sending on behalf of its domain
+    pub component: bool,
+}
+
+/// Why an iq sent with [`Aparte::send_iq`] got no result
+#[derive(Debug, Clone)]
+pub enum IqError {
+    /// The recipient answered with an error
+    Stanza(Box<StanzaError>),
+    /// The connection was lost before any answer
+    Disconnected,
+    /// No answer came in time, even after retrying
+    Timeout,
+}
+
+impl fmt::Display for IqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IqError::Stanza(err) => match err.texts.get("en") {
+                Some(text) => write!(f, "{:?}: {}", err.defined_condition, text),
+                None => write!(f, "{:?}", err.defined_condition),
+            },
+            IqError::Disconnected => write!(f, "Disconnected"),
+            IqError::Timeout => write!(f, "No answer"),
+        }
+    }
+}
+
+/// Payload of the result of an iq, or why there is none
+pub type IqResponse = Result<Option<Element>, IqError>;
+
+type IqCallback = Box<dyn FnOnce(&mut Aparte, &Account, IqResponse)>;
+
+/// Iq waiting for its answer
+struct PendingIq {
+    /// Request, sent again on timeout when it's a get
+    iq: Iq,
+    /// Number of times the request was sent again
+    attempt: u32,
+    callback: IqCallback,
 }
 
 pub struct Aparte {
@@ -341,6 +982,19 @@ pub struct Aparte {
     event_channel: Option<mpsc::Sender<Event>>,
     /// Aparté main configuration
     pub config: Config,
+    /// Path of the configuration file
+    pub config_path: PathBuf,
+    /// Stanzas are shown in the console instead of being sent, see /simulate
+    dry_run: bool,
+    /// Mods not given events anymore, see /plugin
+    disabled_mods: HashSet<TypeId>,
+    /// Resolver shared by connections, created when first needed
+    resolver: Option<dns::Resolver>,
+    /// Iqs sent with send_iq by account and id
+    pending_iqs: HashMap<(Account, String), PendingIq>,
+    /// File descriptor given on the command line to read the password of the first account
+    /// connecting automatically from
+    password_fd: Option<RawFd>,
 }
 
 command_def!(connect,
@@ -366,6 +1020,13 @@ Examples:
     password: Password<String>
 },
 |aparte, _command| {
+    let disabled = {
+        let accounts = aparte.get_mod::<mods::accounts::AccountsMod>();
+        accounts.disabled(&aparte.config, &account_name)
+    };
+    if let Some(name) = disabled {
+        return Err(format!("Account {} is disabled, enable it with /account enable {}", name, name));
+    }
     let account = {
         if let Some((_, account)) = aparte.config.accounts.iter().find(|(name, _)| *name == &account_name) {
             account.clone()
@@ -377,6 +1038,14 @@ Examples:
                 server: None,
                 port: None,
                 autoconnect: false,
+                component: false,
+                keepalive: account::default_keepalive(),
+                compression: false,
+                openpgp_key: None,
+                initial_presence: InitialPresence::default(),
+                nick: None,
+                stream_management: account::default_stream_management(),
+                schedule: Vec::new(),
             }
         } else {
             return Err(format!("Unknown account or invalid jid {}", account_name));
@@ -541,9 +1210,7 @@ Example:
                 let from: Jid = account.clone().into();
                 let timestamp = LocalTz::now();
                 let message = Message::outgoing_chat(id, timestamp.into(), &from, &jid, &bodies);
-                aparte.schedule(Event::Message(Some(account.clone()), message.clone()));
-
-                aparte.send(&account, Element::try_from(message).unwrap());
+                aparte.schedule(Event::SendMessage(account.clone(), message));
             }
             Ok(())
         },
@@ -630,6 +1297,80 @@ Example:
     }
 );
 
+command_def!(
+    simulate,
+    r#"/simulate <command>
+
+    command       Command to simulate, with its arguments
+
+Description:
+    Show the stanzas a command would send instead of sending them, to learn
+    how Aparté speaks XMPP or to review an action before doing it. Local
+    effects of the command, like an edited bookmark, still apply, and stanzas
+    sent later by background tasks are sent as usual.
+
+Examples:
+    /simulate /msg juliet@example.org hello
+    /simulate /bookmark add aparte aparte@conference.fariello.eu"#,
+    {},
+    |aparte, _command| {
+        if _command.args.len() < 2 {
+            return Err("Missing command argument".to_string());
+        }
+        let mut buf = Command::assemble_args(&_command.args[1..]);
+        if !buf.starts_with('/') {
+            buf.insert(0, '/');
+        }
+        if Command::parse_name(&buf)? == "simulate" {
+            return Err("Cannot simulate /simulate".to_string());
+        }
+
+        aparte.log(format!("Simulating {}", buf));
+        // Events scheduled by the command are handled in dry run too, until the event queue is empty
+        aparte.dry_run = true;
+        let result = aparte.handle_raw_command(&_command.account, &_command.context, &buf);
+        if result.is_err() {
+            aparte.dry_run = false;
+        }
+        result
+    }
+);
+
+command_def!(lowbandwidth,
+r#"/lowbandwidth [on|off]
+
+    on|off        Enable or disable the low bandwidth mode
+
+Description:
+    Reduce the traffic for mobile or tethered connections: chat states,
+    displayed markers and periodic pings aren't sent anymore, history is
+    fetched by smaller pages and the server is told Aparté is inactive so that
+    it holds back presences. Without argument, show the current mode.
+
+    Stream compression isn't enabled along as it weakens encryption, it is
+    enabled per account with compression = true.
+
+Examples:
+    /lowbandwidth
+    /lowbandwidth on"#,
+{
+    state: Option<String> = {
+        values: ["on", "off"]
+    }
+},
+|aparte, _command| {
+    if let Some(state) = state {
+        aparte.config.low_bandwidth = state == "on";
+        aparte.schedule(Event::LowBandwidth(aparte.config.low_bandwidth));
+    }
+    let state = match aparte.config.low_bandwidth {
+        true => "on",
+        false => "off",
+    };
+    aparte.log(format!("Low bandwidth mode is {}", state));
+    Ok(())
+});
+
 command_def!(help,
 r#"/help [command]
 
@@ -662,6 +1403,111 @@ Examples:
     }
 });
 
+/// Mods Aparté can't work without, they can't be disabled
+const ESSENTIAL_MODS: [&str; 6] = [
+    "completion",
+    "contact",
+    "conversation",
+    "disco",
+    "messages",
+    "ui",
+];
+
+command_def!(
+    plugin_list,
+    r#"/plugin list
+
+Description:
+    List the built-in plugins, the disabled ones being dimmed."#,
+    {},
+    |aparte, _command| {
+        let mut plugins = aparte
+            .mods
+            .iter()
+            .map(|(type_id, r#mod)| {
+                let r#mod = r#mod.borrow();
+                (
+                    r#mod.name(),
+                    r#mod.to_string(),
+                    aparte.disabled_mods.contains(type_id),
+                )
+            })
+            .collect::<Vec<_>>();
+        plugins.sort();
+        let plugins = plugins
+            .into_iter()
+            .map(|(name, description, disabled)| {
+                let line = format!("  {}: {}", name, description);
+                match disabled {
+                    true => color::dimmed(&format!("{} (disabled)", line)),
+                    false => line,
+                }
+            })
+            .collect::<Vec<_>>();
+        aparte.log(format!("Plugins:\n{}", plugins.join("\n")));
+        Ok(())
+    }
+);
+
+command_def!(plugin_enable,
+r#"/plugin enable <plugin>
+
+    plugin        Name of the plugin, as listed by /plugin list
+
+Description:
+    Enable a plugin disabled in the configuration or with /plugin disable.
+
+Examples:
+    /plugin enable carbons"#,
+{
+    name: String = {
+        completion: (|aparte, _command| {
+            aparte.mod_names()
+        })
+    }
+},
+|aparte, _command| {
+    aparte.enable_mod(&name, true)?;
+    aparte.log(format!("Plugin {} enabled", name));
+    Ok(())
+});
+
+command_def!(plugin_disable,
+r#"/plugin disable <plugin>
+
+    plugin        Name of the plugin, as listed by /plugin list
+
+Description:
+    Disable a plugin, which stops receiving events and stanzas until enabled
+    again. Its commands stay available.
+
+Examples:
+    /plugin disable carbons"#,
+{
+    name: String = {
+        completion: (|aparte, _command| {
+            aparte.mod_names()
+        })
+    }
+},
+|aparte, _command| {
+    aparte.enable_mod(&name, false)?;
+    aparte.log(format!("Plugin {} disabled", name));
+    Ok(())
+});
+
+command_def!(plugin,
+r#"/plugin list|enable|disable"#,
+{
+    action: Command = {
+        children: {
+            "list": plugin_list,
+            "enable": plugin_enable,
+            "disable": plugin_disable,
+        }
+    },
+});
+
 mod me {
     use chrono::Local as LocalTz;
     use std::collections::HashMap;
@@ -755,17 +1601,22 @@ Examples:
             parse,
             exec,
             autocompletions: vec![],
+            subcommands: HashMap::new(),
         }
     }
 }
 
 impl Aparte {
-    pub fn new(config_path: PathBuf) -> Self {
+    pub fn new(
+        config_path: PathBuf,
+        profile: Option<String>,
+        password_fd: Option<RawFd>,
+    ) -> Result<Self, String> {
         let mut config_file = match OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(config_path)
+            .open(&config_path)
         {
             Err(err) => panic!("Cannot read config file {}", err),
             Ok(config_file) => config_file,
@@ -776,21 +1627,50 @@ impl Aparte {
             panic!("Cannot read config file {}", e);
         }
 
-        let config = match config_str.len() {
-            0 => Config {
-                accounts: HashMap::new(),
-            },
+        let mut config = match config_str.len() {
+            0 => Config::default(),
             _ => match toml::from_str(&config_str) {
                 Err(err) => {
                     error!("Malformed config file: {}", err);
-                    Config {
-                        accounts: HashMap::new(),
-                    }
+                    Config::default()
                 }
                 Ok(config) => config,
             },
         };
 
+        if profile.is_some() {
+            config.profile = profile;
+        }
+        if let Some(profile) = &config.profile {
+            if let Err(err) = config.with_profile(profile) {
+                return Err(format!("Cannot use profile: {}", err));
+            }
+        }
+
+        let mut aparte = Self::with_ui(config, config_path, mods::ui::UIMod::new());
+        aparte.password_fd = password_fd;
+        Ok(aparte)
+    }
+
+    /// Aparté connected to nothing yet but a headless UI, events spawned in the background are
+    /// sent to the given channel
+    #[cfg(test)]
+    pub fn headless(config: Config, config_path: PathBuf, events: mpsc::Sender<Event>) -> Self {
+        let mut aparte = Self::with_ui(config, config_path, mods::ui::UIMod::headless(80, 24));
+        aparte.event_channel = Some(events);
+        aparte
+    }
+
+    fn with_ui(config: Config, config_path: PathBuf, ui: mods::ui::UIMod) -> Self {
+        // Mods only see the configuration of the profile used
+        let base = config.clone();
+        let config = match &base.profile {
+            Some(profile) => base.with_profile(profile).unwrap_or_else(|err| {
+                error!("Cannot use profile: {}", err);
+                base.clone()
+            }),
+            None => base.clone(),
+        };
         let mut aparte = Self {
             command_parsers: Rc::new(HashMap::new()),
             mods: Rc::new(HashMap::new()),
@@ -800,6 +1680,12 @@ impl Aparte {
             send_queue: VecDeque::new(),
             event_channel: None,
             config: config,
+            config_path,
+            dry_run: false,
+            disabled_mods: HashSet::new(),
+            resolver: None,
+            pending_iqs: HashMap::new(),
+            password_fd: None,
         };
 
         aparte.add_mod(Mod::Completion(mods::completion::CompletionMod::new()));
@@ -808,10 +1694,51 @@ impl Aparte {
         aparte.add_mod(Mod::Conversation(mods::conversation::ConversationMod::new()));
         aparte.add_mod(Mod::Disco(mods::disco::DiscoMod::new()));
         aparte.add_mod(Mod::Bookmarks(mods::bookmarks::BookmarksMod::new()));
-        aparte.add_mod(Mod::UI(mods::ui::UIMod::new()));
+        aparte.add_mod(Mod::UI(ui));
         aparte.add_mod(Mod::Mam(mods::mam::MamMod::new()));
         aparte.add_mod(Mod::Messages(mods::messages::MessagesMod::new()));
         aparte.add_mod(Mod::Correction(mods::correction::CorrectionMod::new()));
+        aparte.add_mod(Mod::Receipts(mods::receipts::ReceiptsMod::new()));
+        aparte.add_mod(Mod::Rosterx(mods::rosterx::RosterxMod::new()));
+        aparte.add_mod(Mod::Moved(mods::moved::MovedMod::new()));
+        aparte.add_mod(Mod::Room(mods::room::RoomMod::new()));
+        aparte.add_mod(Mod::Upload(mods::upload::UploadMod::new()));
+        aparte.add_mod(Mod::Stats(mods::stats::StatsMod::new()));
+        aparte.add_mod(Mod::Filter(mods::filter::FilterMod::new()));
+        aparte.add_mod(Mod::Spoiler(mods::spoiler::SpoilerMod::new()));
+        aparte.add_mod(Mod::Requests(mods::requests::RequestsMod::new()));
+        #[cfg(feature = "dbus")]
+        aparte.add_mod(Mod::DBus(mods::dbus::DBusMod::new()));
+        aparte.add_mod(Mod::Captcha(mods::captcha::CaptchaMod::new()));
+        aparte.add_mod(Mod::Bob(mods::bob::BobMod::new()));
+        aparte.add_mod(Mod::Emoji(mods::emoji::EmojiMod::new()));
+        aparte.add_mod(Mod::Search(mods::search::SearchMod::new()));
+        aparte.add_mod(Mod::Remind(mods::remind::RemindMod::new()));
+        aparte.add_mod(Mod::OpenPgp(mods::openpgp::OpenPgpMod::new()));
+        aparte.add_mod(Mod::Avatar(mods::avatar::AvatarMod::new()));
+        aparte.add_mod(Mod::Attention(mods::attention::AttentionMod::new()));
+        aparte.add_mod(Mod::VCard(mods::vcard::VCardMod::new()));
+        aparte.add_mod(Mod::Presence(mods::presence::PresenceMod::new()));
+        aparte.add_mod(Mod::Blocking(mods::blocking::BlockingMod::new()));
+        aparte.add_mod(Mod::Preview(mods::preview::PreviewMod::new()));
+        aparte.add_mod(Mod::StreamManagement(mods::sm::StreamManagementMod::new()));
+        aparte.add_mod(Mod::Tune(mods::tune::TuneMod::new()));
+        aparte.add_mod(Mod::Profile(mods::profile::ProfileMod::new(base)));
+        aparte.add_mod(Mod::Idle(mods::idle::IdleMod::new()));
+        aparte.add_mod(Mod::LinkArchive(mods::links::LinkArchiveMod::new()));
+        aparte.add_mod(Mod::Mood(mods::mood::MoodMod::new()));
+        aparte.add_mod(Mod::Switch(mods::switch::SwitchMod::new()));
+        aparte.add_mod(Mod::Register(mods::register::RegisterMod::new()));
+        aparte.add_mod(Mod::Adhoc(mods::adhoc::AdhocMod::new()));
+        aparte.add_mod(Mod::Browse(mods::browse::BrowseMod::new()));
+        aparte.add_mod(Mod::Invite(mods::invite::InviteMod::new()));
+        aparte.add_mod(Mod::SelfPing(mods::selfping::SelfPingMod::new()));
+        aparte.add_mod(Mod::Workspace(mods::workspace::WorkspaceMod::new()));
+        aparte.add_mod(Mod::Accounts(mods::accounts::AccountsMod::new()));
+        aparte.add_mod(Mod::Bytestreams(mods::bytestreams::BytestreamsMod::new()));
+        aparte.add_mod(Mod::Ibb(mods::ibb::IbbMod::new()));
+        aparte.add_mod(Mod::FileTransfer(mods::filetransfer::FileTransferMod::new()));
+        aparte.add_mod(Mod::ClientState(mods::csi::ClientStateMod::new()));
 
         aparte
     }
@@ -831,7 +1758,7 @@ impl Aparte {
 
         let parser = {
             match self.command_parsers.get(command_name) {
-                Some(parser) => parser.clone(),
+                Some(parser) => parser,
                 None => return Err(format!("Unknown command {}", command_name)),
             }
         };
@@ -843,7 +1770,7 @@ impl Aparte {
     pub fn handle_command(&mut self, command: Command) -> Result<(), String> {
         let parser = {
             match self.command_parsers.get(&command.args[0]) {
-                Some(parser) => parser.clone(),
+                Some(parser) => parser,
                 None => return Err(format!("Unknown command {}", command.args[0])),
             }
         };
@@ -916,75 +1843,390 @@ impl Aparte {
                     RefCell::new(Mod::Correction(r#mod)),
                 );
             }
-        }
-    }
-
-    pub fn get_mod<'a, T>(&'a self) -> Ref<'a, T>
-    where
-        T: 'static,
-        for<'b> &'b T: From<&'b Mod>,
-    {
-        match self.mods.get(&TypeId::of::<T>()) {
-            Some(r#mod) => Ref::map(r#mod.borrow(), |m| m.into()),
-            None => unreachable!(),
-        }
-    }
-
-    pub fn get_mod_mut<T: 'static>(&self) -> RefMut<T>
-    where
-        T: 'static,
-        for<'b> &'b mut T: From<&'b mut Mod>,
-    {
-        match self.mods.get(&TypeId::of::<T>()) {
-            Some(r#mod) => RefMut::map(r#mod.borrow_mut(), |m| m.into()),
-            None => unreachable!(),
-        }
-    }
-
-    pub fn add_connection(&mut self, account: Account, sink: mpsc::Sender<Element>) {
-        let connection = Connection {
-            account: account.clone(),
-            sink,
-        };
-
-        self.connections.insert(account.clone(), connection);
-        self.current_connection = Some(account.clone());
-    }
-
-    pub fn current_account(&self) -> Option<Account> {
-        self.current_connection.clone()
-    }
-
-    pub fn init(&mut self) -> Result<(), ()> {
-        self.add_command(help::new());
-        self.add_command(connect::new());
-        self.add_command(win::new());
-        self.add_command(close::new());
-        self.add_command(leave::new());
-        self.add_command(msg::new());
-        self.add_command(join::new());
-        self.add_command(quit::new());
-        self.add_command(me::new());
-
-        let mods = Rc::clone(&self.mods);
-        for (_, r#mod) in mods.iter() {
-            if let Err(err) = r#mod.borrow_mut().init(self) {
-                return Err(err);
+            Mod::Receipts(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::receipts::ReceiptsMod>(),
+                    RefCell::new(Mod::Receipts(r#mod)),
+                );
             }
-        }
-
-        Ok(())
-    }
-
-    pub fn run(mut self) {
-        let mut input_event_stream = {
-            let ui = self.get_mod::<mods::ui::UIMod>();
-            ui.event_stream()
-        };
+            Mod::Rosterx(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::rosterx::RosterxMod>(),
+                    RefCell::new(Mod::Rosterx(r#mod)),
+                );
+            }
+            Mod::Moved(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::moved::MovedMod>(),
+                    RefCell::new(Mod::Moved(r#mod)),
+                );
+            }
+            Mod::Room(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::room::RoomMod>(),
+                    RefCell::new(Mod::Room(r#mod)),
+                );
+            }
+            Mod::Upload(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::upload::UploadMod>(),
+                    RefCell::new(Mod::Upload(r#mod)),
+                );
+            }
+            Mod::Stats(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::stats::StatsMod>(),
+                    RefCell::new(Mod::Stats(r#mod)),
+                );
+            }
+            Mod::Filter(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::filter::FilterMod>(),
+                    RefCell::new(Mod::Filter(r#mod)),
+                );
+            }
+            Mod::Spoiler(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::spoiler::SpoilerMod>(),
+                    RefCell::new(Mod::Spoiler(r#mod)),
+                );
+            }
+            Mod::Requests(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::requests::RequestsMod>(),
+                    RefCell::new(Mod::Requests(r#mod)),
+                );
+            }
+            #[cfg(feature = "dbus")]
+            Mod::DBus(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::dbus::DBusMod>(),
+                    RefCell::new(Mod::DBus(r#mod)),
+                );
+            }
+            Mod::Captcha(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::captcha::CaptchaMod>(),
+                    RefCell::new(Mod::Captcha(r#mod)),
+                );
+            }
+            Mod::Bob(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::bob::BobMod>(),
+                    RefCell::new(Mod::Bob(r#mod)),
+                );
+            }
+            Mod::Emoji(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::emoji::EmojiMod>(),
+                    RefCell::new(Mod::Emoji(r#mod)),
+                );
+            }
+            Mod::Search(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::search::SearchMod>(),
+                    RefCell::new(Mod::Search(r#mod)),
+                );
+            }
+            Mod::Remind(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::remind::RemindMod>(),
+                    RefCell::new(Mod::Remind(r#mod)),
+                );
+            }
+            Mod::OpenPgp(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::openpgp::OpenPgpMod>(),
+                    RefCell::new(Mod::OpenPgp(r#mod)),
+                );
+            }
+            Mod::Avatar(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::avatar::AvatarMod>(),
+                    RefCell::new(Mod::Avatar(r#mod)),
+                );
+            }
+            Mod::Attention(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::attention::AttentionMod>(),
+                    RefCell::new(Mod::Attention(r#mod)),
+                );
+            }
+            Mod::VCard(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::vcard::VCardMod>(),
+                    RefCell::new(Mod::VCard(r#mod)),
+                );
+            }
+            Mod::Presence(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::presence::PresenceMod>(),
+                    RefCell::new(Mod::Presence(r#mod)),
+                );
+            }
+            Mod::Blocking(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::blocking::BlockingMod>(),
+                    RefCell::new(Mod::Blocking(r#mod)),
+                );
+            }
+            Mod::Preview(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::preview::PreviewMod>(),
+                    RefCell::new(Mod::Preview(r#mod)),
+                );
+            }
+            Mod::StreamManagement(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::sm::StreamManagementMod>(),
+                    RefCell::new(Mod::StreamManagement(r#mod)),
+                );
+            }
+            Mod::Tune(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::tune::TuneMod>(),
+                    RefCell::new(Mod::Tune(r#mod)),
+                );
+            }
+            Mod::Profile(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::profile::ProfileMod>(),
+                    RefCell::new(Mod::Profile(r#mod)),
+                );
+            }
+            Mod::Idle(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::idle::IdleMod>(),
+                    RefCell::new(Mod::Idle(r#mod)),
+                );
+            }
+            Mod::LinkArchive(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::links::LinkArchiveMod>(),
+                    RefCell::new(Mod::LinkArchive(r#mod)),
+                );
+            }
+            Mod::Mood(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::mood::MoodMod>(),
+                    RefCell::new(Mod::Mood(r#mod)),
+                );
+            }
+            Mod::Switch(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::switch::SwitchMod>(),
+                    RefCell::new(Mod::Switch(r#mod)),
+                );
+            }
+            Mod::Register(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::register::RegisterMod>(),
+                    RefCell::new(Mod::Register(r#mod)),
+                );
+            }
+            Mod::Adhoc(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::adhoc::AdhocMod>(),
+                    RefCell::new(Mod::Adhoc(r#mod)),
+                );
+            }
+            Mod::Browse(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::browse::BrowseMod>(),
+                    RefCell::new(Mod::Browse(r#mod)),
+                );
+            }
+            Mod::Invite(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::invite::InviteMod>(),
+                    RefCell::new(Mod::Invite(r#mod)),
+                );
+            }
+            Mod::SelfPing(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::selfping::SelfPingMod>(),
+                    RefCell::new(Mod::SelfPing(r#mod)),
+                );
+            }
+            Mod::Workspace(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::workspace::WorkspaceMod>(),
+                    RefCell::new(Mod::Workspace(r#mod)),
+                );
+            }
+            Mod::Accounts(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::accounts::AccountsMod>(),
+                    RefCell::new(Mod::Accounts(r#mod)),
+                );
+            }
+            Mod::Bytestreams(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::bytestreams::BytestreamsMod>(),
+                    RefCell::new(Mod::Bytestreams(r#mod)),
+                );
+            }
+            Mod::Ibb(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::ibb::IbbMod>(),
+                    RefCell::new(Mod::Ibb(r#mod)),
+                );
+            }
+            Mod::FileTransfer(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::filetransfer::FileTransferMod>(),
+                    RefCell::new(Mod::FileTransfer(r#mod)),
+                );
+            }
+            Mod::ClientState(r#mod) => {
+                mods.insert(
+                    TypeId::of::<mods::csi::ClientStateMod>(),
+                    RefCell::new(Mod::ClientState(r#mod)),
+                );
+            }
+        }
+    }
+
+    pub fn get_mod<'a, T>(&'a self) -> Ref<'a, T>
+    where
+        T: 'static,
+        for<'b> &'b T: From<&'b Mod>,
+    {
+        match self.mods.get(&TypeId::of::<T>()) {
+            Some(r#mod) => Ref::map(r#mod.borrow(), |m| m.into()),
+            None => unreachable!(),
+        }
+    }
+
+    pub fn get_mod_mut<T: 'static>(&self) -> RefMut<'_, T>
+    where
+        T: 'static,
+        for<'b> &'b mut T: From<&'b mut Mod>,
+    {
+        match self.mods.get(&TypeId::of::<T>()) {
+            Some(r#mod) => RefMut::map(r#mod.borrow_mut(), |m| m.into()),
+            None => unreachable!(),
+        }
+    }
+
+    pub fn add_connection(&mut self, account: Account, sink: mpsc::Sender<Element>) {
+        let connection = Connection {
+            account: account.clone(),
+            sink,
+            component: false,
+        };
+
+        self.connections.insert(account.clone(), connection);
+        self.current_connection = Some(account.clone());
+    }
+
+    pub fn add_component_connection(&mut self, account: Account, sink: mpsc::Sender<Element>) {
+        let connection = Connection {
+            account: account.clone(),
+            sink,
+            component: true,
+        };
+
+        self.connections.insert(account.clone(), connection);
+        self.current_connection = Some(account.clone());
+    }
+
+    /// Channel feeding the event loop from other tasks, once it's running
+    #[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+    pub fn event_sender(&self) -> Option<mpsc::Sender<Event>> {
+        self.event_channel.clone()
+    }
+
+    pub fn current_account(&self) -> Option<Account> {
+        self.current_connection.clone()
+    }
+
+    /// Accounts currently connected
+    pub fn accounts(&self) -> Vec<Account> {
+        self.connections.keys().cloned().collect()
+    }
+
+    /// Close the stream of an account, it isn't reconnected
+    pub fn disconnect(&mut self, account: &Account) {
+        // The stream is closed once its sink is dropped, see connect
+        self.connections.remove(account);
+        if self.current_connection.as_ref() == Some(account) {
+            self.current_connection = self.connections.keys().next().cloned();
+        }
+    }
+
+    /// Nicks to use in a channel, by precedence: the one of its bookmark, of the account, the
+    /// global one and finally the node of the account
+    pub fn nicks(&self, account: &Account, channel: &BareJid) -> Vec<String> {
+        let bookmark = {
+            let bookmarks = self.get_mod::<mods::bookmarks::BookmarksMod>();
+            bookmarks
+                .get_by_jid(channel)
+                .and_then(|bookmark| bookmark.nick)
+        };
+        let jid: BareJid = account.clone().into();
+        let configured = self
+            .config
+            .accounts
+            .values()
+            .filter(|info| Jid::from_str(&info.jid).map(BareJid::from).as_ref() == Ok(&jid))
+            .find_map(|info| info.nick.clone());
+
+        let mut nicks = Vec::new();
+        let candidates = vec![
+            bookmark,
+            configured,
+            self.config.nick.clone(),
+            account.node.clone(),
+        ];
+        for nick in candidates.into_iter().flatten() {
+            if !nick.is_empty() && !nicks.contains(&nick) {
+                nicks.push(nick);
+            }
+        }
+        nicks
+    }
+
+    pub fn init(&mut self) -> Result<(), ()> {
+        self.add_command(help::new());
+        self.add_command(connect::new());
+        self.add_command(win::new());
+        self.add_command(close::new());
+        self.add_command(leave::new());
+        self.add_command(msg::new());
+        self.add_command(join::new());
+        self.add_command(quit::new());
+        self.add_command(lowbandwidth::new());
+        self.add_command(simulate::new());
+        self.add_command(me::new());
+        self.add_command(plugin::new());
+
+        let mods = Rc::clone(&self.mods);
+        for (_, r#mod) in mods.iter() {
+            if let Err(err) = r#mod.borrow_mut().init(self) {
+                return Err(err);
+            }
+        }
+
+        for name in self.config.plugins.disabled.clone() {
+            if let Err(err) = self.enable_mod(&name, false) {
+                self.log(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn run(mut self) {
+        let mut input_event_stream = {
+            let ui = self.get_mod::<mods::ui::UIMod>();
+            ui.event_stream()
+        };
 
         let (tx, mut rx) = mpsc::channel(32);
         let tx_for_signal = tx.clone();
         let tx_for_event = tx.clone();
+        let tx_for_remote = tx.clone();
         self.event_channel = Some(tx);
 
         let mut rt = TokioRuntime::new().unwrap();
@@ -1000,6 +2242,15 @@ impl Aparte {
             }
         });
 
+        if let Some(path) = remote::socket_path() {
+            rt.spawn(async move {
+                match remote::bind(&path) {
+                    Ok(socket) => remote::listen(socket, tx_for_remote).await,
+                    Err(err) => warn!("Remote commands are disabled: {}", err),
+                }
+            });
+        }
+
         rt.spawn(async move {
             loop {
                 match input_event_stream.next().await {
@@ -1040,32 +2291,205 @@ impl Aparte {
     pub fn start(&mut self) {
         self.log(color::rainbow(WELCOME));
         self.log(format!("Version: {}", VERSION));
+        self.autoconnect();
+    }
 
-        for (_, account) in self.config.accounts.clone() {
-            if account.autoconnect {
-                self.schedule(Event::RawCommand(
-                    None,
-                    "console".to_string(),
-                    format!("/connect {}", account.jid),
-                ));
+    /// Connect the accounts configured to be connected automatically, unless disabled or outside
+    /// of their schedule, see AccountsMod
+    pub fn autoconnect(&mut self) {
+        for (name, account) in self.config.accounts.clone() {
+            if !account.autoconnect {
+                continue;
+            }
+            let connectable = {
+                let mut accounts = self.get_mod_mut::<mods::accounts::AccountsMod>();
+                accounts.connectable(&name, &account)
+            };
+            if connectable {
+                self.connect_configured(account);
             }
         }
     }
 
+    /// Connect a configured account, reading its password from the file descriptor given on the
+    /// command line if not used yet
+    pub fn connect_configured(&mut self, account: ConnectionInfo) {
+        match self.password_fd.take() {
+            Some(fd) => match Password::from_fd(fd) {
+                Ok(password) => self.schedule(Event::Connect(account, password)),
+                Err(err) => self.log(format!("Cannot read password of {}: {}", account.jid, err)),
+            },
+            None => self.schedule(Event::RawCommand(
+                None,
+                "console".to_string(),
+                format!("/connect {}", account.jid),
+            )),
+        }
+    }
+
     pub fn send(&mut self, account: &Account, stanza: Element) {
+        if self.dry_run {
+            self.log(format!(
+                "Would send from {}:\n{}",
+                account,
+                pretty_xml(&stanza, None)
+            ));
+            return;
+        }
         self.send_queue.push_back((account.clone(), stanza));
     }
 
+    /// Send an iq and give its answer to a mod, the request being matched to its answer by id
+    /// and recipient
+    pub fn send_iq<T, F>(&mut self, account: &Account, iq: Iq, callback: F)
+    where
+        T: 'static,
+        for<'b> &'b mut T: From<&'b mut Mod>,
+        F: FnOnce(&mut T, &mut Aparte, &Account, IqResponse) + 'static,
+    {
+        if !self.dry_run {
+            let callback: IqCallback = Box::new(move |aparte, account, response| {
+                let mods = Rc::clone(&aparte.mods);
+                let mut r#mod = match mods.get(&TypeId::of::<T>()) {
+                    Some(r#mod) => RefMut::map(r#mod.borrow_mut(), |m| m.into()),
+                    None => unreachable!(),
+                };
+                callback(&mut r#mod, aparte, account, response);
+            });
+            let pending = PendingIq {
+                iq: iq.clone(),
+                attempt: 0,
+                callback,
+            };
+            self.pending_iqs
+                .insert((account.clone(), iq.id.clone()), pending);
+            self.watch_iq(account, &iq.id, 0);
+        }
+        self.send(account, iq.into());
+    }
+
+    /// Wait for the answer to an attempt at sending an iq
+    fn watch_iq(&self, account: &Account, id: &str, attempt: u32) {
+        let timeout = self.config.iq.timeout;
+        if timeout > 0 {
+            let account = account.clone();
+            let id = id.to_string();
+            self.spawn(async move {
+                time::sleep(Duration::from_secs(timeout)).await;
+                Event::IqTimeout(account, id, attempt)
+            });
+        }
+    }
+
+    /// Send again a get left unanswered, sets are not as they may not be idempotent, or give up
+    fn iq_timeout(&mut self, account: Account, id: String, attempt: u32) {
+        let key = (account.clone(), id.clone());
+        let retry = match self.pending_iqs.get_mut(&key) {
+            Some(pending) if pending.attempt == attempt => {
+                let get = matches!(pending.iq.payload, IqType::Get(_));
+                if get && pending.attempt < self.config.iq.retries {
+                    pending.attempt += 1;
+                    Some(pending.iq.clone())
+                } else {
+                    None
+                }
+            }
+            // Answered or sent again meanwhile
+            _ => return,
+        };
+        match retry {
+            Some(iq) => {
+                self.watch_iq(&account, &id, attempt + 1);
+                self.send(&account, iq.into());
+            }
+            None => {
+                if let Some(pending) = self.pending_iqs.remove(&key) {
+                    let to = match &pending.iq.to {
+                        Some(to) => to.to_string(),
+                        None => account.domain.clone(),
+                    };
+                    self.log(format!(
+                        "No answer from {} to {} query after {} seconds",
+                        to,
+                        match &pending.iq.payload {
+                            IqType::Get(query) | IqType::Set(query) => query.ns(),
+                            _ => String::new(),
+                        },
+                        self.config.iq.timeout * (attempt as u64 + 1)
+                    ));
+                    (pending.callback)(self, &account, Err(IqError::Timeout));
+                }
+            }
+        }
+    }
+
+    /// Answer to an iq sent with send_iq, None if the iq wasn't or if the answer doesn't come
+    /// from its recipient
+    fn take_pending_iq(&mut self, account: &Account, iq: &Iq) -> Option<PendingIq> {
+        let key = (account.clone(), iq.id.clone());
+        let to = &self.pending_iqs.get(&key)?.iq.to;
+        // Our server answers on behalf of our own account
+        let bare: BareJid = account.clone().into();
+        let own = |jid: &Option<Jid>| match jid {
+            None => true,
+            Some(Jid::Bare(jid)) => jid == &bare || jid == &BareJid::domain(&account.domain),
+            Some(Jid::Full(_)) => false,
+        };
+        match to == &iq.from || (own(to) && own(&iq.from)) {
+            true => self.pending_iqs.remove(&key),
+            false => None,
+        }
+    }
+
     async fn send_loop(&mut self) {
-        for (account, stanza) in self.send_queue.drain(..) {
+        let send_queue = std::mem::take(&mut self.send_queue);
+        for (account, mut stanza) in send_queue {
+            // Available presences advertise the features of Aparté (XEP-0115)
+            if stanza.is("presence", ns::DEFAULT_NS)
+                && stanza.attr("type").is_none()
+                && !stanza.has_child("c", ns::CAPS)
+            {
+                let caps = {
+                    let disco = self.get_mod::<mods::disco::DiscoMod>();
+                    disco.caps()
+                };
+                stanza.append_child(caps.into());
+            }
+            {
+                let mut idle = self.get_mod_mut::<mods::idle::IdleMod>();
+                idle.sent(&account, &mut stanza);
+            }
+            // Unlike the server of a client, the one of a component doesn't stamp what it sends
+            let component = self
+                .connections
+                .get(&account)
+                .map(|connection| connection.component)
+                .unwrap_or(false);
+            if component && stanza.attr("from").is_none() {
+                stanza.set_attr("from", account.domain.clone());
+            }
             let mut raw = Vec::<u8>::new();
             stanza.write_to(&mut raw).unwrap();
+            {
+                let mut stats = self.get_mod_mut::<mods::stats::StatsMod>();
+                stats.sent(&account, raw.len());
+            }
+            let request_ack = {
+                let mut sm = self.get_mod_mut::<mods::sm::StreamManagementMod>();
+                sm.sent(&account, &stanza)
+            };
             debug!("SEND: {}", String::from_utf8(raw).unwrap());
             match self.connections.get_mut(&account) {
                 Some(connection) => {
                     if let Err(e) = connection.sink.send(stanza).await {
                         warn!("Cannot send stanza: {}", e);
                     }
+                    if request_ack {
+                        let request = Element::builder("r", mods::sm::SM).build();
+                        if let Err(e) = connection.sink.send(request).await {
+                            warn!("Cannot request acknowledgement: {}", e);
+                        }
+                    }
                 }
                 None => {
                     warn!("No connection found for {}", account);
@@ -1075,6 +2499,10 @@ impl Aparte {
     }
 
     pub async fn connect(&mut self, connection_info: &ConnectionInfo, password: Password<String>) {
+        if connection_info.component {
+            return self.connect_component(connection_info, password).await;
+        }
+
         let account: Account = match Jid::from_str(&connection_info.jid) {
             Ok(Jid::Full(jid)) => jid,
             Ok(Jid::Bare(jid)) => {
@@ -1095,27 +2523,57 @@ impl Aparte {
         };
 
         self.log(format!("Connecting as {}", account));
-        let mut client = match TokioXmppClient::new(&account.to_string(), &password.0) {
-            Ok(client) => client,
+        let resolver = match self.resolver() {
+            Ok(resolver) => resolver,
             Err(err) => {
                 self.log(format!("Cannot connect as {}: {}", account, err));
                 return;
             }
         };
-
-        client.set_reconnect(true);
+        let mut client = Client::new(Jid::Full(account.clone()), &password.0, resolver);
+        client
+            .set_reconnect(true)
+            .set_compression(connection_info.compression)
+            .set_keepalive(connection_info.keepalive);
 
         let (connection_channel, mut rx) = mpsc::channel(32);
 
         self.add_connection(account.clone(), connection_channel);
 
         let (mut writer, mut reader) = client.split();
+        let keepalive = connection_info.keepalive;
+        let closing = Rc::new(Cell::new(false));
+        let writer_closing = Rc::clone(&closing);
         // XXX could use self.rt.spawn if client was impl Send
         task::spawn_local(async move {
-            while let Some(element) = rx.recv().await {
-                if let Err(err) = writer.send(XmppPacket::Stanza(element)).await {
-                    error!("cannot send Stanza to internal channel: {}", err);
-                    break;
+            let enabled = keepalive > 0;
+            let period = Duration::from_secs(keepalive.max(1));
+            let mut keepalive = time::interval_at(time::Instant::now() + period, period);
+            loop {
+                tokio::select! {
+                    element = rx.recv() => match element {
+                        Some(element) => {
+                            if let Err(err) = writer.send(XmppPacket::Stanza(element)).await {
+                                error!("cannot send Stanza to internal channel: {}", err);
+                                break;
+                            }
+                            // Traffic already keeps the connection alive
+                            keepalive.reset();
+                        }
+                        // Disconnected on purpose, the stream must not be reconnected
+                        None => {
+                            writer_closing.set(true);
+                            if let Err(err) = writer.send(XmppPacket::StreamEnd).await {
+                                warn!("Cannot close stream: {}", err);
+                            }
+                            break;
+                        }
+                    },
+                    _ = keepalive.tick(), if enabled => {
+                        if let Err(err) = writer.send(XmppPacket::Text(" ".to_string())).await {
+                            warn!("Cannot send keepalive: {}", err);
+                        }
+                    }
                 }
             }
         });
@@ -1125,7 +2583,6 @@ impl Aparte {
             None => unreachable!(),
         };
 
-        let reconnect = true;
         task::spawn_local(async move {
             while let Some(event) = reader.next().await {
                 debug!("XMPP Event: {:?}", event);
@@ -1146,7 +2603,7 @@ impl Aparte {
                         {
                             error!("Cannot send event to internal channel: {}", err);
                         };
-                        if !reconnect {
+                        if closing.get() {
                             break;
                         }
                     }
@@ -1183,14 +2640,174 @@ impl Aparte {
         });
     }
 
+    /// Connect a component to the first address of the server accepting it
+    async fn open_component(
+        resolver: dns::Resolver,
+        domain: &str,
+        secret: &str,
+        server: &str,
+        port: u16,
+        keepalive: u64,
+    ) -> Result<ComponentStream, String> {
+        let mut error = format!("{} has no address", server);
+        for address in resolver.lookup_host(server, port).await? {
+            match client::connect_component(domain, secret, address, keepalive).await {
+                Ok(component) => return Ok(component),
+                Err(err) => error = err.to_string(),
+            }
+        }
+        Err(error)
+    }
+
+    async fn connect_component(
+        &mut self,
+        connection_info: &ConnectionInfo,
+        secret: Password<String>,
+    ) {
+        // The resource is only used to identify the connection inside Aparté, the component
+        // itself is bound to the domain part of the jid.
+        let account: Account = match Jid::from_str(&connection_info.jid) {
+            Ok(Jid::Full(jid)) => jid,
+            Ok(Jid::Bare(jid)) => jid.with_resource("component"),
+            Err(err) => {
+                self.log(format!(
+                    "Cannot connect component {}: {}",
+                    connection_info.jid, err
+                ));
+                return;
+            }
+        };
+
+        let server = match &connection_info.server {
+            Some(server) => server.clone(),
+            None => "localhost".to_string(),
+        };
+        let port = connection_info.port.unwrap_or(5347);
+
+        self.log(format!(
+            "Connecting component {} to {}:{}",
+            account.domain, server, port
+        ));
+        let component = match self.resolver() {
+            Ok(resolver) => {
+                let keepalive = connection_info.keepalive;
+                Self::open_component(
+                    resolver,
+                    &account.domain,
+                    &secret.0,
+                    &server,
+                    port,
+                    keepalive,
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        };
+        let component = match component {
+            Ok(component) => component,
+            Err(err) => {
+                self.log(format!(
+                    "Cannot connect component {}: {}",
+                    account.domain, err
+                ));
+                return;
+            }
+        };
+
+        let (connection_channel, mut rx) = mpsc::channel(32);
+
+        self.add_component_connection(account.clone(), connection_channel);
+
+        let (mut writer, mut reader) = component.split();
+        let keepalive = connection_info.keepalive;
+        task::spawn_local(async move {
+            let enabled = keepalive > 0;
+            let period = Duration::from_secs(keepalive.max(1));
+            let mut keepalive = time::interval_at(time::Instant::now() + period, period);
+            loop {
+                tokio::select! {
+                    element = rx.recv() => match element {
+                        Some(element) => {
+                            if let Err(err) = writer.send(XmppPacket::Stanza(element)).await {
+                                error!("cannot send Stanza to internal channel: {}", err);
+                                break;
+                            }
+                            keepalive.reset();
+                        }
+                        None => break,
+                    },
+                    _ = keepalive.tick(), if enabled => {
+                        if let Err(err) = writer.send(XmppPacket::Text(" ".to_string())).await {
+                            warn!("Cannot send keepalive: {}", err);
+                        }
+                    }
+                }
+            }
+        });
+
+        let event_channel = match &self.event_channel {
+            Some(event_channel) => event_channel.clone(),
+            None => unreachable!(),
+        };
+
+        self.schedule(Event::ComponentConnected(account.clone()));
+
+        task::spawn_local(async move {
+            while let Some(Ok(packet)) = reader.next().await {
+                let stanza = match packet {
+                    XmppPacket::Stanza(stanza) => stanza,
+                    // Whitespace between stanzas
+                    XmppPacket::Text(_) => continue,
+                    _ => break,
+                };
+                debug!("RECV: {}", String::from(&stanza));
+                if let Err(err) = event_channel
+                    .send(Event::Stanza(account.clone(), stanza))
+                    .await
+                {
+                    error!("Cannot send stanza to internal channel: {}", err);
+                    return;
+                }
+            }
+
+            // Components don't reconnect, the stream is gone for good
+            if let Err(err) = event_channel
+                .send(Event::Disconnected(
+                    account.clone(),
+                    "Stream closed".to_string(),
+                ))
+                .await
+            {
+                error!("Cannot send event to internal channel: {}", err);
+            }
+        });
+    }
+
     pub async fn event_loop(&mut self) -> Result<(), ()> {
         while self.event_queue.len() > 0 {
-            let event = self.event_queue.remove(0);
-            debug!("Event: {:?}", event);
+            let event = match self.event_queue.remove(0) {
+                Event::Stanza(account, stanza) => match self.ingest(&account, stanza) {
+                    Some(stanza) => Event::Stanza(account, stanza),
+                    None => continue,
+                },
+                // Simulated messages are neither stored nor encrypted by mods
+                Event::SendMessage(account, message) if self.dry_run => {
+                    self.simulate_message(&account, message);
+                    continue;
+                }
+                event => event,
+            };
+            match &event {
+                // Arguments can hold a password read from the prompt
+                Event::Command(command) => debug!("Event: Command({:?})", command.args.first()),
+                event => debug!("Event: {:?}", event),
+            }
             {
                 let mods = Rc::clone(&self.mods);
-                for (_, r#mod) in mods.iter() {
-                    r#mod.borrow_mut().on_event(self, &event);
+                for (type_id, r#mod) in mods.iter() {
+                    if !self.disabled_mods.contains(type_id) {
+                        r#mod.borrow_mut().on_event(self, &event);
+                    }
                 }
                 self.send_loop().await;
             }
@@ -1211,8 +2828,26 @@ impl Aparte {
                 }
                 Event::SendMessage(account, message) => {
                     self.schedule(Event::Message(Some(account.clone()), message.clone()));
-                    if let Ok(xmpp_message) = Element::try_from(message) {
-                        self.send(&account, xmpp_message);
+                    // Encrypted messages are sent by the OpenPGP mod once encrypted
+                    let encrypted = {
+                        let openpgp = self.get_mod::<mods::openpgp::OpenPgpMod>();
+                        openpgp.encrypts(&account, &message)
+                    };
+                    let chat_states = match &message {
+                        Message::Xmpp(message) => {
+                            !self.config.low_bandwidth
+                                && self.config.privacy.chat_states(&message.to)
+                        }
+                        Message::Log(_) => false,
+                    };
+                    match Element::try_from(message) {
+                        Ok(mut xmpp_message) if !encrypted => {
+                            if chat_states {
+                                xmpp_message.append_child(ChatState::Active.into());
+                            }
+                            self.send(&account, xmpp_message);
+                        }
+                        _ => {}
                     }
                 }
                 Event::Connect(account, password) => {
@@ -1220,13 +2855,26 @@ impl Aparte {
                 }
                 Event::Connected(account, _) => {
                     self.log(format!("Connected as {}", account));
-                    let mut presence = Presence::new(PresenceType::None);
-                    presence.show = Some(PresenceShow::Chat);
-
-                    self.send(&account, presence.into());
+                }
+                Event::ComponentConnected(account) => {
+                    self.log(format!("Connected as component {}", account.domain));
                 }
                 Event::Disconnected(account, err) => {
-                    self.log(format!("Connection lost for {}: {}", account, err));
+                    match self.connections.contains_key(&account) {
+                        true => self.log(format!("Connection lost for {}: {}", account, err)),
+                        false => self.log(format!("Disconnected from {}", account)),
+                    }
+                    let lost = self
+                        .pending_iqs
+                        .keys()
+                        .filter(|(other, _)| other == &account)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    for key in lost {
+                        if let Some(pending) = self.pending_iqs.remove(&key) {
+                            (pending.callback)(self, &account, Err(IqError::Disconnected));
+                        }
+                    }
                 }
                 Event::AuthError(account, err) => {
                     self.log(format!("Authentication error for {}: {}", account, err));
@@ -1234,6 +2882,9 @@ impl Aparte {
                 Event::Stanza(account, stanza) => {
                     self.handle_stanza(account, stanza);
                 }
+                Event::IqTimeout(account, id, attempt) => {
+                    self.iq_timeout(account, id, attempt);
+                }
                 Event::RawMessage(account, message, delay) => {
                     self.handle_xmpp_message(account, message, delay);
                 }
@@ -1245,8 +2896,8 @@ impl Aparte {
                     let to = match channel.clone() {
                         Jid::Full(jid) => jid,
                         Jid::Bare(jid) => {
-                            let node = account.node.clone().unwrap();
-                            jid.with_resource(node)
+                            let nick = self.nicks(&account, &jid).into_iter().next();
+                            jid.with_resource(nick.unwrap_or_default())
                         }
                     };
                     let from: Jid = account.clone().into();
@@ -1257,8 +2908,8 @@ impl Aparte {
                     presence.add_payload(Muc::new());
                     self.send(&account, presence.into());
 
-                    // Successful join
-                    self.log(format!("Joined {}", channel));
+                    // The channel window waits for the channel to let us in, see RoomMod
+                    self.log(format!("Joining {}", channel));
                     self.schedule(Event::Joined {
                         account: account.clone(),
                         channel: to,
@@ -1280,19 +2931,124 @@ impl Aparte {
             }
             self.send_loop().await;
         }
+        self.dry_run = false;
 
         Ok(())
     }
 
+    fn simulate_message(&mut self, account: &Account, message: Message) {
+        let encrypted = {
+            let openpgp = self.get_mod::<mods::openpgp::OpenPgpMod>();
+            openpgp.encrypts(account, &message)
+        };
+        if encrypted {
+            self.log("The following message would be encrypted with OpenPGP".to_string());
+        }
+        if let Ok(stanza) = Element::try_from(message) {
+            self.send(account, stanza);
+        }
+    }
+
     pub fn schedule(&mut self, event: Event) {
         self.event_queue.push(event);
     }
 
+    /// Names of the mods, as shown by /plugin list
+    pub fn mod_names(&self) -> Vec<String> {
+        self.mods
+            .values()
+            .map(|r#mod| r#mod.borrow().name())
+            .collect()
+    }
+
+    /// Give a mod events again, or stop doing so
+    pub fn enable_mod(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        if ESSENTIAL_MODS.contains(&name) {
+            return Err(format!("Plugin {} can't be disabled", name));
+        }
+        let type_id = self
+            .mods
+            .iter()
+            .find(|(_, r#mod)| r#mod.borrow().name() == name)
+            .map(|(type_id, _)| *type_id)
+            .ok_or(format!("Unknown plugin {}", name))?;
+        match enabled {
+            true => self.disabled_mods.remove(&type_id),
+            false => self.disabled_mods.insert(type_id),
+        };
+        Ok(())
+    }
+
+    /// Resolver of server addresses, whose cache is shared by all connections
+    pub fn resolver(&mut self) -> Result<dns::Resolver, String> {
+        if self.resolver.is_none() {
+            self.resolver = Some(dns::Resolver::new()?);
+        }
+        Ok(self.resolver.clone().unwrap())
+    }
+
+    /// Run a long task in the background, the event it resolves to is then handled as usual
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = Event> + 'static,
+    {
+        let event_channel = match &self.event_channel {
+            Some(event_channel) => event_channel.clone(),
+            None => unreachable!(),
+        };
+
+        task::spawn_local(async move {
+            let event = future.await;
+            if let Err(err) = event_channel.send(event).await {
+                error!("Cannot send event to internal channel: {}", err);
+            }
+        });
+    }
+
     pub fn log(&mut self, message: String) {
         let message = Message::log(message);
         self.schedule(Event::Message(None, message));
     }
 
+    /// Take malformed payloads out of an incoming stanza and log them in the console, None if
+    /// the stanza itself is malformed
+    fn ingest(&mut self, account: &Account, stanza: Element) -> Option<Element> {
+        let from = stanza.attr("from").unwrap_or(&account.domain).to_string();
+        // Requests dropped are answered, their sender would wait for an answer otherwise
+        let request = match (stanza.is("iq", ns::DEFAULT_NS), stanza.attr("type")) {
+            (true, Some("get")) | (true, Some("set")) => stanza
+                .attr("id")
+                .map(|id| (id.to_string(), stanza.attr("from").map(Jid::from_str))),
+            _ => None,
+        };
+        let (stanza, quarantined) = quarantine::ingest(stanza);
+        if let (None, Some((id, to)), Some(dropped)) = (&stanza, request, quarantined.last()) {
+            let error = StanzaError::new(
+                xmpp_parsers::stanza_error::ErrorType::Modify,
+                xmpp_parsers::stanza_error::DefinedCondition::BadRequest,
+                "en",
+                dropped.reason.clone(),
+            );
+            let answer = match to {
+                Some(Ok(to)) => Iq::from_error(id, error).with_to(to),
+                _ => Iq::from_error(id, error),
+            };
+            self.send(account, answer.into());
+        }
+        for quarantined in quarantined {
+            let xml = String::from(&quarantined.element);
+            warn!("Quarantined malformed element from {}: {}", from, xml);
+            self.log(format!(
+                "Ignoring malformed <{}/> from {} ({}):\n{}",
+                quarantined.element.name(),
+                from,
+                quarantined.reason,
+                xml
+            ));
+        }
+        stanza
+    }
+
     fn handle_stanza(&mut self, account: Account, stanza: Element) {
         if let Ok(message) = XmppParsersMessage::try_from(stanza.clone()) {
             self.handle_xmpp_message(account, message, None);
@@ -1303,7 +3059,19 @@ impl Aparte {
                     self.schedule(Event::Message(Some(account.clone()), message));
                 }
             }
-            self.schedule(Event::Iq(account, iq));
+            let pending = match &iq.payload {
+                IqType::Result(_) | IqType::Error(_) => self.take_pending_iq(&account, &iq),
+                IqType::Get(_) | IqType::Set(_) => None,
+            };
+            match (pending, iq.payload.clone()) {
+                (Some(pending), IqType::Result(payload)) => {
+                    (pending.callback)(self, &account, Ok(payload))
+                }
+                (Some(pending), IqType::Error(err)) => {
+                    (pending.callback)(self, &account, Err(IqError::Stanza(Box::new(err))))
+                }
+                _ => self.schedule(Event::Iq(account, iq)),
+            }
         } else if let Ok(presence) = Presence::try_from(stanza.clone()) {
             self.schedule(Event::Presence(account, presence));
         }
@@ -1315,11 +3083,35 @@ impl Aparte {
         message: XmppParsersMessage,
         delay: Option<Delay>,
     ) {
+        let duplicate = {
+            let mut messages = self.get_mod_mut::<mods::messages::MessagesMod>();
+            messages.is_duplicate(&account, &message)
+        };
+        if duplicate {
+            debug!("Dropping already received message {:?}", message.id);
+            return;
+        }
+
+        let blocked = match &message.from {
+            Some(from) => {
+                let blocking = self.get_mod::<mods::blocking::BlockingMod>();
+                blocking.is_blocked(&account, from)
+            }
+            None => false,
+        };
+        if blocked {
+            debug!("Dropping message of blocked {:?}", message.from);
+            return;
+        }
+
         let mut best_match = 0f64;
         let mut matched_mod = None;
 
         let mods = Rc::clone(&self.mods);
-        for (_, r#mod) in mods.iter() {
+        for (type_id, r#mod) in mods.iter() {
+            if self.disabled_mods.contains(type_id) {
+                continue;
+            }
             let message_match = r#mod
                 .borrow_mut()
                 .can_handle_xmpp_message(self, &account, &message, &delay);
@@ -1339,3 +3131,395 @@ impl Aparte {
         }
     }
 }
+
+/// Indented XML of a stanza, namespaces are only declared where they change
+fn pretty_xml(element: &Element, parent_ns: Option<&str>) -> String {
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('\'', "&apos;")
+            .replace('"', "&quot;")
+    }
+
+    let ns = element.ns();
+    let mut open = format!("<{}", element.name());
+    if parent_ns != Some(ns.as_str()) {
+        open.push_str(&format!(" xmlns='{}'", escape(&ns)));
+    }
+    for (name, value) in element.attrs() {
+        open.push_str(&format!(" {}='{}'", name, escape(value)));
+    }
+
+    let mut children = Vec::new();
+    for node in element.nodes() {
+        match node.as_element() {
+            Some(child) => children.push(pretty_xml(child, Some(&ns))),
+            None => {
+                let text = node.as_text().unwrap_or_default().trim();
+                if !text.is_empty() {
+                    children.push(escape(text));
+                }
+            }
+        }
+    }
+
+    match children.as_slice() {
+        [] => format!("{}/>", open),
+        [text] if element.children().next().is_none() => {
+            format!("{}>{}</{}>", open, text, element.name())
+        }
+        children => format!(
+            "{}>\n{}\n</{}>",
+            open,
+            children
+                .join("\n")
+                .lines()
+                .map(|line| format!("  {}", line))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            element.name()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PluginsConfig;
+    use crate::testing::{self, Harness};
+    use xmpp_parsers::ns;
+
+    #[test]
+    fn test_pretty_xml() {
+        // Given
+        let stanza = Element::from_str(
+            "<message xmlns='jabber:client' to='juliet@example.org'><body>Hi &amp; bye</body><active xmlns='http://jabber.org/protocol/chatstates'/></message>",
+        )
+        .unwrap();
+
+        // When
+        let pretty = pretty_xml(&stanza, None);
+
+        // Then
+        assert_eq!(
+            pretty,
+            "<message xmlns='jabber:client' to='juliet@example.org'>
+  <body>Hi &amp; bye</body>
+  <active xmlns='http://jabber.org/protocol/chatstates'/>
+</message>"
+        );
+    }
+
+    #[test]
+    fn test_password_is_read_from_an_open_file_descriptor_only() {
+        // Given
+        let path = std::env::temp_dir().join(format!("aparte-password-{}", Uuid::new_v4()));
+        std::fs::write(&path, "secret\nignored\n").unwrap();
+        let fd = std::os::unix::io::IntoRawFd::into_raw_fd(File::open(&path).unwrap());
+
+        // When
+        let password = Password::from_fd(fd);
+        let stdin = Password::from_fd(0);
+        let stderr = Password::from_fd(2);
+        let closed = Password::from_fd(RawFd::MAX);
+
+        // Then
+        assert_eq!(
+            password.map(|password| password.0.clone()),
+            Ok(String::from("secret"))
+        );
+        assert_eq!(
+            stdin.err(),
+            Some(String::from("File descriptor 0 is a standard stream"))
+        );
+        assert!(stderr.is_err());
+        assert_eq!(
+            closed.err(),
+            Some(format!("File descriptor {} is not open", RawFd::MAX))
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_profile_is_an_error() {
+        // Given
+        let path = std::env::temp_dir().join(format!("aparte-config-{}.toml", Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            "[accounts]\n\n[profiles.work]\naccounts = [\"missing\"]\n",
+        )
+        .unwrap();
+
+        // When
+        let unknown = Aparte::new(path.clone(), Some(String::from("home")), None);
+        let missing_account = Aparte::new(path.clone(), Some(String::from("work")), None);
+
+        // Then
+        assert_eq!(
+            unknown.err(),
+            Some(String::from("Cannot use profile: Unknown profile home"))
+        );
+        assert_eq!(
+            missing_account.err(),
+            Some(String::from(
+                "Cannot use profile: Unknown account missing in profile work"
+            ))
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_simulate_doesnt_send_stanzas() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness.sent.clear();
+
+            // When
+            harness
+                .input("console", "/simulate /msg juliet@example.org \"Hello\"")
+                .await;
+
+            // Then
+            assert!(harness.take_sent("message", ns::DEFAULT_NS).is_empty());
+            let juliet = BareJid::from_str("juliet@example.org").unwrap();
+            let messages = harness
+                .aparte
+                .get_mod::<mods::messages::MessagesMod>()
+                .conversation(&Some(harness.account.clone()), &juliet);
+            assert!(messages.is_empty());
+
+            // When
+            harness
+                .input("juliet@example.org", "/msg juliet@example.org \"Hello\"")
+                .await;
+
+            // Then
+            assert_eq!(harness.take_sent("message", ns::DEFAULT_NS).len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_iq_answer_is_given_to_its_requester() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            let responses = Rc::new(RefCell::new(Vec::new()));
+            let iq = Iq::from_get("version-1", xmpp_parsers::version::VersionQuery)
+                .with_to(Jid::from_str("juliet@example.org/balcony").unwrap());
+            let received = Rc::clone(&responses);
+            let account = harness.account.clone();
+            harness.aparte.send_iq::<mods::disco::DiscoMod, _>(
+                &account,
+                iq,
+                move |_, _, _, response| received.borrow_mut().push(response),
+            );
+            harness.settle().await;
+
+            // When
+            harness
+                .receive(
+                    "<iq xmlns='jabber:client' type='result' id='version-1'
+                        from='tybalt@example.org/dagger' to='{account}'/>",
+                )
+                .await;
+
+            // Then
+            assert!(responses.borrow().is_empty());
+
+            // When
+            harness
+                .receive(
+                    "<iq xmlns='jabber:client' type='error' id='version-1'
+                        from='juliet@example.org/balcony' to='{account}'>
+                        <error type='cancel'>
+                            <service-unavailable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+                        </error>
+                    </iq>",
+                )
+                .await;
+
+            // Then
+            match responses.borrow().as_slice() {
+                [Err(IqError::Stanza(err))] => assert_eq!(
+                    err.defined_condition,
+                    xmpp_parsers::stanza_error::DefinedCondition::ServiceUnavailable
+                ),
+                other => panic!("Unexpected responses {:?}", other),
+            }
+            let key = (account, "version-1".to_string());
+            assert!(!harness.aparte.pending_iqs.contains_key(&key));
+        });
+    }
+
+    #[test]
+    fn test_malformed_request_is_answered_with_bad_request() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+
+            // When
+            harness
+                .receive(
+                    "<iq xmlns='jabber:client' type='set' id='r1'
+                        from='juliet@example.org/balcony' to='{account}'>
+                        <query xmlns='jabber:iq:roster'><item/></query>
+                    </iq>",
+                )
+                .await;
+
+            // Then
+            let answer = harness
+                .take_sent("error", ns::DEFAULT_NS)
+                .into_iter()
+                .map(|answer| Iq::try_from(answer).unwrap())
+                .find(|answer| answer.id == "r1")
+                .unwrap();
+            assert_eq!(
+                answer.to,
+                Some(Jid::from_str("juliet@example.org/balcony").unwrap())
+            );
+            match answer.payload {
+                IqType::Error(err) => assert_eq!(
+                    err.defined_condition,
+                    xmpp_parsers::stanza_error::DefinedCondition::BadRequest
+                ),
+                other => panic!("Unexpected answer {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn test_unanswered_query_is_sent_again_then_given_up() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            let responses = Rc::new(RefCell::new(Vec::new()));
+            let iq = Iq::from_get("version-1", xmpp_parsers::version::VersionQuery)
+                .with_to(Jid::from_str("juliet@example.org/balcony").unwrap());
+            let received = Rc::clone(&responses);
+            let account = harness.account.clone();
+            harness.aparte.send_iq::<mods::disco::DiscoMod, _>(
+                &account,
+                iq,
+                move |_, _, _, response| received.borrow_mut().push(response),
+            );
+
+            // When
+            let timeout = Event::IqTimeout(account.clone(), "version-1".to_string(), 0);
+            harness.aparte.schedule(timeout);
+            harness.settle().await;
+
+            // Then
+            assert_eq!(harness.take_sent("query", ns::VERSION).len(), 2);
+            assert!(responses.borrow().is_empty());
+
+            // When
+            let timeout = Event::IqTimeout(account.clone(), "version-1".to_string(), 1);
+            harness.aparte.schedule(timeout);
+            harness.settle().await;
+
+            // Then
+            assert!(harness.take_sent("query", ns::VERSION).is_empty());
+            assert!(matches!(
+                responses.borrow().as_slice(),
+                [Err(IqError::Timeout)]
+            ));
+            assert!(harness
+                .screen()
+                .contains("No answer from juliet@example.org/balcony to jabber:iq:version"));
+        });
+    }
+
+    #[test]
+    fn test_disabled_plugin_gets_no_event_until_enabled() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::with_config(Config {
+                plugins: PluginsConfig {
+                    disabled: vec!["carbons".to_string()],
+                },
+                ..Config::default()
+            });
+            harness.connect().await;
+            assert!(harness.take_sent("enable", ns::CARBONS).is_empty());
+            let carbon = "<message xmlns='jabber:client' from='romeo@example.org' to='{account}'>
+                <sent xmlns='urn:xmpp:carbons:2'>
+                    <forwarded xmlns='urn:xmpp:forward:0'>
+                        <message xmlns='jabber:client' type='chat' id='carbon-1'
+                            from='romeo@example.org/phone' to='juliet@example.org/balcony'>
+                            <body>On my way</body>
+                        </message>
+                    </forwarded>
+                </sent>
+            </message>";
+            harness.receive(carbon).await;
+            let juliet = BareJid::from_str("juliet@example.org").unwrap();
+            let conversation = |harness: &Harness| {
+                harness
+                    .aparte
+                    .get_mod::<mods::messages::MessagesMod>()
+                    .conversation(&Some(harness.account.clone()), &juliet)
+                    .len()
+            };
+            assert_eq!(conversation(&harness), 0);
+
+            // When
+            harness.input("console", "/plugin enable carbons").await;
+            harness.receive(carbon).await;
+
+            // Then
+            assert!(harness.screen().contains("Plugin carbons enabled"));
+            assert_eq!(conversation(&harness), 1);
+        });
+    }
+
+    #[test]
+    fn test_essential_plugin_cant_be_disabled() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+
+            // When
+            harness.input("console", "/plugin disable ui").await;
+
+            // Then
+            assert!(harness.screen().contains("Plugin ui can't be disabled"));
+            assert!(harness.aparte.disabled_mods.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_component_stanzas_are_sent_from_its_domain() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect_component().await;
+            // Gateways send on behalf of the users of their domain
+            let mut gateway =
+                XmppParsersMessage::new(Some(Jid::from_str("juliet@example.org").unwrap()));
+            gateway.from = Some(Jid::from_str("nurse@example.org").unwrap());
+
+            // When
+            let account = harness.account.clone();
+            harness.aparte.send(&account, gateway.into());
+            harness
+                .input("console", "/msg juliet@example.org \"Hello\"")
+                .await;
+
+            // Then
+            let sent = harness.take_sent("message", ns::DEFAULT_NS);
+            let from = sent
+                .iter()
+                .map(|message| message.attr("from"))
+                .collect::<Vec<_>>();
+            assert_eq!(from, vec![Some("nurse@example.org"), Some("example.org")]);
+        });
+    }
+}
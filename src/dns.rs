@@ -0,0 +1,178 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Asynchronous resolution of server addresses, from their SRV records (RFC 6120) or their own
+//! name.
+//!
+//! A single resolver is shared by all the connections, its cache sparing the lookups of
+//! reconnections and of accounts hosted on the same server. Failed lookups are cached too, so that
+//! a server going away isn't asked again on each reconnection attempt.
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::system_conf::read_system_conf;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Records kept in the cache
+const CACHE_SIZE: usize = 256;
+/// Shortest time a failed lookup is remembered
+const NEGATIVE_MIN_TTL: Duration = Duration::from_secs(30);
+/// Longest time a failed lookup is remembered, whatever the server answering says
+const NEGATIVE_MAX_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Clone)]
+pub struct Resolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl Resolver {
+    pub fn new() -> Result<Self, String> {
+        let (config, mut opts) = read_system_conf().unwrap_or_else(|err| {
+            warn!("Cannot read system resolver configuration: {}", err);
+            (ResolverConfig::default(), ResolverOpts::default())
+        });
+        opts.cache_size = CACHE_SIZE;
+        opts.negative_min_ttl = Some(NEGATIVE_MIN_TTL);
+        opts.negative_max_ttl = Some(NEGATIVE_MAX_TTL);
+        let resolver = TokioAsyncResolver::tokio(config, opts).map_err(|err| err.to_string())?;
+        Ok(Self { resolver })
+    }
+
+    /// Addresses of a service, from its SRV records in order of preference or from the name of
+    /// the domain and the default port when it has none
+    pub async fn lookup_service(
+        &self,
+        domain: &str,
+        service: &str,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>, String> {
+        if let Ok(ip) = domain.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let hosts = match self
+            .resolver
+            .srv_lookup(format!("{}.{}.", service, domain))
+            .await
+        {
+            Ok(lookup) => targets(
+                lookup
+                    .iter()
+                    .map(|srv| Target {
+                        priority: srv.priority(),
+                        weight: srv.weight(),
+                        host: srv.target().to_ascii(),
+                        port: srv.port(),
+                    })
+                    .collect(),
+            )
+            .ok_or(format!("{} doesn't offer {}", domain, service))?,
+            Err(_) => vec![(domain.to_string(), port)],
+        };
+
+        let mut addresses = Vec::new();
+        for (host, port) in hosts {
+            match self.lookup_host(&host, port).await {
+                Ok(found) => addresses.extend(found),
+                Err(err) => debug!("{}", err),
+            }
+        }
+        match addresses.is_empty() {
+            true => Err(format!("Cannot resolve {}", domain)),
+            false => Ok(addresses),
+        }
+    }
+
+    /// Addresses of a host
+    pub async fn lookup_host(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let ips = self
+            .resolver
+            .lookup_ip(host)
+            .await
+            .map_err(|err| format!("Cannot resolve {}: {}", host, err))?;
+        Ok(ips.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+}
+
+/// Host given by a SRV record
+#[derive(Debug, Clone)]
+struct Target {
+    priority: u16,
+    weight: u16,
+    host: String,
+    port: u16,
+}
+
+/// Hosts to try in turn, by priority then heaviest first. None when the domain states it doesn't
+/// offer the service with a single "." target.
+fn targets(mut targets: Vec<Target>) -> Option<Vec<(String, u16)>> {
+    if let [target] = targets.as_slice() {
+        if target.host == "." {
+            return None;
+        }
+    }
+    targets.sort_by_key(|target| (target.priority, u16::MAX - target.weight));
+    Some(
+        targets
+            .into_iter()
+            .map(|target| (target.host, target.port))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(priority: u16, weight: u16, host: &str) -> Target {
+        Target {
+            priority,
+            weight,
+            host: host.to_string(),
+            port: 5222,
+        }
+    }
+
+    #[test]
+    fn test_srv_targets_are_tried_by_priority_then_weight() {
+        // Given
+        let records = vec![
+            target(20, 0, "backup.example.org."),
+            target(10, 10, "light.example.org."),
+            target(10, 60, "heavy.example.org."),
+        ];
+
+        // When
+        let hosts = targets(records).unwrap();
+
+        // Then
+        let hosts = hosts
+            .iter()
+            .map(|(host, _)| host.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            hosts,
+            vec![
+                "heavy.example.org.",
+                "light.example.org.",
+                "backup.example.org."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_srv_dot_target_means_no_service() {
+        // Given
+        let records = vec![target(0, 0, ".")];
+
+        // When
+        let hosts = targets(records);
+
+        // Then
+        assert!(hosts.is_none());
+    }
+}
@@ -0,0 +1,223 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Data forms (XEP-0004) filled in the input, one field after the other, and forms shown in the
+//! console.
+use std::collections::HashMap;
+use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
+
+/// Field of a form asked to the user
+#[derive(Debug, Clone, PartialEq)]
+pub struct Question {
+    pub var: String,
+    pub label: String,
+    pub required: bool,
+    /// Typed without being shown
+    pub private: bool,
+    /// Takes several values, separated by commas
+    pub multi: bool,
+    /// Values kept when answered with an empty input
+    pub default: Vec<String>,
+    /// Values allowed, any when empty
+    pub options: Vec<String>,
+}
+
+impl Question {
+    /// Question for a field to fill, None for hidden and informative fields
+    pub fn from_field(field: &Field) -> Option<Self> {
+        let options = match field.type_ {
+            FieldType::Hidden | FieldType::Fixed => return None,
+            FieldType::Boolean => vec![String::from("true"), String::from("false")],
+            _ => field
+                .options
+                .iter()
+                .map(|option| option.value.clone())
+                .collect(),
+        };
+        Some(Self {
+            var: field.var.clone(),
+            label: field.label.clone().unwrap_or_else(|| field.var.clone()),
+            required: field.required,
+            private: field.type_ == FieldType::TextPrivate,
+            multi: matches!(
+                field.type_,
+                FieldType::JidMulti | FieldType::ListMulti | FieldType::TextMulti
+            ),
+            default: field.values.clone(),
+            options,
+        })
+    }
+
+    /// Text logged when the question is asked
+    pub fn prompt(&self) -> String {
+        let mut prompt = self.label.clone();
+        if !self.options.is_empty() {
+            prompt.push_str(&format!(" ({})", self.options.join(", ")));
+        }
+        if !self.default.is_empty() && !self.private {
+            prompt.push_str(&format!(" [{}]", self.default.join(", ")));
+        }
+        if !self.required {
+            prompt.push_str(" (optional)");
+        }
+        prompt.push(':');
+        prompt
+    }
+
+    /// Values of an answer, the default ones for an empty answer
+    pub fn values(&self, answer: &str) -> Result<Vec<String>, String> {
+        if answer.is_empty() {
+            return Ok(self.default.clone());
+        }
+        let values: Vec<String> = match self.multi {
+            true => answer
+                .split(',')
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .collect(),
+            false => vec![answer.to_string()],
+        };
+        match values
+            .iter()
+            .find(|value| !self.options.is_empty() && !self.options.contains(value))
+        {
+            Some(value) => Err(format!(
+                "{} isn't one of {}",
+                value,
+                self.options.join(", ")
+            )),
+            None => Ok(values),
+        }
+    }
+}
+
+/// Fields of a form to fill, in order
+pub fn questions(form: &DataForm) -> Vec<Question> {
+    form.fields
+        .iter()
+        .filter_map(Question::from_field)
+        .collect()
+}
+
+/// Form filled with the values answered, by field var. Hidden fields are returned untouched.
+pub fn submission(form: &DataForm, answers: &HashMap<String, Vec<String>>) -> DataForm {
+    let fields = form
+        .fields
+        .iter()
+        .filter_map(|field| match (&field.type_, answers.get(&field.var)) {
+            (FieldType::Hidden, _) => Some(field.clone()),
+            (_, Some(values)) => Some(Field {
+                values: values.clone(),
+                options: Vec::new(),
+                media: Vec::new(),
+                ..field.clone()
+            }),
+            _ => None,
+        })
+        .collect();
+    DataForm {
+        type_: DataFormType::Submit,
+        form_type: form.form_type.clone(),
+        title: None,
+        instructions: None,
+        fields,
+    }
+}
+
+/// Lines showing a form, its title and instructions first then each field with its values
+pub fn lines(form: &DataForm) -> Vec<String> {
+    let mut lines: Vec<String> = form.title.iter().cloned().collect();
+    lines.extend(form.instructions.clone());
+    for field in form.fields.iter() {
+        match field.type_ {
+            FieldType::Hidden => {}
+            FieldType::Fixed => lines.extend(field.values.iter().cloned()),
+            _ => lines.push(format!(
+                "  {}: {}",
+                field.label.as_ref().unwrap_or(&field.var),
+                field.values.join(", ")
+            )),
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+    use xmpp_parsers::Element;
+
+    fn form() -> DataForm {
+        DataForm::try_from(
+            Element::from_str(
+                "<x xmlns='jabber:x:data' type='form'>
+                    <title>Adding a User</title>
+                    <field type='hidden' var='FORM_TYPE'>
+                        <value>http://jabber.org/protocol/admin</value>
+                    </field>
+                    <field type='jid-single' label='Jabber ID' var='accountjid'>
+                        <required/>
+                    </field>
+                    <field type='list-multi' label='Groups' var='groups'>
+                        <value>friends</value>
+                        <option><value>friends</value></option>
+                        <option><value>family</value></option>
+                    </field>
+                </x>",
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_questions_are_asked_with_options_and_defaults() {
+        // Given
+        let form = form();
+
+        // When
+        let questions = questions(&form);
+
+        // Then
+        let prompts: Vec<String> = questions.iter().map(Question::prompt).collect();
+        assert_eq!(
+            prompts,
+            vec![
+                "Jabber ID:",
+                "Groups (friends, family) [friends] (optional):"
+            ]
+        );
+        assert_eq!(questions[1].values(""), Ok(vec![String::from("friends")]));
+        assert_eq!(
+            questions[1].values("family, friends"),
+            Ok(vec![String::from("family"), String::from("friends")])
+        );
+        assert!(questions[1].values("enemies").is_err());
+    }
+
+    #[test]
+    fn test_submission_keeps_hidden_fields_and_answers() {
+        // Given
+        let form = form();
+        let mut answers = HashMap::new();
+        answers.insert(
+            String::from("accountjid"),
+            vec![String::from("juliet@example.org")],
+        );
+
+        // When
+        let submitted = submission(&form, &answers);
+
+        // Then
+        assert_eq!(submitted.type_, DataFormType::Submit);
+        assert_eq!(
+            submitted.form_type.as_deref(),
+            Some("http://jabber.org/protocol/admin")
+        );
+        assert_eq!(submitted.fields.len(), 1);
+        assert_eq!(submitted.fields[0].var, "accountjid");
+        assert_eq!(submitted.fields[0].values, vec!["juliet@example.org"]);
+    }
+}
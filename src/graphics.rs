@@ -0,0 +1,435 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Terminal graphics.
+//!
+//! Images are rendered with the best protocol supported by the terminal: kitty graphics, iTerm2
+//! inline images or sixel. Other terminals get unicode half blocks art. Whatever the protocol,
+//! an image rendered for a buffer takes one column per pixel and one line per pair of pixel rows
+//! so that layout doesn't depend on the terminal.
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+/// Approximate size of a terminal cell in pixels, used to scale sixel images
+const CELL_WIDTH: usize = 8;
+const CELL_HEIGHT: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Kitty,
+    Iterm2,
+    Sixel,
+    Blocks,
+}
+
+impl Protocol {
+    /// Guess the graphics protocol supported by the terminal from its environment
+    pub fn detect() -> Self {
+        let var = |name| env::var(name).unwrap_or_default();
+        Self::from_env(
+            &var("TERM"),
+            &var("TERM_PROGRAM"),
+            !var("KITTY_WINDOW_ID").is_empty(),
+        )
+    }
+
+    fn from_env(term: &str, term_program: &str, kitty: bool) -> Self {
+        if kitty || term == "xterm-kitty" || term == "xterm-ghostty" {
+            Protocol::Kitty
+        } else if term_program == "iTerm.app" || term_program == "WezTerm" {
+            Protocol::Iterm2
+        } else if term.contains("sixel") || term == "foot" || term.starts_with("mlterm") {
+            Protocol::Sixel
+        } else {
+            Protocol::Blocks
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kitty" => Ok(Protocol::Kitty),
+            "iterm2" => Ok(Protocol::Iterm2),
+            "sixel" => Ok(Protocol::Sixel),
+            "blocks" => Ok(Protocol::Blocks),
+            _ => Err(format!("Unknown graphics protocol {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Kitty => write!(f, "kitty"),
+            Protocol::Iterm2 => write!(f, "iterm2"),
+            Protocol::Sixel => write!(f, "sixel"),
+            Protocol::Blocks => write!(f, "blocks"),
+        }
+    }
+}
+
+pub type Rgb = (u8, u8, u8);
+
+/// Render an image file as terminal lines when the terminal decodes it itself: kitty reads PNG
+/// and iTerm2 any common format. The image is scaled to the given number of lines.
+pub fn render_encoded(
+    data: &[u8],
+    mime: &str,
+    protocol: Protocol,
+    lines: usize,
+) -> Option<Vec<String>> {
+    let image = match (protocol, mime) {
+        (Protocol::Kitty, "image/png") => kitty(&format!("f=100,r={}", lines), data),
+        (Protocol::Iterm2, _) => format!(
+            "\x1b]1337;File=inline=1;size={};height={}:{}\x07",
+            data.len(),
+            lines,
+            base64::encode(data)
+        ),
+        _ => return None,
+    };
+
+    let mut rendered = vec![String::new(); lines];
+    if let Some(first) = rendered.first_mut() {
+        *first = image;
+    }
+    Some(rendered)
+}
+
+/// Render an image file in the given number of columns of a single line of text, when the
+/// terminal decodes it itself. The image is followed by blanks taking its room in the text.
+pub fn render_inline(
+    data: &[u8],
+    mime: &str,
+    protocol: Protocol,
+    columns: usize,
+) -> Option<String> {
+    let image = match (protocol, mime) {
+        (Protocol::Kitty, "image/png") => kitty(&format!("f=100,c={},r=1", columns), data),
+        // iTerm2 moves the cursor after the image, it's moved back before the blanks
+        (Protocol::Iterm2, _) => format!(
+            "\x1b]1337;File=inline=1;size={};width={};height=1:{}\x07\x1b[{}D",
+            data.len(),
+            columns,
+            base64::encode(data),
+            columns
+        ),
+        _ => return None,
+    };
+
+    Some(format!("{}{}", image, " ".repeat(columns)))
+}
+
+/// Kitty graphics command transmitting and displaying data, sent in chunks
+fn kitty(control: &str, data: &[u8]) -> String {
+    let data = base64::encode(data);
+    let chunks = data.as_bytes().chunks(4096).collect::<Vec<&[u8]>>();
+    let mut rendered = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = if i + 1 < chunks.len() { 1 } else { 0 };
+        let control = match i {
+            0 => format!("a=T,{},C=1,q=2,m={}", control, more),
+            _ => format!("m={}", more),
+        };
+        rendered.push_str(&format!(
+            "\x1b_G{};{}\x1b\\",
+            control,
+            String::from_utf8_lossy(chunk)
+        ));
+    }
+    rendered
+}
+
+/// RGB bitmap
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgb>,
+}
+
+impl Image {
+    pub fn from_fn<F>(width: usize, height: usize, pixel: F) -> Self
+    where
+        F: Fn(usize, usize) -> Rgb,
+    {
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push(pixel(x, y));
+            }
+        }
+
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> Rgb {
+        self.pixels[y * self.width + x]
+    }
+
+    /// Number of terminal lines taken by the image
+    pub fn lines(&self) -> usize {
+        self.height / 2 + self.height % 2
+    }
+
+    /// Render the image as terminal lines. Image protocols draw the whole image from the first
+    /// line, following lines are left blank to reserve the space.
+    pub fn render(&self, protocol: Protocol) -> Vec<String> {
+        let image = match protocol {
+            Protocol::Blocks => return self.blocks(),
+            Protocol::Kitty => self.kitty(),
+            Protocol::Iterm2 => self.iterm2(),
+            Protocol::Sixel => self.sixel(),
+        };
+
+        let mut lines = vec![String::new(); self.lines()];
+        if let Some(first) = lines.first_mut() {
+            *first = image;
+        }
+        lines
+    }
+
+    /// Upper half blocks, foreground is the top pixel and background the bottom one
+    fn blocks(&self) -> Vec<String> {
+        (0..self.lines())
+            .map(|line| {
+                let mut rendered = String::new();
+                for x in 0..self.width {
+                    let (r, g, b) = self.pixel(x, line * 2);
+                    rendered.push_str(&format!("\x1b[38;2;{};{};{}m", r, g, b));
+                    match line * 2 + 1 < self.height {
+                        true => {
+                            let (r, g, b) = self.pixel(x, line * 2 + 1);
+                            rendered.push_str(&format!("\x1b[48;2;{};{};{}m", r, g, b));
+                        }
+                        false => rendered.push_str("\x1b[49m"),
+                    }
+                    rendered.push('▀');
+                }
+                rendered.push_str("\x1b[0m");
+                rendered
+            })
+            .collect()
+    }
+
+    fn rgb_bytes(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|(r, g, b)| vec![*r, *g, *b])
+            .collect()
+    }
+
+    /// Kitty graphics protocol, raw RGB data
+    fn kitty(&self) -> String {
+        let control = format!(
+            "f=24,s={},v={},c={},r={}",
+            self.width,
+            self.height,
+            self.width,
+            self.lines()
+        );
+        kitty(&control, &self.rgb_bytes())
+    }
+
+    /// iTerm2 inline image, sent as a BMP file
+    fn iterm2(&self) -> String {
+        let bmp = self.bmp();
+        format!(
+            "\x1b]1337;File=inline=1;size={};width={};height={};preserveAspectRatio=0:{}\x07",
+            bmp.len(),
+            self.width,
+            self.lines(),
+            base64::encode(&bmp)
+        )
+    }
+
+    fn bmp(&self) -> Vec<u8> {
+        // Rows are padded to 4 bytes
+        let row_size = (self.width * 3 + 3) & !3;
+        let data_size = row_size * self.height;
+        let mut bmp = Vec::with_capacity(54 + data_size);
+
+        // File header
+        bmp.extend_from_slice(b"BM");
+        bmp.extend_from_slice(&((54 + data_size) as u32).to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        bmp.extend_from_slice(&54u32.to_le_bytes());
+
+        // Info header, negative height for top-down rows
+        bmp.extend_from_slice(&40u32.to_le_bytes());
+        bmp.extend_from_slice(&(self.width as i32).to_le_bytes());
+        bmp.extend_from_slice(&(-(self.height as i32)).to_le_bytes());
+        bmp.extend_from_slice(&1u16.to_le_bytes());
+        bmp.extend_from_slice(&24u16.to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        bmp.extend_from_slice(&(data_size as u32).to_le_bytes());
+        bmp.extend_from_slice(&2835i32.to_le_bytes());
+        bmp.extend_from_slice(&2835i32.to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (r, g, b) = self.pixel(x, y);
+                bmp.extend_from_slice(&[b, g, r]);
+            }
+            bmp.resize(bmp.len() + row_size - self.width * 3, 0);
+        }
+
+        bmp
+    }
+
+    /// Sixel image using a 6×6×6 color cube, scaled to the cells it is supposed to take
+    fn sixel(&self) -> String {
+        let level = |value: u8| (value as usize * 5 + 127) / 255;
+        let width = self.width * CELL_WIDTH;
+        let height = self.lines() * CELL_HEIGHT;
+        let color = |x: usize, y: usize| {
+            let (x, y) = (x / CELL_WIDTH, y * self.height / height);
+            let (r, g, b) = self.pixel(x, y.min(self.height - 1));
+            level(r) * 36 + level(g) * 6 + level(b)
+        };
+
+        let mut rendered = String::from("\x1bPq");
+        rendered.push_str(&format!("\"1;1;{};{}", width, height));
+        for i in 0..216 {
+            let (r, g, b) = (i / 36, i / 6 % 6, i % 6);
+            rendered.push_str(&format!("#{};2;{};{};{}", i, r * 20, g * 20, b * 20));
+        }
+
+        for band in (0..height).step_by(6) {
+            let mut colors = Vec::new();
+            for x in 0..width {
+                for bit in 0..6 {
+                    let y = band + bit;
+                    if y < height && !colors.contains(&color(x, y)) {
+                        colors.push(color(x, y));
+                    }
+                }
+            }
+
+            for (i, current) in colors.iter().enumerate() {
+                if i > 0 {
+                    rendered.push('$');
+                }
+                rendered.push_str(&format!("#{}", current));
+                for x in 0..width {
+                    let mut sixel = 0;
+                    for bit in 0..6 {
+                        let y = band + bit;
+                        if y < height && color(x, y) == *current {
+                            sixel |= 1 << bit;
+                        }
+                    }
+                    rendered.push((0x3f + sixel) as u8 as char);
+                }
+            }
+            rendered.push('-');
+        }
+
+        rendered.push_str("\x1b\\");
+        rendered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminus::term_string_visible_len;
+
+    fn checkerboard() -> Image {
+        Image::from_fn(4, 3, |x, y| match (x + y) % 2 {
+            0 => (0, 0, 0),
+            _ => (255, 255, 255),
+        })
+    }
+
+    #[test]
+    fn test_detect_protocol() {
+        assert_eq!(
+            Protocol::from_env("xterm-kitty", "", false),
+            Protocol::Kitty
+        );
+        assert_eq!(
+            Protocol::from_env("xterm-256color", "", true),
+            Protocol::Kitty
+        );
+        assert_eq!(
+            Protocol::from_env("xterm-256color", "iTerm.app", false),
+            Protocol::Iterm2
+        );
+        assert_eq!(Protocol::from_env("foot", "", false), Protocol::Sixel);
+        assert_eq!(
+            Protocol::from_env("xterm-256color", "", false),
+            Protocol::Blocks
+        );
+    }
+
+    #[test]
+    fn test_render_takes_the_same_space_with_all_protocols() {
+        // Given
+        let image = checkerboard();
+
+        for protocol in &[
+            Protocol::Blocks,
+            Protocol::Kitty,
+            Protocol::Iterm2,
+            Protocol::Sixel,
+        ] {
+            // When
+            let lines = image.render(*protocol);
+
+            // Then
+            assert_eq!(lines.len(), 2);
+            let width = match protocol {
+                Protocol::Blocks => 4,
+                _ => 0,
+            };
+            assert_eq!(term_string_visible_len(&lines[0]), width);
+        }
+    }
+
+    #[test]
+    fn test_render_inline_takes_the_given_columns() {
+        // Given
+        let png = base64::decode("iVBORw0KGgo=").unwrap();
+
+        for protocol in &[Protocol::Kitty, Protocol::Iterm2] {
+            // When
+            let rendered = render_inline(&png, "image/png", *protocol, 2).unwrap();
+
+            // Then
+            assert_eq!(term_string_visible_len(&rendered), 2);
+        }
+        assert_eq!(render_inline(&png, "image/gif", Protocol::Kitty, 2), None);
+        assert_eq!(render_inline(&png, "image/png", Protocol::Blocks, 2), None);
+    }
+
+    #[test]
+    fn test_render_blocks() {
+        // Given
+        let image = Image::from_fn(1, 3, |_, y| (y as u8, 0, 0));
+
+        // When
+        let lines = image.render(Protocol::Blocks);
+
+        // Then
+        assert_eq!(
+            lines,
+            vec![
+                "\x1b[38;2;0;0;0m\x1b[48;2;1;0;0m▀\x1b[0m",
+                "\x1b[38;2;2;0;0m\x1b[49m▀\x1b[0m",
+            ]
+        );
+    }
+}
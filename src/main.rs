@@ -10,7 +10,6 @@ extern crate derive_error;
 extern crate dirs;
 extern crate flexi_logger;
 extern crate futures;
-extern crate rpassword;
 extern crate tokio;
 extern crate tokio_xmpp;
 extern crate xmpp_parsers;
@@ -25,15 +24,36 @@ mod core;
 mod message;
 #[macro_use]
 mod command;
+mod client;
 mod color;
 mod cursor;
+mod dns;
+mod form;
+mod graphics;
 mod i18n;
 mod mods;
+mod qrcode;
+mod quarantine;
+mod remote;
+mod sims;
+mod template;
+#[cfg(test)]
+mod testing;
 mod word;
+mod zlib;
 
 use crate::core::Aparte;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("remote") {
+        if let Err(err) = remote::send(&args[2..]) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let data_dir = dirs::data_dir().unwrap();
     let aparte_data = data_dir.join("aparte");
 
@@ -63,7 +83,33 @@ fn main() {
 
     info!("Starting aparté");
 
-    let mut aparte = Aparte::new(config);
+    let profile = args
+        .iter()
+        .position(|arg| arg == "--profile")
+        .and_then(|index| args.get(index + 1))
+        .cloned();
+    // The password of the first account connecting automatically can be read from an inherited
+    // file descriptor, only given here so that the configuration cannot make Aparté take another
+    let password_fd = args
+        .iter()
+        .position(|arg| arg == "--password-fd")
+        .and_then(|index| args.get(index + 1))
+        .cloned();
+    let password_fd = match password_fd.map(|fd| fd.parse::<i32>()) {
+        Some(Ok(fd)) => Some(fd),
+        Some(Err(err)) => {
+            eprintln!("Invalid password file descriptor: {}", err);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    let mut aparte = match Aparte::new(config, profile, password_fd) {
+        Ok(aparte) => aparte,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
     aparte.init().unwrap();
 
@@ -9,10 +9,18 @@ use std::hash;
 use uuid::Uuid;
 use xmpp_parsers::delay::Delay;
 use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType as XmppParsersMessageType};
+use xmpp_parsers::stanza_id::StanzaId;
 use xmpp_parsers::{BareJid, Jid};
 
 use crate::account::Account;
+use crate::conversation::Conversation;
 use crate::i18n;
+use crate::sims::MediaSharing;
+
+pub const SPOILER: &str = "urn:xmpp:spoiler:0";
+pub const OOB: &str = "jabber:x:oob";
+/// Namespaces of OMEMO (XEP-0384), legacy and current ones, which Aparté can't decrypt
+const OMEMO: [&str; 2] = ["eu.siacs.conversations.axolotl", "urn:xmpp:omemo:2"];
 
 #[derive(Debug, Clone)]
 pub struct XmppMessageVersion {
@@ -51,6 +59,63 @@ impl XmppMessageVersion {
     }
 }
 
+/// Link to a file shared out of band (XEP-0066)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Oob {
+    pub url: String,
+    pub desc: Option<String>,
+}
+
+impl Oob {
+    pub fn from_element(element: &xmpp_parsers::Element) -> Option<Self> {
+        if !element.is("x", OOB) {
+            return None;
+        }
+        let url = element.get_child("url", OOB)?.text().trim().to_string();
+        if url.is_empty() {
+            return None;
+        }
+        let desc = element
+            .get_child("desc", OOB)
+            .map(|desc| desc.text().trim().to_string())
+            .filter(|desc| !desc.is_empty());
+        Some(Self { url, desc })
+    }
+
+    pub fn to_element(&self) -> xmpp_parsers::Element {
+        let mut element = xmpp_parsers::Element::builder("x", OOB)
+            .append(
+                xmpp_parsers::Element::builder("url", OOB)
+                    .append(self.url.as_str())
+                    .build(),
+            )
+            .build();
+        if let Some(desc) = &self.desc {
+            element.append_child(
+                xmpp_parsers::Element::builder("desc", OOB)
+                    .append(desc.as_str())
+                    .build(),
+            );
+        }
+        element
+    }
+
+    /// Description of the file, or its name taken from the url
+    pub fn summary(&self) -> String {
+        match &self.desc {
+            Some(desc) => desc.clone(),
+            None => self
+                .url
+                .split(['?', '#'])
+                .next()
+                .and_then(|path| path.trim_end_matches('/').rsplit('/').next())
+                .filter(|name| !name.is_empty())
+                .unwrap_or(&self.url)
+                .to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct VersionedXmppMessage {
     pub id: String,
@@ -61,6 +126,17 @@ pub struct VersionedXmppMessage {
     pub history: Vec<XmppMessageVersion>,
     pub type_: XmppMessageType,
     pub direction: Direction,
+    /// Hint of a spoiler (XEP-0382), the body is hidden until revealed
+    pub spoiler: Option<String>,
+    /// Link to a shared file (XEP-0066), for clients to display it as an attachment
+    pub oob: Option<Oob>,
+    /// Files shared with their metadata (XEP-0385)
+    pub media: Vec<MediaSharing>,
+    /// Id given by the room to a channel message (XEP-0359), used to refer to it
+    pub stanza_id: Option<String>,
+    /// Delivered late with its original timestamp (XEP-0203), replayed from channel history or
+    /// offline storage
+    pub delayed: bool,
 }
 
 impl VersionedXmppMessage {
@@ -78,6 +154,14 @@ impl VersionedXmppMessage {
         &first.timestamp
     }
 
+    /// Contact or channel the message was exchanged with
+    pub fn conversation(&self) -> &BareJid {
+        match self.direction {
+            Direction::Incoming => &self.from,
+            Direction::Outgoing => &self.to,
+        }
+    }
+
     pub fn add_version_from_xmpp(&mut self, message: &XmppParsersMessage) {
         let id = message
             .id
@@ -98,6 +182,11 @@ impl VersionedXmppMessage {
             .map(|delay| delay.stamp.0)
             .unwrap_or(LocalTz::now().into());
 
+        // Corrections can be received twice, from carbons or channel reflections
+        if self.history.iter().any(|version| version.id == id) {
+            return;
+        }
+
         self.history.push(XmppMessageVersion {
             id,
             timestamp,
@@ -105,9 +194,39 @@ impl VersionedXmppMessage {
         });
     }
 
+    /// Add a corrected version of an outgoing message (XEP-0308)
+    pub fn add_version(&mut self, bodies: HashMap<String, String>) {
+        self.history.push(XmppMessageVersion {
+            id: Uuid::new_v4().to_string(),
+            timestamp: LocalTz::now().into(),
+            bodies,
+        });
+    }
+
+    /// Id of the stanza carrying the last version
+    pub fn get_last_id(&self) -> &str {
+        let last = self.history.iter().max().unwrap();
+        &last.id
+    }
+
     pub fn has_multiple_version(&self) -> bool {
         self.history.len() > 1
     }
+
+    /// Replace the content of the message and of all its versions by a notice
+    pub fn redact(&mut self, notice: String) {
+        let first = self.history.iter().min().unwrap();
+        let mut bodies = HashMap::new();
+        bodies.insert("".to_string(), notice);
+        self.history = vec![XmppMessageVersion {
+            id: first.id.clone(),
+            timestamp: first.timestamp,
+            bodies,
+        }];
+        self.spoiler = None;
+        self.oob = None;
+        self.media = Vec::new();
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -151,20 +270,48 @@ impl Message {
                 .iter()
                 .map(|(lang, body)| (lang.clone(), body.0.clone()))
                 .collect();
-            let delay = match delay {
-                Some(delay) => Some(delay.clone()),
-                None => message
-                    .payloads
-                    .iter()
-                    .filter_map(|payload| Delay::try_from(payload.clone()).ok())
-                    .nth(0),
-            };
+            // A delay given along the message comes from forwarding it (archives, carbons),
+            // only one carried by the message itself means it was delivered late
+            let own_delay = message
+                .payloads
+                .iter()
+                .filter_map(|payload| Delay::try_from(payload.clone()).ok())
+                .nth(0);
+            let delayed = delay.is_none() && own_delay.is_some();
+            let delay = delay.clone().or(own_delay);
             let to = match message.to.clone() {
                 Some(to) => to,
                 None => account.clone().into(),
             };
+            let spoiler = message
+                .payloads
+                .iter()
+                .find(|payload| payload.is("spoiler", SPOILER))
+                .map(|spoiler| spoiler.text());
+            let media = message
+                .payloads
+                .iter()
+                .filter_map(MediaSharing::from_reference)
+                .collect();
+            let oob = message.payloads.iter().find_map(Oob::from_element);
+            // Only the id given by the room can be trusted for channel messages
+            let room = Jid::Bare(BareJid::from(from.clone()));
+            let stanza_id = match message.type_ {
+                XmppParsersMessageType::Groupchat => message
+                    .payloads
+                    .iter()
+                    .filter_map(|payload| StanzaId::try_from(payload.clone()).ok())
+                    .find(|stanza_id| stanza_id.by == room)
+                    .map(|stanza_id| stanza_id.id),
+                _ => None,
+            };
+            // The body of OMEMO messages is a fallback asking to use a client supporting it
+            let omemo = message
+                .payloads
+                .iter()
+                .any(|payload| OMEMO.iter().any(|ns| payload.is("encrypted", *ns)));
 
-            match message.type_ {
+            let message = match message.type_ {
                 XmppParsersMessageType::Chat => {
                     if from.clone().node() == account.node
                         && from.clone().domain() == account.domain
@@ -200,12 +347,96 @@ impl Message {
                     &bodies,
                 )),
                 _ => Err(()),
-            }
+            };
+            message.map(|message| {
+                let message = message
+                    .with_spoiler(spoiler)
+                    .with_stanza_id(stanza_id)
+                    .with_delayed(delayed)
+                    .with_media(media)
+                    .with_oob(oob);
+                match message {
+                    Message::Xmpp(mut message) if omemo => {
+                        message.redact(
+                            "🔒 Encrypted with OMEMO, which Aparté can't decrypt".to_string(),
+                        );
+                        Message::Xmpp(message)
+                    }
+                    message => message,
+                }
+            })
         } else {
             Err(())
         }
     }
 
+    pub fn with_spoiler(self, spoiler: Option<String>) -> Self {
+        match self {
+            Message::Xmpp(message) => Message::Xmpp(VersionedXmppMessage { spoiler, ..message }),
+            Message::Log(message) => Message::Log(message),
+        }
+    }
+
+    pub fn with_oob(self, oob: Option<Oob>) -> Self {
+        match self {
+            Message::Xmpp(message) => Message::Xmpp(VersionedXmppMessage { oob, ..message }),
+            Message::Log(message) => Message::Log(message),
+        }
+    }
+
+    pub fn with_media(self, media: Vec<MediaSharing>) -> Self {
+        match self {
+            Message::Xmpp(message) => Message::Xmpp(VersionedXmppMessage { media, ..message }),
+            Message::Log(message) => Message::Log(message),
+        }
+    }
+
+    pub fn with_delayed(self, delayed: bool) -> Self {
+        match self {
+            Message::Xmpp(message) => Message::Xmpp(VersionedXmppMessage { delayed, ..message }),
+            Message::Log(message) => Message::Log(message),
+        }
+    }
+
+    pub fn with_stanza_id(self, stanza_id: Option<String>) -> Self {
+        match self {
+            Message::Xmpp(message) => Message::Xmpp(VersionedXmppMessage {
+                stanza_id,
+                ..message
+            }),
+            Message::Log(message) => Message::Log(message),
+        }
+    }
+
+    /// Message sent by us in a conversation, a chat with the given jid if the conversation isn't
+    /// known
+    pub fn outgoing(
+        account: &Account,
+        jid: &BareJid,
+        conversation: Option<Conversation>,
+        body: String,
+    ) -> Self {
+        let id = Uuid::new_v4().to_string();
+        let timestamp = LocalTz::now().into();
+        let mut bodies = HashMap::new();
+        bodies.insert("".to_string(), body);
+
+        match conversation {
+            Some(Conversation::Channel(channel)) => {
+                let mut us = account.clone();
+                us.resource = channel.nick.clone();
+                let from: Jid = us.into();
+                let to: Jid = channel.jid.into();
+                Message::outgoing_channel(id, timestamp, &from, &to, &bodies)
+            }
+            _ => {
+                let from: Jid = account.clone().into();
+                let to: Jid = jid.clone().into();
+                Message::outgoing_chat(id, timestamp, &from, &to, &bodies)
+            }
+        }
+    }
+
     pub fn get_local_destination_from_xmpp<'a>(
         account: &Account,
         message: &'a XmppParsersMessage,
@@ -290,6 +521,11 @@ impl Message {
             history: vec![version],
             type_: XmppMessageType::Chat,
             direction: Direction::Incoming,
+            spoiler: None,
+            oob: None,
+            media: Vec::new(),
+            stanza_id: None,
+            delayed: false,
         })
     }
 
@@ -327,6 +563,11 @@ impl Message {
             history: vec![version],
             type_: XmppMessageType::Chat,
             direction: Direction::Outgoing,
+            spoiler: None,
+            oob: None,
+            media: Vec::new(),
+            stanza_id: None,
+            delayed: false,
         })
     }
 
@@ -364,6 +605,11 @@ impl Message {
             history: vec![version],
             type_: XmppMessageType::Channel,
             direction: Direction::Incoming,
+            spoiler: None,
+            oob: None,
+            media: Vec::new(),
+            stanza_id: None,
+            delayed: false,
         })
     }
 
@@ -401,13 +647,23 @@ impl Message {
             history: vec![version],
             type_: XmppMessageType::Channel,
             direction: Direction::Outgoing,
+            spoiler: None,
+            oob: None,
+            media: Vec::new(),
+            stanza_id: None,
+            delayed: false,
         })
     }
 
     pub fn log(msg: String) -> Self {
+        Self::log_at(msg, LocalTz::now().into())
+    }
+
+    /// Log line of something that happened at the given time
+    pub fn log_at(msg: String, timestamp: DateTime<FixedOffset>) -> Self {
         Message::Log(LogMessage {
             id: Uuid::new_v4().to_string(),
-            timestamp: LocalTz::now().into(),
+            timestamp,
             body: msg,
         })
     }
@@ -479,8 +735,35 @@ impl TryFrom<Message> for xmpp_parsers::Element {
                         let mut xmpp_message = xmpp_parsers::message::Message::new(Some(
                             Jid::Bare(message.to.clone()),
                         ));
-                        xmpp_message.id = Some(message.id.clone());
+                        xmpp_message.id = Some(message.get_last_id().to_string());
                         xmpp_message.type_ = xmpp_parsers::message::MessageType::Chat;
+                        xmpp_message.payloads.push(
+                            xmpp_parsers::stanza_id::OriginId {
+                                id: message.get_last_id().to_string(),
+                            }
+                            .into(),
+                        );
+                        if message.has_multiple_version() {
+                            xmpp_message.payloads.push(
+                                xmpp_parsers::message_correct::Replace {
+                                    id: message.id.clone(),
+                                }
+                                .into(),
+                            );
+                        }
+                        if let Some(hint) = &message.spoiler {
+                            xmpp_message.payloads.push(
+                                xmpp_parsers::Element::builder("spoiler", SPOILER)
+                                    .append(hint.as_str())
+                                    .build(),
+                            );
+                        }
+                        if let Some(oob) = &message.oob {
+                            xmpp_message.payloads.push(oob.to_element());
+                        }
+                        for media in &message.media {
+                            xmpp_message.payloads.push(media.to_reference());
+                        }
                         xmpp_message.bodies = message
                             .get_last_bodies()
                             .map(|(lang, body)| {
@@ -493,8 +776,35 @@ impl TryFrom<Message> for xmpp_parsers::Element {
                         let mut xmpp_message = xmpp_parsers::message::Message::new(Some(
                             Jid::Bare(message.to.clone()),
                         ));
-                        xmpp_message.id = Some(message.id.clone());
+                        xmpp_message.id = Some(message.get_last_id().to_string());
                         xmpp_message.type_ = xmpp_parsers::message::MessageType::Groupchat;
+                        xmpp_message.payloads.push(
+                            xmpp_parsers::stanza_id::OriginId {
+                                id: message.get_last_id().to_string(),
+                            }
+                            .into(),
+                        );
+                        if message.has_multiple_version() {
+                            xmpp_message.payloads.push(
+                                xmpp_parsers::message_correct::Replace {
+                                    id: message.id.clone(),
+                                }
+                                .into(),
+                            );
+                        }
+                        if let Some(hint) = &message.spoiler {
+                            xmpp_message.payloads.push(
+                                xmpp_parsers::Element::builder("spoiler", SPOILER)
+                                    .append(hint.as_str())
+                                    .build(),
+                            );
+                        }
+                        if let Some(oob) = &message.oob {
+                            xmpp_message.payloads.push(oob.to_element());
+                        }
+                        for media in &message.media {
+                            xmpp_message.payloads.push(media.to_reference());
+                        }
                         xmpp_message.bodies = message
                             .get_last_bodies()
                             .map(|(lang, body)| {
@@ -0,0 +1,447 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! When configured accounts are connected, to keep work and personal life apart without editing
+//! the configuration.
+//!
+//! Accounts disabled with /account disable are disconnected and not connected again until
+//! enabled. Accounts with a schedule are disconnected outside of it and connected again once it
+//! allows them, provided they are connected automatically.
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Weekday};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time;
+use xmpp_parsers::{BareJid, Jid};
+
+use crate::account::{Account, ConnectionInfo};
+use crate::command::{Command, CommandParser};
+use crate::config::Config;
+use crate::core::{Aparte, Event, ModTrait};
+
+/// Interval between two checks of the schedules
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+command_def!(account,
+r#"/account list|enable|disable"#,
+{
+    action: Command = {
+        children: {
+            "list": account_list,
+            "enable": account_enable,
+            "disable": account_disable,
+        }
+    },
+});
+
+command_def!(
+    account_list,
+    r#"/account list
+
+Description:
+    List the configured accounts, whether they are connected and their
+    schedule."#,
+    {},
+    |aparte, _command| {
+        let lines = {
+            let accounts = aparte.get_mod::<AccountsMod>();
+            accounts.list(aparte)
+        };
+        match lines.is_empty() {
+            true => aparte.log("No account configured".to_string()),
+            false => aparte.log(format!("Accounts:\n{}", lines.join("\n"))),
+        }
+        Ok(())
+    }
+);
+
+command_def!(
+    account_enable,
+    r#"/account enable <name>
+
+    name          Configured account to enable
+
+Description:
+    Allow an account disabled with /account disable to be connected again, it
+    is connected right away unless its schedule keeps it offline.
+
+Example:
+    /account enable work"#,
+    {
+        name: String = {
+            completion: (|aparte, _command| {
+                let accounts = aparte.get_mod::<AccountsMod>();
+                accounts.disabled.iter().cloned().collect()
+            })
+        },
+    },
+    |aparte, _command| {
+        let info = aparte
+            .config
+            .accounts
+            .get(&name)
+            .cloned()
+            .ok_or(format!("Unknown account {}", name))?;
+        let connectable = {
+            let mut accounts = aparte.get_mod_mut::<AccountsMod>();
+            if !accounts.disabled.remove(&name) {
+                return Err(format!("Account {} isn't disabled", name));
+            }
+            accounts.connectable(&name, &info)
+        };
+        match connectable {
+            true => {
+                aparte.log(format!("Account {} enabled", name));
+                aparte.connect_configured(info);
+            }
+            false => aparte.log(format!(
+                "Account {} enabled, it will be connected once its schedule allows it",
+                name
+            )),
+        }
+        Ok(())
+    }
+);
+
+command_def!(
+    account_disable,
+    r#"/account disable <name>
+
+    name          Configured account to disable
+
+Description:
+    Disconnect an account and keep it disconnected, even by its schedule,
+    until /account enable. Nothing is saved: accounts are enabled again on
+    restart.
+
+Example:
+    /account disable work"#,
+    {
+        name: String = {
+            completion: (|aparte, _command| {
+                aparte.config.accounts.keys().cloned().collect()
+            })
+        },
+    },
+    |aparte, _command| {
+        let info = aparte
+            .config
+            .accounts
+            .get(&name)
+            .cloned()
+            .ok_or(format!("Unknown account {}", name))?;
+        {
+            let mut accounts = aparte.get_mod_mut::<AccountsMod>();
+            if !accounts.disabled.insert(name.clone()) {
+                return Err(format!("Account {} is already disabled", name));
+            }
+            accounts.asleep.remove(&name);
+        }
+        if let Some(account) = connected(aparte, &info) {
+            aparte.disconnect(&account);
+        }
+        aparte.log(format!(
+            "Account {} disabled, enable it again with /account enable {}",
+            name, name
+        ));
+        Ok(())
+    }
+);
+
+/// Connection of a configured account, if connected
+fn connected(aparte: &Aparte, info: &ConnectionInfo) -> Option<Account> {
+    let jid = Jid::from_str(&info.jid).ok().map(BareJid::from)?;
+    aparte.accounts().into_iter().find(|account| {
+        let bare: BareJid = account.clone().into();
+        bare == jid
+    })
+}
+
+/// Whether a time is within a schedule made of entries like "mon-fri 09:00-18:00", "sat,sun" or
+/// "22:00-07:00", an empty one allowing any time
+fn in_schedule(schedule: &[String], now: &NaiveDateTime) -> Result<bool, String> {
+    if schedule.is_empty() {
+        return Ok(true);
+    }
+    let mut allowed = false;
+    for entry in schedule {
+        allowed |=
+            in_entry(entry, now).map_err(|err| format!("Invalid schedule {}: {}", entry, err))?;
+    }
+    Ok(allowed)
+}
+
+fn in_entry(entry: &str, now: &NaiveDateTime) -> Result<bool, String> {
+    let mut days = None;
+    let mut hours = None;
+    for part in entry.split_whitespace() {
+        match part.contains(':') {
+            true => hours = Some(in_hours(part, &now.time())?),
+            false => days = Some(in_days(part, now.weekday())?),
+        }
+    }
+    match (days, hours) {
+        (None, None) => Err("no day nor hours".to_string()),
+        (days, hours) => Ok(days.unwrap_or(true) && hours.unwrap_or(true)),
+    }
+}
+
+/// Whether a day is in a list like "mon-fri,sun", ranges wrapping around the week
+fn in_days(days: &str, day: Weekday) -> Result<bool, String> {
+    let parse = |name: &str| Weekday::from_str(name).map_err(|_| format!("unknown day {}", name));
+    let day = day.num_days_from_monday();
+    let mut allowed = false;
+    for range in days.split(',') {
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (parse(first)?, parse(last)?),
+            None => (parse(range)?, parse(range)?),
+        };
+        let (first, last) = (first.num_days_from_monday(), last.num_days_from_monday());
+        allowed |= match first <= last {
+            true => first <= day && day <= last,
+            false => day >= first || day <= last,
+        };
+    }
+    Ok(allowed)
+}
+
+/// Whether a time is in hours like "09:00-18:00", ranges ending before they start spanning
+/// midnight
+fn in_hours(hours: &str, time: &NaiveTime) -> Result<bool, String> {
+    let parse = |time: &str| {
+        NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| format!("invalid time {}", time))
+    };
+    let (start, end) = hours
+        .split_once('-')
+        .ok_or(format!("invalid hours {}", hours))?;
+    let (start, end) = (parse(start)?, parse(end)?);
+    Ok(match start <= end {
+        true => &start <= time && time < &end,
+        false => time >= &start || time < &end,
+    })
+}
+
+pub struct AccountsMod {
+    /// Accounts disabled with /account disable, by name
+    disabled: HashSet<String>,
+    /// Accounts kept offline by their schedule, to connect once it allows them
+    asleep: HashSet<String>,
+    /// Whether the next check of the schedules is scheduled
+    ticking: bool,
+}
+
+impl AccountsMod {
+    pub fn new() -> Self {
+        Self {
+            disabled: HashSet::new(),
+            asleep: HashSet::new(),
+            ticking: false,
+        }
+    }
+
+    /// Whether an account can be connected now. Accounts kept offline by their schedule are
+    /// connected once it allows them.
+    pub fn connectable(&mut self, name: &str, info: &ConnectionInfo) -> bool {
+        if self.disabled.contains(name) {
+            return false;
+        }
+        // Invalid schedules are reported when loaded, they don't keep accounts offline
+        let now = Local::now().naive_local();
+        match in_schedule(&info.schedule, &now).unwrap_or(true) {
+            true => true,
+            false => {
+                self.asleep.insert(name.to_string());
+                false
+            }
+        }
+    }
+
+    /// Name of a disabled account, given by name or jid
+    pub fn disabled(&self, config: &Config, account: &str) -> Option<String> {
+        let jid = Jid::from_str(account).ok().map(BareJid::from);
+        config
+            .accounts
+            .iter()
+            .filter(|(name, info)| {
+                name.as_str() == account
+                    || (jid.is_some() && Jid::from_str(&info.jid).ok().map(BareJid::from) == jid)
+            })
+            .map(|(name, _)| name.clone())
+            .find(|name| self.disabled.contains(name))
+    }
+
+    fn list(&self, aparte: &Aparte) -> Vec<String> {
+        let mut names: Vec<&String> = aparte.config.accounts.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| {
+                let info = &aparte.config.accounts[name];
+                let state = if self.disabled.contains(name) {
+                    "disabled"
+                } else if connected(aparte, info).is_some() {
+                    "connected"
+                } else if self.asleep.contains(name) {
+                    "offline until its schedule allows it"
+                } else {
+                    "disconnected"
+                };
+                match info.schedule.is_empty() {
+                    true => format!("  {} ({}): {}", name, info.jid, state),
+                    false => format!(
+                        "  {} ({}): {}, connected {}",
+                        name,
+                        info.jid,
+                        state,
+                        info.schedule.join(", ")
+                    ),
+                }
+            })
+            .collect()
+    }
+
+    fn schedule_check(&mut self, aparte: &mut Aparte) {
+        self.ticking = aparte
+            .config
+            .accounts
+            .values()
+            .any(|info| !info.schedule.is_empty());
+        if self.ticking {
+            aparte.spawn(async {
+                time::sleep(CHECK_INTERVAL).await;
+                Event::AccountSchedule
+            });
+        }
+    }
+
+    /// Report invalid schedules, once when the configuration is loaded
+    fn check_schedules(&self, aparte: &mut Aparte) {
+        let now = Local::now().naive_local();
+        let errors = aparte
+            .config
+            .accounts
+            .values()
+            .filter_map(|info| in_schedule(&info.schedule, &now).err())
+            .collect::<Vec<_>>();
+        for err in errors {
+            aparte.log(err);
+        }
+    }
+
+    /// Connect the accounts whose schedule now allows them and disconnect the ones it doesn't
+    fn check(&mut self, aparte: &mut Aparte, now: &NaiveDateTime) {
+        for (name, info) in aparte.config.accounts.clone() {
+            if self.disabled.contains(&name) {
+                continue;
+            }
+            let allowed = in_schedule(&info.schedule, now).unwrap_or(true);
+            match (allowed, connected(aparte, &info)) {
+                (true, None) if self.asleep.remove(&name) => {
+                    aparte.log(format!("Connecting {}, following its schedule", name));
+                    aparte.connect_configured(info);
+                }
+                (false, Some(account)) => {
+                    aparte.log(format!("Disconnecting {}, following its schedule", name));
+                    aparte.disconnect(&account);
+                    if info.autoconnect {
+                        self.asleep.insert(name);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl ModTrait for AccountsMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(account::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Start | Event::Profile(_) => {
+                self.check_schedules(aparte);
+                if !self.ticking {
+                    self.schedule_check(aparte);
+                }
+            }
+            Event::AccountSchedule => {
+                self.check(aparte, &Local::now().naive_local());
+                self.schedule_check(aparte);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for AccountsMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Account schedules")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+    use chrono::NaiveDate;
+
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        // 2021-03-15 is a monday
+        NaiveDate::from_ymd_opt(2021, 3, day)
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    #[test]
+    fn test_in_schedule() {
+        // Given
+        let office = vec![String::from("mon-fri 09:00-18:00")];
+        let nights = vec![String::from("sat,sun"), String::from("22:00-07:00")];
+
+        // Then
+        assert_eq!(in_schedule(&[], &at(15, "03:00")), Ok(true));
+        assert_eq!(in_schedule(&office, &at(15, "09:00")), Ok(true));
+        assert_eq!(in_schedule(&office, &at(19, "17:59")), Ok(true));
+        assert_eq!(in_schedule(&office, &at(19, "18:00")), Ok(false));
+        assert_eq!(in_schedule(&office, &at(20, "10:00")), Ok(false));
+        assert_eq!(in_schedule(&nights, &at(20, "12:00")), Ok(true));
+        assert_eq!(in_schedule(&nights, &at(16, "23:30")), Ok(true));
+        assert_eq!(in_schedule(&nights, &at(17, "06:59")), Ok(true));
+        assert_eq!(in_schedule(&nights, &at(17, "12:00")), Ok(false));
+        assert!(in_schedule(&[String::from("someday")], &at(15, "12:00")).is_err());
+    }
+
+    #[test]
+    fn test_disabled_account_is_disconnected_and_not_connected_again() {
+        testing::run(async {
+            // Given
+            let config: Config = toml::from_str(
+                r#"
+                [accounts.work]
+                jid = "romeo@example.org/aparte"
+                autoconnect = true
+                "#,
+            )
+            .unwrap();
+            let mut harness = Harness::with_config(config);
+            harness.connect().await;
+
+            // When
+            harness.input("console", "/account disable work").await;
+
+            // Then
+            assert!(harness.aparte.accounts().is_empty());
+            harness.input("console", "/connect work secret").await;
+            assert!(harness
+                .screen()
+                .contains("Account work is disabled, enable it with /account enable work"));
+            harness.aparte.autoconnect();
+            harness.settle().await;
+            assert!(harness.aparte.accounts().is_empty());
+        });
+    }
+}
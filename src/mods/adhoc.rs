@@ -0,0 +1,597 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Run the commands offered by an entity (XEP-0050), typically the administration commands of a
+//! server.
+//!
+//! The fields of each form sent back by a command are asked one after the other in the input,
+//! like registration forms, then the form is submitted with the action offered by the entity until
+//! the command completes. A single command runs at a time.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::data_forms::{DataForm, DataFormType};
+use xmpp_parsers::disco::{DiscoItemsQuery, DiscoItemsResult};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::stanza_error::StanzaError;
+use xmpp_parsers::{ns, Element, Jid};
+use zeroize::Zeroize;
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait, Password};
+use crate::form::{self, Question};
+
+const COMMANDS: &str = "http://jabber.org/protocol/commands";
+
+command_def!(adhoc,
+r#"/adhoc <jid> [<node>] [<answer>]
+
+    jid           Entity offering the commands, usually a server
+    node          Command to run, as listed by /adhoc <jid>
+    answer        Answer to the field asked, typed in the input when asked
+
+Description:
+    Run ad-hoc commands (XEP-0050). Without node, list the commands offered
+    by the entity. With one, run it: the fields of each form sent by the
+    command are asked one after the other in the input, passwords without
+    being shown. An empty answer keeps the default value of a field, and
+    cancels the command if the field is required and has none.
+
+Examples:
+    /adhoc example.org
+    /adhoc example.org http://jabber.org/protocol/admin#add-user"#,
+{
+    jid: Jid,
+    node: Option<String>,
+    answer: Option<Password<String>>
+},
+|aparte, command| {
+    let account = command.account.clone().ok_or("No connection found".to_string())?;
+    match (node, answer) {
+        (None, _) => {
+            let request = {
+                let mut adhoc = aparte.get_mod_mut::<AdhocMod>();
+                adhoc.list(&jid)
+            };
+            aparte.send(&account, request);
+        }
+        (Some(node), None) => {
+            let request = {
+                let mut adhoc = aparte.get_mod_mut::<AdhocMod>();
+                adhoc.execute(&account, &jid, &node)
+            };
+            aparte.send(&account, request);
+        }
+        (Some(node), Some(answer)) => {
+            // The answer can be a password
+            for arg in command.args.iter_mut() {
+                arg.zeroize();
+            }
+            let step = {
+                let mut adhoc = aparte.get_mod_mut::<AdhocMod>();
+                adhoc.answer(&jid, &node, answer)?
+            };
+            step.run(aparte);
+        }
+    }
+    Ok(())
+});
+
+/// Text of an error returned by the entity
+fn error_text(err: &StanzaError) -> String {
+    match err.texts.get("en") {
+        Some(text) => text.clone(),
+        None => format!("{:?}", err.defined_condition),
+    }
+}
+
+/// Notes attached to a command response, one per line
+fn notes(command: &Element) -> Vec<String> {
+    command
+        .children()
+        .filter(|child| child.is("note", COMMANDS))
+        .map(|note| match note.attr("type") {
+            Some("warn") => format!("Warning: {}", note.text()),
+            Some("error") => format!("Error: {}", note.text()),
+            _ => note.text(),
+        })
+        .collect()
+}
+
+/// Action submitting the form of a command response, the default one of the entity if any, else
+/// going to the next stage when possible
+fn action(command: &Element) -> String {
+    let actions = match command.get_child("actions", COMMANDS) {
+        Some(actions) => actions,
+        None => return String::from("execute"),
+    };
+    if let Some(execute) = actions.attr("execute") {
+        return execute.to_string();
+    }
+    match actions.has_child("next", COMMANDS) {
+        true => String::from("next"),
+        false => String::from("complete"),
+    }
+}
+
+/// Pending requests
+enum Query {
+    /// Commands offered by an entity
+    List(Jid),
+    /// Execution of a command, or of one of its stages
+    Execute { jid: Jid, node: String },
+}
+
+/// What to do next in a command
+enum Step {
+    /// Ask a field, the answer being appended to the command
+    Ask {
+        prompt: String,
+        command: Command,
+        private: bool,
+    },
+    /// Send a request to the entity
+    Send(Account, Element),
+    /// Nothing left to do
+    Done(String),
+}
+
+impl Step {
+    /// Log a text before asking the next field
+    fn after(self, text: String) -> Self {
+        match self {
+            Step::Ask {
+                prompt,
+                command,
+                private,
+            } => Step::Ask {
+                prompt: format!("{}\n{}", text, prompt),
+                command,
+                private,
+            },
+            step => step,
+        }
+    }
+
+    fn run(self, aparte: &mut Aparte) {
+        match self {
+            Step::Ask {
+                prompt,
+                command,
+                private,
+            } => {
+                aparte.log(prompt);
+                match private {
+                    true => aparte.schedule(Event::ReadPassword(command)),
+                    false => aparte.schedule(Event::ReadInput(command)),
+                }
+            }
+            Step::Send(account, request) => aparte.send(&account, request),
+            Step::Done(message) => aparte.log(message),
+        }
+    }
+}
+
+/// Command being filled in
+struct Session {
+    account: Account,
+    jid: Jid,
+    node: String,
+    id: Option<String>,
+    /// Form of the current stage
+    form: DataForm,
+    /// Action submitting the form
+    action: String,
+    /// Fields left to ask
+    questions: Vec<Question>,
+    answers: HashMap<String, Vec<String>>,
+}
+
+pub struct AdhocMod {
+    /// Pending queries by iq id
+    queries: HashMap<String, Query>,
+    session: Option<Session>,
+}
+
+impl AdhocMod {
+    pub fn new() -> Self {
+        Self {
+            queries: HashMap::new(),
+            session: None,
+        }
+    }
+
+    fn list(&mut self, jid: &Jid) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        self.queries.insert(id.clone(), Query::List(jid.clone()));
+        let query = DiscoItemsQuery {
+            node: Some(String::from(COMMANDS)),
+        };
+        Iq::from_get(id, query).with_to(jid.clone()).into()
+    }
+
+    /// Request running a command, or a stage of the current one with the given action
+    fn request(
+        &mut self,
+        jid: &Jid,
+        node: &str,
+        id: Option<String>,
+        action: &str,
+        form: Option<DataForm>,
+    ) -> Element {
+        let iq_id = Uuid::new_v4().to_hyphenated().to_string();
+        self.queries.insert(
+            iq_id.clone(),
+            Query::Execute {
+                jid: jid.clone(),
+                node: node.to_string(),
+            },
+        );
+        let mut command = Element::builder("command", COMMANDS)
+            .attr("node", node)
+            .attr("sessionid", id)
+            .attr("action", action)
+            .build();
+        if let Some(form) = form {
+            command.append_child(form.into());
+        }
+        Element::builder("iq", ns::DEFAULT_NS)
+            .attr("type", "set")
+            .attr("id", iq_id)
+            .attr("to", jid.to_string())
+            .append(command)
+            .build()
+    }
+
+    fn execute(&mut self, account: &Account, jid: &Jid, node: &str) -> Element {
+        // A command started again replaces the one in progress
+        self.session = None;
+        let request = self.request(jid, node, None, "execute", None);
+        self.session = Some(Session {
+            account: account.clone(),
+            jid: jid.clone(),
+            node: node.to_string(),
+            id: None,
+            form: DataForm {
+                type_: DataFormType::Form,
+                form_type: None,
+                title: None,
+                instructions: None,
+                fields: Vec::new(),
+            },
+            action: String::from("execute"),
+            questions: Vec::new(),
+            answers: HashMap::new(),
+        });
+        request
+    }
+
+    /// Handle the response of an entity running a command
+    fn received(&mut self, jid: &Jid, node: &str, command: &Element) -> Step {
+        let mut lines = notes(command);
+        let form = command
+            .get_child("x", ns::DATA_FORMS)
+            .and_then(|form| DataForm::try_from(form.clone()).ok());
+        let status = command.attr("status").unwrap_or("completed");
+
+        let session = match &mut self.session {
+            Some(session)
+                if status == "executing" && &session.jid == jid && session.node == node =>
+            {
+                session
+            }
+            _ => {
+                self.session = None;
+                if let Some(form) = form {
+                    lines.extend(form::lines(&form));
+                }
+                if lines.is_empty() || status == "canceled" {
+                    lines.push(format!("Command {} {}", node, status));
+                }
+                return Step::Done(lines.join("\n"));
+            }
+        };
+        let form = match form {
+            Some(form) if form.type_ == DataFormType::Form => form,
+            _ => {
+                self.session = None;
+                lines.push(format!("Command {} sent nothing to fill", node));
+                return Step::Done(lines.join("\n"));
+            }
+        };
+
+        session.id = command.attr("sessionid").map(String::from);
+        session.action = action(command);
+        session.questions = form::questions(&form);
+        session.answers.clear();
+        lines.extend(form.title.clone());
+        lines.extend(form.instructions.clone());
+        session.form = form;
+        match lines.is_empty() {
+            true => self.step(),
+            false => self.step().after(lines.join("\n")),
+        }
+    }
+
+    fn answer(&mut self, jid: &Jid, node: &str, answer: Password<String>) -> Result<Step, String> {
+        let session = match &mut self.session {
+            Some(session) if &session.jid == jid && session.node == node => session,
+            _ => return Err(format!("Command {} isn't running on {}", node, jid)),
+        };
+        if session.questions.is_empty() {
+            return Err(format!("Nothing asked by command {}", node));
+        }
+        let question = session.questions.remove(0);
+        let values = match question.values(&answer.0) {
+            Ok(values) => values,
+            Err(err) => {
+                session.questions.insert(0, question);
+                return Ok(self.step().after(err));
+            }
+        };
+        if values.is_empty() && question.required {
+            let session = self.session.take().unwrap();
+            let request = self.request(&session.jid, node, session.id, "cancel", None);
+            return Ok(Step::Send(session.account, request));
+        }
+        if !values.is_empty() {
+            session.answers.insert(question.var, values);
+        }
+        Ok(self.step())
+    }
+
+    /// Ask the next field, or submit the form once they are all filled
+    fn step(&mut self) -> Step {
+        let session = match &self.session {
+            Some(session) => session,
+            None => return Step::Done("No command running".to_string()),
+        };
+        if let Some(question) = session.questions.first() {
+            let command = Command {
+                account: Some(session.account.clone()),
+                context: String::from("console"),
+                args: vec![
+                    String::from("adhoc"),
+                    session.jid.to_string(),
+                    session.node.clone(),
+                ],
+                cursor: 0,
+            };
+            return Step::Ask {
+                prompt: question.prompt(),
+                command,
+                private: question.private,
+            };
+        }
+
+        let (account, jid, node, id, action) = (
+            session.account.clone(),
+            session.jid.clone(),
+            session.node.clone(),
+            session.id.clone(),
+            session.action.clone(),
+        );
+        let form = form::submission(&session.form, &session.answers);
+        let request = self.request(&jid, &node, id, &action, Some(form));
+        Step::Send(account, request)
+    }
+}
+
+impl ModTrait for AdhocMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(adhoc::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        if let Event::Iq(_, iq) = event {
+            let query = match self.queries.remove(&iq.id) {
+                Some(query) => query,
+                None => return,
+            };
+
+            match (query, iq.payload.clone()) {
+                (Query::List(jid), IqType::Result(Some(items))) => {
+                    let items = match DiscoItemsResult::try_from(items) {
+                        Ok(items) => items.items,
+                        Err(err) => {
+                            aparte.log(format!("Invalid commands of {}: {}", jid, err));
+                            return;
+                        }
+                    };
+                    let lines: Vec<String> = items
+                        .iter()
+                        .filter_map(|item| {
+                            let node = item.node.as_ref()?;
+                            Some(match &item.name {
+                                Some(name) => format!("  {} ({})", name, node),
+                                None => format!("  {}", node),
+                            })
+                        })
+                        .collect();
+                    match lines.is_empty() {
+                        true => aparte.log(format!("No command offered by {}", jid)),
+                        false => aparte.log(format!(
+                            "Commands of {}, run one with /adhoc {} <node>:\n{}",
+                            jid,
+                            jid,
+                            lines.join("\n")
+                        )),
+                    }
+                }
+                (Query::List(jid), IqType::Error(err)) => aparte.log(format!(
+                    "Cannot list the commands of {}: {}",
+                    jid,
+                    error_text(&err)
+                )),
+                (Query::Execute { jid, node }, IqType::Result(Some(command))) => {
+                    self.received(&jid, &node, &command).run(aparte)
+                }
+                (Query::Execute { jid, node }, IqType::Error(err)) => {
+                    if matches!(&self.session, Some(session) if session.jid == jid && session.node == node)
+                    {
+                        self.session = None;
+                    }
+                    aparte.log(format!("Command {} failed: {}", node, error_text(&err)));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl fmt::Display for AdhocMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0050: Ad-Hoc Commands")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+    use termion::event::Key;
+
+    async fn answer(harness: &mut Harness, answer: &str) {
+        for c in answer.chars() {
+            harness.aparte.schedule(Event::Key(Key::Char(c)));
+        }
+        harness.aparte.schedule(Event::Key(Key::Char('\n')));
+        harness.settle().await;
+    }
+
+    #[test]
+    fn test_commands_are_listed() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness.reply(
+                "query",
+                ns::DISCO_ITEMS,
+                "<iq xmlns='jabber:client' type='result' id='{id}' from='example.org' to='{account}'>
+                    <query xmlns='http://jabber.org/protocol/disco#items'
+                        node='http://jabber.org/protocol/commands'>
+                        <item jid='example.org' name='Add a User'
+                            node='http://jabber.org/protocol/admin#add-user'/>
+                        <item jid='example.org' name='Send Announcement to Online Users'
+                            node='http://jabber.org/protocol/admin#announce'/>
+                    </query>
+                </iq>",
+            );
+
+            // When
+            harness.input("console", "/adhoc example.org").await;
+
+            // Then
+            let screen = harness.screen();
+            assert!(screen.contains("Add a User (http://jabber.org/protocol/admin#add-user)"));
+            assert!(screen.contains("Send Announcement to Online Users"));
+        });
+    }
+
+    #[test]
+    fn test_form_is_asked_then_submitted_until_completed() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness
+                .reply(
+                    "command",
+                    COMMANDS,
+                    "<iq xmlns='jabber:client' type='result' id='{id}' from='example.org' to='{account}'>
+                        <command xmlns='http://jabber.org/protocol/commands' sessionid='add-1'
+                            node='http://jabber.org/protocol/admin#add-user' status='executing'>
+                            <actions execute='complete'><complete/></actions>
+                            <x xmlns='jabber:x:data' type='form'>
+                                <title>Adding a User</title>
+                                <field type='hidden' var='FORM_TYPE'>
+                                    <value>http://jabber.org/protocol/admin</value>
+                                </field>
+                                <field type='jid-single' label='Jabber ID' var='accountjid'>
+                                    <required/>
+                                </field>
+                                <field type='text-private' label='Password' var='password'>
+                                    <required/>
+                                </field>
+                            </x>
+                        </command>
+                    </iq>",
+                )
+                .reply(
+                    "command",
+                    COMMANDS,
+                    "<iq xmlns='jabber:client' type='result' id='{id}' from='example.org' to='{account}'>
+                        <command xmlns='http://jabber.org/protocol/commands' sessionid='add-1'
+                            node='http://jabber.org/protocol/admin#add-user' status='completed'>
+                            <note type='info'>User juliet@example.org added</note>
+                        </command>
+                    </iq>",
+                );
+
+            // When
+            harness
+                .input(
+                    "console",
+                    "/adhoc example.org http://jabber.org/protocol/admin#add-user",
+                )
+                .await;
+            answer(&mut harness, "juliet@example.org").await;
+            answer(&mut harness, "capulet").await;
+
+            // Then
+            let screen = harness.screen();
+            assert!(screen.contains("Adding a User"));
+            assert!(screen.contains("Jabber ID:"));
+            assert!(!screen.contains("capulet"));
+            assert!(screen.contains("User juliet@example.org added"));
+            let adhoc = harness.aparte.get_mod::<AdhocMod>();
+            assert!(adhoc.session.is_none());
+        });
+    }
+
+    #[test]
+    fn test_empty_required_answer_cancels_the_command() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness.reply(
+                "command",
+                COMMANDS,
+                "<iq xmlns='jabber:client' type='result' id='{id}' from='example.org' to='{account}'>
+                    <command xmlns='http://jabber.org/protocol/commands' sessionid='announce-1'
+                        node='http://jabber.org/protocol/admin#announce' status='executing'>
+                        <x xmlns='jabber:x:data' type='form'>
+                            <field type='text-multi' label='Announcement' var='announcement'>
+                                <required/>
+                            </field>
+                        </x>
+                    </command>
+                </iq>",
+            );
+            harness
+                .input(
+                    "console",
+                    "/adhoc example.org http://jabber.org/protocol/admin#announce",
+                )
+                .await;
+
+            // When
+            answer(&mut harness, "").await;
+
+            // Then
+            let sent = harness.take_sent("command", COMMANDS);
+            assert_eq!(sent.len(), 1);
+            let command = sent[0].get_child("command", COMMANDS).unwrap();
+            assert_eq!(command.attr("action"), Some("cancel"));
+            assert_eq!(command.attr("sessionid"), Some("announce-1"));
+            let adhoc = harness.aparte.get_mod::<AdhocMod>();
+            assert!(adhoc.session.is_none());
+        });
+    }
+}
@@ -0,0 +1,206 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Attention requests (XEP-0224), getting a contact to look at the conversation.
+//!
+//! Requests are only honoured from roster contacts so that strangers can't ring the bell.
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::attention::Attention;
+use xmpp_parsers::delay::Delay;
+use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType as XmppParsersMessageType};
+use xmpp_parsers::{ns, BareJid, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::conversation::Conversation;
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::Message;
+use crate::mods;
+
+command_def!(
+    attention,
+    r#"/attention
+
+Description:
+    Request the attention of the contact of the current chat (XEP-0224). Their
+    client usually rings or flashes the conversation."#,
+    {},
+    |aparte, _command| {
+        let account = _command
+            .account
+            .clone()
+            .ok_or("No connection found".to_string())?;
+        let contact = BareJid::from_str(&_command.context)
+            .map_err(|_| "This command can only be used in a chat window".to_string())?;
+        let is_channel = {
+            let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+            matches!(
+                conversations.get(&account, &contact),
+                Some(Conversation::Channel(_))
+            )
+        };
+        // Windows like the console aren't chats with a contact
+        if is_channel || contact.node.is_none() {
+            return Err("This command can only be used in a chat window".to_string());
+        }
+        let supported = {
+            let disco = aparte.get_mod::<mods::disco::DiscoMod>();
+            disco.peer_supports(&account, &contact, ns::ATTENTION)
+        };
+        if supported == Some(false) {
+            return Err(format!("{} doesn't support attention requests", contact));
+        }
+
+        let mut message = XmppParsersMessage::new(Some(Jid::Bare(contact.clone())));
+        message.type_ = XmppParsersMessageType::Headline;
+        message.id = Some(Uuid::new_v4().to_hyphenated().to_string());
+        message.payloads.push(Attention.into());
+        aparte.send(&account, message.into());
+        aparte.log(format!("Attention of {} requested", contact));
+        Ok(())
+    }
+);
+
+pub struct AttentionMod {}
+
+impl AttentionMod {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    fn has_attention(message: &XmppParsersMessage) -> bool {
+        message
+            .payloads
+            .iter()
+            .any(|payload| Attention::try_from(payload.clone()).is_ok())
+    }
+}
+
+impl ModTrait for AttentionMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(attention::new());
+        let mut disco = aparte.get_mod_mut::<mods::disco::DiscoMod>();
+        disco.add_feature(ns::ATTENTION)
+    }
+
+    fn can_handle_xmpp_message(
+        &mut self,
+        _aparte: &mut Aparte,
+        _account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+    ) -> f64 {
+        match (&message.type_, Self::has_attention(message)) {
+            (XmppParsersMessageType::Groupchat, _) | (_, false) => 0f64,
+            (_, true) => 1f64,
+        }
+    }
+
+    fn handle_xmpp_message(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: &XmppParsersMessage,
+        delay: &Option<Delay>,
+    ) {
+        let contact = match &message.from {
+            Some(from) => BareJid::from(from.clone()),
+            None => return,
+        };
+        let is_contact = {
+            let contacts = aparte.get_mod::<mods::contact::ContactMod>();
+            contacts.is_contact(account, &contact)
+        };
+        if !is_contact {
+            info!("Ignoring attention request of stranger {}", contact);
+            return;
+        }
+
+        // A text may come along the request, it belongs to the chat
+        if !message.bodies.is_empty() {
+            let mut chat = message.clone();
+            chat.type_ = XmppParsersMessageType::Chat;
+            if let Ok(chat) = Message::from_xmpp(account, &chat, delay) {
+                aparte.schedule(Event::Message(Some(account.clone()), chat));
+            }
+        }
+        aparte.schedule(Event::Attention {
+            account: account.clone(),
+            contact,
+        });
+    }
+
+    fn on_event(&mut self, _aparte: &mut Aparte, _event: &Event) {}
+}
+
+impl fmt::Display for AttentionMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0224: Attention")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+
+    #[test]
+    fn test_attention_is_requested_from_the_chat_contact() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+
+            // When
+            harness.input("juliet@example.org", "/attention").await;
+            harness.input("console", "/attention").await;
+
+            // Then
+            let sent = harness.take_sent("attention", ns::ATTENTION);
+            assert_eq!(sent.len(), 1);
+            let message = XmppParsersMessage::try_from(sent[0].clone()).unwrap();
+            assert_eq!(message.type_, XmppParsersMessageType::Headline);
+            assert_eq!(
+                message.to,
+                Some(Jid::from_str("juliet@example.org").unwrap())
+            );
+        });
+    }
+
+    #[test]
+    fn test_attention_requests_of_contacts_mark_their_window_urgent() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.reply(
+                "query",
+                ns::ROSTER,
+                "<iq xmlns='jabber:client' type='result' id='{id}'>
+                    <query xmlns='jabber:iq:roster'>
+                        <item jid='juliet@example.org' subscription='both'/>
+                    </query>
+                </iq>",
+            );
+            harness.connect().await;
+
+            // When
+            for from in &["juliet@example.org/balcony", "tybalt@example.org/street"] {
+                let stanza = format!(
+                    "<message xmlns='jabber:client' type='headline' from='{}' to='{{account}}'>
+                        <attention xmlns='urn:xmpp:attention:0'/>
+                    </message>",
+                    from
+                );
+                harness.receive(&stanza).await;
+            }
+
+            // Then
+            let screen = harness.screen();
+            assert!(screen.contains("!juliet@example.org"));
+            assert!(!screen.contains("tybalt@example.org"));
+        });
+    }
+}
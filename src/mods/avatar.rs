@@ -0,0 +1,499 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Avatars of contacts, published with PEP (XEP-0084) or in their vCard (XEP-0153).
+//!
+//! Images are only downloaded when the terminal can display them, other terminals show a block
+//! colored after the avatar hash. Downloaded images are cached on disk by hash so that they are
+//! fetched once, not on every start.
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use uuid::Uuid;
+use xmpp_parsers::avatar::{Data, Metadata};
+use xmpp_parsers::delay::Delay;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::message::Message as XmppParsersMessage;
+use xmpp_parsers::presence::Presence;
+use xmpp_parsers::pubsub::{pubsub, Item, ItemId, NodeName, PubSub, PubSubEvent};
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::core::{Aparte, Event, ModTrait};
+use crate::graphics::Protocol;
+use crate::mods;
+use crate::mods::bob::Bob;
+
+const METADATA_NOTIFY: &str = "urn:xmpp:avatar:metadata+notify";
+const VCARD: &str = "vcard-temp";
+const VCARD_UPDATE: &str = "vcard-temp:x:update";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Avatar {
+    /// SHA-1 of the image, in hexadecimal
+    pub hash: String,
+    /// The image itself, once downloaded
+    pub image: Option<Bob>,
+}
+
+/// Where an avatar is published
+enum Source {
+    /// PEP data node, with the content type announced in the metadata
+    Pep(String),
+    VCard,
+}
+
+fn sha1(data: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+/// Hashes are used as file names, anything but hexadecimal isn't
+fn is_safe(hash: &str) -> bool {
+    !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Avatar announced in a PEP notification, None for an avatar removed
+fn parse_metadata(event: &PubSubEvent) -> Option<Option<(String, String)>> {
+    let item = match event {
+        PubSubEvent::PublishedItems { node, items } if node.0 == ns::AVATAR_METADATA => {
+            items.first()?
+        }
+        _ => return None,
+    };
+    let metadata = Metadata::try_from(item.0.payload.clone()?).ok()?;
+    // Images hosted over HTTP aren't fetched, the one in the data node is always PNG
+    Some(
+        metadata
+            .infos
+            .iter()
+            .find(|info| info.url.is_none())
+            .map(|info| (info.id.to_hex(), info.type_.clone())),
+    )
+}
+
+/// Avatar advertised in a presence, None for no avatar and Some(None) when the client doesn't
+/// know it yet
+fn parse_vcard_update(presence: &Presence) -> Option<Option<String>> {
+    let update = presence
+        .payloads
+        .iter()
+        .find(|payload| payload.is("x", VCARD_UPDATE))?;
+    let photo = update.get_child("photo", VCARD_UPDATE)?;
+    match photo.text().trim() {
+        "" => Some(None),
+        hash => Some(Some(hash.to_lowercase())),
+    }
+}
+
+/// Photo of a vCard, as its content type and data
+fn parse_vcard(vcard: &Element) -> Option<(String, Vec<u8>)> {
+    let photo = vcard.get_child("PHOTO", VCARD)?;
+    let type_ = photo
+        .get_child("TYPE", VCARD)
+        .map(|type_| type_.text())
+        .unwrap_or_else(|| String::from("image/png"));
+    let binval = photo
+        .get_child("BINVAL", VCARD)?
+        .text()
+        .split_whitespace()
+        .collect::<String>();
+    Some((type_, base64::decode(binval).ok()?))
+}
+
+pub struct AvatarMod {
+    /// Hash of the current avatar of contacts
+    hashes: HashMap<BareJid, String>,
+    /// Where images are cached across sessions, if there is a cache directory
+    dir: Option<PathBuf>,
+    /// Images requested, by IQ id, with the contact and hash they are requested for
+    requests: HashMap<String, (BareJid, String, Source)>,
+}
+
+impl AvatarMod {
+    pub fn new() -> Self {
+        Self {
+            hashes: HashMap::new(),
+            dir: dirs::cache_dir().map(|dir| dir.join("aparte").join("avatars")),
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Current avatar of a contact, with its image if it's downloaded
+    pub fn get(&self, contact: &BareJid) -> Option<Avatar> {
+        let hash = self.hashes.get(contact)?;
+        Some(Avatar {
+            hash: hash.clone(),
+            image: self.load(hash),
+        })
+    }
+
+    /// Cached files start with the content type on its own line
+    fn load(&self, hash: &str) -> Option<Bob> {
+        let path = self.dir.as_ref()?.join(hash);
+        if !is_safe(hash) || !path.exists() {
+            return None;
+        }
+        let content = fs::read(path).ok()?;
+        let split = content.iter().position(|byte| *byte == b'\n')?;
+        Some(Bob {
+            type_: String::from_utf8_lossy(&content[..split]).to_string(),
+            data: content[split + 1..].to_vec(),
+        })
+    }
+
+    fn store(&self, hash: &str, image: &Bob) {
+        if let (Some(dir), true) = (&self.dir, is_safe(hash)) {
+            let mut content = format!("{}\n", image.type_).into_bytes();
+            content.extend_from_slice(&image.data);
+            let result = fs::create_dir_all(dir).and_then(|_| fs::write(dir.join(hash), content));
+            if let Err(err) = result {
+                warn!("Cannot cache avatar {}: {}", hash, err);
+            }
+        }
+    }
+
+    /// Images are only downloaded when the terminal can display them inline
+    fn shows_images(aparte: &Aparte) -> bool {
+        let protocol = {
+            let ui = aparte.get_mod::<mods::ui::UIMod>();
+            ui.graphics()
+        };
+        !aparte.config.low_bandwidth && matches!(protocol, Protocol::Kitty | Protocol::Iterm2)
+    }
+
+    /// A contact announced its current avatar, None when it has none
+    fn update(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        contact: BareJid,
+        hash: Option<String>,
+        source: Source,
+    ) {
+        let hash = match hash {
+            Some(hash) if is_safe(&hash) => hash,
+            Some(_) => return,
+            None => {
+                if self.hashes.remove(&contact).is_some() {
+                    aparte.schedule(Event::Avatar {
+                        contact,
+                        avatar: None,
+                    });
+                }
+                return;
+            }
+        };
+        if self.hashes.get(&contact) == Some(&hash) {
+            return;
+        }
+        self.hashes.insert(contact.clone(), hash.clone());
+
+        let image = self.load(&hash);
+        let requested = self
+            .requests
+            .values()
+            .any(|(_, requested, _)| requested == &hash);
+        if image.is_none() && !requested && Self::shows_images(aparte) {
+            let request = self.request(&contact, &hash, source);
+            aparte.send(account, request);
+        }
+        aparte.schedule(Event::Avatar {
+            contact,
+            avatar: Some(Avatar { hash, image }),
+        });
+    }
+
+    fn request(&mut self, contact: &BareJid, hash: &str, source: Source) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let payload = match source {
+            Source::Pep(_) => PubSub::Items(pubsub::Items {
+                max_items: None,
+                node: NodeName(String::from(ns::AVATAR_DATA)),
+                subid: None,
+                items: vec![pubsub::Item(Item {
+                    id: Some(ItemId(hash.to_string())),
+                    publisher: None,
+                    payload: None,
+                })],
+            })
+            .into(),
+            Source::VCard => Element::builder("vCard", VCARD).build(),
+        };
+        let iq = Iq {
+            from: None,
+            to: Some(Jid::Bare(contact.clone())),
+            id: id.clone(),
+            payload: IqType::Get(payload),
+        };
+        self.requests
+            .insert(id, (contact.clone(), hash.to_string(), source));
+        iq.into()
+    }
+
+    /// Keep a downloaded image, provided it's the one announced
+    fn handle_image(&mut self, aparte: &mut Aparte, contact: BareJid, hash: String, image: Bob) {
+        if sha1(&image.data) != hash {
+            warn!("Avatar of {} doesn't match its hash {}", contact, hash);
+            return;
+        }
+        self.store(&hash, &image);
+        if self.hashes.get(&contact) == Some(&hash) {
+            aparte.schedule(Event::Avatar {
+                contact,
+                avatar: Some(Avatar {
+                    hash,
+                    image: Some(image),
+                }),
+            });
+        }
+    }
+
+    fn handle_presence(&mut self, aparte: &mut Aparte, account: &Account, presence: &Presence) {
+        // Occupants of rooms advertise their own avatar, not the one of a contact
+        if presence
+            .payloads
+            .iter()
+            .any(|payload| payload.has_ns(ns::MUC_USER))
+        {
+            return;
+        }
+        if let (Some(from), Some(hash)) = (&presence.from, parse_vcard_update(presence)) {
+            let contact = BareJid::from(from.clone());
+            self.update(aparte, account, contact, hash, Source::VCard);
+        }
+    }
+
+    fn get_event(message: &XmppParsersMessage) -> Option<PubSubEvent> {
+        message
+            .payloads
+            .iter()
+            .filter(|payload| payload.is("event", ns::PUBSUB_EVENT))
+            .find_map(|payload| PubSubEvent::try_from(payload.clone()).ok())
+    }
+}
+
+impl ModTrait for AvatarMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        let mut disco = aparte.get_mod_mut::<mods::disco::DiscoMod>();
+        disco.add_feature(METADATA_NOTIFY)
+    }
+
+    fn can_handle_xmpp_message(
+        &mut self,
+        _aparte: &mut Aparte,
+        _account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+    ) -> f64 {
+        match Self::get_event(message).as_ref().and_then(parse_metadata) {
+            Some(_) => 1f64,
+            None => 0f64,
+        }
+    }
+
+    fn handle_xmpp_message(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+    ) {
+        let contact = match &message.from {
+            Some(from) => BareJid::from(from.clone()),
+            None => BareJid::from(Jid::Full(account.clone())),
+        };
+        match Self::get_event(message).as_ref().and_then(parse_metadata) {
+            Some(Some((hash, type_))) => {
+                self.update(aparte, account, contact, Some(hash), Source::Pep(type_))
+            }
+            Some(None) => self.update(aparte, account, contact, None, Source::VCard),
+            None => {}
+        }
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Presence(account, presence) => self.handle_presence(aparte, account, presence),
+            Event::Iq(_, iq) if self.requests.contains_key(&iq.id) => {
+                let (contact, hash, source) = self.requests.remove(&iq.id).unwrap();
+                let image = match (&iq.payload, source) {
+                    (IqType::Result(Some(payload)), Source::Pep(type_)) => {
+                        match PubSub::try_from(payload.clone()) {
+                            Ok(PubSub::Items(items)) => items
+                                .items
+                                .into_iter()
+                                .find_map(|item| Data::try_from(item.0.payload?).ok())
+                                .map(|data| Bob {
+                                    type_,
+                                    data: data.data,
+                                }),
+                            _ => None,
+                        }
+                    }
+                    (IqType::Result(Some(payload)), Source::VCard)
+                        if payload.is("vCard", VCARD) =>
+                    {
+                        parse_vcard(payload).map(|(type_, data)| Bob { type_, data })
+                    }
+                    _ => None,
+                };
+                match image {
+                    Some(image) => self.handle_image(aparte, contact, hash, image),
+                    None => warn!("Cannot fetch avatar {} of {}", hash, contact),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for AvatarMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0084: User Avatar")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::{self, Harness};
+    use std::str::FromStr;
+
+    fn metadata(hash: &str) -> String {
+        format!(
+            "<message xmlns='jabber:client' type='headline' from='juliet@example.org'
+                to='{{account}}'>
+                <event xmlns='http://jabber.org/protocol/pubsub#event'>
+                    <items node='urn:xmpp:avatar:metadata'>
+                        <item id='{hash}'>
+                            <metadata xmlns='urn:xmpp:avatar:metadata'>
+                                <info bytes='16' id='{hash}' type='image/png'/>
+                            </metadata>
+                        </item>
+                    </items>
+                </event>
+            </message>",
+            hash = hash
+        )
+    }
+
+    #[test]
+    fn test_pep_avatars_are_downloaded_once() {
+        testing::run(async {
+            // Given
+            let config = Config {
+                graphics: Some(Protocol::Kitty),
+                ..Config::default()
+            };
+            let data = b"\x89PNG juliet's avatar".to_vec();
+            let hash = sha1(&data);
+            let juliet = BareJid::from_str("juliet@example.org").unwrap();
+            let mut harness = Harness::with_config(config.clone());
+            harness.connect().await;
+            harness.reply(
+                "pubsub",
+                ns::PUBSUB,
+                &format!(
+                    "<iq xmlns='jabber:client' type='result' id='{{id}}' from='juliet@example.org'>
+                        <pubsub xmlns='http://jabber.org/protocol/pubsub'>
+                            <items node='urn:xmpp:avatar:data'>
+                                <item id='{}'>
+                                    <data xmlns='urn:xmpp:avatar:data'>{}</data>
+                                </item>
+                            </items>
+                        </pubsub>
+                    </iq>",
+                    hash,
+                    base64::encode(&data)
+                ),
+            );
+
+            // When
+            harness.receive(&metadata(&hash)).await;
+            let mut restarted = Harness::with_config(config);
+            restarted.connect().await;
+            restarted.receive(&metadata(&hash)).await;
+
+            // Then
+            let avatar = harness.aparte.get_mod::<AvatarMod>().get(&juliet).unwrap();
+            assert_eq!(avatar.image.unwrap().data, data);
+            assert!(restarted.take_sent("pubsub", ns::PUBSUB).is_empty());
+            let avatar = restarted
+                .aparte
+                .get_mod::<AvatarMod>()
+                .get(&juliet)
+                .unwrap();
+            assert_eq!(avatar.image.unwrap().data, data);
+        });
+    }
+
+    #[test]
+    fn test_avatars_arent_downloaded_when_they_cant_be_displayed() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            let romeo = BareJid::from_str("mercutio@example.org").unwrap();
+            let hash = "0123456789abcdef0123456789abcdef01234567";
+
+            // When
+            harness
+                .receive(&format!(
+                    "<presence xmlns='jabber:client' from='mercutio@example.org/phone'
+                        to='{{account}}'>
+                        <x xmlns='vcard-temp:x:update'><photo>{}</photo></x>
+                    </presence>",
+                    hash
+                ))
+                .await;
+            let shown = harness.aparte.get_mod::<AvatarMod>().get(&romeo);
+            harness
+                .receive(
+                    "<presence xmlns='jabber:client' from='mercutio@example.org/phone'
+                        to='{account}'>
+                        <x xmlns='vcard-temp:x:update'><photo/></x>
+                    </presence>",
+                )
+                .await;
+
+            // Then
+            assert!(harness.take_sent("vCard", VCARD).is_empty());
+            assert_eq!(
+                shown,
+                Some(Avatar {
+                    hash: hash.to_string(),
+                    image: None
+                })
+            );
+            assert_eq!(harness.aparte.get_mod::<AvatarMod>().get(&romeo), None);
+        });
+    }
+
+    #[test]
+    fn test_parse_vcard_photo() {
+        // Given
+        let vcard = Element::from_str(
+            "<vCard xmlns='vcard-temp'>
+                <PHOTO>
+                    <TYPE>image/jpeg</TYPE>
+                    <BINVAL>aGVs
+                        bG8=</BINVAL>
+                </PHOTO>
+            </vCard>",
+        )
+        .unwrap();
+
+        // When
+        let photo = parse_vcard(&vcard);
+
+        // Then
+        assert_eq!(photo, Some((String::from("image/jpeg"), b"hello".to_vec())));
+    }
+}
@@ -0,0 +1,470 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Server side blocklist (XEP-0191).
+//!
+//! The blocklist is fetched once the server is known to support it and kept up to date with the
+//! pushes of the server. Messages of blocked jids are also dropped on our side, for those still
+//! getting through (archives, servers applying the list lazily).
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::blocking::{Block, BlocklistRequest, BlocklistResult, Unblock};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::mods;
+
+command_def!(
+    block,
+    r#"/block <jid>
+
+    jid    Contact, resource or domain to block
+
+Description:
+    Block a jid on the server (XEP-0191): it doesn't receive our presence and
+    its messages don't reach us anymore.
+
+Examples:
+    /block tybalt@example.org
+    /block spam.example.org"#,
+{
+    jid: Jid
+},
+|aparte, _command| {
+    let account = _command
+        .account
+        .clone()
+        .ok_or("No connection found".to_string())?;
+    supported(aparte, &account)?;
+    let request = {
+        let mut blocking = aparte.get_mod_mut::<BlockingMod>();
+        blocking.request(&account, Query::Block(jid))
+    };
+    aparte.send(&account, request);
+    Ok(())
+});
+
+command_def!(
+    unblock,
+    r#"/unblock <jid>
+
+    jid    Jid to unblock
+
+Description:
+    Remove a jid from the server blocklist.
+
+Example:
+    /unblock tybalt@example.org"#,
+{
+    jid: Jid = {
+        completion: (|aparte, _command| {
+            let blocking = aparte.get_mod::<BlockingMod>();
+            match _command.account {
+                Some(account) => blocking.blocked(&account).iter().map(|jid| jid.to_string()).collect(),
+                None => Vec::new(),
+            }
+        })
+    }
+},
+|aparte, _command| {
+    let account = _command
+        .account
+        .clone()
+        .ok_or("No connection found".to_string())?;
+    supported(aparte, &account)?;
+    let request = {
+        let mut blocking = aparte.get_mod_mut::<BlockingMod>();
+        blocking.request(&account, Query::Unblock(jid))
+    };
+    aparte.send(&account, request);
+    Ok(())
+});
+
+command_def!(
+    blocklist,
+    r#"/blocklist
+
+Description:
+    List the jids blocked on the server."#,
+    {},
+    |aparte, _command| {
+        let account = _command
+            .account
+            .clone()
+            .ok_or("No connection found".to_string())?;
+        supported(aparte, &account)?;
+        let request = {
+            let mut blocking = aparte.get_mod_mut::<BlockingMod>();
+            blocking.request(&account, Query::List)
+        };
+        aparte.send(&account, request);
+        Ok(())
+    }
+);
+
+/// Fail unless the server of the account supports blocking
+fn supported(aparte: &mut Aparte, account: &Account) -> Result<(), String> {
+    let disco = aparte.get_mod::<mods::disco::DiscoMod>();
+    match disco.has_feature(account, ns::BLOCKING) {
+        true => Ok(()),
+        false => Err(format!(
+            "The server of {} doesn't support blocking",
+            account
+        )),
+    }
+}
+
+/// Whether a blocked jid covers `from`: domains cover everything on them, bare jids all their
+/// resources and full jids only themselves
+pub fn covers(blocked: &Jid, from: &Jid) -> bool {
+    match blocked {
+        Jid::Full(_) => blocked == from,
+        Jid::Bare(BareJid { node: None, domain }) => &from.clone().domain() == domain,
+        Jid::Bare(bare) => &BareJid::from(from.clone()) == bare,
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Query {
+    /// Blocklist fetched once connected
+    Sync,
+    List,
+    Block(Jid),
+    Unblock(Jid),
+}
+
+pub struct BlockingMod {
+    blocklists: HashMap<Account, HashSet<Jid>>,
+    requests: HashMap<String, (Account, Query)>,
+}
+
+impl BlockingMod {
+    pub fn new() -> Self {
+        Self {
+            blocklists: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    pub fn blocked(&self, account: &Account) -> Vec<Jid> {
+        let mut blocked: Vec<Jid> = self
+            .blocklists
+            .get(account)
+            .map(|blocklist| blocklist.iter().cloned().collect())
+            .unwrap_or_default();
+        blocked.sort_by_key(|jid| jid.to_string());
+        blocked
+    }
+
+    pub fn is_blocked(&self, account: &Account, from: &Jid) -> bool {
+        match self.blocklists.get(account) {
+            Some(blocklist) => blocklist.iter().any(|blocked| covers(blocked, from)),
+            None => false,
+        }
+    }
+
+    fn request(&mut self, account: &Account, query: Query) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let iq = match &query {
+            Query::Sync | Query::List => Iq::from_get(id.clone(), BlocklistRequest),
+            Query::Block(jid) => Iq::from_set(
+                id.clone(),
+                Block {
+                    items: vec![jid.clone()],
+                },
+            ),
+            Query::Unblock(jid) => Iq::from_set(
+                id.clone(),
+                Unblock {
+                    items: vec![jid.clone()],
+                },
+            ),
+        };
+        self.requests.insert(id, (account.clone(), query));
+        iq.into()
+    }
+
+    fn handle_result(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        query: Query,
+        payload: &Option<Element>,
+    ) {
+        let blocklist = self.blocklists.entry(account.clone()).or_default();
+        match query {
+            Query::Sync | Query::List => {
+                let result = payload
+                    .clone()
+                    .and_then(|payload| BlocklistResult::try_from(payload).ok());
+                *blocklist = result
+                    .map(|result| result.items)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect();
+                if let Query::List = query {
+                    let blocked = self.blocked(account);
+                    match blocked.len() {
+                        0 => aparte.log(format!("Nobody is blocked by {}", account)),
+                        _ => aparte.log(format!(
+                            "Blocked by {}: {}",
+                            account,
+                            blocked
+                                .iter()
+                                .map(|jid| jid.to_string())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        )),
+                    }
+                }
+            }
+            Query::Block(jid) => {
+                blocklist.insert(jid.clone());
+                aparte.log(format!("{} blocked", jid));
+            }
+            Query::Unblock(jid) => {
+                blocklist.remove(&jid);
+                aparte.log(format!("{} unblocked", jid));
+            }
+        }
+    }
+
+    /// Changes of the blocklist made by other clients, pushed by the server
+    fn handle_push(&mut self, aparte: &mut Aparte, account: &Account, iq: &Iq) -> bool {
+        let payload = match &iq.payload {
+            IqType::Set(payload) => payload,
+            _ => return false,
+        };
+        // Only our server can change our blocklist
+        let own: BareJid = account.clone().into();
+        let from_server = match &iq.from {
+            None => true,
+            Some(Jid::Bare(jid)) => jid == &own,
+            Some(Jid::Full(_)) => false,
+        };
+        if !from_server {
+            return false;
+        }
+
+        let blocklist = self.blocklists.entry(account.clone()).or_default();
+        if let Ok(block) = Block::try_from(payload.clone()) {
+            blocklist.extend(block.items);
+        } else if let Ok(unblock) = Unblock::try_from(payload.clone()) {
+            match unblock.items.is_empty() {
+                // Unblocking nobody in particular unblocks everybody
+                true => blocklist.clear(),
+                false => {
+                    for jid in unblock.items {
+                        blocklist.remove(&jid);
+                    }
+                }
+            }
+        } else {
+            return false;
+        }
+
+        let result = Iq {
+            from: None,
+            to: iq.from.clone(),
+            id: iq.id.clone(),
+            payload: IqType::Result(None),
+        };
+        aparte.send(account, result.into());
+        true
+    }
+}
+
+impl ModTrait for BlockingMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(block::new());
+        aparte.add_command(unblock::new());
+        aparte.add_command(blocklist::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Disco(account) if supported(aparte, account).is_ok() => {
+                let request = self.request(account, Query::Sync);
+                aparte.send(account, request);
+            }
+            Event::Iq(account, iq) if self.requests.contains_key(&iq.id) => {
+                let (account, query) = match self.requests.remove(&iq.id) {
+                    Some((requested, query)) if &requested == account => (requested, query),
+                    _ => return,
+                };
+                match &iq.payload {
+                    IqType::Result(payload) => self.handle_result(aparte, &account, query, payload),
+                    IqType::Error(err) => match query {
+                        Query::Sync => warn!("Cannot fetch blocklist: {:?}", err.defined_condition),
+                        Query::List => aparte.log(format!(
+                            "Cannot fetch blocklist: {:?}",
+                            err.defined_condition
+                        )),
+                        Query::Block(jid) => {
+                            aparte.log(format!("Cannot block {}: {:?}", jid, err.defined_condition))
+                        }
+                        Query::Unblock(jid) => aparte.log(format!(
+                            "Cannot unblock {}: {:?}",
+                            jid, err.defined_condition
+                        )),
+                    },
+                    _ => {}
+                }
+            }
+            Event::Iq(account, iq) => {
+                self.handle_push(aparte, account, iq);
+            }
+            Event::Disconnected(account, _) => {
+                self.blocklists.remove(account);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for BlockingMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0191: Blocking Command")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+
+    const DISCO: &str = "<iq xmlns='jabber:client' type='result' id='{id}' from='example.org'>
+        <query xmlns='http://jabber.org/protocol/disco#info'>
+            <identity category='server' type='im'/>
+            <feature var='http://jabber.org/protocol/disco#info'/>
+            <feature var='urn:xmpp:blocking'/>
+        </query>
+    </iq>";
+
+    #[test]
+    fn test_covers() {
+        // Given
+        let from = Jid::from_str("tybalt@example.org/street").unwrap();
+
+        // Then
+        for blocked in &[
+            "example.org",
+            "tybalt@example.org",
+            "tybalt@example.org/street",
+        ] {
+            assert!(
+                covers(&Jid::from_str(blocked).unwrap(), &from),
+                "{}",
+                blocked
+            );
+        }
+        for blocked in &[
+            "example.net",
+            "juliet@example.org",
+            "tybalt@example.org/balcony",
+        ] {
+            assert!(
+                !covers(&Jid::from_str(blocked).unwrap(), &from),
+                "{}",
+                blocked
+            );
+        }
+    }
+
+    #[test]
+    fn test_messages_of_blocked_jids_are_dropped() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness
+                .reply("query", ns::DISCO_INFO, DISCO)
+                .reply(
+                    "blocklist",
+                    ns::BLOCKING,
+                    "<iq xmlns='jabber:client' type='result' id='{id}'>
+                        <blocklist xmlns='urn:xmpp:blocking'/>
+                    </iq>",
+                )
+                .reply(
+                    "block",
+                    ns::BLOCKING,
+                    "<iq xmlns='jabber:client' type='result' id='{id}'/>",
+                );
+            harness.connect().await;
+
+            // When
+            harness.input("console", "/block tybalt@example.org").await;
+            for from in &["tybalt@example.org/street", "juliet@example.org/balcony"] {
+                let stanza = format!(
+                    "<message xmlns='jabber:client' type='chat' from='{}' to='{{account}}'>
+                        <body>Message of {}</body>
+                    </message>",
+                    from, from
+                );
+                harness.receive(&stanza).await;
+            }
+
+            // Then
+            let blocking = harness.aparte.get_mod::<BlockingMod>();
+            assert_eq!(
+                blocking.blocked(&harness.account),
+                vec![Jid::from_str("tybalt@example.org").unwrap()]
+            );
+            drop(blocking);
+            let screen = harness.screen();
+            assert!(screen.contains("tybalt@example.org blocked"));
+            assert!(!screen.contains("Message of tybalt"));
+        });
+    }
+
+    #[test]
+    fn test_blocklist_is_kept_up_to_date_with_pushes() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.reply("query", ns::DISCO_INFO, DISCO).reply(
+                "blocklist",
+                ns::BLOCKING,
+                "<iq xmlns='jabber:client' type='result' id='{id}'>
+                    <blocklist xmlns='urn:xmpp:blocking'>
+                        <item jid='tybalt@example.org'/>
+                        <item jid='spam.example.net'/>
+                    </blocklist>
+                </iq>",
+            );
+            harness.connect().await;
+
+            // When
+            harness
+                .receive(
+                    "<iq xmlns='jabber:client' type='set' id='push1'>
+                        <unblock xmlns='urn:xmpp:blocking'>
+                            <item jid='tybalt@example.org'/>
+                        </unblock>
+                    </iq>",
+                )
+                .await;
+            harness
+                .receive(
+                    "<iq xmlns='jabber:client' type='set' id='push2' from='mallory@example.net'>
+                        <unblock xmlns='urn:xmpp:blocking'/>
+                    </iq>",
+                )
+                .await;
+
+            // Then
+            let blocking = harness.aparte.get_mod::<BlockingMod>();
+            assert_eq!(
+                blocking.blocked(&harness.account),
+                vec![Jid::from_str("spam.example.net").unwrap()]
+            );
+        });
+    }
+}
@@ -0,0 +1,224 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Bits of Binary (XEP-0231), small data referenced by `cid:` URIs.
+//!
+//! Data is either attached to the stanza referencing it or fetched from its sender. It's cached
+//! in memory and, unless told otherwise with a max-age of 0, on disk.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use uuid::Uuid;
+use xmpp_parsers::iq::IqType;
+use xmpp_parsers::message::Message as XmppParsersMessage;
+use xmpp_parsers::{ns, Element, Jid};
+
+use crate::core::{Aparte, Event, ModTrait};
+use crate::mods;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bob {
+    pub type_: String,
+    pub data: Vec<u8>,
+}
+
+/// Content id of a `cid:` URI, None for other URIs
+pub fn cid(uri: &str) -> Option<&str> {
+    uri.strip_prefix("cid:")
+}
+
+/// Parse a BOB data element, returning its content id and content
+fn parse(data: &Element) -> Option<(String, Bob, Option<u64>)> {
+    let cid = data.attr("cid")?.to_string();
+    let bob = Bob {
+        type_: data
+            .attr("type")
+            .unwrap_or("application/octet-stream")
+            .to_string(),
+        data: base64::decode(data.text().trim()).ok()?,
+    };
+    let max_age = data.attr("max-age").and_then(|age| age.parse().ok());
+    Some((cid, bob, max_age))
+}
+
+/// Content ids are made of a hash algorithm and an hexadecimal digest, anything else isn't
+/// used as a file name
+fn is_safe(cid: &str) -> bool {
+    !cid.is_empty()
+        && !cid.starts_with('.')
+        && cid
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.@".contains(c))
+}
+
+pub struct BobMod {
+    cache: HashMap<String, Bob>,
+    /// Where data is cached across sessions, if there is a cache directory
+    dir: Option<PathBuf>,
+    /// Content ids requested, by IQ id
+    requests: HashMap<String, String>,
+}
+
+impl BobMod {
+    pub fn new() -> Self {
+        Self {
+            cache: HashMap::new(),
+            dir: dirs::cache_dir().map(|dir| dir.join("aparte").join("bob")),
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Cached data for a content id
+    pub fn get(&mut self, cid: &str) -> Option<Bob> {
+        if let Some(bob) = self.cache.get(cid) {
+            return Some(bob.clone());
+        }
+
+        // Cached files start with the content type on its own line
+        let path = self.dir.as_ref()?.join(cid);
+        if !is_safe(cid) || !path.exists() {
+            return None;
+        }
+        let content = fs::read(path).ok()?;
+        let split = content.iter().position(|byte| *byte == b'\n')?;
+        let bob = Bob {
+            type_: String::from_utf8_lossy(&content[..split]).to_string(),
+            data: content[split + 1..].to_vec(),
+        };
+        self.cache.insert(cid.to_string(), bob.clone());
+        Some(bob)
+    }
+
+    fn store(&mut self, cid: String, bob: Bob, max_age: Option<u64>) {
+        if let (Some(dir), true) = (&self.dir, max_age != Some(0) && is_safe(&cid)) {
+            let mut content = format!("{}\n", bob.type_).into_bytes();
+            content.extend_from_slice(&bob.data);
+            let result = fs::create_dir_all(dir).and_then(|_| fs::write(dir.join(&cid), content));
+            if let Err(err) = result {
+                warn!("Cannot cache {}: {}", cid, err);
+            }
+        }
+        self.cache.insert(cid, bob);
+    }
+
+    /// Keep data attached to a message
+    pub fn collect(&mut self, message: &XmppParsersMessage) {
+        for payload in message.payloads.iter().filter(|p| p.is("data", ns::BOB)) {
+            if let Some((cid, bob, max_age)) = parse(payload) {
+                self.store(cid, bob, max_age);
+            }
+        }
+    }
+
+    /// Request to send to the entity referencing data, None when it's already requested.
+    /// Event::Bob is scheduled once the data is received.
+    pub fn request(&mut self, from: &Jid, cid: &str) -> Option<Element> {
+        if self.requests.values().any(|requested| requested == cid) {
+            return None;
+        }
+
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let iq = Element::builder("iq", ns::DEFAULT_NS)
+            .attr("type", "get")
+            .attr("id", id.clone())
+            .attr("to", from.to_string())
+            .append(Element::builder("data", ns::BOB).attr("cid", cid).build())
+            .build();
+        self.requests.insert(id, cid.to_string());
+        Some(iq)
+    }
+}
+
+impl ModTrait for BobMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        let mut disco = aparte.get_mod_mut::<mods::disco::DiscoMod>();
+        disco.add_feature(ns::BOB)
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Iq(_, iq) if self.requests.contains_key(&iq.id) => {
+                let cid = self.requests.remove(&iq.id).unwrap();
+                match &iq.payload {
+                    IqType::Result(Some(payload)) if payload.is("data", ns::BOB) => {
+                        match parse(payload) {
+                            Some((_, bob, max_age)) => {
+                                // Trust the requested id rather than the announced one
+                                self.store(cid.clone(), bob, max_age);
+                                aparte.schedule(Event::Bob(cid));
+                            }
+                            None => warn!("Invalid data received for {}", cid),
+                        }
+                    }
+                    IqType::Error(err) => {
+                        aparte.log(format!("Cannot fetch {}: {:?}", cid, err.defined_condition))
+                    }
+                    _ => warn!("Unexpected response for {}", cid),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for BobMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0231: Bits of Binary")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_attached_data_is_cached() {
+        // Given
+        let mut bob = BobMod {
+            cache: HashMap::new(),
+            dir: None,
+            requests: HashMap::new(),
+        };
+        let message = XmppParsersMessage::try_from(
+            Element::from_str(
+                r#"<message xmlns="jabber:client" from="room@conference.example.org">
+                    <data xmlns="urn:xmpp:bob" cid="sha1+8f35fef110ffc5df08d579a50083ff9308fb6242@bob.xmpp.org" type="image/png" max-age="0">iVBORw0K</data>
+                </message>"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        // When
+        bob.collect(&message);
+
+        // Then
+        assert_eq!(
+            bob.get("sha1+8f35fef110ffc5df08d579a50083ff9308fb6242@bob.xmpp.org"),
+            Some(Bob {
+                type_: String::from("image/png"),
+                data: base64::decode("iVBORw0K").unwrap(),
+            })
+        );
+        assert_eq!(bob.get("sha1+0000@bob.xmpp.org"), None);
+    }
+
+    #[test]
+    fn test_unsafe_cids_are_not_used_as_file_names() {
+        // Given
+        let cids = [
+            "sha1+8f35fef110ffc5df08d579a50083ff9308fb6242@bob.xmpp.org",
+            "../../.bashrc",
+            ".hidden",
+        ];
+
+        // When
+        let safe = cids.iter().map(|cid| is_safe(cid)).collect::<Vec<bool>>();
+
+        // Then
+        assert_eq!(safe, vec![true, false, false]);
+    }
+}
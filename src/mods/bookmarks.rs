@@ -21,7 +21,7 @@ use xmpp_parsers::{BareJid, Jid};
 use crate::account::Account;
 use crate::command::{Command, CommandParser};
 use crate::contact;
-use crate::core::{Aparte, Event, ModTrait};
+use crate::core::{Aparte, Event, IqError, IqResponse, ModTrait};
 use crate::mods::disco;
 
 command_def!(bookmark_add,
@@ -42,16 +42,18 @@ Examples:
 "#,
 {
     name: String,
-    conference: BareJid,
+    conference: BareJid = {
+        jid: bare
+    },
     nick: Named<String>,
-    autojoin: Named<bool>
+    autojoin: Named<String> = {
+        values: ["on", "off"]
+    }
 },
 |aparte, _command| {
     let account = aparte.current_account().ok_or(format!("No connection found"))?;
-    let autojoin = match autojoin {
-        None => false, // Autojoin default to false
-        Some(autojoin) => autojoin,
-    };
+    // Autojoin default to false
+    let autojoin = autojoin.as_deref() == Some("on");
     let bookmark = contact::Bookmark {
         jid: conference.into(),
         name: Some(name),
@@ -60,12 +62,14 @@ Examples:
         autojoin: autojoin,
         extensions: None,
     };
-    let add = {
+    let updates = {
         let mut bookmarks = aparte.get_mod_mut::<BookmarksMod>();
         bookmarks.add(bookmark.clone())
     };
     aparte.schedule(Event::Bookmark(bookmark));
-    aparte.send(&account, add);
+    for update in updates {
+        aparte.send(&account, update);
+    }
     Ok(())
 });
 
@@ -86,12 +90,14 @@ Examples:
         let account = aparte
             .current_account()
             .ok_or(format!("No connection found"))?;
-        if let Some((bookmark, delete)) = {
+        if let Some((bookmark, updates)) = {
             let mut bookmarks = aparte.get_mod_mut::<BookmarksMod>();
             bookmarks.delete(conference.clone())
         } {
             aparte.schedule(Event::DeletedBookmark(bookmark.jid));
-            aparte.send(&account, delete);
+            for update in updates {
+                aparte.send(&account, update);
+            }
         }
         Ok(())
     }
@@ -108,24 +114,30 @@ Description:
     Edit a bookmark
 
 Examples:
-    /bookmark edit aparte autojoin=true
+    /bookmark edit aparte autojoin=on
     /bookmark edit aparte aparte@conference.fariello.eu
     /bookmark edit aparte nick=needle
-    /bookmark edit aparte aparte@conference.fariello.eu autojoin=false
+    /bookmark edit aparte aparte@conference.fariello.eu autojoin=off
 "#,
 {
     name: String,
     nick: Named<String>,
-    autojoin: Named<bool>,
-    conference: Option<BareJid>,
+    autojoin: Named<String> = {
+        values: ["on", "off"]
+    },
+    conference: Option<BareJid> = {
+        jid: bare
+    },
 },
 |aparte, _command| {
     let account = aparte.current_account().ok_or(format!("No connection found"))?;
-    if let Some(edit) = {
+    if let Some(updates) = {
         let mut bookmarks = aparte.get_mod_mut::<BookmarksMod>();
-        bookmarks.edit(name.clone(), conference, nick, autojoin)
+        bookmarks.edit(name.clone(), conference, nick, autojoin.map(|autojoin| autojoin == "on"))
     } {
-        aparte.send(&account, edit);
+        for update in updates {
+            aparte.send(&account, update);
+        }
         Ok(())
     } else {
         Err(format!("Unknown bookmark {}", name))
@@ -144,15 +156,56 @@ r#"/bookmark add|del|edit"#,
     },
 });
 
+/// Private XML storage (XEP-0049)
+const PRIVATE: &str = "jabber:iq:private";
+
 enum Backend {
     Bookmarks(Bookmarks),
     Bookmarks2(Bookmarks2),
+    Private(PrivateBookmarks),
+}
+
+/// Legacy storage of all the bookmarks (XEP-0048)
+fn storage(bookmarks: &[contact::Bookmark]) -> bookmarks::Storage {
+    let conferences = bookmarks
+        .iter()
+        .map(|bookmark| bookmarks::Conference {
+            autojoin: match bookmark.autojoin {
+                true => bookmarks::Autojoin::True,
+                false => bookmarks::Autojoin::False,
+            },
+            jid: bookmark.jid.clone(),
+            name: Some(bookmark.name.clone().unwrap_or(bookmark.jid.to_string())),
+            nick: bookmark.nick.clone(),
+            password: None,
+        })
+        .collect();
+    bookmarks::Storage {
+        conferences,
+        urls: vec![],
+    }
+}
+
+/// Bookmarks of a legacy storage
+fn from_storage(storage: bookmarks::Storage) -> Vec<contact::Bookmark> {
+    storage
+        .conferences
+        .into_iter()
+        .map(|conf| contact::Bookmark {
+            jid: conf.jid,
+            name: conf.name,
+            nick: conf.nick,
+            password: conf.password,
+            autojoin: conf.autojoin == bookmarks::Autojoin::True,
+            extensions: None,
+        })
+        .collect()
 }
 
 struct Bookmarks {}
 
 impl Bookmarks {
-    fn retreive(&self) -> Element {
+    fn retreive(&self) -> Iq {
         let id = Uuid::new_v4().to_hyphenated().to_string();
         let items = Items {
             max_items: None,
@@ -161,32 +214,14 @@ impl Bookmarks {
             items: vec![],
         };
         let pubsub = PubSub::Items(items);
-        let iq = Iq::from_get(id, pubsub);
-        iq.into()
+        Iq::from_get(id, pubsub)
     }
 
     fn update(&self, bookmarks: &Vec<contact::Bookmark>) -> Element {
         let id = Uuid::new_v4().to_hyphenated().to_string();
-        let confs = bookmarks
-            .iter()
-            .map(|bookmark| bookmarks::Conference {
-                autojoin: match bookmark.autojoin {
-                    true => bookmarks::Autojoin::True,
-                    false => bookmarks::Autojoin::False,
-                },
-                jid: bookmark.jid.clone(),
-                name: Some(bookmark.name.clone().unwrap_or(bookmark.jid.to_string())),
-                nick: bookmark.nick.clone(),
-                password: None,
-            })
-            .collect();
-        let storage = bookmarks::Storage {
-            conferences: confs,
-            urls: vec![],
-        };
         let item = Item {
             id: Some(ItemId(String::from("current"))),
-            payload: Some(storage.into()),
+            payload: Some(storage(bookmarks).into()),
             publisher: None,
         };
         let publish = Publish {
@@ -236,18 +271,7 @@ impl Bookmarks {
         for item in items {
             if let Some(el) = item.payload.clone() {
                 if let Ok(storage) = bookmarks::Storage::try_from(el) {
-                    for conf in storage.conferences {
-                        let bookmark = contact::Bookmark {
-                            jid: conf.jid.clone(),
-                            name: conf.name.clone(),
-                            nick: conf.nick.clone(),
-                            password: conf.password.clone(),
-                            autojoin: conf.autojoin == bookmarks::Autojoin::True,
-                            extensions: None,
-                        };
-
-                        bookmarks.push(bookmark);
-                    }
+                    bookmarks.extend(from_storage(storage));
                 }
             } else {
                 warn!("Missing storage element");
@@ -281,7 +305,7 @@ impl Bookmarks {
 struct Bookmarks2 {}
 
 impl Bookmarks2 {
-    pub fn retreive(&self) -> Element {
+    pub fn retreive(&self) -> Iq {
         let id = Uuid::new_v4().to_hyphenated().to_string();
         let items = Items {
             max_items: None,
@@ -290,8 +314,7 @@ impl Bookmarks2 {
             items: vec![],
         };
         let pubsub = PubSub::Items(items);
-        let iq = Iq::from_get(id, pubsub);
-        iq.into()
+        Iq::from_get(id, pubsub)
     }
 
     fn config_node_form(&self) -> DataForm {
@@ -500,8 +523,48 @@ impl Bookmarks2 {
     }
 }
 
+/// Legacy bookmarks kept in private XML storage, for servers without PEP and for clients not
+/// supporting native bookmarks yet
+struct PrivateBookmarks {}
+
+impl PrivateBookmarks {
+    fn query(storage: bookmarks::Storage) -> Element {
+        Element::builder("query", PRIVATE)
+            .append(Element::from(storage))
+            .build()
+    }
+
+    fn retreive(&self) -> Iq {
+        Iq {
+            from: None,
+            to: None,
+            id: Uuid::new_v4().to_hyphenated().to_string(),
+            payload: IqType::Get(Self::query(storage(&[]))),
+        }
+    }
+
+    fn update(&self, bookmarks: &[contact::Bookmark]) -> Element {
+        let iq = Iq {
+            from: None,
+            to: None,
+            id: Uuid::new_v4().to_hyphenated().to_string(),
+            payload: IqType::Set(Self::query(storage(bookmarks))),
+        };
+        iq.into()
+    }
+
+    fn handle(&self, query: Element) -> Option<Vec<contact::Bookmark>> {
+        let storage = query.get_child("storage", ns::BOOKMARKS)?;
+        bookmarks::Storage::try_from(storage.clone())
+            .map(from_storage)
+            .ok()
+    }
+}
+
 pub struct BookmarksMod {
     backend: Backend,
+    /// Legacy bookmarks written along the native ones when the server doesn't convert them
+    mirror: bool,
     pub bookmarks: Vec<contact::Bookmark>,
     pub bookmarks_by_name: HashMap<String, usize>,
     pub bookmarks_by_jid: HashMap<Jid, usize>,
@@ -511,16 +574,50 @@ impl BookmarksMod {
     pub fn new() -> Self {
         Self {
             backend: Backend::Bookmarks(Bookmarks {}),
+            mirror: false,
             bookmarks: vec![],
             bookmarks_by_name: HashMap::new(),
             bookmarks_by_jid: HashMap::new(),
         }
     }
 
-    fn retreive(&self) -> Element {
-        match &self.backend {
+    fn retreive(&self, aparte: &mut Aparte, account: &Account) {
+        let iq = match &self.backend {
             Backend::Bookmarks(backend) => backend.retreive(),
             Backend::Bookmarks2(backend) => backend.retreive(),
+            Backend::Private(backend) => backend.retreive(),
+        };
+        aparte.send_iq::<Self, _>(account, iq, |bookmarks, aparte, account, response| {
+            bookmarks.retreived(aparte, account, response)
+        });
+    }
+
+    fn retreived(&mut self, aparte: &mut Aparte, account: &Account, response: IqResponse) {
+        match (response, &self.backend) {
+            (Err(IqError::Stanza(_)), Backend::Bookmarks(_)) => {
+                // Without PEP, legacy bookmarks are in private storage
+                self.backend = Backend::Private(PrivateBookmarks {});
+                self.retreive(aparte, account);
+            }
+            (Err(err @ IqError::Stanza(_)), _) => {
+                aparte.log(format!("Cannot retrieve bookmarks of {}: {}", account, err));
+            }
+            (Ok(Some(query)), Backend::Private(backend)) => {
+                if let Some(bookmarks) = backend.handle(query) {
+                    self.received(aparte, account, bookmarks);
+                }
+            }
+            (Ok(Some(pubsub)), _) => {
+                if let Ok(PubSub::Items(items)) = PubSub::try_from(pubsub) {
+                    self.handle_bookmarks(
+                        aparte,
+                        account,
+                        &items.node,
+                        items.items.iter().cloned().map(|item| item.0).collect(),
+                    );
+                }
+            }
+            (Ok(None), _) | (Err(IqError::Disconnected | IqError::Timeout), _) => {}
         }
     }
 
@@ -528,16 +625,29 @@ impl BookmarksMod {
         match &self.backend {
             Backend::Bookmarks(backend) => backend.init(aparte),
             Backend::Bookmarks2(backend) => backend.init(aparte),
+            Backend::Private(_) => vec![],
         }
     }
 
-    fn add(&mut self, bookmark: contact::Bookmark) -> Element {
+    /// Requests storing a change, along with the legacy bookmarks if mirrored
+    fn updates(&self, update: Element) -> Vec<Element> {
+        let mut updates = vec![update];
+        if self.mirror {
+            updates.push(PrivateBookmarks {}.update(&self.bookmarks));
+        }
+        updates
+    }
+
+    fn add(&mut self, bookmark: contact::Bookmark) -> Vec<Element> {
         self.bookmarks.push(bookmark.clone());
+        self.update_indexes();
 
-        match &self.backend {
+        let update = match &self.backend {
             Backend::Bookmarks(backend) => backend.update(&self.bookmarks),
             Backend::Bookmarks2(backend) => backend.add(bookmark),
-        }
+            Backend::Private(backend) => backend.update(&self.bookmarks),
+        };
+        self.updates(update)
     }
 
     pub fn edit(
@@ -546,9 +656,9 @@ impl BookmarksMod {
         jid: Option<BareJid>,
         nick: Option<String>,
         autojoin: Option<bool>,
-    ) -> Option<Element> {
+    ) -> Option<Vec<Element>> {
         if let Some(index) = self.bookmarks_by_name.get(&name) {
-            let mut bookmark = self.bookmarks.get_mut(*index).unwrap();
+            let bookmark = self.bookmarks.get_mut(*index).unwrap();
             match jid {
                 Some(jid) => bookmark.jid = jid,
                 None => {}
@@ -563,29 +673,31 @@ impl BookmarksMod {
                 None => {}
             }
 
-            Some(match &self.backend {
+            let update = match &self.backend {
                 Backend::Bookmarks(backend) => backend.update(&self.bookmarks),
                 Backend::Bookmarks2(backend) => backend.add(bookmark.clone()),
-            })
+                Backend::Private(backend) => backend.update(&self.bookmarks),
+            };
+            Some(self.updates(update))
         } else {
             None
         }
     }
 
-    fn delete(&mut self, conference: BareJid) -> Option<(contact::Bookmark, Element)> {
+    fn delete(&mut self, conference: BareJid) -> Option<(contact::Bookmark, Vec<Element>)> {
         if let Some(index) = self.bookmarks.iter().position(|b| {
             (conference.node.is_none() && b.name == Some(conference.to_string()))
                 || (!conference.node.is_none() && b.jid == conference)
         }) {
             let bookmark = self.bookmarks.remove(index);
+            self.update_indexes();
 
-            Some((
-                bookmark,
-                match &self.backend {
-                    Backend::Bookmarks(backend) => backend.update(&self.bookmarks),
-                    Backend::Bookmarks2(backend) => backend.delete(conference),
-                },
-            ))
+            let update = match &self.backend {
+                Backend::Bookmarks(backend) => backend.update(&self.bookmarks),
+                Backend::Bookmarks2(backend) => backend.delete(conference),
+                Backend::Private(backend) => backend.update(&self.bookmarks),
+            };
+            Some((bookmark, self.updates(update)))
         } else {
             None
         }
@@ -619,7 +731,15 @@ impl BookmarksMod {
             (ns::BOOKMARKS2, Backend::Bookmarks2(backend)) => backend.handle(items.clone()),
             _ => return,
         };
+        self.received(aparte, account, bookmarks);
+    }
 
+    fn received(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        bookmarks: Vec<contact::Bookmark>,
+    ) {
         let added: Vec<contact::Bookmark> = bookmarks
             .iter()
             .filter(|bookmark| !self.bookmarks.contains(bookmark))
@@ -663,6 +783,13 @@ impl BookmarksMod {
             None => None,
         }
     }
+
+    pub fn get_by_jid(&self, jid: &BareJid) -> Option<contact::Bookmark> {
+        match self.bookmarks_by_jid.get(&Jid::Bare(jid.clone())) {
+            Some(index) => self.bookmarks.get(*index).cloned(),
+            None => None,
+        }
+    }
 }
 
 impl ModTrait for BookmarksMod {
@@ -679,30 +806,15 @@ impl ModTrait for BookmarksMod {
                     let disco = aparte.get_mod::<disco::DiscoMod>();
                     if disco.has_feature(account, ns::BOOKMARKS2) {
                         self.backend = Backend::Bookmarks2(Bookmarks2 {});
+                        self.mirror = !disco.has_feature(account, ns::BOOKMARKS2_COMPAT);
                     }
                 }
 
                 for elem in self.init_backend(aparte).drain(..) {
                     aparte.send(account, elem);
                 }
-                aparte.send(account, self.retreive());
+                self.retreive(aparte, account);
             }
-            Event::Iq(account, iq) => match iq.payload.clone() {
-                IqType::Result(Some(el)) => {
-                    if let Ok(PubSub::Items(items)) = PubSub::try_from(el) {
-                        match &items.node.0 as &str {
-                            ns::BOOKMARKS | ns::BOOKMARKS2 => self.handle_bookmarks(
-                                aparte,
-                                account,
-                                &items.node,
-                                items.items.iter().cloned().map(|item| item.0).collect(),
-                            ),
-                            _ => {}
-                        }
-                    }
-                }
-                _ => {}
-            },
             Event::PubSub(account, pubsub_event) => match pubsub_event {
                 PubSubEvent::PublishedItems { node, items } => match &node.0 as &str {
                     ns::BOOKMARKS | ns::BOOKMARKS2 => self.handle_bookmarks(
@@ -725,3 +837,103 @@ impl fmt::Display for BookmarksMod {
         write!(f, "XEP-0402: PEP Native Bookmarks")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+
+    #[test]
+    fn test_private_storage_is_used_without_pep() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            let unsupported = "<iq xmlns='jabber:client' type='error' id='{id}'>
+                <error type='cancel'>
+                    <feature-not-implemented xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+                </error>
+            </iq>";
+            // Neither the subscription nor the request of the legacy bookmarks in PEP succeed
+            harness
+                .reply("pubsub", ns::PUBSUB, unsupported)
+                .reply("pubsub", ns::PUBSUB, unsupported)
+                .reply(
+                    "query",
+                    PRIVATE,
+                    "<iq xmlns='jabber:client' type='result' id='{id}'>
+                        <query xmlns='jabber:iq:private'>
+                            <storage xmlns='storage:bookmarks'>
+                                <conference jid='ball@conference.example.org' autojoin='true'>
+                                    <nick>romeo</nick>
+                                </conference>
+                            </storage>
+                        </query>
+                    </iq>",
+                );
+
+            // When
+            harness.connect().await;
+            harness
+                .aparte
+                .schedule(Event::Disco(harness.account.clone()));
+            harness.settle().await;
+
+            // Then
+            let presences = harness.take_sent("x", ns::MUC);
+            assert_eq!(presences.len(), 1);
+            assert_eq!(
+                presences[0].attr("to"),
+                Some("ball@conference.example.org/romeo")
+            );
+            let bookmarks = harness.aparte.get_mod::<BookmarksMod>();
+            assert!(bookmarks
+                .get_by_jid(&BareJid::from_str("ball@conference.example.org").unwrap())
+                .is_some());
+        });
+    }
+
+    #[test]
+    fn test_native_bookmarks_are_mirrored_without_compat() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.reply(
+                "query",
+                ns::DISCO_INFO,
+                "<iq xmlns='jabber:client' type='result' id='{id}' from='example.org'>
+                    <query xmlns='http://jabber.org/protocol/disco#info'>
+                        <identity category='server' type='im'/>
+                        <feature var='http://jabber.org/protocol/disco#info'/>
+                        <feature var='urn:xmpp:bookmarks:1'/>
+                    </query>
+                </iq>",
+            );
+            harness.connect().await;
+            harness.sent.clear();
+
+            // When
+            harness
+                .input(
+                    "console",
+                    "/bookmark add ball ball@conference.example.org autojoin=on",
+                )
+                .await;
+
+            // Then
+            let publish = harness.take_sent("pubsub", ns::PUBSUB);
+            assert_eq!(publish.len(), 1);
+            let item = publish[0]
+                .get_child("pubsub", ns::PUBSUB)
+                .and_then(|pubsub| pubsub.get_child("publish", ns::PUBSUB))
+                .unwrap();
+            assert_eq!(item.attr("node"), Some(ns::BOOKMARKS2));
+            let private = harness.take_sent("query", PRIVATE);
+            assert_eq!(private.len(), 1);
+            let storage = private[0]
+                .get_child("query", PRIVATE)
+                .and_then(|query| query.get_child("storage", ns::BOOKMARKS))
+                .unwrap();
+            assert_eq!(storage.children().count(), 1);
+        });
+    }
+}
@@ -0,0 +1,352 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Browse entities with service discovery (XEP-0030), like the channel services, pubsub nodes and
+//! gateways of a server.
+//!
+//! The info and items of an entity are listed in the disco window, where an item is discovered in
+//! turn by selecting it. The entities discovered since the one given to /disco are kept to go back
+//! to them.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::disco::{
+    DiscoInfoQuery, DiscoInfoResult, DiscoItemsQuery, DiscoItemsResult, Item,
+};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::stanza_error::StanzaError;
+use xmpp_parsers::{Element, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::mods;
+use crate::mods::ui::{DiscoLine, DISCO_WINDOW};
+
+command_def!(disco,
+r#"/disco <jid> [<node>]
+
+    jid           Entity to discover, like a server or a channel service
+    node          Node of the entity, like a pubsub node
+
+Description:
+    List the identities, features and items of an entity in the disco
+    window. Select an item with Up and Down, or Ctrl-p and Ctrl-n, then press
+    Right or Enter to discover it, and Left to go back.
+
+Examples:
+    /disco example.org
+    /disco pubsub.example.org princely_musings"#,
+{
+    jid: Jid,
+    node: Option<String>
+},
+|aparte, command| {
+    let account = command.account.clone().ok_or("No connection found".to_string())?;
+    let request = {
+        let mut browse = aparte.get_mod_mut::<BrowseMod>();
+        browse.discover(&account, jid, node, command.context == DISCO_WINDOW)
+    };
+    aparte.send(&account, request);
+    Ok(())
+});
+
+/// Entity discovered, with its node if any
+#[derive(Debug, Clone, PartialEq)]
+struct Target {
+    jid: Jid,
+    node: Option<String>,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.node {
+            Some(node) => write!(f, "{} node {}", self.jid, node),
+            None => write!(f, "{}", self.jid),
+        }
+    }
+}
+
+/// Discovery in progress, the items of the entity being asked once its info is received
+struct Discovery {
+    account: Account,
+    target: Target,
+    /// None until received
+    info: Option<Result<DiscoInfoResult, String>>,
+}
+
+/// Text of an error returned by the entity
+fn error_text(err: &StanzaError) -> String {
+    match err.texts.get("en") {
+        Some(text) => text.clone(),
+        None => format!("{:?}", err.defined_condition),
+    }
+}
+
+/// Lines of the disco window for the info and items of an entity, items leading to their own
+/// discovery
+fn lines(
+    target: &Target,
+    info: &Result<DiscoInfoResult, String>,
+    items: &Result<Vec<Item>, String>,
+) -> Vec<DiscoLine> {
+    let mut lines = Vec::new();
+    let mut push = |text: String, target: Option<(Jid, Option<String>)>| {
+        lines.push(DiscoLine {
+            index: lines.len(),
+            text,
+            target,
+        })
+    };
+    push(target.to_string(), None);
+    match info {
+        Ok(info) => {
+            push(String::from("Identities:"), None);
+            for identity in info.identities.iter() {
+                let line = match &identity.name {
+                    Some(name) => format!("  {}/{}: {}", identity.category, identity.type_, name),
+                    None => format!("  {}/{}", identity.category, identity.type_),
+                };
+                push(line, None);
+            }
+            push(String::from("Features:"), None);
+            for feature in info.features.iter() {
+                push(format!("  {}", feature.var), None);
+            }
+        }
+        Err(err) => push(format!("No info: {}", err), None),
+    }
+    match items {
+        Ok(items) if items.is_empty() => push(String::from("No items"), None),
+        Ok(items) => {
+            push(String::from("Items:"), None);
+            for item in items.iter() {
+                let target = Target {
+                    jid: item.jid.clone(),
+                    node: item.node.clone(),
+                };
+                let line = match &item.name {
+                    Some(name) => format!("  {}: {}", target, name),
+                    None => format!("  {}", target),
+                };
+                push(line, Some((target.jid, target.node)));
+            }
+        }
+        Err(err) => push(format!("No items: {}", err), None),
+    }
+    lines
+}
+
+pub struct BrowseMod {
+    /// Pending queries by iq id
+    discoveries: HashMap<String, Discovery>,
+    /// Entities discovered since the one given to /disco, the current one last
+    trail: Vec<Target>,
+}
+
+impl BrowseMod {
+    pub fn new() -> Self {
+        Self {
+            discoveries: HashMap::new(),
+            trail: Vec::new(),
+        }
+    }
+
+    /// Ask the info of an entity, discovered from the disco window or from a new /disco
+    fn discover(
+        &mut self,
+        account: &Account,
+        jid: Jid,
+        node: Option<String>,
+        browsing: bool,
+    ) -> Element {
+        let target = Target { jid, node };
+        let len = self.trail.len();
+        match browsing {
+            true if len >= 2 && self.trail[len - 2] == target => {
+                self.trail.pop();
+            }
+            true => self.trail.push(target.clone()),
+            false => self.trail = vec![target.clone()],
+        }
+
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let query = DiscoInfoQuery {
+            node: target.node.clone(),
+        };
+        let iq = Iq::from_get(id.clone(), query).with_to(target.jid.clone());
+        self.discoveries.insert(
+            id,
+            Discovery {
+                account: account.clone(),
+                target,
+                info: None,
+            },
+        );
+        iq.into()
+    }
+
+    /// Entity discovered before the current one
+    fn back(&self) -> Option<(Jid, Option<String>)> {
+        match self.trail.len() {
+            len if len >= 2 => {
+                let target = self.trail[len - 2].clone();
+                Some((target.jid, target.node))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl ModTrait for BrowseMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(disco::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        if let Event::Iq(_, iq) = event {
+            let mut discovery = match self.discoveries.remove(&iq.id) {
+                Some(discovery) => discovery,
+                None => return,
+            };
+
+            match discovery.info.take() {
+                None => {
+                    discovery.info = Some(match iq.payload.clone() {
+                        IqType::Result(Some(info)) => {
+                            DiscoInfoResult::try_from(info).map_err(|err| err.to_string())
+                        }
+                        IqType::Error(err) => Err(error_text(&err)),
+                        _ => Err("empty answer".to_string()),
+                    });
+                    let id = Uuid::new_v4().to_hyphenated().to_string();
+                    let query = DiscoItemsQuery {
+                        node: discovery.target.node.clone(),
+                    };
+                    let items =
+                        Iq::from_get(id.clone(), query).with_to(discovery.target.jid.clone());
+                    aparte.send(&discovery.account, items.into());
+                    self.discoveries.insert(id, discovery);
+                }
+                Some(info) => {
+                    let items = match iq.payload.clone() {
+                        IqType::Result(Some(items)) => DiscoItemsResult::try_from(items)
+                            .map(|items| items.items)
+                            .map_err(|err| err.to_string()),
+                        IqType::Error(err) => Err(error_text(&err)),
+                        _ => Ok(Vec::new()),
+                    };
+                    let lines = lines(&discovery.target, &info, &items);
+                    {
+                        let mut ui = aparte.get_mod_mut::<mods::ui::UIMod>();
+                        ui.show_disco(&discovery.account, lines, self.back());
+                    }
+                    // The UI may have handled this event already, it is laid out again on the next
+                    aparte.schedule(Event::WindowChange);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for BrowseMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Service discovery browser")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+    use termion::event::Key;
+    use xmpp_parsers::ns;
+
+    const SERVER: &str =
+        "<iq xmlns='jabber:client' type='result' id='{id}' from='verona.example.org' to='{account}'>
+        <query xmlns='http://jabber.org/protocol/disco#info'>
+            <identity category='server' type='im' name='Verona'/>
+            <feature var='http://jabber.org/protocol/disco#info'/>
+        </query>
+    </iq>";
+
+    const SERVER_ITEMS: &str =
+        "<iq xmlns='jabber:client' type='result' id='{id}' from='verona.example.org' to='{account}'>
+        <query xmlns='http://jabber.org/protocol/disco#items'>
+            <item jid='conference.verona.example.org' name='Chatrooms'/>
+            <item jid='pubsub.verona.example.org' name='Publish-Subscribe'/>
+        </query>
+    </iq>";
+
+    const UNAVAILABLE: &str =
+        "<iq xmlns='jabber:client' type='error' id='{id}' from='pubsub.verona.example.org' to='{account}'>
+        <error type='cancel'>
+            <service-unavailable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+        </error>
+    </iq>";
+
+    #[test]
+    fn test_info_and_items_are_listed() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness.reply("query", ns::DISCO_INFO, SERVER).reply(
+                "query",
+                ns::DISCO_ITEMS,
+                SERVER_ITEMS,
+            );
+
+            // When
+            harness.input("console", "/disco verona.example.org").await;
+
+            // Then
+            let screen = harness.screen();
+            assert!(screen.contains("server/im: Verona"));
+            assert!(screen.contains("http://jabber.org/protocol/disco#info"));
+            assert!(screen.contains("conference.verona.example.org: Chatrooms"));
+        });
+    }
+
+    #[test]
+    fn test_items_are_discovered_with_arrow_keys_and_left_goes_back() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness.reply("query", ns::DISCO_INFO, SERVER).reply(
+                "query",
+                ns::DISCO_ITEMS,
+                SERVER_ITEMS,
+            );
+            harness.input("console", "/disco verona.example.org").await;
+            harness.take_sent("query", ns::DISCO_INFO);
+            harness.reply("query", ns::DISCO_INFO, UNAVAILABLE).reply(
+                "query",
+                ns::DISCO_ITEMS,
+                UNAVAILABLE,
+            );
+
+            // When
+            for key in [Key::Down, Key::Down, Key::Right].iter() {
+                harness.aparte.schedule(Event::Key(*key));
+                harness.settle().await;
+            }
+            let screen = harness.screen();
+            harness.aparte.schedule(Event::Key(Key::Left));
+            harness.settle().await;
+            let back = harness.take_sent("query", ns::DISCO_INFO);
+
+            // Then
+            assert!(screen.contains("No info: ServiceUnavailable"));
+            assert!(screen.contains("No items: ServiceUnavailable"));
+            assert_eq!(back.len(), 1);
+            assert_eq!(back[0].attr("to"), Some("verona.example.org"));
+            let browse = harness.aparte.get_mod::<BrowseMod>();
+            assert_eq!(browse.trail.len(), 1);
+        });
+    }
+}
@@ -0,0 +1,430 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! SOCKS5 bytestreams (XEP-0065) relayed by the proxies of the server, carrying the files sent
+//! to contacts that cannot connect to each other directly, see `filetransfer`.
+//!
+//! Proxies are found among the services of the server discovered once connected, then asked for
+//! the address they listen on.
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+use uuid::Uuid;
+use xmpp_parsers::disco::DiscoInfoResult;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::{Element, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::Message;
+
+const BYTESTREAMS: &str = "http://jabber.org/protocol/bytestreams";
+/// Time given to a proxy or a peer to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+command_def!(
+    proxies,
+    r#"/proxies
+
+Description:
+    List the SOCKS5 proxies (XEP-0065) offered by the server of the current
+    account, with the address they listen on."#,
+    {},
+    |aparte, _command| {
+        let account = aparte
+            .current_account()
+            .ok_or("No connection found".to_string())?;
+        let lines = {
+            let bytestreams = aparte.get_mod::<BytestreamsMod>();
+            bytestreams
+                .proxies(&account)
+                .iter()
+                .map(|proxy| {
+                    let streamhost = &proxy.streamhost;
+                    format!(
+                        "  {} ({}:{})",
+                        streamhost.jid, streamhost.host, streamhost.port
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        match lines.is_empty() {
+            true => aparte.log(format!("No SOCKS5 proxy found on {}", account.domain)),
+            false => aparte.log(format!(
+                "SOCKS5 proxies of {}:\n{}",
+                account.domain,
+                lines.join("\n")
+            )),
+        }
+        Ok(())
+    }
+);
+
+/// Address a SOCKS5 proxy listens on
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamHost {
+    pub jid: Jid,
+    pub host: String,
+    pub port: u16,
+}
+
+/// Address given by a proxy in answer to a bytestreams query
+fn parse_streamhost(query: &Element) -> Option<StreamHost> {
+    if !query.is("query", BYTESTREAMS) {
+        return None;
+    }
+    let streamhost = query.get_child("streamhost", BYTESTREAMS)?;
+    Some(StreamHost {
+        jid: Jid::from_str(streamhost.attr("jid")?).ok()?,
+        host: streamhost.attr("host")?.to_string(),
+        // 1080 is the default SOCKS5 port
+        port: match streamhost.attr("port") {
+            Some(port) => port.parse().ok()?,
+            None => 1080,
+        },
+    })
+}
+
+/// SOCKS5 proxy of the server, along with the address its host resolves to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proxy {
+    pub streamhost: StreamHost,
+    pub address: SocketAddr,
+}
+
+/// Destination address identifying a bytestream to SOCKS5 proxies, from its sid and the full jids
+/// of the entity requesting it then of its target
+pub fn dstaddr(sid: &str, requester: &Jid, target: &Jid) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.input_str(&format!("{}{}{}", sid, requester, target));
+    sha1.result_str()
+}
+
+/// Ask a proxy to relay the bytestream with the given sid, once both ends are connected to it
+pub fn activate(proxy: Jid, sid: &str, target: &Jid) -> Iq {
+    let query = Element::builder("query", BYTESTREAMS)
+        .attr("sid", sid)
+        .append(
+            Element::builder("activate", BYTESTREAMS)
+                .append(target.to_string())
+                .build(),
+        )
+        .build();
+    Iq {
+        from: None,
+        to: Some(proxy),
+        id: Uuid::new_v4().to_hyphenated().to_string(),
+        payload: IqType::Set(query),
+    }
+}
+
+/// Open a bytestream through a SOCKS5 proxy or peer, identified by its destination address
+pub async fn connect(address: SocketAddr, dstaddr: &str) -> Result<TcpStream, String> {
+    match time::timeout(CONNECT_TIMEOUT, handshake(address, dstaddr)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(err)) => Err(format!("Cannot connect to {}: {}", address, err)),
+        Err(_) => Err(format!("Cannot connect to {}: no answer", address)),
+    }
+}
+
+async fn handshake(address: SocketAddr, dstaddr: &str) -> Result<TcpStream, String> {
+    let mut stream = TcpStream::connect(address)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // SOCKS version 5, offering a single authentication method: none
+    stream
+        .write_all(&[5, 1, 0])
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut method = [0u8; 2];
+    stream
+        .read_exact(&mut method)
+        .await
+        .map_err(|e| e.to_string())?;
+    if method != [5, 0] {
+        return Err(String::from("authentication required"));
+    }
+
+    // CONNECT to the destination address as a domain name, on port 0
+    let mut request = vec![5, 1, 0, 3, dstaddr.len() as u8];
+    request.extend_from_slice(dstaddr.as_bytes());
+    request.extend_from_slice(&[0, 0]);
+    stream
+        .write_all(&request)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut reply = [0u8; 5];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .map_err(|e| e.to_string())?;
+    if reply[1] != 0 {
+        return Err(format!("connection refused ({})", reply[1]));
+    }
+
+    // Bound address and port follow, the last byte read being the first of the address
+    let remaining = match reply[3] {
+        1 => 4 - 1 + 2,
+        3 => reply[4] as usize + 2,
+        4 => 16 - 1 + 2,
+        _ => return Err(String::from("invalid reply")),
+    };
+    let mut bound = vec![0u8; remaining];
+    stream
+        .read_exact(&mut bound)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(stream)
+}
+
+enum Query {
+    Address,
+}
+
+pub struct BytestreamsMod {
+    /// SOCKS5 proxies of the server of each account
+    proxies: HashMap<Account, Vec<Proxy>>,
+    /// Pending queries by iq id
+    queries: HashMap<String, Query>,
+}
+
+impl BytestreamsMod {
+    pub fn new() -> Self {
+        Self {
+            proxies: HashMap::new(),
+            queries: HashMap::new(),
+        }
+    }
+
+    /// SOCKS5 proxies found on the server of an account
+    pub fn proxies(&self, account: &Account) -> &[Proxy] {
+        self.proxies
+            .get(account)
+            .map(|proxies| proxies.as_slice())
+            .unwrap_or(&[])
+    }
+
+    fn query(&mut self, query: Query, iq: Iq) -> Element {
+        self.queries.insert(iq.id.clone(), query);
+        iq.into()
+    }
+
+    fn handle_service(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        jid: &Jid,
+        info: &DiscoInfoResult,
+    ) {
+        let proxy = info
+            .identities
+            .iter()
+            .any(|identity| identity.category == "proxy" && identity.type_ == "bytestreams");
+        if proxy {
+            let id = Uuid::new_v4().to_hyphenated().to_string();
+            let iq = Iq {
+                from: None,
+                to: Some(jid.clone()),
+                id,
+                payload: IqType::Get(Element::builder("query", BYTESTREAMS).build()),
+            };
+            let iq = self.query(Query::Address, iq);
+            aparte.send(account, iq);
+        }
+    }
+
+    fn handle_address(&mut self, aparte: &mut Aparte, account: &Account, query: Element) {
+        let streamhost = match parse_streamhost(&query) {
+            Some(streamhost) => streamhost,
+            None => {
+                warn!("Invalid SOCKS5 proxy address: {}", String::from(&query));
+                return;
+            }
+        };
+        // Candidates offered to peers are given by address
+        let resolver = match aparte.resolver() {
+            Ok(resolver) => resolver,
+            Err(err) => {
+                warn!("Cannot resolve SOCKS5 proxy {}: {}", streamhost.host, err);
+                return;
+            }
+        };
+        let account = account.clone();
+        aparte.spawn(async move {
+            match resolver
+                .lookup_host(&streamhost.host, streamhost.port)
+                .await
+            {
+                Ok(addresses) if !addresses.is_empty() => Event::Proxy(
+                    account,
+                    Proxy {
+                        address: addresses[0],
+                        streamhost,
+                    },
+                ),
+                Ok(_) => Event::Message(
+                    None,
+                    Message::log(format!("Cannot resolve SOCKS5 proxy {}", streamhost.host)),
+                ),
+                Err(err) => Event::Message(None, Message::log(err)),
+            }
+        });
+    }
+
+    fn handle_proxy(&mut self, account: &Account, proxy: &Proxy) {
+        let proxies = self.proxies.entry(account.clone()).or_default();
+        if !proxies.contains(proxy) {
+            proxies.push(proxy.clone());
+        }
+    }
+}
+
+impl ModTrait for BytestreamsMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(proxies::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Connected(account, _) => {
+                self.proxies.remove(account);
+            }
+            Event::Service(account, jid, info) => self.handle_service(aparte, account, jid, info),
+            Event::Proxy(account, proxy) => self.handle_proxy(account, proxy),
+            Event::Disconnected(account, _) => {
+                self.proxies.remove(account);
+            }
+            Event::Iq(account, iq) => {
+                let query = match self.queries.remove(&iq.id) {
+                    Some(query) => query,
+                    None => return,
+                };
+
+                if let (Query::Address, IqType::Result(Some(query))) = (query, iq.payload.clone()) {
+                    self.handle_address(aparte, account, query)
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for BytestreamsMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0065: SOCKS5 Bytestreams")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+    use xmpp_parsers::ns;
+
+    #[test]
+    fn test_proxies_of_the_server_are_discovered() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness
+                .reply(
+                    "query",
+                    ns::DISCO_ITEMS,
+                    "<iq xmlns='jabber:client' type='result' id='{id}' from='example.org'
+                        to='{account}'>
+                        <query xmlns='http://jabber.org/protocol/disco#items'>
+                            <item jid='proxy.example.org'/>
+                        </query>
+                    </iq>",
+                )
+                .reply(
+                    "query",
+                    ns::DISCO_INFO,
+                    "<iq xmlns='jabber:client' type='result' id='{id}'
+                        from='proxy.example.org' to='{account}'>
+                        <query xmlns='http://jabber.org/protocol/disco#info'>
+                            <identity category='proxy' type='bytestreams' name='SOCKS5'/>
+                            <feature var='http://jabber.org/protocol/disco#info'/>
+                            <feature var='http://jabber.org/protocol/bytestreams'/>
+                        </query>
+                    </iq>",
+                );
+            harness.reply(
+                "query",
+                BYTESTREAMS,
+                "<iq xmlns='jabber:client' type='result' id='{id}' from='proxy.example.org'
+                    to='{account}'>
+                    <query xmlns='http://jabber.org/protocol/bytestreams'>
+                        <streamhost jid='proxy.example.org' host='192.0.2.1' port='7777'/>
+                    </query>
+                </iq>",
+            );
+
+            // When
+            harness.connect().await;
+            harness.input("console", "/proxies").await;
+
+            // Then
+            assert!(harness
+                .screen()
+                .contains("proxy.example.org (192.0.2.1:7777)"));
+        });
+    }
+
+    #[test]
+    fn test_dstaddr() {
+        // Example of XEP-0065
+        let requester = Jid::from_str("romeo@montague.lit/orchard").unwrap();
+        let target = Jid::from_str("juliet@capulet.lit/balcony").unwrap();
+        assert_eq!(
+            dstaddr("vj3hs98y", &requester, &target),
+            "972b7bf47291ca609517f67f86b5081086052dad"
+        );
+    }
+
+    #[test]
+    fn test_connection_asks_the_proxy_for_the_stream() {
+        testing::run(async {
+            // Given
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let proxy = tokio::task::spawn_local(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut greeting = [0u8; 3];
+                socket.read_exact(&mut greeting).await.unwrap();
+                socket.write_all(&[5, 0]).await.unwrap();
+                let mut request = [0u8; 5 + 40 + 2];
+                socket.read_exact(&mut request).await.unwrap();
+                let mut reply = vec![5, 0, 0, 3, 40];
+                reply.extend_from_slice(&request[5..]);
+                socket.write_all(&reply).await.unwrap();
+                socket.write_all(b"hello").await.unwrap();
+                (greeting, request)
+            });
+
+            // When
+            let dstaddr = "972b7bf47291ca609517f67f86b5081086052dad";
+            let mut stream = connect(address, dstaddr).await.unwrap();
+
+            // Then
+            let (greeting, request) = proxy.await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            assert_eq!(request[..5], [5, 1, 0, 3, 40]);
+            assert_eq!(&request[5..45], dstaddr.as_bytes());
+            assert_eq!(request[45..], [0, 0]);
+            let mut data = String::new();
+            stream.read_to_string(&mut data).await.unwrap();
+            assert_eq!(data, "hello");
+        });
+    }
+}
@@ -0,0 +1,380 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::process;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
+use xmpp_parsers::message::Message as XmppParsersMessage;
+use xmpp_parsers::{ns, Element, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::graphics;
+use crate::mods;
+use crate::mods::bob::{self, Bob};
+
+const CAPTCHA: &str = "urn:xmpp:captcha";
+
+/// Lines taken by a challenge image displayed in the console
+const IMAGE_LINES: usize = 6;
+
+command_def!(captcha,
+r#"/captcha <answer> [<challenge>]
+
+    answer        Answer to the challenge
+    challenge     Challenge to answer, the last one received if omitted
+
+Description:
+    Answer a CAPTCHA (XEP-0158) sent by a server or a room, usually before
+    being allowed to join or talk in it.
+
+Examples:
+    /captcha 7xk4p
+    /captcha "seven" F3A6292C"#,
+{
+    answer: String,
+    challenge: Option<String> = {
+        completion: (|aparte, _command| {
+            let captcha = aparte.get_mod::<CaptchaMod>();
+            captcha.challenges.iter().map(|(id, _)| id.clone()).collect()
+        })
+    }
+},
+|aparte, _command| {
+    let (account, from, iq) = {
+        let mut captcha = aparte.get_mod_mut::<CaptchaMod>();
+        captcha.answer(&challenge, &answer)?
+    };
+    aparte.send(&account, iq);
+    aparte.log(format!("Answer sent to {}", from));
+    Ok(())
+});
+
+struct Challenge {
+    account: Account,
+    from: Jid,
+    form: DataForm,
+}
+
+/// Field the user has to fill, the others are either hidden or informative
+fn question(form: &DataForm) -> Option<&Field> {
+    form.fields
+        .iter()
+        .find(|field| field.type_ != FieldType::Hidden && field.type_ != FieldType::Fixed)
+}
+
+/// Form sent back with the answer, hidden fields are returned untouched
+fn response(form: &DataForm, answer: &str) -> Result<DataForm, String> {
+    let question = question(form).ok_or("Nothing to answer in this challenge".to_string())?;
+    let fields = form
+        .fields
+        .iter()
+        .filter_map(|field| match field.type_ {
+            FieldType::Hidden => Some(field.clone()),
+            _ if field.var == question.var => Some(Field {
+                values: vec![answer.to_string()],
+                media: Vec::new(),
+                ..field.clone()
+            }),
+            _ => None,
+        })
+        .collect();
+
+    Ok(DataForm {
+        type_: DataFormType::Submit,
+        form_type: Some(CAPTCHA.to_string()),
+        title: None,
+        instructions: None,
+        fields,
+    })
+}
+
+pub struct CaptchaMod {
+    /// Challenges waiting for an answer, by challenge id, last received last
+    challenges: Vec<(String, Challenge)>,
+    /// Challenges waiting for their image, by content id
+    waiting: HashMap<String, String>,
+}
+
+impl CaptchaMod {
+    pub fn new() -> Self {
+        Self {
+            challenges: Vec::new(),
+            waiting: HashMap::new(),
+        }
+    }
+
+    fn answer(
+        &mut self,
+        challenge: &Option<String>,
+        answer: &str,
+    ) -> Result<(Account, Jid, Element), String> {
+        let index = match challenge {
+            Some(challenge) => self
+                .challenges
+                .iter()
+                .position(|(id, _)| id == challenge)
+                .ok_or(format!("Unknown challenge {}", challenge))?,
+            None => self
+                .challenges
+                .len()
+                .checked_sub(1)
+                .ok_or("No pending challenge".to_string())?,
+        };
+
+        let form = response(&self.challenges[index].1.form, answer)?;
+        let (_, challenge) = self.challenges.remove(index);
+        let iq = Element::builder("iq", ns::DEFAULT_NS)
+            .attr("type", "set")
+            .attr("id", Uuid::new_v4().to_hyphenated().to_string())
+            .attr("to", challenge.from.to_string())
+            .append(
+                Element::builder("captcha", CAPTCHA)
+                    .append(Element::from(form))
+                    .build(),
+            )
+            .build();
+        Ok((challenge.account, challenge.from, iq))
+    }
+
+    fn handle_message(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: XmppParsersMessage,
+    ) {
+        let (from, form) = match (
+            &message.from,
+            message.payloads.iter().find(|p| p.is("captcha", CAPTCHA)),
+        ) {
+            (Some(from), Some(captcha)) => {
+                let form = captcha
+                    .get_child("x", ns::DATA_FORMS)
+                    .map(|x| DataForm::try_from(x.clone()));
+                match form {
+                    Some(Ok(form)) => (from.clone(), form),
+                    _ => {
+                        aparte.log(format!("Invalid CAPTCHA received from {}", from));
+                        return;
+                    }
+                }
+            }
+            _ => return,
+        };
+
+        // Images are usually attached to the challenge
+        {
+            let mut bob = aparte.get_mod_mut::<mods::bob::BobMod>();
+            bob.collect(&message);
+        }
+
+        let id = form
+            .fields
+            .iter()
+            .find(|field| field.var == "challenge")
+            .and_then(|field| field.values.first().cloned())
+            .or_else(|| message.id.clone())
+            .unwrap_or_else(|| Uuid::new_v4().to_hyphenated().to_string());
+
+        let question = match question(&form) {
+            Some(question) => question.clone(),
+            None => {
+                aparte.log(format!("{} sent a CAPTCHA without question", from));
+                return;
+            }
+        };
+
+        let mut lines = vec![format!(
+            "{} asks you to solve a CAPTCHA: {}",
+            from,
+            question
+                .label
+                .clone()
+                .unwrap_or_else(|| question.var.clone())
+        )];
+        lines.extend(form.instructions.clone());
+
+        let protocol = {
+            let ui = aparte.get_mod::<mods::ui::UIMod>();
+            ui.graphics()
+        };
+        for media in &question.media {
+            for uri in &media.uris {
+                let cid = match bob::cid(&uri.uri) {
+                    Some(cid) => cid,
+                    None => {
+                        lines.push(format!("See {}", uri.uri));
+                        continue;
+                    }
+                };
+                let (cached, request) = {
+                    let mut bob = aparte.get_mod_mut::<mods::bob::BobMod>();
+                    match bob.get(cid) {
+                        Some(image) => (Some(image), None),
+                        None => (None, bob.request(&from, cid)),
+                    }
+                };
+                match cached {
+                    Some(image) => lines.extend(self.show(aparte, &id, &image, protocol)),
+                    None => {
+                        if let Some(request) = request {
+                            aparte.send(account, request);
+                        }
+                        self.waiting.insert(cid.to_string(), id.clone());
+                        lines.push("Fetching the image…".to_string());
+                    }
+                }
+            }
+        }
+        lines.push(format!("Answer with /captcha <answer> {}", id));
+        aparte.log(lines.join("\n"));
+
+        self.challenges.retain(|(pending, _)| pending != &id);
+        self.challenges.push((
+            id,
+            Challenge {
+                account: account.clone(),
+                from,
+                form,
+            },
+        ));
+    }
+
+    /// Display an image in the console, or in the configured viewer when the terminal can't
+    fn show(
+        &self,
+        aparte: &Aparte,
+        id: &str,
+        image: &Bob,
+        protocol: graphics::Protocol,
+    ) -> Vec<String> {
+        if let Some(lines) =
+            graphics::render_encoded(&image.data, &image.type_, protocol, IMAGE_LINES)
+        {
+            return lines;
+        }
+
+        let extension = image.type_.rsplit('/').next().unwrap_or("bin");
+        let path = std::env::temp_dir().join(format!("aparte-captcha-{}.{}", id, extension));
+        if let Err(err) = fs::write(&path, &image.data) {
+            return vec![format!("Cannot save the CAPTCHA image: {}", err)];
+        }
+
+        match &aparte.config.image_viewer {
+            Some(viewer) => {
+                let spawned = process::Command::new("sh")
+                    .arg("-c")
+                    .arg(format!("{} \"$1\"", viewer))
+                    .arg("sh")
+                    .arg(&path)
+                    .stdout(process::Stdio::null())
+                    .stderr(process::Stdio::null())
+                    .spawn();
+                match spawned {
+                    Ok(_) => vec![format!("Image opened with {}", viewer)],
+                    Err(err) => vec![format!("Cannot open image with {}: {}", viewer, err)],
+                }
+            }
+            None => vec![format!("Image saved to {}", path.display())],
+        }
+    }
+}
+
+impl ModTrait for CaptchaMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(captcha::new());
+        let mut disco = aparte.get_mod_mut::<mods::disco::DiscoMod>();
+        disco.add_feature(CAPTCHA)
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Stanza(account, stanza) if stanza.is("message", ns::DEFAULT_NS) => {
+                if let Ok(message) = XmppParsersMessage::try_from(stanza.clone()) {
+                    self.handle_message(aparte, account, message);
+                }
+            }
+            Event::Bob(cid) if self.waiting.contains_key(cid) => {
+                let id = self.waiting.remove(cid).unwrap();
+                let (image, protocol) = {
+                    let mut bob = aparte.get_mod_mut::<mods::bob::BobMod>();
+                    let ui = aparte.get_mod::<mods::ui::UIMod>();
+                    (bob.get(cid), ui.graphics())
+                };
+                if let Some(image) = image {
+                    let lines = self.show(aparte, &id, &image, protocol);
+                    aparte.log(format!("Image of challenge {}:\n{}", id, lines.join("\n")));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for CaptchaMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0158: CAPTCHA Forms")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(var: &str, type_: FieldType, values: Vec<&str>) -> Field {
+        Field {
+            var: var.to_string(),
+            type_,
+            label: None,
+            required: false,
+            options: Vec::new(),
+            values: values.into_iter().map(String::from).collect(),
+            media: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_response_fills_the_question_and_keeps_hidden_fields() {
+        // Given
+        let form = DataForm {
+            type_: DataFormType::Form,
+            form_type: Some(CAPTCHA.to_string()),
+            title: None,
+            instructions: None,
+            fields: vec![
+                field(
+                    "from",
+                    FieldType::Hidden,
+                    vec!["room@conference.example.org"],
+                ),
+                field("challenge", FieldType::Hidden, vec!["F3A6292C"]),
+                field("ocr", FieldType::TextSingle, vec![]),
+                field("info", FieldType::Fixed, vec!["Type the letters"]),
+            ],
+        };
+
+        // When
+        let response = response(&form, "7xk4p").unwrap();
+
+        // Then
+        assert_eq!(response.type_, DataFormType::Submit);
+        assert_eq!(
+            response
+                .fields
+                .iter()
+                .map(|field| (field.var.as_str(), field.values.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("from", vec![String::from("room@conference.example.org")]),
+                ("challenge", vec![String::from("F3A6292C")]),
+                ("ocr", vec![String::from("7xk4p")]),
+            ]
+        );
+    }
+}
@@ -88,3 +88,62 @@ impl fmt::Display for CarbonsMod {
         write!(f, "XEP-0280: Message Carbons")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, LayoutConfig};
+    use crate::message::Direction;
+    use crate::message::Message;
+    use crate::mods::messages::MessagesMod;
+    use crate::testing::{self, Harness};
+    use std::str::FromStr;
+    use xmpp_parsers::BareJid;
+
+    #[test]
+    fn test_sent_carbons_are_routed_to_the_recipient_conversation() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::with_config(Config {
+                layout: LayoutConfig {
+                    roster_min_width: 80,
+                    ..LayoutConfig::default()
+                },
+                ..Config::default()
+            });
+            harness.connect().await;
+
+            // When
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' from='romeo@example.org' to='{account}'>
+                        <sent xmlns='urn:xmpp:carbons:2'>
+                            <forwarded xmlns='urn:xmpp:forward:0'>
+                                <message xmlns='jabber:client' type='chat' id='carbon-1'
+                                    from='romeo@example.org/phone' to='juliet@example.org/balcony'>
+                                    <body>On my way</body>
+                                </message>
+                            </forwarded>
+                        </sent>
+                    </message>",
+                )
+                .await;
+
+            // Then
+            assert_eq!(harness.take_sent("enable", ns::CARBONS).len(), 1);
+            let juliet = BareJid::from_str("juliet@example.org").unwrap();
+            let messages = harness
+                .aparte
+                .get_mod::<MessagesMod>()
+                .conversation(&Some(harness.account.clone()), &juliet);
+            match messages.as_slice() {
+                [Message::Xmpp(message)] => {
+                    assert_eq!(message.direction, Direction::Outgoing);
+                    assert_eq!(message.get_last_body(), "On my way");
+                }
+                messages => panic!("Unexpected messages {:?}", messages),
+            }
+            assert!(harness.screen().contains("juliet@example.org"));
+        });
+    }
+}
@@ -14,6 +14,7 @@ use crate::conversation::Conversation;
 use crate::core::{Aparte, Event, ModTrait};
 use crate::cursor::Cursor;
 use crate::mods::conversation::ConversationMod;
+use crate::mods::emoji::EmojiMod;
 use crate::word::Words;
 
 pub struct CompletionMod {
@@ -119,9 +120,27 @@ impl CompletionMod {
                 } else {
                     let command_parsers = Rc::clone(&aparte.command_parsers);
                     if let Some(parser) = command_parsers.get(&command.args[0]) {
-                        if command.cursor - 1 < parser.autocompletions.len() {
-                            if let Some(completion) = &parser.autocompletions[command.cursor - 1] {
-                                completions = completion(aparte, command.clone())
+                        let (parser, depth) = parser.resolve(&command);
+                        let index = command.cursor - depth - 1;
+                        let named = command
+                            .args
+                            .get(command.cursor)
+                            .and_then(|arg| arg.find('=').map(|end| &arg[..=end]));
+                        match named {
+                            // Named arguments can be given at any position
+                            Some(prefix) => {
+                                for completion in parser.autocompletions.iter().flatten() {
+                                    completions.extend(
+                                        completion(aparte, command.clone())
+                                            .into_iter()
+                                            .filter(|value| value.starts_with(prefix)),
+                                    );
+                                }
+                            }
+                            None => {
+                                if let Some(Some(completion)) = parser.autocompletions.get(index) {
+                                    completions = completion(aparte, command.clone())
+                                }
                             }
                         }
                     }
@@ -150,14 +169,28 @@ impl CompletionMod {
             let conversation = BareJid::from_str(context);
             match (account, &conversation) {
                 (Some(account), Ok(conversation)) => {
+                    let words = Words::new(&raw_buf[..cursor.index(&raw_buf)]).collect::<Vec<_>>();
+                    let current_word = *words.last().unwrap_or(&"");
+
+                    // Custom emoji shortcodes
+                    if current_word.starts_with(':') {
+                        let emoji = aparte.get_mod::<EmojiMod>();
+                        self.completions = Some(
+                            emoji
+                                .shortnames(conversation)
+                                .iter()
+                                .map(|name| format!(":{}: ", name))
+                                .filter(|code| code.starts_with(current_word))
+                                .collect(),
+                        );
+                        self.current_completion = 0;
+                        return;
+                    }
+
                     let conversation_mod = aparte.get_mod::<ConversationMod>();
                     if let Some(Conversation::Channel(channel)) =
                         conversation_mod.get(account, conversation)
                     {
-                        let words =
-                            Words::new(&raw_buf[..cursor.index(&raw_buf)]).collect::<Vec<_>>();
-                        let current_word = *words.last().unwrap_or(&"");
-
                         let append = if words.len() <= 1 { ": " } else { " " };
 
                         // Collect completion candidates
@@ -4,13 +4,78 @@
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
+use std::str::FromStr;
 use uuid::Uuid;
+use xmpp_parsers::caps::Caps;
+use xmpp_parsers::idle::Idle;
 use xmpp_parsers::iq::{Iq, IqType};
-use xmpp_parsers::{ns, presence, roster, BareJid, Element, Jid};
+use xmpp_parsers::{ns, presence, roster, BareJid, Element, FullJid, Jid};
 
 use crate::account::Account;
+use crate::command::{Command, CommandParser};
 use crate::contact;
 use crate::core::{Aparte, Event, ModTrait};
+use crate::mods;
+
+command_def!(resources,
+r#"/resources <jid>
+
+    jid           Address of the contact
+
+Description:
+    List the online resources of a contact with their priority, availability,
+    status message, client and features.
+
+Examples:
+    /resources juliet@example.org"#,
+{
+    jid: BareJid = {
+        jid: bare,
+        completion: (|aparte, _command| {
+            let contacts = aparte.get_mod::<ContactMod>();
+            contacts.contacts.values().map(|contact| contact.jid.to_string()).collect()
+        })
+    }
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let mut resources = {
+        let contacts = aparte.get_mod::<ContactMod>();
+        contacts.resources(&account, &jid)
+    };
+    if resources.is_empty() {
+        return Err(format!("{} has no online resource", jid));
+    }
+    resources.sort_by_key(|(_, resource)| -resource.priority);
+
+    let mut lines = vec![format!("Resources of {}:", jid)];
+    {
+        let disco = aparte.get_mod::<mods::disco::DiscoMod>();
+        for (name, resource) in resources {
+            let show = match &resource.show {
+                Some(show) => mods::presence::show_text(show),
+                None => "available",
+            };
+            let mut line = format!("  {}, priority {}, {}", name, resource.priority, show);
+            if let Some(status) = &resource.status {
+                line.push_str(&format!(": {}", status));
+            }
+            lines.push(line);
+
+            let full = jid.clone().with_resource(name);
+            let client = disco.peer_client(&account, &full).or(resource.node.as_ref());
+            if let Some(client) = client {
+                lines.push(format!("    Client: {}", client));
+            }
+            match disco.peer_features(&account, &full) {
+                Some(features) => lines.push(format!("    Features: {}", features.join(", "))),
+                None => lines.push("    Features: unknown".to_string()),
+            }
+        }
+    }
+    aparte.log(lines.join("\n"));
+    Ok(())
+});
 
 impl From<roster::Group> for contact::Group {
     fn from(item: roster::Group) -> Self {
@@ -30,11 +95,23 @@ impl From<roster::Item> for contact::Contact {
             name: item.name.clone(),
             subscription: item.subscription.clone(),
             presence: contact::Presence::Unavailable,
+            status: None,
+            idle: None,
             groups: groups,
         }
     }
 }
 
+/// Online resource of a contact
+#[derive(Debug, Clone)]
+pub struct Resource {
+    pub priority: i8,
+    pub show: Option<presence::Show>,
+    pub status: Option<String>,
+    /// Node of the client in its capabilities (XEP-0115)
+    pub node: Option<String>,
+}
+
 #[derive(Eq, PartialEq, Hash)]
 pub struct ContactIndex {
     account: Account,
@@ -43,15 +120,88 @@ pub struct ContactIndex {
 
 pub struct ContactMod {
     pub contacts: HashMap<ContactIndex, contact::Contact>,
+    /// Online resources of everyone sending us their presence, by resource
+    resources: HashMap<ContactIndex, HashMap<String, Resource>>,
 }
 
 impl ContactMod {
     pub fn new() -> Self {
         Self {
             contacts: HashMap::new(),
+            resources: HashMap::new(),
+        }
+    }
+
+    /// Online resources of a contact
+    pub fn resources(&self, account: &Account, jid: &BareJid) -> Vec<(String, Resource)> {
+        let index = ContactIndex {
+            account: account.clone(),
+            jid: jid.clone(),
+        };
+        match self.resources.get(&index) {
+            Some(resources) => resources
+                .iter()
+                .map(|(name, resource)| (name.clone(), resource.clone()))
+                .collect(),
+            None => Vec::new(),
         }
     }
 
+    /// Keep track of the online resources, channel occupants aside
+    fn update_resource(
+        &mut self,
+        account: &Account,
+        from: &FullJid,
+        presence: &presence::Presence,
+    ) {
+        if presence
+            .payloads
+            .iter()
+            .any(|payload| payload.is("x", ns::MUC_USER))
+        {
+            return;
+        }
+        let index = ContactIndex {
+            account: account.clone(),
+            jid: from.clone().into(),
+        };
+        match presence.type_ {
+            presence::Type::None => {
+                let resource = Resource {
+                    priority: presence.priority,
+                    show: presence.show.clone(),
+                    status: presence.statuses.values().next().cloned(),
+                    node: presence
+                        .payloads
+                        .iter()
+                        .find_map(|payload| Caps::try_from(payload.clone()).ok())
+                        .map(|caps| caps.node),
+                };
+                self.resources
+                    .entry(index)
+                    .or_default()
+                    .insert(from.resource.clone(), resource);
+            }
+            presence::Type::Unavailable => {
+                if let Some(resources) = self.resources.get_mut(&index) {
+                    resources.remove(&from.resource);
+                    if resources.is_empty() {
+                        self.resources.remove(&index);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether someone is in the roster of an account
+    pub fn is_contact(&self, account: &Account, jid: &BareJid) -> bool {
+        self.contacts.contains_key(&ContactIndex {
+            account: account.clone(),
+            jid: jid.clone(),
+        })
+    }
+
     fn request(&self) -> Element {
         let id = Uuid::new_v4().to_hyphenated().to_string();
         let iq = Iq::from_get(
@@ -66,13 +216,17 @@ impl ContactMod {
 }
 
 impl ModTrait for ContactMod {
-    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(resources::new());
         Ok(())
     }
 
     fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
         match event {
-            Event::Connected(account, _jid) => aparte.send(account, self.request()),
+            Event::Connected(account, _jid) => {
+                self.resources.retain(|index, _| &index.account != account);
+                aparte.send(account, self.request())
+            }
             Event::Iq(account, iq) => {
                 if let IqType::Result(Some(payload)) = iq.payload.clone() {
                     if payload.is("query", ns::ROSTER) {
@@ -91,6 +245,9 @@ impl ModTrait for ContactMod {
                 }
             }
             Event::Presence(account, presence) => {
+                if let Some(Jid::Full(from)) = &presence.from {
+                    self.update_resource(account, from, presence);
+                }
                 if let Some(from) = &presence.from {
                     let jid = match from {
                         Jid::Bare(jid) => jid.clone(),
@@ -101,13 +258,31 @@ impl ModTrait for ContactMod {
                         jid,
                     };
                     if let Some(contact) = self.contacts.get_mut(&index) {
-                        contact.presence = match presence.show {
-                            Some(presence::Show::Away) => contact::Presence::Away,
-                            Some(presence::Show::Chat) => contact::Presence::Chat,
-                            Some(presence::Show::Dnd) => contact::Presence::Dnd,
-                            Some(presence::Show::Xa) => contact::Presence::Xa,
-                            None => contact::Presence::Available,
+                        contact.presence = match (&presence.type_, &presence.show) {
+                            (presence::Type::Unavailable, _) => contact::Presence::Unavailable,
+                            (presence::Type::None, Some(presence::Show::Away)) => {
+                                contact::Presence::Away
+                            }
+                            (presence::Type::None, Some(presence::Show::Chat)) => {
+                                contact::Presence::Chat
+                            }
+                            (presence::Type::None, Some(presence::Show::Dnd)) => {
+                                contact::Presence::Dnd
+                            }
+                            (presence::Type::None, Some(presence::Show::Xa)) => {
+                                contact::Presence::Xa
+                            }
+                            (presence::Type::None, None) => contact::Presence::Available,
+                            // Subscription management and errors don't change availability
+                            _ => return,
                         };
+                        contact.status = presence.statuses.values().next().cloned();
+                        contact.idle = presence
+                            .payloads
+                            .iter()
+                            .filter(|payload| payload.is("idle", ns::IDLE))
+                            .find_map(|payload| Idle::try_from(payload.clone()).ok())
+                            .map(|idle| idle.since.0);
                         aparte.schedule(Event::ContactUpdate(account.clone(), contact.clone()));
                     }
                 }
@@ -122,3 +297,65 @@ impl fmt::Display for ContactMod {
         write!(f, "Contact management")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+
+    #[test]
+    fn test_resources_lists_presence_client_and_features() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.reply(
+                "query",
+                ns::DISCO_INFO,
+                "<iq xmlns='jabber:client' type='result' id='{id}'
+                    from='juliet@example.org/balcony' to='{account}'>
+                    <query xmlns='http://jabber.org/protocol/disco#info'>
+                        <identity category='client' type='pc' name='Gajim'/>
+                        <feature var='http://jabber.org/protocol/disco#info'/>
+                        <feature var='urn:xmpp:receipts'/>
+                    </query>
+                </iq>",
+            );
+            harness.connect().await;
+            harness
+                .receive(
+                    "<presence xmlns='jabber:client' from='juliet@example.org/balcony' to='{account}'>
+                        <show>away</show>
+                        <status>On the balcony</status>
+                        <priority>5</priority>
+                    </presence>",
+                )
+                .await;
+            harness
+                .receive(
+                    "<presence xmlns='jabber:client' from='juliet@example.org/phone' to='{account}'>
+                        <priority>-1</priority>
+                        <c xmlns='http://jabber.org/protocol/caps' hash='sha-1'
+                            node='https://conversations.im' ver='q07IKJEyjvHSyhy//CH0CxmKi8w='/>
+                    </presence>",
+                )
+                .await;
+
+            // When
+            harness
+                .input("console", "/resources juliet@example.org")
+                .await;
+
+            // Then
+            let screen = harness.screen();
+            let balcony = screen
+                .find("balcony, priority 5, away: On the balcony")
+                .unwrap();
+            let phone = screen.find("phone, priority -1, available").unwrap();
+            assert!(balcony < phone);
+            assert!(screen.contains("Client: Gajim (client/pc)"));
+            assert!(screen.contains("urn:xmpp:receipts"));
+            assert!(screen.contains("Features: unknown"));
+            assert!(screen.contains("Client: https://conversations.im"));
+        });
+    }
+}
@@ -1,10 +1,11 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use chrono::Local as LocalTz;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
-use xmpp_parsers::{muc, BareJid, Jid};
+use xmpp_parsers::{muc, presence, BareJid, Jid};
 
 use crate::account::Account;
 use crate::conversation;
@@ -39,6 +40,47 @@ impl ConversationMod {
         };
         self.conversations.get(&index)
     }
+
+    /// Change the affiliation of the occupants of a channel with the given jid, returning them
+    pub fn set_affiliation(
+        &mut self,
+        account: &Account,
+        room: &BareJid,
+        jid: &BareJid,
+        affiliation: conversation::Affiliation,
+    ) -> Vec<conversation::Occupant> {
+        let index = ConversationIndex {
+            account: account.clone(),
+            jid: room.clone(),
+        };
+        match self.conversations.get_mut(&index) {
+            Some(conversation::Conversation::Channel(channel)) => channel
+                .occupants
+                .values_mut()
+                .filter(|occupant| occupant.jid.as_ref() == Some(jid))
+                .map(|occupant| {
+                    occupant.affiliation = affiliation;
+                    occupant.clone()
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Channels currently opened on an account
+    pub fn channels<'a>(
+        &'a self,
+        account: &'a Account,
+    ) -> impl Iterator<Item = &'a conversation::Channel> {
+        self.conversations
+            .values()
+            .filter_map(move |conversation| match conversation {
+                conversation::Conversation::Channel(channel) if &channel.account == account => {
+                    Some(channel)
+                }
+                _ => None,
+            })
+    }
 }
 
 impl From<muc::user::Role> for conversation::Role {
@@ -112,16 +154,27 @@ impl ModTrait for ConversationMod {
                     {
                         for payload in presence.clone().payloads {
                             if let Some(muc_user) = muc::user::MucUser::try_from(payload).ok() {
+                                // Our nick changed, following /nick or the room enforcing one
+                                if muc_user.status.contains(&muc::user::Status::SelfPresence)
+                                    && presence.type_ != presence::Type::Unavailable
+                                {
+                                    channel.nick = from.resource.clone();
+                                }
                                 for item in muc_user.items {
                                     let occupant_jid = match item.jid {
                                         Some(full) => Some(full.into()),
                                         None => None,
                                     };
+                                    let joined = match channel.occupants.get(&from.resource) {
+                                        Some(occupant) => occupant.joined,
+                                        None => LocalTz::now().into(),
+                                    };
                                     let occupant = conversation::Occupant {
                                         nick: from.resource.clone(),
                                         jid: occupant_jid,
                                         affiliation: item.affiliation.into(),
                                         role: item.role.into(),
+                                        joined,
                                     };
                                     aparte.schedule(Event::Occupant {
                                         account: index.account.clone(),
@@ -175,3 +228,55 @@ impl fmt::Display for ConversationMod {
         write!(f, "Conversations management")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_join_channel_and_track_occupants() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+
+            // When
+            harness
+                .input("console", "/join room@conference.example.org")
+                .await;
+            harness
+                .receive(
+                    "<presence xmlns='jabber:client' from='room@conference.example.org/juliet'
+                        to='{account}'>
+                        <x xmlns='http://jabber.org/protocol/muc#user'>
+                            <item affiliation='owner' role='moderator'/>
+                        </x>
+                    </presence>",
+                )
+                .await;
+
+            // Then
+            let joins = harness.take_sent("x", "http://jabber.org/protocol/muc");
+            assert_eq!(joins.len(), 1);
+            assert_eq!(
+                joins[0].attr("to"),
+                Some("room@conference.example.org/romeo")
+            );
+            let room = BareJid::from_str("room@conference.example.org").unwrap();
+            let conversations = harness.aparte.get_mod::<ConversationMod>();
+            match conversations.get(&harness.account, &room) {
+                Some(conversation::Conversation::Channel(channel)) => {
+                    assert_eq!(channel.nick, "romeo");
+                    assert_eq!(
+                        channel.occupants["juliet"].role,
+                        conversation::Role::Moderator
+                    );
+                }
+                _ => panic!("Channel not joined"),
+            }
+            assert!(harness.screen().contains("juliet"));
+        });
+    }
+}
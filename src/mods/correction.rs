@@ -1,19 +1,81 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
+use std::str::FromStr;
 use xmpp_parsers::delay::Delay;
 use xmpp_parsers::message::Message as XmppParsersMessage;
 use xmpp_parsers::message_correct::Replace;
-use xmpp_parsers::ns;
+use xmpp_parsers::{ns, BareJid, Jid};
 
 use crate::account::Account;
+use crate::command::{Command, CommandParser};
 use crate::core::{Aparte, Event, ModTrait};
-use crate::message::Message;
+use crate::message::{Direction, Message, VersionedXmppMessage, XmppMessageType};
 use crate::mods::disco;
 use crate::mods::messages;
 
+command_def!(
+    correct,
+    r#"/correct <message>
+
+    message       Corrected text of the message
+
+Description:
+    Replace the last message sent in the current conversation (XEP-0308).
+    Clients not supporting corrections show it as a new message.
+
+Examples:
+    /correct Hello world"#,
+    {},
+    |aparte, _command| {
+        let account = _command
+            .account
+            .clone()
+            .ok_or("No connection found".to_string())?;
+        let conversation = BareJid::from_str(&_command.context)
+            .map_err(|_| "Not in a conversation".to_string())?;
+        let text = _command.args[1..].join(" ");
+        if text.is_empty() {
+            return Err("Missing corrected message".to_string());
+        }
+
+        let mut message = {
+            let messages = aparte.get_mod::<messages::MessagesMod>();
+            messages
+                .conversation(&Some(account.clone()), &conversation)
+                .into_iter()
+                .rev()
+                .find_map(|message| match message {
+                    Message::Xmpp(message) if message.direction == Direction::Outgoing => {
+                        Some(message)
+                    }
+                    _ => None,
+                })
+        }
+        .ok_or(format!("No message to correct in {}", conversation))?;
+
+        let supported = {
+            let disco = aparte.get_mod::<disco::DiscoMod>();
+            disco.peer_supports(&account, &conversation, ns::MESSAGE_CORRECT)
+        };
+        if message.type_ == XmppMessageType::Chat && supported == Some(false) {
+            aparte.log(format!(
+                "{} doesn't support corrections, the correction will be shown as a new message",
+                conversation
+            ));
+        }
+
+        let mut bodies = HashMap::new();
+        bodies.insert("".to_string(), text);
+        message.add_version(bodies);
+        aparte.schedule(Event::SendMessage(account, Message::Xmpp(message)));
+        Ok(())
+    }
+);
+
 pub struct CorrectionMod {}
 
 impl CorrectionMod {
@@ -21,59 +83,66 @@ impl CorrectionMod {
         Self {}
     }
 
-    fn handle_replace(
-        &mut self,
-        aparte: &mut Aparte,
-        account: &Account,
-        message: &XmppParsersMessage,
-        replace: Replace,
-    ) {
-        let event = {
-            let mut messages = aparte.get_mod_mut::<messages::MessagesMod>();
-            if let Some(original) = messages.get_mut(&Some(account.clone()), &replace.id) {
-                match original {
-                    Message::Xmpp(original) => original.add_version_from_xmpp(message),
-                    Message::Log(_) => error!(
-                        "Can't replace a log message (conflicting id? {})",
-                        replace.id
-                    ),
-                }
-                Event::Message(Some(account.clone()), original.clone())
-            } else {
-                let mut message = message.clone();
-                message.payloads = message
-                    .payloads
-                    .iter()
-                    .filter(|payload| !payload.is("replace", ns::MESSAGE_CORRECT))
-                    .cloned()
-                    .collect();
-                Event::RawMessage(account.clone(), message, None)
+    fn get_replace(message: &XmppParsersMessage) -> Option<Replace> {
+        message
+            .payloads
+            .iter()
+            .find_map(|payload| Replace::try_from(payload.clone()).ok())
+    }
+
+    /// Whether a correction comes from the author of the original message, the occupant for
+    /// channels and any resource of the contact for chats
+    fn same_sender(original: &VersionedXmppMessage, correction: &XmppParsersMessage) -> bool {
+        match (&original.type_, &correction.from) {
+            (XmppMessageType::Channel, Some(from)) => from == &original.from_full,
+            (XmppMessageType::Chat, Some(Jid::Full(from))) => {
+                from.node == original.from.node && from.domain == original.from.domain
             }
-        };
-        aparte.schedule(event);
+            (XmppMessageType::Chat, Some(Jid::Bare(from))) => from == &original.from,
+            (_, None) => false,
+        }
     }
 }
 
 impl ModTrait for CorrectionMod {
     fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(correct::new());
         let mut disco = aparte.get_mod_mut::<disco::DiscoMod>();
         disco.add_feature(ns::MESSAGE_CORRECT)
     }
 
     fn can_handle_xmpp_message(
         &mut self,
-        _aparte: &mut Aparte,
-        _account: &Account,
+        aparte: &mut Aparte,
+        account: &Account,
         message: &XmppParsersMessage,
         _delay: &Option<Delay>,
     ) -> f64 {
-        for payload in message.payloads.iter().cloned() {
-            if Replace::try_from(payload.clone()).is_ok() {
-                return 1f64;
+        let replace = match Self::get_replace(message) {
+            Some(replace) => replace,
+            None => return 0f64,
+        };
+
+        // Unknown or spoofed corrections are left to be shown as new messages
+        let messages = aparte.get_mod::<messages::MessagesMod>();
+        match messages.get(&Some(account.clone()), &replace.id) {
+            Some(Message::Xmpp(original)) if Self::same_sender(original, message) => 1f64,
+            Some(Message::Xmpp(_)) => {
+                warn!(
+                    "Ignoring correction of {} not sent by its author",
+                    replace.id
+                );
+                0f64
+            }
+            Some(Message::Log(_)) => {
+                error!(
+                    "Can't replace a log message (conflicting id? {})",
+                    replace.id
+                );
+                0f64
             }
+            None => 0f64,
         }
-
-        return 0f64;
     }
 
     fn handle_xmpp_message(
@@ -83,29 +152,129 @@ impl ModTrait for CorrectionMod {
         message: &XmppParsersMessage,
         _delay: &Option<Delay>,
     ) {
-        for payload in message.payloads.iter().cloned() {
-            if let Ok(replace) = Replace::try_from(payload.clone()) {
-                self.handle_replace(aparte, account, message, replace);
-            }
-        }
-    }
+        let replace = match Self::get_replace(message) {
+            Some(replace) => replace,
+            None => return,
+        };
 
-    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
-        match event {
-            Event::RawMessage(account, message, _delay) => {
-                for payload in message.payloads.iter().cloned() {
-                    if let Ok(replace) = Replace::try_from(payload.clone()) {
-                        self.handle_replace(aparte, account, message, replace);
-                    }
+        let original = {
+            let mut messages = aparte.get_mod_mut::<messages::MessagesMod>();
+            match messages.get_mut(&Some(account.clone()), &replace.id) {
+                Some(Message::Xmpp(original)) => {
+                    original.add_version_from_xmpp(message);
+                    Some(Message::Xmpp(original.clone()))
                 }
+                _ => None,
             }
-            _ => {}
+        };
+
+        if let Some(original) = original {
+            aparte.schedule(Event::Message(Some(account.clone()), original));
         }
     }
+
+    fn on_event(&mut self, _aparte: &mut Aparte, _event: &Event) {}
 }
 
 impl fmt::Display for CorrectionMod {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "XEP-0280: Message Correction")
+        write!(f, "XEP-0308: Last Message Correction")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+
+    #[test]
+    fn test_correct_resends_the_last_message_with_a_replace_payload() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness
+                .input("console", "/msg juliet@example.org \"Helo\"")
+                .await;
+            let original = harness.take_sent("message", "jabber:client").remove(0);
+
+            // When
+            harness
+                .input("juliet@example.org", "/correct Hello Juliet")
+                .await;
+
+            // Then
+            let correction = harness.take_sent("message", "jabber:client").remove(0);
+            let correction = XmppParsersMessage::try_from(correction).unwrap();
+            let replace = correction
+                .payloads
+                .into_iter()
+                .find_map(|payload| Replace::try_from(payload).ok())
+                .unwrap();
+            assert_eq!(Some(replace.id.as_str()), original.attr("id"));
+            assert_ne!(correction.id.as_deref(), original.attr("id"));
+            assert_eq!(correction.bodies[""].0, "Hello Juliet");
+
+            let juliet = BareJid::from_str("juliet@example.org").unwrap();
+            let messages = harness
+                .aparte
+                .get_mod::<messages::MessagesMod>()
+                .conversation(&Some(harness.account.clone()), &juliet);
+            match messages.as_slice() {
+                [Message::Xmpp(message)] => assert_eq!(message.get_last_body(), "Hello Juliet"),
+                messages => panic!("Unexpected messages {:?}", messages),
+            }
+        });
+    }
+
+    #[test]
+    fn test_corrections_only_replace_messages_of_their_author() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='chat' id='m1'
+                        from='juliet@example.org/balcony' to='{account}'>
+                        <body>Wherefore art thou</body>
+                    </message>",
+                )
+                .await;
+
+            // When
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='chat' id='m2'
+                        from='juliet@example.org/balcony' to='{account}'>
+                        <body>Wherefore art thou Romeo?</body>
+                        <replace xmlns='urn:xmpp:message-correct:0' id='m1'/>
+                    </message>",
+                )
+                .await;
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='chat' id='m3'
+                        from='tybalt@example.org/street' to='{account}'>
+                        <body>Draw!</body>
+                        <replace xmlns='urn:xmpp:message-correct:0' id='m1'/>
+                    </message>",
+                )
+                .await;
+
+            // Then
+            let account = Some(harness.account.clone());
+            let messages = harness.aparte.get_mod::<messages::MessagesMod>();
+            let juliet = BareJid::from_str("juliet@example.org").unwrap();
+            match messages.conversation(&account, &juliet).as_slice() {
+                [Message::Xmpp(message)] => {
+                    assert_eq!(message.id, "m1");
+                    assert_eq!(message.get_last_body(), "Wherefore art thou Romeo?");
+                }
+                messages => panic!("Unexpected messages {:?}", messages),
+            }
+            let tybalt = BareJid::from_str("tybalt@example.org").unwrap();
+            assert_eq!(messages.conversation(&account, &tybalt).len(), 1);
+        });
     }
 }
@@ -0,0 +1,155 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Tell the server when Aparté is inactive (XEP-0352), so that it holds back traffic not worth
+//! sending right away like presences and chat states.
+//!
+//! Aparté is inactive while the terminal doesn't have the focus, on terminals reporting focus
+//! changes, and in low bandwidth mode. Servers not offering client state indication never get the
+//! state, the client leaves it out.
+use std::collections::HashMap;
+use std::fmt;
+use xmpp_parsers::Element;
+
+use crate::account::Account;
+use crate::core::{Aparte, Event, ModTrait};
+
+pub const CSI: &str = "urn:xmpp:csi:0";
+
+pub struct ClientStateMod {
+    /// Whether the last state sent on the stream of each connected account is inactive, streams
+    /// starting active
+    inactive: HashMap<Account, bool>,
+    /// Whether the terminal has the focus, assumed until told otherwise
+    focused: bool,
+}
+
+impl ClientStateMod {
+    pub fn new() -> Self {
+        Self {
+            inactive: HashMap::new(),
+            focused: true,
+        }
+    }
+
+    fn is_inactive(&self, aparte: &Aparte) -> bool {
+        aparte.config.low_bandwidth || !self.focused
+    }
+
+    /// Send the current state on the stream of an account, if it changed
+    fn update(&mut self, aparte: &mut Aparte, account: &Account) {
+        let inactive = self.is_inactive(aparte);
+        if self.inactive.insert(account.clone(), inactive) == Some(inactive) {
+            return;
+        }
+        let state = match inactive {
+            true => "inactive",
+            false => "active",
+        };
+        aparte.send(account, Element::builder(state, CSI).build());
+    }
+
+    fn update_all(&mut self, aparte: &mut Aparte) {
+        let accounts = self.inactive.keys().cloned().collect::<Vec<_>>();
+        for account in accounts {
+            self.update(aparte, &account);
+        }
+    }
+}
+
+impl ModTrait for ClientStateMod {
+    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Connected(account, _) => {
+                self.inactive.insert(account.clone(), false);
+                self.update(aparte, account);
+            }
+            Event::Disconnected(account, _) => {
+                self.inactive.remove(account);
+            }
+            Event::LowBandwidth(_) => self.update_all(aparte),
+            Event::Focus(focused) => {
+                self.focused = *focused;
+                self.update_all(aparte);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for ClientStateMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0352: Client State Indication")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::{self, Harness};
+
+    #[test]
+    fn test_low_bandwidth_mode_makes_the_client_inactive() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+
+            // When
+            harness.input("console", "/lowbandwidth on").await;
+            let inactive = harness.take_sent("inactive", CSI);
+            harness.input("console", "/lowbandwidth on").await;
+            let repeated = harness.take_sent("inactive", CSI);
+            harness.input("console", "/lowbandwidth off").await;
+
+            // Then
+            assert_eq!(inactive.len(), 1);
+            assert!(repeated.is_empty());
+            assert_eq!(harness.take_sent("active", CSI).len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_client_is_inactive_while_the_terminal_is_not_focused() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+
+            // When
+            harness.aparte.schedule(Event::Focus(false));
+            harness.settle().await;
+            let inactive = harness.take_sent("inactive", CSI);
+            harness.aparte.schedule(Event::Focus(true));
+            harness.settle().await;
+
+            // Then
+            assert_eq!(inactive.len(), 1);
+            assert_eq!(harness.take_sent("active", CSI).len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_client_connected_in_low_bandwidth_mode_is_inactive() {
+        testing::run(async {
+            // Given
+            let config = Config {
+                low_bandwidth: true,
+                ..Default::default()
+            };
+            let mut harness = Harness::with_config(config);
+
+            // When
+            harness.connect().await;
+
+            // Then
+            assert_eq!(harness.take_sent("inactive", CSI).len(), 1);
+            assert!(harness.take_sent("active", CSI).is_empty());
+        });
+    }
+}
@@ -0,0 +1,217 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! DBus service letting desktop widgets send messages, change the status and follow unread
+//! conversations.
+//!
+//! The service is `org.aparte.Client` with a single object `/org/aparte/Client`:
+//!
+//!  - `SendMessage(to: s, body: s)`
+//!  - `SetStatus(show: s, status: s)` where show is one of available, chat, away, dnd or xa
+//!  - signal `MessageReceived(from: s, body: s)`
+//!  - signal `UnreadCount(count: u)`
+use dbus::channel::{MatchingReceiver, Sender as _};
+use dbus::message::MatchRule;
+use dbus::nonblock::SyncConnection;
+use dbus::strings::{Interface, Member, Path};
+use dbus_crossroads::{Crossroads, MethodErr};
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use xmpp_parsers::presence::Show as PresenceShow;
+
+use crate::command::Command;
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::{Direction, Message};
+use crate::mods;
+
+const NAME: &str = "org.aparte.Client";
+const PATH: &str = "/org/aparte/Client";
+
+/// Parse the show of a presence as given to SetStatus, None being plainly available
+fn parse_show(show: &str) -> Result<Option<PresenceShow>, String> {
+    match show {
+        "available" | "" => Ok(None),
+        "chat" => Ok(Some(PresenceShow::Chat)),
+        "away" => Ok(Some(PresenceShow::Away)),
+        "dnd" => Ok(Some(PresenceShow::Dnd)),
+        "xa" => Ok(Some(PresenceShow::Xa)),
+        other => Err(format!("Unknown show {}", other)),
+    }
+}
+
+/// Object exposed on the bus, forwarding method calls to the event loop
+struct Client {
+    events: mpsc::Sender<Event>,
+}
+
+impl Client {
+    fn forward(&self, event: Event) -> Result<(), MethodErr> {
+        self.events
+            .try_send(event)
+            .map_err(|err| MethodErr::failed(&err))
+    }
+}
+
+pub struct DBusMod {
+    connection: Option<Arc<SyncConnection>>,
+    unread: usize,
+}
+
+impl DBusMod {
+    pub fn new() -> Self {
+        Self {
+            connection: None,
+            unread: 0,
+        }
+    }
+
+    fn start(&mut self, aparte: &mut Aparte) -> Result<(), String> {
+        let events = aparte
+            .event_sender()
+            .ok_or(format!("Event loop isn't running"))?;
+        let (resource, connection) =
+            dbus_tokio::connection::new_session_sync().map_err(|err| err.to_string())?;
+        tokio::spawn(async {
+            let err = resource.await;
+            warn!("Lost connection to DBus: {}", err);
+        });
+
+        let mut cr = Crossroads::new();
+        let iface = cr.register(NAME, |b| {
+            b.signal::<(String, String), _>("MessageReceived", ("from", "body"));
+            b.signal::<(u32,), _>("UnreadCount", ("count",));
+            b.method(
+                "SendMessage",
+                ("to", "body"),
+                (),
+                |_, client: &mut Client, (to, body): (String, String)| {
+                    let command = format!("/{}", Command::assemble_args(&["msg".into(), to, body]));
+                    client.forward(Event::RawCommand(None, "console".to_string(), command))
+                },
+            );
+            b.method(
+                "SetStatus",
+                ("show", "status"),
+                (),
+                |_, client: &mut Client, (show, status): (String, String)| {
+                    let show = parse_show(&show).map_err(|err| MethodErr::invalid_arg(&err))?;
+                    let status = match status.is_empty() {
+                        true => None,
+                        false => Some(status),
+                    };
+                    client.forward(Event::SetPresence { show, status })
+                },
+            );
+        });
+        cr.insert(PATH, &[iface], Client { events });
+
+        connection.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |message, connection| {
+                if cr.handle_message(message, connection).is_err() {
+                    warn!("Cannot handle DBus method call");
+                }
+                true
+            }),
+        );
+
+        let name_connection = connection.clone();
+        tokio::spawn(async move {
+            if let Err(err) = name_connection.request_name(NAME, false, true, false).await {
+                warn!("Cannot request DBus name {}: {}", NAME, err);
+            }
+        });
+
+        self.connection = Some(connection);
+        Ok(())
+    }
+
+    fn signal<A: dbus::arg::AppendAll>(&self, name: &'static str, args: A) {
+        if let Some(connection) = &self.connection {
+            let mut signal = dbus::Message::signal(
+                &Path::from(PATH),
+                &Interface::from(NAME),
+                &Member::from(name),
+            );
+            signal.append_all(args);
+            if connection.send(signal).is_err() {
+                warn!("Cannot send DBus signal {}", name);
+            }
+        }
+    }
+
+    fn update_unread(&mut self, aparte: &Aparte) {
+        let unread = {
+            let ui = aparte.get_mod::<mods::ui::UIMod>();
+            ui.unread_count()
+        };
+        if unread != self.unread {
+            self.unread = unread;
+            self.signal("UnreadCount", (unread as u32,));
+        }
+    }
+}
+
+impl ModTrait for DBusMod {
+    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            // The connection needs the event loop to run
+            Event::Start => {
+                if let Err(err) = self.start(aparte) {
+                    aparte.log(format!("Cannot start DBus service: {}", err));
+                }
+            }
+            Event::Message(_, Message::Xmpp(message))
+                if message.direction == Direction::Incoming =>
+            {
+                self.signal(
+                    "MessageReceived",
+                    (
+                        message.from.to_string(),
+                        message.get_last_body().to_string(),
+                    ),
+                );
+            }
+            Event::Notification(_) | Event::ChangeWindow(_) | Event::Close(_) => {
+                self.update_unread(aparte)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for DBusMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DBus service")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_show() {
+        // Given
+        let shows = ["available", "away", "xa", "busy"];
+
+        // When
+        let parsed: Vec<_> = shows.iter().map(|show| parse_show(show)).collect();
+
+        // Then
+        assert_eq!(
+            parsed,
+            vec![
+                Ok(None),
+                Ok(Some(PresenceShow::Away)),
+                Ok(Some(PresenceShow::Xa)),
+                Err(String::from("Unknown show busy")),
+            ]
+        );
+    }
+}
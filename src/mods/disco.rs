@@ -1,21 +1,160 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Service discovery (XEP-0030) and entity capabilities (XEP-0115).
+//!
+//! Features of contacts' clients are announced in their presences as a hash, the features behind
+//! each hash are cached between runs so that a client is only queried the first time it is seen.
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
+use std::fs;
+use std::path::PathBuf;
 use std::str::FromStr;
 use uuid::Uuid;
+use xmpp_parsers::caps::{self, Caps};
 use xmpp_parsers::disco;
+use xmpp_parsers::hashes::Algo;
 use xmpp_parsers::iq::{Iq, IqType};
-use xmpp_parsers::{Element, Jid};
+use xmpp_parsers::ns;
+use xmpp_parsers::presence::Type as PresenceType;
+use xmpp_parsers::stanza_error::{DefinedCondition, ErrorType, StanzaError};
+use xmpp_parsers::{BareJid, Element, FullJid, Jid};
 
 use crate::account::Account;
-use crate::core::{Aparte, Event, ModTrait};
+use crate::color;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, IqResponse, ModTrait};
+use crate::mods;
+
+/// Node identifying Aparté in capabilities
+const NODE: &str = "https://github.com/paulfariello/aparte";
+
+/// Actions depending on the support of the peer client
+const PEER_ACTIONS: &[(&str, &str)] = &[
+    ("correction", ns::MESSAGE_CORRECT),
+    ("receipts", ns::RECEIPTS),
+    ("chat states", ns::CHATSTATES),
+    ("attention", ns::ATTENTION),
+    ("file transfer", ns::JINGLE_FT),
+    ("reactions", "urn:xmpp:reactions:0"),
+];
+
+command_def!(features,
+r#"/features [<contact>]
+
+    contact       Contact to check features of
+
+Description:
+    List actions available with the current or a given contact. Actions not
+    supported by any of the contact's clients, or by the server for uploads,
+    are greyed out along with why.
+
+Examples:
+    /features
+    /features contact@server.tld"#,
+{
+    contact: Option<BareJid> = {
+        jid: bare
+    }
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let contact = match contact {
+        Some(contact) => contact,
+        None => BareJid::from_str(&_command.context).map_err(|_| "Missing contact".to_string())?,
+    };
+
+    let lines = actions(aparte, &account, &contact)?.into_iter().map(|(action, unsupported)| {
+        match unsupported {
+            None => format!("  {}", action),
+            Some(why) => color::dimmed(&format!("  {} ({})", action, why)),
+        }
+    }).collect::<Vec<String>>();
+
+    aparte.log(format!("Features of {}:\n{}", contact, lines.join("\n")));
+    Ok(())
+});
+
+/// Actions available with a contact, along with why the ones it can't use are unavailable
+pub fn actions(
+    aparte: &Aparte,
+    account: &Account,
+    contact: &BareJid,
+) -> Result<Vec<(&'static str, Option<String>)>, String> {
+    let mut actions = {
+        let disco = aparte.get_mod::<DiscoMod>();
+        PEER_ACTIONS
+            .iter()
+            .map(
+                |(action, feature)| match disco.peer_supports(account, contact, feature) {
+                    Some(true) => Ok((*action, None)),
+                    Some(false) => Ok((*action, Some(format!("not supported by {}", contact)))),
+                    None => Err(format!(
+                        "Features of {} are unknown, is it online?",
+                        contact
+                    )),
+                },
+            )
+            .collect::<Result<Vec<_>, String>>()?
+    };
+
+    // Files are uploaded to the server rather than sent to the contact
+    let upload = aparte.get_mod::<mods::upload::UploadMod>();
+    actions.push(match upload.available(account) {
+        true => ("upload", None),
+        false => (
+            "upload",
+            Some(String::from("no upload service on the server")),
+        ),
+    });
+    Ok(actions)
+}
+
+/// Actions available with a contact as a status line, the unavailable ones greyed out
+pub fn actions_status(aparte: &Aparte, account: &Account, contact: &BareJid) -> String {
+    let actions = match actions(aparte, account, contact) {
+        Ok(actions) => actions,
+        Err(err) => return color::dimmed(&err),
+    };
+    let mut available = Vec::new();
+    let mut unavailable: Vec<(String, Vec<&str>)> = Vec::new();
+    for (action, unsupported) in actions {
+        match unsupported {
+            None => available.push(action),
+            Some(why) => match unavailable.iter_mut().find(|(reason, _)| reason == &why) {
+                Some((_, actions)) => actions.push(action),
+                None => unavailable.push((why, vec![action])),
+            },
+        }
+    }
+
+    let mut status = available.join(", ");
+    for (why, actions) in unavailable {
+        if !status.is_empty() {
+            status.push_str(", ");
+        }
+        status.push_str(&color::dimmed(&format!("{} ({})", actions.join(", "), why)));
+    }
+    status
+}
 
 pub struct DiscoMod {
     client_features: Vec<String>,
     server_features: HashMap<Account, Vec<String>>,
+    /// Features of each online resource of our contacts
+    peer_features: HashMap<Account, HashMap<FullJid, Vec<String>>>,
+    /// Client identity of the online resources of our contacts that answered a query, it isn't
+    /// cached along the features
+    peer_clients: HashMap<Account, HashMap<FullJid, String>>,
+    /// Pending disco#info queries of peers by iq id
+    queries: HashMap<String, FullJid>,
+    /// Capabilities to verify the answer of pending disco#info queries against, by iq id
+    caps_queries: HashMap<String, Caps>,
+    /// Features of verified capabilities, by node#ver, saved between runs
+    caps: HashMap<String, Vec<String>>,
+    /// File where verified capabilities are saved
+    cache: Option<PathBuf>,
 }
 
 impl DiscoMod {
@@ -23,9 +162,75 @@ impl DiscoMod {
         Self {
             client_features: Vec::new(),
             server_features: HashMap::new(),
+            peer_features: HashMap::new(),
+            peer_clients: HashMap::new(),
+            queries: HashMap::new(),
+            caps_queries: HashMap::new(),
+            caps: HashMap::new(),
+            cache: dirs::cache_dir().map(|dir| dir.join("aparte").join("caps.toml")),
+        }
+    }
+
+    fn load(&mut self) -> Result<(), String> {
+        let path = match &self.cache {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let cache = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        self.caps = toml::from_str(&cache).map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.cache {
+            let result = toml::to_string(&self.caps)
+                .map_err(|err| err.to_string())
+                .and_then(|cache| {
+                    let dir = path.parent().unwrap();
+                    fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+                    fs::write(path, cache).map_err(|err| err.to_string())
+                });
+            if let Err(err) = result {
+                warn!("Cannot save capabilities cache: {}", err);
+            }
+        }
+    }
+
+    /// Identity and features of Aparté
+    pub fn info(&self, node: Option<String>) -> disco::DiscoInfoResult {
+        let mut features = self.client_features.clone();
+        features.push(String::from(ns::DISCO_INFO));
+        features.push(String::from(ns::CAPS));
+        features.sort();
+        features.dedup();
+        disco::DiscoInfoResult {
+            node,
+            identities: vec![disco::Identity::new("client", "console", "en", "Aparté")],
+            features: features
+                .into_iter()
+                .map(|var| disco::Feature { var })
+                .collect(),
+            extensions: Vec::new(),
         }
     }
 
+    /// Capabilities advertised in presences
+    pub fn caps(&self) -> Caps {
+        let hash = caps::hash_caps(&caps::compute_disco(&self.info(None)), Algo::Sha_1).unwrap();
+        Caps::new(NODE, hash)
+    }
+
+    fn caps_node(caps: &Caps) -> String {
+        format!("{}#{}", caps.node, caps.hash.to_base64())
+    }
+
+    /// Whether the answer to a disco#info query matches the capabilities hash it was made for
+    fn verify(info: &disco::DiscoInfoResult, caps: &Caps) -> bool {
+        caps::hash_caps(&caps::compute_disco(info), caps.hash.algo.clone())
+            .map(|hash| hash == caps.hash)
+            .unwrap_or(false)
+    }
+
     pub fn add_feature(&mut self, feature: &str) -> Result<(), ()> {
         debug!("Adding `{}` feature", feature);
         self.client_features.push(feature.to_string());
@@ -41,16 +246,143 @@ impl DiscoMod {
             .any(|i| i == feature)
     }
 
-    pub fn disco(&mut self, jid: Jid) -> Element {
+    /// Whether any online resource of `contact` supports `feature`, None if no resource
+    /// features are known
+    pub fn peer_supports(
+        &self,
+        account: &Account,
+        contact: &BareJid,
+        feature: &str,
+    ) -> Option<bool> {
+        let resources = self
+            .peer_features
+            .get(account)?
+            .iter()
+            .filter(|(jid, _)| &BareJid::from(Jid::Full((*jid).clone())) == contact)
+            .collect::<Vec<_>>();
+
+        match resources.len() {
+            0 => None,
+            _ => Some(
+                resources
+                    .iter()
+                    .any(|(_, features)| features.iter().any(|i| i == feature)),
+            ),
+        }
+    }
+
+    /// Online resources of `contact` known to support `feature`
+    pub fn peer_resources(
+        &self,
+        account: &Account,
+        contact: &BareJid,
+        feature: &str,
+    ) -> Vec<FullJid> {
+        match self.peer_features.get(account) {
+            Some(peers) => peers
+                .iter()
+                .filter(|(jid, _)| &BareJid::from(Jid::Full((*jid).clone())) == contact)
+                .filter(|(_, features)| features.iter().any(|i| i == feature))
+                .map(|(jid, _)| jid.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Features of an online resource of a contact, if known
+    pub fn peer_features(&self, account: &Account, jid: &FullJid) -> Option<&Vec<String>> {
+        self.peer_features.get(account)?.get(jid)
+    }
+
+    /// Client of an online resource of a contact, as it describes itself
+    pub fn peer_client(&self, account: &Account, jid: &FullJid) -> Option<&String> {
+        self.peer_clients.get(account)?.get(jid)
+    }
+
+    /// Answer to a disco#info query of Aparté, on no node or on the one of its capabilities
+    fn answer(&self, iq: &Iq, query: disco::DiscoInfoQuery) -> Element {
+        let own = Self::caps_node(&self.caps());
+        let answer = match query.node {
+            None => Iq::from_result(iq.id.clone(), Some(self.info(None))),
+            Some(node) if node == own => {
+                Iq::from_result(iq.id.clone(), Some(self.info(Some(node))))
+            }
+            Some(node) => Iq::from_error(
+                iq.id.clone(),
+                StanzaError::new(
+                    ErrorType::Cancel,
+                    DefinedCondition::ItemNotFound,
+                    "en",
+                    format!("Unknown node {}", node),
+                ),
+            ),
+        };
+        match &iq.from {
+            Some(from) => answer.with_to(from.clone()).into(),
+            None => answer.into(),
+        }
+    }
+
+    pub fn disco(&mut self, jid: Jid) -> Iq {
         let id = Uuid::new_v4().to_hyphenated().to_string();
         let query = disco::DiscoInfoQuery { node: None };
-        let iq = Iq::from_get(id, query).with_to(Jid::from_str(&jid.domain()).unwrap());
+        let domain = Jid::from_str(&jid.domain()).unwrap();
+        Iq::from_get(id, query).with_to(domain)
+    }
+
+    /// Features of the server of an account
+    fn server_disco(&mut self, aparte: &mut Aparte, account: &Account, response: IqResponse) {
+        let disco = match response {
+            Ok(Some(el)) => disco::DiscoInfoResult::try_from(el),
+            _ => return,
+        };
+        if let (Ok(disco), Some(server_features)) = (disco, self.server_features.get_mut(account)) {
+            server_features.extend(disco.features.iter().map(|i| i.var.clone()));
+            aparte.schedule(Event::Disco(account.clone()));
+        }
+    }
+
+    /// Services of the server of an account, each described once discovered by Event::Service
+    fn server_items(aparte: &mut Aparte, account: &Account, response: IqResponse) {
+        let items = match response.map(|items| items.map(disco::DiscoItemsResult::try_from)) {
+            Ok(Some(Ok(items))) => items,
+            _ => return,
+        };
+        for item in items.items {
+            let id = Uuid::new_v4().to_hyphenated().to_string();
+            let query = disco::DiscoInfoQuery { node: None };
+            let iq = Iq::from_get(id, query).with_to(item.jid.clone());
+            aparte.send_iq::<Self, _>(account, iq, move |_, aparte, account, response| {
+                if let Ok(Some(Ok(info))) =
+                    response.map(|info| info.map(disco::DiscoInfoResult::try_from))
+                {
+                    aparte.schedule(Event::Service(account.clone(), item.jid, info));
+                }
+            });
+        }
+    }
+
+    pub fn disco_peer(&mut self, jid: FullJid, caps: Option<Caps>) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let query = disco::DiscoInfoQuery {
+            node: caps.as_ref().map(Self::caps_node),
+        };
+        self.queries.insert(id.clone(), jid.clone());
+        if let Some(caps) = caps {
+            self.caps_queries.insert(id.clone(), caps);
+        }
+        let iq = Iq::from_get(id, query).with_to(Jid::Full(jid));
         iq.into()
     }
 }
 
 impl ModTrait for DiscoMod {
-    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(features::new());
+        if let Err(err) = self.load() {
+            warn!("Cannot load capabilities cache: {}", err);
+        }
+
         Ok(())
     }
 
@@ -58,17 +390,115 @@ impl ModTrait for DiscoMod {
         match event {
             Event::Connected(account, jid) => {
                 self.server_features.insert(account.clone(), Vec::new());
-                aparte.send(account, self.disco(jid.clone()));
+                self.peer_features.insert(account.clone(), HashMap::new());
+                self.peer_clients.insert(account.clone(), HashMap::new());
+                let iq = self.disco(jid.clone());
+                aparte.send_iq::<Self, _>(account, iq, |disco, aparte, account, response| {
+                    disco.server_disco(aparte, account, response)
+                });
+                let id = Uuid::new_v4().to_hyphenated().to_string();
+                let domain = Jid::from_str(&jid.clone().domain()).unwrap();
+                let iq = Iq::from_get(id, disco::DiscoItemsQuery { node: None }).with_to(domain);
+                aparte.send_iq::<Self, _>(account, iq, |_, aparte, account, response| {
+                    Self::server_items(aparte, account, response)
+                });
+            }
+            Event::Presence(account, presence) => {
+                if let Some(Jid::Full(from)) = &presence.from {
+                    // Channel occupants are not our contacts
+                    if presence.payloads.iter().any(|p| p.is("x", ns::MUC_USER)) {
+                        return;
+                    }
+
+                    let caps = presence
+                        .payloads
+                        .iter()
+                        .find_map(|payload| Caps::try_from(payload.clone()).ok());
+                    let pending = self.queries.values().any(|jid| jid == from);
+                    let peers = match self.peer_features.get_mut(account) {
+                        Some(peers) => peers,
+                        None => return,
+                    };
+                    let query = match (&presence.type_, caps) {
+                        (PresenceType::Unavailable, _) => {
+                            peers.remove(from);
+                            if let Some(clients) = self.peer_clients.get_mut(account) {
+                                clients.remove(from);
+                            }
+                            None
+                        }
+                        (PresenceType::None, Some(caps)) => {
+                            match self.caps.get(&Self::caps_node(&caps)) {
+                                Some(features) => {
+                                    peers.insert(from.clone(), features.clone());
+                                    None
+                                }
+                                // Capabilities may have changed along the features
+                                None if !pending => Some(Some(caps)),
+                                None => None,
+                            }
+                        }
+                        (PresenceType::None, None) if !peers.contains_key(from) && !pending => {
+                            Some(None)
+                        }
+                        _ => None,
+                    };
+
+                    if let Some(caps) = query {
+                        aparte.send(account, self.disco_peer(from.clone(), caps));
+                    }
+                }
             }
             Event::Iq(account, iq) => match iq.payload.clone() {
                 IqType::Result(Some(el)) => {
                     if let Ok(disco) = disco::DiscoInfoResult::try_from(el) {
-                        if let Some(features) = self.server_features.get_mut(account) {
-                            features.extend(disco.features.iter().map(|i| i.var.clone()));
-                            aparte.schedule(Event::Disco(account.clone()));
+                        if let Some(peer) = self.queries.remove(&iq.id) {
+                            let features: Vec<String> =
+                                disco.features.iter().map(|i| i.var.clone()).collect();
+                            if let Some(caps) = self.caps_queries.remove(&iq.id) {
+                                match Self::verify(&disco, &caps) {
+                                    true => {
+                                        self.caps.insert(Self::caps_node(&caps), features.clone());
+                                        self.save();
+                                    }
+                                    false => {
+                                        warn!("Capabilities of {} don't match its features", peer)
+                                    }
+                                }
+                            }
+                            let client = disco
+                                .identities
+                                .iter()
+                                .find(|identity| identity.category == "client")
+                                .map(|identity| match &identity.name {
+                                    Some(name) => {
+                                        format!(
+                                            "{} ({}/{})",
+                                            name, identity.category, identity.type_
+                                        )
+                                    }
+                                    None => format!("{}/{}", identity.category, identity.type_),
+                                });
+                            if let (Some(client), Some(clients)) =
+                                (client, self.peer_clients.get_mut(account))
+                            {
+                                clients.insert(peer.clone(), client);
+                            }
+                            if let Some(peers) = self.peer_features.get_mut(account) {
+                                peers.insert(peer, features);
+                            }
                         }
                     }
                 }
+                IqType::Get(el) => {
+                    if let Ok(query) = disco::DiscoInfoQuery::try_from(el) {
+                        aparte.send(account, self.answer(iq, query));
+                    }
+                }
+                IqType::Error(_) => {
+                    self.queries.remove(&iq.id);
+                    self.caps_queries.remove(&iq.id);
+                }
                 _ => {}
             },
             _ => {}
@@ -81,3 +511,154 @@ impl fmt::Display for DiscoMod {
         write!(f, "XEP-0030: Service Discovery")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+
+    #[test]
+    fn test_presences_advertise_capabilities_answered_by_disco() {
+        testing::run(async {
+            // Given
+            let mut config = crate::config::Config::default();
+            config.privacy.receipts = true;
+            let mut harness = Harness::with_config(config);
+            harness.connect().await;
+            harness.take_sent("query", ns::DISCO_INFO);
+            let presence = harness.take_sent("presence", ns::DEFAULT_NS).remove(0);
+            let caps = Caps::try_from(presence.get_child("c", ns::CAPS).unwrap().clone()).unwrap();
+
+            // When
+            harness
+                .receive(&format!(
+                    "<iq xmlns='jabber:client' type='get' id='q1' from='juliet@example.org/balcony' to='{{account}}'>
+                        <query xmlns='http://jabber.org/protocol/disco#info' node='{}'/>
+                    </iq>",
+                    DiscoMod::caps_node(&caps)
+                ))
+                .await;
+
+            // Then
+            let answer =
+                Iq::try_from(harness.take_sent("query", ns::DISCO_INFO).remove(0)).unwrap();
+            let info = match answer.payload {
+                IqType::Result(Some(info)) => disco::DiscoInfoResult::try_from(info).unwrap(),
+                _ => panic!("Unexpected answer {:?}", answer),
+            };
+            assert!(DiscoMod::verify(&info, &caps));
+            assert!(info
+                .features
+                .iter()
+                .any(|feature| feature.var == ns::RECEIPTS));
+        });
+    }
+
+    #[test]
+    fn test_capabilities_are_only_queried_once() {
+        testing::run(async {
+            // Given
+            let info = disco::DiscoInfoResult {
+                node: None,
+                identities: vec![disco::Identity::new_anonymous::<_, _, String, String>(
+                    "client", "pc",
+                )],
+                features: vec![
+                    disco::Feature {
+                        var: String::from(ns::DISCO_INFO),
+                    },
+                    disco::Feature {
+                        var: String::from(ns::RECEIPTS),
+                    },
+                ],
+                extensions: Vec::new(),
+            };
+            let caps = Caps::new(
+                "https://example.org/client",
+                caps::hash_caps(&caps::compute_disco(&info), Algo::Sha_1).unwrap(),
+            );
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness.take_sent("query", ns::DISCO_INFO);
+            harness.reply(
+                "query",
+                ns::DISCO_INFO,
+                &format!(
+                    "<iq xmlns='jabber:client' type='result' id='{{id}}' from='juliet@example.org/balcony'>
+                        <query xmlns='http://jabber.org/protocol/disco#info' node='{}'>
+                            <identity category='client' type='pc'/>
+                            <feature var='http://jabber.org/protocol/disco#info'/>
+                            <feature var='urn:xmpp:receipts'/>
+                        </query>
+                    </iq>",
+                    DiscoMod::caps_node(&caps)
+                ),
+            );
+
+            // When
+            for from in &["juliet@example.org/balcony", "juliet@example.org/garden"] {
+                harness
+                    .receive(&format!(
+                        "<presence xmlns='jabber:client' from='{}' to='{{account}}'>
+                            <c xmlns='http://jabber.org/protocol/caps' hash='sha-1' node='https://example.org/client' ver='{}'/>
+                        </presence>",
+                        from,
+                        caps.hash.to_base64()
+                    ))
+                    .await;
+            }
+
+            // Then
+            assert!(harness.take_sent("query", ns::DISCO_INFO).is_empty());
+            let disco = harness.aparte.get_mod::<DiscoMod>();
+            let peers = &disco.peer_features[&harness.account];
+            assert_eq!(peers.len(), 2);
+            assert!(peers
+                .values()
+                .all(|features| features.iter().any(|feature| feature == ns::RECEIPTS)));
+        });
+    }
+
+    #[test]
+    fn test_actions_unavailable_with_a_contact_tell_why() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            let juliet = BareJid::from_str("juliet@example.org").unwrap();
+            {
+                let mut disco = harness.aparte.get_mod_mut::<DiscoMod>();
+                disco
+                    .peer_features
+                    .get_mut(&harness.account)
+                    .unwrap()
+                    .insert(
+                        FullJid::from_str("juliet@example.org/balcony").unwrap(),
+                        vec![String::from(ns::RECEIPTS)],
+                    );
+            }
+
+            // When
+            let actions = actions(&harness.aparte, &harness.account, &juliet).unwrap();
+
+            // Then
+            let why = |name| {
+                actions
+                    .iter()
+                    .find(|(action, _)| action == &name)
+                    .unwrap()
+                    .1
+                    .clone()
+            };
+            assert_eq!(why("receipts"), None);
+            assert_eq!(
+                why("correction"),
+                Some(String::from("not supported by juliet@example.org"))
+            );
+            assert_eq!(
+                why("upload"),
+                Some(String::from("no upload service on the server"))
+            );
+        });
+    }
+}
@@ -0,0 +1,340 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Custom emoji, images standing for `:shortname:` codes in a conversation.
+//!
+//! Sets are configured per conversation, or learned from the XHTML-IM images sent in it whose
+//! alternative text is a shortcode. Shortcodes are kept in text, terminals able to display
+//! images show the image instead once it's fetched. Only `cid:` images are fetched for learned
+//! emoji, HTTP ones must be configured so that rooms can't make us reach arbitrary servers.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::process;
+use std::str::FromStr;
+use tokio::process::Command as ProcessCommand;
+use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType};
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::core::{Aparte, Event, ModTrait};
+use crate::graphics::Protocol;
+use crate::message::Message;
+use crate::mods;
+use crate::mods::bob::{self, Bob};
+
+/// Custom emoji aren't meant to be large images
+const MAX_SIZE: u64 = 256 * 1024;
+
+/// Shortname of a `:shortname:` code
+fn shortname(code: &str) -> Option<&str> {
+    let name = code.strip_prefix(':')?.strip_suffix(':')?;
+    match !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || "_-+".contains(c))
+    {
+        true => Some(name),
+        false => None,
+    }
+}
+
+/// Custom emoji of an XHTML-IM body, as shortnames and image URIs
+fn parse_xhtml(element: &Element) -> Vec<(String, String)> {
+    let mut emoji = Vec::new();
+    if element.is("img", ns::XHTML) {
+        if let (Some(alt), Some(src)) = (element.attr("alt"), element.attr("src")) {
+            if let Some(name) = shortname(alt) {
+                emoji.push((name.to_string(), src.to_string()));
+            }
+        }
+    }
+    for child in element.children() {
+        emoji.extend(parse_xhtml(child));
+    }
+    emoji
+}
+
+/// Content type of an image guessed from its URL
+fn mime(url: &str) -> &'static str {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    match path
+        .rsplit('.')
+        .next()
+        .map(|ext| ext.to_lowercase())
+        .as_deref()
+    {
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Download an image
+async fn download(url: String) -> Result<Bob, String> {
+    let output = ProcessCommand::new("curl")
+        .arg("--fail")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--location")
+        .arg("--max-filesize")
+        .arg(MAX_SIZE.to_string())
+        .arg(&url)
+        .stdin(process::Stdio::null())
+        .output()
+        .await
+        .map_err(|e| format!("Cannot run curl: {}", e))?;
+    match output.status.success() {
+        true => Ok(Bob {
+            type_: mime(&url).to_string(),
+            data: output.stdout,
+        }),
+        false => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+    }
+}
+
+pub struct EmojiMod {
+    /// Image URI of custom emoji, by conversation and shortname
+    sets: HashMap<BareJid, HashMap<String, String>>,
+    /// Emoji waiting for their image, by content id
+    waiting: HashMap<String, Vec<(BareJid, String)>>,
+}
+
+impl EmojiMod {
+    pub fn new() -> Self {
+        Self {
+            sets: HashMap::new(),
+            waiting: HashMap::new(),
+        }
+    }
+
+    /// Shortnames of the custom emoji of a conversation
+    pub fn shortnames(&self, conversation: &BareJid) -> Vec<String> {
+        let mut shortnames = match self.sets.get(conversation) {
+            Some(set) => set.keys().cloned().collect(),
+            None => Vec::new(),
+        };
+        shortnames.sort();
+        shortnames
+    }
+
+    /// Images are only fetched when the terminal can display them inline
+    fn shows_images(aparte: &Aparte) -> bool {
+        let protocol = {
+            let ui = aparte.get_mod::<mods::ui::UIMod>();
+            ui.graphics()
+        };
+        !aparte.config.low_bandwidth && matches!(protocol, Protocol::Kitty | Protocol::Iterm2)
+    }
+
+    fn load_config(&mut self, aparte: &mut Aparte) {
+        let configured = aparte.config.emoji.clone();
+        for (jid, set) in configured {
+            let conversation = match BareJid::from_str(&jid) {
+                Ok(conversation) => conversation,
+                Err(_) => {
+                    aparte.log(format!("Invalid emoji conversation {}", jid));
+                    continue;
+                }
+            };
+            for (name, uri) in set {
+                self.sets
+                    .entry(conversation.clone())
+                    .or_default()
+                    .insert(name.clone(), uri.clone());
+                if !Self::shows_images(aparte) {
+                    continue;
+                }
+                match bob::cid(&uri) {
+                    Some(cid) => self.resolve(aparte, conversation.clone(), name, cid),
+                    None => {
+                        let conversation = conversation.clone();
+                        aparte.spawn(async move {
+                            match download(uri.clone()).await {
+                                Ok(image) => Event::CustomEmoji {
+                                    conversation,
+                                    shortname: name,
+                                    image,
+                                },
+                                Err(err) => Event::Message(
+                                    None,
+                                    Message::log(format!("Cannot fetch emoji {}: {}", uri, err)),
+                                ),
+                            }
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Show a cached image, or wait for it
+    fn resolve(&mut self, aparte: &mut Aparte, conversation: BareJid, name: String, cid: &str) {
+        let cached = {
+            let mut bob = aparte.get_mod_mut::<mods::bob::BobMod>();
+            bob.get(cid)
+        };
+        match cached {
+            Some(image) => aparte.schedule(Event::CustomEmoji {
+                conversation,
+                shortname: name,
+                image,
+            }),
+            None => self
+                .waiting
+                .entry(cid.to_string())
+                .or_default()
+                .push((conversation, name)),
+        }
+    }
+
+    fn handle_message(&mut self, aparte: &mut Aparte, account: &Account, message: &Element) {
+        let message = match XmppParsersMessage::try_from(message.clone()) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        let from = match (&message.from, &message.type_) {
+            (Some(from), MessageType::Chat) | (Some(from), MessageType::Groupchat) => from.clone(),
+            _ => return,
+        };
+        let conversation = BareJid::from(from.clone());
+        let emoji = message
+            .payloads
+            .iter()
+            .filter(|payload| payload.is("html", ns::XHTML_IM))
+            .flat_map(parse_xhtml)
+            .filter(|(_, uri)| bob::cid(uri).is_some())
+            .collect::<Vec<(String, String)>>();
+        if emoji.is_empty() {
+            return;
+        }
+
+        {
+            let mut bob = aparte.get_mod_mut::<mods::bob::BobMod>();
+            bob.collect(&message);
+        }
+        let shows_images = Self::shows_images(aparte);
+        for (name, uri) in emoji {
+            let set = self.sets.entry(conversation.clone()).or_default();
+            // Configured emoji take precedence
+            if set.contains_key(&name) {
+                continue;
+            }
+            set.insert(name.clone(), uri.clone());
+            if !shows_images {
+                continue;
+            }
+
+            let cid = bob::cid(&uri).unwrap();
+            self.resolve(aparte, conversation.clone(), name, cid);
+            if self.waiting.contains_key(cid) {
+                let request = {
+                    let mut bob = aparte.get_mod_mut::<mods::bob::BobMod>();
+                    bob.request(&from, cid)
+                };
+                if let Some(request) = request {
+                    aparte.send(account, request);
+                }
+            }
+        }
+    }
+}
+
+impl ModTrait for EmojiMod {
+    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            // Downloads need the event loop to run
+            Event::Start => self.load_config(aparte),
+            // Configured images missing from the cache are requested to their conversation
+            Event::Connected(account, _) => {
+                let waiting = self
+                    .waiting
+                    .iter()
+                    .filter_map(|(cid, emoji)| Some((cid.clone(), emoji.first()?.0.clone())))
+                    .collect::<Vec<(String, BareJid)>>();
+                for (cid, conversation) in waiting {
+                    let request = {
+                        let mut bob = aparte.get_mod_mut::<mods::bob::BobMod>();
+                        bob.request(&Jid::Bare(conversation), &cid)
+                    };
+                    if let Some(request) = request {
+                        aparte.send(account, request);
+                    }
+                }
+            }
+            Event::Stanza(account, stanza) if stanza.is("message", ns::DEFAULT_NS) => {
+                self.handle_message(aparte, account, stanza)
+            }
+            Event::Bob(cid) if self.waiting.contains_key(cid) => {
+                let image = {
+                    let mut bob = aparte.get_mod_mut::<mods::bob::BobMod>();
+                    bob.get(cid)
+                };
+                if let Some(image) = image {
+                    for (conversation, name) in self.waiting.remove(cid).unwrap() {
+                        aparte.schedule(Event::CustomEmoji {
+                            conversation,
+                            shortname: name,
+                            image: image.clone(),
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for EmojiMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Custom emoji")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_xhtml_emoji() {
+        // Given
+        let html = Element::from_str(
+            r#"<html xmlns="http://jabber.org/protocol/xhtml-im">
+                <body xmlns="http://www.w3.org/1999/xhtml">
+                    <p>Nice <img alt=":parrot:" src="cid:sha1+8f35@bob.xmpp.org"/></p>
+                    <img alt="a cat" src="cid:sha1+0000@bob.xmpp.org"/>
+                    <img alt=":not an emoji:" src="cid:sha1+1111@bob.xmpp.org"/>
+                </body>
+            </html>"#,
+        )
+        .unwrap();
+
+        // When
+        let emoji = parse_xhtml(&html);
+
+        // Then
+        assert_eq!(
+            emoji,
+            vec![(
+                String::from("parrot"),
+                String::from("cid:sha1+8f35@bob.xmpp.org")
+            )]
+        );
+    }
+
+    #[test]
+    fn test_mime_from_url() {
+        assert_eq!(mime("https://example.org/parrot.PNG"), "image/png");
+        assert_eq!(mime("https://example.org/parrot.gif?v=2"), "image/gif");
+        assert_eq!(
+            mime("https://example.org/parrot"),
+            "application/octet-stream"
+        );
+    }
+}
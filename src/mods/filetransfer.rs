@@ -0,0 +1,1629 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Files sent to contacts and received from them peer to peer (XEP-0234).
+//!
+//! A file is offered in a Jingle session carrying it through a SOCKS5 bytestream (XEP-0260),
+//! relayed by a proxy of either server, see `bytestreams`. Each side connects to a candidate of
+//! the other one; when neither can, or the proxy chosen fails, the initiator replaces it with an
+//! in-band bytestream (XEP-0261), see `ibb`. Files offered are only received once accepted, in
+//! the transfer directory.
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, oneshot};
+use uuid::Uuid;
+use xmpp_parsers::ibb::{Stanza, StreamId as IbbStreamId};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::jingle::{
+    Action, Content, ContentId, Creator, Description, Jingle, Reason, ReasonElement, Senders,
+    SessionId, Transport,
+};
+use xmpp_parsers::jingle_s5b::{
+    Candidate, CandidateId, StreamId as S5bStreamId, Transport as S5bTransport, TransportPayload,
+    Type,
+};
+use xmpp_parsers::stanza_error::{DefinedCondition, ErrorType, StanzaError};
+use xmpp_parsers::{jingle_ft, jingle_ibb, ns, BareJid, Jid};
+
+use crate::account::Account;
+use crate::color;
+use crate::command::{Command, CommandParser};
+use crate::config::Config;
+use crate::core::{Aparte, Event, IqResponse, ModTrait};
+use crate::mods;
+use crate::mods::bytestreams::{self, Proxy};
+use crate::mods::upload::{content_type, expand, human_size};
+
+/// Name of the single content of the sessions, the file
+const CONTENT: &str = "file";
+/// Priority of proxy candidates, whose type preference is 10 (XEP-0260)
+const PROXY_PRIORITY: u32 = 10 << 16;
+
+command_def!(transfer,
+r#"/transfer send|accept|decline|list"#,
+{
+    action: Command = {
+        children: {
+            "send": transfer_send,
+            "accept": transfer_accept,
+            "decline": transfer_decline,
+            "list": transfer_list,
+        }
+    },
+});
+
+command_def!(transfer_send,
+r#"/transfer send <file>
+
+    file          Path of the file to send
+
+Description:
+    Send a file to the contact of the current conversation, through a SOCKS5
+    proxy or in band when no proxy can be used. Unlike with /upload the file
+    isn't stored on a server, the contact has to accept it while online.
+
+Examples:
+    /transfer send ~/picture.jpg"#,
+{
+    file: String
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or(String::from("No connection found"))?;
+    let contact = BareJid::from_str(&_command.context)
+        .map_err(|_| String::from("Files can only be sent in a conversation window"))?;
+    let path = expand(&file)?;
+    offer(aparte, &account, &contact, path)
+});
+
+command_def!(transfer_accept,
+r#"/transfer accept [<contact>]
+
+    contact       Contact whose file to receive, the last one offering one if
+                  omitted
+
+Description:
+    Accept a file offered by a contact, saved in the transfer directory (the
+    download directory unless configured otherwise).
+
+Examples:
+    /transfer accept
+    /transfer accept juliet@example.org"#,
+{
+    contact: Option<BareJid> = {
+        completion: (|aparte, _command| { offering(aparte) })
+    }
+},
+|aparte, _command| {
+    let mut transfer = {
+        let mut transfers = aparte.get_mod_mut::<FileTransferMod>();
+        transfers.take_offer(&contact)?
+    };
+    transfer.accept(aparte)?;
+    let mut transfers = aparte.get_mod_mut::<FileTransferMod>();
+    transfers.transfers.push(transfer);
+    Ok(())
+});
+
+command_def!(transfer_decline,
+r#"/transfer decline [<contact>]
+
+    contact       Contact whose file to decline, the last one offering one if
+                  omitted
+
+Description:
+    Decline a file offered by a contact.
+
+Examples:
+    /transfer decline
+    /transfer decline juliet@example.org"#,
+{
+    contact: Option<BareJid> = {
+        completion: (|aparte, _command| { offering(aparte) })
+    }
+},
+|aparte, _command| {
+    let transfer = {
+        let mut transfers = aparte.get_mod_mut::<FileTransferMod>();
+        transfers.take_offer(&contact)?
+    };
+    transfer.terminate(aparte, Reason::Decline);
+    aparte.log(format!("{} from {} declined", transfer.name(), transfer.peer));
+    Ok(())
+});
+
+command_def!(
+    transfer_list,
+    r#"/transfer list
+
+Description:
+    List the files being sent and received, along with the ones offered by
+    contacts."#,
+    {},
+    |aparte, _command| {
+        let lines = {
+            let transfers = aparte.get_mod::<FileTransferMod>();
+            transfers
+                .transfers
+                .iter()
+                .map(|transfer| {
+                    let direction = match transfer.sending {
+                        true => "to",
+                        false => "from",
+                    };
+                    let line = format!(
+                        "  {} ({}) {} {}",
+                        transfer.name(),
+                        transfer.size(),
+                        direction,
+                        transfer.peer
+                    );
+                    match transfer.accepted {
+                        true => line,
+                        false => color::dimmed(&format!("{}, not accepted yet", line)),
+                    }
+                })
+                .collect::<Vec<String>>()
+        };
+        match lines.is_empty() {
+            true => aparte.log(String::from("No file transfer")),
+            false => aparte.log(format!("File transfers:\n{}", lines.join("\n"))),
+        }
+        Ok(())
+    }
+);
+
+/// Contacts whose offers wait for an answer
+fn offering(aparte: &Aparte) -> Vec<String> {
+    let transfers = aparte.get_mod::<FileTransferMod>();
+    transfers
+        .transfers
+        .iter()
+        .filter(|transfer| !transfer.sending && !transfer.accepted)
+        .map(|transfer| BareJid::from(transfer.peer.clone()).to_string())
+        .collect()
+}
+
+/// Offer a file to a resource of a contact able to receive it
+pub fn offer(
+    aparte: &mut Aparte,
+    account: &Account,
+    contact: &BareJid,
+    path: PathBuf,
+) -> Result<(), String> {
+    let metadata =
+        std::fs::metadata(&path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a file", path.display()));
+    }
+    let name = path
+        .file_name()
+        .ok_or(format!("Invalid file name {}", path.display()))?
+        .to_string_lossy()
+        .to_string();
+
+    let (peer, features) = {
+        let disco = aparte.get_mod::<mods::disco::DiscoMod>();
+        let peer = disco
+            .peer_resources(account, contact, ns::JINGLE_FT)
+            .into_iter()
+            .next()
+            .ok_or(format!("No client of {} can receive files", contact))?;
+        let features = disco.peer_features(account, &peer).cloned();
+        (Jid::Full(peer), features.unwrap_or_default())
+    };
+    let supports = |feature| features.iter().any(|i| i == feature);
+    let stream = if supports(ns::JINGLE_S5B) {
+        let bytestreams = aparte.get_mod::<mods::bytestreams::BytestreamsMod>();
+        Stream::Socks5(Box::new(Socks5::new(
+            Uuid::new_v4().to_hyphenated().to_string(),
+            candidates(bytestreams.proxies(account)),
+        )))
+    } else if supports(ns::JINGLE_IBB) {
+        Stream::Ibb(in_band())
+    } else {
+        return Err(format!(
+            "{} supports neither SOCKS5 nor in-band bytestreams",
+            peer
+        ));
+    };
+
+    let file = jingle_ft::File::new()
+        .with_name(name)
+        .with_size(metadata.len())
+        .with_media_type(content_type(&path).to_string());
+    let mut transfer = Transfer::new(account, Uuid::new_v4().to_hyphenated().to_string(), peer);
+    transfer.sending = true;
+    transfer.file = file;
+    transfer.path = Some(path);
+    transfer.stream = stream;
+
+    let jingle = transfer
+        .jingle(Action::SessionInitiate)
+        .with_initiator(Jid::Full(account.clone()))
+        .add_content(transfer.content(true));
+    let iq = Iq::from_set(Uuid::new_v4().to_hyphenated().to_string(), jingle)
+        .with_to(transfer.peer.clone());
+    let sid = transfer.sid.clone();
+    aparte.send_iq::<FileTransferMod, _>(
+        account,
+        iq,
+        move |transfers, aparte, account, response| {
+            transfers.answered(aparte, account, &sid, response)
+        },
+    );
+    aparte.log(format!(
+        "Offering {} ({}) to {}",
+        transfer.name(),
+        transfer.size(),
+        transfer.peer
+    ));
+
+    let mut transfers = aparte.get_mod_mut::<FileTransferMod>();
+    transfers.transfers.push(transfer);
+    Ok(())
+}
+
+/// Proxies offered as candidates
+fn candidates(proxies: &[Proxy]) -> Vec<Candidate> {
+    proxies
+        .iter()
+        .map(|proxy| {
+            let cid = CandidateId(Uuid::new_v4().to_hyphenated().to_string());
+            Candidate::new(
+                cid,
+                proxy.address.ip(),
+                proxy.streamhost.jid.clone(),
+                PROXY_PRIORITY,
+            )
+            .with_port(proxy.address.port())
+            .with_type(Type::Proxy)
+        })
+        .collect()
+}
+
+fn in_band() -> jingle_ibb::Transport {
+    jingle_ibb::Transport {
+        block_size: mods::ibb::BLOCK_SIZE,
+        sid: IbbStreamId(Uuid::new_v4().to_hyphenated().to_string()),
+        stanza: Stanza::Iq,
+    }
+}
+
+/// Directory received files are saved in
+fn directory(config: &Config) -> Result<PathBuf, String> {
+    match &config.transfer.directory {
+        Some(directory) => expand(directory),
+        None => dirs::download_dir()
+            .or_else(dirs::home_dir)
+            .ok_or(String::from("Cannot find download directory")),
+    }
+}
+
+/// Create the file a received file is written to, named after it but never overwriting another
+fn create(directory: &Path, name: &str) -> Result<(PathBuf, File), String> {
+    std::fs::create_dir_all(directory)
+        .map_err(|e| format!("Cannot create {}: {}", directory.display(), e))?;
+    // The name is chosen by the peer, only its last component is kept
+    let name = Path::new(name)
+        .file_name()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(CONTENT));
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = name
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    for copy in 0.. {
+        let path = match copy {
+            0 => directory.join(&name),
+            copy => directory.join(format!("{} ({}){}", stem, copy, extension)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(format!("Cannot create {}: {}", path.display(), err)),
+        }
+    }
+    unreachable!()
+}
+
+/// Write a file to a bytestream
+async fn send_file(mut stream: TcpStream, path: PathBuf) -> Result<u64, String> {
+    let mut file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let sent = tokio::io::copy(&mut file, &mut stream)
+        .await
+        .map_err(|e| e.to_string())?;
+    stream.shutdown().await.map_err(|e| e.to_string())?;
+    Ok(sent)
+}
+
+/// Write the data of a bytestream to a file, no more than the size announced if any
+async fn receive_file(stream: TcpStream, file: File, size: Option<u64>) -> Result<u64, String> {
+    let mut file = tokio::fs::File::from_std(file);
+    let mut stream = stream.take(size.unwrap_or(u64::MAX));
+    let received = tokio::io::copy(&mut stream, &mut file)
+        .await
+        .map_err(|e| e.to_string())?;
+    file.flush().await.map_err(|e| e.to_string())?;
+    Ok(received)
+}
+
+/// SOCKS5 bytestream (XEP-0260)
+struct Socks5 {
+    sid: String,
+    /// Candidates we offered, proxies of our server
+    ours: Vec<Candidate>,
+    /// Candidates offered by the peer, along with the destination address it gave
+    theirs: Vec<Candidate>,
+    dstaddr: Option<String>,
+    state: Socks5State,
+    /// Connection given by the task establishing it, once done
+    connection: Option<oneshot::Receiver<TcpStream>>,
+    stream: Option<TcpStream>,
+}
+
+enum Socks5State {
+    /// Each side trying the candidates of the other one, along with the candidate each one
+    /// connected to once known, None if it couldn't connect to any
+    Negotiating {
+        local: Option<Option<Candidate>>,
+        remote: Option<Option<Candidate>>,
+    },
+    /// Connecting to our proxy candidate chosen by the peer, to activate it
+    Activating(Candidate),
+    /// Connected to the proxy candidate of the peer, waiting for the peer to activate it
+    Waiting(Candidate),
+    Streaming,
+}
+
+impl Socks5 {
+    fn new(sid: String, ours: Vec<Candidate>) -> Self {
+        Self {
+            sid,
+            ours,
+            theirs: Vec::new(),
+            dstaddr: None,
+            state: Socks5State::Negotiating {
+                local: None,
+                remote: None,
+            },
+            connection: None,
+            stream: None,
+        }
+    }
+}
+
+/// Bytestream carrying a file
+enum Stream {
+    Socks5(Box<Socks5>),
+    Ibb(jingle_ibb::Transport),
+}
+
+impl Stream {
+    fn sid(&self) -> &str {
+        match self {
+            Stream::Socks5(socks5) => &socks5.sid,
+            Stream::Ibb(transport) => &transport.sid.0,
+        }
+    }
+}
+
+struct Transfer {
+    account: Account,
+    /// Id of the Jingle session
+    sid: String,
+    /// Full jid of the contact
+    peer: Jid,
+    /// Whether the file is ours, we are then the initiator of the session
+    sending: bool,
+    file: jingle_ft::File,
+    /// File sent, or where the file received is written once accepted
+    path: Option<PathBuf>,
+    /// File received, until given to the bytestream writing to it
+    output: Option<File>,
+    accepted: bool,
+    /// Whether all the data went through
+    done: bool,
+    stream: Stream,
+    /// Dropped along the transfer, stopping its tasks
+    cancel: broadcast::Sender<()>,
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        // A file partially received is of no use
+        if let (false, Some(path)) = (self.sending || self.done, &self.path) {
+            if let Err(err) = std::fs::remove_file(path) {
+                warn!("Cannot remove {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+impl Transfer {
+    fn new(account: &Account, sid: String, peer: Jid) -> Self {
+        Self {
+            account: account.clone(),
+            sid,
+            peer,
+            sending: false,
+            file: jingle_ft::File::new(),
+            path: None,
+            output: None,
+            accepted: false,
+            done: false,
+            stream: Stream::Ibb(in_band()),
+            cancel: broadcast::channel(1).0,
+        }
+    }
+
+    fn name(&self) -> String {
+        self.file
+            .name
+            .clone()
+            .unwrap_or_else(|| String::from(CONTENT))
+    }
+
+    fn size(&self) -> String {
+        match self.file.size {
+            Some(size) => human_size(size),
+            None => String::from("unknown size"),
+        }
+    }
+
+    fn own(&self) -> Jid {
+        Jid::Full(self.account.clone())
+    }
+
+    fn jingle(&self, action: Action) -> Jingle {
+        Jingle::new(action, SessionId(self.sid.clone()))
+    }
+
+    /// Content of the session with our transport, and the description of the file if needed
+    fn content(&self, description: bool) -> Content {
+        let content = Content::new(Creator::Initiator, ContentId(String::from(CONTENT)))
+            .with_senders(Senders::Initiator)
+            .with_transport(self.transport());
+        match description {
+            true => content.with_description(Description::Unknown(
+                jingle_ft::Description {
+                    file: self.file.clone(),
+                }
+                .into(),
+            )),
+            false => content,
+        }
+    }
+
+    fn transport(&self) -> Transport {
+        match &self.stream {
+            Stream::Socks5(socks5) => Transport::Socks5(
+                S5bTransport::new(S5bStreamId(socks5.sid.clone()))
+                    .with_dstaddr(bytestreams::dstaddr(&socks5.sid, &self.own(), &self.peer))
+                    .with_payload(TransportPayload::Candidates(socks5.ours.clone())),
+            ),
+            Stream::Ibb(transport) => Transport::Ibb(transport.clone()),
+        }
+    }
+
+    fn send(&self, aparte: &mut Aparte, jingle: Jingle) {
+        let iq = Iq::from_set(Uuid::new_v4().to_hyphenated().to_string(), jingle)
+            .with_to(self.peer.clone());
+        aparte.send(&self.account, iq.into());
+    }
+
+    /// Tell the peer about our side of the SOCKS5 bytestream
+    fn send_socks5(&self, aparte: &mut Aparte, payload: TransportPayload) {
+        let socks5 = match &self.stream {
+            Stream::Socks5(socks5) => socks5,
+            Stream::Ibb(_) => return,
+        };
+        let transport = S5bTransport::new(S5bStreamId(socks5.sid.clone())).with_payload(payload);
+        let content = Content::new(Creator::Initiator, ContentId(String::from(CONTENT)))
+            .with_transport(transport);
+        self.send(
+            aparte,
+            self.jingle(Action::TransportInfo).add_content(content),
+        );
+    }
+
+    fn terminate(&self, aparte: &mut Aparte, reason: Reason) {
+        let reason = ReasonElement {
+            reason,
+            texts: Default::default(),
+        };
+        self.send(
+            aparte,
+            self.jingle(Action::SessionTerminate).set_reason(reason),
+        );
+    }
+
+    /// Accept a file offered, creating the file it is written to
+    fn accept(&mut self, aparte: &mut Aparte) -> Result<(), String> {
+        let directory = directory(&aparte.config)?;
+        let (path, file) = create(&directory, &self.name())?;
+        self.path = Some(path);
+        self.accepted = true;
+        match &mut self.stream {
+            Stream::Socks5(socks5) => {
+                let bytestreams = aparte.get_mod::<mods::bytestreams::BytestreamsMod>();
+                socks5.ours = candidates(bytestreams.proxies(&self.account));
+                self.output = Some(file);
+            }
+            Stream::Ibb(transport) => {
+                let mut ibb = aparte.get_mod_mut::<mods::ibb::IbbMod>();
+                ibb.expect(
+                    &self.account,
+                    self.peer.clone(),
+                    transport.sid.0.clone(),
+                    file,
+                );
+            }
+        }
+
+        let jingle = self
+            .jingle(Action::SessionAccept)
+            .with_responder(self.own())
+            .add_content(self.content(true));
+        let iq = Iq::from_set(Uuid::new_v4().to_hyphenated().to_string(), jingle)
+            .with_to(self.peer.clone());
+        let sid = self.sid.clone();
+        aparte.send_iq::<FileTransferMod, _>(
+            &self.account,
+            iq,
+            move |transfers, aparte, account, response| {
+                transfers.answered(aparte, account, &sid, response)
+            },
+        );
+        aparte.log(format!("Receiving {} from {}", self.name(), self.peer));
+
+        if let Stream::Socks5(_) = self.stream {
+            self.try_candidates(aparte);
+        }
+        Ok(())
+    }
+
+    /// Connect to the first candidate of the peer accepting the connection, by priority
+    fn try_candidates(&mut self, aparte: &mut Aparte) {
+        let own = self.own();
+        let peer = &self.peer;
+        let socks5 = match &mut self.stream {
+            Stream::Socks5(socks5) => socks5,
+            Stream::Ibb(_) => return,
+        };
+        let mut candidates = socks5.theirs.clone();
+        candidates.sort_by_key(|candidate| u32::MAX - candidate.priority);
+        let dstaddr = socks5
+            .dstaddr
+            .clone()
+            .unwrap_or_else(|| bytestreams::dstaddr(&socks5.sid, peer, &own));
+        let (sink, connection) = oneshot::channel();
+        socks5.connection = Some(connection);
+
+        let account = self.account.clone();
+        let sid = socks5.sid.clone();
+        let mut cancel = self.cancel.subscribe();
+        aparte.spawn(async move {
+            let connect = async {
+                let mut errors = Vec::new();
+                for candidate in candidates {
+                    let port = candidate.port.unwrap_or(1080);
+                    let address = (candidate.host, port).into();
+                    match bytestreams::connect(address, &dstaddr).await {
+                        Ok(stream) => return Ok((candidate.cid, stream)),
+                        Err(err) => errors.push(err),
+                    }
+                }
+                match errors.is_empty() {
+                    true => Err(String::from("No candidate")),
+                    false => Err(errors.join(", ")),
+                }
+            };
+            let result = tokio::select! {
+                result = connect => result,
+                _ = cancel.recv() => Err(String::from("Cancelled")),
+            };
+            Event::Socks5 {
+                account,
+                sid,
+                result: result.map(|(cid, stream)| {
+                    let _ = sink.send(stream);
+                    cid.0
+                }),
+            }
+        });
+    }
+
+    /// Connect to our proxy candidate chosen by the peer
+    fn connect_proxy(&mut self, aparte: &mut Aparte, candidate: &Candidate) {
+        let own = self.own();
+        let socks5 = match &mut self.stream {
+            Stream::Socks5(socks5) => socks5,
+            Stream::Ibb(_) => return,
+        };
+        let dstaddr = bytestreams::dstaddr(&socks5.sid, &own, &self.peer);
+        let address = (candidate.host, candidate.port.unwrap_or(1080)).into();
+        let (sink, connection) = oneshot::channel();
+        socks5.connection = Some(connection);
+        socks5.stream = None;
+
+        let account = self.account.clone();
+        let sid = socks5.sid.clone();
+        let cid = candidate.cid.0.clone();
+        let mut cancel = self.cancel.subscribe();
+        aparte.spawn(async move {
+            let result = tokio::select! {
+                result = bytestreams::connect(address, &dstaddr) => result,
+                _ = cancel.recv() => Err(String::from("Cancelled")),
+            };
+            Event::Socks5 {
+                account,
+                sid,
+                result: result.map(|stream| {
+                    let _ = sink.send(stream);
+                    cid
+                }),
+            }
+        });
+    }
+
+    /// Choose the candidate to use once both sides tried the candidates of the other one
+    fn nominate(&mut self, aparte: &mut Aparte) {
+        let socks5 = match &mut self.stream {
+            Stream::Socks5(socks5) => socks5,
+            Stream::Ibb(_) => return,
+        };
+        let (local, remote) = match &socks5.state {
+            Socks5State::Negotiating {
+                local: Some(local),
+                remote: Some(remote),
+            } => (local.clone(), remote.clone()),
+            _ => return,
+        };
+
+        // The candidate of highest priority wins, the one of the initiator on a tie
+        let candidate = match (local, remote) {
+            (None, None) => {
+                if self.sending {
+                    self.fall_back(aparte);
+                }
+                return;
+            }
+            (Some(local), None) => Err(local),
+            (None, Some(remote)) => Ok(remote),
+            (Some(local), Some(remote)) => {
+                match remote.priority > local.priority
+                    || (remote.priority == local.priority && self.sending)
+                {
+                    true => Ok(remote),
+                    false => Err(local),
+                }
+            }
+        };
+        match candidate {
+            // Ours, a proxy both sides connect to
+            Ok(candidate) => {
+                socks5.state = Socks5State::Activating(candidate.clone());
+                self.connect_proxy(aparte, &candidate);
+            }
+            Err(candidate) if candidate.type_ == Type::Proxy => {
+                socks5.state = Socks5State::Waiting(candidate);
+            }
+            Err(_) => self.stream_data(aparte),
+        }
+    }
+
+    /// Send or receive the file through the SOCKS5 bytestream established
+    fn stream_data(&mut self, aparte: &mut Aparte) {
+        let socks5 = match &mut self.stream {
+            Stream::Socks5(socks5) => socks5,
+            Stream::Ibb(_) => return,
+        };
+        socks5.state = Socks5State::Streaming;
+        let account = self.account.clone();
+        let sid = socks5.sid.clone();
+        let stream = socks5.stream.take();
+        let output = self.output.take();
+        let path = self.path.clone();
+        let size = self.file.size;
+        let sending = self.sending;
+        let mut cancel = self.cancel.subscribe();
+        aparte.spawn(async move {
+            let transfer = async {
+                match (stream, sending, path, output) {
+                    (Some(stream), true, Some(path), _) => send_file(stream, path).await,
+                    (Some(stream), false, _, Some(output)) => {
+                        receive_file(stream, output, size).await
+                    }
+                    _ => Err(String::from("Connection lost")),
+                }
+            };
+            let result = tokio::select! {
+                result = transfer => result,
+                _ = cancel.recv() => Err(String::from("Cancelled")),
+            };
+            Event::Transferred {
+                account,
+                sid,
+                result,
+            }
+        });
+    }
+
+    /// Replace the SOCKS5 bytestream with an in-band one
+    fn fall_back(&mut self, aparte: &mut Aparte) {
+        self.stream = Stream::Ibb(in_band());
+        let content = Content::new(Creator::Initiator, ContentId(String::from(CONTENT)))
+            .with_transport(self.transport());
+        self.send(
+            aparte,
+            self.jingle(Action::TransportReplace).add_content(content),
+        );
+    }
+
+    /// A proxy couldn't be used, the initiator then falling back in band
+    fn proxy_failed(&mut self, aparte: &mut Aparte, err: &str) {
+        warn!("SOCKS5 proxy of {} failed: {}", self.sid, err);
+        self.send_socks5(aparte, TransportPayload::ProxyError);
+        if self.sending {
+            self.fall_back(aparte);
+        }
+    }
+
+    /// Start sending the file in band
+    fn send_in_band(&mut self, aparte: &mut Aparte) -> Result<(), String> {
+        let (transport, path) = match (&self.stream, &self.path) {
+            (Stream::Ibb(transport), Some(path)) => (transport, path),
+            _ => return Err(String::from("No in-band bytestream")),
+        };
+        mods::ibb::send(
+            aparte,
+            &self.account,
+            self.peer.clone(),
+            transport.sid.0.clone(),
+            path,
+            transport.block_size,
+        )
+    }
+}
+
+pub struct FileTransferMod {
+    /// Transfers in progress and files offered, oldest first
+    transfers: Vec<Transfer>,
+}
+
+impl FileTransferMod {
+    pub fn new() -> Self {
+        Self {
+            transfers: Vec::new(),
+        }
+    }
+
+    /// Take the last file offered by a contact, or by anyone
+    fn take_offer(&mut self, contact: &Option<BareJid>) -> Result<Transfer, String> {
+        let index = self
+            .transfers
+            .iter()
+            .rposition(|transfer| {
+                !transfer.sending
+                    && !transfer.accepted
+                    && match contact {
+                        Some(contact) => &BareJid::from(transfer.peer.clone()) == contact,
+                        None => true,
+                    }
+            })
+            .ok_or(match contact {
+                Some(contact) => format!("No file offered by {}", contact),
+                None => String::from("No file offered"),
+            })?;
+        Ok(self.transfers.remove(index))
+    }
+
+    fn position(&self, account: &Account, sid: &str) -> Option<usize> {
+        self.transfers
+            .iter()
+            .position(|transfer| &transfer.account == account && transfer.sid == sid)
+    }
+
+    /// Transfer using the bytestream with the given sid
+    fn by_stream(&mut self, account: &Account, sid: &str) -> Option<usize> {
+        self.transfers
+            .iter()
+            .position(|transfer| &transfer.account == account && transfer.stream.sid() == sid)
+    }
+
+    /// Drop a transfer, along with the in-band bytestream it may be using
+    fn remove(&mut self, aparte: &mut Aparte, index: usize) -> Transfer {
+        let transfer = self.transfers.remove(index);
+        let mut ibb = aparte.get_mod_mut::<mods::ibb::IbbMod>();
+        ibb.cancel(&transfer.account, transfer.stream.sid());
+        transfer
+    }
+
+    /// Answer of the peer to the offer of a file, or to its acceptance
+    fn answered(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        sid: &str,
+        response: IqResponse,
+    ) {
+        if let (Err(err), Some(index)) = (response, self.position(account, sid)) {
+            let transfer = self.remove(aparte, index);
+            aparte.log(format!(
+                "Transfer of {} with {} failed: {}",
+                transfer.name(),
+                transfer.peer,
+                err
+            ));
+        }
+    }
+
+    /// File offered by a peer
+    fn offered(&mut self, aparte: &mut Aparte, account: &Account, from: &Jid, jingle: Jingle) {
+        let mut transfer = Transfer::new(account, jingle.sid.0.clone(), from.clone());
+        let content = match jingle.contents.into_iter().next() {
+            Some(content) => content,
+            None => return transfer.terminate(aparte, Reason::UnsupportedApplications),
+        };
+        // Files requested rather than offered aren't supported
+        let description = match (content.senders, content.description) {
+            (Senders::Initiator | Senders::Both, Some(Description::Unknown(description))) => {
+                jingle_ft::Description::try_from(description).ok()
+            }
+            _ => None,
+        };
+        transfer.file = match description {
+            Some(description) => description.file,
+            None => return transfer.terminate(aparte, Reason::UnsupportedApplications),
+        };
+        transfer.stream = match content.transport {
+            Some(Transport::Socks5(transport)) => {
+                let mut socks5 = Socks5::new(transport.sid.0, Vec::new());
+                if let TransportPayload::Candidates(candidates) = transport.payload {
+                    socks5.theirs = candidates;
+                }
+                socks5.dstaddr = transport.dstaddr;
+                Stream::Socks5(Box::new(socks5))
+            }
+            Some(Transport::Ibb(transport)) => Stream::Ibb(transport),
+            _ => return transfer.terminate(aparte, Reason::UnsupportedTransports),
+        };
+
+        aparte.log(format!(
+            "{} offers {} ({})\n{}",
+            transfer.peer,
+            transfer.name(),
+            transfer.size(),
+            color::dimmed(&format!(
+                "answer with: /transfer accept|decline {}",
+                BareJid::from(transfer.peer.clone())
+            ))
+        ));
+        self.transfers.push(transfer);
+    }
+
+    /// Action of the peer on a session, None if it isn't supported
+    fn handle_jingle(&mut self, aparte: &mut Aparte, index: usize, jingle: Jingle) -> Option<()> {
+        let transport = jingle
+            .contents
+            .into_iter()
+            .next()
+            .and_then(|content| content.transport);
+        let transfer = &mut self.transfers[index];
+        match (jingle.action, transport) {
+            (Action::SessionAccept, transport) if transfer.sending => {
+                transfer.accepted = true;
+                match (&mut transfer.stream, transport) {
+                    (Stream::Socks5(socks5), Some(Transport::Socks5(transport))) => {
+                        if let TransportPayload::Candidates(candidates) = transport.payload {
+                            socks5.theirs = candidates;
+                        }
+                        socks5.dstaddr = transport.dstaddr;
+                        transfer.try_candidates(aparte);
+                    }
+                    (Stream::Ibb(_), Some(Transport::Ibb(_))) => {
+                        if let Err(err) = transfer.send_in_band(aparte) {
+                            self.failed(aparte, index, &err);
+                        }
+                    }
+                    _ => {
+                        transfer.terminate(aparte, Reason::IncompatibleParameters);
+                        self.remove(aparte, index);
+                    }
+                }
+            }
+            (Action::SessionTerminate, _) => {
+                let transfer = self.remove(aparte, index);
+                let reason = jingle.reason;
+                aparte.log(match reason.as_ref().map(|reason| &reason.reason) {
+                    Some(Reason::Success) if transfer.sending => {
+                        format!("{} received {}", transfer.peer, transfer.name())
+                    }
+                    Some(Reason::Decline) => {
+                        format!("{} declined {}", transfer.peer, transfer.name())
+                    }
+                    _ if !transfer.accepted && !transfer.sending => format!(
+                        "{} doesn't offer {} anymore",
+                        transfer.peer,
+                        transfer.name()
+                    ),
+                    _ => format!(
+                        "Transfer of {} with {} ended: {}",
+                        transfer.name(),
+                        transfer.peer,
+                        match reason {
+                            Some(reason) => reason.to_string(),
+                            None => String::from("no reason"),
+                        }
+                    ),
+                });
+            }
+            (Action::TransportInfo, Some(Transport::Socks5(transport))) => {
+                let socks5 = match &mut transfer.stream {
+                    Stream::Socks5(socks5) if socks5.sid == transport.sid.0 => socks5,
+                    _ => return Some(()),
+                };
+                match (&mut socks5.state, transport.payload) {
+                    (
+                        Socks5State::Negotiating {
+                            remote: remote @ None,
+                            ..
+                        },
+                        TransportPayload::CandidateUsed(cid),
+                    ) => {
+                        let candidate = socks5.ours.iter().find(|candidate| candidate.cid == cid);
+                        *remote = Some(candidate.cloned());
+                        transfer.nominate(aparte);
+                    }
+                    (
+                        Socks5State::Negotiating {
+                            remote: remote @ None,
+                            ..
+                        },
+                        TransportPayload::CandidateError,
+                    ) => {
+                        *remote = Some(None);
+                        transfer.nominate(aparte);
+                    }
+                    (Socks5State::Waiting(candidate), TransportPayload::Activated(cid))
+                        if candidate.cid == cid =>
+                    {
+                        transfer.stream_data(aparte);
+                    }
+                    (_, TransportPayload::ProxyError) if transfer.sending => {
+                        transfer.fall_back(aparte);
+                    }
+                    _ => {}
+                }
+            }
+            (Action::TransportReplace, Some(Transport::Ibb(transport))) if !transfer.sending => {
+                let output = match transfer.output.take() {
+                    Some(output) => output,
+                    None => {
+                        let content =
+                            Content::new(Creator::Initiator, ContentId(String::from(CONTENT)))
+                                .with_transport(transport);
+                        let jingle = transfer
+                            .jingle(Action::TransportReject)
+                            .add_content(content);
+                        transfer.send(aparte, jingle);
+                        return Some(());
+                    }
+                };
+                transfer.stream = Stream::Ibb(transport.clone());
+                {
+                    let mut ibb = aparte.get_mod_mut::<mods::ibb::IbbMod>();
+                    ibb.expect(
+                        &transfer.account,
+                        transfer.peer.clone(),
+                        transport.sid.0.clone(),
+                        output,
+                    );
+                }
+                let content = Content::new(Creator::Initiator, ContentId(String::from(CONTENT)))
+                    .with_transport(transport);
+                let jingle = transfer
+                    .jingle(Action::TransportAccept)
+                    .add_content(content);
+                transfer.send(aparte, jingle);
+            }
+            (Action::TransportAccept, Some(Transport::Ibb(_))) if transfer.sending => {
+                if let Err(err) = transfer.send_in_band(aparte) {
+                    self.failed(aparte, index, &err);
+                }
+            }
+            (Action::TransportReject, _) if transfer.sending => {
+                self.failed(aparte, index, "no bytestream left to try");
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// End a transfer that cannot go on
+    fn failed(&mut self, aparte: &mut Aparte, index: usize, err: &str) {
+        let transfer = self.remove(aparte, index);
+        transfer.terminate(aparte, Reason::FailedTransport);
+        aparte.log(format!(
+            "Transfer of {} with {} failed: {}",
+            transfer.name(),
+            transfer.peer,
+            err
+        ));
+    }
+
+    fn handle_socks5(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        sid: &str,
+        result: &Result<String, String>,
+    ) {
+        let index = match self.by_stream(account, sid) {
+            Some(index) => index,
+            None => return,
+        };
+        let transfer = &mut self.transfers[index];
+        let socks5 = match &mut transfer.stream {
+            Stream::Socks5(socks5) => socks5,
+            Stream::Ibb(_) => return,
+        };
+        socks5.stream = socks5
+            .connection
+            .take()
+            .and_then(|mut connection| connection.try_recv().ok());
+
+        let candidate = result.as_ref().ok().and_then(|cid| {
+            socks5
+                .theirs
+                .iter()
+                .find(|candidate| &candidate.cid.0 == cid)
+                .cloned()
+        });
+
+        match (&mut socks5.state, result) {
+            (
+                Socks5State::Negotiating {
+                    local: local @ None,
+                    ..
+                },
+                _,
+            ) => {
+                let payload = match &candidate {
+                    Some(candidate) => TransportPayload::CandidateUsed(candidate.cid.clone()),
+                    None => TransportPayload::CandidateError,
+                };
+                *local = Some(candidate);
+                transfer.send_socks5(aparte, payload);
+                transfer.nominate(aparte);
+            }
+            (Socks5State::Activating(candidate), Ok(_)) => {
+                let iq = bytestreams::activate(candidate.jid.clone(), sid, &transfer.peer);
+                let sid = transfer.sid.clone();
+                aparte.send_iq::<Self, _>(
+                    account,
+                    iq,
+                    move |transfers, aparte, account, response| {
+                        transfers.activated(aparte, account, &sid, response)
+                    },
+                );
+            }
+            (Socks5State::Activating(_), Err(err)) => {
+                let err = err.clone();
+                transfer.proxy_failed(aparte, &err);
+            }
+            _ => {}
+        }
+    }
+
+    /// Answer of our proxy to its activation
+    fn activated(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        sid: &str,
+        response: IqResponse,
+    ) {
+        let transfer = match self.position(account, sid) {
+            Some(index) => &mut self.transfers[index],
+            None => return,
+        };
+        let cid = match &transfer.stream {
+            Stream::Socks5(socks5) => match &socks5.state {
+                Socks5State::Activating(candidate) => candidate.cid.clone(),
+                _ => return,
+            },
+            Stream::Ibb(_) => return,
+        };
+        match response {
+            Ok(_) => {
+                transfer.send_socks5(aparte, TransportPayload::Activated(cid));
+                transfer.stream_data(aparte);
+            }
+            Err(err) => transfer.proxy_failed(aparte, &err.to_string()),
+        }
+    }
+
+    fn handle_transferred(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        sid: &str,
+        result: &Result<u64, String>,
+    ) {
+        let index = match self.by_stream(account, sid) {
+            Some(index) => index,
+            None => return,
+        };
+        let transfer = &mut self.transfers[index];
+        let result = match (result, transfer.file.size) {
+            (Ok(received), Some(size)) if !transfer.sending && *received != size => Err(format!(
+                "{} received out of {}",
+                human_size(*received),
+                human_size(size)
+            )),
+            (result, _) => result.clone(),
+        };
+        match result {
+            // The receiver ends the session once it got everything
+            Ok(_) if transfer.sending => transfer.done = true,
+            Ok(_) => {
+                transfer.done = true;
+                let transfer = self.remove(aparte, index);
+                transfer.terminate(aparte, Reason::Success);
+                aparte.log(format!(
+                    "{} received from {} in {}",
+                    transfer.name(),
+                    transfer.peer,
+                    transfer.path.as_deref().unwrap_or(Path::new("")).display()
+                ));
+            }
+            Err(err) => self.failed(aparte, index, &err),
+        }
+    }
+}
+
+impl ModTrait for FileTransferMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(transfer::new());
+        let mut disco = aparte.get_mod_mut::<mods::disco::DiscoMod>();
+        disco.add_feature(ns::JINGLE)?;
+        disco.add_feature(ns::JINGLE_FT)?;
+        disco.add_feature(ns::JINGLE_S5B)?;
+        disco.add_feature(ns::JINGLE_IBB)
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Iq(account, iq) => {
+                let (from, payload) = match (&iq.from, &iq.payload) {
+                    (Some(from), IqType::Set(payload)) if payload.is("jingle", ns::JINGLE) => {
+                        (from, payload)
+                    }
+                    _ => return,
+                };
+                let error = |condition, text: &str| {
+                    let error = StanzaError::new(ErrorType::Cancel, condition, "en", text);
+                    Iq::from_error(iq.id.clone(), error).with_to(from.clone())
+                };
+                let jingle = match Jingle::try_from(payload.clone()) {
+                    Ok(jingle) => jingle,
+                    Err(err) => {
+                        let answer = error(DefinedCondition::BadRequest, &err.to_string());
+                        return aparte.send(account, answer.into());
+                    }
+                };
+
+                if jingle.action == Action::SessionInitiate {
+                    aparte.send(
+                        account,
+                        Iq::empty_result(from.clone(), iq.id.clone()).into(),
+                    );
+                    return self.offered(aparte, account, from, jingle);
+                }
+                let index = match self.position(account, &jingle.sid.0) {
+                    Some(index) if &self.transfers[index].peer == from => index,
+                    _ => {
+                        let answer = error(DefinedCondition::ItemNotFound, "Unknown session");
+                        return aparte.send(account, answer.into());
+                    }
+                };
+                let answer = match self.handle_jingle(aparte, index, jingle) {
+                    Some(()) => Iq::empty_result(from.clone(), iq.id.clone()),
+                    None => error(
+                        DefinedCondition::FeatureNotImplemented,
+                        "Unsupported action",
+                    ),
+                };
+                aparte.send(account, answer.into());
+            }
+            Event::Socks5 {
+                account,
+                sid,
+                result,
+            } => self.handle_socks5(aparte, account, sid, result),
+            Event::Transferred {
+                account,
+                sid,
+                result,
+            } => self.handle_transferred(aparte, account, sid, result),
+            Event::Disconnected(account, _) => {
+                while let Some(index) = self
+                    .transfers
+                    .iter()
+                    .position(|transfer| &transfer.account == account)
+                {
+                    let transfer = self.remove(aparte, index);
+                    if transfer.accepted {
+                        aparte.log(format!(
+                            "Transfer of {} with {} interrupted",
+                            transfer.name(),
+                            transfer.peer
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for FileTransferMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0234: Jingle File Transfer")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+
+    #[test]
+    fn test_file_accepted_is_received_in_band() {
+        testing::run(async {
+            // Given
+            let directory =
+                std::env::temp_dir().join(format!("aparte-transfer-{}", Uuid::new_v4()));
+            let mut config = Config::default();
+            config.transfer.directory = Some(directory.to_string_lossy().to_string());
+            let mut harness = Harness::with_config(config);
+            harness.connect().await;
+            harness
+                .receive(
+                    "<iq xmlns='jabber:client' type='set' id='i1' from='juliet@example.org/balcony'
+                        to='{account}'>
+                        <jingle xmlns='urn:xmpp:jingle:1' action='session-initiate' sid='j1'
+                            initiator='juliet@example.org/balcony'>
+                            <content creator='initiator' name='file' senders='initiator'>
+                                <description xmlns='urn:xmpp:jingle:apps:file-transfer:5'>
+                                    <file><name>hello.txt</name><size>5</size></file>
+                                </description>
+                                <transport xmlns='urn:xmpp:jingle:transports:ibb:1'
+                                    block-size='4096' sid='s1'/>
+                            </content>
+                        </jingle>
+                    </iq>",
+                )
+                .await;
+            assert!(harness
+                .screen()
+                .contains("juliet@example.org/balcony offers hello.txt (5 B)"));
+
+            // When
+            harness.input("console", "/transfer accept").await;
+            for (id, payload) in &[
+                (
+                    "o1",
+                    "<open xmlns='http://jabber.org/protocol/ibb' block-size='4096' sid='s1'/>",
+                ),
+                (
+                    "d1",
+                    "<data xmlns='http://jabber.org/protocol/ibb' seq='0' sid='s1'>aGVsbG8=</data>",
+                ),
+                (
+                    "c1",
+                    "<close xmlns='http://jabber.org/protocol/ibb' sid='s1'/>",
+                ),
+            ] {
+                harness
+                    .receive(&format!(
+                        "<iq xmlns='jabber:client' type='set' id='{}'
+                            from='juliet@example.org/balcony' to='{{account}}'>{}</iq>",
+                        id, payload
+                    ))
+                    .await;
+            }
+
+            // Then
+            let path = directory.join("hello.txt");
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello");
+            let actions = harness
+                .take_sent("jingle", ns::JINGLE)
+                .iter()
+                .map(|iq| iq.get_child("jingle", ns::JINGLE).unwrap().clone())
+                .map(|jingle| Jingle::try_from(jingle).unwrap())
+                .map(|jingle| (jingle.action, jingle.reason.map(|reason| reason.reason)))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                actions,
+                vec![
+                    (Action::SessionAccept, None),
+                    (Action::SessionTerminate, Some(Reason::Success))
+                ]
+            );
+            assert!(harness
+                .aparte
+                .get_mod::<FileTransferMod>()
+                .transfers
+                .is_empty());
+            std::fs::remove_dir_all(directory).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_file_is_sent_in_band_when_no_proxy_can_be_used() {
+        testing::run(async {
+            // Given
+            let path = std::env::temp_dir().join(format!("aparte-transfer-{}.txt", Uuid::new_v4()));
+            std::fs::write(&path, "hello").unwrap();
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness.reply(
+                "query",
+                ns::DISCO_INFO,
+                "<iq xmlns='jabber:client' type='result' id='{id}' from='juliet@example.org/balcony'>
+                    <query xmlns='http://jabber.org/protocol/disco#info'>
+                        <identity category='client' type='pc'/>
+                        <feature var='http://jabber.org/protocol/disco#info'/>
+                        <feature var='urn:xmpp:jingle:1'/>
+                        <feature var='urn:xmpp:jingle:apps:file-transfer:5'/>
+                        <feature var='urn:xmpp:jingle:transports:s5b:1'/>
+                        <feature var='urn:xmpp:jingle:transports:ibb:1'/>
+                    </query>
+                </iq>",
+            );
+            harness
+                .receive("<presence xmlns='jabber:client' from='juliet@example.org/balcony' to='{account}'/>")
+                .await;
+            for name in &["open", "data", "close"] {
+                harness.reply(
+                    name,
+                    ns::IBB,
+                    "<iq xmlns='jabber:client' type='result' id='{id}'
+                        from='juliet@example.org/balcony' to='{account}'/>",
+                );
+            }
+            let send = format!("/transfer send {}", path.display());
+            harness.input("juliet@example.org", &send).await;
+            let initiate = harness.take_sent("jingle", ns::JINGLE).remove(0);
+            let jingle =
+                Jingle::try_from(initiate.get_child("jingle", ns::JINGLE).unwrap().clone())
+                    .unwrap();
+            let s5b = match &jingle.contents[0].transport {
+                Some(Transport::Socks5(transport)) => transport.sid.0.clone(),
+                other => panic!("Unexpected transport {:?}", other),
+            };
+            let jingle = |action: &str, payload: &str| {
+                format!(
+                    "<iq xmlns='jabber:client' type='set' id='{}' from='juliet@example.org/balcony'
+                        to='{{account}}'>
+                        <jingle xmlns='urn:xmpp:jingle:1' action='{}' sid='{}'>{}</jingle>
+                    </iq>",
+                    Uuid::new_v4(),
+                    action,
+                    jingle.sid.0,
+                    payload
+                )
+            };
+            let content = |transport: String| {
+                format!(
+                    "<content creator='initiator' name='file'>{}</content>",
+                    transport
+                )
+            };
+            harness
+                .receive(&format!(
+                    "<iq xmlns='jabber:client' type='result' id='{}'
+                        from='juliet@example.org/balcony' to='{{account}}'/>",
+                    initiate.attr("id").unwrap()
+                ))
+                .await;
+            let accept = content(format!(
+                "<transport xmlns='urn:xmpp:jingle:transports:s5b:1' sid='{}'/>",
+                s5b
+            ));
+            harness.receive(&jingle("session-accept", &accept)).await;
+
+            // When
+            let candidate_error = content(format!(
+                "<transport xmlns='urn:xmpp:jingle:transports:s5b:1' sid='{}'>
+                    <candidate-error/>
+                </transport>",
+                s5b
+            ));
+            harness
+                .receive(&jingle("transport-info", &candidate_error))
+                .await;
+            let replace = harness
+                .take_sent("jingle", ns::JINGLE)
+                .into_iter()
+                .map(|iq| Jingle::try_from(iq.get_child("jingle", ns::JINGLE).unwrap().clone()))
+                .map(Result::unwrap)
+                .find(|jingle| jingle.action == Action::TransportReplace)
+                .unwrap();
+            let ibb = match &replace.contents[0].transport {
+                Some(Transport::Ibb(transport)) => transport.sid.0.clone(),
+                other => panic!("Unexpected transport {:?}", other),
+            };
+            let transport_accept = content(format!(
+                "<transport xmlns='urn:xmpp:jingle:transports:ibb:1' block-size='4096' sid='{}'/>",
+                ibb
+            ));
+            harness
+                .receive(&jingle("transport-accept", &transport_accept))
+                .await;
+            harness
+                .receive(&jingle("session-terminate", "<reason><success/></reason>"))
+                .await;
+
+            // Then
+            assert!(harness
+                .screen()
+                .contains("juliet@example.org/balcony received"));
+            assert!(harness
+                .aparte
+                .get_mod::<FileTransferMod>()
+                .transfers
+                .is_empty());
+            std::fs::remove_file(path).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_file_is_sent_through_the_proxy_chosen_by_the_peer() {
+        testing::run(async {
+            // Given
+            let path = std::env::temp_dir().join(format!("aparte-transfer-{}.txt", Uuid::new_v4()));
+            std::fs::write(&path, "hello").unwrap();
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let proxy = tokio::task::spawn_local(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut greeting = [0u8; 3];
+                socket.read_exact(&mut greeting).await.unwrap();
+                socket.write_all(&[5, 0]).await.unwrap();
+                let mut request = [0u8; 5 + 40 + 2];
+                socket.read_exact(&mut request).await.unwrap();
+                let mut reply = vec![5, 0, 0, 3, 40];
+                reply.extend_from_slice(&request[5..]);
+                socket.write_all(&reply).await.unwrap();
+                let mut data = String::new();
+                socket.read_to_string(&mut data).await.unwrap();
+                data
+            });
+            let mut harness = Harness::new();
+            harness.connect().await;
+            let streamhost = bytestreams::StreamHost {
+                jid: Jid::from_str("proxy.example.org").unwrap(),
+                host: address.ip().to_string(),
+                port: address.port(),
+            };
+            let proxy_event = Event::Proxy(
+                harness.account.clone(),
+                Proxy {
+                    streamhost,
+                    address,
+                },
+            );
+            harness.aparte.schedule(proxy_event);
+            harness.reply(
+                "query",
+                ns::DISCO_INFO,
+                "<iq xmlns='jabber:client' type='result' id='{id}' from='juliet@example.org/balcony'>
+                    <query xmlns='http://jabber.org/protocol/disco#info'>
+                        <identity category='client' type='pc'/>
+                        <feature var='http://jabber.org/protocol/disco#info'/>
+                        <feature var='urn:xmpp:jingle:1'/>
+                        <feature var='urn:xmpp:jingle:apps:file-transfer:5'/>
+                        <feature var='urn:xmpp:jingle:transports:s5b:1'/>
+                    </query>
+                </iq>",
+            );
+            harness
+                .receive("<presence xmlns='jabber:client' from='juliet@example.org/balcony' to='{account}'/>")
+                .await;
+            harness.reply(
+                "query",
+                "http://jabber.org/protocol/bytestreams",
+                "<iq xmlns='jabber:client' type='result' id='{id}' from='proxy.example.org'
+                    to='{account}'/>",
+            );
+            let send = format!("/transfer send {}", path.display());
+            harness.input("juliet@example.org", &send).await;
+            let initiate = harness.take_sent("jingle", ns::JINGLE).remove(0);
+            let jingle =
+                Jingle::try_from(initiate.get_child("jingle", ns::JINGLE).unwrap().clone())
+                    .unwrap();
+            let (s5b, cid) = match &jingle.contents[0].transport {
+                Some(Transport::Socks5(S5bTransport {
+                    sid,
+                    payload: TransportPayload::Candidates(candidates),
+                    ..
+                })) => (sid.0.clone(), candidates[0].cid.0.clone()),
+                other => panic!("Unexpected transport {:?}", other),
+            };
+            let jingle = |action: &str, payload: &str| {
+                format!(
+                    "<iq xmlns='jabber:client' type='set' id='{}' from='juliet@example.org/balcony'
+                        to='{{account}}'>
+                        <jingle xmlns='urn:xmpp:jingle:1' action='{}' sid='{}'>
+                            <content creator='initiator' name='file'>
+                                <transport xmlns='urn:xmpp:jingle:transports:s5b:1' sid='{}'>{}</transport>
+                            </content>
+                        </jingle>
+                    </iq>",
+                    Uuid::new_v4(),
+                    action,
+                    jingle.sid.0,
+                    s5b,
+                    payload
+                )
+            };
+            harness
+                .receive(&format!(
+                    "<iq xmlns='jabber:client' type='result' id='{}'
+                        from='juliet@example.org/balcony' to='{{account}}'/>",
+                    initiate.attr("id").unwrap()
+                ))
+                .await;
+            harness.receive(&jingle("session-accept", "")).await;
+
+            // When
+            let candidate_used = format!("<candidate-used cid='{}'/>", cid);
+            harness
+                .receive(&jingle("transport-info", &candidate_used))
+                .await;
+            for _ in 0..500 {
+                harness.settle().await;
+                if proxy.is_finished() {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+
+            // Then
+            assert_eq!(proxy.await.unwrap(), "hello");
+            let payloads = harness
+                .take_sent("jingle", ns::JINGLE)
+                .into_iter()
+                .map(|iq| Jingle::try_from(iq.get_child("jingle", ns::JINGLE).unwrap().clone()))
+                .map(Result::unwrap)
+                .filter_map(
+                    |jingle| match jingle.contents.into_iter().next()?.transport? {
+                        Transport::Socks5(transport) => Some(transport.payload),
+                        _ => None,
+                    },
+                )
+                .collect::<Vec<_>>();
+            assert_eq!(
+                payloads,
+                vec![
+                    TransportPayload::CandidateError,
+                    TransportPayload::Activated(CandidateId(cid))
+                ]
+            );
+            std::fs::remove_file(path).unwrap();
+        });
+    }
+}
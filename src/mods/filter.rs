@@ -0,0 +1,440 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use regex::Regex;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use xmpp_parsers::BareJid;
+
+use crate::account::Account;
+use crate::color;
+use crate::command::{Command, CommandParser};
+use crate::config::FilterConfig;
+use crate::core::{Aparte, Event, ModTrait};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    Hide,
+    Highlight,
+    Notify,
+}
+
+impl FromStr for FilterAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hide" => Ok(FilterAction::Hide),
+            "highlight" => Ok(FilterAction::Highlight),
+            "notify" => Ok(FilterAction::Notify),
+            _ => Err(format!("Unknown filter action {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for FilterAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilterAction::Hide => write!(f, "hide"),
+            FilterAction::Highlight => write!(f, "highlight"),
+            FilterAction::Notify => write!(f, "notify"),
+        }
+    }
+}
+
+command_def!(filter,
+r#"/filter add|list|del"#,
+{
+    action: Command = {
+        children: {
+            "add": filter_add,
+            "list": filter_list,
+            "del": filter_del,
+        }
+    },
+});
+
+command_def!(filter_add,
+r#"/filter add <conversation> <regex> <action>
+
+    conversation  Jid of the contact or channel, * for all conversations
+    regex         Regular expression matched against incoming messages
+    action        hide, highlight or notify
+
+Description:
+    Add a filter on incoming messages and save it in the configuration file.
+    Hidden messages are not displayed, highlighted ones stand out in their
+    window and notify also copies them to the console.
+
+Examples:
+    /filter add bot@example.org "^\[build\]" hide
+    /filter add * "(?i)production incident" notify"#,
+{
+    conversation: String,
+    regex: String,
+    action: FilterAction = {
+        completion: (|_aparte, _command| {
+            ["hide", "highlight", "notify"].iter().map(|a| a.to_string()).collect()
+        })
+    }
+},
+|aparte, _command| {
+    let config = FilterConfig { conversation, regex, action };
+    let filter = Filter::new(config.clone())?;
+    let table = table(aparte);
+    let content = fs::read_to_string(&aparte.config_path)
+        .map_err(|err| format!("Cannot read configuration file: {}", err))?;
+    let content = save(&content, &table, &config)?;
+    fs::write(&aparte.config_path, content)
+        .map_err(|err| format!("Cannot write configuration file: {}", err))?;
+    aparte.log(format!("Filter added: {}", filter));
+    let mut filters = aparte.get_mod_mut::<FilterMod>();
+    filters.filters.push(filter);
+    Ok(())
+});
+
+command_def!(
+    filter_list,
+    r#"/filter list
+
+Description:
+    List filters on incoming messages."#,
+    {},
+    |aparte, _command| {
+        let list = {
+            let filters = aparte.get_mod::<FilterMod>();
+            filters
+                .filters
+                .iter()
+                .enumerate()
+                .map(|(i, filter)| format!("  {}: {}", i + 1, filter))
+                .collect::<Vec<String>>()
+        };
+        match list.is_empty() {
+            true => aparte.log("No filter".to_string()),
+            false => aparte.log(format!(
+                "Filters:\n{}\n{}",
+                list.join("\n"),
+                color::dimmed("remove with: /filter del <number>")
+            )),
+        }
+        Ok(())
+    }
+);
+
+command_def!(filter_del,
+r#"/filter del <number>
+
+    number        Number of the filter as shown by /filter list
+
+Description:
+    Remove a filter and save the configuration file."#,
+{
+    number: usize = {
+        range: (1..)
+    }
+},
+|aparte, _command| {
+    let count = {
+        let filters = aparte.get_mod::<FilterMod>();
+        filters.filters.len()
+    };
+    if number > count {
+        return Err(format!("No filter number {}", number));
+    }
+
+    let config = fs::read_to_string(&aparte.config_path)
+        .map_err(|err| format!("Cannot read configuration file: {}", err))?;
+    let config = remove(&config, &table(aparte), number - 1, count)?;
+    fs::write(&aparte.config_path, config)
+        .map_err(|err| format!("Cannot write configuration file: {}", err))?;
+
+    let filter = {
+        let mut filters = aparte.get_mod_mut::<FilterMod>();
+        filters.filters.remove(number - 1)
+    };
+    aparte.log(format!("Filter removed: {}", filter));
+    Ok(())
+});
+
+struct Filter {
+    config: FilterConfig,
+    regex: Regex,
+}
+
+impl Filter {
+    fn new(config: FilterConfig) -> Result<Self, String> {
+        if config.conversation != "*" {
+            BareJid::from_str(&config.conversation)
+                .map_err(|err| format!("Invalid conversation {}: {}", config.conversation, err))?;
+        }
+        let regex = Regex::new(&config.regex)
+            .map_err(|err| format!("Invalid regex {}: {}", config.regex, err))?;
+        Ok(Self { config, regex })
+    }
+
+    fn matches(&self, conversation: &BareJid, body: &str) -> bool {
+        (self.config.conversation == "*" || self.config.conversation == conversation.to_string())
+            && self.regex.is_match(body)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} messages matching {} in {}",
+            self.config.action, self.config.regex, self.config.conversation
+        )
+    }
+}
+
+/// Table holding the filters in use, the ones of the profile when it has its own
+fn table(aparte: &Aparte) -> String {
+    let name = match &aparte.config.profile {
+        Some(name) => name,
+        None => return "filters".to_string(),
+    };
+    match aparte.config.profiles.get(name) {
+        Some(profile) if profile.filters.is_some() => {
+            let bare = name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            match bare {
+                true => format!("profiles.{}.filters", name),
+                false => format!("profiles.{}.filters", toml::Value::String(name.clone())),
+            }
+        }
+        _ => "filters".to_string(),
+    }
+}
+
+/// Append a `[[<table>]]` filter to the configuration file content
+fn save(config: &str, table: &str, filter: &FilterConfig) -> Result<String, String> {
+    let quote = |value: &str| toml::Value::String(value.to_string()).to_string();
+    let mut result = config.to_string();
+    if !result.is_empty() && !result.ends_with('\n') {
+        result.push('\n');
+    }
+    result.push_str(&format!(
+        "\n[[{}]]\nconversation = {}\nregex = {}\naction = \"{}\"\n",
+        table,
+        quote(&filter.conversation),
+        quote(&filter.regex),
+        filter.action
+    ));
+    // An inline array can't be extended with tables
+    toml::from_str::<toml::Value>(&result).map_err(|_| {
+        format!(
+            "Filters are not all declared as [[{}]] tables, edit the configuration file",
+            table
+        )
+    })?;
+    Ok(result)
+}
+
+/// Remove a `[[<table>]]` filter from the configuration file content, keeping everything else
+/// untouched
+fn remove(config: &str, table: &str, index: usize, count: usize) -> Result<String, String> {
+    let header = format!("[[{}]]", table);
+    let is_filter = |line: &str| line.trim() == header;
+    if config.lines().filter(|line| is_filter(line)).count() != count {
+        return Err(format!(
+            "Filters are not all declared as {} tables, edit the configuration file",
+            header
+        ));
+    }
+
+    let mut result = String::new();
+    let mut filter = 0;
+    let mut removing = false;
+    for line in config.lines() {
+        if is_filter(line) {
+            removing = filter == index;
+            filter += 1;
+        } else if line.trim_start().starts_with('[') {
+            removing = false;
+        }
+
+        if !removing {
+            result.push_str(line);
+            result.push('\n');
+        }
+    }
+
+    Ok(result)
+}
+
+pub struct FilterMod {
+    filters: Vec<Filter>,
+}
+
+impl FilterMod {
+    pub fn new() -> Self {
+        Self {
+            filters: Vec::new(),
+        }
+    }
+
+    /// Action of the first filter matching an incoming message
+    pub fn action(&self, conversation: &BareJid, body: &str) -> Option<FilterAction> {
+        self.filters
+            .iter()
+            .find(|filter| filter.matches(conversation, body))
+            .map(|filter| filter.config.action)
+    }
+
+    fn load(&mut self, aparte: &mut Aparte) {
+        self.filters.clear();
+        for config in aparte.config.filters.clone() {
+            match Filter::new(config) {
+                Ok(filter) => self.filters.push(filter),
+                Err(err) => aparte.log(format!("Ignoring filter: {}", err)),
+            }
+        }
+    }
+}
+
+impl ModTrait for FilterMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(filter::new());
+        self.load(aparte);
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        if let Event::Profile(_) = event {
+            self.load(aparte);
+        }
+    }
+}
+
+impl fmt::Display for FilterMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Message filters")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(conversation: &str, regex: &str, action: FilterAction) -> Filter {
+        Filter::new(FilterConfig {
+            conversation: conversation.to_string(),
+            regex: regex.to_string(),
+            action,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_first_matching_filter_wins() {
+        // Given
+        let mut filters = FilterMod::new();
+        filters.filters.push(filter(
+            "bot@example.org",
+            "^\\[build\\]",
+            FilterAction::Hide,
+        ));
+        filters
+            .filters
+            .push(filter("*", "(?i)incident", FilterAction::Notify));
+        let bot = BareJid::from_str("bot@example.org").unwrap();
+        let friend = BareJid::from_str("friend@example.org").unwrap();
+
+        // Then
+        assert_eq!(
+            filters.action(&bot, "[build] passed"),
+            Some(FilterAction::Hide)
+        );
+        assert_eq!(filters.action(&friend, "[build] passed"), None);
+        assert_eq!(
+            filters.action(&friend, "Production INCIDENT"),
+            Some(FilterAction::Notify)
+        );
+    }
+
+    #[test]
+    fn test_remove_filter_from_config() {
+        // Given
+        let config = r#"# My accounts
+[accounts.example]
+jid = "me@example.org"
+
+[[filters]]
+conversation = "*"
+regex = "foo"
+action = "hide"
+
+[[filters]]
+conversation = "*"
+regex = "bar"
+action = "notify"
+"#;
+
+        // When
+        let result = remove(config, "filters", 0, 2).unwrap();
+
+        // Then
+        assert_eq!(
+            result,
+            r#"# My accounts
+[accounts.example]
+jid = "me@example.org"
+
+[[filters]]
+conversation = "*"
+regex = "bar"
+action = "notify"
+"#
+        );
+        assert!(remove(config, "filters", 0, 3).is_err());
+    }
+
+    #[test]
+    fn test_profile_filters_are_edited_in_the_profile() {
+        // Given
+        let config = r#"[accounts]
+
+[[filters]]
+conversation = "*"
+regex = "foo"
+action = "hide"
+
+[profiles.work]
+accounts = ["work"]
+
+[[profiles.work.filters]]
+conversation = "*"
+regex = "bar"
+action = "notify"
+"#;
+        let filter = FilterConfig {
+            conversation: "*".to_string(),
+            regex: "baz".to_string(),
+            action: FilterAction::Highlight,
+        };
+
+        // When
+        let added = save(config, "profiles.work.filters", &filter).unwrap();
+        let removed = remove(&added, "profiles.work.filters", 0, 2).unwrap();
+
+        // Then
+        let parsed: crate::config::Config = toml::from_str(&removed).unwrap();
+        assert_eq!(parsed.filters.len(), 1);
+        assert_eq!(parsed.filters[0].regex, "foo");
+        let work = parsed.profiles["work"].filters.as_ref().unwrap();
+        assert_eq!(work.len(), 1);
+        assert_eq!(work[0].regex, "baz");
+        assert!(save(
+            "[profiles.work]\nfilters = []\n",
+            "profiles.work.filters",
+            &filter
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,358 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! In-band bytestreams (XEP-0047), carrying the files sent to contacts through the XMPP servers
+//! when no SOCKS5 bytestream can be used, see `filetransfer`.
+//!
+//! Data are sent in iqs, each block waiting for the previous one to be acknowledged. Only the
+//! streams expected by a file transfer are accepted.
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use uuid::Uuid;
+use xmpp_parsers::ibb::{Close, Data, Open, Stanza, StreamId};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::stanza_error::{DefinedCondition, ErrorType, StanzaError};
+use xmpp_parsers::{ns, Element, Jid};
+
+use crate::account::Account;
+use crate::core::{Aparte, Event, IqResponse, ModTrait};
+use crate::mods;
+
+/// Size of the blocks sent, the one recommended by XEP-0047
+pub const BLOCK_SIZE: u16 = 4096;
+
+/// Stream being sent, block after block
+struct Outgoing {
+    to: Jid,
+    sid: String,
+    file: File,
+    block_size: u16,
+    seq: u16,
+    sent: u64,
+}
+
+/// Stream expected or being received
+struct Incoming {
+    from: Jid,
+    file: File,
+    open: bool,
+    seq: u16,
+    received: u64,
+}
+
+pub struct IbbMod {
+    /// Streams being sent, by account and sid
+    outgoing: HashSet<(Account, String)>,
+    /// Streams expected or being received, by account and sid
+    incoming: HashMap<(Account, String), Incoming>,
+}
+
+impl IbbMod {
+    pub fn new() -> Self {
+        Self {
+            outgoing: HashSet::new(),
+            incoming: HashMap::new(),
+        }
+    }
+
+    /// Accept the stream with the given sid from a peer, writing its data to a file
+    pub fn expect(&mut self, account: &Account, from: Jid, sid: String, file: File) {
+        let incoming = Incoming {
+            from,
+            file,
+            open: false,
+            seq: 0,
+            received: 0,
+        };
+        self.incoming.insert((account.clone(), sid), incoming);
+    }
+
+    /// Stop sending or receiving the stream with the given sid, without telling the peer
+    pub fn cancel(&mut self, account: &Account, sid: &str) {
+        let key = (account.clone(), sid.to_string());
+        self.outgoing.remove(&key);
+        self.incoming.remove(&key);
+    }
+
+    /// Send the next block of a stream once the previous one was acknowledged, or close it
+    fn sent(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        mut outgoing: Outgoing,
+        response: IqResponse,
+    ) {
+        let key = (account.clone(), outgoing.sid.clone());
+        if !self.outgoing.contains(&key) {
+            return;
+        }
+        if let Err(err) = response {
+            self.outgoing.remove(&key);
+            aparte.schedule(Event::Transferred {
+                account: account.clone(),
+                sid: outgoing.sid,
+                result: Err(err.to_string()),
+            });
+            return;
+        }
+
+        let mut block = vec![0u8; outgoing.block_size as usize];
+        let read = match outgoing.file.read(&mut block) {
+            Ok(read) => read,
+            Err(err) => {
+                self.outgoing.remove(&key);
+                let close = Close {
+                    sid: StreamId(outgoing.sid.clone()),
+                };
+                let id = Uuid::new_v4().to_hyphenated().to_string();
+                aparte.send(account, Iq::from_set(id, close).with_to(outgoing.to).into());
+                aparte.schedule(Event::Transferred {
+                    account: account.clone(),
+                    sid: outgoing.sid,
+                    result: Err(err.to_string()),
+                });
+                return;
+            }
+        };
+
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        if read == 0 {
+            let close = Close {
+                sid: StreamId(outgoing.sid.clone()),
+            };
+            let iq = Iq::from_set(id, close).with_to(outgoing.to.clone());
+            aparte.send_iq::<Self, _>(account, iq, move |ibb, aparte, account, response| {
+                let key = (account.clone(), outgoing.sid.clone());
+                if ibb.outgoing.remove(&key) {
+                    let sent = outgoing.sent;
+                    aparte.schedule(Event::Transferred {
+                        account: account.clone(),
+                        sid: outgoing.sid,
+                        result: response.map(|_| sent).map_err(|e| e.to_string()),
+                    });
+                }
+            });
+            return;
+        }
+
+        block.truncate(read);
+        let data = Data {
+            seq: outgoing.seq,
+            sid: StreamId(outgoing.sid.clone()),
+            data: block,
+        };
+        outgoing.seq = outgoing.seq.wrapping_add(1);
+        outgoing.sent += read as u64;
+        let iq = Iq::from_set(id, data).with_to(outgoing.to.clone());
+        aparte.send_iq::<Self, _>(account, iq, move |ibb, aparte, account, response| {
+            ibb.sent(aparte, account, outgoing, response)
+        });
+    }
+
+    /// Answer a request of a peer about a stream, None if it isn't about in-band bytestreams
+    fn handle_request(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        from: &Jid,
+        payload: &Element,
+    ) -> Option<Result<(), StanzaError>> {
+        if !payload.has_ns(ns::IBB) {
+            return None;
+        }
+        let sid = payload.attr("sid")?.to_string();
+        let key = (account.clone(), sid.clone());
+        let incoming = match self.incoming.get_mut(&key) {
+            Some(incoming) if &incoming.from == from => incoming,
+            _ => {
+                return Some(Err(error(
+                    DefinedCondition::ItemNotFound,
+                    format!("Unknown stream {}", sid),
+                )))
+            }
+        };
+
+        if payload.is("open", ns::IBB) {
+            let open = Open::try_from(payload.clone()).ok()?;
+            // Blocks in messages would go unacknowledged, lost blocks going unnoticed
+            if open.stanza != Stanza::Iq {
+                return Some(Err(error(
+                    DefinedCondition::NotAcceptable,
+                    String::from("Only iq stanzas are accepted"),
+                )));
+            }
+            incoming.open = true;
+            Some(Ok(()))
+        } else if payload.is("data", ns::IBB) {
+            let data = Data::try_from(payload.clone()).ok()?;
+            if !incoming.open || data.seq != incoming.seq {
+                self.incoming.remove(&key);
+                aparte.schedule(Event::Transferred {
+                    account: account.clone(),
+                    sid,
+                    result: Err(String::from("Data missing from the stream")),
+                });
+                return Some(Err(error(
+                    DefinedCondition::UnexpectedRequest,
+                    format!("Unexpected block {}", data.seq),
+                )));
+            }
+            if let Err(err) = incoming.file.write_all(&data.data) {
+                self.incoming.remove(&key);
+                aparte.schedule(Event::Transferred {
+                    account: account.clone(),
+                    sid,
+                    result: Err(err.to_string()),
+                });
+                return Some(Err(error(
+                    DefinedCondition::ResourceConstraint,
+                    String::from("Cannot write the data received"),
+                )));
+            }
+            incoming.seq = incoming.seq.wrapping_add(1);
+            incoming.received += data.data.len() as u64;
+            Some(Ok(()))
+        } else if payload.is("close", ns::IBB) {
+            let incoming = self.incoming.remove(&key)?;
+            aparte.schedule(Event::Transferred {
+                account: account.clone(),
+                sid,
+                result: Ok(incoming.received),
+            });
+            Some(Ok(()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Send a file through the stream with the given sid, Event::Transferred telling once closed
+pub fn send(
+    aparte: &mut Aparte,
+    account: &Account,
+    to: Jid,
+    sid: String,
+    path: &Path,
+    block_size: u16,
+) -> Result<(), String> {
+    let file = File::open(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let open = Open {
+        block_size,
+        sid: StreamId(sid.clone()),
+        stanza: Stanza::Iq,
+    };
+    let iq = Iq::from_set(Uuid::new_v4().to_hyphenated().to_string(), open).with_to(to.clone());
+    {
+        let mut ibb = aparte.get_mod_mut::<IbbMod>();
+        ibb.outgoing.insert((account.clone(), sid.clone()));
+    }
+
+    let outgoing = Outgoing {
+        to,
+        sid,
+        file,
+        block_size,
+        seq: 0,
+        sent: 0,
+    };
+    aparte.send_iq::<IbbMod, _>(account, iq, move |ibb, aparte, account, response| {
+        ibb.sent(aparte, account, outgoing, response)
+    });
+    Ok(())
+}
+
+fn error(condition: DefinedCondition, text: String) -> StanzaError {
+    StanzaError::new(ErrorType::Cancel, condition, "en", text)
+}
+
+impl ModTrait for IbbMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        let mut disco = aparte.get_mod_mut::<mods::disco::DiscoMod>();
+        disco.add_feature(ns::IBB)
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Iq(account, iq) => {
+                let (from, payload) = match (&iq.from, &iq.payload) {
+                    (Some(from), IqType::Set(payload)) => (from, payload),
+                    _ => return,
+                };
+                let answer = match self.handle_request(aparte, account, from, payload) {
+                    Some(Ok(())) => Iq::empty_result(from.clone(), iq.id.clone()),
+                    Some(Err(err)) => Iq::from_error(iq.id.clone(), err).with_to(from.clone()),
+                    None => return,
+                };
+                aparte.send(account, answer.into());
+            }
+            Event::Disconnected(account, _) => {
+                self.outgoing.retain(|(other, _)| other != account);
+                self.incoming.retain(|(other, _), _| other != account);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for IbbMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0047: In-Band Bytestreams")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_blocks_out_of_sequence_end_the_stream() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            let path = std::env::temp_dir().join(format!("aparte-ibb-{}", Uuid::new_v4()));
+            let file = File::create(&path).unwrap();
+            let juliet = Jid::from_str("juliet@example.org/balcony").unwrap();
+            {
+                let mut ibb = harness.aparte.get_mod_mut::<IbbMod>();
+                ibb.expect(&harness.account, juliet, String::from("s1"), file);
+            }
+            harness
+                .receive(
+                    "<iq xmlns='jabber:client' type='set' id='o1' from='juliet@example.org/balcony'
+                        to='{account}'>
+                        <open xmlns='http://jabber.org/protocol/ibb' block-size='4096' sid='s1'/>
+                    </iq>",
+                )
+                .await;
+
+            // When
+            harness
+                .receive(
+                    "<iq xmlns='jabber:client' type='set' id='d1' from='juliet@example.org/balcony'
+                        to='{account}'>
+                        <data xmlns='http://jabber.org/protocol/ibb' seq='1' sid='s1'>aGVsbG8=</data>
+                    </iq>",
+                )
+                .await;
+
+            // Then
+            let answers = harness.take_sent("iq", ns::DEFAULT_NS);
+            let answer = |id| {
+                let answer = answers.iter().find(|iq| iq.attr("id") == Some(id)).unwrap();
+                answer.attr("type")
+            };
+            assert_eq!(answer("o1"), Some("result"));
+            assert_eq!(answer("d1"), Some("error"));
+            let ibb = harness.aparte.get_mod::<IbbMod>();
+            assert!(ibb.incoming.is_empty());
+            std::fs::remove_file(path).unwrap();
+        });
+    }
+}
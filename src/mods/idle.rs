@@ -0,0 +1,168 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Publish since when the user is idle in presence (XEP-0319), when configured to.
+//!
+//! Typing in Aparté is the only interaction known of. Once idle, the last available presence of
+//! each account is sent again with the time of the last key typed, and sent without it on the
+//! next key.
+use chrono::{DateTime as ChronoDateTime, Duration as ChronoDuration, Utc};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use xmpp_parsers::date::DateTime;
+use xmpp_parsers::idle::Idle;
+use xmpp_parsers::{ns, Element};
+
+use crate::account::Account;
+use crate::core::{Aparte, Event, ModTrait};
+
+/// Interval between two checks of the last interaction
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Presences sent to every contact and not only to a given entity
+fn is_broadcast_presence(stanza: &Element) -> bool {
+    stanza.is("presence", ns::DEFAULT_NS)
+        && stanza.attr("type").is_none()
+        && stanza.attr("to").is_none()
+}
+
+pub struct IdleMod {
+    /// Last key typed
+    last_interaction: ChronoDateTime<Utc>,
+    /// Presences currently tell since when the user is idle
+    idle: bool,
+    /// A check of the last interaction is pending
+    checking: bool,
+    /// Last broadcast available presence of each account, without idle time
+    presences: HashMap<Account, Element>,
+}
+
+impl IdleMod {
+    pub fn new() -> Self {
+        Self {
+            last_interaction: Utc::now(),
+            idle: false,
+            checking: false,
+            presences: HashMap::new(),
+        }
+    }
+
+    /// Keep track of an element written on the stream of an account, adding the idle time to
+    /// available presences while idle
+    pub fn sent(&mut self, account: &Account, stanza: &mut Element) {
+        if !stanza.is("presence", ns::DEFAULT_NS)
+            || stanza.attr("type").is_some()
+            || stanza.has_child("idle", ns::IDLE)
+        {
+            return;
+        }
+        if is_broadcast_presence(stanza) {
+            self.presences.insert(account.clone(), stanza.clone());
+        }
+        if self.idle {
+            let idle = Idle {
+                since: DateTime(self.last_interaction.into()),
+            };
+            stanza.append_child(idle.into());
+        }
+    }
+
+    fn check(&mut self, aparte: &mut Aparte) {
+        if self.checking || !aparte.config.idle.publish {
+            return;
+        }
+        self.checking = true;
+        aparte.spawn(async {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            Event::IdleCheck
+        });
+    }
+
+    /// Send the last presences again, with or without idle time depending on the new state
+    fn set_idle(&mut self, aparte: &mut Aparte, idle: bool) {
+        self.idle = idle;
+        for (account, presence) in self.presences.iter() {
+            aparte.send(account, presence.clone());
+        }
+    }
+}
+
+impl ModTrait for IdleMod {
+    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Connected(_, _) => self.check(aparte),
+            Event::Disconnected(account, _) => {
+                self.presences.remove(account);
+            }
+            Event::IdleCheck => {
+                self.checking = false;
+                let after = ChronoDuration::seconds(aparte.config.idle.after as i64);
+                if aparte.config.idle.publish
+                    && !self.idle
+                    && Utc::now() - self.last_interaction >= after
+                {
+                    self.set_idle(aparte, true);
+                }
+                self.check(aparte);
+            }
+            Event::Key(_) => {
+                self.last_interaction = Utc::now();
+                if self.idle {
+                    self.set_idle(aparte, false);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for IdleMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0319: Last User Interaction in Presence")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, IdleConfig};
+    use crate::testing::{self, Harness};
+    use termion::event::Key;
+
+    #[test]
+    fn test_idle_time_is_published_until_next_key() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::with_config(Config {
+                idle: IdleConfig {
+                    publish: true,
+                    after: 0,
+                },
+                ..Config::default()
+            });
+            harness.connect().await;
+            let online = harness.take_sent("presence", ns::DEFAULT_NS);
+
+            // When
+            harness.aparte.schedule(Event::IdleCheck);
+            harness.settle().await;
+            let idle = harness.take_sent("presence", ns::DEFAULT_NS);
+            harness.aparte.schedule(Event::Key(Key::Char('a')));
+            harness.settle().await;
+            let back = harness.take_sent("presence", ns::DEFAULT_NS);
+
+            // Then
+            assert_eq!(online.len(), 1);
+            assert!(!online[0].has_child("idle", ns::IDLE));
+            assert_eq!(idle.len(), 1);
+            assert!(idle[0].has_child("idle", ns::IDLE));
+            assert_eq!(back.len(), 1);
+            assert!(!back[0].has_child("idle", ns::IDLE));
+        });
+    }
+}
@@ -0,0 +1,400 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Channel invitations, sent directly to contacts (XEP-0249) and received either directly or
+//! through the channel (XEP-0045 mediated invitations).
+//!
+//! Invitations received are asked in the console and only joined once accepted.
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::delay::Delay;
+use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType as XmppParsersMessageType};
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::color;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::mods;
+use crate::mods::room::current_channel;
+
+const CONFERENCE: &str = "jabber:x:conference";
+
+command_def!(invite,
+r#"/invite <jid> [<reason>]
+
+    jid           Address of the contact to invite
+    reason        Optional reason shown to the contact
+
+Description:
+    Invite a contact to join the current channel (XEP-0249).
+
+Examples:
+    /invite juliet@example.org
+    /invite juliet@example.org "Come to the ball""#,
+{
+    jid: BareJid,
+    reason: Option<String>
+},
+|aparte, _command| {
+    let channel = current_channel(aparte, &_command.context)?;
+    let mut x = Element::builder("x", CONFERENCE)
+        .attr("jid", channel.jid.to_string())
+        .build();
+    if let Some(reason) = reason {
+        x.set_attr("reason", reason);
+    }
+    let mut message = XmppParsersMessage::new(Some(Jid::Bare(jid.clone())));
+    message.id = Some(Uuid::new_v4().to_hyphenated().to_string());
+    message.payloads.push(x);
+    aparte.send(&channel.account, message.into());
+    aparte.log(format!("{} invited to {}", jid, channel.jid));
+    Ok(())
+});
+
+command_def!(invitations,
+r#"/invitations accept|decline"#,
+{
+    action: Command = {
+        children: {
+            "accept": invitations_accept,
+            "decline": invitations_decline,
+        }
+    },
+});
+
+command_def!(invitations_accept,
+r#"/invitations accept [<channel>]
+
+    channel       Channel to join, the last one invited to if omitted
+
+Description:
+    Accept an invitation and join its channel.
+
+Examples:
+    /invitations accept
+    /invitations accept room@conference.example.org"#,
+{
+    channel: Option<BareJid> = {
+        completion: (|aparte, _command| {
+            let invite = aparte.get_mod::<InviteMod>();
+            invite.pending.iter().map(|invitation| invitation.room.to_string()).collect()
+        })
+    }
+},
+|aparte, _command| {
+    let invitation = {
+        let mut invite = aparte.get_mod_mut::<InviteMod>();
+        invite.take(&channel)?
+    };
+    aparte.schedule(Event::Join {
+        account: invitation.account,
+        channel: Jid::Bare(invitation.room),
+        user_request: true,
+    });
+    Ok(())
+});
+
+command_def!(invitations_decline,
+r#"/invitations decline [<channel>] [<reason>]
+
+    channel       Channel not to join, the last one invited to if omitted
+    reason        Optional reason given to the inviter, when the invitation
+                  came through the channel
+
+Description:
+    Decline an invitation. Invitations sent through the channel are declined
+    to the inviter, direct ones are forgotten.
+
+Examples:
+    /invitations decline
+    /invitations decline room@conference.example.org "Not tonight""#,
+{
+    channel: Option<BareJid> = {
+        completion: (|aparte, _command| {
+            let invite = aparte.get_mod::<InviteMod>();
+            invite.pending.iter().map(|invitation| invitation.room.to_string()).collect()
+        })
+    },
+    reason: Option<String>
+},
+|aparte, _command| {
+    let invitation = {
+        let mut invite = aparte.get_mod_mut::<InviteMod>();
+        invite.take(&channel)?
+    };
+    if invitation.mediated {
+        let mut decline = Element::builder("decline", ns::MUC_USER)
+            .attr("to", invitation.from.to_string())
+            .build();
+        if let Some(reason) = reason {
+            decline.append_child(Element::builder("reason", ns::MUC_USER).append(reason).build());
+        }
+        let mut message = XmppParsersMessage::new(Some(Jid::Bare(invitation.room.clone())));
+        message.id = Some(Uuid::new_v4().to_hyphenated().to_string());
+        message
+            .payloads
+            .push(Element::builder("x", ns::MUC_USER).append(decline).build());
+        aparte.send(&invitation.account, message.into());
+    }
+    aparte.log(format!("Invitation to {} declined", invitation.room));
+    Ok(())
+});
+
+/// Invitation waiting to be accepted or declined
+#[derive(Debug, Clone)]
+struct Invitation {
+    account: Account,
+    room: BareJid,
+    /// Who invites, not the channel for mediated invitations
+    from: Jid,
+    reason: Option<String>,
+    /// Sent through the channel, which forwards declines to the inviter
+    mediated: bool,
+}
+
+impl Invitation {
+    /// Direct or mediated invitation carried by a message, if any
+    fn from_message(account: &Account, message: &XmppParsersMessage) -> Option<Self> {
+        if message.type_ == XmppParsersMessageType::Groupchat {
+            return None;
+        }
+        let from = message.from.clone()?;
+        message.payloads.iter().find_map(|payload| {
+            if payload.is("x", CONFERENCE) {
+                Some(Self {
+                    account: account.clone(),
+                    room: BareJid::from_str(payload.attr("jid")?).ok()?,
+                    from: from.clone(),
+                    reason: payload.attr("reason").map(String::from),
+                    mediated: false,
+                })
+            } else if payload.is("x", ns::MUC_USER) {
+                let invite = payload.get_child("invite", ns::MUC_USER)?;
+                Some(Self {
+                    account: account.clone(),
+                    room: BareJid::from(from.clone()),
+                    from: Jid::from_str(invite.attr("from")?).ok()?,
+                    reason: invite
+                        .get_child("reason", ns::MUC_USER)
+                        .map(|reason| reason.text()),
+                    mediated: true,
+                })
+            } else {
+                None
+            }
+        })
+    }
+}
+
+pub struct InviteMod {
+    /// Invitations not answered yet, last received last
+    pending: Vec<Invitation>,
+}
+
+impl InviteMod {
+    pub fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+        }
+    }
+
+    /// Remove the invitation to a channel, the last one received by default
+    fn take(&mut self, room: &Option<BareJid>) -> Result<Invitation, String> {
+        let index = match room {
+            Some(room) => self
+                .pending
+                .iter()
+                .rposition(|invitation| &invitation.room == room)
+                .ok_or(format!("No invitation to {}", room))?,
+            None => self
+                .pending
+                .len()
+                .checked_sub(1)
+                .ok_or("No pending invitation".to_string())?,
+        };
+        Ok(self.pending.remove(index))
+    }
+}
+
+impl ModTrait for InviteMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(invite::new());
+        aparte.add_command(invitations::new());
+        let mut disco = aparte.get_mod_mut::<mods::disco::DiscoMod>();
+        disco.add_feature(CONFERENCE)
+    }
+
+    fn can_handle_xmpp_message(
+        &mut self,
+        _aparte: &mut Aparte,
+        account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+    ) -> f64 {
+        match Invitation::from_message(account, message) {
+            Some(_) => 1f64,
+            None => 0f64,
+        }
+    }
+
+    fn handle_xmpp_message(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+    ) {
+        let invitation = match Invitation::from_message(account, message) {
+            Some(invitation) => invitation,
+            None => return,
+        };
+
+        let mut prompt = format!(
+            "{} invites you to join {}",
+            invitation.from, invitation.room
+        );
+        if let Some(reason) = &invitation.reason {
+            prompt.push_str(&format!(": {}", reason));
+        }
+        aparte.log(format!(
+            "{}\nAccept to join? {}",
+            prompt,
+            color::dimmed(&format!("/invitations accept|decline {}", invitation.room))
+        ));
+
+        // A new invitation to the same channel replaces the previous one
+        self.pending.retain(|pending| {
+            pending.account != invitation.account || pending.room != invitation.room
+        });
+        self.pending.push(invitation);
+    }
+
+    fn on_event(&mut self, _aparte: &mut Aparte, _event: &Event) {}
+}
+
+impl fmt::Display for InviteMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0249: Direct MUC Invitations")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+
+    #[test]
+    fn test_direct_invitation_is_joined_once_accepted() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness.take_sent("presence", ns::DEFAULT_NS);
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' from='juliet@example.org/balcony' to='{account}'>
+                        <x xmlns='jabber:x:conference' jid='ball@conference.example.org'
+                            reason='Come to the ball'/>
+                    </message>",
+                )
+                .await;
+            assert!(harness.take_sent("presence", ns::DEFAULT_NS).is_empty());
+
+            // When
+            harness.input("console", "/invitations accept").await;
+
+            // Then
+            assert!(harness
+                .screen()
+                .contains("juliet@example.org/balcony invites you to join"));
+            let presences = harness.take_sent("presence", ns::DEFAULT_NS);
+            assert_eq!(presences.len(), 1);
+            assert!(presences[0]
+                .attr("to")
+                .unwrap()
+                .starts_with("ball@conference.example.org/"));
+            assert!(harness.aparte.get_mod::<InviteMod>().pending.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_mediated_invitation_is_declined_to_the_inviter() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness.take_sent("presence", ns::DEFAULT_NS);
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' from='ball@conference.example.org' to='{account}'>
+                        <x xmlns='http://jabber.org/protocol/muc#user'>
+                            <invite from='juliet@example.org/balcony'>
+                                <reason>Come to the ball</reason>
+                            </invite>
+                        </x>
+                    </message>",
+                )
+                .await;
+
+            // When
+            harness
+                .input(
+                    "console",
+                    "/invitations decline ball@conference.example.org \"Not tonight\"",
+                )
+                .await;
+
+            // Then
+            let declines = harness.take_sent("x", ns::MUC_USER);
+            assert_eq!(declines.len(), 1);
+            assert_eq!(declines[0].attr("to"), Some("ball@conference.example.org"));
+            let decline = declines[0]
+                .get_child("x", ns::MUC_USER)
+                .and_then(|x| x.get_child("decline", ns::MUC_USER))
+                .unwrap();
+            assert_eq!(decline.attr("to"), Some("juliet@example.org/balcony"));
+            assert_eq!(
+                decline.get_child("reason", ns::MUC_USER).unwrap().text(),
+                "Not tonight"
+            );
+            assert!(harness.take_sent("presence", ns::DEFAULT_NS).is_empty());
+        });
+    }
+
+    #[test]
+    fn test_invite_from_channel_window() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness
+                .input("console", "/join ball@conference.example.org")
+                .await;
+            harness
+                .receive(
+                    "<presence xmlns='jabber:client' from='ball@conference.example.org/romeo' to='{account}'>
+                        <x xmlns='http://jabber.org/protocol/muc#user'>
+                            <item affiliation='member' role='participant'/>
+                            <status code='110'/>
+                        </x>
+                    </presence>",
+                )
+                .await;
+
+            // When
+            harness
+                .input("ball@conference.example.org", "/invite juliet@example.org")
+                .await;
+
+            // Then
+            let invitations = harness.take_sent("x", CONFERENCE);
+            assert_eq!(invitations.len(), 1);
+            assert_eq!(invitations[0].attr("to"), Some("juliet@example.org"));
+            let x = invitations[0].get_child("x", CONFERENCE).unwrap();
+            assert_eq!(x.attr("jid"), Some("ball@conference.example.org"));
+            assert_eq!(x.attr("reason"), None);
+        });
+    }
+}
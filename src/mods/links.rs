@@ -0,0 +1,248 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Links received in some channels handed to a command, for channels used as link feeds.
+//!
+//! The command is typically a script saving the link to a read-it-later service or a bookmark
+//! manager. Links archived are remembered in the data directory so that the history of a channel
+//! joined again doesn't archive them twice, failed ones being tried again when seen again.
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use tokio::process::Command as ProcessCommand;
+use xmpp_parsers::BareJid;
+
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::{Direction, Message, XmppMessageType};
+use crate::mods::preview;
+
+/// Run the archive command for a link
+async fn archive_link(command: String, url: String) -> Result<(), String> {
+    let output = ProcessCommand::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", command))
+        .arg("sh")
+        .arg(&url)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::piped())
+        .output()
+        .await
+        .map_err(|err| format!("Cannot run `{}`: {}", command, err))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(match stderr.lines().next() {
+            Some(line) => format!("`{}` failed with {}: {}", command, output.status, line),
+            None => format!("`{}` failed with {}", command, output.status),
+        });
+    }
+    Ok(())
+}
+
+pub struct LinkArchiveMod {
+    /// Links archived or being archived
+    links: HashSet<String>,
+    /// Links archived so far, one per line
+    path: Option<PathBuf>,
+}
+
+impl LinkArchiveMod {
+    pub fn new() -> Self {
+        Self {
+            links: HashSet::new(),
+            path: dirs::data_dir().map(|dir| dir.join("aparte").join("archived_links")),
+        }
+    }
+
+    fn load(&mut self) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let links = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        self.links = links.lines().map(str::to_string).collect();
+        Ok(())
+    }
+
+    /// Remember a link archived
+    fn save(&self, url: &str) {
+        if let Some(path) = &self.path {
+            let result = fs::create_dir_all(path.parent().unwrap()).and_then(|_| {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", url)
+            });
+            if let Err(err) = result {
+                warn!("Cannot save archived link {}: {}", url, err);
+            }
+        }
+    }
+
+    /// Links not archived yet of a message received in one of the configured channels
+    fn links_to_archive(&mut self, rooms: &[String], message: &Message) -> Vec<String> {
+        let message = match message {
+            Message::Xmpp(message)
+                if message.direction == Direction::Incoming
+                    && message.type_ == XmppMessageType::Channel =>
+            {
+                message
+            }
+            _ => return Vec::new(),
+        };
+        let archived = rooms
+            .iter()
+            .filter_map(|room| BareJid::from_str(room).ok())
+            .any(|room| &room == message.conversation());
+        if !archived {
+            return Vec::new();
+        }
+        let mut links: Vec<String> = preview::links(message.get_last_body())
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        links.extend(message.oob.as_ref().map(|oob| oob.url.clone()));
+        links
+            .into_iter()
+            .filter(|link| self.links.insert(link.clone()))
+            .collect()
+    }
+}
+
+impl ModTrait for LinkArchiveMod {
+    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+        if let Err(err) = self.load() {
+            warn!("Cannot load archived links: {}", err);
+        }
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Message(Some(_), message) => {
+                let config = aparte.config.links.clone();
+                let command = match config.command {
+                    Some(command) => command,
+                    None => return,
+                };
+                for url in self.links_to_archive(&config.rooms, message) {
+                    let command = command.clone();
+                    aparte.spawn(async move {
+                        let result = archive_link(command, url.clone()).await;
+                        Event::LinkArchived { url, result }
+                    });
+                }
+            }
+            Event::LinkArchived { url, result } => match result {
+                Ok(()) => {
+                    self.save(url);
+                    aparte.log(format!("Link archived: {}", url));
+                }
+                Err(err) => {
+                    self.links.remove(url);
+                    aparte.log(format!("Cannot archive link {}: {}", url, err));
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for LinkArchiveMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Link archiving")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, LinksConfig};
+    use crate::testing::{self, Harness};
+
+    #[test]
+    fn test_only_links_of_configured_rooms_are_archived() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::with_config(Config {
+                links: LinksConfig {
+                    command: Some(String::from("true")),
+                    rooms: vec![String::from("links@conference.example.org")],
+                },
+                ..Config::default()
+            });
+            harness.connect().await;
+            for room in [
+                "links@conference.example.org",
+                "chat@conference.example.org",
+            ]
+            .iter()
+            {
+                harness.input("console", &format!("/join {}", room)).await;
+                harness
+                    .receive(&format!(
+                        "<presence xmlns='jabber:client' from='{}/romeo' to='{{account}}'>
+                            <x xmlns='http://jabber.org/protocol/muc#user'>
+                                <item affiliation='member' role='participant'/>
+                                <status code='110'/>
+                            </x>
+                        </presence>",
+                        room
+                    ))
+                    .await;
+            }
+
+            // When
+            for (room, page) in [
+                ("links@conference.example.org", "queen-mab"),
+                ("chat@conference.example.org", "swords"),
+            ]
+            .iter()
+            {
+                harness
+                    .receive(&format!(
+                        "<message xmlns='jabber:client' type='groupchat' id='{}'
+                            from='{}/mercutio' to='{{account}}'>
+                            <body>Read https://example.org/{}</body>
+                        </message>",
+                        page, room, page
+                    ))
+                    .await;
+            }
+
+            // Then
+            let archive = harness.aparte.get_mod::<LinkArchiveMod>();
+            assert!(archive.links.contains("https://example.org/queen-mab"));
+            assert!(!archive.links.contains("https://example.org/swords"));
+        });
+    }
+
+    #[test]
+    fn test_failed_links_are_reported_and_tried_again() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            let url = String::from("https://example.org/queen-mab");
+            {
+                let mut archive = harness.aparte.get_mod_mut::<LinkArchiveMod>();
+                archive.links.insert(url.clone());
+            }
+
+            // When
+            harness.aparte.schedule(Event::LinkArchived {
+                url: url.clone(),
+                result: Err(String::from("`wallabag` failed with exit status: 1")),
+            });
+            harness.settle().await;
+
+            // Then
+            assert!(harness
+                .screen()
+                .contains("Cannot archive link https://example.org/queen-mab"));
+            let archive = harness.aparte.get_mod::<LinkArchiveMod>();
+            assert!(!archive.links.contains(&url));
+        });
+    }
+}
@@ -1,10 +1,18 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
-use chrono::{DateTime, FixedOffset};
+//! Message archive (XEP-0313) of conversations, fetched when they are opened, when scrolling up
+//! past their first message, around the dates given to /goto and to catch up once connected.
+//!
+//! Pages stopping short of the messages already known leave a gap in the history, shown by a
+//! placeholder in the conversation. Selecting it and pressing Enter fetches the page preceding it.
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveTime, TimeZone};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
 use uuid::Uuid;
 use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
 use xmpp_parsers::delay::Delay;
@@ -13,32 +21,237 @@ use xmpp_parsers::mam;
 use xmpp_parsers::message::Message as XmppParsersMessage;
 use xmpp_parsers::ns;
 use xmpp_parsers::rsm::SetQuery;
-use xmpp_parsers::{BareJid, Jid};
+use xmpp_parsers::stanza_error::DefinedCondition;
+use xmpp_parsers::stanza_id::StanzaId;
+use xmpp_parsers::{BareJid, Element, Jid};
 
 use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::conversation::Conversation;
 use crate::core::{Aparte, Event, ModTrait};
+use crate::message::{LogMessage, Message};
+use crate::mods;
+
+/// Prefix of the ids of gap placeholders
+const GAP: &str = "mam-gap-";
+
+command_def!(archive,
+r#"/archive prefs"#,
+{
+    action: Command = {
+        children: {
+            "prefs": archive_prefs,
+        }
+    },
+});
+
+command_def!(archive_prefs,
+r#"/archive prefs [always|never|roster]
+
+    always        Archive every conversation
+    never         Don't archive conversations
+    roster        Only archive conversations with roster contacts
+
+Description:
+    Show or change which conversations the server stores in the archive of
+    the current account (XEP-0441). Per contact exceptions are kept.
+
+Examples:
+    /archive prefs
+    /archive prefs roster"#,
+{
+    default: Option<String> = {
+        completion: (|_aparte, _command| {
+            ["always", "never", "roster"].iter().map(|d| d.to_string()).collect()
+        })
+    }
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let change = match default.as_deref() {
+        Some("always") => Some(mam::DefaultPrefs::Always),
+        Some("never") => Some(mam::DefaultPrefs::Never),
+        Some("roster") => Some(mam::DefaultPrefs::Roster),
+        Some(default) => return Err(format!("Unknown archiving preference {}", default)),
+        None => None,
+    };
+
+    let id = Uuid::new_v4().to_hyphenated().to_string();
+    // Preferences are replaced as a whole, get them first to keep exceptions
+    let iq = Iq::from_get(id.clone(), mam::Prefs {
+        default_: mam::DefaultPrefs::Always,
+        always: vec![],
+        never: vec![],
+    });
+    {
+        let mut mam = aparte.get_mod_mut::<MamMod>();
+        mam.prefs.insert(id, change);
+    }
+    aparte.send(&account, iq.into());
+    Ok(())
+});
+
+command_def!(goto,
+r#"/goto <date>
+
+    date          Day as YYYY-MM-DD, optionally followed by a time as HH:MM,
+                  a time of today, today or yesterday
+
+Description:
+    Load the messages exchanged around a date in the current conversation,
+    from the archive and from the messages already known, and scroll to the
+    first one sent at or after it.
+
+Examples:
+    /goto 2021-03-14
+    /goto "2021-03-14 18:30"
+    /goto yesterday"#,
+{
+    date: String
+},
+|aparte, _command| {
+    let account = _command.account.clone().ok_or("No connection found".to_string())?;
+    let conversation = BareJid::from_str(&_command.context)
+        .map_err(|_| "/goto only works in a conversation window".to_string())?;
+    let date = parse_date(&date, Local::now())?;
+    aparte.schedule(Event::GoTo { account, conversation, date });
+    Ok(())
+});
+
+command_def!(history,
+r#"/history [<count>]
+
+    count         Number of messages to fetch, a page by default
+
+Description:
+    Fetch the messages of the current conversation preceding the oldest one
+    shown from the archive (XEP-0313). Scrolling up with PageUp past the
+    first message does the same.
+
+Examples:
+    /history
+    /history 500"#,
+{
+    count: Option<usize> = {
+        range: (1..)
+    }
+},
+|aparte, _command| {
+    let account = _command.account.clone().ok_or("No connection found".to_string())?;
+    let conversation = BareJid::from_str(&_command.context)
+        .map_err(|_| "/history only works in a conversation window".to_string())?;
+    let count = count.unwrap_or_else(|| page_size(aparte));
+    aparte.schedule(Event::History { account, conversation, count });
+    Ok(())
+});
+
+/// Date given to /goto, times are local
+fn parse_date(date: &str, now: DateTime<Local>) -> Result<DateTime<FixedOffset>, String> {
+    let invalid = || format!("Invalid date {}, expected YYYY-MM-DD [HH:MM]", date);
+    let today = now.date_naive();
+    let midnight = NaiveTime::MIN;
+    let (day, time) = match date.trim() {
+        "today" => (today, midnight),
+        "yesterday" => (today.pred_opt().ok_or_else(invalid)?, midnight),
+        date => {
+            let mut parts = date.split_whitespace();
+            let first = parts.next().ok_or_else(invalid)?;
+            let day = NaiveDate::parse_from_str(first, "%Y-%m-%d");
+            let time = |time| NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| invalid());
+            match (day, parts.next(), parts.next()) {
+                (Ok(day), None, None) => (day, midnight),
+                (Ok(day), Some(at), None) => (day, time(at)?),
+                (Err(_), None, None) => (today, time(first)?),
+                _ => return Err(invalid()),
+            }
+        }
+    };
+
+    Local
+        .from_local_datetime(&day.and_time(time))
+        .earliest()
+        .map(|date| date.into())
+        .ok_or_else(invalid)
+}
+
+/// Archive holding a conversation, and the contact to filter it with for chats
+fn archive_of(
+    aparte: &Aparte,
+    account: &Account,
+    conversation: &BareJid,
+) -> (BareJid, Option<BareJid>) {
+    let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+    match conversations.get(account, conversation) {
+        Some(Conversation::Channel(_)) => (conversation.clone(), None),
+        _ => (
+            BareJid::from(Jid::Full(account.clone())),
+            Some(conversation.clone()),
+        ),
+    }
+}
+
+/// Whether a message is the placeholder of messages missing from the history, see MamMod
+pub fn is_gap(message: &Message) -> bool {
+    matches!(message, Message::Log(log) if log.id.starts_with(GAP))
+}
+
+/// Number of archived messages fetched when opening a conversation or scrolling its history
+fn page_size(aparte: &Aparte) -> usize {
+    match aparte.config.low_bandwidth {
+        true => 20,
+        false => 100,
+    }
+}
 
 struct Query {
     jid: BareJid,
     with: Option<BareJid>,
     from: Option<DateTime<FixedOffset>>,
+    /// Date from which messages are fetched oldest first
+    start: Option<DateTime<FixedOffset>>,
     count: usize,
+    /// Archive id after which messages are fetched oldest first, used to catch up on what was
+    /// received while offline
+    after: Option<String>,
+    /// Number of messages received from each contact, only counted while catching up
+    received: HashMap<BareJid, usize>,
+    /// Dates of the oldest and newest messages fetched so far
+    fetched: Option<(DateTime<FixedOffset>, DateTime<FixedOffset>)>,
 }
 
 impl Query {
     pub fn start(&self) -> (String, Iq) {
-        // Start with before set to empty string in order to force xmpp_parser to generate a
-        // <before/> element and to ensure we get last page first
-        self.query(Some("".to_string()))
+        match (&self.after, &self.start) {
+            (Some(after), _) => self.query(None, Some(after.clone())),
+            (None, Some(_)) => self.query(None, None),
+            // Start with before set to empty string in order to force xmpp_parser to generate a
+            // <before/> element and to ensure we get last page first
+            (None, None) => self.query(Some("".to_string()), None),
+        }
     }
 
-    pub fn cont(&self, before: String) -> (String, Iq) {
-        self.query(Some(before))
+    pub fn cont(&self, id: String) -> (String, Iq) {
+        match (&self.after, &self.start) {
+            (None, None) => self.query(Some(id), None),
+            _ => self.query(None, Some(id)),
+        }
     }
 
-    fn query(&self, before: Option<String>) -> (String, Iq) {
+    fn query(&self, before: Option<String>, after: Option<String>) -> (String, Iq) {
         let mut fields = Vec::new();
 
+        if let Some(start) = self.start {
+            fields.push(Field {
+                var: "start".to_string(),
+                type_: FieldType::default(),
+                label: None,
+                required: false,
+                options: vec![],
+                values: vec![start.to_rfc3339()],
+                media: vec![],
+            });
+        }
+
         if let Some(end) = self.from {
             let datetime = end.to_rfc3339();
             fields.push(Field {
@@ -74,7 +287,7 @@ impl Query {
 
         let set = SetQuery {
             max: Some(self.count),
-            after: None,
+            after,
             before,
             index: None,
         };
@@ -95,12 +308,36 @@ impl Query {
     }
 }
 
+/// Archived messages missing between two fetched ones, all preceding a date
+struct Gap {
+    account: Account,
+    jid: BareJid,
+    with: Option<BareJid>,
+    before: DateTime<FixedOffset>,
+}
+
 pub struct MamMod {
     /// Queries indexed by queryid
     queries: HashMap<String, Query>,
 
     /// Mapping between iq ids and query ids
     iq2id: HashMap<String, String>,
+
+    /// Last archive id known for each account, indexed by bare jid and saved between runs
+    last_ids: HashMap<String, String>,
+
+    /// File where last archive ids are saved
+    state: Option<PathBuf>,
+
+    /// Whether last archive ids changed since they were saved, they are saved once a query
+    /// completes and on exit rather than for each message
+    dirty: bool,
+
+    /// Pending archiving preferences requests by iq id, with the new default to set if any
+    prefs: HashMap<String, Option<mam::DefaultPrefs>>,
+
+    /// Gaps in the history of conversations, by id of their placeholder
+    gaps: HashMap<String, Gap>,
 }
 
 impl MamMod {
@@ -108,6 +345,36 @@ impl MamMod {
         Self {
             queries: HashMap::new(),
             iq2id: HashMap::new(),
+            last_ids: HashMap::new(),
+            state: dirs::data_dir().map(|dir| dir.join("aparte").join("mam.toml")),
+            dirty: false,
+            prefs: HashMap::new(),
+            gaps: HashMap::new(),
+        }
+    }
+
+    fn load(&mut self) -> Result<(), String> {
+        let path = match &self.state {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let state = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        self.last_ids = toml::from_str(&state).map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        if let Some(path) = &self.state {
+            let result = toml::to_string(&self.last_ids)
+                .map_err(|err| err.to_string())
+                .and_then(|state| fs::write(path, state).map_err(|err| err.to_string()));
+            match result {
+                Ok(()) => self.dirty = false,
+                Err(err) => warn!("Cannot save last archive ids: {}", err),
+            }
         }
     }
 
@@ -118,13 +385,186 @@ impl MamMod {
         aparte.send(account, iq.into());
     }
 
+    /// Fetch every message archived since the last one seen by a previous session
+    fn catch_up(&mut self, aparte: &mut Aparte, account: &Account) {
+        let archive = BareJid::from(Jid::Full(account.clone()));
+        if let Some(after) = self.last_ids.get(&archive.to_string()).cloned() {
+            let query = Query {
+                jid: archive,
+                with: None,
+                from: None,
+                start: None,
+                count: page_size(aparte),
+                after: Some(after),
+                received: HashMap::new(),
+                fetched: None,
+            };
+            self.query(aparte, account, query);
+        }
+    }
+
+    /// Fetch the messages of a conversation archived around a date
+    fn go_to(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        conversation: &BareJid,
+        date: &DateTime<FixedOffset>,
+    ) {
+        let (jid, with) = archive_of(aparte, account, conversation);
+        let count = page_size(aparte) / 2;
+        let query = |from, start| Query {
+            jid: jid.clone(),
+            with: with.clone(),
+            from,
+            start,
+            count,
+            after: None,
+            received: HashMap::new(),
+            fetched: None,
+        };
+        self.query(aparte, account, query(Some(*date), None));
+        self.query(aparte, account, query(None, Some(*date)));
+    }
+
+    /// Fetch the messages of a conversation preceding the oldest one known
+    fn history(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        conversation: &BareJid,
+        count: usize,
+    ) {
+        let oldest = {
+            let messages = aparte.get_mod::<mods::messages::MessagesMod>();
+            messages
+                .conversation(&Some(account.clone()), conversation)
+                .first()
+                .map(|message| *message.timestamp())
+        };
+        match &oldest {
+            Some(oldest) => aparte.log(format!(
+                "Fetching {} messages with {} preceding {}",
+                count,
+                conversation,
+                oldest.format("%Y-%m-%d %H:%M")
+            )),
+            None => aparte.log(format!("Fetching {} messages with {}", count, conversation)),
+        }
+
+        let (jid, with) = archive_of(aparte, account, conversation);
+        let query = Query {
+            jid,
+            with,
+            from: oldest,
+            start: None,
+            count,
+            after: None,
+            received: HashMap::new(),
+            fetched: None,
+        };
+        self.query(aparte, account, query);
+    }
+
+    /// Remember the id given by the account archive to a live message
+    fn archived(&mut self, account: &Account, stanza: &Element) {
+        let archive = BareJid::from(Jid::Full(account.clone()));
+        for child in stanza.children() {
+            if let Ok(stanza_id) = StanzaId::try_from(child.clone()) {
+                if stanza_id.by == Jid::Bare(archive.clone()) {
+                    self.last_ids.insert(archive.to_string(), stanza_id.id);
+                    self.dirty = true;
+                }
+            }
+        }
+    }
+
+    fn handle_prefs(&mut self, aparte: &mut Aparte, account: &Account, iq: &Iq) {
+        let change = match self.prefs.remove(&iq.id) {
+            Some(change) => change,
+            None => return,
+        };
+
+        let prefs = match &iq.payload {
+            IqType::Result(Some(payload)) => match mam::Prefs::try_from(payload.clone()) {
+                Ok(prefs) => prefs,
+                Err(err) => {
+                    aparte.log(format!("Invalid archiving preferences: {}", err));
+                    return;
+                }
+            },
+            // Setting preferences may not return them
+            IqType::Result(None) => {
+                aparte.log("Archiving preferences saved".to_string());
+                return;
+            }
+            IqType::Error(err) => {
+                aparte.log(format!(
+                    "Cannot access archiving preferences: {:?}",
+                    err.defined_condition
+                ));
+                return;
+            }
+            _ => return,
+        };
+
+        match change {
+            Some(default_) => {
+                let id = Uuid::new_v4().to_hyphenated().to_string();
+                let iq = Iq::from_set(id.clone(), mam::Prefs { default_, ..prefs });
+                self.prefs.insert(id, None);
+                aparte.send(account, iq.into());
+            }
+            None => aparte.log(describe_prefs(&prefs)),
+        }
+    }
+
     fn handle_result(&mut self, aparte: &mut Aparte, account: &Account, result: mam::Result_) {
         if let Some(id) = &result.queryid {
             if let Some(query) = self.queries.get_mut(&id.0) {
-                query.count -= 1;
                 match (result.forwarded.delay, result.forwarded.stanza) {
-                    (Some(delay), Some(message)) => {
-                        aparte.schedule(Event::RawMessage(account.clone(), message, Some(delay)));
+                    (Some(delay), Some(mut message)) => {
+                        match query.after {
+                            // Catching up fetches everything, by pages
+                            Some(_) => {
+                                self.last_ids
+                                    .insert(query.jid.to_string(), result.id.clone());
+                                self.dirty = true;
+                                if let Some(from) = &message.from {
+                                    let from = BareJid::from(from.clone());
+                                    if from != query.jid {
+                                        *query.received.entry(from).or_insert(0) += 1;
+                                    }
+                                }
+                            }
+                            None => query.count = query.count.saturating_sub(1),
+                        }
+                        let stamp = delay.stamp.0;
+                        query.fetched = Some(match query.fetched {
+                            Some((oldest, newest)) => (oldest.min(stamp), newest.max(stamp)),
+                            None => (stamp, stamp),
+                        });
+                        // Live copies were stamped with the archive id, keep it to recognize them
+                        message.payloads.push(
+                            StanzaId {
+                                id: result.id,
+                                by: Jid::Bare(query.jid.clone()),
+                            }
+                            .into(),
+                        );
+                        // Copies of what a previous session showed are fetched again when it
+                        // couldn't save how far it got before exiting
+                        let shown = query.after.is_some() && {
+                            let messages = aparte.get_mod::<mods::messages::MessagesMod>();
+                            messages.seen_before(account, &message)
+                        };
+                        if !shown {
+                            aparte.schedule(Event::RawMessage(
+                                account.clone(),
+                                message,
+                                Some(delay),
+                            ));
+                        }
                     }
                     _ => {}
                 }
@@ -133,25 +573,171 @@ impl MamMod {
     }
 
     fn handle_fin(&mut self, aparte: &mut Aparte, account: &Account, query: Query, fin: mam::Fin) {
-        if fin.complete == mam::Complete::False {
-            if let Some(start) = fin.set.first {
+        self.save();
+        let next = match (&fin.complete, &query.after, &query.start) {
+            (mam::Complete::True, _, _) => None,
+            (mam::Complete::False, Some(_), _) => fin.set.last,
+            // Messages following a date are only fetched until there are enough of them
+            (mam::Complete::False, None, Some(_)) if query.count > 0 => fin.set.last,
+            (mam::Complete::False, None, Some(_)) => None,
+            // Same for messages preceding a date, a page at a time
+            (mam::Complete::False, None, None) if query.count > 0 => fin.set.first,
+            (mam::Complete::False, None, None) => None,
+        };
+        match next {
+            Some(id) => {
                 info!(
                     "Continuing MAM retrieval for {} with {:?} from {:?}",
                     query.jid,
                     query.with.clone().map(|jid| jid.to_string()),
                     query.from
                 );
-                let (queryid, iq) = query.cont(start);
+                let (queryid, iq) = query.cont(id);
                 self.queries.insert(queryid.clone(), query);
                 self.iq2id.insert(iq.id.clone(), queryid);
                 aparte.send(account, iq.into());
             }
+            None if query.after.is_some() => {
+                if let Some(away_log) = away_log(&query.received) {
+                    aparte.log(away_log);
+                }
+            }
+            None if fin.complete == mam::Complete::False => self.find_gap(aparte, account, query),
+            None => {}
+        }
+    }
+
+    /// Leave a placeholder where the pages fetched by a query stop short of the messages already
+    /// known
+    fn find_gap(&mut self, aparte: &mut Aparte, account: &Account, query: Query) {
+        let (oldest, newest) = match query.fetched {
+            Some(fetched) => fetched,
+            None => return,
+        };
+        let conversation = query.with.clone().unwrap_or_else(|| query.jid.clone());
+        let known = {
+            let messages = aparte.get_mod::<mods::messages::MessagesMod>();
+            messages.conversation(&Some(account.clone()), &conversation)
+        };
+        let before = match query.start {
+            // Paging forward stopped before the messages following the fetched ones
+            Some(_) => known
+                .iter()
+                .map(|message| *message.timestamp())
+                .find(|timestamp| timestamp > &newest),
+            // Paging backward stopped after the messages preceding the fetched ones
+            None => match known.iter().any(|message| message.timestamp() < &oldest) {
+                true => Some(oldest),
+                false => None,
+            },
+        };
+        if let Some(before) = before {
+            self.add_gap(aparte, account, query, before);
         }
     }
+
+    fn add_gap(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        query: Query,
+        before: DateTime<FixedOffset>,
+    ) {
+        let conversation = query.with.clone().unwrap_or_else(|| query.jid.clone());
+        let id = format!("{}{}", GAP, Uuid::new_v4().to_hyphenated());
+        aparte.schedule(Event::Notice {
+            account: account.clone(),
+            conversation,
+            message: Message::Log(LogMessage {
+                id: id.clone(),
+                timestamp: before - chrono::Duration::milliseconds(1),
+                body: "Gap in history, select it and press Enter to fetch more".to_string(),
+            }),
+        });
+        self.gaps.insert(
+            id,
+            Gap {
+                account: account.clone(),
+                jid: query.jid,
+                with: query.with,
+                before,
+            },
+        );
+    }
+
+    /// Fetch the page of messages preceding a gap placeholder
+    fn fetch_gap(&mut self, aparte: &mut Aparte, id: &str) {
+        let gap = match self.gaps.remove(id) {
+            Some(gap) => gap,
+            None => return,
+        };
+        let query = Query {
+            jid: gap.jid,
+            with: gap.with,
+            from: Some(gap.before),
+            start: None,
+            count: page_size(aparte),
+            after: None,
+            received: HashMap::new(),
+            fetched: None,
+        };
+        self.query(aparte, &gap.account, query);
+    }
+}
+
+fn describe_prefs(prefs: &mam::Prefs) -> String {
+    let default = match prefs.default_ {
+        mam::DefaultPrefs::Always => "every conversation",
+        mam::DefaultPrefs::Never => "no conversation",
+        mam::DefaultPrefs::Roster => "conversations with roster contacts",
+    };
+    let mut description = format!("The server archives {}", default);
+    let jids = |jids: &[Jid]| {
+        jids.iter()
+            .map(|jid| jid.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+    if !prefs.always.is_empty() {
+        description.push_str(&format!("\n  always: {}", jids(&prefs.always)));
+    }
+    if !prefs.never.is_empty() {
+        description.push_str(&format!("\n  never: {}", jids(&prefs.never)));
+    }
+    description
+}
+
+/// Summary of the messages received while offline, None if there is none
+fn away_log(received: &HashMap<BareJid, usize>) -> Option<String> {
+    let mut received = received
+        .iter()
+        .map(|(jid, count)| (jid.to_string(), *count))
+        .collect::<Vec<(String, usize)>>();
+    if received.is_empty() {
+        return None;
+    }
+    received.sort();
+
+    let total: usize = received.iter().map(|(_, count)| count).sum();
+    let senders = received
+        .iter()
+        .map(|(jid, count)| format!("{} ({})", jid, count))
+        .collect::<Vec<String>>();
+    Some(format!(
+        "{} messages received while offline: {}",
+        total,
+        senders.join(", ")
+    ))
 }
 
 impl ModTrait for MamMod {
-    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(archive::new());
+        aparte.add_command(goto::new());
+        aparte.add_command(history::new());
+        if let Err(err) = self.load() {
+            aparte.log(format!("Cannot load last archive ids: {}", err));
+        }
         Ok(())
     }
 
@@ -193,7 +779,11 @@ impl ModTrait for MamMod {
                     jid: channel.clone().into(),
                     with: None,
                     from: None,
-                    count: 100,
+                    start: None,
+                    count: page_size(aparte),
+                    after: None,
+                    received: HashMap::new(),
+                    fetched: None,
                 };
                 self.query(aparte, account, query);
             }
@@ -202,7 +792,11 @@ impl ModTrait for MamMod {
                     jid: account.clone().into(),
                     with: Some(contact.clone()),
                     from: None,
-                    count: 100,
+                    start: None,
+                    count: page_size(aparte),
+                    after: None,
+                    received: HashMap::new(),
+                    fetched: None,
                 };
                 self.query(aparte, account, query);
             }
@@ -211,7 +805,11 @@ impl ModTrait for MamMod {
                     jid: jid.clone(),
                     with: None,
                     from: from.clone(),
-                    count: 100,
+                    start: None,
+                    count: page_size(aparte),
+                    after: None,
+                    received: HashMap::new(),
+                    fetched: None,
                 };
                 self.query(aparte, account, query);
             }
@@ -224,19 +822,71 @@ impl ModTrait for MamMod {
                     jid: account.clone().into(),
                     with: Some(contact.clone()),
                     from: from.clone(),
-                    count: 100,
+                    start: None,
+                    count: page_size(aparte),
+                    after: None,
+                    received: HashMap::new(),
+                    fetched: None,
                 };
                 self.query(aparte, account, query);
             }
+            Event::GoTo {
+                account,
+                conversation,
+                date,
+            } => self.go_to(aparte, account, conversation, date),
+            Event::History {
+                account,
+                conversation,
+                count,
+            } => self.history(aparte, account, conversation, *count),
+            Event::FetchGap(id) => self.fetch_gap(aparte, id),
+            Event::Connected(account, _) => self.catch_up(aparte, account),
+            Event::Quit => self.save(),
+            Event::Stanza(account, stanza) if stanza.is("message", ns::DEFAULT_NS) => {
+                self.archived(account, stanza)
+            }
+            Event::Iq(account, iq) if self.prefs.contains_key(&iq.id) => {
+                self.handle_prefs(aparte, account, iq)
+            }
             Event::Iq(account, iq) => {
                 if let Some(id) = self.iq2id.remove(&iq.id) {
                     if let Some(query) = self.queries.remove(&id) {
-                        if let IqType::Result(Some(payload)) = &iq.payload {
-                            if let Ok(fin) = mam::Fin::try_from(payload.clone()) {
-                                self.handle_fin(aparte, account, query, fin);
-                            } else {
-                                warn!("Incorrect IQ response for MAM query");
+                        match &iq.payload {
+                            IqType::Result(Some(payload)) => {
+                                if let Ok(fin) = mam::Fin::try_from(payload.clone()) {
+                                    self.handle_fin(aparte, account, query, fin);
+                                } else {
+                                    warn!("Incorrect IQ response for MAM query");
+                                }
+                            }
+                            IqType::Error(err) if query.after.is_some() => {
+                                aparte.log(format!(
+                                    "Cannot fetch messages received while offline: {:?}",
+                                    err.defined_condition
+                                ));
+                                // The archive no longer knows the last id, start over
+                                if err.defined_condition == DefinedCondition::ItemNotFound {
+                                    self.last_ids.remove(&query.jid.to_string());
+                                    self.dirty = true;
+                                    self.save();
+                                }
+                            }
+                            // Messages preceding a date are missing, until fetched again
+                            IqType::Error(err) if query.start.is_none() => {
+                                let conversation = query.with.as_ref().unwrap_or(&query.jid);
+                                aparte.log(format!(
+                                    "Cannot fetch the history of {}: {:?}",
+                                    conversation, err.defined_condition
+                                ));
+                                let before = match (query.fetched, query.from) {
+                                    (Some((oldest, _)), _) => oldest,
+                                    (None, Some(from)) => from,
+                                    (None, None) => Local::now().into(),
+                                };
+                                self.add_gap(aparte, account, query, before);
                             }
+                            _ => {}
                         }
                     }
                 }
@@ -251,3 +901,227 @@ impl fmt::Display for MamMod {
         write!(f, "XEP-0313: Message Archive Management")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+    use termion::event::Key;
+
+    fn set(iq: Iq) -> SetQuery {
+        match iq.payload {
+            IqType::Set(payload) => mam::Query::try_from(payload).unwrap().set.unwrap(),
+            _ => panic!("MAM queries are sent as iq set"),
+        }
+    }
+
+    #[test]
+    fn test_catch_up_pages_forward() {
+        // Given
+        let query = Query {
+            jid: BareJid::from_str("me@example.org").unwrap(),
+            with: None,
+            from: None,
+            start: None,
+            count: 100,
+            after: Some(String::from("last-seen")),
+            received: HashMap::new(),
+            fetched: None,
+        };
+
+        // When
+        let (_, start) = query.start();
+        let (_, cont) = query.cont(String::from("last-fetched"));
+
+        // Then
+        let start = set(start);
+        assert_eq!(start.after, Some(String::from("last-seen")));
+        assert_eq!(start.before, None);
+        let cont = set(cont);
+        assert_eq!(cont.after, Some(String::from("last-fetched")));
+        assert_eq!(cont.before, None);
+    }
+
+    #[test]
+    fn test_messages_following_a_date_page_forward() {
+        // Given
+        let start = DateTime::parse_from_rfc3339("2021-03-14T18:30:00+01:00").unwrap();
+        let query = Query {
+            jid: BareJid::from_str("room@conference.example.org").unwrap(),
+            with: None,
+            from: None,
+            start: Some(start),
+            count: 50,
+            after: None,
+            received: HashMap::new(),
+            fetched: None,
+        };
+
+        // When
+        let (_, first) = query.start();
+        let (_, cont) = query.cont(String::from("last-fetched"));
+
+        // Then
+        let first = set(first);
+        assert_eq!(first.after, None);
+        assert_eq!(first.before, None);
+        let cont = set(cont);
+        assert_eq!(cont.after, Some(String::from("last-fetched")));
+        assert_eq!(cont.before, None);
+    }
+
+    #[test]
+    fn test_history_pages_backward_from_the_oldest_message() {
+        // Given
+        let oldest = DateTime::parse_from_rfc3339("2021-03-14T18:30:00+01:00").unwrap();
+        let query = Query {
+            jid: BareJid::from_str("me@example.org").unwrap(),
+            with: Some(BareJid::from_str("juliet@example.org").unwrap()),
+            from: Some(oldest),
+            start: None,
+            count: 500,
+            after: None,
+            received: HashMap::new(),
+            fetched: None,
+        };
+
+        // When
+        let (_, first) = query.start();
+        let (_, cont) = query.cont(String::from("first-fetched"));
+
+        // Then
+        let first = set(first);
+        assert_eq!(first.before, Some(String::new()));
+        assert_eq!(first.max, Some(500));
+        let cont = set(cont);
+        assert_eq!(cont.before, Some(String::from("first-fetched")));
+        assert_eq!(cont.after, None);
+    }
+
+    #[test]
+    fn test_history_failing_leaves_a_gap_fetched_on_enter() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness.input("console", "/msg juliet@example.org").await;
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='chat' id='m1'
+                        from='juliet@example.org/balcony' to='{account}'>
+                        <body>Art thou not Romeo?</body>
+                    </message>",
+                )
+                .await;
+            harness.take_sent("query", ns::MAM);
+            harness.reply(
+                "query",
+                ns::MAM,
+                "<iq xmlns='jabber:client' type='error' id='{id}' to='{account}'>
+                    <error type='wait'>
+                        <resource-constraint xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+                    </error>
+                </iq>",
+            );
+            harness.input("juliet@example.org", "/history").await;
+            assert!(harness.screen().contains("Gap in history"));
+            assert_eq!(harness.aparte.get_mod::<MamMod>().gaps.len(), 1);
+
+            // When
+            harness.aparte.schedule(Event::Key(Key::Ctrl('p')));
+            harness.aparte.schedule(Event::Key(Key::Ctrl('p')));
+            harness.aparte.schedule(Event::Key(Key::Char('\n')));
+            harness.settle().await;
+
+            // Then
+            assert_eq!(harness.take_sent("query", ns::MAM).len(), 1);
+            assert!(harness.aparte.get_mod::<MamMod>().gaps.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_last_archive_id_is_saved_on_exit_rather_than_for_each_message() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            let state = std::env::temp_dir().join(format!("aparte-mam-{}.toml", Uuid::new_v4()));
+            harness.aparte.get_mod_mut::<MamMod>().state = Some(state.clone());
+            harness.connect().await;
+
+            // When
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='chat' id='m1'
+                        from='juliet@example.org/balcony' to='{account}'>
+                        <body>Art thou not Romeo?</body>
+                        <stanza-id xmlns='urn:xmpp:sid:0' id='archived-1' by='romeo@example.org'/>
+                    </message>",
+                )
+                .await;
+            let saved_on_message = state.exists();
+            harness.aparte.schedule(Event::Quit);
+            harness.settle().await;
+
+            // Then
+            assert!(!saved_on_message);
+            let saved = fs::read_to_string(&state).unwrap();
+            assert!(saved.contains("archived-1"));
+            fs::remove_file(state).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_parse_date() {
+        // Given
+        let now = Local.with_ymd_and_hms(2021, 3, 14, 18, 30, 0).unwrap();
+        let at = |y, m, d, h, min| -> DateTime<FixedOffset> {
+            Local.with_ymd_and_hms(y, m, d, h, min, 0).unwrap().into()
+        };
+
+        // Then
+        assert_eq!(parse_date("2021-01-02", now), Ok(at(2021, 1, 2, 0, 0)));
+        assert_eq!(
+            parse_date("2021-01-02 09:15", now),
+            Ok(at(2021, 1, 2, 9, 15))
+        );
+        assert_eq!(parse_date("12:00", now), Ok(at(2021, 3, 14, 12, 0)));
+        assert_eq!(parse_date("yesterday", now), Ok(at(2021, 3, 13, 0, 0)));
+        assert!(parse_date("next week", now).is_err());
+    }
+
+    #[test]
+    fn test_describe_prefs() {
+        // Given
+        let prefs = mam::Prefs {
+            default_: mam::DefaultPrefs::Roster,
+            always: vec![],
+            never: vec![Jid::from_str("secret@example.org").unwrap()],
+        };
+
+        // When
+        let description = describe_prefs(&prefs);
+
+        // Then
+        assert_eq!(
+            description,
+            "The server archives conversations with roster contacts\n  never: secret@example.org"
+        );
+    }
+
+    #[test]
+    fn test_away_log() {
+        // Given
+        let mut received = HashMap::new();
+        received.insert(BareJid::from_str("bob@example.org").unwrap(), 2);
+        received.insert(BareJid::from_str("alice@example.org").unwrap(), 3);
+
+        // Then
+        assert_eq!(away_log(&HashMap::new()), None);
+        assert_eq!(
+            away_log(&received),
+            Some(String::from(
+                "5 messages received while offline: alice@example.org (3), bob@example.org (2)"
+            ))
+        );
+    }
+}
@@ -1,26 +1,240 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryFrom;
 use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
 use xmpp_parsers::delay::Delay;
 use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType as XmppParsersMessageType};
-use xmpp_parsers::ns;
+use xmpp_parsers::stanza_id::{OriginId, StanzaId};
+use xmpp_parsers::{ns, BareJid, Jid};
 
 use crate::account::Account;
 use crate::core::{Aparte, Event, ModTrait};
-use crate::message::Message;
+use crate::message::{Message, VersionedXmppMessage, XmppMessageType};
 use crate::mods::disco;
 
+/// Identifiers remembered for each conversation, the oldest ones are forgotten first
+const SEEN_LIMIT: usize = 1000;
+
+/// Identifiers of the last messages of a conversation
+#[derive(Default)]
+struct Seen {
+    /// Oldest first, at most SEEN_LIMIT of them
+    order: VecDeque<String>,
+    /// Whether each identifier was seen by this session, rather than loaded from a previous one
+    current: HashMap<String, bool>,
+}
+
+impl Seen {
+    fn contains(&self, id: &str, previous: bool) -> bool {
+        match self.current.get(id) {
+            Some(current) => *current || previous,
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, id: String, current: bool) {
+        if let Some(seen) = self.current.get_mut(&id) {
+            *seen |= current;
+            return;
+        }
+        if self.order.len() == SEEN_LIMIT {
+            if let Some(oldest) = self.order.pop_front() {
+                self.current.remove(&oldest);
+            }
+        }
+        self.order.push_back(id.clone());
+        self.current.insert(id, current);
+    }
+}
+
+/// Deduplication of messages, by conversation of each account
+///
+/// Aparté doesn't keep messages between runs, windows are filled again with the history fetched
+/// from archives. Identifiers seen by a previous session are saved so that catching up on the
+/// messages received while offline skips the ones already shown, but they don't hide history
+/// pages: only identifiers seen by this session drop copies fetched from archives.
 pub struct MessagesMod {
     messages: HashMap<Option<Account>, HashMap<String, Message>>,
+    /// Identifiers of the messages already received or sent, by bare jid of the account and
+    /// conversation, so that copies coming through carbons or MAM are dropped
+    seen: HashMap<String, HashMap<BareJid, Seen>>,
+    /// File where identifiers are saved
+    state: Option<PathBuf>,
+    /// Whether identifiers changed since they were saved, they are saved on exit
+    dirty: bool,
+}
+
+/// Conversation a received message is part of
+fn conversation(account: &Account, message: &XmppParsersMessage) -> BareJid {
+    let us = BareJid::from(Jid::Full(account.clone()));
+    let from = message.from.clone().map(BareJid::from);
+    let to = message.to.clone().map(BareJid::from);
+    match (message.type_.clone(), from, to) {
+        (XmppParsersMessageType::Groupchat, Some(from), _) => from,
+        // Carbons of messages we sent
+        (_, Some(from), Some(to)) if from == us => to,
+        (_, Some(from), _) => from,
+        (_, None, _) => us,
+    }
+}
+
+/// Identifiers of a message: ids chosen by its sender and stanza-ids given by the archive
+/// storing it. Only stanza-ids from our own archive or from the room can be trusted (XEP-0359).
+/// Occupants choose their ids independently, those are kept with their full occupant jid.
+fn identifiers(account: &Account, message: &XmppParsersMessage) -> Vec<String> {
+    let sender = match (&message.type_, &message.from) {
+        (XmppParsersMessageType::Groupchat, Some(from)) => from.to_string(),
+        (_, Some(from)) => BareJid::from(from.clone()).to_string(),
+        (_, None) => BareJid::from(Jid::Full(account.clone())).to_string(),
+    };
+    let archive = match (&message.type_, &message.from) {
+        (XmppParsersMessageType::Groupchat, Some(from)) => BareJid::from(from.clone()),
+        _ => BareJid::from(Jid::Full(account.clone())),
+    };
+
+    let mut identifiers = Vec::new();
+    if let Some(id) = &message.id {
+        identifiers.push(format!("id {} {}", sender, id));
+    }
+    for payload in message.payloads.iter().cloned() {
+        if let Ok(origin_id) = OriginId::try_from(payload.clone()) {
+            identifiers.push(format!("id {} {}", sender, origin_id.id));
+        } else if let Ok(stanza_id) = StanzaId::try_from(payload) {
+            let by = BareJid::from(stanza_id.by);
+            if by == archive {
+                identifiers.push(format!("stanza-id {} {}", archive, stanza_id.id));
+            }
+        }
+    }
+
+    identifiers
 }
 
 impl MessagesMod {
     pub fn new() -> Self {
         Self {
             messages: HashMap::new(),
+            seen: HashMap::new(),
+            state: dirs::data_dir().map(|dir| dir.join("aparte").join("seen.toml")),
+            dirty: false,
+        }
+    }
+
+    fn load(&mut self) -> Result<(), String> {
+        let path = match &self.state {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let state = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let seen: HashMap<String, HashMap<String, Vec<String>>> =
+            toml::from_str(&state).map_err(|err| err.to_string())?;
+        for (account, conversations) in seen {
+            let account = self.seen.entry(account).or_default();
+            for (conversation, identifiers) in conversations {
+                let conversation = BareJid::from_str(&conversation)
+                    .map_err(|err| format!("{}: {}", conversation, err))?;
+                let seen = account.entry(conversation).or_default();
+                for id in identifiers {
+                    seen.insert(id, false);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        if let Some(path) = &self.state {
+            let seen = self
+                .seen
+                .iter()
+                .map(|(account, conversations)| {
+                    let conversations = conversations
+                        .iter()
+                        .map(|(conversation, seen)| {
+                            (
+                                conversation.to_string(),
+                                seen.order.iter().cloned().collect(),
+                            )
+                        })
+                        .collect::<HashMap<String, Vec<String>>>();
+                    (account.clone(), conversations)
+                })
+                .collect::<HashMap<String, HashMap<String, Vec<String>>>>();
+            let result = toml::to_string(&seen)
+                .map_err(|err| err.to_string())
+                .and_then(|state| fs::write(path, state).map_err(|err| err.to_string()));
+            match result {
+                Ok(()) => self.dirty = false,
+                Err(err) => warn!("Cannot save seen messages: {}", err),
+            }
+        }
+    }
+
+    fn seen_mut(&mut self, account: &Account, conversation: BareJid) -> &mut Seen {
+        let account = BareJid::from(Jid::Full(account.clone())).to_string();
+        self.seen
+            .entry(account)
+            .or_default()
+            .entry(conversation)
+            .or_default()
+    }
+
+    /// Whether the message was already received by this session, live, as a carbon or from an
+    /// archive. All its identifiers are remembered so that any later copy is recognized.
+    pub fn is_duplicate(&mut self, account: &Account, message: &XmppParsersMessage) -> bool {
+        // Only messages with content are stored and displayed
+        if message.bodies.is_empty() {
+            return false;
+        }
+
+        let identifiers = identifiers(account, message);
+        let seen = self.seen_mut(account, conversation(account, message));
+        let duplicate = identifiers.iter().any(|id| seen.contains(id, false));
+        for id in identifiers {
+            seen.insert(id, true);
+        }
+        self.dirty = true;
+        duplicate
+    }
+
+    /// Whether the message was already received, by this session or a previous one
+    pub fn seen_before(&self, account: &Account, message: &XmppParsersMessage) -> bool {
+        let bare = BareJid::from(Jid::Full(account.clone())).to_string();
+        match self
+            .seen
+            .get(&bare)
+            .and_then(|seen| seen.get(&conversation(account, message)))
+        {
+            Some(seen) => identifiers(account, message)
+                .iter()
+                .any(|id| seen.contains(id, true)),
+            None => false,
+        }
+    }
+
+    /// Remember a message we sent, its copies from the archive or reflected by the room are
+    /// dropped
+    fn handle_sent_message(&mut self, account: &Account, message: &Message) {
+        if let Message::Xmpp(message) = message {
+            let sender = match (&message.type_, &message.from_full) {
+                (XmppMessageType::Chat, _) => message.from.to_string(),
+                // Reflected by the room from our occupant jid
+                (XmppMessageType::Channel, Jid::Full(from)) => {
+                    format!("{}/{}", message.to, from.resource)
+                }
+                (XmppMessageType::Channel, Jid::Bare(_)) => message.to.to_string(),
+            };
+            let id = format!("id {} {}", sender, message.id);
+            self.seen_mut(account, message.to.clone()).insert(id, true);
+            self.dirty = true;
         }
     }
 
@@ -36,6 +250,51 @@ impl MessagesMod {
         self.messages.get_mut(account)?.get_mut(id)
     }
 
+    /// All known messages exchanged with a contact, oldest first
+    pub fn conversation(&self, account: &Option<Account>, contact: &BareJid) -> Vec<Message> {
+        let mut conversation = match self.messages.get(account) {
+            Some(messages) => messages
+                .values()
+                .filter(|message| match message {
+                    Message::Xmpp(message) => &message.from == contact || &message.to == contact,
+                    Message::Log(_) => false,
+                })
+                .cloned()
+                .collect::<Vec<Message>>(),
+            None => Vec::new(),
+        };
+        conversation.sort();
+        conversation
+    }
+
+    /// Messages containing the given text, case insensitively, in a conversation or in all of
+    /// them. Oldest first.
+    pub fn search(
+        &self,
+        text: &str,
+        conversation: Option<&BareJid>,
+    ) -> Vec<(Account, VersionedXmppMessage)> {
+        let text = text.to_lowercase();
+        let mut found = self
+            .messages
+            .iter()
+            .filter_map(|(account, messages)| Some((account.as_ref()?, messages)))
+            .flat_map(|(account, messages)| {
+                messages.values().filter_map(move |message| match message {
+                    Message::Xmpp(message) => Some((account.clone(), message.clone())),
+                    Message::Log(_) => None,
+                })
+            })
+            .filter(|(_, message)| match conversation {
+                Some(conversation) => message.conversation() == conversation,
+                None => true,
+            })
+            .filter(|(_, message)| message.get_last_body().to_lowercase().contains(&text))
+            .collect::<Vec<(Account, VersionedXmppMessage)>>();
+        found.sort_by(|(_, a), (_, b)| a.get_original_timestamp().cmp(b.get_original_timestamp()));
+        found
+    }
+
     pub fn handle_message(&mut self, account: &Option<Account>, message: &Message) {
         let messages = self
             .messages
@@ -49,7 +308,7 @@ impl MessagesMod {
         aparte: &mut Aparte,
         account: &Account,
         message: &XmppParsersMessage,
-        _delay: &Option<Delay>,
+        delay: &Option<Delay>,
     ) {
         for payload in message.payloads.iter().cloned() {
             if let Ok(pubsub_event) = xmpp_parsers::pubsub::event::PubSubEvent::try_from(payload) {
@@ -57,11 +316,52 @@ impl MessagesMod {
                 aparte.schedule(Event::PubSub(account.clone(), pubsub_event));
             }
         }
+        self.handle_announcement(aparte, account, message, delay);
+    }
+
+    /// Messages sent by servers themselves (MOTD, maintenance notices…) have no node, or no
+    /// sender at all when coming from our own server
+    fn from_server(message: &XmppParsersMessage) -> bool {
+        match &message.from {
+            Some(from) => BareJid::from(from.clone()).node.is_none(),
+            None => true,
+        }
+    }
+
+    /// Headlines and server messages aren't part of any conversation, they are shown in the
+    /// announcements window
+    fn handle_announcement(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: &XmppParsersMessage,
+        delay: &Option<Delay>,
+    ) {
+        let body = match message.get_best_body(Vec::new()) {
+            Some((_, body)) => &body.0,
+            None => return,
+        };
+        let from = match &message.from {
+            Some(from) => from.to_string(),
+            None => account.domain.clone(),
+        };
+        let text = match message.get_best_subject(Vec::new()) {
+            Some((_, subject)) => format!("{}: {}\n{}", from, subject.0, body),
+            None => format!("{}: {}", from, body),
+        };
+        let announcement = match delay {
+            Some(delay) => Message::log_at(text, delay.stamp.0),
+            None => Message::log(text),
+        };
+        aparte.schedule(Event::Announcement(account.clone(), announcement));
     }
 }
 
 impl ModTrait for MessagesMod {
     fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        if let Err(err) = self.load() {
+            aparte.log(format!("Cannot load seen messages: {}", err));
+        }
         let mut disco = aparte.get_mod_mut::<disco::DiscoMod>();
         disco.add_feature(ns::MESSAGE_CORRECT)
     }
@@ -89,16 +389,22 @@ impl ModTrait for MessagesMod {
                 }
             }
             XmppParsersMessageType::Headline => {
-                if message
-                    .payloads
-                    .iter()
-                    .any(|p| p.is("event", ns::PUBSUB_EVENT))
+                if !message.bodies.is_empty()
+                    || message
+                        .payloads
+                        .iter()
+                        .any(|p| p.is("event", ns::PUBSUB_EVENT))
                 {
                     0.01f64
                 } else {
                     0f64
                 }
             }
+            XmppParsersMessageType::Normal
+                if !message.bodies.is_empty() && Self::from_server(message) =>
+            {
+                0.01f64
+            }
             _ => 0f64,
         }
     }
@@ -142,6 +448,9 @@ impl ModTrait for MessagesMod {
             XmppParsersMessageType::Headline => {
                 self.handle_headline_message(aparte, account, message, delay)
             }
+            XmppParsersMessageType::Normal if Self::from_server(message) => {
+                self.handle_announcement(aparte, account, message, delay)
+            }
             XmppParsersMessageType::Error => {}
             XmppParsersMessageType::Normal => {}
         };
@@ -150,6 +459,8 @@ impl ModTrait for MessagesMod {
     fn on_event(&mut self, _aparte: &mut Aparte, event: &Event) {
         match event {
             Event::Message(account, message) => self.handle_message(account, message),
+            Event::SendMessage(account, message) => self.handle_sent_message(account, message),
+            Event::Quit => self.save(),
             _ => {}
         }
     }
@@ -160,3 +471,226 @@ impl fmt::Display for MessagesMod {
         write!(f, "Message store")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use xmpp_parsers::message::Body;
+
+    fn message(id: Option<&str>, payloads: Vec<xmpp_parsers::Element>) -> XmppParsersMessage {
+        let mut message = XmppParsersMessage::new(Some(Jid::from_str("me@example.org").unwrap()));
+        message.from = Some(Jid::from_str("friend@example.org/phone").unwrap());
+        message.type_ = XmppParsersMessageType::Chat;
+        message.id = id.map(String::from);
+        message
+            .bodies
+            .insert("".to_string(), Body("Hello".to_string()));
+        message.payloads = payloads;
+        message
+    }
+
+    fn stanza_id(id: &str, by: &str) -> xmpp_parsers::Element {
+        StanzaId {
+            id: id.to_string(),
+            by: Jid::from_str(by).unwrap(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_copies_from_archive_are_duplicates() {
+        // Given
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let mut messages = MessagesMod::new();
+        let live = message(None, vec![stanza_id("archived", "me@example.org")]);
+        let archived = message(Some("other"), vec![stanza_id("archived", "me@example.org")]);
+        let carbon = message(
+            Some("sent"),
+            vec![OriginId {
+                id: "origin".to_string(),
+            }
+            .into()],
+        );
+        let archived_carbon = message(Some("origin"), vec![]);
+
+        // Then
+        assert!(!messages.is_duplicate(&account, &live));
+        assert!(messages.is_duplicate(&account, &archived));
+        assert!(!messages.is_duplicate(&account, &carbon));
+        assert!(messages.is_duplicate(&account, &archived_carbon));
+    }
+
+    fn groupchat(from: &str, id: &str) -> XmppParsersMessage {
+        let mut message = message(Some(id), vec![]);
+        message.from = Some(Jid::from_str(from).unwrap());
+        message.type_ = XmppParsersMessageType::Groupchat;
+        message
+    }
+
+    #[test]
+    fn test_occupants_using_the_same_id_are_not_duplicates() {
+        // Given
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let mut messages = MessagesMod::new();
+        let juliet = groupchat("room@chat.example.org/juliet", "1");
+        let romeo = groupchat("room@chat.example.org/romeo", "1");
+
+        // Then
+        assert!(!messages.is_duplicate(&account, &juliet));
+        assert!(!messages.is_duplicate(&account, &romeo));
+        assert!(messages.is_duplicate(&account, &juliet));
+    }
+
+    #[test]
+    fn test_messages_reflected_by_the_room_are_duplicates() {
+        // Given
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let mut messages = MessagesMod::new();
+        let from = Jid::from_str("me@example.org/mynick").unwrap();
+        let to = Jid::from_str("room@chat.example.org").unwrap();
+        let mut bodies = HashMap::new();
+        bodies.insert(String::new(), String::from("Hello"));
+        let sent =
+            Message::outgoing_channel("sent", chrono::Local::now().into(), &from, &to, &bodies);
+
+        // When
+        messages.handle_sent_message(&account, &sent);
+
+        // Then
+        assert!(messages.is_duplicate(&account, &groupchat("room@chat.example.org/mynick", "sent")));
+    }
+
+    #[test]
+    fn test_oldest_identifiers_are_forgotten() {
+        // Given
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let mut messages = MessagesMod::new();
+        let first = message(Some("first"), vec![]);
+        messages.is_duplicate(&account, &first);
+
+        // When
+        for i in 0..SEEN_LIMIT {
+            messages.is_duplicate(&account, &message(Some(&i.to_string()), vec![]));
+        }
+
+        // Then
+        assert!(!messages.is_duplicate(&account, &first));
+    }
+
+    #[test]
+    fn test_messages_seen_by_a_previous_session_only_skip_catch_up() {
+        // Given
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let state = std::env::temp_dir().join(format!("aparte-seen-{}.toml", std::process::id()));
+        let mut previous = MessagesMod::new();
+        previous.state = Some(state.clone());
+        let seen = message(Some("seen"), vec![stanza_id("archived", "me@example.org")]);
+        previous.is_duplicate(&account, &seen);
+        previous.save();
+
+        // When
+        let mut messages = MessagesMod::new();
+        messages.state = Some(state.clone());
+        messages.load().unwrap();
+        std::fs::remove_file(&state).unwrap();
+
+        // Then
+        assert!(messages.seen_before(&account, &seen));
+        assert!(!messages.is_duplicate(&account, &seen));
+        assert!(messages.is_duplicate(&account, &seen));
+    }
+
+    #[test]
+    fn test_search_in_a_conversation() {
+        // Given
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let friend = BareJid::from_str("friend@example.org").unwrap();
+        let mut messages = MessagesMod::new();
+        let mut other = message(Some("other"), vec![]);
+        other.from = Some(Jid::from_str("other@example.org/phone").unwrap());
+        for message in &[message(Some("hello"), vec![]), other] {
+            let message = Message::from_xmpp(&account, message, &None).unwrap();
+            messages.handle_message(&Some(account.clone()), &message);
+        }
+
+        // When
+        let everywhere = messages.search("hel", None);
+        let with_friend = messages.search("HELLO", Some(&friend));
+
+        // Then
+        assert_eq!(everywhere.len(), 2);
+        assert_eq!(
+            with_friend
+                .iter()
+                .map(|(_, message)| message.id.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["hello"]
+        );
+    }
+
+    #[test]
+    fn test_only_delay_of_the_message_itself_marks_it_delayed() {
+        // Given
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let delay = Delay {
+            from: Some(Jid::from_str("example.org").unwrap()),
+            stamp: xmpp_parsers::date::DateTime::from_str("2002-09-10T23:08:25Z").unwrap(),
+            data: None,
+        };
+        let offline = message(Some("offline"), vec![delay.clone().into()]);
+        let archived = message(Some("archived"), vec![]);
+
+        // When
+        let offline = Message::from_xmpp(&account, &offline, &None).unwrap();
+        let archived = Message::from_xmpp(&account, &archived, &Some(delay.clone())).unwrap();
+
+        // Then
+        match (offline, archived) {
+            (Message::Xmpp(offline), Message::Xmpp(archived)) => {
+                assert!(offline.delayed);
+                assert_eq!(offline.get_original_timestamp(), &delay.stamp.0);
+                assert!(!archived.delayed);
+                assert_eq!(archived.get_original_timestamp(), &delay.stamp.0);
+            }
+            _ => panic!("Not xmpp messages"),
+        }
+    }
+
+    #[test]
+    fn test_omemo_fallback_body_is_replaced() {
+        // Given
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let encrypted =
+            xmpp_parsers::Element::builder("encrypted", "eu.siacs.conversations.axolotl").build();
+        let omemo = message(Some("omemo"), vec![encrypted]);
+
+        // When
+        let omemo = Message::from_xmpp(&account, &omemo, &None).unwrap();
+
+        // Then
+        match omemo {
+            Message::Xmpp(omemo) => assert_eq!(
+                omemo.get_last_body(),
+                "🔒 Encrypted with OMEMO, which Aparté can't decrypt"
+            ),
+            _ => panic!("Not an xmpp message"),
+        }
+    }
+
+    #[test]
+    fn test_stanza_ids_from_strangers_are_ignored() {
+        // Given
+        let account = Account::from_str("me@example.org/aparte").unwrap();
+        let mut messages = MessagesMod::new();
+        let forged = message(
+            Some("forged"),
+            vec![stanza_id("real", "friend@example.org")],
+        );
+        let real = message(Some("real"), vec![stanza_id("real", "me@example.org")]);
+
+        // Then
+        assert!(!messages.is_duplicate(&account, &forged));
+        assert!(!messages.is_duplicate(&account, &real));
+    }
+}
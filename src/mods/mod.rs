@@ -1,13 +1,54 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+pub mod accounts;
+pub mod adhoc;
+pub mod attention;
+pub mod avatar;
+pub mod blocking;
+pub mod bob;
 pub mod bookmarks;
+pub mod browse;
+pub mod bytestreams;
+pub mod captcha;
 pub mod carbons;
 pub mod completion;
 pub mod contact;
 pub mod conversation;
 pub mod correction;
+pub mod csi;
+#[cfg(feature = "dbus")]
+pub mod dbus;
 pub mod disco;
+pub mod emoji;
+pub mod filetransfer;
+pub mod filter;
+pub mod ibb;
+pub mod idle;
+pub mod invite;
+pub mod links;
 pub mod mam;
 pub mod messages;
+pub mod mood;
+pub mod moved;
+pub mod openpgp;
+pub mod presence;
+pub mod preview;
+pub mod profile;
+pub mod receipts;
+pub mod register;
+pub mod remind;
+pub mod requests;
+pub mod room;
+pub mod rosterx;
+pub mod search;
+pub mod selfping;
+pub mod sm;
+pub mod spoiler;
+pub mod stats;
+pub mod switch;
+pub mod tune;
 pub mod ui;
+pub mod upload;
+pub mod vcard;
+pub mod workspace;
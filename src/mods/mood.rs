@@ -0,0 +1,626 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Mood (XEP-0107) and activity (XEP-0108) of the user and of their contacts, through PEP.
+//!
+//! Both are published on every connected account, and the ones of contacts are shown next to
+//! them in the roster.
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::delay::Delay;
+use xmpp_parsers::iq::Iq;
+use xmpp_parsers::message::Message as XmppParsersMessage;
+use xmpp_parsers::pubsub::pubsub::{self, Publish};
+use xmpp_parsers::pubsub::{Item, ItemId, NodeName, PubSub, PubSubEvent};
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::mods;
+
+pub const ACTIVITY: &str = "http://jabber.org/protocol/activity";
+
+/// Moods defined by XEP-0107
+const MOODS: &[&str] = &[
+    "afraid",
+    "amazed",
+    "amorous",
+    "angry",
+    "annoyed",
+    "anxious",
+    "aroused",
+    "ashamed",
+    "bored",
+    "brave",
+    "calm",
+    "cautious",
+    "cold",
+    "confident",
+    "confused",
+    "contemplative",
+    "contented",
+    "cranky",
+    "crazy",
+    "creative",
+    "curious",
+    "dejected",
+    "depressed",
+    "disappointed",
+    "disgusted",
+    "dismayed",
+    "distracted",
+    "embarrassed",
+    "envious",
+    "excited",
+    "flirtatious",
+    "frustrated",
+    "grateful",
+    "grieving",
+    "grumpy",
+    "guilty",
+    "happy",
+    "hopeful",
+    "hot",
+    "humbled",
+    "humiliated",
+    "hungry",
+    "hurt",
+    "impressed",
+    "in_awe",
+    "in_love",
+    "indignant",
+    "interested",
+    "intoxicated",
+    "invincible",
+    "jealous",
+    "lonely",
+    "lost",
+    "lucky",
+    "mean",
+    "moody",
+    "nervous",
+    "neutral",
+    "offended",
+    "outraged",
+    "playful",
+    "proud",
+    "relaxed",
+    "relieved",
+    "remorseful",
+    "restless",
+    "sad",
+    "sarcastic",
+    "satisfied",
+    "serious",
+    "shocked",
+    "shy",
+    "sick",
+    "sleepy",
+    "spontaneous",
+    "stressed",
+    "strong",
+    "surprised",
+    "thankful",
+    "thirsty",
+    "tired",
+    "undefined",
+    "weak",
+    "worried",
+];
+
+/// General activities defined by XEP-0108, with their specific ones
+const ACTIVITIES: &[(&str, &[&str])] = &[
+    (
+        "doing_chores",
+        &[
+            "buying_groceries",
+            "cleaning",
+            "cooking",
+            "doing_maintenance",
+            "doing_the_dishes",
+            "doing_the_laundry",
+            "gardening",
+            "running_an_errand",
+            "walking_the_dog",
+        ],
+    ),
+    (
+        "drinking",
+        &["having_a_beer", "having_coffee", "having_tea"],
+    ),
+    (
+        "eating",
+        &[
+            "having_a_snack",
+            "having_breakfast",
+            "having_dinner",
+            "having_lunch",
+        ],
+    ),
+    (
+        "exercising",
+        &[
+            "cycling",
+            "dancing",
+            "hiking",
+            "jogging",
+            "playing_sports",
+            "running",
+            "skiing",
+            "swimming",
+            "working_out",
+        ],
+    ),
+    (
+        "grooming",
+        &[
+            "at_the_spa",
+            "brushing_teeth",
+            "getting_a_haircut",
+            "shaving",
+            "taking_a_bath",
+            "taking_a_shower",
+        ],
+    ),
+    ("having_appointment", &[]),
+    (
+        "inactive",
+        &[
+            "day_off",
+            "hanging_out",
+            "hiding",
+            "on_vacation",
+            "praying",
+            "scheduled_holiday",
+            "sleeping",
+            "thinking",
+        ],
+    ),
+    (
+        "relaxing",
+        &[
+            "fishing",
+            "gaming",
+            "going_out",
+            "partying",
+            "reading",
+            "rehearsing",
+            "shopping",
+            "smoking",
+            "socializing",
+            "sunbathing",
+            "watching_tv",
+            "watching_a_movie",
+        ],
+    ),
+    (
+        "talking",
+        &["in_real_life", "on_the_phone", "on_video_phone"],
+    ),
+    (
+        "traveling",
+        &[
+            "commuting",
+            "cycling",
+            "driving",
+            "in_a_car",
+            "on_a_bus",
+            "on_a_plane",
+            "on_a_train",
+            "on_a_trip",
+            "walking",
+        ],
+    ),
+    ("undefined", &[]),
+    (
+        "working",
+        &["coding", "in_a_meeting", "studying", "writing"],
+    ),
+];
+
+command_def!(
+    mood,
+    r#"/mood <mood> [<text>]
+
+    mood          One of the moods of XEP-0107, or none to stop publishing it
+    text          Free text telling more
+
+Description:
+    Publish your mood (XEP-0107) on every connected account.
+
+Examples:
+    /mood happy
+    /mood tired "Long day"
+    /mood none"#,
+    {
+        mood: String = {
+            completion: (|_aparte, _command| {
+                let mut moods: Vec<String> = MOODS.iter().map(|mood| mood.to_string()).collect();
+                moods.push(String::from("none"));
+                moods
+            })
+        },
+        text: Option<String>,
+    },
+    |aparte, _command| {
+        let mood = match mood.as_str() {
+            "none" => None,
+            mood if MOODS.contains(&mood) => Some(Mood {
+                mood: mood.to_string(),
+                text,
+            }),
+            mood => return Err(format!("Unknown mood {}", mood)),
+        };
+        let payload = match &mood {
+            Some(mood) => mood.to_element(),
+            None => Element::builder("mood", ns::MOOD).build(),
+        };
+        for account in aparte.accounts() {
+            aparte.send(&account, publish(ns::MOOD, payload.clone()));
+        }
+        match mood {
+            Some(mood) => aparte.log(format!("Mood set to {}", mood)),
+            None => aparte.log("Mood isn't published anymore".to_string()),
+        }
+        Ok(())
+    }
+);
+
+command_def!(
+    activity,
+    r#"/activity <activity> [<text>]
+
+    activity      One of the general activities of XEP-0108, optionally followed
+                  by a specific one, or none to stop publishing it
+    text          Free text telling more
+
+Description:
+    Publish what you are doing (XEP-0108) on every connected account.
+
+Examples:
+    /activity working
+    /activity relaxing/reading "The Two Gentlemen of Verona"
+    /activity none"#,
+    {
+        activity: String = {
+            completion: (|_aparte, _command| {
+                let mut activities = Vec::new();
+                for (general, specifics) in ACTIVITIES.iter() {
+                    activities.push(general.to_string());
+                    for specific in specifics.iter() {
+                        activities.push(format!("{}/{}", general, specific));
+                    }
+                }
+                activities.push(String::from("none"));
+                activities
+            })
+        },
+        text: Option<String>,
+    },
+    |aparte, _command| {
+        let activity = match activity.as_str() {
+            "none" => None,
+            activity => Some(Activity::parse(activity, text)?),
+        };
+        let payload = match &activity {
+            Some(activity) => activity.to_element(),
+            None => Element::builder("activity", ACTIVITY).build(),
+        };
+        for account in aparte.accounts() {
+            aparte.send(&account, publish(ACTIVITY, payload.clone()));
+        }
+        match activity {
+            Some(activity) => aparte.log(format!("Activity set to {}", activity)),
+            None => aparte.log("Activity isn't published anymore".to_string()),
+        }
+        Ok(())
+    }
+);
+
+/// Publication of a mood or activity, an empty one stopping to publish it
+fn publish(node: &str, payload: Element) -> Element {
+    let publish = PubSub::Publish {
+        publish: Publish {
+            node: NodeName(String::from(node)),
+            items: vec![pubsub::Item(Item {
+                id: Some(ItemId(String::from("current"))),
+                payload: Some(payload),
+                publisher: None,
+            })],
+        },
+        publish_options: None,
+    };
+    Iq::from_set(Uuid::new_v4().to_hyphenated().to_string(), publish).into()
+}
+
+/// Text child of a mood or activity
+fn text(element: &Element, ns: &str) -> Option<String> {
+    element
+        .get_child("text", ns)
+        .map(|text| text.text())
+        .filter(|text| !text.is_empty())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mood {
+    pub mood: String,
+    pub text: Option<String>,
+}
+
+impl Mood {
+    /// None for an empty mood, published to stop publishing one
+    fn parse(element: &Element) -> Option<Self> {
+        let mood = element
+            .children()
+            .find(|child| child.ns() == ns::MOOD && child.name() != "text")?;
+        Some(Self {
+            mood: mood.name().to_string(),
+            text: text(element, ns::MOOD),
+        })
+    }
+
+    fn to_element(&self) -> Element {
+        let mut mood = Element::builder("mood", ns::MOOD)
+            .append(Element::builder(self.mood.as_str(), ns::MOOD).build());
+        if let Some(text) = &self.text {
+            mood = mood.append(Element::builder("text", ns::MOOD).append(text.as_str()));
+        }
+        mood.build()
+    }
+}
+
+impl fmt::Display for Mood {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mood.replace('_', " "))?;
+        if let Some(text) = &self.text {
+            write!(f, " ({})", text)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Activity {
+    pub general: String,
+    pub specific: Option<String>,
+    pub text: Option<String>,
+}
+
+impl Activity {
+    /// Activity given as general or general/specific
+    fn parse(activity: &str, text: Option<String>) -> Result<Self, String> {
+        let mut parts = activity.splitn(2, '/');
+        let general = parts.next().unwrap_or("");
+        let specific = parts.next();
+        let specifics = ACTIVITIES
+            .iter()
+            .find(|(name, _)| *name == general)
+            .map(|(_, specifics)| specifics)
+            .ok_or(format!("Unknown activity {}", general))?;
+        match specific {
+            Some(specific) if specific != "other" && !specifics.contains(&specific) => {
+                Err(format!("Unknown activity {}", activity))
+            }
+            _ => Ok(Self {
+                general: general.to_string(),
+                specific: specific.map(str::to_string),
+                text,
+            }),
+        }
+    }
+
+    /// None for an empty activity, published to stop publishing one
+    fn from_element(element: &Element) -> Option<Self> {
+        let general = element
+            .children()
+            .find(|child| child.ns() == ACTIVITY && child.name() != "text")?;
+        Some(Self {
+            general: general.name().to_string(),
+            specific: general
+                .children()
+                .find(|child| child.ns() == ACTIVITY)
+                .map(|specific| specific.name().to_string()),
+            text: text(element, ACTIVITY),
+        })
+    }
+
+    fn to_element(&self) -> Element {
+        let mut general = Element::builder(self.general.as_str(), ACTIVITY);
+        if let Some(specific) = &self.specific {
+            general = general.append(Element::builder(specific.as_str(), ACTIVITY).build());
+        }
+        let mut activity = Element::builder("activity", ACTIVITY).append(general.build());
+        if let Some(text) = &self.text {
+            activity = activity.append(Element::builder("text", ACTIVITY).append(text.as_str()));
+        }
+        activity.build()
+    }
+}
+
+impl fmt::Display for Activity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.specific {
+            Some(specific) => write!(f, "{}", specific.replace('_', " "))?,
+            None => write!(f, "{}", self.general.replace('_', " "))?,
+        }
+        if let Some(text) = &self.text {
+            write!(f, " ({})", text)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct MoodMod {}
+
+impl MoodMod {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    /// Last item of a mood or activity notification
+    fn get_item(message: &XmppParsersMessage) -> Option<Element> {
+        message
+            .payloads
+            .iter()
+            .filter(|payload| payload.is("event", ns::PUBSUB_EVENT))
+            .find_map(|payload| match PubSubEvent::try_from(payload.clone()) {
+                Ok(PubSubEvent::PublishedItems { node, items })
+                    if node.0 == ns::MOOD || node.0 == ACTIVITY =>
+                {
+                    items.into_iter().rev().find_map(|item| item.0.payload)
+                }
+                _ => None,
+            })
+            .filter(|item| item.is("mood", ns::MOOD) || item.is("activity", ACTIVITY))
+    }
+}
+
+impl ModTrait for MoodMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(mood::new());
+        aparte.add_command(activity::new());
+        let mut disco = aparte.get_mod_mut::<mods::disco::DiscoMod>();
+        disco.add_feature(&format!("{}+notify", ns::MOOD))?;
+        disco.add_feature(&format!("{}+notify", ACTIVITY))
+    }
+
+    fn can_handle_xmpp_message(
+        &mut self,
+        _aparte: &mut Aparte,
+        _account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+    ) -> f64 {
+        match Self::get_item(message) {
+            Some(_) => 1f64,
+            None => 0f64,
+        }
+    }
+
+    fn handle_xmpp_message(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+    ) {
+        let contact = match &message.from {
+            Some(from) => BareJid::from(from.clone()),
+            None => BareJid::from(Jid::Full(account.clone())),
+        };
+        match Self::get_item(message) {
+            Some(item) if item.is("mood", ns::MOOD) => aparte.schedule(Event::Mood {
+                contact,
+                mood: Mood::parse(&item),
+            }),
+            Some(item) => aparte.schedule(Event::Activity {
+                contact,
+                activity: Activity::from_element(&item),
+            }),
+            None => {}
+        }
+    }
+
+    fn on_event(&mut self, _aparte: &mut Aparte, _event: &Event) {}
+}
+
+impl fmt::Display for MoodMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0107, XEP-0108: User Mood and User Activity")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+
+    #[test]
+    fn test_activity_parse() {
+        assert_eq!(
+            Activity::parse("relaxing/reading", None),
+            Ok(Activity {
+                general: String::from("relaxing"),
+                specific: Some(String::from("reading")),
+                text: None,
+            })
+        );
+        assert!(Activity::parse("working/other", None).is_ok());
+        assert!(Activity::parse("working/reading", None).is_err());
+        assert!(Activity::parse("sleeping", None).is_err());
+    }
+
+    #[test]
+    fn test_mood_is_published_on_connected_accounts() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+
+            // When
+            harness.input("console", "/mood happy \"Sunny day\"").await;
+            harness.input("console", "/mood grumpyish").await;
+
+            // Then
+            let published = harness.take_sent("pubsub", ns::PUBSUB);
+            assert_eq!(published.len(), 1);
+            assert!(String::from(&published[0]).contains(
+                "<mood xmlns=\"http://jabber.org/protocol/mood\"><happy/><text>Sunny day</text></mood>"
+            ));
+            assert!(harness.screen().contains("Unknown mood grumpyish"));
+        });
+    }
+
+    #[test]
+    fn test_moods_and_activities_of_contacts_are_notified() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+
+            // When
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='headline'
+                        from='juliet@example.org' to='{account}'>
+                        <event xmlns='http://jabber.org/protocol/pubsub#event'>
+                            <items node='http://jabber.org/protocol/activity'>
+                                <item id='current'>
+                                    <activity xmlns='http://jabber.org/protocol/activity'>
+                                        <relaxing><reading/></relaxing>
+                                    </activity>
+                                </item>
+                            </items>
+                        </event>
+                    </message>",
+                )
+                .await;
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='headline'
+                        from='juliet@example.org' to='{account}'>
+                        <event xmlns='http://jabber.org/protocol/pubsub#event'>
+                            <items node='http://jabber.org/protocol/mood'>
+                                <item id='current'>
+                                    <mood xmlns='http://jabber.org/protocol/mood'>
+                                        <in_love/>
+                                    </mood>
+                                </item>
+                            </items>
+                        </event>
+                    </message>",
+                )
+                .await;
+
+            // Then
+            let juliet = BareJid::from_str("juliet@example.org").unwrap();
+            assert_eq!(
+                mods::ui::mood_suffix(&juliet),
+                Some(String::from("in love, reading"))
+            );
+        });
+    }
+}
@@ -0,0 +1,310 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::presence::Type as PresenceType;
+use xmpp_parsers::pubsub::{pubsub::Items, NodeName, PubSub};
+use xmpp_parsers::{BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::{Direction, Message};
+
+const MOVED: &str = "urn:xmpp:moved:1";
+
+command_def!(merge,
+r#"/merge <old> <new>
+
+    old           Previous address of the contact
+    new           New address of the contact
+
+Description:
+    Merge the conversation with a contact who moved to a new address. History
+    and privacy settings of the old address follow the contact.
+
+Examples:
+    /merge friend@old.tld friend@new.tld"#,
+{
+    old: BareJid,
+    new: BareJid
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    if old == new {
+        return Err(format!("Cannot merge {} with itself", old));
+    }
+
+    {
+        let mut moved = aparte.get_mod_mut::<MovedMod>();
+        moved.alias(&account, old.clone(), new.clone());
+    }
+    aparte.schedule(Event::Merge { account, old, new });
+    Ok(())
+});
+
+pub struct MovedMod {
+    /// Previous addresses of contacts and where they moved, indexed by bare jid of the account
+    /// and saved between runs
+    aliases: HashMap<String, HashMap<BareJid, BareJid>>,
+    /// File where aliases are saved
+    state: Option<PathBuf>,
+    /// Pending verifications of moves announced by new addresses, by iq id
+    verifications: HashMap<String, (BareJid, BareJid)>,
+}
+
+impl MovedMod {
+    pub fn new() -> Self {
+        Self {
+            aliases: HashMap::new(),
+            state: dirs::data_dir().map(|dir| dir.join("aparte").join("moved.toml")),
+            verifications: HashMap::new(),
+        }
+    }
+
+    fn load(&mut self) -> Result<(), String> {
+        let path = match &self.state {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let state = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let aliases: HashMap<String, HashMap<String, String>> =
+            toml::from_str(&state).map_err(|err| err.to_string())?;
+        for (account, moves) in aliases {
+            let mut parsed = HashMap::new();
+            for (old, new) in moves {
+                let old = BareJid::from_str(&old).map_err(|err| format!("{}: {}", old, err))?;
+                let new = BareJid::from_str(&new).map_err(|err| format!("{}: {}", new, err))?;
+                parsed.insert(old, new);
+            }
+            self.aliases.insert(account, parsed);
+        }
+        Ok(())
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.state {
+            let aliases = self
+                .aliases
+                .iter()
+                .map(|(account, moves)| {
+                    let moves = moves
+                        .iter()
+                        .map(|(old, new)| (old.to_string(), new.to_string()))
+                        .collect::<HashMap<String, String>>();
+                    (account.clone(), moves)
+                })
+                .collect::<HashMap<String, HashMap<String, String>>>();
+            let result = toml::to_string(&aliases)
+                .map_err(|err| err.to_string())
+                .and_then(|state| fs::write(path, state).map_err(|err| err.to_string()));
+            if let Err(err) = result {
+                warn!("Cannot save moved contacts: {}", err);
+            }
+        }
+    }
+
+    fn alias(&mut self, account: &Account, old: BareJid, new: BareJid) {
+        let account = BareJid::from(Jid::Full(account.clone())).to_string();
+        let aliases = self.aliases.entry(account).or_default();
+        // Contacts that previously moved to the old address now live at the new one
+        for target in aliases.values_mut() {
+            if *target == old {
+                *target = new.clone();
+            }
+        }
+        aliases.remove(&new);
+        aliases.insert(old, new);
+        self.save();
+    }
+
+    /// Current address of a contact
+    pub fn resolve(&self, account: &Account, jid: &BareJid) -> BareJid {
+        let account = BareJid::from(Jid::Full(account.clone())).to_string();
+        match self
+            .aliases
+            .get(&account)
+            .and_then(|aliases| aliases.get(jid))
+        {
+            Some(new) => new.clone(),
+            None => jid.clone(),
+        }
+    }
+
+    /// Rewrite a message exchanged with a previous address of a contact as if exchanged with
+    /// its current one
+    pub fn follow(&self, account: &Option<Account>, message: &Message) -> Message {
+        let account = match account {
+            Some(account) => account,
+            None => return message.clone(),
+        };
+
+        match message {
+            Message::Xmpp(xmpp) => {
+                let mut xmpp = xmpp.clone();
+                match xmpp.direction {
+                    Direction::Incoming => xmpp.from = self.resolve(account, &xmpp.from),
+                    Direction::Outgoing => xmpp.to = self.resolve(account, &xmpp.to),
+                }
+                Message::Xmpp(xmpp)
+            }
+            Message::Log(_) => message.clone(),
+        }
+    }
+
+    fn verify(&mut self, old: BareJid, new: BareJid) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let items = Items {
+            max_items: Some(1),
+            node: NodeName(String::from(MOVED)),
+            subid: None,
+            items: vec![],
+        };
+        let iq = Iq::from_get(id.clone(), PubSub::Items(items)).with_to(Jid::Bare(old.clone()));
+        self.verifications.insert(id, (old, new));
+        iq.into()
+    }
+
+    fn handle_verification(&mut self, aparte: &mut Aparte, account: &Account, iq: &Iq) {
+        let (old, new) = match self.verifications.remove(&iq.id) {
+            Some(verification) => verification,
+            None => return,
+        };
+
+        // The old account must confirm the move by publishing its new address
+        let confirmed = match &iq.payload {
+            IqType::Result(Some(el)) => match PubSub::try_from(el.clone()) {
+                Ok(PubSub::Items(items)) => items
+                    .items
+                    .iter()
+                    .filter_map(|item| item.0.payload.as_ref())
+                    .filter(|payload| payload.is("moved", MOVED))
+                    .filter_map(|payload| payload.get_child("new-jid", MOVED))
+                    .any(|new_jid| BareJid::from_str(&new_jid.text()).as_ref() == Ok(&new)),
+                _ => false,
+            },
+            _ => false,
+        };
+
+        if confirmed {
+            self.alias(account, old.clone(), new.clone());
+            aparte.schedule(Event::Merge {
+                account: account.clone(),
+                old,
+                new,
+            });
+        } else {
+            aparte.log(format!(
+                "{} claims to be the new address of {} but this could not be verified, use /merge {} {} if you trust it",
+                new, old, old, new
+            ));
+        }
+    }
+}
+
+impl ModTrait for MovedMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(merge::new());
+        if let Err(err) = self.load() {
+            aparte.log(format!("Cannot load moved contacts: {}", err));
+        }
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Presence(account, presence) if presence.type_ == PresenceType::Subscribe => {
+                let new = match &presence.from {
+                    Some(from) => BareJid::from(from.clone()),
+                    None => return,
+                };
+                let old = presence
+                    .payloads
+                    .iter()
+                    .filter(|payload| payload.is("moved", MOVED))
+                    .filter_map(|payload| payload.get_child("old-jid", MOVED))
+                    .find_map(|old_jid| BareJid::from_str(&old_jid.text()).ok());
+
+                if let Some(old) = old {
+                    if old != new {
+                        aparte.log(format!("{} announces moving to {}", old, new));
+                        let verification = self.verify(old, new);
+                        aparte.send(account, verification);
+                    }
+                }
+            }
+            Event::Iq(account, iq) => self.handle_verification(aparte, account, iq),
+            Event::Merge { old, new, .. } => {
+                // Privacy settings follow the contact
+                let privacy = &mut aparte.config.privacy;
+                if let Some(settings) = privacy.contacts.get(&old.to_string()).cloned() {
+                    privacy.contacts.entry(new.to_string()).or_insert(settings);
+                }
+                aparte.log(format!("Merged conversation with {} into {}", old, new));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for MovedMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0283: Moved")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alias_follows_successive_moves() {
+        // Given
+        let account = Account::from_str("me@server.tld/aparte").unwrap();
+        let first = BareJid::from_str("friend@first.tld").unwrap();
+        let second = BareJid::from_str("friend@second.tld").unwrap();
+        let third = BareJid::from_str("friend@third.tld").unwrap();
+        let mut moved = MovedMod::new();
+        moved.state = None;
+
+        // When
+        moved.alias(&account, first.clone(), second.clone());
+        moved.alias(&account, second.clone(), third.clone());
+
+        // Then
+        assert_eq!(moved.resolve(&account, &first), third);
+        assert_eq!(moved.resolve(&account, &second), third);
+        assert_eq!(moved.resolve(&account, &third), third);
+    }
+
+    #[test]
+    fn test_aliases_are_saved_between_runs() {
+        // Given
+        let account = Account::from_str("me@server.tld/aparte").unwrap();
+        let old = BareJid::from_str("friend@old.tld").unwrap();
+        let new = BareJid::from_str("friend@new.tld").unwrap();
+        let state = std::env::temp_dir().join(format!("aparte-moved-{}.toml", Uuid::new_v4()));
+        let mut moved = MovedMod::new();
+        moved.state = Some(state.clone());
+        moved.alias(&account, old.clone(), new.clone());
+
+        // When
+        let mut restarted = MovedMod::new();
+        restarted.state = Some(state.clone());
+        let loaded = restarted.load();
+        // A new resource of the same account
+        let other = Account::from_str("me@server.tld/phone").unwrap();
+
+        // Then
+        assert_eq!(loaded, Ok(()));
+        assert_eq!(restarted.resolve(&other, &old), new);
+        fs::remove_file(state).unwrap();
+    }
+}
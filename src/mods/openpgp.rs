@@ -0,0 +1,1417 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! OpenPGP for XMPP (XEP-0373) and its instant messaging profile (XEP-0374).
+//!
+//! Cryptography is left to gpg, keys never leave its keyring and passphrases are asked by
+//! gpg-agent's pinentry. Keys announced by contacts are imported in the keyring and messages are
+//! encrypted to the fingerprints they announced, along with our own key so that carbons and
+//! archives stay readable.
+use chrono::Local as LocalTz;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Write;
+use std::process;
+use std::str::FromStr;
+use std::thread;
+use uuid::Uuid;
+use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
+use xmpp_parsers::delay::Delay;
+use xmpp_parsers::eme::ExplicitMessageEncryption;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::message::{Body, Message as XmppParsersMessage};
+use xmpp_parsers::openpgp::{PubKey, PubKeyData};
+use xmpp_parsers::pubsub::event::PubSubEvent;
+use xmpp_parsers::pubsub::pubsub::{self, Items, Publish, PublishOptions};
+use xmpp_parsers::pubsub::{Item, ItemId, NodeName, PubSub};
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::{Direction, Message, VersionedXmppMessage, XmppMessageType};
+use crate::mods;
+
+const NOTIFY: &str = "urn:xmpp:openpgp:0:public-keys+notify";
+const HINTS: &str = "urn:xmpp:hints";
+/// Sent in clear by /openpgp heal, as no protocol exists to ask for messages to be sent again
+const RESEND_REQUEST: &str = "I couldn't decrypt your last OpenPGP encrypted messages. I fetched \
+your keys again and published mine, could you send them again?";
+
+/// Body shown by clients not supporting OpenPGP
+const FALLBACK: &str =
+    "This message is encrypted with OpenPGP for XMPP (XEP-0374), your client can't display it.";
+
+command_def!(openpgp,
+r#"/openpgp on|off|publish|keys|heal"#,
+{
+    action: Command = {
+        children: {
+            "on": openpgp_on,
+            "off": openpgp_off,
+            "publish": openpgp_publish,
+            "keys": openpgp_keys,
+            "heal": openpgp_heal,
+        }
+    },
+});
+
+command_def!(openpgp_on,
+r#"/openpgp on [<jid>]
+
+    jid           Contact to encrypt messages to, the current one if omitted
+
+Description:
+    Encrypt and sign the messages sent to a contact with OpenPGP (XEP-0373).
+    Their keys are fetched from what they published and imported in the gpg
+    keyring, messages aren't sent as long as none is known.
+
+Examples:
+    /openpgp on
+    /openpgp on juliet@example.org"#,
+{
+    jid: Option<BareJid>
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let contact = match jid {
+        Some(jid) => jid,
+        None => BareJid::from_str(&_command.context)
+            .map_err(|_| "Missing jid argument".to_string())?,
+    };
+    let conversation = {
+        let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+        conversations.get(&account, &contact).cloned()
+    };
+    if let Some(crate::conversation::Conversation::Channel(_)) = conversation {
+        return Err("OpenPGP isn't supported in rooms".to_string());
+    }
+
+    // Messages aren't sent in clear while the key is looked up, they wait for it
+    {
+        let mut openpgp = aparte.get_mod_mut::<OpenPgpMod>();
+        openpgp.enabled.insert((account.clone(), contact.clone()));
+    }
+    aparte.send(&account, OpenPgpMod::request(&contact, ns::OX_PUBKEYS));
+    look_up_own_key(aparte, &account, KeyUse::Encrypt(contact));
+    Ok(())
+});
+
+command_def!(openpgp_off,
+r#"/openpgp off [<jid>]
+
+    jid           Contact to stop encrypting messages to, the current one if
+                  omitted
+
+Description:
+    Send messages to a contact in clear again.
+
+Examples:
+    /openpgp off"#,
+{
+    jid: Option<BareJid>
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let contact = match jid {
+        Some(jid) => jid,
+        None => BareJid::from_str(&_command.context)
+            .map_err(|_| "Missing jid argument".to_string())?,
+    };
+    let removed = {
+        let mut openpgp = aparte.get_mod_mut::<OpenPgpMod>();
+        openpgp.enabled.remove(&(account, contact.clone()))
+    };
+    match removed {
+        true => aparte.log(format!("Messages to {} are now sent in clear", contact)),
+        false => return Err(format!("Messages to {} aren't encrypted", contact)),
+    }
+    Ok(())
+});
+
+command_def!(
+    openpgp_publish,
+    r#"/openpgp publish
+
+Description:
+    Publish the public key of the current account so that contacts can
+    encrypt messages to it. The key is the one configured with openpgp_key,
+    or the secret key of the gpg keyring with the xmpp:<jid> user id."#,
+    {},
+    |aparte, _command| {
+        let account = aparte
+            .current_account()
+            .ok_or("No connection found".to_string())?;
+        look_up_own_key(aparte, &account, KeyUse::Publish);
+        Ok(())
+    }
+);
+
+command_def!(openpgp_keys,
+r#"/openpgp keys [<jid>]
+
+    jid           Contact whose keys are listed, the current one if omitted
+
+Description:
+    List the fingerprints of the OpenPGP keys announced by a contact.
+
+Examples:
+    /openpgp keys juliet@example.org"#,
+{
+    jid: Option<BareJid>
+},
+|aparte, _command| {
+    let contact = match jid {
+        Some(jid) => jid,
+        None => BareJid::from_str(&_command.context)
+            .map_err(|_| "Missing jid argument".to_string())?,
+    };
+    let keys = {
+        let openpgp = aparte.get_mod::<OpenPgpMod>();
+        openpgp.keys.get(&contact).cloned().unwrap_or_default()
+    };
+    match keys.is_empty() {
+        true => aparte.log(format!("No OpenPGP key known for {}", contact)),
+        false => aparte.log(format!(
+            "OpenPGP keys of {}:\n{}",
+            contact,
+            keys.iter()
+                .map(|key| format!("  {}", key))
+                .collect::<Vec<String>>()
+                .join("\n")
+        )),
+    }
+    Ok(())
+});
+
+command_def!(openpgp_heal,
+r#"/openpgp heal [<jid>]
+
+    jid           Contact whose messages can't be decrypted, the current one if
+                  omitted
+
+Description:
+    Fetch again the keys announced by a contact and publish the key of the
+    current account again, so that the next messages exchanged with them can be
+    decrypted. The contact is then asked, with a message in clear, to send again
+    the messages that couldn't be decrypted.
+
+Examples:
+    /openpgp heal
+    /openpgp heal juliet@example.org"#,
+{
+    jid: Option<BareJid>
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let contact = match jid {
+        Some(jid) => jid,
+        None => BareJid::from_str(&_command.context)
+            .map_err(|_| "Missing jid argument".to_string())?,
+    };
+    // Keys announced again are imported even if they were already known
+    let request = {
+        let mut openpgp = aparte.get_mod_mut::<OpenPgpMod>();
+        openpgp.keys.remove(&contact);
+        OpenPgpMod::request(&contact, ns::OX_PUBKEYS)
+    };
+    aparte.send(&account, request);
+    look_up_own_key(aparte, &account, KeyUse::Publish);
+    aparte.log(format!("Fetching the keys of {} again", contact));
+
+    // Sent in clear, the keys it could be encrypted to are the ones being fetched again
+    let mut bodies = HashMap::new();
+    bodies.insert(String::new(), String::from(RESEND_REQUEST));
+    let from: Jid = account.clone().into();
+    let message = Message::outgoing_chat(
+        Uuid::new_v4().to_string(),
+        LocalTz::now().into(),
+        &from,
+        &Jid::Bare(contact),
+        &bodies,
+    );
+    if let Ok(mut element) = Element::try_from(message.clone()) {
+        element.append_child(Element::builder("store", HINTS).build());
+        aparte.send(&account, element);
+    }
+    aparte.schedule(Event::Message(Some(account), message));
+    Ok(())
+});
+
+/// What the key of an account is looked up for, Event::OwnKey is scheduled once found
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyUse {
+    /// Signing the messages encrypted to a contact
+    Encrypt(BareJid),
+    /// Publishing its public key
+    Publish,
+}
+
+/// Look up the key of an account out of the event loop, unless it is already known
+fn look_up_own_key(aparte: &mut Aparte, account: &Account, purpose: KeyUse) {
+    let known = {
+        let openpgp = aparte.get_mod::<OpenPgpMod>();
+        openpgp.own.get(&account.clone().into()).cloned()
+    };
+    let user_id = user_id(&aparte.config, account);
+    let account = account.clone();
+    aparte.spawn(async move {
+        let result = match known {
+            Some(fingerprint) => Ok(fingerprint),
+            None => own_key(user_id).await,
+        };
+        Event::OwnKey {
+            account,
+            purpose,
+            result,
+        }
+    });
+}
+
+/// Outcome of the signature check of a decrypted message
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signature {
+    /// Good signature of the key with the given primary fingerprint
+    Valid(String),
+    /// Bad signature, or made with an expired or revoked key
+    Invalid,
+    Missing,
+}
+
+/// How an outgoing message is protected, or why a decrypted one can't be trusted
+#[derive(Debug, Clone, PartialEq)]
+pub enum Protection {
+    /// Being encrypted
+    Pending,
+    /// Encrypted and signed, for the given number of keys of the recipient
+    Encrypted(usize),
+    /// Sent in clear by an account signing its messages
+    Plaintext,
+    /// Couldn't be encrypted, so it wasn't sent
+    Failed,
+    /// Received encrypted but not signed, anyone could have sent it
+    Unsigned,
+    /// Received signed with a key the sender didn't announce
+    UnknownKey,
+}
+
+/// Fingerprint of the key configured for an account
+fn configured_key(config: &crate::config::Config, account: &Account) -> Option<String> {
+    let jid: BareJid = account.clone().into();
+    config
+        .accounts
+        .values()
+        .filter(|info| Jid::from_str(&info.jid).map(BareJid::from).as_ref() == Ok(&jid))
+        .find_map(|info| info.openpgp_key.clone())
+}
+
+/// User id of the key of an account, either its configured fingerprint or its xmpp: uri
+fn user_id(config: &crate::config::Config, account: &Account) -> String {
+    let jid: BareJid = account.clone().into();
+    configured_key(config, account).unwrap_or_else(|| format!("=xmpp:{}", jid))
+}
+
+/// Run gpg with some input, returning its output and its status lines
+fn gpg(args: &[String], input: Vec<u8>) -> Result<(Vec<u8>, String), String> {
+    let mut child = process::Command::new("gpg")
+        .arg("--batch")
+        .arg("--status-fd")
+        .arg("2")
+        .args(args)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Cannot run gpg: {}", e))?;
+
+    // gpg can fill its output before reading all its input
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Cannot run gpg: {}", e))?;
+    let _ = writer.join();
+
+    let status = String::from_utf8_lossy(&output.stderr).to_string();
+    match output.status.success() {
+        true => Ok((output.stdout, status)),
+        false => Err(status
+            .lines()
+            .rev()
+            .find(|line| !line.starts_with("[GNUPG:]"))
+            .map(|line| line.trim_start_matches("gpg: ").to_string())
+            .unwrap_or_else(|| format!("gpg failed with {}", output.status))),
+    }
+}
+
+/// Run gpg out of the event loop, it may wait for a passphrase
+async fn gpg_async(args: Vec<String>, input: Vec<u8>) -> Result<(Vec<u8>, String), String> {
+    tokio::task::spawn_blocking(move || gpg(&args, input))
+        .await
+        .map_err(|e| format!("Cannot run gpg: {}", e))?
+}
+
+/// Fingerprint of the secret key with the given user id in the gpg keyring
+async fn own_key(user_id: String) -> Result<String, String> {
+    let args = vec![
+        String::from("--with-colons"),
+        String::from("--list-secret-keys"),
+        user_id.clone(),
+    ];
+    let (listing, _) = gpg_async(args, Vec::new())
+        .await
+        .map_err(|_| format!("No OpenPGP secret key found for {}", user_id))?;
+    secret_key(&String::from_utf8_lossy(&listing)).ok_or(format!(
+        "No usable OpenPGP secret key found for {}",
+        user_id
+    ))
+}
+
+/// Fingerprint of the first usable secret key of a `--with-colons` listing
+fn secret_key(listing: &str) -> Option<String> {
+    let mut primary = false;
+    for line in listing.lines() {
+        let fields = line.split(':').collect::<Vec<&str>>();
+        match fields.as_slice() {
+            // Revoked, expired and disabled keys can't sign
+            ["sec", validity, ..] => primary = !["r", "e", "d"].contains(validity),
+            ["fpr", .., fingerprint, _] if primary => return Some(fingerprint.to_string()),
+            _ => primary = false,
+        }
+    }
+    None
+}
+
+/// Fingerprints of the keys imported according to gpg status lines
+fn imported(status: &str) -> Vec<String> {
+    status
+        .lines()
+        .filter_map(|line| line.strip_prefix("[GNUPG:] IMPORT_OK "))
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(String::from)
+        .collect()
+}
+
+/// Signature check of a decrypted message according to gpg status lines
+fn signature(status: &str) -> Signature {
+    let mut signature = Signature::Missing;
+    for line in status.lines() {
+        let fields = match line.strip_prefix("[GNUPG:] ") {
+            Some(line) => line.split_whitespace().collect::<Vec<&str>>(),
+            None => continue,
+        };
+        match fields.as_slice() {
+            ["BADSIG", ..] | ["EXPKEYSIG", ..] | ["REVKEYSIG", ..] | ["ERRSIG", ..] => {
+                return Signature::Invalid
+            }
+            // The primary key fingerprint comes last when signed with a subkey
+            ["VALIDSIG", fingerprint, rest @ ..] => {
+                let primary = rest.get(8).unwrap_or(fingerprint);
+                signature = Signature::Valid(primary.to_string());
+            }
+            _ => {}
+        }
+    }
+    signature
+}
+
+/// Fingerprints announced in a public keys metadata item
+fn fingerprints(metadata: &Element) -> Vec<String> {
+    metadata
+        .children()
+        .filter(|child| child.is("pubkey-metadata", ns::OX))
+        .filter_map(|child| child.attr("v4-fingerprint"))
+        .map(|fingerprint| fingerprint.to_uppercase())
+        .collect()
+}
+
+/// Placeholder shown in the conversation in place of a message that couldn't be decrypted, with
+/// the id and timestamp of the original one so that archived messages stay in order
+fn undecryptable(
+    account: Account,
+    message: &XmppParsersMessage,
+    delay: &Option<Delay>,
+    err: String,
+) -> Event {
+    let from = message
+        .from
+        .as_ref()
+        .map(|from| from.to_string())
+        .unwrap_or_default();
+    match Message::from_xmpp(&account, message, delay) {
+        Ok(Message::Xmpp(mut placeholder)) => {
+            placeholder.redact(format!(
+                "🔒 Cannot decrypt this message: {}. /openpgp heal {} exchanges keys again and asks for it",
+                err,
+                placeholder.conversation()
+            ));
+            Event::Message(Some(account), Message::Xmpp(placeholder))
+        }
+        _ => Event::Message(
+            None,
+            Message::log(format!("Cannot decrypt message from {}: {}", from, err)),
+        ),
+    }
+}
+
+/// Element to encrypt, holding the bodies and payloads of a message along with its recipient
+fn signcrypt(to: &BareJid, message: &XmppParsersMessage, rpad: &str) -> Element {
+    let mut payload = Element::builder("payload", ns::OX);
+    for (lang, body) in &message.bodies {
+        let mut element = Element::builder("body", ns::DEFAULT_NS).append(body.0.as_str());
+        if !lang.is_empty() {
+            element = element.attr("xml:lang", lang.as_str());
+        }
+        payload = payload.append(element.build());
+    }
+    for element in message
+        .payloads
+        .iter()
+        .filter(|p| !p.is("origin-id", ns::SID))
+    {
+        payload = payload.append(element.clone());
+    }
+
+    Element::builder("signcrypt", ns::OX)
+        .append(
+            Element::builder("to", ns::OX)
+                .attr("jid", to.to_string())
+                .build(),
+        )
+        .append(
+            Element::builder("time", ns::OX)
+                .attr("stamp", LocalTz::now().to_rfc3339())
+                .build(),
+        )
+        .append(Element::builder("rpad", ns::OX).append(rpad).build())
+        .append(payload.build())
+        .build()
+}
+
+/// Message as if it had been sent in clear, once checked it was really meant for its recipient
+fn open(message: &XmppParsersMessage, signcrypt: &Element) -> Result<XmppParsersMessage, String> {
+    if !signcrypt.is("signcrypt", ns::OX) {
+        return Err(format!("Unexpected {} element", signcrypt.name()));
+    }
+
+    // A message encrypted to someone else could be replayed to us
+    if let Some(to) = &message.to {
+        let to = BareJid::from(to.clone()).to_string();
+        if !signcrypt
+            .children()
+            .filter(|child| child.is("to", ns::OX))
+            .any(|child| child.attr("jid") == Some(to.as_str()))
+        {
+            return Err(format!("Message wasn't encrypted for {}", to));
+        }
+    }
+
+    let mut opened = message.clone();
+    opened.bodies.clear();
+    opened.payloads.retain(|payload| {
+        !payload.is("openpgp", ns::OX)
+            && !payload.is("encryption", ns::EME)
+            && !payload.is("store", HINTS)
+    });
+    let payload = signcrypt
+        .get_child("payload", ns::OX)
+        .ok_or("Missing payload".to_string())?;
+    for element in payload.children() {
+        if element.is("body", ns::DEFAULT_NS) {
+            let lang = element.attr("xml:lang").unwrap_or("").to_string();
+            opened.bodies.insert(lang, Body(element.text()));
+        } else {
+            opened.payloads.push(element.clone());
+        }
+    }
+    Ok(opened)
+}
+
+pub struct OpenPgpMod {
+    /// Conversations in which messages are encrypted
+    enabled: HashSet<(Account, BareJid)>,
+    /// Fingerprints announced by contacts and imported in the keyring
+    keys: HashMap<BareJid, Vec<String>>,
+    /// Fingerprint of the key of each account
+    own: HashMap<BareJid, String>,
+}
+
+impl OpenPgpMod {
+    pub fn new() -> Self {
+        Self {
+            enabled: HashSet::new(),
+            keys: HashMap::new(),
+            own: HashMap::new(),
+        }
+    }
+
+    /// Whether a message is to be encrypted before being sent
+    pub fn encrypts(&self, account: &Account, message: &Message) -> bool {
+        match message {
+            Message::Xmpp(message) => {
+                message.direction == Direction::Outgoing
+                    && message.type_ == XmppMessageType::Chat
+                    && self
+                        .enabled
+                        .contains(&(account.clone(), message.to.clone()))
+            }
+            Message::Log(_) => false,
+        }
+    }
+
+    /// Request for the items of a PEP node of a contact
+    fn request(contact: &BareJid, node: &str) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let items = Items {
+            max_items: None,
+            node: NodeName(String::from(node)),
+            subid: None,
+            items: vec![],
+        };
+        Iq::from_get(id, PubSub::Items(items))
+            .with_to(Jid::Bare(contact.clone()))
+            .into()
+    }
+
+    /// Requests publishing a public key and announcing it
+    fn publish(fingerprint: &str, key: Vec<u8>) -> Vec<Element> {
+        let date = LocalTz::now();
+        let pubkey = PubKey {
+            date: Some(xmpp_parsers::date::DateTime(date.into())),
+            data: PubKeyData { data: key },
+        };
+        let metadata = Element::builder("public-keys-list", ns::OX)
+            .append(
+                Element::builder("pubkey-metadata", ns::OX)
+                    .attr("v4-fingerprint", fingerprint)
+                    .attr("date", date.to_rfc3339())
+                    .build(),
+            )
+            .build();
+
+        let items = [
+            (
+                format!("{}:{}", ns::OX_PUBKEYS, fingerprint),
+                date.to_rfc3339(),
+                Element::from(pubkey),
+            ),
+            (
+                String::from(ns::OX_PUBKEYS),
+                String::from("current"),
+                metadata,
+            ),
+        ];
+        IntoIterator::into_iter(items)
+            .map(|(node, id, payload)| {
+                let publish = Publish {
+                    node: NodeName(node),
+                    items: vec![pubsub::Item(Item {
+                        id: Some(ItemId(id)),
+                        payload: Some(payload),
+                        publisher: None,
+                    })],
+                };
+                // Keys are meant to be fetched by anyone
+                let options = PublishOptions {
+                    form: Some(DataForm {
+                        type_: DataFormType::Submit,
+                        form_type: Some(String::from(
+                            "http://jabber.org/protocol/pubsub#publish-options",
+                        )),
+                        title: None,
+                        instructions: None,
+                        fields: vec![Field {
+                            var: String::from("pubsub#access_model"),
+                            type_: FieldType::TextSingle,
+                            label: None,
+                            required: false,
+                            media: vec![],
+                            options: vec![],
+                            values: vec![String::from("open")],
+                        }],
+                    }),
+                };
+                let pubsub = PubSub::Publish {
+                    publish,
+                    publish_options: Some(options),
+                };
+                let id = Uuid::new_v4().to_hyphenated().to_string();
+                Iq::from_set(id, pubsub).into()
+            })
+            .collect()
+    }
+
+    /// Fetch the keys newly announced by a contact
+    fn handle_metadata(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        contact: BareJid,
+        metadata: &Element,
+    ) {
+        let announced = fingerprints(metadata);
+        let known = self.keys.entry(contact.clone()).or_default();
+        known.retain(|fingerprint| announced.contains(fingerprint));
+        for fingerprint in announced {
+            if !known.contains(&fingerprint) {
+                let node = format!("{}:{}", ns::OX_PUBKEYS, fingerprint);
+                aparte.send(account, Self::request(&contact, &node));
+            }
+        }
+    }
+
+    fn handle_pubkey(&mut self, aparte: &mut Aparte, contact: BareJid, node: &str, key: &Element) {
+        let fingerprint = node[ns::OX_PUBKEYS.len() + 1..].to_uppercase();
+        let key = match PubKey::try_from(key.clone()) {
+            Ok(key) => key,
+            Err(_) => {
+                aparte.log(format!(
+                    "Invalid OpenPGP key {} of {}",
+                    fingerprint, contact
+                ));
+                return;
+            }
+        };
+        let import = gpg_async(vec![String::from("--import")], key.data.data);
+        aparte.spawn(async move {
+            Event::KeyImported {
+                contact,
+                fingerprint,
+                result: import.await.map(|(_, status)| status),
+            }
+        });
+    }
+
+    fn key_imported(
+        &mut self,
+        aparte: &mut Aparte,
+        contact: &BareJid,
+        fingerprint: &str,
+        result: &Result<String, String>,
+    ) {
+        match result {
+            Ok(status) if imported(status).iter().any(|key| key == fingerprint) => {
+                let known = self.keys.entry(contact.clone()).or_default();
+                if !known.iter().any(|key| key == fingerprint) {
+                    known.push(fingerprint.to_string());
+                    aparte.log(format!(
+                        "OpenPGP key {} of {} imported",
+                        fingerprint, contact
+                    ));
+                }
+            }
+            Ok(_) => aparte.log(format!(
+                "OpenPGP key published by {} isn't {}",
+                contact, fingerprint
+            )),
+            Err(err) => aparte.log(format!(
+                "Cannot import OpenPGP key {} of {}: {}",
+                fingerprint, contact, err
+            )),
+        }
+    }
+
+    fn own_key_found(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        purpose: &KeyUse,
+        result: &Result<String, String>,
+    ) {
+        let fingerprint = match (result, purpose) {
+            (Ok(fingerprint), _) => fingerprint.clone(),
+            (Err(err), KeyUse::Encrypt(contact)) => {
+                self.enabled.remove(&(account.clone(), contact.clone()));
+                aparte.log(format!("Messages to {} aren't encrypted: {}", contact, err));
+                return;
+            }
+            (Err(err), KeyUse::Publish) => {
+                aparte.log(format!("Public key not published: {}", err));
+                return;
+            }
+        };
+        self.own.insert(account.clone().into(), fingerprint.clone());
+
+        match purpose {
+            KeyUse::Encrypt(contact) => aparte.log(format!(
+                "Messages to {} are now encrypted, and signed with {}",
+                contact, fingerprint
+            )),
+            KeyUse::Publish => {
+                let account = account.clone();
+                let export = gpg_async(
+                    vec![String::from("--export"), fingerprint.clone()],
+                    Vec::new(),
+                );
+                aparte.spawn(async move {
+                    Event::PublicKeyExported {
+                        account,
+                        fingerprint,
+                        result: export.await.map(|(key, _)| key),
+                    }
+                });
+            }
+        }
+    }
+
+    /// Encrypt a message, Event::Encrypted is scheduled once done
+    fn encrypt(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: &VersionedXmppMessage,
+    ) -> Result<(), String> {
+        let recipient = message.to.clone();
+        let own = self.own.get(&account.clone().into()).cloned();
+        let user_id = user_id(&aparte.config, account);
+        let keys = self.keys.get(&recipient).cloned().unwrap_or_default();
+        if keys.is_empty() {
+            aparte.send(account, Self::request(&recipient, ns::OX_PUBKEYS));
+            return Err("no OpenPGP key known for them".to_string());
+        }
+
+        let clear = Element::try_from(Message::Xmpp(message.clone()))
+            .ok()
+            .and_then(|element| XmppParsersMessage::try_from(element).ok())
+            .ok_or("invalid message".to_string())?;
+        let rpad = Uuid::new_v4().to_simple().to_string();
+        let rpad = &rpad[..usize::from(Uuid::new_v4().as_bytes()[0] % 32)];
+        let signcrypt = String::from(&signcrypt(&recipient, &clear, rpad));
+
+        let count = keys.len();
+        let account = account.clone();
+        let id = message.id.clone();
+        aparte.spawn(async move {
+            let own = match own {
+                Some(own) => Ok(own),
+                None => own_key(user_id).await,
+            };
+            let result = match own {
+                Ok(own) => {
+                    let mut args = vec![
+                        String::from("--sign"),
+                        String::from("--encrypt"),
+                        // Recipients are the fingerprints they announced
+                        String::from("--trust-model"),
+                        String::from("always"),
+                        String::from("--local-user"),
+                        own.clone(),
+                        String::from("--recipient"),
+                        own,
+                    ];
+                    for key in keys {
+                        args.push(String::from("--recipient"));
+                        args.push(key);
+                    }
+                    gpg_async(args, signcrypt.into_bytes()).await
+                }
+                Err(err) => Err(err),
+            }
+            .map(|(encrypted, _)| {
+                let mut message = XmppParsersMessage::new(clear.to.clone());
+                message.id = clear.id.clone();
+                message.type_ = clear.type_.clone();
+                message
+                    .bodies
+                    .insert(String::new(), Body(String::from(FALLBACK)));
+                message.payloads = clear
+                    .payloads
+                    .iter()
+                    .filter(|payload| payload.is("origin-id", ns::SID))
+                    .cloned()
+                    .collect();
+                message.payloads.push(
+                    Element::builder("openpgp", ns::OX)
+                        .append(base64::encode(encrypted))
+                        .build(),
+                );
+                message.payloads.push(
+                    ExplicitMessageEncryption {
+                        namespace: String::from(ns::OX),
+                        name: Some(String::from("OpenPGP for XMPP")),
+                    }
+                    .into(),
+                );
+                message
+                    .payloads
+                    .push(Element::builder("store", HINTS).build());
+                (message.into(), count)
+            });
+            Event::Encrypted {
+                account,
+                conversation: recipient,
+                id,
+                result,
+            }
+        });
+        Ok(())
+    }
+
+    /// Warn when a message of an account signing its messages is sent in clear
+    fn check_plaintext(&self, aparte: &mut Aparte, account: &Account, message: &Message) {
+        let message = match message {
+            Message::Xmpp(message)
+                if message.direction == Direction::Outgoing
+                    && message.type_ == XmppMessageType::Chat =>
+            {
+                message
+            }
+            _ => return,
+        };
+        if configured_key(&aparte.config, account).is_none() {
+            return;
+        }
+
+        aparte.log(format!(
+            "Warning: message to {} sent in clear, enable OpenPGP with /openpgp on",
+            message.to
+        ));
+        aparte.schedule(Event::Protection {
+            conversation: message.to.clone(),
+            id: message.id.clone(),
+            protection: Protection::Plaintext,
+        });
+    }
+
+    fn decrypted(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: &XmppParsersMessage,
+        delay: &Option<Delay>,
+        signature: &Signature,
+        own_key: &Option<String>,
+    ) {
+        let us: BareJid = account.clone().into();
+        let sender = match &message.from {
+            Some(from) => BareJid::from(from.clone()),
+            None => us.clone(),
+        };
+        let mut known = self.keys.get(&sender).cloned().unwrap_or_default();
+        if let Some(own_key) = own_key {
+            self.own.insert(us, own_key.clone());
+            known.push(own_key.clone());
+        }
+        let protection = match signature {
+            Signature::Valid(fingerprint) if known.contains(fingerprint) => None,
+            Signature::Valid(fingerprint) => {
+                aparte.log(format!(
+                    "Message from {} is signed with {}, a key they didn't announce",
+                    sender, fingerprint
+                ));
+                Some(Protection::UnknownKey)
+            }
+            Signature::Invalid => {
+                // Forged, or signed with a revoked key
+                aparte.log(format!(
+                    "Message from {} dropped, its signature is invalid",
+                    sender
+                ));
+                return;
+            }
+            Signature::Missing => {
+                aparte.log(format!("Message from {} isn't signed", sender));
+                Some(Protection::Unsigned)
+            }
+        };
+
+        let mut message = message.clone();
+        if let Some(protection) = protection {
+            // Marked before being shown, under the id it is shown with
+            let id = message
+                .id
+                .get_or_insert_with(|| Uuid::new_v4().to_string())
+                .clone();
+            aparte.schedule(Event::Protection {
+                conversation: sender,
+                id,
+                protection,
+            });
+        }
+        aparte.schedule(Event::RawMessage(account.clone(), message, delay.clone()));
+    }
+}
+
+impl ModTrait for OpenPgpMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(openpgp::new());
+        let mut disco = aparte.get_mod_mut::<mods::disco::DiscoMod>();
+        disco.add_feature(NOTIFY)
+    }
+
+    fn can_handle_xmpp_message(
+        &mut self,
+        _aparte: &mut Aparte,
+        _account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+    ) -> f64 {
+        match message.payloads.iter().any(|p| p.is("openpgp", ns::OX)) {
+            true => 1f64,
+            false => 0f64,
+        }
+    }
+
+    fn handle_xmpp_message(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: &XmppParsersMessage,
+        delay: &Option<Delay>,
+    ) {
+        let encrypted = match message
+            .payloads
+            .iter()
+            .find(|p| p.is("openpgp", ns::OX))
+            .and_then(|openpgp| base64::decode(openpgp.text().trim()).ok())
+        {
+            Some(encrypted) => encrypted,
+            None => {
+                aparte.log("Invalid OpenPGP message received".to_string());
+                return;
+            }
+        };
+
+        // Our own messages are signed with our key rather than with an announced one
+        let us: BareJid = account.clone().into();
+        let sender = match &message.from {
+            Some(from) => BareJid::from(from.clone()),
+            None => us.clone(),
+        };
+        let from_us = sender == us;
+        let known = self.own.get(&us).cloned();
+        let user_id = user_id(&aparte.config, account);
+
+        let account = account.clone();
+        let message = message.clone();
+        let delay = delay.clone();
+        aparte.spawn(async move {
+            let own_key = match (from_us, known) {
+                (true, None) => own_key(user_id).await.ok(),
+                (true, known) => known,
+                (false, _) => None,
+            };
+            let result = match gpg_async(vec![String::from("--decrypt")], encrypted).await {
+                Ok((clear, status)) => String::from_utf8(clear)
+                    .map_err(|_| "Invalid content".to_string())
+                    .and_then(|clear| {
+                        Element::from_str(&clear).map_err(|_| "Invalid content".to_string())
+                    })
+                    .and_then(|signcrypt| open(&message, &signcrypt))
+                    .map(|opened| (opened, signature(&status))),
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok((message, signature)) => Event::Decrypted {
+                    account,
+                    message,
+                    delay,
+                    signature,
+                    own_key,
+                },
+                Err(err) => undecryptable(account, &message, &delay, err),
+            }
+        });
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::SendMessage(account, Message::Xmpp(message))
+                if self.encrypts(account, &Message::Xmpp(message.clone())) =>
+            {
+                let protection = match self.encrypt(aparte, account, message) {
+                    Ok(()) => Protection::Pending,
+                    Err(err) => {
+                        aparte.log(format!("Message to {} not sent: {}", message.to, err));
+                        Protection::Failed
+                    }
+                };
+                aparte.schedule(Event::Protection {
+                    conversation: message.to.clone(),
+                    id: message.id.clone(),
+                    protection,
+                });
+            }
+            Event::SendMessage(account, message) => self.check_plaintext(aparte, account, message),
+            Event::Encrypted {
+                account,
+                conversation,
+                id,
+                result,
+            } => {
+                let protection = match result {
+                    Ok((stanza, count)) => {
+                        aparte.send(account, stanza.clone());
+                        Protection::Encrypted(*count)
+                    }
+                    Err(err) => {
+                        aparte.log(format!("Message to {} not sent: {}", conversation, err));
+                        Protection::Failed
+                    }
+                };
+                aparte.schedule(Event::Protection {
+                    conversation: conversation.clone(),
+                    id: id.clone(),
+                    protection,
+                });
+            }
+            Event::Decrypted {
+                account,
+                message,
+                delay,
+                signature,
+                own_key,
+            } => self.decrypted(aparte, account, message, delay, signature, own_key),
+            Event::OwnKey {
+                account,
+                purpose,
+                result,
+            } => self.own_key_found(aparte, account, purpose, result),
+            Event::PublicKeyExported {
+                account,
+                fingerprint,
+                result,
+            } => match result {
+                Ok(key) if !key.is_empty() => {
+                    for iq in Self::publish(fingerprint, key.clone()) {
+                        aparte.send(account, iq);
+                    }
+                    aparte.log(format!("Public key {} published", fingerprint));
+                }
+                Ok(_) => aparte.log(format!("Cannot export the public key {}", fingerprint)),
+                Err(err) => aparte.log(format!(
+                    "Cannot export the public key {}: {}",
+                    fingerprint, err
+                )),
+            },
+            Event::KeyImported {
+                contact,
+                fingerprint,
+                result,
+            } => self.key_imported(aparte, contact, fingerprint, result),
+            Event::Iq(account, iq) => {
+                let contact = match &iq.from {
+                    Some(from) => BareJid::from(from.clone()),
+                    None => return,
+                };
+                if let IqType::Result(Some(payload)) = &iq.payload {
+                    if let Ok(PubSub::Items(items)) = PubSub::try_from(payload.clone()) {
+                        let node = items.node.0.clone();
+                        for item in items
+                            .items
+                            .iter()
+                            .filter_map(|item| item.0.payload.as_ref())
+                        {
+                            if node == ns::OX_PUBKEYS {
+                                self.handle_metadata(aparte, account, contact.clone(), item);
+                            } else if node.starts_with(&format!("{}:", ns::OX_PUBKEYS)) {
+                                self.handle_pubkey(aparte, contact.clone(), &node, item);
+                            }
+                        }
+                    }
+                }
+            }
+            // Keys announced by contacts are kept up to date
+            Event::Stanza(account, stanza) if stanza.is("message", ns::DEFAULT_NS) => {
+                let message = match XmppParsersMessage::try_from(stanza.clone()) {
+                    Ok(message) => message,
+                    Err(_) => return,
+                };
+                let contact = match &message.from {
+                    Some(from) => BareJid::from(from.clone()),
+                    None => return,
+                };
+                for payload in message.payloads {
+                    if let Ok(PubSubEvent::PublishedItems { node, items }) =
+                        PubSubEvent::try_from(payload)
+                    {
+                        if node.0 == ns::OX_PUBKEYS {
+                            for item in items.iter().filter_map(|item| item.0.payload.as_ref()) {
+                                self.handle_metadata(aparte, account, contact.clone(), item);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for OpenPgpMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0373: OpenPGP for XMPP")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xmpp_parsers::stanza_id::OriginId;
+
+    #[test]
+    fn test_secret_key_skips_revoked_keys() {
+        // Given
+        let listing = "sec:r:255:22:AAAA:1600000000:::u:::scESC:::+:::ed25519:::0:\n\
+                       fpr:::::::::1111111111111111111111111111111111111111:\n\
+                       uid:r::::1600000000::HASH::xmpp\\x3aromeo@example.org::::::::::0:\n\
+                       sec:u:255:22:BBBB:1600000000:::u:::scESC:::+:::ed25519:::0:\n\
+                       fpr:::::::::2222222222222222222222222222222222222222:\n\
+                       ssb:u:255:18:CCCC:1600000000::::::e:::+:::cv25519::\n\
+                       fpr:::::::::3333333333333333333333333333333333333333:\n";
+
+        // When
+        let fingerprint = secret_key(listing);
+
+        // Then
+        assert_eq!(
+            fingerprint,
+            Some(String::from("2222222222222222222222222222222222222222"))
+        );
+    }
+
+    #[test]
+    fn test_signature_from_status() {
+        // Given
+        let valid = "[GNUPG:] NEWSIG\n\
+                     gpg: Good signature from \"xmpp:juliet@example.org\"\n\
+                     [GNUPG:] GOODSIG CCCC xmpp:juliet@example.org\n\
+                     [GNUPG:] VALIDSIG 3333 2021-03-14 1615734000 0 4 0 22 10 00 2222\n";
+        let bad = "[GNUPG:] BADSIG CCCC xmpp:juliet@example.org\n";
+
+        // When
+        let signatures = [signature(valid), signature(bad), signature("")];
+
+        // Then
+        assert_eq!(
+            signatures,
+            [
+                Signature::Valid(String::from("2222")),
+                Signature::Invalid,
+                Signature::Missing
+            ]
+        );
+    }
+
+    #[test]
+    fn test_signcrypt_round_trip() {
+        // Given
+        let juliet = BareJid::from_str("juliet@example.org").unwrap();
+        let mut message = XmppParsersMessage::new(Some(Jid::Bare(juliet.clone())));
+        message
+            .bodies
+            .insert(String::new(), Body(String::from("Wherefore art thou?")));
+        message.payloads.push(
+            OriginId {
+                id: String::from("1"),
+            }
+            .into(),
+        );
+        let element = signcrypt(&juliet, &message, "pad");
+
+        // When
+        let mut received = XmppParsersMessage::new(Some(Jid::Bare(juliet)));
+        received
+            .bodies
+            .insert(String::new(), Body(String::from(FALLBACK)));
+        received
+            .payloads
+            .push(Element::builder("openpgp", ns::OX).build());
+        let opened = open(&received, &element).unwrap();
+
+        // Then
+        assert_eq!(
+            opened.bodies.get(""),
+            Some(&Body(String::from("Wherefore art thou?")))
+        );
+        assert!(opened.payloads.is_empty());
+    }
+
+    #[test]
+    fn test_messages_encrypted_for_someone_else_are_refused() {
+        // Given
+        let juliet = BareJid::from_str("juliet@example.org").unwrap();
+        let tybalt = BareJid::from_str("tybalt@example.org").unwrap();
+        let element = signcrypt(&tybalt, &XmppParsersMessage::new(None), "");
+
+        // When
+        let received = XmppParsersMessage::new(Some(Jid::Bare(juliet)));
+        let opened = open(&received, &element);
+
+        // Then
+        assert!(opened.is_err());
+    }
+
+    #[test]
+    fn test_undecryptable_archived_message_keeps_its_place() {
+        // Given
+        let account = Account::from_str("romeo@example.org/aparte").unwrap();
+        let mut message = XmppParsersMessage::new(Some(Jid::Full(account.clone())));
+        message.from = Some(Jid::from_str("juliet@example.org/balcony").unwrap());
+        message.id = Some(String::from("m1"));
+        message
+            .bodies
+            .insert(String::new(), Body(String::from(FALLBACK)));
+        let delay = Delay {
+            from: None,
+            stamp: xmpp_parsers::date::DateTime::from_str("2021-03-14T18:30:00Z").unwrap(),
+            data: None,
+        };
+
+        // When
+        let event = undecryptable(
+            account,
+            &message,
+            &Some(delay.clone()),
+            String::from("No secret key"),
+        );
+
+        // Then
+        match event {
+            Event::Message(Some(_), Message::Xmpp(placeholder)) => {
+                assert_eq!(placeholder.id, "m1");
+                assert_eq!(placeholder.get_original_timestamp(), &delay.stamp.0);
+                assert_eq!(
+                    placeholder.get_last_body(),
+                    "🔒 Cannot decrypt this message: No secret key. /openpgp heal juliet@example.org exchanges keys again and asks for it"
+                );
+            }
+            _ => panic!("No placeholder"),
+        }
+    }
+
+    #[test]
+    fn test_encryption_is_turned_off_without_a_secret_key() {
+        crate::testing::run(async {
+            // Given
+            let mut harness = crate::testing::Harness::new();
+            harness.connect().await;
+            let juliet = BareJid::from_str("juliet@example.org").unwrap();
+            {
+                let mut openpgp = harness.aparte.get_mod_mut::<OpenPgpMod>();
+                openpgp
+                    .enabled
+                    .insert((harness.account.clone(), juliet.clone()));
+            }
+
+            // When
+            harness.aparte.schedule(Event::OwnKey {
+                account: harness.account.clone(),
+                purpose: KeyUse::Encrypt(juliet.clone()),
+                result: Err(String::from("No OpenPGP secret key found")),
+            });
+            harness.settle().await;
+
+            // Then
+            let openpgp = harness.aparte.get_mod::<OpenPgpMod>();
+            assert!(!openpgp.enabled.contains(&(harness.account.clone(), juliet)));
+            drop(openpgp);
+            assert!(harness.screen().contains("aren't encrypted"));
+        });
+    }
+
+    #[test]
+    fn test_heal_asks_for_messages_to_be_sent_again() {
+        crate::testing::run(async {
+            // Given
+            let mut harness = crate::testing::Harness::new();
+            harness.connect().await;
+            harness.sent.clear();
+
+            // When
+            harness
+                .input("console", "/openpgp heal juliet@example.org")
+                .await;
+
+            // Then
+            let messages = harness.take_sent("message", ns::DEFAULT_NS);
+            assert_eq!(messages.len(), 1);
+            let message = XmppParsersMessage::try_from(messages[0].clone()).unwrap();
+            assert_eq!(
+                message.to,
+                Some(Jid::from_str("juliet@example.org").unwrap())
+            );
+            assert_eq!(message.bodies[""].0, RESEND_REQUEST);
+            assert!(message.payloads.iter().any(|p| p.is("store", HINTS)));
+            assert!(!message.payloads.iter().any(|p| p.is("openpgp", ns::OX)));
+        });
+    }
+
+    /// Message from juliet decrypted with the given signature, shown in her open chat window
+    async fn show_decrypted(signature: Signature) -> String {
+        let mut harness = crate::testing::Harness::new();
+        harness.connect().await;
+        harness.input("console", "/msg juliet@example.org").await;
+        {
+            let mut openpgp = harness.aparte.get_mod_mut::<OpenPgpMod>();
+            openpgp.keys.insert(
+                BareJid::from_str("juliet@example.org").unwrap(),
+                vec![String::from("1111")],
+            );
+        }
+
+        let mut message = XmppParsersMessage::new(Some(Jid::Full(harness.account.clone())));
+        message.type_ = xmpp_parsers::message::MessageType::Chat;
+        message.from = Some(Jid::from_str("juliet@example.org/balcony").unwrap());
+        message
+            .bodies
+            .insert(String::new(), Body(String::from("Meet me at the balcony")));
+        harness.aparte.schedule(Event::Decrypted {
+            account: harness.account.clone(),
+            message,
+            delay: None,
+            signature,
+            own_key: None,
+        });
+        harness.settle().await;
+        harness.screen()
+    }
+
+    #[test]
+    fn test_messages_signed_by_an_announced_key_are_shown() {
+        crate::testing::run(async {
+            // When
+            let screen = show_decrypted(Signature::Valid(String::from("1111"))).await;
+
+            // Then
+            assert!(screen.contains("Meet me at the balcony"));
+            assert!(!screen.contains("UNVERIFIED"));
+        });
+    }
+
+    #[test]
+    fn test_messages_with_an_invalid_signature_are_dropped() {
+        crate::testing::run(async {
+            // When
+            let screen = show_decrypted(Signature::Invalid).await;
+
+            // Then
+            assert!(!screen.contains("Meet me at the balcony"));
+        });
+    }
+
+    #[test]
+    fn test_unsigned_messages_are_marked_unverified() {
+        crate::testing::run(async {
+            // When
+            let screen = show_decrypted(Signature::Missing).await;
+
+            // Then
+            assert!(screen.contains("Meet me at the balcony"));
+            assert!(screen.contains("[UNVERIFIED: NOT SIGNED]"));
+        });
+    }
+
+    #[test]
+    fn test_messages_signed_by_an_unannounced_key_are_marked_unverified() {
+        crate::testing::run(async {
+            // When
+            let screen = show_decrypted(Signature::Valid(String::from("2222"))).await;
+
+            // Then
+            assert!(screen.contains("Meet me at the balcony"));
+            assert!(screen.contains("[UNVERIFIED: UNKNOWN KEY]"));
+        });
+    }
+}
@@ -0,0 +1,604 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Own presence of accounts: initial presence, availability and invisibility (XEP-0186).
+//!
+//! Accounts can be configured to connect without presence, or invisibly: the server then
+//! doesn't broadcast the presence, but still sends the ones of contacts. Messages are received
+//! either way, `/online` makes the account appear online. `/invisible` and `/visible` switch
+//! invisibility once connected.
+//!
+//! `/away`, `/dnd`, `/xa` and `/status` change the availability and status message of every
+//! account, and of our occupants in the joined channels.
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::presence::{Presence, Show as PresenceShow, Type as PresenceType};
+use xmpp_parsers::{BareJid, Element, Jid};
+
+use crate::conversation::Channel;
+
+use crate::account::{Account, InitialPresence};
+use crate::command::{Command, CommandParser};
+use crate::config::Config;
+use crate::core::{Aparte, Event, ModTrait};
+use crate::mods;
+
+const INVISIBLE: &str = "urn:xmpp:invisible:0";
+
+command_def!(
+    online,
+    r#"/online
+
+Description:
+    Appear online on the current account, after connecting without presence or invisibly
+    (see initial_presence in the account configuration), or available again after /away,
+    /dnd or /xa."#,
+    {},
+    |aparte, _command| {
+        let account = _command
+            .account
+            .clone()
+            .ok_or("No connection found".to_string())?;
+        let back = {
+            let presence = aparte.get_mod::<PresenceMod>();
+            presence.show != PresenceShow::Chat
+                && presence.states.get(&account) == Some(&InitialPresence::Online)
+        };
+        if back {
+            aparte.schedule(Event::SetPresence {
+                show: None,
+                status: None,
+            });
+            return Ok(());
+        }
+        let stanzas = {
+            let mut presence = aparte.get_mod_mut::<PresenceMod>();
+            presence.online(&account)?
+        };
+        for stanza in stanzas {
+            aparte.send(&account, stanza);
+        }
+        aparte.log(format!("{} is online", account));
+        Ok(())
+    }
+);
+
+command_def!(
+    invisible,
+    r#"/invisible
+
+Description:
+    Appear offline to contacts on the current account while still receiving their presences and
+    messages, where the server supports it (XEP-0186). /visible ends it."#,
+    {},
+    |aparte, _command| {
+        let account = _command
+            .account
+            .clone()
+            .ok_or("No connection found".to_string())?;
+        let supported = {
+            let disco = aparte.get_mod::<mods::disco::DiscoMod>();
+            disco.has_feature(&account, INVISIBLE)
+        };
+        if !supported {
+            return Err(format!(
+                "The server of {} doesn't support invisibility",
+                account
+            ));
+        }
+        let invisible = {
+            let mut presence = aparte.get_mod_mut::<PresenceMod>();
+            let state = presence.states.get(&account).copied().unwrap_or_default();
+            if state == InitialPresence::Invisible {
+                return Err(format!("{} is already invisible", account));
+            }
+            presence.invisible(&account, state)
+        };
+        aparte.send(&account, invisible);
+        Ok(())
+    }
+);
+
+command_def!(
+    visible,
+    r#"/visible
+
+Description:
+    Appear online again on the current account after /invisible, or after connecting
+    invisibly (see initial_presence in the account configuration)."#,
+    {},
+    |aparte, _command| {
+        let account = _command
+            .account
+            .clone()
+            .ok_or("No connection found".to_string())?;
+        let stanzas = {
+            let mut presence = aparte.get_mod_mut::<PresenceMod>();
+            if presence.states.get(&account) != Some(&InitialPresence::Invisible) {
+                return Err(format!("{} isn't invisible", account));
+            }
+            presence.online(&account)?
+        };
+        for stanza in stanzas {
+            aparte.send(&account, stanza);
+        }
+        aparte.log(format!("{} is visible", account));
+        Ok(())
+    }
+);
+
+command_def!(
+    away,
+    r#"/away [<message>]
+
+    message       Optional status message
+
+Description:
+    Appear away on every account, until /online.
+
+Examples:
+    /away
+    /away "Having lunch""#,
+    { message: Option<String> },
+    |aparte, _command| {
+        aparte.schedule(Event::SetPresence {
+            show: Some(PresenceShow::Away),
+            status: message,
+        });
+        Ok(())
+    }
+);
+
+command_def!(
+    dnd,
+    r#"/dnd [<message>]
+
+    message       Optional status message
+
+Description:
+    Appear busy on every account, until /online.
+
+Examples:
+    /dnd
+    /dnd "In a meeting""#,
+    { message: Option<String> },
+    |aparte, _command| {
+        aparte.schedule(Event::SetPresence {
+            show: Some(PresenceShow::Dnd),
+            status: message,
+        });
+        Ok(())
+    }
+);
+
+command_def!(
+    xa,
+    r#"/xa [<message>]
+
+    message       Optional status message
+
+Description:
+    Appear away for an extended period on every account, until /online.
+
+Examples:
+    /xa
+    /xa "On holidays""#,
+    { message: Option<String> },
+    |aparte, _command| {
+        aparte.schedule(Event::SetPresence {
+            show: Some(PresenceShow::Xa),
+            status: message,
+        });
+        Ok(())
+    }
+);
+
+command_def!(
+    status,
+    r#"/status [<message>]
+
+    message       Status message, removed if omitted
+
+Description:
+    Change the status message of every account, keeping the availability.
+
+Examples:
+    /status "Reading Shakespeare"
+    /status"#,
+    { message: Option<String> },
+    |aparte, _command| {
+        let show = aparte.get_mod::<PresenceMod>().show.clone();
+        aparte.schedule(Event::SetPresence {
+            show: Some(show).filter(|show| show != &PresenceShow::Chat),
+            status: message,
+        });
+        Ok(())
+    }
+);
+
+/// Availability as shown to the user
+pub fn show_text(show: &PresenceShow) -> &'static str {
+    match show {
+        PresenceShow::Away => "away",
+        PresenceShow::Chat => "available",
+        PresenceShow::Dnd => "busy",
+        PresenceShow::Xa => "away for an extended period",
+    }
+}
+
+/// Initial presence configured for an account, accounts connected with /connect appear online
+fn configured(config: &Config, account: &Account) -> InitialPresence {
+    let jid: BareJid = account.clone().into();
+    config
+        .accounts
+        .values()
+        .find(|info| Jid::from_str(&info.jid).map(BareJid::from).as_ref() == Ok(&jid))
+        .map(|info| info.initial_presence)
+        .unwrap_or_default()
+}
+
+fn command(payload: Element) -> Element {
+    Iq {
+        from: None,
+        to: None,
+        id: Uuid::new_v4().to_hyphenated().to_string(),
+        payload: IqType::Set(payload),
+    }
+    .into()
+}
+
+pub struct PresenceMod {
+    /// How accounts currently appear, kept across reconnections
+    states: HashMap<Account, InitialPresence>,
+    /// Pending invisible commands by IQ id, with the state to go back to if refused
+    requests: HashMap<String, (Account, InitialPresence)>,
+    /// Availability of every account, see /away
+    show: PresenceShow,
+    /// Status message of every account, see /status
+    status: Option<String>,
+}
+
+impl PresenceMod {
+    pub fn new() -> Self {
+        Self {
+            states: HashMap::new(),
+            requests: HashMap::new(),
+            show: PresenceShow::Chat,
+            status: None,
+        }
+    }
+
+    fn available(&self) -> Presence {
+        let mut presence = Presence::new(PresenceType::None);
+        presence.show = Some(self.show.clone());
+        if let Some(status) = &self.status {
+            presence.set_status("", status);
+        }
+        presence
+    }
+
+    /// Broadcast a new availability and status message, from the accounts appearing online and
+    /// to the channels they joined
+    fn set(&mut self, aparte: &mut Aparte, show: &Option<PresenceShow>, status: &Option<String>) {
+        self.show = show.clone().unwrap_or(PresenceShow::Chat);
+        self.status = status.clone();
+        for account in aparte.accounts() {
+            if self.states.get(&account) == Some(&InitialPresence::None) {
+                continue;
+            }
+            let channels: Vec<Channel> = {
+                let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+                conversations.channels(&account).cloned().collect()
+            };
+            aparte.send(&account, self.available().into());
+            for channel in channels {
+                let to = Jid::Full(channel.jid.with_resource(channel.nick));
+                aparte.send(&account, self.available().with_to(to).into());
+            }
+        }
+        aparte.log(match (&self.show, &self.status) {
+            (show, Some(status)) => format!("You are now {}: {}", show_text(show), status),
+            (show, None) => format!("You are now {}", show_text(show)),
+        });
+    }
+
+    /// Stanzas making an account appear online
+    fn online(&mut self, account: &Account) -> Result<Vec<Element>, String> {
+        let state = self.states.insert(account.clone(), InitialPresence::Online);
+        match state {
+            Some(InitialPresence::Online) | None => Err(format!("{} is already online", account)),
+            Some(InitialPresence::None) => Ok(vec![self.available().into()]),
+            Some(InitialPresence::Invisible) => {
+                let visible = Element::builder("visible", INVISIBLE).build();
+                Ok(vec![command(visible), self.available().into()])
+            }
+        }
+    }
+
+    fn invisible(&mut self, account: &Account, fallback: InitialPresence) -> Element {
+        let invisible = command(Element::builder("invisible", INVISIBLE).build());
+        let id = invisible.attr("id").unwrap().to_string();
+        self.requests.insert(id, (account.clone(), fallback));
+        invisible
+    }
+}
+
+impl ModTrait for PresenceMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(online::new());
+        aparte.add_command(invisible::new());
+        aparte.add_command(visible::new());
+        aparte.add_command(away::new());
+        aparte.add_command(dnd::new());
+        aparte.add_command(xa::new());
+        aparte.add_command(status::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Connected(account, _) => {
+                let state = match self.states.get(account) {
+                    Some(state) => *state,
+                    None => configured(&aparte.config, account),
+                };
+                self.states.insert(account.clone(), state);
+                match state {
+                    InitialPresence::Online => aparte.send(account, self.available().into()),
+                    InitialPresence::None => aparte.log(format!(
+                        "{} is connected without presence, use /online to appear online",
+                        account
+                    )),
+                    // Invisibility must be supported by the server, which is known once discovered
+                    InitialPresence::Invisible => {}
+                }
+            }
+            Event::Disco(account)
+                if self.states.get(account) == Some(&InitialPresence::Invisible) =>
+            {
+                let supported = {
+                    let disco = aparte.get_mod::<mods::disco::DiscoMod>();
+                    disco.has_feature(account, INVISIBLE)
+                };
+                match supported {
+                    true => {
+                        let invisible = self.invisible(account, InitialPresence::None);
+                        aparte.send(account, invisible);
+                    }
+                    false => {
+                        self.states.insert(account.clone(), InitialPresence::None);
+                        aparte.log(format!(
+                            "The server of {} doesn't support invisibility, no presence sent, use /online to appear online",
+                            account
+                        ));
+                    }
+                }
+            }
+            Event::SetPresence { show, status } => self.set(aparte, show, status),
+            Event::Iq(_, iq) if self.requests.contains_key(&iq.id) => {
+                let (account, fallback) = self.requests.remove(&iq.id).unwrap();
+                match &iq.payload {
+                    // Sending presence while invisible gets the presences of contacts
+                    IqType::Result(_) => {
+                        self.states
+                            .insert(account.clone(), InitialPresence::Invisible);
+                        aparte.send(&account, self.available().into());
+                        aparte.log(format!("{} is invisible", account));
+                    }
+                    IqType::Error(err) => {
+                        self.states.insert(account.clone(), fallback);
+                        let hint = match fallback {
+                            InitialPresence::None => ", use /online to appear online",
+                            _ => "",
+                        };
+                        aparte.log(format!(
+                            "Cannot become invisible on {}: {:?}{}",
+                            account, err.defined_condition, hint
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for PresenceMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0186: Invisible Command")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::{self, ConnectionInfo};
+    use crate::testing::{self, Harness};
+    use std::convert::TryFrom;
+    use xmpp_parsers::ns;
+
+    fn config(initial_presence: InitialPresence) -> Config {
+        let info = ConnectionInfo {
+            jid: String::from("romeo@example.org"),
+            server: None,
+            port: None,
+            autoconnect: false,
+            component: false,
+            keepalive: account::default_keepalive(),
+            compression: false,
+            openpgp_key: None,
+            initial_presence,
+            nick: None,
+            stream_management: account::default_stream_management(),
+            schedule: Vec::new(),
+        };
+        Config {
+            accounts: HashMap::from([(String::from("romeo"), info)]),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_no_presence_is_sent_until_online() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::with_config(config(InitialPresence::None));
+
+            // When
+            harness.connect().await;
+            let connected = harness.take_sent("presence", ns::DEFAULT_NS);
+            harness.input("console", "/online").await;
+
+            // Then
+            assert!(connected.is_empty());
+            assert_eq!(harness.take_sent("presence", ns::DEFAULT_NS).len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_invisible_then_visible_once_connected() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness
+                .reply(
+                    "query",
+                    ns::DISCO_INFO,
+                    "<iq xmlns='jabber:client' type='result' id='{id}' from='example.org'>
+                        <query xmlns='http://jabber.org/protocol/disco#info'>
+                            <identity category='server' type='im'/>
+                            <feature var='http://jabber.org/protocol/disco#info'/>
+                            <feature var='urn:xmpp:invisible:0'/>
+                        </query>
+                    </iq>",
+                )
+                .reply(
+                    "invisible",
+                    INVISIBLE,
+                    "<iq xmlns='jabber:client' type='result' id='{id}'/>",
+                );
+            harness.connect().await;
+            harness.take_sent("presence", ns::DEFAULT_NS);
+
+            // When
+            harness.input("console", "/invisible").await;
+
+            // Then
+            assert!(harness
+                .screen()
+                .contains("romeo@example.org/aparte is invisible"));
+            let account = harness.account.clone();
+            let state = harness.aparte.get_mod::<PresenceMod>().states[&account];
+            assert_eq!(state, InitialPresence::Invisible);
+
+            // When
+            harness.take_sent("presence", ns::DEFAULT_NS);
+            harness.input("console", "/visible").await;
+
+            // Then
+            assert_eq!(harness.take_sent("visible", INVISIBLE).len(), 1);
+            assert_eq!(harness.take_sent("presence", ns::DEFAULT_NS).len(), 1);
+            let state = harness.aparte.get_mod::<PresenceMod>().states[&account];
+            assert_eq!(state, InitialPresence::Online);
+        });
+    }
+
+    #[test]
+    fn test_away_is_broadcast_to_contacts_and_channels() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness
+                .input("console", "/join room@conference.example.org")
+                .await;
+            harness.take_sent("presence", ns::DEFAULT_NS);
+
+            // When
+            harness.input("console", "/away \"Having lunch\"").await;
+
+            // Then
+            let sent = harness
+                .take_sent("presence", ns::DEFAULT_NS)
+                .into_iter()
+                .map(|presence| Presence::try_from(presence).unwrap())
+                .collect::<Vec<_>>();
+            let to = sent
+                .iter()
+                .map(|presence| presence.to.as_ref().map(|to| to.to_string()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                to,
+                vec![None, Some("room@conference.example.org/romeo".to_string())]
+            );
+            for presence in sent {
+                assert_eq!(presence.show, Some(PresenceShow::Away));
+                assert_eq!(presence.statuses.get("").unwrap(), "Having lunch");
+            }
+            assert!(harness.screen().contains("romeo@example.org/aparte [away]"));
+        });
+    }
+
+    #[test]
+    fn test_online_ends_away() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness.input("console", "/dnd").await;
+            harness.take_sent("presence", ns::DEFAULT_NS);
+
+            // When
+            harness.input("console", "/online").await;
+
+            // Then
+            let sent = harness.take_sent("presence", ns::DEFAULT_NS);
+            assert_eq!(sent.len(), 1);
+            let presence = Presence::try_from(sent[0].clone()).unwrap();
+            assert_eq!(presence.show, Some(PresenceShow::Chat));
+            assert!(harness.screen().contains("You are now available"));
+        });
+    }
+
+    #[test]
+    fn test_invisibility_is_requested_when_the_server_supports_it() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::with_config(config(InitialPresence::Invisible));
+            harness
+                .reply(
+                    "query",
+                    ns::DISCO_INFO,
+                    "<iq xmlns='jabber:client' type='result' id='{id}' from='example.org'>
+                        <query xmlns='http://jabber.org/protocol/disco#info'>
+                            <identity category='server' type='im'/>
+                            <feature var='http://jabber.org/protocol/disco#info'/>
+                            <feature var='urn:xmpp:invisible:0'/>
+                        </query>
+                    </iq>",
+                )
+                .reply(
+                    "invisible",
+                    INVISIBLE,
+                    "<iq xmlns='jabber:client' type='result' id='{id}'/>",
+                );
+
+            // When
+            harness.connect().await;
+            let invisible = harness.take_sent("presence", ns::DEFAULT_NS);
+            harness.input("console", "/online").await;
+
+            // Then
+            assert_eq!(invisible.len(), 1);
+            assert_eq!(harness.take_sent("visible", INVISIBLE).len(), 1);
+            assert_eq!(harness.take_sent("presence", ns::DEFAULT_NS).len(), 1);
+            assert!(harness
+                .screen()
+                .contains("romeo@example.org/aparte is invisible"));
+        });
+    }
+}
@@ -0,0 +1,227 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Titles of the web pages linked in incoming messages, shown under them.
+//!
+//! Fetching a page tells its server that the link is looked at, so previews are opt-in. Pages are
+//! fetched with curl, which follows the usual proxy environment variables, and only their first
+//! bytes are read.
+use regex::Regex;
+use std::collections::HashSet;
+use std::fmt;
+use std::process;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command as ProcessCommand;
+
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::{Direction, Message};
+
+/// Characters kept from a title, longer ones are cut
+const MAX_TITLE_LEN: usize = 200;
+
+/// HTTP(S) links of a text
+pub fn links(text: &str) -> Vec<&str> {
+    thread_local! {
+        static LINK: Regex = Regex::new(r#"https?://[^\s<>"']+"#).unwrap();
+    }
+    LINK.with(|link| {
+        link.find_iter(text)
+            .map(|link| {
+                link.as_str()
+                    .trim_end_matches(&['.', ',', ';', ':', '!', '?', ')'][..])
+            })
+            .collect()
+    })
+}
+
+/// Content of the title element of an HTML page, unescaped and on a single line
+pub fn parse_title(page: &str) -> Option<String> {
+    thread_local! {
+        static TITLE: Regex = Regex::new(r"(?is)<title[^>]*>(.*?)</title").unwrap();
+    }
+    let title = TITLE.with(|title| Some(title.captures(page)?.get(1)?.as_str().to_string()))?;
+    let title = title
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&");
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    match title.chars().count() {
+        0 => None,
+        len if len > MAX_TITLE_LEN => Some(format!(
+            "{}…",
+            title.chars().take(MAX_TITLE_LEN).collect::<String>()
+        )),
+        _ => Some(title),
+    }
+}
+
+/// Read the beginning of a page to find its title
+async fn fetch_title(
+    url: String,
+    max_size: u64,
+    timeout: u64,
+    proxy: Option<String>,
+) -> Result<Option<String>, String> {
+    let mut curl = ProcessCommand::new("curl");
+    curl.arg("--silent")
+        .arg("--fail")
+        .arg("--location")
+        .arg("--max-redirs")
+        .arg("5")
+        .arg("--proto")
+        .arg("=http,https")
+        .arg("--max-time")
+        .arg(timeout.to_string())
+        .arg("--max-filesize")
+        .arg(max_size.to_string());
+    if let Some(proxy) = proxy {
+        curl.arg("--proxy").arg(proxy);
+    }
+    curl.arg(&url);
+
+    let mut child = curl
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::null())
+        // Pages larger than allowed are cut, curl being killed once enough is read
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Cannot run curl: {}", e))?;
+    let mut page = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        stdout
+            .take(max_size)
+            .read_to_end(&mut page)
+            .await
+            .map_err(|e| format!("Cannot read {}: {}", url, e))?;
+    }
+    Ok(parse_title(&String::from_utf8_lossy(&page)))
+}
+
+pub struct PreviewMod {
+    /// Links already fetched or being fetched
+    fetched: HashSet<String>,
+}
+
+impl PreviewMod {
+    pub fn new() -> Self {
+        Self {
+            fetched: HashSet::new(),
+        }
+    }
+}
+
+impl ModTrait for PreviewMod {
+    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Message(Some(_), Message::Xmpp(message))
+                if message.direction == Direction::Incoming =>
+            {
+                let config = aparte.config.previews.clone();
+                if !config.enabled || aparte.config.low_bandwidth {
+                    return;
+                }
+                for url in links(message.get_last_body()) {
+                    if !self.fetched.insert(url.to_string()) {
+                        continue;
+                    }
+                    let conversation = message.conversation().clone();
+                    let url = url.to_string();
+                    let proxy = config.proxy.clone();
+                    let (max_size, timeout) = (config.max_size, config.timeout);
+                    aparte.spawn(async move {
+                        let title = match fetch_title(url.clone(), max_size, timeout, proxy).await {
+                            Ok(title) => title,
+                            Err(err) => {
+                                debug!("No title for {}: {}", url, err);
+                                None
+                            }
+                        };
+                        Event::LinkTitle {
+                            conversation,
+                            url,
+                            title,
+                        }
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for PreviewMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Link previews")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+    use std::str::FromStr;
+    use xmpp_parsers::BareJid;
+
+    #[test]
+    fn test_links() {
+        assert_eq!(
+            links("See https://example.org/a?b=c, or (http://example.net/).\nftp://no"),
+            vec!["https://example.org/a?b=c", "http://example.net/"]
+        );
+    }
+
+    #[test]
+    fn test_parse_title() {
+        assert_eq!(
+            parse_title("<html><head><TITLE lang='en'>\n  Romeo &amp; Juliet\n</TITLE>"),
+            Some(String::from("Romeo & Juliet"))
+        );
+        assert_eq!(parse_title("<title> </title>"), None);
+        assert_eq!(parse_title("<p>No title</p>"), None);
+        assert_eq!(
+            parse_title(&format!("<title>{}</title>", "a".repeat(300)))
+                .unwrap()
+                .chars()
+                .count(),
+            MAX_TITLE_LEN + 1
+        );
+    }
+
+    #[test]
+    fn test_link_title_is_shown_under_the_message() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='chat' id='m1'
+                        from='juliet@example.org/balcony' to='{account}'>
+                        <body>Look https://example.org/verona</body>
+                    </message>",
+                )
+                .await;
+            harness.input("console", "/win juliet@example.org").await;
+
+            // When
+            harness.aparte.schedule(Event::LinkTitle {
+                conversation: BareJid::from_str("juliet@example.org").unwrap(),
+                url: String::from("https://example.org/verona"),
+                title: Some(String::from("Fair Verona")),
+            });
+            harness.settle().await;
+
+            // Then
+            assert!(harness.screen().contains("Fair Verona"));
+        });
+    }
+}
@@ -0,0 +1,241 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Profiles bundling accounts, rooms, format and filters, like work and personal ones.
+//!
+//! Only the accounts of the profile used are connected and known to the rest of Aparté, the
+//! configuration it sees being the one of the profile. Switching profile disconnects the accounts
+//! of the previous one and closes their windows.
+use std::fmt;
+use std::str::FromStr;
+use xmpp_parsers::{BareJid, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::config::Config;
+use crate::core::{Aparte, Event, ModTrait};
+
+command_def!(profile,
+r#"/profile list|switch"#,
+{
+    action: Command = {
+        children: {
+            "list": profile_list,
+            "switch": profile_switch,
+        }
+    },
+});
+
+command_def!(
+    profile_list,
+    r#"/profile list
+
+Description:
+    List the profiles of the configuration, the one used being marked with a
+    star."#,
+    {},
+    |aparte, _command| {
+        let lines = {
+            let profile = aparte.get_mod::<ProfileMod>();
+            profile.list(aparte.config.profile.as_deref())
+        };
+        match lines.is_empty() {
+            true => aparte.log("No profile configured".to_string()),
+            false => aparte.log(format!("Profiles:\n{}", lines.join("\n"))),
+        }
+        Ok(())
+    }
+);
+
+command_def!(
+    profile_switch,
+    r#"/profile switch <name>
+
+    name          Profile to use
+
+Description:
+    Disconnect the accounts of the current profile, close their windows and
+    connect the ones of the given profile, using its format and filters.
+
+Example:
+    /profile switch work"#,
+    {
+        name: String = {
+            completion: (|aparte, _command| {
+                let profile = aparte.get_mod::<ProfileMod>();
+                profile.base.profiles.keys().cloned().collect()
+            })
+        },
+    },
+    |aparte, _command| {
+        if aparte.config.profile.as_ref() == Some(&name) {
+            return Err(format!("Profile {} is already used", name));
+        }
+        let config = {
+            let mut profile = aparte.get_mod_mut::<ProfileMod>();
+            profile.rooms_joined = false;
+            profile.base.with_profile(&name)?
+        };
+        for account in aparte.accounts() {
+            aparte.disconnect(&account);
+        }
+        aparte.config = config;
+        aparte.schedule(Event::Profile(name.clone()));
+        aparte.autoconnect();
+        aparte.log(format!("Using profile {}", name));
+        Ok(())
+    }
+);
+
+pub struct ProfileMod {
+    /// Whole configuration, before being restricted to a profile
+    base: Config,
+    /// Rooms of the profile were joined since it's used
+    rooms_joined: bool,
+}
+
+impl ProfileMod {
+    pub fn new(base: Config) -> Self {
+        Self {
+            base,
+            rooms_joined: false,
+        }
+    }
+
+    fn list(&self, current: Option<&str>) -> Vec<String> {
+        let mut names: Vec<&String> = self.base.profiles.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| {
+                let accounts = self.base.profiles[name].accounts.join(", ");
+                match current == Some(name.as_str()) {
+                    true => format!("* {} ({})", name, accounts),
+                    false => format!("  {} ({})", name, accounts),
+                }
+            })
+            .collect()
+    }
+
+    /// Rooms to join with an account just connected, the first one of the profile
+    fn rooms(&mut self, config: &Config, account: &Account) -> Vec<Jid> {
+        let profile = match config
+            .profile
+            .as_ref()
+            .and_then(|name| self.base.profiles.get(name))
+            .cloned()
+        {
+            Some(profile) if !self.rooms_joined => profile,
+            _ => return Vec::new(),
+        };
+        let first = profile
+            .accounts
+            .first()
+            .and_then(|name| config.accounts.get(name))
+            .and_then(|info| Jid::from_str(&info.jid).ok())
+            .map(BareJid::from);
+        if first != Some(account.clone().into()) {
+            return Vec::new();
+        }
+        self.rooms_joined = true;
+        profile
+            .rooms
+            .iter()
+            .filter_map(|room| match Jid::from_str(room) {
+                Ok(room) => Some(room),
+                Err(err) => {
+                    warn!("Invalid room {}: {}", room, err);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+impl ModTrait for ProfileMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(profile::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        if let Event::Connected(account, _) = event {
+            for channel in self.rooms(&aparte.config, account) {
+                aparte.schedule(Event::Join {
+                    account: account.clone(),
+                    channel,
+                    user_request: false,
+                });
+            }
+        }
+    }
+}
+
+impl fmt::Display for ProfileMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Profiles")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+    use xmpp_parsers::ns;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            profile = "personal"
+
+            [accounts.work]
+            jid = "romeo@example.org/aparte"
+            autoconnect = false
+
+            [accounts.personal]
+            jid = "romeo@example.net"
+            autoconnect = false
+
+            [profiles.work]
+            accounts = ["work"]
+            rooms = ["team@conference.example.org"]
+
+            [profiles.personal]
+            accounts = ["personal"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_switching_profile_disconnects_accounts_and_joins_rooms() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::with_config(config());
+            assert_eq!(
+                harness.aparte.config.accounts.keys().collect::<Vec<_>>(),
+                vec!["personal"]
+            );
+            harness.connect().await;
+
+            // When
+            harness.input("console", "/profile switch work").await;
+            let accounts = harness.aparte.accounts();
+            harness.connect().await;
+
+            // Then
+            assert!(accounts.is_empty());
+            assert_eq!(
+                harness.aparte.config.accounts.keys().collect::<Vec<_>>(),
+                vec!["work"]
+            );
+            let joins = harness.take_sent("x", ns::MUC);
+            assert_eq!(joins.len(), 1);
+            assert_eq!(
+                joins[0].attr("to"),
+                Some("team@conference.example.org/romeo")
+            );
+            assert!(harness.screen().contains("Using profile work"));
+        });
+    }
+}
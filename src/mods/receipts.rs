@@ -0,0 +1,202 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType as XmppParsersMessageType};
+use xmpp_parsers::ns;
+use xmpp_parsers::receipts::{Received, Request};
+use xmpp_parsers::stanza_id::StanzaId;
+use xmpp_parsers::{BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::Message;
+use crate::mods::disco;
+
+const CHAT_MARKERS: &str = "urn:xmpp:chat-markers:0";
+
+/// Last markable message of a conversation
+struct Markable {
+    id: String,
+    to: Jid,
+    type_: XmppParsersMessageType,
+}
+
+pub struct ReceiptsMod {
+    /// Markable messages not yet marked as displayed
+    markables: HashMap<(Account, BareJid), Markable>,
+}
+
+impl ReceiptsMod {
+    pub fn new() -> Self {
+        Self {
+            markables: HashMap::new(),
+        }
+    }
+
+    fn received(&self, to: Jid, id: String) -> Element {
+        let mut message = XmppParsersMessage::new(Some(to));
+        message.payloads.push(Received { id }.into());
+        message.into()
+    }
+
+    fn displayed(&self, markable: Markable) -> Element {
+        let mut message = XmppParsersMessage::new(Some(markable.to));
+        message.type_ = markable.type_;
+        message.payloads.push(
+            Element::builder("displayed", CHAT_MARKERS)
+                .attr("id", markable.id)
+                .build(),
+        );
+        message.into()
+    }
+
+    fn handle_message(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: XmppParsersMessage,
+    ) {
+        // Only messages with content are acknowledged
+        if message.bodies.is_empty() {
+            return;
+        }
+
+        let from = match &message.from {
+            Some(from) => from.clone(),
+            None => return,
+        };
+        let bare = BareJid::from(from.clone());
+
+        match message.type_ {
+            XmppParsersMessageType::Chat | XmppParsersMessageType::Normal => {
+                let id = match &message.id {
+                    Some(id) => id.clone(),
+                    None => return,
+                };
+
+                let requested = message
+                    .payloads
+                    .iter()
+                    .any(|p| Request::try_from(p.clone()).is_ok());
+                if requested && aparte.config.privacy.receipts(&bare) {
+                    aparte.send(account, self.received(from.clone(), id.clone()));
+                }
+
+                if message
+                    .payloads
+                    .iter()
+                    .any(|p| p.is("markable", CHAT_MARKERS))
+                {
+                    self.markables.insert(
+                        (account.clone(), bare.clone()),
+                        Markable {
+                            id,
+                            to: from,
+                            type_: XmppParsersMessageType::Chat,
+                        },
+                    );
+                }
+            }
+            XmppParsersMessageType::Groupchat => {
+                // In channels messages are referenced by the id stamped by the room
+                let room: Jid = bare.clone().into();
+                let stanza_id = message
+                    .payloads
+                    .iter()
+                    .filter_map(|p| StanzaId::try_from(p.clone()).ok())
+                    .find(|stanza_id| stanza_id.by == room);
+
+                if let Some(stanza_id) = stanza_id {
+                    if message
+                        .payloads
+                        .iter()
+                        .any(|p| p.is("markable", CHAT_MARKERS))
+                    {
+                        self.markables.insert(
+                            (account.clone(), bare.clone()),
+                            Markable {
+                                id: stanza_id.id,
+                                to: room,
+                                type_: XmppParsersMessageType::Groupchat,
+                            },
+                        );
+                    }
+                }
+            }
+            _ => return,
+        }
+
+        let current = {
+            let ui = aparte.get_mod::<crate::mods::ui::UIMod>();
+            ui.current_window().cloned()
+        };
+        if current == Some(bare.to_string()) {
+            self.mark_displayed(aparte, account, &bare);
+        }
+    }
+
+    fn mark_displayed(&mut self, aparte: &mut Aparte, account: &Account, jid: &BareJid) {
+        if let Some(markable) = self.markables.remove(&(account.clone(), jid.clone())) {
+            if !aparte.config.low_bandwidth && aparte.config.privacy.markers(jid) {
+                aparte.send(account, self.displayed(markable));
+            }
+        }
+    }
+}
+
+impl ModTrait for ReceiptsMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        let privacy = aparte.config.privacy.clone();
+        let mut disco = aparte.get_mod_mut::<disco::DiscoMod>();
+        // Features are advertised to everyone as soon as a contact or room overrides them
+        if privacy.anywhere(privacy.receipts, |o| o.receipts) {
+            disco.add_feature(ns::RECEIPTS)?;
+        }
+        if privacy.anywhere(privacy.markers, |o| o.markers) {
+            disco.add_feature(CHAT_MARKERS)?;
+        }
+        if privacy.anywhere(privacy.chat_states, |o| o.chat_states) {
+            disco.add_feature(ns::CHATSTATES)?;
+        }
+
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Stanza(account, stanza) if stanza.is("message", ns::DEFAULT_NS) => {
+                if let Ok(message) = XmppParsersMessage::try_from(stanza.clone()) {
+                    self.handle_message(aparte, account, message);
+                }
+            }
+            // Answering means the conversation has been read
+            Event::SendMessage(account, Message::Xmpp(message)) => {
+                self.mark_displayed(aparte, account, &message.to);
+            }
+            Event::ChangeWindow(window) => {
+                if let Ok(jid) = BareJid::from_str(window) {
+                    let accounts = self
+                        .markables
+                        .keys()
+                        .filter(|(_, conversation)| conversation == &jid)
+                        .map(|(account, _)| account.clone())
+                        .collect::<Vec<_>>();
+                    for account in accounts {
+                        self.mark_displayed(aparte, &account, &jid);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for ReceiptsMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0184, XEP-0333: Message Receipts and Chat Markers")
+    }
+}
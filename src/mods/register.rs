@@ -0,0 +1,618 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Create an account with in-band registration (XEP-0077), Aparté being the first client used.
+//!
+//! Registering happens on a stream of its own, negotiated up to TLS only as servers answer the
+//! registration requests of unauthenticated clients. The fields of the registration form are asked
+//! one after the other in the input, private ones without being shown, then the account created
+//! is connected.
+use futures::{SinkExt, StreamExt};
+use native_tls::TlsConnector as NativeTlsConnector;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::str::FromStr;
+use tokio::net::TcpStream;
+use tokio_native_tls::{TlsConnector, TlsStream};
+use tokio_xmpp::xmpp_stream::XMPPStream;
+use tokio_xmpp::Packet;
+use uuid::Uuid;
+use xmpp_parsers::ibr::Query;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::stanza_error::{DefinedCondition, StanzaError};
+use xmpp_parsers::{ns, Element, Jid};
+use zeroize::Zeroize;
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait, Password};
+use crate::dns::Resolver;
+use crate::form::{self, Question};
+
+/// Client port of servers without SRV records
+const PORT: u16 = 5222;
+
+type Stream = XMPPStream<TlsStream<TcpStream>>;
+
+command_def!(register,
+r#"/register <server> [<answer>]
+
+    server        Server to create the account on
+    answer        Answer to the field asked, typed in the input when asked
+
+Description:
+    Create an account with in-band registration (XEP-0077). The fields of the
+    registration form of the server are asked one after the other in the input,
+    passwords without being shown, then the account is connected. An empty
+    answer to a required field cancels the registration.
+
+Example:
+    /register example.org"#,
+{
+    server: String,
+    answer: Option<Password<String>>
+},
+|aparte, command| {
+    match answer {
+        None => {
+            let resolver = aparte.resolver()?;
+            let fetch = {
+                let mut register = aparte.get_mod_mut::<RegisterMod>();
+                register.start(&server, resolver)?
+            };
+            aparte.spawn(fetch);
+            aparte.log(format!("Fetching the registration form of {}", server));
+        }
+        Some(answer) => {
+            // The answer can be a password
+            for arg in command.args.iter_mut() {
+                arg.zeroize();
+            }
+            let step = {
+                let mut register = aparte.get_mod_mut::<RegisterMod>();
+                register.answer(&server, answer)?
+            };
+            step.run(aparte);
+        }
+    }
+    Ok(())
+});
+
+/// Fields to fill, in the order they are asked, with the instructions of the server if any
+fn questions(query: &Query) -> (Vec<Question>, Option<String>) {
+    match &query.form {
+        Some(form) => (form::questions(form), form.instructions.clone()),
+        None => {
+            // All the fields of the legacy form are required
+            let mut vars: Vec<&String> = query
+                .fields
+                .keys()
+                .filter(|var| var.as_str() != "instructions")
+                .collect();
+            vars.sort_by_key(|var| match var.as_str() {
+                "username" => (0, var.as_str()),
+                "password" => (1, var.as_str()),
+                _ => (2, var.as_str()),
+            });
+            let questions = vars
+                .into_iter()
+                .map(|var| Question {
+                    var: var.clone(),
+                    label: var.clone(),
+                    required: true,
+                    private: var == "password",
+                    multi: false,
+                    default: Vec::new(),
+                    options: Vec::new(),
+                })
+                .collect();
+            (questions, query.fields.get("instructions").cloned())
+        }
+    }
+}
+
+/// Registration form filled with the answers, hidden fields being returned untouched
+fn submission(query: &Query, answers: &[(Question, Password<String>)]) -> Query {
+    match &query.form {
+        Some(form) => {
+            let answers = answers
+                .iter()
+                .filter_map(|(question, answer)| {
+                    let values = question.values(&answer.0).ok()?;
+                    Some((question.var.clone(), values))
+                })
+                .collect();
+            Query {
+                fields: HashMap::new(),
+                registered: false,
+                remove: false,
+                form: Some(form::submission(form, &answers)),
+            }
+        }
+        None => Query {
+            fields: answers
+                .iter()
+                .map(|(question, answer)| (question.var.clone(), answer.0.clone()))
+                .collect(),
+            registered: false,
+            remove: false,
+            form: None,
+        },
+    }
+}
+
+/// Why the server refused the registration
+fn refusal(err: &StanzaError) -> String {
+    match (&err.defined_condition, err.texts.get("en")) {
+        (_, Some(text)) => text.clone(),
+        (DefinedCondition::Conflict, None) => "username already taken".to_string(),
+        (DefinedCondition::NotAcceptable, None) => "some fields are missing".to_string(),
+        (DefinedCondition::NotAllowed, None) => "registration is disabled".to_string(),
+        (condition, None) => format!("{:?}", condition),
+    }
+}
+
+/// Open a stream to the server, encrypted but not authenticated
+async fn connect(resolver: Resolver, server: &str) -> Result<Stream, String> {
+    let jid = Jid::from_str(server).map_err(|err| err.to_string())?;
+    let addresses = resolver
+        .lookup_service(server, "_xmpp-client._tcp", PORT)
+        .await?;
+    let tcp = TcpStream::connect(addresses.as_slice())
+        .await
+        .map_err(|err| format!("Cannot connect to {}: {}", server, err))?;
+    let mut stream = XMPPStream::start(tcp, jid.clone(), ns::JABBER_CLIENT.to_owned())
+        .await
+        .map_err(|err| err.to_string())?;
+    if !stream.stream_features.can_starttls() {
+        return Err(format!("{} doesn't support TLS", server));
+    }
+
+    let starttls = Element::builder("starttls", ns::TLS).build();
+    stream
+        .send(Packet::Stanza(starttls))
+        .await
+        .map_err(|err| err.to_string())?;
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) if stanza.is("proceed", ns::TLS) => break,
+            Some(Ok(Packet::Text(_))) => {}
+            Some(Err(err)) => return Err(err.to_string()),
+            _ => return Err(format!("{} refused to start TLS", server)),
+        }
+    }
+
+    let connector = NativeTlsConnector::new().map_err(|err| err.to_string())?;
+    let tls = TlsConnector::from(connector)
+        .connect(server, stream.into_inner())
+        .await
+        .map_err(|err| err.to_string())?;
+    XMPPStream::start(tls, jid, ns::JABBER_CLIENT.to_owned())
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Send a request on the stream and wait for its response
+async fn request(stream: &mut Stream, iq: Iq) -> Result<Option<Element>, String> {
+    let id = iq.id.clone();
+    stream
+        .send(Packet::Stanza(iq.into()))
+        .await
+        .map_err(|err| err.to_string())?;
+    loop {
+        match stream.next().await {
+            Some(Ok(Packet::Stanza(stanza))) if stanza.attr("id") == Some(id.as_str()) => {
+                let iq = Iq::try_from(stanza).map_err(|err| err.to_string())?;
+                return match iq.payload {
+                    IqType::Result(payload) => Ok(payload),
+                    IqType::Error(err) => Err(refusal(&err)),
+                    _ => Err("Unexpected request from the server".to_string()),
+                };
+            }
+            Some(Ok(Packet::Stanza(_))) | Some(Ok(Packet::Text(_))) => {}
+            Some(Err(err)) => return Err(err.to_string()),
+            _ => return Err("Connection closed by the server".to_string()),
+        }
+    }
+}
+
+/// Connect to the server and ask its registration form
+async fn fetch(resolver: Resolver, server: &str) -> Result<(Stream, Query), String> {
+    let mut stream = connect(resolver, server).await?;
+    let get = Iq::from_get(Uuid::new_v4().to_hyphenated().to_string(), empty_query());
+    let query = match request(&mut stream, get).await? {
+        Some(payload) => Query::try_from(payload).map_err(|err| err.to_string())?,
+        None => return Err(format!("{} sent an empty registration form", server)),
+    };
+    if query.registered {
+        return Err(format!("Already registered on {}", server));
+    }
+    Ok((stream, query))
+}
+
+fn empty_query() -> Query {
+    Query {
+        fields: HashMap::new(),
+        registered: false,
+        remove: false,
+        form: None,
+    }
+}
+
+/// What to do next in a registration
+enum Step {
+    /// Ask a field, the answer being appended to the command
+    Ask {
+        prompt: String,
+        command: Command,
+        private: bool,
+    },
+    /// Submit the form filled
+    Submit(Pin<Box<dyn Future<Output = Event>>>),
+    /// Nothing left to do
+    Done(String),
+}
+
+impl Step {
+    /// Log a text before asking the next field
+    fn after(self, text: String) -> Self {
+        match self {
+            Step::Ask {
+                prompt,
+                command,
+                private,
+            } => Step::Ask {
+                prompt: format!("{}\n{}", text, prompt),
+                command,
+                private,
+            },
+            step => step,
+        }
+    }
+
+    fn run(self, aparte: &mut Aparte) {
+        match self {
+            Step::Ask {
+                prompt,
+                command,
+                private,
+            } => {
+                aparte.log(prompt);
+                match private {
+                    true => aparte.schedule(Event::ReadPassword(command)),
+                    false => aparte.schedule(Event::ReadInput(command)),
+                }
+            }
+            Step::Submit(submit) => aparte.spawn(submit),
+            Step::Done(message) => aparte.log(message),
+        }
+    }
+}
+
+struct Registration {
+    server: String,
+    /// Stream to the server, handed back by the request fetching the form
+    stream: Rc<RefCell<Option<Stream>>>,
+    /// Form of the server, None until fetched
+    form: Option<Query>,
+    /// Fields left to ask
+    questions: Vec<Question>,
+    answers: Vec<(Question, Password<String>)>,
+}
+
+pub struct RegisterMod {
+    registration: Option<Registration>,
+}
+
+impl RegisterMod {
+    pub fn new() -> Self {
+        Self { registration: None }
+    }
+
+    /// Start a registration, returns the request of the form to spawn
+    fn start(
+        &mut self,
+        server: &str,
+        resolver: Resolver,
+    ) -> Result<impl Future<Output = Event>, String> {
+        match Jid::from_str(server) {
+            Ok(Jid::Bare(jid)) if jid.node.is_none() => {}
+            _ => return Err(format!("Invalid server {}", server)),
+        }
+        let stream = Rc::new(RefCell::new(None));
+        self.registration = Some(Registration {
+            server: server.to_string(),
+            stream: Rc::clone(&stream),
+            form: None,
+            questions: Vec::new(),
+            answers: Vec::new(),
+        });
+        let server = server.to_string();
+        Ok(async move {
+            let result = fetch(resolver, &server).await.map(|(connected, query)| {
+                stream.replace(Some(connected));
+                query
+            });
+            Event::RegistrationForm { server, result }
+        })
+    }
+
+    fn received(&mut self, server: &str, query: &Query) -> Step {
+        let registration = match &mut self.registration {
+            Some(registration) if registration.server == server && registration.form.is_none() => {
+                registration
+            }
+            _ => return Step::Done(format!("Registration on {} was cancelled", server)),
+        };
+        let (questions, instructions) = questions(query);
+        registration.form = Some(query.clone());
+        registration.questions = questions;
+        match instructions {
+            Some(instructions) => self.step().after(instructions),
+            None => self.step(),
+        }
+    }
+
+    fn answer(&mut self, server: &str, answer: Password<String>) -> Result<Step, String> {
+        let registration = match &mut self.registration {
+            Some(registration) if registration.server == server => registration,
+            _ => return Err(format!("No registration in progress on {}", server)),
+        };
+        if registration.questions.is_empty() {
+            return Err(format!("Nothing asked for the registration on {}", server));
+        }
+        let question = registration.questions.remove(0);
+        if let Err(err) = question.values(&answer.0) {
+            registration.questions.insert(0, question);
+            return Ok(self.step().after(err));
+        }
+        if answer.0.is_empty() && question.default.is_empty() {
+            if question.required {
+                self.registration = None;
+                return Ok(Step::Done(format!("Registration on {} cancelled", server)));
+            }
+        } else {
+            registration.answers.push((question, answer));
+        }
+        Ok(self.step())
+    }
+
+    /// Ask the next field, or submit the form once they are all filled
+    fn step(&mut self) -> Step {
+        let registration = match &mut self.registration {
+            Some(registration) => registration,
+            None => return Step::Done("No registration in progress".to_string()),
+        };
+        let server = registration.server.clone();
+        if let Some(question) = registration.questions.first() {
+            let prompt = question.prompt();
+            let command = Command {
+                account: None,
+                context: String::from("console"),
+                args: vec![String::from("register"), server],
+                cursor: 0,
+            };
+            return Step::Ask {
+                prompt,
+                command,
+                private: question.private,
+            };
+        }
+
+        let stream = registration.stream.borrow_mut().take();
+        let mut stream = match stream {
+            Some(stream) => stream,
+            None => {
+                self.registration = None;
+                return Step::Done(format!("Connection to {} lost", server));
+            }
+        };
+        let form = registration.form.as_ref().unwrap();
+        let set = Iq::from_set(
+            Uuid::new_v4().to_hyphenated().to_string(),
+            submission(form, &registration.answers),
+        );
+        Step::Submit(Box::pin(async move {
+            let result = request(&mut stream, set).await.map(|_| ());
+            let _ = stream.send(Packet::StreamEnd).await;
+            Event::Registered { server, result }
+        }))
+    }
+
+    /// Connect the account just created, when the form had the usual fields
+    fn registered(&mut self, server: &str) -> Option<Command> {
+        let registration = match self.registration.take() {
+            Some(registration) if registration.server == server => registration,
+            other => {
+                self.registration = other;
+                return None;
+            }
+        };
+        let answer = |var: &str| {
+            registration
+                .answers
+                .iter()
+                .find(|(question, _)| question.var == var)
+                .map(|(_, answer)| answer.0.clone())
+        };
+        let jid = format!("{}@{}", answer("username")?, server);
+        let password = answer("password")?;
+        Some(Command {
+            account: None,
+            context: String::from("console"),
+            args: vec![String::from("connect"), jid, password],
+            cursor: 0,
+        })
+    }
+}
+
+impl ModTrait for RegisterMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(register::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::RegistrationForm { server, result } => match result {
+                Ok(query) => self.received(server, query).run(aparte),
+                Err(err) => {
+                    self.registration = None;
+                    aparte.log(format!("Cannot register on {}: {}", server, err));
+                }
+            },
+            Event::Registered { server, result } => match result {
+                Ok(()) => match self.registered(server) {
+                    Some(connect) => {
+                        aparte.log(format!("Account {} created", connect.args[1]));
+                        aparte.schedule(Event::Command(connect));
+                    }
+                    None => aparte.log(format!("Account created on {}", server)),
+                },
+                Err(err) => {
+                    self.registration = None;
+                    aparte.log(format!("Cannot register on {}: {}", server, err));
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for RegisterMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0077: In-Band Registration")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+    use termion::event::Key;
+    use xmpp_parsers::data_forms::DataFormType;
+
+    fn form() -> Query {
+        Query::try_from(
+            Element::from_str(
+                "<query xmlns='jabber:iq:register'>
+                    <x xmlns='jabber:x:data' type='form'>
+                        <instructions>Choose a username and password</instructions>
+                        <field type='hidden' var='FORM_TYPE'>
+                            <value>jabber:iq:register</value>
+                        </field>
+                        <field type='text-single' label='Username' var='username'>
+                            <required/>
+                        </field>
+                        <field type='text-private' label='Password' var='password'>
+                            <required/>
+                        </field>
+                        <field type='text-single' label='Email' var='email'/>
+                    </x>
+                </query>",
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_legacy_fields_are_asked_username_and_password_first() {
+        // Given
+        let query = Query::try_from(
+            Element::from_str(
+                "<query xmlns='jabber:iq:register'>
+                    <instructions>Fill the fields</instructions>
+                    <email/>
+                    <password/>
+                    <username/>
+                </query>",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        // When
+        let (questions, instructions) = questions(&query);
+
+        // Then
+        let vars: Vec<&str> = questions.iter().map(|q| q.var.as_str()).collect();
+        assert_eq!(vars, vec!["username", "password", "email"]);
+        assert!(questions[1].private);
+        assert_eq!(instructions, Some(String::from("Fill the fields")));
+    }
+
+    #[test]
+    fn test_form_is_submitted_with_answers_and_hidden_fields() {
+        // Given
+        let query = form();
+        let (questions, _) = questions(&query);
+        let answers = vec![
+            (questions[0].clone(), Password(String::from("romeo"))),
+            (questions[1].clone(), Password(String::from("juliet"))),
+        ];
+
+        // When
+        let submitted = submission(&query, &answers);
+
+        // Then
+        let form = submitted.form.unwrap();
+        assert_eq!(form.type_, DataFormType::Submit);
+        let values: Vec<(&str, &[String])> = form
+            .fields
+            .iter()
+            .map(|field| (field.var.as_str(), field.values.as_slice()))
+            .collect();
+        assert_eq!(form.form_type.as_deref(), Some(ns::REGISTER));
+        assert_eq!(
+            values,
+            vec![
+                ("username", &[String::from("romeo")][..]),
+                ("password", &[String::from("juliet")][..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fields_are_asked_in_the_input() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            let resolver = harness.aparte.resolver().unwrap();
+            {
+                let mut register = harness.aparte.get_mod_mut::<RegisterMod>();
+                // The form is received without connecting to the server
+                drop(register.start("example.org", resolver).unwrap());
+            }
+
+            // When
+            harness.aparte.schedule(Event::RegistrationForm {
+                server: String::from("example.org"),
+                result: Ok(form()),
+            });
+            harness.settle().await;
+            for answer in ["romeo", "juliet", ""].iter() {
+                for c in answer.chars() {
+                    harness.aparte.schedule(Event::Key(Key::Char(c)));
+                }
+                harness.aparte.schedule(Event::Key(Key::Char('\n')));
+                harness.settle().await;
+            }
+
+            // Then
+            let screen = harness.screen();
+            assert!(screen.contains("Choose a username and password"));
+            assert!(screen.contains("Email (optional):"));
+            assert!(!screen.contains("juliet"));
+            // The stream of the fake server was never opened
+            assert!(screen.contains("Connection to example.org lost"));
+            let register = harness.aparte.get_mod::<RegisterMod>();
+            assert!(register.registration.is_none());
+        });
+    }
+}
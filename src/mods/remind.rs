@@ -0,0 +1,440 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveTime, TimeZone};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use xmpp_parsers::BareJid;
+
+use crate::account::Account;
+use crate::color;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::{Message, VersionedXmppMessage};
+use crate::mods;
+use crate::mods::ui::SearchResult;
+
+/// Time of the day used when only a day is given
+const MORNING: (u32, u32) = (9, 0);
+
+command_def!(
+    remind,
+    r#"/remind <when> [<note>]
+
+    when          A delay like 2h, 1h30m or in 20m, a time like 18:30, or a
+                  day like tomorrow or 2021-03-14 optionally followed by a time
+    note          What to be reminded of
+
+Description:
+    Raise a notification and log the note in the console when the time comes.
+    The message selected with Ctrl-p and Ctrl-n in the current conversation,
+    if any, is attached to the reminder and shown again with
+    /reminders show <id>. Reminders are forgotten when Aparté exits.
+
+Examples:
+    /remind 2h
+    /remind in 20m check the build
+    /remind tomorrow 9:00 "answer Alice"
+    /remind 2021-03-14 18:30 pi day"#,
+    {},
+    |aparte, _command| {
+        let (due, used) = parse_when(&_command.args[1..], Local::now())?;
+        let note = match _command.args[1 + used..].join(" ") {
+            note if note.is_empty() => None,
+            note => Some(note),
+        };
+
+        let (id, delay, bound) = {
+            let mut remind = aparte.get_mod_mut::<RemindMod>();
+            let message = match (&_command.account, BareJid::from_str(&_command.context)) {
+                (Some(account), Ok(conversation)) => remind
+                    .selected
+                    .get(&(account.clone(), conversation))
+                    .map(|message| (account.clone(), message.clone())),
+                _ => None,
+            };
+            let bound = message.is_some();
+            let id = remind.add(Reminder { due, note, message });
+            (id, due - Local::now(), bound)
+        };
+
+        aparte.spawn(async move {
+            tokio::time::sleep(delay.to_std().unwrap_or_default()).await;
+            Event::Reminder(id)
+        });
+        aparte.log(format!(
+            "Reminder {} set for {}{}",
+            id,
+            due.format("%Y-%m-%d %H:%M"),
+            match bound {
+                true => " on the selected message",
+                false => "",
+            }
+        ));
+        Ok(())
+    }
+);
+
+command_def!(reminders,
+r#"/reminders list|show|cancel"#,
+{
+    action: Command = {
+        children: {
+            "list": reminders_list,
+            "show": reminders_show,
+            "cancel": reminders_cancel,
+        }
+    },
+});
+
+command_def!(
+    reminders_list,
+    r#"/reminders list
+
+Description:
+    List the reminders that didn't go off yet."#,
+    {},
+    |aparte, _command| {
+        let list = {
+            let remind = aparte.get_mod::<RemindMod>();
+            remind
+                .pending
+                .iter()
+                .map(|(id, reminder)| format!("  {} {}", id, reminder))
+                .collect::<Vec<String>>()
+        };
+        match list.is_empty() {
+            true => aparte.log("No pending reminder".to_string()),
+            false => aparte.log(format!("Pending reminders:\n{}", list.join("\n"))),
+        }
+        Ok(())
+    }
+);
+
+command_def!(reminders_show,
+r#"/reminders show <id>
+
+    id            Id of the reminder
+
+Description:
+    Show the message attached to a reminder in its conversation.
+
+Examples:
+    /reminders show 2"#,
+{
+    id: usize = {
+        completion: (|aparte, _command| {
+            let remind = aparte.get_mod::<RemindMod>();
+            remind.ids().iter().map(|id| id.to_string()).collect()
+        })
+    }
+},
+|aparte, _command| {
+    let (account, message) = {
+        let remind = aparte.get_mod::<RemindMod>();
+        let reminder = remind.get(id).ok_or(format!("Unknown reminder {}", id))?;
+        reminder.message.clone().ok_or(format!("No message attached to reminder {}", id))?
+    };
+    aparte.schedule(Event::ShowMessage {
+        account,
+        conversation: message.conversation().clone(),
+        message: Message::Xmpp(message),
+    });
+    Ok(())
+});
+
+command_def!(reminders_cancel,
+r#"/reminders cancel <id>
+
+    id            Id of the reminder
+
+Description:
+    Cancel a reminder that didn't go off yet.
+
+Examples:
+    /reminders cancel 2"#,
+{
+    id: usize = {
+        completion: (|aparte, _command| {
+            let remind = aparte.get_mod::<RemindMod>();
+            remind.pending.keys().map(|id| id.to_string()).collect()
+        })
+    }
+},
+|aparte, _command| {
+    let cancelled = {
+        let mut remind = aparte.get_mod_mut::<RemindMod>();
+        remind.pending.remove(&id)
+    };
+    match cancelled {
+        Some(_) => aparte.log(format!("Reminder {} cancelled", id)),
+        None => return Err(format!("No pending reminder {}", id)),
+    }
+    Ok(())
+});
+
+/// Delay made of numbers followed by a unit, like 1h30m
+fn parse_delay(delay: &str) -> Option<Duration> {
+    let mut total = Duration::zero();
+    let mut number = String::new();
+    for c in delay.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let count = number.parse::<i64>().ok()?;
+        number.clear();
+        total += match c {
+            'd' => Duration::try_days(count)?,
+            'h' => Duration::try_hours(count)?,
+            'm' => Duration::try_minutes(count)?,
+            's' => Duration::try_seconds(count)?,
+            _ => return None,
+        };
+    }
+    match number.is_empty() && total > Duration::zero() {
+        true => Some(total),
+        false => None,
+    }
+}
+
+/// When a reminder should go off and how many arguments describe it, times are local
+fn parse_when(args: &[String], now: DateTime<Local>) -> Result<(DateTime<Local>, usize), String> {
+    let words = args.iter().map(String::as_str).collect::<Vec<&str>>();
+    let invalid = || {
+        format!(
+            "Invalid time {}, expected a delay like 2h or a time like 18:30",
+            words.first().unwrap_or(&"")
+        )
+    };
+    let time =
+        |time: Option<&&str>| time.and_then(|time| NaiveTime::parse_from_str(time, "%H:%M").ok());
+    let morning = NaiveTime::from_hms_opt(MORNING.0, MORNING.1, 0).unwrap();
+    let today = now.date_naive();
+
+    let (day, at, used) = match words.as_slice() {
+        [] => return Err("Missing when argument".to_string()),
+        ["in", delay, ..] => {
+            let delay = parse_delay(delay).ok_or_else(invalid)?;
+            return Ok((now + delay, 2));
+        }
+        ["tomorrow", rest @ ..] => {
+            let tomorrow = today.succ_opt().ok_or_else(invalid)?;
+            match time(rest.first()) {
+                Some(at) => (tomorrow, at, 2),
+                None => (tomorrow, morning, 1),
+            }
+        }
+        [first, rest @ ..] => {
+            if let Some(delay) = parse_delay(first) {
+                return Ok((now + delay, 1));
+            }
+            match (
+                NaiveDate::parse_from_str(first, "%Y-%m-%d"),
+                time(Some(first)),
+            ) {
+                (Ok(day), _) => match time(rest.first()) {
+                    Some(at) => (day, at, 2),
+                    None => (day, morning, 1),
+                },
+                // Times already past today are for tomorrow
+                (Err(_), Some(at)) if at > now.time() => (today, at, 1),
+                (Err(_), Some(at)) => (today.succ_opt().ok_or_else(invalid)?, at, 1),
+                (Err(_), None) => return Err(invalid()),
+            }
+        }
+    };
+
+    let due = Local
+        .from_local_datetime(&day.and_time(at))
+        .earliest()
+        .ok_or_else(invalid)?;
+    match due > now {
+        true => Ok((due, used)),
+        false => Err(format!("{} is already past", due.format("%Y-%m-%d %H:%M"))),
+    }
+}
+
+struct Reminder {
+    due: DateTime<Local>,
+    note: Option<String>,
+    /// Message selected when the reminder was set
+    message: Option<(Account, VersionedXmppMessage)>,
+}
+
+impl fmt::Display for Reminder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.due.format("%Y-%m-%d %H:%M"))?;
+        if let Some(note) = &self.note {
+            write!(f, " {}", note)?;
+        }
+        if let Some((_, message)) = &self.message {
+            write!(f, " (message in {})", message.conversation())?;
+        }
+        Ok(())
+    }
+}
+
+pub struct RemindMod {
+    next_id: usize,
+    pending: BTreeMap<usize, Reminder>,
+    /// Reminders that went off, kept so that their message can still be shown
+    fired: BTreeMap<usize, Reminder>,
+    /// Message selected in each conversation window
+    selected: HashMap<(Account, BareJid), VersionedXmppMessage>,
+}
+
+impl RemindMod {
+    pub fn new() -> Self {
+        Self {
+            next_id: 1,
+            pending: BTreeMap::new(),
+            fired: BTreeMap::new(),
+            selected: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, reminder: Reminder) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, reminder);
+        id
+    }
+
+    fn get(&self, id: usize) -> Option<&Reminder> {
+        self.pending.get(&id).or_else(|| self.fired.get(&id))
+    }
+
+    fn ids(&self) -> Vec<usize> {
+        self.pending
+            .keys()
+            .chain(self.fired.keys())
+            .cloned()
+            .collect()
+    }
+
+    fn fire(&mut self, aparte: &mut Aparte, id: usize) {
+        // Cancelled reminders still wake up
+        let reminder = match self.pending.remove(&id) {
+            Some(reminder) => reminder,
+            None => return,
+        };
+
+        let mut lines = vec![format!(
+            "Reminder {}: {}",
+            id,
+            reminder.note.as_deref().unwrap_or("time's up")
+        )];
+        if let Some((account, message)) = &reminder.message {
+            let result = SearchResult {
+                account: account.clone(),
+                snippet: mods::search::snippet(message.get_last_body(), ""),
+                message: message.clone(),
+            };
+            lines.push(format!("  {}", result));
+            lines.push(format!(
+                "  {}",
+                color::dimmed(&format!("show it with: /reminders show {}", id))
+            ));
+        }
+        aparte.log(lines.join("\n"));
+        aparte.schedule(Event::Notification(lines[0].clone()));
+        self.fired.insert(id, reminder);
+    }
+}
+
+impl ModTrait for RemindMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(remind::new());
+        aparte.add_command(reminders::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::MessageSelected {
+                account,
+                conversation,
+                message,
+            } => {
+                let key = (account.clone(), conversation.clone());
+                match message {
+                    Some(Message::Xmpp(message)) => {
+                        self.selected.insert(key, message.clone());
+                    }
+                    _ => {
+                        self.selected.remove(&key);
+                    }
+                }
+            }
+            Event::Reminder(id) => self.fire(aparte, *id),
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for RemindMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reminders")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2021, 3, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_delays() {
+        // Given
+        let now = at(14, 18, 0);
+
+        // When
+        let delays = [
+            parse_when(&args(&["2h", "call", "Bob"]), now),
+            parse_when(&args(&["in", "1h30m", "call", "Bob"]), now),
+            parse_when(&args(&["in", "soon"]), now),
+        ];
+
+        // Then
+        assert_eq!(delays[0], Ok((at(14, 20, 0), 1)));
+        assert_eq!(delays[1], Ok((at(14, 19, 30), 2)));
+        assert!(delays[2].is_err());
+    }
+
+    #[test]
+    fn test_parse_times() {
+        // Given
+        let now = at(14, 18, 0);
+
+        // When
+        let times = [
+            parse_when(&args(&["tomorrow", "9:30", "standup"]), now),
+            parse_when(&args(&["tomorrow", "standup"]), now),
+            parse_when(&args(&["18:30"]), now),
+            parse_when(&args(&["08:00"]), now),
+            parse_when(&args(&["2021-03-20", "12:00"]), now),
+            parse_when(&args(&["2021-03-01"]), now),
+            parse_when(&args(&["call", "Bob"]), now),
+        ];
+
+        // Then
+        assert_eq!(times[0], Ok((at(15, 9, 30), 2)));
+        assert_eq!(times[1], Ok((at(15, 9, 0), 1)));
+        assert_eq!(times[2], Ok((at(14, 18, 30), 1)));
+        assert_eq!(times[3], Ok((at(15, 8, 0), 1)));
+        assert_eq!(times[4], Ok((at(20, 12, 0), 2)));
+        assert!(times[5].is_err());
+        assert!(times[6].is_err());
+    }
+}
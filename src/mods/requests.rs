@@ -0,0 +1,306 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use xmpp_parsers::{BareJid, Jid};
+
+use crate::account::Account;
+use crate::color;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::{Direction, Message, XmppMessageType};
+use crate::mods;
+
+/// What to do with messages of people who aren't in the roster
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StrangersPolicy {
+    /// Open a conversation window like for any contact
+    #[default]
+    Open,
+    /// Hold messages in the requests window until the conversation is accepted
+    Requests,
+}
+
+/// Where an incoming message should go
+#[derive(Debug, PartialEq)]
+pub enum Routing {
+    /// To its conversation window
+    Deliver,
+    /// To the requests window, `first` is set for the first message of a new request
+    Hold { first: bool },
+    /// Nowhere, the sender was ignored
+    Drop,
+}
+
+command_def!(requests,
+r#"/requests list|accept|ignore"#,
+{
+    action: Command = {
+        children: {
+            "list": requests_list,
+            "accept": requests_accept,
+            "ignore": requests_ignore,
+        }
+    },
+});
+
+command_def!(
+    requests_list,
+    r#"/requests list
+
+Description:
+    List people outside of the roster waiting for their conversation to be
+    accepted."#,
+    {},
+    |aparte, _command| {
+        let list = {
+            let requests = aparte.get_mod::<RequestsMod>();
+            requests
+                .pending
+                .iter()
+                .map(|((account, jid), messages)| {
+                    format!("  {} ({} messages) on {}", jid, messages.len(), account)
+                })
+                .collect::<Vec<String>>()
+        };
+        match list.is_empty() {
+            true => aparte.log("No pending request".to_string()),
+            false => aparte.log(format!(
+                "Pending requests:\n{}\n{}",
+                list.join("\n"),
+                color::dimmed("answer with: /requests accept|ignore <jid>")
+            )),
+        }
+        Ok(())
+    }
+);
+
+command_def!(requests_accept,
+r#"/requests accept <jid>
+
+    jid           Address of the person to talk with
+
+Description:
+    Open the conversation with someone outside of the roster and show the
+    messages they already sent.
+
+Examples:
+    /requests accept stranger@example.org"#,
+{
+    jid: BareJid = {
+        completion: (|aparte, _command| {
+            let requests = aparte.get_mod::<RequestsMod>();
+            requests.pending.keys().map(|(_, jid)| jid.to_string()).collect()
+        })
+    }
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let messages = {
+        let mut requests = aparte.get_mod_mut::<RequestsMod>();
+        requests.accept(&account, &jid)
+    };
+    aparte.schedule(Event::Chat { account: account.clone(), contact: jid });
+    for message in messages {
+        aparte.schedule(Event::Message(Some(account.clone()), message));
+    }
+    Ok(())
+});
+
+command_def!(requests_ignore,
+r#"/requests ignore <jid>
+
+    jid           Address of the person to ignore
+
+Description:
+    Drop the messages of someone outside of the roster, along with the ones
+    they send until Aparté is restarted.
+
+Examples:
+    /requests ignore spammer@example.org"#,
+{
+    jid: BareJid = {
+        completion: (|aparte, _command| {
+            let requests = aparte.get_mod::<RequestsMod>();
+            requests.pending.keys().map(|(_, jid)| jid.to_string()).collect()
+        })
+    }
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let dropped = {
+        let mut requests = aparte.get_mod_mut::<RequestsMod>();
+        requests.ignore(&account, &jid)
+    };
+    aparte.log(format!("Ignoring {} ({} messages dropped)", jid, dropped));
+    Ok(())
+});
+
+pub struct RequestsMod {
+    /// Messages held until their conversation is accepted
+    pending: HashMap<(Account, BareJid), Vec<Message>>,
+    /// Strangers whose messages are delivered anyway
+    accepted: HashSet<(Account, BareJid)>,
+    /// Strangers whose messages are dropped
+    ignored: HashSet<(Account, BareJid)>,
+}
+
+impl RequestsMod {
+    pub fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+            accepted: HashSet::new(),
+            ignored: HashSet::new(),
+        }
+    }
+
+    /// Decide where an incoming message goes, holding it when it comes from a stranger
+    pub fn route(
+        &mut self,
+        aparte: &Aparte,
+        account: &Option<Account>,
+        message: &Message,
+    ) -> Routing {
+        if aparte.config.strangers == StrangersPolicy::Open {
+            return Routing::Deliver;
+        }
+
+        let (account, xmpp) = match (account, message) {
+            (Some(account), Message::Xmpp(xmpp))
+                if xmpp.direction == Direction::Incoming && xmpp.type_ == XmppMessageType::Chat =>
+            {
+                (account, xmpp)
+            }
+            _ => return Routing::Deliver,
+        };
+
+        let known = {
+            let contacts = aparte.get_mod::<mods::contact::ContactMod>();
+            contacts.is_contact(account, &xmpp.from)
+        };
+        self.hold(account, &xmpp.from, known, message)
+    }
+
+    fn hold(
+        &mut self,
+        account: &Account,
+        from: &BareJid,
+        known: bool,
+        message: &Message,
+    ) -> Routing {
+        let key = (account.clone(), from.clone());
+        let own = BareJid::from(Jid::Full(account.clone()));
+        if self.ignored.contains(&key) {
+            Routing::Drop
+        } else if known || self.accepted.contains(&key) || own == *from {
+            Routing::Deliver
+        } else {
+            let messages = self.pending.entry(key).or_default();
+            messages.push(message.clone());
+            Routing::Hold {
+                first: messages.len() == 1,
+            }
+        }
+    }
+
+    /// Deliver future messages of a stranger and return the held ones
+    fn accept(&mut self, account: &Account, jid: &BareJid) -> Vec<Message> {
+        let key = (account.clone(), jid.clone());
+        self.ignored.remove(&key);
+        self.accepted.insert(key.clone());
+        self.pending.remove(&key).unwrap_or_default()
+    }
+
+    /// Drop messages of a stranger and return how many were held
+    fn ignore(&mut self, account: &Account, jid: &BareJid) -> usize {
+        let key = (account.clone(), jid.clone());
+        self.accepted.remove(&key);
+        self.ignored.insert(key.clone());
+        self.pending
+            .remove(&key)
+            .map(|messages| messages.len())
+            .unwrap_or(0)
+    }
+}
+
+impl ModTrait for RequestsMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(requests::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, _aparte: &mut Aparte, event: &Event) {
+        match event {
+            // Talking to someone is accepting their messages
+            Event::Chat { account, contact } => {
+                self.accepted.insert((account.clone(), contact.clone()));
+            }
+            Event::SendMessage(account, Message::Xmpp(message)) => {
+                self.accepted.insert((account.clone(), message.to.clone()));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for RequestsMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Conversation requests")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xmpp_parsers::FullJid;
+
+    #[test]
+    fn test_strangers_are_held_until_accepted() {
+        // Given
+        let mut requests = RequestsMod::new();
+        let account = FullJid::from_str("me@example.org/aparte").unwrap();
+        let stranger = BareJid::from_str("stranger@example.org").unwrap();
+        let friend = BareJid::from_str("friend@example.org").unwrap();
+        let message = Message::log(String::from("Hello"));
+
+        // When
+        let first = requests.hold(&account, &stranger, false, &message);
+        let second = requests.hold(&account, &stranger, false, &message);
+        let from_friend = requests.hold(&account, &friend, true, &message);
+        let held = requests.accept(&account, &stranger);
+
+        // Then
+        assert_eq!(first, Routing::Hold { first: true });
+        assert_eq!(second, Routing::Hold { first: false });
+        assert_eq!(from_friend, Routing::Deliver);
+        assert_eq!(held.len(), 2);
+        assert_eq!(
+            requests.hold(&account, &stranger, false, &message),
+            Routing::Deliver
+        );
+    }
+
+    #[test]
+    fn test_ignored_strangers_are_dropped() {
+        // Given
+        let mut requests = RequestsMod::new();
+        let account = FullJid::from_str("me@example.org/aparte").unwrap();
+        let spammer = BareJid::from_str("spammer@example.org").unwrap();
+        let message = Message::log(String::from("Buy now"));
+        requests.hold(&account, &spammer, false, &message);
+
+        // When
+        let dropped = requests.ignore(&account, &spammer);
+
+        // Then
+        assert_eq!(dropped, 1);
+        assert_eq!(
+            requests.hold(&account, &spammer, false, &message),
+            Routing::Drop
+        );
+    }
+}
@@ -0,0 +1,1515 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::delay::Delay;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::message::{
+    Message as XmppParsersMessage, MessageType as XmppParsersMessageType, Subject,
+};
+use xmpp_parsers::muc::user::{MucUser, Status};
+use xmpp_parsers::presence::{Presence, Type as PresenceType};
+use xmpp_parsers::stanza_error::{DefinedCondition, StanzaError};
+use xmpp_parsers::{BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::color;
+use crate::command::{Command, CommandParser};
+use crate::conversation::{Affiliation, Channel, Conversation, Role};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::i18n;
+use crate::message::{Message, VersionedXmppMessage};
+use crate::mods;
+
+const MUC_ADMIN: &str = "http://jabber.org/protocol/muc#admin";
+const FASTEN: &str = "urn:xmpp:fasten:0";
+const MODERATE: &str = "urn:xmpp:message-moderate:0";
+const RETRACT: &str = "urn:xmpp:message-retract:0";
+
+/// Channel of the window the command is issued in
+pub fn current_channel(aparte: &Aparte, context: &str) -> Result<Channel, String> {
+    let account = aparte
+        .current_account()
+        .ok_or("No connection found".to_string())?;
+    let jid = BareJid::from_str(context)
+        .map_err(|_| "This command can only be used in a channel window".to_string())?;
+    let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+    match conversations.get(&account, &jid) {
+        Some(Conversation::Channel(channel)) => Ok(channel.clone()),
+        _ => Err("This command can only be used in a channel window".to_string()),
+    }
+}
+
+fn list(aparte: &mut Aparte, context: &str, affiliation: Affiliation) -> Result<(), String> {
+    let channel = current_channel(aparte, context)?;
+    let request = {
+        let mut room = aparte.get_mod_mut::<RoomMod>();
+        room.request_list(&channel.jid, affiliation)
+    };
+    aparte.send(&channel.account, request);
+    Ok(())
+}
+
+/// Ask the current channel to change the affiliation of a user, when we are allowed to
+fn set(
+    aparte: &mut Aparte,
+    context: &str,
+    jid: BareJid,
+    affiliation: Affiliation,
+    reason: Option<String>,
+) -> Result<(), String> {
+    let channel = current_channel(aparte, context)?;
+    let own = own_affiliation(&channel);
+    let current = channel
+        .occupants
+        .values()
+        .find(|occupant| occupant.jid.as_ref() == Some(&jid))
+        .map(|occupant| occupant.affiliation)
+        .unwrap_or(Affiliation::None);
+    if !can_change(own, current) || !can_change(own, affiliation) {
+        return Err(format!(
+            "You are not allowed to change the affiliation of {} to {} in {}",
+            jid, affiliation, channel.jid
+        ));
+    }
+
+    let request = {
+        let mut room = aparte.get_mod_mut::<RoomMod>();
+        room.request_set(&channel.jid, jid, affiliation, reason)
+    };
+    aparte.send(&channel.account, request);
+    Ok(())
+}
+
+command_def!(
+    room_members,
+    r#"/room members
+
+Description:
+    List members of the current channel."#,
+    {},
+    |aparte, _command| { list(aparte, &_command.context, Affiliation::Member) }
+);
+
+command_def!(
+    room_admins,
+    r#"/room admins
+
+Description:
+    List admins of the current channel."#,
+    {},
+    |aparte, _command| { list(aparte, &_command.context, Affiliation::Admin) }
+);
+
+command_def!(
+    room_owners,
+    r#"/room owners
+
+Description:
+    List owners of the current channel."#,
+    {},
+    |aparte, _command| { list(aparte, &_command.context, Affiliation::Owner) }
+);
+
+command_def!(
+    room_banned,
+    r#"/room banned
+
+Description:
+    List users banned from the current channel."#,
+    {},
+    |aparte, _command| { list(aparte, &_command.context, Affiliation::Outcast) }
+);
+
+command_def!(room_set,
+r#"/room set <jid> <affiliation> [<reason>]
+
+    jid           User to change the affiliation of
+    affiliation   New affiliation: owner, admin, member, outcast or none
+    reason        Optional reason given to the user
+
+Description:
+    Change the affiliation of a user in the current channel.
+
+Examples:
+    /room set user@server.tld member
+    /room set user@server.tld outcast "Spamming"
+    /room set user@server.tld none"#,
+{
+    jid: BareJid,
+    affiliation: Affiliation = {
+        completion: (|_aparte, _command| {
+            ["owner", "admin", "member", "outcast", "none"].iter().map(|a| a.to_string()).collect()
+        })
+    },
+    reason: Option<String>
+},
+|aparte, _command| { set(aparte, &_command.context, jid, affiliation, reason) });
+
+command_def!(room,
+r#"/room members|admins|owners|banned|set"#,
+{
+    action: Command = {
+        children: {
+            "members": room_members,
+            "admins": room_admins,
+            "owners": room_owners,
+            "banned": room_banned,
+            "set": room_set,
+        }
+    },
+});
+
+command_def!(affiliation,
+r#"/affiliation <affiliation> <jid> [<reason>]
+
+    affiliation   New affiliation: owner, admin, member, outcast or none
+    jid           User to change the affiliation of
+    reason        Optional reason given to the user
+
+Description:
+    Change the affiliation of a user in the current channel, like /room set.
+
+Examples:
+    /affiliation member user@server.tld
+    /affiliation outcast user@server.tld "Spamming""#,
+{
+    affiliation: Affiliation = {
+        completion: (|_aparte, _command| {
+            ["owner", "admin", "member", "outcast", "none"].iter().map(|a| a.to_string()).collect()
+        })
+    },
+    jid: BareJid,
+    reason: Option<String>
+},
+|aparte, _command| { set(aparte, &_command.context, jid, affiliation, reason) });
+
+command_def!(affiliations,
+r#"/affiliations list"#,
+{
+    action: Command = {
+        children: {
+            "list": affiliations_list,
+        }
+    },
+});
+
+command_def!(affiliations_list,
+r#"/affiliations list [<affiliation>]
+
+    affiliation   Affiliation to list: owner, admin, member or outcast, all of
+                  them if omitted
+
+Description:
+    List the users having an affiliation in the current channel.
+
+Examples:
+    /affiliations list
+    /affiliations list outcast"#,
+{
+    affiliation: Option<Affiliation> = {
+        completion: (|_aparte, _command| {
+            ["owner", "admin", "member", "outcast"].iter().map(|a| a.to_string()).collect()
+        })
+    }
+},
+|aparte, _command| {
+    match affiliation {
+        Some(Affiliation::None) => Err("Users without affiliation can't be listed".to_string()),
+        Some(affiliation) => list(aparte, &_command.context, affiliation),
+        None => {
+            for affiliation in [
+                Affiliation::Owner,
+                Affiliation::Admin,
+                Affiliation::Member,
+                Affiliation::Outcast,
+            ]
+            .iter()
+            {
+                list(aparte, &_command.context, *affiliation)?;
+            }
+            Ok(())
+        }
+    }
+});
+
+command_def!(kick,
+r#"/kick <nick> [<reason>]
+
+    nick          Occupant to kick
+    reason        Optional reason shown to the occupants
+
+Description:
+    Kick an occupant out of the current channel, they can join it again.
+    Only moderators can do so.
+
+Examples:
+    /kick tybalt
+    /kick tybalt "Calm down""#,
+{
+    nick: String = {
+        completion: (|aparte, _command| {
+            match current_channel(aparte, &_command.context) {
+                Ok(channel) => channel.occupants.keys().cloned().collect(),
+                Err(_) => Vec::new(),
+            }
+        })
+    },
+    reason: Option<String>
+},
+|aparte, _command| {
+    let channel = current_channel(aparte, &_command.context)?;
+    if own_role(&channel) != Role::Moderator {
+        return Err(format!("You are not a moderator of {}", channel.jid));
+    }
+    if !channel.occupants.contains_key(&nick) {
+        return Err(format!("{} isn't in {}", nick, channel.jid));
+    }
+
+    let request = {
+        let mut room = aparte.get_mod_mut::<RoomMod>();
+        room.request_kick(&channel.jid, nick, reason)
+    };
+    aparte.send(&channel.account, request);
+    Ok(())
+});
+
+command_def!(ban,
+r#"/ban <jid> [<reason>]
+
+    jid           User to ban
+    reason        Optional reason given to the user
+
+Description:
+    Ban a user from the current channel, kicking them out if they are in it.
+    Only admins and owners can do so, /affiliation none lifts the ban.
+
+Examples:
+    /ban tybalt@example.org
+    /ban tybalt@example.org "Spamming""#,
+{
+    jid: BareJid = {
+        completion: (|aparte, _command| {
+            match current_channel(aparte, &_command.context) {
+                Ok(channel) => channel
+                    .occupants
+                    .values()
+                    .filter_map(|occupant| occupant.jid.as_ref().map(|jid| jid.to_string()))
+                    .collect(),
+                Err(_) => Vec::new(),
+            }
+        })
+    },
+    reason: Option<String>
+},
+|aparte, _command| { set(aparte, &_command.context, jid, Affiliation::Outcast, reason) });
+
+command_def!(topic,
+r#"/topic [<subject>]
+
+    subject       New subject of the channel, shown if omitted
+
+Description:
+    Show or change the subject of the current channel. An empty subject
+    removes it.
+
+Examples:
+    /topic
+    /topic "Masked ball tonight"
+    /topic """#,
+{
+    subject: Option<String>
+},
+|aparte, _command| {
+    let channel = current_channel(aparte, &_command.context)?;
+    match subject {
+        None => {
+            let notice = {
+                let room = aparte.get_mod::<RoomMod>();
+                match room.subjects.get(&(channel.account.clone(), channel.jid.clone())) {
+                    Some(subject) if !subject.is_empty() => format!("Topic: {}", subject),
+                    _ => "No topic".to_string(),
+                }
+            };
+            notify(aparte, &channel.account, &channel.jid, notice);
+        }
+        Some(subject) => {
+            // The channel tells everyone, us included, once changed
+            let mut message = XmppParsersMessage::new(Some(Jid::Bare(channel.jid.clone())));
+            message.type_ = XmppParsersMessageType::Groupchat;
+            message.id = Some(Uuid::new_v4().to_hyphenated().to_string());
+            message.subjects.insert(String::new(), Subject(subject));
+            aparte.send(&channel.account, message.into());
+        }
+    }
+    Ok(())
+});
+
+command_def!(moderate,
+r#"/moderate [<reason>]
+
+    reason        Optional reason shown to the occupants
+
+Description:
+    Ask the current channel to remove the message selected with Ctrl-p and
+    Ctrl-n for everyone (XEP-0425). Only moderators can do so.
+
+Examples:
+    /moderate
+    /moderate "Off topic""#,
+{
+    reason: Option<String>
+},
+|aparte, _command| {
+    let channel = current_channel(aparte, &_command.context)?;
+    if own_role(&channel) != Role::Moderator {
+        return Err(format!("You are not a moderator of {}", channel.jid));
+    }
+
+    let request = {
+        let mut room = aparte.get_mod_mut::<RoomMod>();
+        let message = room
+            .selected
+            .get(&(channel.account.clone(), channel.jid.clone()))
+            .ok_or("Select the message to moderate with Ctrl-p and Ctrl-n".to_string())?;
+        let stanza_id = message
+            .stanza_id
+            .clone()
+            .ok_or(format!("{} didn't give an id to this message, it can't be moderated", channel.jid))?;
+        room.request_moderate(&channel.jid, stanza_id, reason)
+    };
+    aparte.send(&channel.account, request);
+    Ok(())
+});
+
+command_def!(nick,
+r#"/nick [<nick>]
+
+    nick          New nick, the default one of the channel if omitted
+
+Description:
+    Change your nick in the current channel. The default nick is, in order of
+    precedence, the one of the channel bookmark, the nick of the account in
+    the configuration, the global nick, and finally the name of the account.
+
+Examples:
+    /nick
+    /nick mercutio"#,
+{
+    nick: Option<String> = {
+        completion: (|aparte, _command| {
+            match current_channel(aparte, &_command.context) {
+                Ok(channel) => aparte.nicks(&channel.account, &channel.jid),
+                Err(_) => Vec::new(),
+            }
+        })
+    }
+},
+|aparte, _command| {
+    let channel = current_channel(aparte, &_command.context)?;
+    let nick = match nick {
+        Some(nick) => nick,
+        None => aparte
+            .nicks(&channel.account, &channel.jid)
+            .into_iter()
+            .next()
+            .ok_or(format!("No default nick for {}", channel.jid))?,
+    };
+    if nick == channel.nick {
+        return Err(format!("You are already {} in {}", nick, channel.jid));
+    }
+
+    // The room confirms with our presence under the new nick
+    let to = channel.jid.clone().with_resource(nick);
+    let presence = Presence::new(PresenceType::None).with_to(Jid::Full(to));
+    aparte.send(&channel.account, presence.into());
+    Ok(())
+});
+
+fn own_affiliation(channel: &Channel) -> Affiliation {
+    match channel.occupants.get(&channel.nick) {
+        Some(occupant) => occupant.affiliation,
+        None => Affiliation::None,
+    }
+}
+
+fn own_role(channel: &Channel) -> Role {
+    match channel.occupants.get(&channel.nick) {
+        Some(occupant) => occupant.role,
+        None => Role::None,
+    }
+}
+
+/// Show the outcome of a request in the channel window
+fn notify(aparte: &mut Aparte, account: &Account, room: &BareJid, notice: String) {
+    aparte.schedule(Event::Notice {
+        account: account.clone(),
+        conversation: room.clone(),
+        message: Message::log(notice),
+    });
+}
+
+/// Text of an error returned by the channel
+fn error_text(err: &StanzaError) -> String {
+    match err.texts.get("en") {
+        Some(text) => text.clone(),
+        None => format!("{:?}", err.defined_condition),
+    }
+}
+
+/// Why a channel refused us, and what to do about it
+fn join_error(room: &BareJid, nick: &str, err: &StanzaError) -> (String, String) {
+    let (reason, hint) = match err.defined_condition {
+        DefinedCondition::Forbidden => (
+            "you are banned".to_string(),
+            "Ask one of its admins to lift the ban.".to_string(),
+        ),
+        DefinedCondition::RegistrationRequired => (
+            "it is members-only".to_string(),
+            "Ask one of its admins for membership or for an invitation.".to_string(),
+        ),
+        DefinedCondition::Conflict => (
+            format!("the nickname {} is already used", nick),
+            format!("Join with another one: /join {}/<nick>", room),
+        ),
+        DefinedCondition::NotAuthorized => (
+            "it is protected by a password".to_string(),
+            "Joining channels protected by a password isn't supported yet.".to_string(),
+        ),
+        DefinedCondition::ServiceUnavailable => (
+            "it is full or unavailable".to_string(),
+            "Try again later.".to_string(),
+        ),
+        DefinedCondition::ItemNotFound
+        | DefinedCondition::NotAllowed
+        | DefinedCondition::RemoteServerNotFound => (
+            "it doesn't exist and can't be created".to_string(),
+            format!(
+                "Check its address, or list the channels of its service: /disco {}",
+                room.domain
+            ),
+        ),
+        _ => (error_text(err), "Try again later.".to_string()),
+    };
+    (format!("Cannot join {}: {}", room, reason), hint)
+}
+
+/// Whether a user with `own` affiliation can grant or revoke `affiliation`
+fn can_change(own: Affiliation, affiliation: Affiliation) -> bool {
+    match affiliation {
+        Affiliation::Owner | Affiliation::Admin => own == Affiliation::Owner,
+        Affiliation::Member | Affiliation::Outcast | Affiliation::None => {
+            own == Affiliation::Owner || own == Affiliation::Admin
+        }
+    }
+}
+
+enum Query {
+    List {
+        room: BareJid,
+        affiliation: Affiliation,
+    },
+    Set {
+        room: BareJid,
+        jid: BareJid,
+        affiliation: Affiliation,
+    },
+    Kick {
+        room: BareJid,
+        nick: String,
+    },
+    Moderate {
+        room: BareJid,
+    },
+}
+
+struct Entry {
+    jid: String,
+    nick: Option<String>,
+    reason: Option<String>,
+}
+
+pub struct RoomMod {
+    /// Pending muc#admin queries by iq id
+    queries: HashMap<String, Query>,
+    /// Message selected in each channel window, the one /moderate acts on
+    selected: HashMap<(Account, BareJid), VersionedXmppMessage>,
+    /// Subject of each channel, empty when removed
+    subjects: HashMap<(Account, BareJid), String>,
+    /// Channels joined, waiting for our own presence or an error
+    joining: HashSet<(Account, BareJid)>,
+}
+
+impl RoomMod {
+    pub fn new() -> Self {
+        Self {
+            queries: HashMap::new(),
+            selected: HashMap::new(),
+            subjects: HashMap::new(),
+            joining: HashSet::new(),
+        }
+    }
+
+    /// Tell whether the channel we were joining let us in
+    fn handle_join(&mut self, aparte: &mut Aparte, account: &Account, presence: &Presence) {
+        let from = match &presence.from {
+            Some(Jid::Full(from)) => from,
+            _ => return,
+        };
+        let room: BareJid = from.clone().into();
+        let key = (account.clone(), room.clone());
+        if !self.joining.contains(&key) {
+            return;
+        }
+        let channel = {
+            let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+            match conversations.get(account, &room) {
+                Some(Conversation::Channel(channel)) => channel.clone(),
+                _ => return,
+            }
+        };
+
+        if presence.type_ == PresenceType::Error {
+            let err = presence
+                .payloads
+                .iter()
+                .find_map(|payload| StanzaError::try_from(payload.clone()).ok());
+            if let Some(err) = err {
+                self.joining.remove(&key);
+                let (error, hint) = join_error(&room, &channel.nick, &err);
+                let notice = format!("{}\n{}", error, color::dimmed(&hint));
+                notify(aparte, account, &room, notice);
+                aparte.schedule(Event::Leave(channel));
+            }
+            return;
+        }
+
+        let own = from.resource == channel.nick
+            || presence.payloads.iter().any(|payload| {
+                MucUser::try_from(payload.clone())
+                    .map(|muc_user| muc_user.status.contains(&Status::SelfPresence))
+                    .unwrap_or(false)
+            });
+        if own && presence.type_ != PresenceType::Unavailable {
+            self.joining.remove(&key);
+            notify(aparte, account, &room, format!("Joined {}", room));
+        }
+    }
+
+    fn request_moderate(
+        &mut self,
+        room: &BareJid,
+        stanza_id: String,
+        reason: Option<String>,
+    ) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let mut moderate = Element::builder("moderate", MODERATE)
+            .append(Element::builder("retract", RETRACT).build())
+            .build();
+        if let Some(reason) = reason {
+            moderate.append_child(Element::builder("reason", MODERATE).append(reason).build());
+        }
+        let apply_to = Element::builder("apply-to", FASTEN)
+            .attr("id", stanza_id)
+            .append(moderate)
+            .build();
+        let iq = Iq {
+            from: None,
+            to: Some(Jid::Bare(room.clone())),
+            id: id.clone(),
+            payload: IqType::Set(apply_to),
+        };
+        self.queries
+            .insert(id, Query::Moderate { room: room.clone() });
+        iq.into()
+    }
+
+    /// Moderation announced by a joined channel, with the stanza id of the message removed
+    fn get_moderated<'a>(
+        aparte: &Aparte,
+        account: &Account,
+        message: &'a XmppParsersMessage,
+    ) -> Option<(BareJid, &'a str, &'a Element)> {
+        let room = match (&message.type_, &message.from) {
+            (XmppParsersMessageType::Groupchat, Some(Jid::Bare(room))) => room,
+            _ => return None,
+        };
+        let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+        match conversations.get(account, room) {
+            Some(Conversation::Channel(_)) => {}
+            _ => return None,
+        }
+        message.payloads.iter().find_map(|payload| {
+            let moderated = match payload.is("apply-to", FASTEN) {
+                true => payload.get_child("moderated", MODERATE)?,
+                false => return None,
+            };
+            Some((room.clone(), payload.attr("id")?, moderated))
+        })
+    }
+
+    /// Remember the subject of a channel and tell it in the channel window, with who changed it
+    /// unless it is the one received when joining
+    fn handle_subject(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        from: &Jid,
+        subjects: &HashMap<String, String>,
+    ) {
+        let room = BareJid::from(from.clone());
+        let subject = i18n::get_best(subjects, vec![])
+            .map(|(_, subject)| subject.clone())
+            .unwrap_or_default();
+        let notice = match (
+            self.subjects
+                .insert((account.clone(), room.clone()), subject.clone()),
+            from,
+        ) {
+            (None, _) if subject.is_empty() => return,
+            (None, _) => format!("Topic: {}", subject),
+            (Some(_), Jid::Full(from)) if subject.is_empty() => {
+                format!("{} removed the topic", from.resource)
+            }
+            (Some(_), Jid::Full(from)) => {
+                format!("{} changed the topic to: {}", from.resource, subject)
+            }
+            (Some(_), Jid::Bare(_)) if subject.is_empty() => "Topic removed".to_string(),
+            (Some(_), Jid::Bare(_)) => format!("Topic changed to: {}", subject),
+        };
+        notify(aparte, account, &room, notice);
+    }
+
+    fn request_list(&mut self, room: &BareJid, affiliation: Affiliation) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let query = Element::builder("query", MUC_ADMIN)
+            .append(
+                Element::builder("item", MUC_ADMIN)
+                    .attr("affiliation", affiliation.to_string())
+                    .build(),
+            )
+            .build();
+        let iq = Iq {
+            from: None,
+            to: Some(Jid::Bare(room.clone())),
+            id: id.clone(),
+            payload: IqType::Get(query),
+        };
+        self.queries.insert(
+            id,
+            Query::List {
+                room: room.clone(),
+                affiliation,
+            },
+        );
+        iq.into()
+    }
+
+    fn request_set(
+        &mut self,
+        room: &BareJid,
+        jid: BareJid,
+        affiliation: Affiliation,
+        reason: Option<String>,
+    ) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let mut item = Element::builder("item", MUC_ADMIN)
+            .attr("affiliation", affiliation.to_string())
+            .attr("jid", jid.to_string())
+            .build();
+        if let Some(reason) = reason {
+            item.append_child(Element::builder("reason", MUC_ADMIN).append(reason).build());
+        }
+        let query = Element::builder("query", MUC_ADMIN).append(item).build();
+        let iq = Iq {
+            from: None,
+            to: Some(Jid::Bare(room.clone())),
+            id: id.clone(),
+            payload: IqType::Set(query),
+        };
+        self.queries.insert(
+            id,
+            Query::Set {
+                room: room.clone(),
+                jid,
+                affiliation,
+            },
+        );
+        iq.into()
+    }
+
+    fn request_kick(&mut self, room: &BareJid, nick: String, reason: Option<String>) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let mut item = Element::builder("item", MUC_ADMIN)
+            .attr("nick", nick.clone())
+            .attr("role", "none")
+            .build();
+        if let Some(reason) = reason {
+            item.append_child(Element::builder("reason", MUC_ADMIN).append(reason).build());
+        }
+        let query = Element::builder("query", MUC_ADMIN).append(item).build();
+        let iq = Iq {
+            from: None,
+            to: Some(Jid::Bare(room.clone())),
+            id: id.clone(),
+            payload: IqType::Set(query),
+        };
+        self.queries.insert(
+            id,
+            Query::Kick {
+                room: room.clone(),
+                nick,
+            },
+        );
+        iq.into()
+    }
+
+    fn handle_list(
+        &self,
+        aparte: &mut Aparte,
+        account: &Account,
+        room: &BareJid,
+        affiliation: Affiliation,
+        query: &Element,
+    ) {
+        let entries = query
+            .children()
+            .filter(|item| item.is("item", MUC_ADMIN))
+            .filter_map(|item| {
+                Some(Entry {
+                    jid: item.attr("jid")?.to_string(),
+                    nick: item.attr("nick").map(|nick| nick.to_string()),
+                    reason: item
+                        .get_child("reason", MUC_ADMIN)
+                        .map(|reason| reason.text()),
+                })
+            })
+            .collect::<Vec<Entry>>();
+
+        let title = match affiliation {
+            Affiliation::Owner => "Owners",
+            Affiliation::Admin => "Admins",
+            Affiliation::Member => "Members",
+            Affiliation::Outcast => "Banned users",
+            Affiliation::None => "Users",
+        };
+
+        if entries.is_empty() {
+            aparte.log(format!("{} of {}: none", title, room));
+            return;
+        }
+
+        // Only suggest actions the user is allowed to perform
+        let allowed = {
+            let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+            match conversations.get(account, room) {
+                Some(Conversation::Channel(channel)) => {
+                    can_change(own_affiliation(channel), affiliation)
+                }
+                _ => false,
+            }
+        };
+        let action = match affiliation {
+            Affiliation::Outcast => "unban",
+            _ => "revoke",
+        };
+
+        let lines = entries
+            .iter()
+            .map(|entry| {
+                let mut line = format!("  {}", entry.jid);
+                if let Some(nick) = &entry.nick {
+                    line.push_str(&format!(" ({})", nick));
+                }
+                if let Some(reason) = &entry.reason {
+                    line.push_str(&format!(": {}", reason));
+                }
+                if allowed {
+                    line.push_str(&color::dimmed(&format!(
+                        "  {}: /room set {} none",
+                        action, entry.jid
+                    )));
+                }
+                line
+            })
+            .collect::<Vec<String>>();
+
+        aparte.log(format!(
+            "{} of {} ({}):\n{}",
+            title,
+            room,
+            entries.len(),
+            lines.join("\n")
+        ));
+    }
+}
+
+impl ModTrait for RoomMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(room::new());
+        aparte.add_command(affiliation::new());
+        aparte.add_command(affiliations::new());
+        aparte.add_command(kick::new());
+        aparte.add_command(ban::new());
+        aparte.add_command(topic::new());
+        aparte.add_command(moderate::new());
+        aparte.add_command(nick::new());
+        Ok(())
+    }
+
+    fn can_handle_xmpp_message(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+    ) -> f64 {
+        match Self::get_moderated(aparte, account, message) {
+            Some(_) => 1f64,
+            None => 0f64,
+        }
+    }
+
+    fn handle_xmpp_message(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+    ) {
+        let (room, stanza_id, moderated) = match Self::get_moderated(aparte, account, message) {
+            Some(moderated) => moderated,
+            None => return,
+        };
+        let moderator = match moderated.attr("by").map(Jid::from_str) {
+            Some(Ok(Jid::Full(by))) => by.resource,
+            _ => "a moderator".to_string(),
+        };
+        let notice = match moderated.get_child("reason", MODERATE) {
+            Some(reason) => format!("Message removed by {}: {}", moderator, reason.text()),
+            None => format!("Message removed by {}", moderator),
+        };
+
+        let redacted = {
+            let account = Some(account.clone());
+            let mut messages = aparte.get_mod_mut::<mods::messages::MessagesMod>();
+            let id = messages
+                .conversation(&account, &room)
+                .into_iter()
+                .find_map(|message| match message {
+                    Message::Xmpp(message) if message.stanza_id.as_deref() == Some(stanza_id) => {
+                        Some(message.id)
+                    }
+                    _ => None,
+                });
+            match id.and_then(|id| messages.get_mut(&account, &id)) {
+                Some(Message::Xmpp(message)) => {
+                    message.redact(notice);
+                    Some(Message::Xmpp(message.clone()))
+                }
+                _ => None,
+            }
+        };
+        if let Some(redacted) = redacted {
+            aparte.schedule(Event::Message(Some(account.clone()), redacted));
+        }
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::MessageSelected {
+                account,
+                conversation,
+                message,
+            } => {
+                let key = (account.clone(), conversation.clone());
+                match message {
+                    Some(Message::Xmpp(message)) => {
+                        self.selected.insert(key, message.clone());
+                    }
+                    _ => {
+                        self.selected.remove(&key);
+                    }
+                }
+            }
+            Event::Subject(account, from, subjects) => {
+                self.handle_subject(aparte, account, from, subjects)
+            }
+            Event::Joined {
+                account, channel, ..
+            } => {
+                let room: BareJid = channel.clone().into();
+                self.joining.insert((account.clone(), room.clone()));
+                notify(
+                    aparte,
+                    account,
+                    &room,
+                    format!("Joining {}… (waiting for presence)", room),
+                );
+            }
+            Event::Presence(account, presence) => self.handle_join(aparte, account, presence),
+            Event::Leave(channel) => {
+                let key = (channel.account.clone(), channel.jid.clone());
+                self.subjects.remove(&key);
+                self.joining.remove(&key);
+            }
+            Event::Disconnected(account, _) => {
+                self.joining.retain(|(other, _)| other != account);
+            }
+            Event::Iq(account, iq) => {
+                let query = match self.queries.remove(&iq.id) {
+                    Some(query) => query,
+                    None => return,
+                };
+
+                match (query, &iq.payload) {
+                    (Query::List { room, affiliation }, IqType::Result(Some(payload)))
+                        if payload.is("query", MUC_ADMIN) =>
+                    {
+                        self.handle_list(aparte, account, &room, affiliation, payload)
+                    }
+                    (
+                        Query::Set {
+                            room,
+                            jid,
+                            affiliation,
+                        },
+                        IqType::Result(_),
+                    ) => {
+                        let notice = match affiliation {
+                            Affiliation::Outcast => format!("{} banned", jid),
+                            affiliation => format!("{} is now {}", jid, affiliation),
+                        };
+                        notify(aparte, account, &room, notice);
+                        // Occupants are updated before the channel tells, if it does
+                        let occupants = {
+                            let mut conversations =
+                                aparte.get_mod_mut::<mods::conversation::ConversationMod>();
+                            conversations.set_affiliation(account, &room, &jid, affiliation)
+                        };
+                        for occupant in occupants {
+                            aparte.schedule(Event::Occupant {
+                                account: account.clone(),
+                                conversation: room.clone(),
+                                occupant,
+                            });
+                        }
+                    }
+                    (Query::Kick { room, nick }, IqType::Result(_)) => {
+                        notify(aparte, account, &room, format!("{} kicked", nick))
+                    }
+                    (Query::Moderate { room }, IqType::Result(_)) => {
+                        aparte.log(format!("Message moderated in {}", room))
+                    }
+                    (Query::Set { room, jid, .. }, IqType::Error(err)) => notify(
+                        aparte,
+                        account,
+                        &room,
+                        format!(
+                            "Cannot change the affiliation of {}: {}",
+                            jid,
+                            error_text(err)
+                        ),
+                    ),
+                    (Query::Kick { room, nick }, IqType::Error(err)) => notify(
+                        aparte,
+                        account,
+                        &room,
+                        format!("Cannot kick {}: {}", nick, error_text(err)),
+                    ),
+                    (Query::List { room, .. }, IqType::Error(_))
+                    | (Query::Moderate { room }, IqType::Error(_)) => {
+                        aparte.log(format!("Request refused by {}", room))
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for RoomMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0045: Multi-User Chat administration")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::{self, Harness};
+    use xmpp_parsers::ns;
+
+    async fn join(harness: &mut Harness, role: &str) {
+        harness.connect().await;
+        harness
+            .input("console", "/join room@conference.example.org")
+            .await;
+        harness
+            .receive(&format!(
+                "<presence xmlns='jabber:client' from='room@conference.example.org/romeo'
+                    to='{{account}}'>
+                    <x xmlns='http://jabber.org/protocol/muc#user'>
+                        <item affiliation='member' role='{}'/>
+                        <status code='110'/>
+                    </x>
+                </presence>",
+                role
+            ))
+            .await;
+        harness
+            .receive(
+                "<message xmlns='jabber:client' type='groupchat' id='m1'
+                    from='room@conference.example.org/tybalt' to='{account}'>
+                    <body>Buy cheap swords</body>
+                    <stanza-id xmlns='urn:xmpp:sid:0' id='s1' by='room@conference.example.org'/>
+                </message>",
+            )
+            .await;
+    }
+
+    #[test]
+    fn test_affiliation_change_rights() {
+        assert!(can_change(Affiliation::Owner, Affiliation::Admin));
+        assert!(can_change(Affiliation::Admin, Affiliation::Outcast));
+        assert!(can_change(Affiliation::Admin, Affiliation::None));
+        assert!(!can_change(Affiliation::Admin, Affiliation::Owner));
+        assert!(!can_change(Affiliation::Member, Affiliation::Member));
+        assert!(!can_change(Affiliation::None, Affiliation::Outcast));
+    }
+
+    #[test]
+    fn test_affiliation_change_updates_the_occupant() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            join(&mut harness, "moderator").await;
+            harness
+                .receive(
+                    "<presence xmlns='jabber:client' from='room@conference.example.org/romeo'
+                        to='{account}'>
+                        <x xmlns='http://jabber.org/protocol/muc#user'>
+                            <item affiliation='admin' role='moderator'/>
+                            <status code='110'/>
+                        </x>
+                    </presence>",
+                )
+                .await;
+            harness
+                .receive(
+                    "<presence xmlns='jabber:client' from='room@conference.example.org/benvolio'
+                        to='{account}'>
+                        <x xmlns='http://jabber.org/protocol/muc#user'>
+                            <item affiliation='none' role='participant'
+                                jid='benvolio@example.org/street'/>
+                        </x>
+                    </presence>",
+                )
+                .await;
+            harness.reply(
+                "query",
+                MUC_ADMIN,
+                "<iq xmlns='jabber:client' type='result' id='{id}'
+                    from='room@conference.example.org' to='{account}'/>",
+            );
+
+            // When
+            harness
+                .input(
+                    "room@conference.example.org",
+                    "/affiliation member benvolio@example.org",
+                )
+                .await;
+
+            // Then
+            let channel = current_channel(&harness.aparte, "room@conference.example.org").unwrap();
+            assert_eq!(
+                channel.occupants.get("benvolio").unwrap().affiliation,
+                Affiliation::Member
+            );
+            assert!(harness.screen().contains("&romeo"));
+        });
+    }
+
+    #[test]
+    fn test_affiliations_list_asks_every_affiliation() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            join(&mut harness, "participant").await;
+
+            // When
+            harness
+                .input("room@conference.example.org", "/affiliations list")
+                .await;
+
+            // Then
+            let affiliations = harness
+                .take_sent("query", MUC_ADMIN)
+                .iter()
+                .filter_map(|iq| {
+                    let query = iq.get_child("query", MUC_ADMIN)?;
+                    let item = query.get_child("item", MUC_ADMIN)?;
+                    item.attr("affiliation").map(String::from)
+                })
+                .collect::<Vec<String>>();
+            assert_eq!(affiliations, vec!["owner", "admin", "member", "outcast"]);
+        });
+    }
+
+    #[test]
+    fn test_kick_is_echoed_in_the_channel() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            join(&mut harness, "moderator").await;
+            harness.reply(
+                "query",
+                MUC_ADMIN,
+                "<iq xmlns='jabber:client' type='result' id='{id}'
+                    from='room@conference.example.org' to='{account}'/>",
+            );
+            harness
+                .receive(
+                    "<presence xmlns='jabber:client' from='room@conference.example.org/tybalt'
+                        to='{account}'>
+                        <x xmlns='http://jabber.org/protocol/muc#user'>
+                            <item affiliation='none' role='participant'/>
+                        </x>
+                    </presence>",
+                )
+                .await;
+
+            // When
+            harness
+                .input("room@conference.example.org", "/kick tybalt \"Calm down\"")
+                .await;
+
+            // Then
+            assert!(harness.screen().contains("tybalt kicked"));
+        });
+    }
+
+    #[test]
+    fn test_ban_requires_admin_affiliation() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            join(&mut harness, "moderator").await;
+
+            // When
+            harness
+                .input("room@conference.example.org", "/ban tybalt@example.org")
+                .await;
+
+            // Then
+            assert!(harness.take_sent("query", MUC_ADMIN).is_empty());
+        });
+    }
+
+    #[test]
+    fn test_subject_changes_are_shown_in_the_channel() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            join(&mut harness, "participant").await;
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='groupchat' id='s1'
+                        from='room@conference.example.org/juliet' to='{account}'>
+                        <subject>Masked ball</subject>
+                    </message>",
+                )
+                .await;
+
+            // When
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='groupchat' id='s2'
+                        from='room@conference.example.org/tybalt' to='{account}'>
+                        <subject>Duel at noon</subject>
+                    </message>",
+                )
+                .await;
+            harness.input("room@conference.example.org", "/topic").await;
+
+            // Then
+            let screen = harness.screen();
+            assert!(screen.contains("Topic: Masked ball"));
+            assert!(screen.contains("tybalt changed the topic to: Duel at noon"));
+            assert!(screen.contains("Topic: Duel at noon"));
+        });
+    }
+
+    #[test]
+    fn test_topic_changes_the_subject() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            join(&mut harness, "participant").await;
+
+            // When
+            harness
+                .input("room@conference.example.org", "/topic \"Duel at noon\"")
+                .await;
+
+            // Then
+            let messages = harness.take_sent("subject", ns::DEFAULT_NS);
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].attr("type"), Some("groupchat"));
+            assert_eq!(messages[0].attr("to"), Some("room@conference.example.org"));
+            assert_eq!(
+                messages[0]
+                    .get_child("subject", ns::DEFAULT_NS)
+                    .unwrap()
+                    .text(),
+                "Duel at noon"
+            );
+        });
+    }
+
+    #[test]
+    fn test_moderate_selected_message() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            join(&mut harness, "moderator").await;
+            let room = BareJid::from_str("room@conference.example.org").unwrap();
+            let message = harness
+                .aparte
+                .get_mod::<mods::messages::MessagesMod>()
+                .get(&Some(harness.account.clone()), &"m1".to_string())
+                .cloned();
+            harness.aparte.schedule(Event::MessageSelected {
+                account: harness.account.clone(),
+                conversation: room,
+                message,
+            });
+            harness.settle().await;
+
+            // When
+            harness
+                .input("room@conference.example.org", "/moderate Spam")
+                .await;
+
+            // Then
+            let requests = harness.take_sent("apply-to", FASTEN);
+            assert_eq!(requests.len(), 1);
+            assert_eq!(requests[0].attr("to"), Some("room@conference.example.org"));
+            let apply_to = requests[0].get_child("apply-to", FASTEN).unwrap();
+            assert_eq!(apply_to.attr("id"), Some("s1"));
+            let moderate = apply_to.get_child("moderate", MODERATE).unwrap();
+            assert!(moderate.has_child("retract", RETRACT));
+            assert_eq!(
+                moderate.get_child("reason", MODERATE).unwrap().text(),
+                "Spam"
+            );
+        });
+    }
+
+    #[test]
+    fn test_moderate_requires_moderator_role() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            join(&mut harness, "participant").await;
+
+            // When
+            harness
+                .input("room@conference.example.org", "/moderate")
+                .await;
+
+            // Then
+            assert!(harness.take_sent("apply-to", FASTEN).is_empty());
+        });
+    }
+
+    #[test]
+    fn test_moderated_message_is_redacted() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            join(&mut harness, "participant").await;
+
+            // When
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='groupchat' id='m2'
+                        from='room@conference.example.org' to='{account}'>
+                        <apply-to xmlns='urn:xmpp:fasten:0' id='s1'>
+                            <moderated xmlns='urn:xmpp:message-moderate:0'
+                                by='room@conference.example.org/juliet'>
+                                <retract xmlns='urn:xmpp:message-retract:0'/>
+                                <reason>Spam</reason>
+                            </moderated>
+                        </apply-to>
+                        <body>This message has been moderated</body>
+                    </message>",
+                )
+                .await;
+
+            // Then
+            let room = BareJid::from_str("room@conference.example.org").unwrap();
+            let messages = harness
+                .aparte
+                .get_mod::<mods::messages::MessagesMod>()
+                .conversation(&Some(harness.account.clone()), &room);
+            match messages.as_slice() {
+                [Message::Xmpp(message)] => {
+                    assert_eq!(message.id, "m1");
+                    assert_eq!(message.get_last_body(), "Message removed by juliet: Spam");
+                }
+                messages => panic!("Unexpected messages {:?}", messages),
+            }
+            assert!(harness.screen().contains("Message removed by juliet: Spam"));
+        });
+    }
+
+    #[test]
+    fn test_join_waits_for_own_presence() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+
+            // When
+            harness
+                .input("console", "/join room@conference.example.org")
+                .await;
+
+            // Then
+            assert!(harness
+                .screen()
+                .contains("Joining room@conference.example.org… (waiting for presence)"));
+            let room = BareJid::from_str("room@conference.example.org").unwrap();
+            let key = (harness.account.clone(), room);
+            assert!(harness.aparte.get_mod::<RoomMod>().joining.contains(&key));
+
+            // When
+            harness
+                .receive(
+                    "<presence xmlns='jabber:client' from='room@conference.example.org/romeo'
+                        to='{account}'>
+                        <x xmlns='http://jabber.org/protocol/muc#user'>
+                            <item affiliation='member' role='participant'/>
+                            <status code='110'/>
+                        </x>
+                    </presence>",
+                )
+                .await;
+
+            // Then
+            assert!(harness
+                .screen()
+                .contains("Joined room@conference.example.org"));
+            assert!(!harness.aparte.get_mod::<RoomMod>().joining.contains(&key));
+        });
+    }
+
+    #[test]
+    fn test_join_refused_by_ban_is_explained() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness
+                .input("console", "/join room@conference.example.org")
+                .await;
+
+            // When
+            harness
+                .receive(
+                    "<presence xmlns='jabber:client' type='error'
+                        from='room@conference.example.org/romeo' to='{account}'>
+                        <x xmlns='http://jabber.org/protocol/muc'/>
+                        <error type='auth'>
+                            <forbidden xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+                        </error>
+                    </presence>",
+                )
+                .await;
+
+            // Then
+            let screen = harness.screen();
+            assert!(screen.contains("Cannot join room@conference.example.org: you are banned"));
+            assert!(screen.contains("Ask one of its admins to lift the ban."));
+            let room = BareJid::from_str("room@conference.example.org").unwrap();
+            let conversations = harness
+                .aparte
+                .get_mod::<mods::conversation::ConversationMod>();
+            assert!(conversations.get(&harness.account, &room).is_none());
+        });
+    }
+
+    #[test]
+    fn test_nick_changes_own_nick_in_channel() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            join(&mut harness, "participant").await;
+            harness.take_sent("presence", ns::DEFAULT_NS);
+
+            // When
+            harness
+                .input("room@conference.example.org", "/nick mercutio")
+                .await;
+            harness
+                .receive(
+                    "<presence xmlns='jabber:client' from='room@conference.example.org/mercutio'
+                        to='{account}'>
+                        <x xmlns='http://jabber.org/protocol/muc#user'>
+                            <item affiliation='member' role='participant'/>
+                            <status code='110'/>
+                        </x>
+                    </presence>",
+                )
+                .await;
+
+            // Then
+            let sent = harness.take_sent("presence", ns::DEFAULT_NS);
+            assert_eq!(sent.len(), 1);
+            assert_eq!(
+                sent[0].attr("to"),
+                Some("room@conference.example.org/mercutio")
+            );
+            let channel = current_channel(&harness.aparte, "room@conference.example.org").unwrap();
+            assert_eq!(channel.nick, "mercutio");
+        });
+    }
+
+    #[test]
+    fn test_global_nick_is_used_before_the_account_name() {
+        testing::run(async {
+            // Given
+            let config = Config {
+                nick: Some(String::from("montague")),
+                ..Config::default()
+            };
+            let mut harness = Harness::with_config(config);
+            harness.connect().await;
+            let room = BareJid::from_str("room@conference.example.org").unwrap();
+
+            // When
+            harness
+                .input("console", "/join room@conference.example.org")
+                .await;
+
+            // Then
+            let joins = harness.take_sent("x", "http://jabber.org/protocol/muc");
+            assert_eq!(
+                joins[0].attr("to"),
+                Some("room@conference.example.org/montague")
+            );
+            assert_eq!(
+                harness.aparte.nicks(&harness.account, &room),
+                vec!["montague", "romeo"]
+            );
+        });
+    }
+}
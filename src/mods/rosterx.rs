@@ -0,0 +1,331 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::iq::Iq;
+use xmpp_parsers::message::Message as XmppParsersMessage;
+use xmpp_parsers::presence::{Presence, Type as PresenceType};
+use xmpp_parsers::roster::{self, Roster};
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::mods;
+use crate::mods::disco;
+
+const ROSTERX: &str = "http://jabber.org/protocol/rosterx";
+
+command_def!(share_contact,
+r#"/share contact <contact>
+
+    contact       Contact to recommend
+
+Description:
+    Recommend a contact of your roster to the contact of the current window.
+
+Examples:
+    /share contact friend@server.tld"#,
+{
+    contact: BareJid = {
+        completion: (|aparte, _command| {
+            let contact = aparte.get_mod::<mods::contact::ContactMod>();
+            contact.contacts.values().map(|contact| contact.jid.to_string()).collect()
+        })
+    }
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let to = BareJid::from_str(&_command.context)
+        .map_err(|_| "Contacts can only be shared in a chat window".to_string())?;
+
+    let suggestion = {
+        let contacts = aparte.get_mod::<mods::contact::ContactMod>();
+        match contacts.contacts.values().find(|c| c.jid == contact) {
+            Some(known) => Suggestion {
+                jid: known.jid.clone(),
+                name: known.name.clone(),
+                groups: known.groups.iter().map(|group| group.0.clone()).collect(),
+            },
+            None => Suggestion {
+                jid: contact.clone(),
+                name: None,
+                groups: Vec::new(),
+            },
+        }
+    };
+
+    let message = {
+        let rosterx = aparte.get_mod::<RosterxMod>();
+        rosterx.suggest(to.clone().into(), &suggestion)
+    };
+    aparte.send(&account, message);
+    aparte.log(format!("Recommended {} to {}", contact, to));
+    Ok(())
+});
+
+command_def!(share_accept,
+r#"/share accept [<contact>]
+
+    contact       Suggested contact to add
+
+Description:
+    Add all or a given contact suggested by others to your roster.
+
+Examples:
+    /share accept
+    /share accept friend@server.tld"#,
+{
+    contact: Option<BareJid> = {
+        completion: (|aparte, _command| {
+            let rosterx = aparte.get_mod::<RosterxMod>();
+            rosterx.pending.values().flatten().map(|(_, suggestion)| suggestion.jid.to_string()).collect()
+        })
+    }
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let accepted = {
+        let mut rosterx = aparte.get_mod_mut::<RosterxMod>();
+        rosterx.take(&account, &contact)
+    };
+
+    if accepted.is_empty() {
+        return Err("No pending contact suggestion".to_string());
+    }
+
+    for suggestion in accepted {
+        let (add, subscribe) = {
+            let rosterx = aparte.get_mod::<RosterxMod>();
+            (rosterx.add(&suggestion), rosterx.subscribe(&suggestion))
+        };
+        aparte.send(&account, add);
+        aparte.send(&account, subscribe);
+        aparte.log(format!("Added {} to your roster", suggestion.jid));
+    }
+
+    Ok(())
+});
+
+command_def!(share_reject,
+r#"/share reject [<contact>]
+
+    contact       Suggested contact to ignore
+
+Description:
+    Ignore all or a given contact suggested by others.
+
+Examples:
+    /share reject
+    /share reject friend@server.tld"#,
+{
+    contact: Option<BareJid>
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let mut rosterx = aparte.get_mod_mut::<RosterxMod>();
+    rosterx.take(&account, &contact);
+    Ok(())
+});
+
+command_def!(share,
+r#"/share contact|accept|reject"#,
+{
+    action: Command = {
+        children: {
+            "contact": share_contact,
+            "accept": share_accept,
+            "reject": share_reject,
+        }
+    },
+});
+
+#[derive(Debug, Clone)]
+struct Suggestion {
+    jid: BareJid,
+    name: Option<String>,
+    groups: Vec<String>,
+}
+
+pub struct RosterxMod {
+    /// Suggested contacts waiting for approval, with the contact who suggested them
+    pending: HashMap<Account, Vec<(BareJid, Suggestion)>>,
+}
+
+impl RosterxMod {
+    pub fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+        }
+    }
+
+    fn suggest(&self, to: Jid, suggestion: &Suggestion) -> Element {
+        let mut item = Element::builder("item", ROSTERX)
+            .attr("action", "add")
+            .attr("jid", suggestion.jid.to_string())
+            .attr("name", suggestion.name.clone())
+            .build();
+        for group in &suggestion.groups {
+            item.append_child(
+                Element::builder("group", ROSTERX)
+                    .append(group.clone())
+                    .build(),
+            );
+        }
+
+        let mut message = XmppParsersMessage::new(Some(to));
+        message
+            .payloads
+            .push(Element::builder("x", ROSTERX).append(item).build());
+        message.into()
+    }
+
+    fn add(&self, suggestion: &Suggestion) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let item = roster::Item {
+            jid: suggestion.jid.clone(),
+            name: suggestion.name.clone(),
+            subscription: roster::Subscription::None,
+            ask: roster::Ask::None,
+            groups: suggestion
+                .groups
+                .iter()
+                .map(|group| roster::Group(group.clone()))
+                .collect(),
+        };
+        let iq = Iq::from_set(
+            id,
+            Roster {
+                ver: None,
+                items: vec![item],
+            },
+        );
+        iq.into()
+    }
+
+    fn subscribe(&self, suggestion: &Suggestion) -> Element {
+        let presence =
+            Presence::new(PresenceType::Subscribe).with_to(Jid::Bare(suggestion.jid.clone()));
+        presence.into()
+    }
+
+    /// Remove all or a given pending suggestion
+    fn take(&mut self, account: &Account, contact: &Option<BareJid>) -> Vec<Suggestion> {
+        let pending = match self.pending.get_mut(account) {
+            Some(pending) => pending,
+            None => return Vec::new(),
+        };
+
+        let (taken, kept) = pending
+            .drain(..)
+            .partition(|(_, suggestion)| match contact {
+                Some(contact) => &suggestion.jid == contact,
+                None => true,
+            });
+        *pending = kept;
+
+        taken
+            .into_iter()
+            .map(|(_, suggestion)| suggestion)
+            .collect()
+    }
+
+    fn handle_message(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: XmppParsersMessage,
+    ) {
+        let from = match &message.from {
+            Some(from) => BareJid::from(from.clone()),
+            None => return,
+        };
+
+        let known = {
+            let contacts = aparte.get_mod::<mods::contact::ContactMod>();
+            contacts
+                .contacts
+                .values()
+                .map(|contact| contact.jid.clone())
+                .collect::<Vec<BareJid>>()
+        };
+
+        let mut suggestions = Vec::new();
+        for payload in message.payloads.iter().filter(|p| p.is("x", ROSTERX)) {
+            for item in payload.children().filter(|c| c.is("item", ROSTERX)) {
+                // Only additions are supported, others are silently ignored
+                if item.attr("action").unwrap_or("add") != "add" {
+                    continue;
+                }
+
+                if let Some(Ok(jid)) = item.attr("jid").map(BareJid::from_str) {
+                    if known.contains(&jid) {
+                        continue;
+                    }
+                    suggestions.push(Suggestion {
+                        jid,
+                        name: item.attr("name").map(|name| name.to_string()),
+                        groups: item
+                            .children()
+                            .filter(|c| c.is("group", ROSTERX))
+                            .map(|group| group.text())
+                            .collect(),
+                    });
+                }
+            }
+        }
+
+        if suggestions.is_empty() {
+            return;
+        }
+
+        aparte.log(format!(
+            "{} suggests adding {} to your roster, use /share accept to add them or /share reject to ignore them",
+            from,
+            suggestions
+                .iter()
+                .map(|suggestion| match &suggestion.name {
+                    Some(name) => format!("{} ({})", suggestion.jid, name),
+                    None => suggestion.jid.to_string(),
+                })
+                .collect::<Vec<String>>()
+                .join(", ")
+        ));
+
+        let pending = self.pending.entry(account.clone()).or_default();
+        pending.extend(
+            suggestions
+                .into_iter()
+                .map(|suggestion| (from.clone(), suggestion)),
+        );
+    }
+}
+
+impl ModTrait for RosterxMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(share::new());
+        let mut disco = aparte.get_mod_mut::<disco::DiscoMod>();
+        disco.add_feature(ROSTERX)
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Stanza(account, stanza) if stanza.is("message", ns::DEFAULT_NS) => {
+                if let Ok(message) = XmppParsersMessage::try_from(stanza.clone()) {
+                    self.handle_message(aparte, account, message);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for RosterxMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0144: Roster Item Exchange")
+    }
+}
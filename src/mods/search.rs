@@ -0,0 +1,158 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::fmt;
+use std::str::FromStr;
+use xmpp_parsers::BareJid;
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::mods;
+use crate::mods::ui::SearchResult;
+
+/// Characters of context kept on each side of the searched text
+const CONTEXT: usize = 30;
+
+command_def!(search,
+r#"/search <text> [<conversation>]
+
+    text            Text to search, case insensitive
+    conversation    Bare jid of the conversation to search, or all. Defaults
+                    to the current conversation, or to all of them from the
+                    console
+
+Description:
+    Search the messages received, sent or loaded from archives since start.
+    Results are listed in the search window, select one with Ctrl-p and
+    Ctrl-n then press Enter to jump to it in its conversation.
+
+Examples:
+    /search release
+    /search "see you" all
+    /search meeting friend@example.org"#,
+{
+    text: String,
+    conversation: Option<String> = {
+        completion: (|_aparte, _command| {
+            vec![String::from("all")]
+        })
+    }
+},
+|aparte, _command| {
+    let scope = match conversation.as_deref() {
+        Some("all") => None,
+        Some(jid) => Some(
+            BareJid::from_str(jid).map_err(|_| format!("Invalid conversation {}", jid))?,
+        ),
+        None => {
+            // Only conversation windows are named after a jid
+            let current = BareJid::from_str(&_command.context).ok();
+            let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+            match (&_command.account, current) {
+                (Some(account), Some(jid)) if conversations.get(account, &jid).is_some() => {
+                    Some(jid)
+                }
+                _ => None,
+            }
+        }
+    };
+
+    let results = {
+        let messages = aparte.get_mod::<mods::messages::MessagesMod>();
+        messages.search(&text, scope.as_ref())
+    };
+    if results.is_empty() {
+        return Err(format!("No message contains {}", text));
+    }
+
+    aparte.log(format!("{} messages contain {}", results.len(), text));
+    let results = results
+        .into_iter()
+        .map(|(account, message)| SearchResult {
+            snippet: snippet(message.get_last_body(), &text),
+            account,
+            message,
+        })
+        .collect();
+    let mut ui = aparte.get_mod_mut::<mods::ui::UIMod>();
+    ui.show_search_results(results);
+    Ok(())
+});
+
+/// Line of a body containing the searched text, shortened around it
+pub fn snippet(body: &str, text: &str) -> String {
+    let text = text.to_lowercase();
+    let line = body
+        .lines()
+        .find(|line| line.to_lowercase().contains(&text))
+        .unwrap_or(body);
+
+    // Work on chars, lowercasing can change byte offsets
+    let chars = line.chars().collect::<Vec<char>>();
+    let lower = chars
+        .iter()
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect::<String>();
+    let start = match lower.find(&text) {
+        Some(index) => lower[..index].chars().count(),
+        None => 0,
+    };
+    let end = start + text.chars().count();
+
+    let from = start.saturating_sub(CONTEXT);
+    let to = usize::min(end + CONTEXT, chars.len());
+    let mut snippet = chars[from..to].iter().collect::<String>();
+    if from > 0 {
+        snippet = format!("…{}", snippet);
+    }
+    if to < chars.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+pub struct SearchMod {}
+
+impl SearchMod {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl ModTrait for SearchMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(search::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, _aparte: &mut Aparte, _event: &Event) {}
+}
+
+impl fmt::Display for SearchMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Message search")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snippet_is_shortened_around_the_text() {
+        // Given
+        let body = "First line\nThe next release will be out when it's ready, in a couple of weeks at most";
+
+        // When
+        let snippet = snippet(body, "RELEASE");
+
+        // Then
+        assert_eq!(snippet, "The next release will be out when it's ready, …");
+    }
+
+    #[test]
+    fn test_snippet_of_a_short_body() {
+        assert_eq!(snippet("Ça va ?", "va"), "Ça va ?");
+    }
+}
@@ -0,0 +1,257 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Check joined channels still count us among their occupants by pinging ourselves in them
+//! (XEP-0410), joining again the ones that forgot us, e.g. after their service restarted.
+//!
+//! Only channels silent for a while are pinged, any stanza coming from a channel telling it still
+//! knows us.
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::time;
+use uuid::Uuid;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::ping::Ping;
+use xmpp_parsers::stanza_error::DefinedCondition;
+use xmpp_parsers::{BareJid, Jid};
+
+use crate::account::Account;
+use crate::conversation::Conversation;
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::Message;
+use crate::mods;
+
+pub struct SelfPingMod {
+    /// Last stanza received from each joined channel
+    channels: HashMap<(Account, BareJid), Instant>,
+    /// Pings waiting for their answer by iq id
+    pending: HashMap<String, (Account, BareJid)>,
+    /// Whether the next check is scheduled
+    ticking: bool,
+}
+
+impl SelfPingMod {
+    pub fn new() -> Self {
+        Self {
+            channels: HashMap::new(),
+            pending: HashMap::new(),
+            ticking: false,
+        }
+    }
+
+    fn schedule_check(&mut self, aparte: &mut Aparte) {
+        let interval = aparte.config.stats.channel_ping_interval;
+        self.ticking = interval > 0;
+        if self.ticking {
+            aparte.spawn(async move {
+                time::sleep(Duration::from_secs(interval)).await;
+                Event::SelfPing
+            });
+        }
+    }
+
+    /// Ping our own occupant in the channels silent for longer than the interval
+    fn check(&mut self, aparte: &mut Aparte) {
+        let interval = Duration::from_secs(aparte.config.stats.channel_ping_interval);
+        let silent = self
+            .channels
+            .iter()
+            .filter(|(_, last)| last.elapsed() >= interval)
+            .map(|(channel, _)| channel.clone())
+            .collect::<Vec<_>>();
+        for (account, room) in silent {
+            let nick = {
+                let conversation = aparte.get_mod::<mods::conversation::ConversationMod>();
+                match conversation.get(&account, &room) {
+                    Some(Conversation::Channel(channel)) => channel.nick.clone(),
+                    _ => continue,
+                }
+            };
+            let to = Jid::Full(room.clone().with_resource(nick));
+            let channel = (account.clone(), room);
+            // Unanswered pings are inconclusive, they are replaced by the new one
+            self.pending.retain(|_, pending| pending != &channel);
+            let id = Uuid::new_v4().to_hyphenated().to_string();
+            self.pending.insert(id.clone(), channel);
+            aparte.send(&account, Iq::from_get(id, Ping).with_to(to).into());
+        }
+    }
+
+    /// Join a channel again when the answer to a ping tells we aren't an occupant anymore
+    fn pong(&mut self, aparte: &mut Aparte, account: &Account, room: BareJid, iq: &Iq) {
+        let condition = match &iq.payload {
+            IqType::Error(err) => &err.defined_condition,
+            _ => return,
+        };
+        match condition {
+            // Joined, but our other client doesn't answer pings or is changing nick
+            DefinedCondition::ServiceUnavailable
+            | DefinedCondition::FeatureNotImplemented
+            | DefinedCondition::ItemNotFound
+            // The channel couldn't be reached, we'll see on the next ping
+            | DefinedCondition::RemoteServerNotFound
+            | DefinedCondition::RemoteServerTimeout => return,
+            _ => {}
+        }
+
+        let nick = {
+            let conversation = aparte.get_mod::<mods::conversation::ConversationMod>();
+            match conversation.get(account, &room) {
+                Some(Conversation::Channel(channel)) => channel.nick.clone(),
+                _ => return,
+            }
+        };
+        self.channels.remove(&(account.clone(), room.clone()));
+        aparte.schedule(Event::Notice {
+            account: account.clone(),
+            conversation: room.clone(),
+            message: Message::log(format!("No longer in {}, joining again", room)),
+        });
+        aparte.schedule(Event::Join {
+            account: account.clone(),
+            channel: Jid::Full(room.with_resource(nick)),
+            user_request: false,
+        });
+    }
+}
+
+impl ModTrait for SelfPingMod {
+    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Joined {
+                account, channel, ..
+            } => {
+                let room: BareJid = channel.clone().into();
+                self.channels
+                    .insert((account.clone(), room), Instant::now());
+                if !self.ticking {
+                    self.schedule_check(aparte);
+                }
+            }
+            Event::Leave(channel) => {
+                let channel = (channel.account.clone(), channel.jid.clone());
+                self.channels.remove(&channel);
+                self.pending.retain(|_, pending| pending != &channel);
+            }
+            Event::Disconnected(account, _) => {
+                self.channels.retain(|(other, _), _| other != account);
+                self.pending.retain(|_, (other, _)| other != account);
+            }
+            Event::Stanza(account, stanza) => {
+                let from = stanza
+                    .attr("from")
+                    .and_then(|from| from.parse::<Jid>().ok());
+                if let Some(from) = from {
+                    let channel = (account.clone(), BareJid::from(from));
+                    if let Some(last) = self.channels.get_mut(&channel) {
+                        *last = Instant::now();
+                    }
+                }
+            }
+            Event::SelfPing => {
+                if !aparte.config.low_bandwidth {
+                    self.check(aparte);
+                }
+                self.schedule_check(aparte);
+            }
+            Event::Iq(account, iq) => {
+                if let Some((pinged, room)) = self.pending.remove(&iq.id) {
+                    if &pinged == account {
+                        self.pong(aparte, account, room, iq);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for SelfPingMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0410: MUC Self-Ping (Schrödinger's Chat)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+    use xmpp_parsers::ns;
+
+    async fn join_silent_channel(harness: &mut Harness) {
+        harness.connect().await;
+        harness
+            .input("console", "/join room@conference.example.org")
+            .await;
+        harness.take_sent("presence", ns::DEFAULT_NS);
+        // The server is pinged on connection
+        harness.take_sent("ping", ns::PING);
+        let mut selfping = harness.aparte.get_mod_mut::<SelfPingMod>();
+        for last in selfping.channels.values_mut() {
+            *last = Instant::now() - Duration::from_secs(3600);
+        }
+    }
+
+    #[test]
+    fn test_channel_forgetting_us_is_joined_again() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            join_silent_channel(&mut harness).await;
+            harness.reply(
+                "ping",
+                ns::PING,
+                "<iq xmlns='jabber:client' type='error' id='{id}'
+                    from='room@conference.example.org/romeo' to='{account}'>
+                    <error type='modify'>
+                        <not-acceptable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+                    </error>
+                </iq>",
+            );
+
+            // When
+            harness.aparte.schedule(Event::SelfPing);
+            harness.settle().await;
+
+            // Then
+            let presences = harness.take_sent("presence", ns::DEFAULT_NS);
+            assert_eq!(presences.len(), 1);
+            assert_eq!(
+                presences[0].attr("to"),
+                Some("room@conference.example.org/romeo")
+            );
+            assert!(harness
+                .screen()
+                .contains("No longer in room@conference.example.org, joining again"));
+        });
+    }
+
+    #[test]
+    fn test_active_channel_isnt_pinged() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            join_silent_channel(&mut harness).await;
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='groupchat' id='m1'
+                        from='room@conference.example.org/tybalt' to='{account}'>
+                        <body>Buy cheap swords</body>
+                    </message>",
+                )
+                .await;
+
+            // When
+            harness.aparte.schedule(Event::SelfPing);
+            harness.settle().await;
+
+            // Then
+            assert!(harness.take_sent("ping", ns::PING).is_empty());
+        });
+    }
+}
@@ -0,0 +1,278 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Stream management (XEP-0198), so that stanzas lost with a connection aren't lost for good.
+//!
+//! Sent stanzas are kept until the server acknowledges them, an acknowledgement being requested
+//! after each message. Resumption is requested too, so that the client resumes a lost stream
+//! with the same resource, channels and presence. When the server can't resume it, messages not
+//! acknowledged are sent again once reconnected and opened channels are joined again.
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::core::{Aparte, Event, ModTrait};
+use crate::mods;
+
+pub const SM: &str = "urn:xmpp:sm:3";
+
+/// Whether stream management is configured for an account, accounts connected with /connect use it
+fn configured(aparte: &Aparte, account: &Account) -> bool {
+    let jid: BareJid = account.clone().into();
+    aparte
+        .config
+        .accounts
+        .values()
+        .find(|info| Jid::from_str(&info.jid).map(BareJid::from).as_ref() == Ok(&jid))
+        .map(|info| info.stream_management)
+        .unwrap_or(true)
+}
+
+/// Messages, presences and IQs, the only elements counted
+pub fn is_stanza(element: &Element) -> bool {
+    element.ns() == ns::DEFAULT_NS
+        && (element.name() == "message" || element.name() == "presence" || element.name() == "iq")
+}
+
+#[derive(Default)]
+struct Session {
+    /// `<enable/>` was sent, stanzas sent since are counted
+    counting: bool,
+    /// The server enabled stream management
+    enabled: bool,
+    /// Stanzas received since enabled
+    inbound: u32,
+    /// Stanzas acknowledged by the server
+    acked: u32,
+    /// Stanzas sent and not acknowledged yet, oldest first
+    unacked: VecDeque<Element>,
+}
+
+pub struct StreamManagementMod {
+    sessions: HashMap<Account, Session>,
+}
+
+impl StreamManagementMod {
+    pub fn new() -> Self {
+        Self {
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Keep track of an element written on the stream of an account, returns whether an
+    /// acknowledgement should be requested after it
+    pub fn sent(&mut self, account: &Account, element: &Element) -> bool {
+        let session = match self.sessions.get_mut(account) {
+            Some(session) => session,
+            None => return false,
+        };
+        if element.is("enable", SM) {
+            session.counting = true;
+            return false;
+        }
+        if !session.counting || !is_stanza(element) {
+            return false;
+        }
+        session.unacked.push_back(element.clone());
+        session.enabled && element.name() == "message"
+    }
+
+    fn acknowledged(session: &mut Session, h: u32) {
+        let count = h.wrapping_sub(session.acked) as usize;
+        if count > session.unacked.len() {
+            warn!(
+                "Server acknowledged {} stanzas, only {} were sent",
+                count,
+                session.unacked.len()
+            );
+        }
+        session.unacked.drain(..count.min(session.unacked.len()));
+        session.acked = h;
+    }
+
+    fn handle_stanza(&mut self, aparte: &mut Aparte, account: &Account, stanza: &Element) {
+        let session = match self.sessions.get_mut(account) {
+            Some(session) => session,
+            None => return,
+        };
+        if is_stanza(stanza) {
+            if session.enabled {
+                session.inbound = session.inbound.wrapping_add(1);
+            }
+            return;
+        }
+        if stanza.ns() != SM {
+            return;
+        }
+        match stanza.name() {
+            "enabled" => {
+                session.enabled = true;
+                session.inbound = 0;
+            }
+            "failed" => {
+                warn!("Cannot enable stream management for {}", account);
+                *session = Session::default();
+            }
+            "r" => {
+                let ack = Element::builder("a", SM)
+                    .attr("h", session.inbound.to_string())
+                    .build();
+                aparte.send(account, ack);
+            }
+            "a" => match stanza.attr("h").map(u32::from_str) {
+                Some(Ok(h)) => Self::acknowledged(session, h),
+                _ => warn!("Invalid stream management acknowledgement from {}", account),
+            },
+            _ => {}
+        }
+    }
+}
+
+impl ModTrait for StreamManagementMod {
+    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Connected(account, _) => {
+                let previous = self.sessions.insert(account.clone(), Session::default());
+                if configured(aparte, account) {
+                    let enable = Element::builder("enable", SM).attr("resume", "true");
+                    aparte.send(account, enable.build());
+                }
+
+                let previous = match previous {
+                    Some(previous) => previous,
+                    None => return,
+                };
+                // The previous stream couldn't be resumed, what was lost with it is restored instead
+                let lost: Vec<Element> = previous
+                    .unacked
+                    .into_iter()
+                    .filter(|stanza| stanza.name() == "message")
+                    .collect();
+                if !lost.is_empty() {
+                    aparte.log(format!(
+                        "Sending {} unacknowledged messages of {} again",
+                        lost.len(),
+                        account
+                    ));
+                }
+                for message in lost {
+                    aparte.send(account, message);
+                }
+                let channels: Vec<Jid> = {
+                    let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+                    conversations
+                        .channels(account)
+                        .map(|channel| Jid::Full(channel.jid.clone().with_resource(&channel.nick)))
+                        .collect()
+                };
+                for channel in channels {
+                    aparte.schedule(Event::Join {
+                        account: account.clone(),
+                        channel,
+                        user_request: false,
+                    });
+                }
+            }
+            Event::Stanza(account, stanza) => self.handle_stanza(aparte, account, stanza),
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for StreamManagementMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0198: Stream Management")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+
+    #[test]
+    fn test_acknowledgements() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            let enable = harness.take_sent("enable", SM);
+            assert_eq!(enable.len(), 1);
+            assert_eq!(enable[0].attr("resume"), Some("true"));
+            harness.receive("<enabled xmlns='urn:xmpp:sm:3'/>").await;
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='chat' id='m1'
+                        from='juliet@example.org/balcony' to='{account}'>
+                        <body>Romeo?</body>
+                    </message>",
+                )
+                .await;
+
+            // When
+            harness.receive("<r xmlns='urn:xmpp:sm:3'/>").await;
+            harness
+                .input("console", "/msg juliet@example.org Juliet!")
+                .await;
+
+            // Then
+            let acks = harness.take_sent("a", SM);
+            assert_eq!(acks.len(), 1);
+            assert_eq!(acks[0].attr("h"), Some("1"));
+            assert_eq!(harness.take_sent("r", SM).len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_unacknowledged_messages_and_channels_are_restored_on_reconnection() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness.receive("<enabled xmlns='urn:xmpp:sm:3'/>").await;
+            harness
+                .input("console", "/join room@conference.example.org")
+                .await;
+            harness
+                .input("console", "/msg juliet@example.org Juliet!")
+                .await;
+            harness
+                .input("console", "/msg juliet@example.org \"Are you there?\"")
+                .await;
+            let unacked = {
+                let sm = harness.aparte.get_mod::<StreamManagementMod>();
+                sm.sessions[&harness.account].unacked.len()
+            };
+            harness
+                .receive(&format!("<a xmlns='urn:xmpp:sm:3' h='{}'/>", unacked - 1))
+                .await;
+            harness.take_sent("message", ns::DEFAULT_NS);
+            harness.take_sent("x", ns::MUC);
+
+            // When
+            harness.connect().await;
+
+            // Then
+            let messages = harness.take_sent("message", ns::DEFAULT_NS);
+            assert_eq!(messages.len(), 1);
+            assert_eq!(
+                messages[0]
+                    .get_child("body", ns::DEFAULT_NS)
+                    .map(Element::text),
+                Some(String::from("Are you there?"))
+            );
+            let joins = harness.take_sent("x", ns::MUC);
+            assert_eq!(joins.len(), 1);
+            assert_eq!(
+                joins[0].attr("to"),
+                Some("room@conference.example.org/romeo")
+            );
+        });
+    }
+}
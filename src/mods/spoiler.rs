@@ -0,0 +1,72 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::fmt;
+use std::str::FromStr;
+use xmpp_parsers::BareJid;
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::{Message, SPOILER};
+use crate::mods;
+
+command_def!(spoiler,
+r#"/spoiler [<hint>] <text>
+
+    hint          Optional hint shown instead of the text
+    text          Text hidden until the reader reveals it
+
+Description:
+    Send a message hidden behind a spoiler in the current conversation.
+    Received spoilers are collapsed, select them with Ctrl-p and Ctrl-n then
+    reveal them with Ctrl-o.
+
+Examples:
+    /spoiler "The butler did it"
+    /spoiler "Movie ending" "The butler did it""#,
+{
+    first: String,
+    second: Option<String>
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let jid = BareJid::from_str(&_command.context)
+        .map_err(|_| "Spoilers can only be sent in a conversation window".to_string())?;
+    let (hint, text) = match second {
+        Some(text) => (first, text),
+        None => (String::new(), first),
+    };
+
+    let conversation = {
+        let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+        conversations.get(&account, &jid).cloned()
+    };
+    let message = Message::outgoing(&account, &jid, conversation, text).with_spoiler(Some(hint));
+    aparte.schedule(Event::SendMessage(account, message));
+    Ok(())
+});
+
+pub struct SpoilerMod {}
+
+impl SpoilerMod {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl ModTrait for SpoilerMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(spoiler::new());
+        let mut disco = aparte.get_mod_mut::<mods::disco::DiscoMod>();
+        disco.add_feature(SPOILER)
+    }
+
+    fn on_event(&mut self, _aparte: &mut Aparte, _event: &Event) {}
+}
+
+impl fmt::Display for SpoilerMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0382: Spoiler messages")
+    }
+}
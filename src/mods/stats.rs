@@ -0,0 +1,283 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::time;
+use uuid::Uuid;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::ping::Ping;
+use xmpp_parsers::{BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::color;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::mods::upload::human_size;
+
+command_def!(
+    stats,
+    r#"/stats
+
+Description:
+    Show traffic, reconnections, latency and uptime of each account since
+    Aparté started."#,
+    {},
+    |aparte, _command| {
+        let report = {
+            let stats = aparte.get_mod::<StatsMod>();
+            stats.report()
+        };
+        aparte.log(report);
+        Ok(())
+    }
+);
+
+#[derive(Debug, Default)]
+pub struct AccountStats {
+    pub sent: usize,
+    pub received: usize,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    /// Number of successful connections, the first one included
+    pub connections: usize,
+    /// Start of the current session, None while disconnected
+    pub connected_since: Option<Instant>,
+    /// Sum and count of ping round trips
+    pub pings: (Duration, u32),
+    pub last_ping: Option<Duration>,
+    /// Ping waiting for its answer
+    pending_ping: Option<(String, Instant)>,
+}
+
+impl AccountStats {
+    pub fn reconnections(&self) -> usize {
+        self.connections.saturating_sub(1)
+    }
+
+    pub fn average_ping(&self) -> Option<Duration> {
+        let (total, count) = self.pings;
+        match count {
+            0 => None,
+            count => Some(total / count),
+        }
+    }
+
+    fn pong(&mut self, id: &str) -> Option<Duration> {
+        match self.pending_ping.take() {
+            Some((pending, sent)) if pending == id => {
+                let round_trip = sent.elapsed();
+                self.pings.0 += round_trip;
+                self.pings.1 += 1;
+                self.last_ping = Some(round_trip);
+                Some(round_trip)
+            }
+            pending => {
+                self.pending_ping = pending;
+                None
+            }
+        }
+    }
+}
+
+pub struct StatsMod {
+    accounts: HashMap<Account, AccountStats>,
+}
+
+impl StatsMod {
+    pub fn new() -> Self {
+        Self {
+            accounts: HashMap::new(),
+        }
+    }
+
+    /// Account a stanza sent on the wire
+    pub fn sent(&mut self, account: &Account, bytes: usize) {
+        let stats = self.accounts.entry(account.clone()).or_default();
+        stats.sent += 1;
+        stats.bytes_sent += bytes;
+    }
+
+    fn received(&mut self, account: &Account, bytes: usize) {
+        let stats = self.accounts.entry(account.clone()).or_default();
+        stats.received += 1;
+        stats.bytes_received += bytes;
+    }
+
+    fn ping(&mut self, account: &Account) -> Element {
+        let id = Uuid::new_v4().to_string();
+        let stats = self.accounts.entry(account.clone()).or_default();
+        stats.pending_ping = Some((id.clone(), Instant::now()));
+
+        let server = Jid::Bare(BareJid::domain(account.domain.clone()));
+        Iq::from_get(id, Ping).with_to(server).into()
+    }
+
+    fn schedule_ping(&self, aparte: &mut Aparte, account: &Account) {
+        let interval = Duration::from_secs(aparte.config.stats.ping_interval.max(1));
+        let account = account.clone();
+        aparte.spawn(async move {
+            time::sleep(interval).await;
+            Event::Ping(account)
+        });
+    }
+
+    fn report(&self) -> String {
+        if self.accounts.is_empty() {
+            return "No connection yet".to_string();
+        }
+
+        let mut accounts = self.accounts.iter().collect::<Vec<_>>();
+        accounts.sort_by_key(|(account, _)| account.to_string());
+
+        let mut report = Vec::new();
+        for (account, stats) in accounts {
+            let status = match stats.connected_since {
+                Some(since) => format!("connected for {}", human_duration(since.elapsed())),
+                None => "disconnected".to_string(),
+            };
+            report.push(format!(
+                "{}: {} ({} reconnections)",
+                account,
+                status,
+                stats.reconnections()
+            ));
+            report.push(format!(
+                "  stanzas: {} sent ({}), {} received ({})",
+                stats.sent,
+                human_size(stats.bytes_sent as u64),
+                stats.received,
+                human_size(stats.bytes_received as u64)
+            ));
+            let ping = match (stats.average_ping(), stats.last_ping) {
+                (Some(average), Some(last)) => format!(
+                    "  ping: {} ms on average over {} pings, last {} ms",
+                    average.as_millis(),
+                    stats.pings.1,
+                    last.as_millis()
+                ),
+                _ => color::dimmed("  ping: no answer yet"),
+            };
+            report.push(ping);
+        }
+
+        report.join("\n")
+    }
+}
+
+/// Human readable duration, only the two most significant units are kept
+fn human_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let units = [
+        (seconds / 86400, "d"),
+        (seconds / 3600 % 24, "h"),
+        (seconds / 60 % 60, "m"),
+        (seconds % 60, "s"),
+    ];
+
+    let first = units.iter().position(|(value, _)| *value > 0).unwrap_or(3);
+    units[first..]
+        .iter()
+        .take(2)
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+impl ModTrait for StatsMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(stats::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Connected(account, _) => {
+                let first = {
+                    let stats = self.accounts.entry(account.clone()).or_default();
+                    stats.connections += 1;
+                    stats.connected_since = Some(Instant::now());
+                    stats.connections == 1
+                };
+                aparte.send(account, self.ping(account));
+                // Pings are scheduled once per account, they are skipped while disconnected
+                if first {
+                    self.schedule_ping(aparte, account);
+                }
+            }
+            Event::Disconnected(account, _) => {
+                if let Some(stats) = self.accounts.get_mut(account) {
+                    stats.connected_since = None;
+                    stats.pending_ping = None;
+                }
+            }
+            Event::Ping(account) => {
+                let connected = match self.accounts.get(account) {
+                    Some(stats) => stats.connected_since.is_some(),
+                    None => false,
+                };
+                if connected && !aparte.config.low_bandwidth {
+                    aparte.send(account, self.ping(account));
+                }
+                self.schedule_ping(aparte, account);
+            }
+            Event::Stanza(account, stanza) => {
+                self.received(account, String::from(stanza).len());
+            }
+            Event::Iq(account, iq) => {
+                if let IqType::Result(_) | IqType::Error(_) = iq.payload {
+                    let round_trip = match self.accounts.get_mut(account) {
+                        Some(stats) => stats.pong(&iq.id),
+                        None => None,
+                    };
+                    if let Some(round_trip) = round_trip {
+                        if aparte.config.stats.status_bar {
+                            aparte.schedule(Event::Latency(account.clone(), round_trip));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for StatsMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connection statistics")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_human_duration() {
+        assert_eq!(human_duration(Duration::from_secs(0)), "0s");
+        assert_eq!(human_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(human_duration(Duration::from_secs(3 * 3600 + 120)), "3h 2m");
+        assert_eq!(human_duration(Duration::from_secs(2 * 86400 + 59)), "2d 0h");
+    }
+
+    #[test]
+    fn test_only_matching_pong_is_accounted() {
+        // Given
+        let mut stats = AccountStats {
+            pending_ping: Some(("ping".to_string(), Instant::now())),
+            ..Default::default()
+        };
+
+        // When
+        let other = stats.pong("other");
+        let pong = stats.pong("ping");
+
+        // Then
+        assert!(other.is_none());
+        assert!(pong.is_some());
+        assert_eq!(stats.pings.1, 1);
+        assert_eq!(stats.average_ping(), pong);
+        assert!(stats.pong("ping").is_none());
+    }
+}
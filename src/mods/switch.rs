@@ -0,0 +1,181 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Quick switch between windows, showing how busy each one was lately to choose where to catch
+//! up first.
+//!
+//! Activity is computed from the messages kept in memory, so it only covers what was received,
+//! sent or loaded from archives since start.
+use chrono::{DateTime, FixedOffset, Local};
+use std::fmt;
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::Message;
+use crate::mods;
+use crate::mods::ui::SwitchItem;
+
+/// Hours covered by a sparkline, one character each
+const HOURS: usize = 12;
+
+/// Bars of a sparkline, from lowest to highest
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+command_def!(
+    switch,
+    r#"/switch
+
+Description:
+    List the windows in the switch window with the activity of their last 12
+    hours, one character per hour, and their last message. The busiest are
+    listed last, select one with Ctrl-p and Ctrl-n then press Enter to go to
+    it."#,
+    {},
+    |aparte, _command| {
+        let windows = {
+            let ui = aparte.get_mod::<mods::ui::UIMod>();
+            ui.window_conversations()
+        };
+        let now = Local::now().into();
+        let items = {
+            let messages = aparte.get_mod::<mods::messages::MessagesMod>();
+            windows
+                .into_iter()
+                .map(|(window, conversation)| {
+                    let messages: Vec<_> = match conversation {
+                        Some((account, jid)) => messages
+                            .conversation(&Some(account), &jid)
+                            .into_iter()
+                            .filter_map(|message| match message {
+                                Message::Xmpp(message) => Some(message),
+                                Message::Log(_) => None,
+                            })
+                            .collect(),
+                        None => Vec::new(),
+                    };
+                    let counts = activity(
+                        messages
+                            .iter()
+                            .map(|message| message.get_original_timestamp()),
+                        &now,
+                    );
+                    SwitchItem {
+                        window,
+                        sparkline: sparkline(&counts),
+                        last: messages.into_iter().last(),
+                    }
+                })
+                .collect()
+        };
+        let mut ui = aparte.get_mod_mut::<mods::ui::UIMod>();
+        ui.show_switch(items);
+        Ok(())
+    }
+);
+
+/// Messages per hour over the last hours, oldest first
+pub fn activity<'a>(
+    timestamps: impl Iterator<Item = &'a DateTime<FixedOffset>>,
+    now: &DateTime<FixedOffset>,
+) -> Vec<usize> {
+    let mut counts = vec![0; HOURS];
+    for timestamp in timestamps {
+        let age = (*now - *timestamp).num_hours();
+        if *timestamp <= *now && (age as usize) < HOURS {
+            counts[HOURS - 1 - age as usize] += 1;
+        }
+    }
+    counts
+}
+
+/// Bars scaled to the highest count, hours without messages being blank
+pub fn sparkline(counts: &[usize]) -> String {
+    let max = counts.iter().copied().max().unwrap_or(0);
+    counts
+        .iter()
+        .map(|count| match count {
+            0 => ' ',
+            count => BARS[(count * BARS.len() - 1) / max],
+        })
+        .collect()
+}
+
+pub struct SwitchMod {}
+
+impl SwitchMod {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl ModTrait for SwitchMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(switch::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, _aparte: &mut Aparte, _event: &Event) {}
+}
+
+impl fmt::Display for SwitchMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Quick switch")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+    use chrono::Duration;
+    use termion::event::Key;
+
+    #[test]
+    fn test_activity_and_sparkline() {
+        // Given
+        let now: DateTime<FixedOffset> = Local::now().into();
+        let timestamps = [
+            now - Duration::minutes(5),
+            now - Duration::minutes(10),
+            now - Duration::minutes(20),
+            now - Duration::minutes(30),
+            now - Duration::minutes(90),
+            now - Duration::hours(13),
+        ];
+
+        // When
+        let counts = activity(timestamps.iter(), &now);
+
+        // Then
+        assert_eq!(counts, vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 4]);
+        assert_eq!(sparkline(&counts), "          ▂█");
+    }
+
+    #[test]
+    fn test_switch_lists_windows_with_their_last_message() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='chat' id='m1'
+                        from='juliet@example.org/balcony' to='{account}'>
+                        <body>Wherefore art thou Romeo?</body>
+                    </message>",
+                )
+                .await;
+
+            // When
+            harness.input("console", "/switch").await;
+            harness.aparte.schedule(Event::Key(Key::Ctrl('p')));
+            harness.settle().await;
+
+            // Then
+            let screen = harness.screen();
+            assert!(screen.contains("juliet@example.org — juliet@example.org: Wherefore"));
+            assert!(screen.contains("console"));
+        });
+    }
+}
@@ -0,0 +1,400 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Publish the track currently played as user tune (XEP-0118), "now playing" style.
+//!
+//! The track is read from the media players on the session bus (MPRIS), which needs the dbus
+//! feature. Players are polled rather than followed, a tune changing every few seconds at most.
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
+use xmpp_parsers::iq::Iq;
+use xmpp_parsers::pubsub::pubsub::{self, Publish};
+use xmpp_parsers::pubsub::{Item, ItemId, NodeName, PubSub};
+use xmpp_parsers::{ns, Element};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+
+/// Interval between two reads of the track played
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+command_def!(
+    tune,
+    r#"/tune on|off
+
+Description:
+    Publish the track played by your media player as user tune (XEP-0118), on
+    every connected account. The player must support MPRIS, and Aparté be built
+    with the dbus feature.
+
+Examples:
+    /tune on
+    /tune off"#,
+    {
+        action: String = {
+            values: ["on", "off"],
+        },
+    },
+    |aparte, _command| {
+        match action.as_str() {
+            "on" => {
+                let poll = {
+                    let mut tune = aparte.get_mod_mut::<TuneMod>();
+                    tune.enable()?
+                };
+                if let Some(poll) = poll {
+                    aparte.spawn(poll);
+                }
+                aparte.log("Playing tracks are now published".to_string());
+            }
+            _ => {
+                let (accounts, published) = {
+                    let mut tune = aparte.get_mod_mut::<TuneMod>();
+                    tune.disable()?
+                };
+                // Contacts would otherwise see the last track played forever
+                if published {
+                    for account in accounts {
+                        aparte.send(&account, TuneMod::publish(None));
+                    }
+                }
+                aparte.log("Playing tracks aren't published anymore".to_string());
+            }
+        }
+        Ok(())
+    }
+);
+
+/// Track played, as published in a user tune
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Track {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    /// Album or other collection
+    pub source: Option<String>,
+    /// Duration in seconds
+    pub length: Option<u16>,
+    pub uri: Option<String>,
+}
+
+impl Track {
+    fn to_element(&self) -> Element {
+        let fields = [
+            ("artist", &self.artist),
+            ("title", &self.title),
+            ("source", &self.source),
+            ("length", &self.length.map(|length| length.to_string())),
+            ("uri", &self.uri),
+        ];
+        let mut tune = Element::builder("tune", ns::TUNE);
+        for (name, value) in fields.iter() {
+            if let Some(value) = value {
+                tune = tune.append(Element::builder(*name, ns::TUNE).append(value.as_str()));
+            }
+        }
+        tune.build()
+    }
+}
+
+impl fmt::Display for Track {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.artist, &self.title) {
+            (Some(artist), Some(title)) => write!(f, "{} – {}", artist, title),
+            (None, Some(title)) => write!(f, "{}", title),
+            (Some(artist), None) => write!(f, "{}", artist),
+            (None, None) => write!(f, "Unknown track"),
+        }
+    }
+}
+
+#[cfg(feature = "dbus")]
+mod mpris {
+    use dbus::arg::{prop_cast, PropMap, RefArg};
+    use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+    use dbus::nonblock::{Proxy, SyncConnection};
+    use std::convert::TryFrom;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::Track;
+
+    const PREFIX: &str = "org.mpris.MediaPlayer2.";
+    const PATH: &str = "/org/mpris/MediaPlayer2";
+    const PLAYER: &str = "org.mpris.MediaPlayer2.Player";
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Media players of the session bus
+    pub struct Players {
+        connection: Arc<SyncConnection>,
+    }
+
+    impl Players {
+        pub fn connect() -> Result<Self, String> {
+            let (resource, connection) = dbus_tokio::connection::new_session_sync()
+                .map_err(|err| format!("Cannot connect to DBus: {}", err))?;
+            tokio::spawn(async {
+                let err = resource.await;
+                warn!("Lost connection to DBus: {}", err);
+            });
+            Ok(Self { connection })
+        }
+
+        fn track(metadata: &PropMap) -> Track {
+            let text = |key: &str| prop_cast::<String>(metadata, key).cloned();
+            Track {
+                artist: prop_cast::<Vec<String>>(metadata, "xesam:artist")
+                    .filter(|artists| !artists.is_empty())
+                    .map(|artists| artists.join(", ")),
+                title: text("xesam:title"),
+                source: text("xesam:album"),
+                // Microseconds, as signed or unsigned depending on players
+                length: metadata
+                    .get("mpris:length")
+                    .and_then(|length| length.0.as_i64())
+                    .and_then(|length| u16::try_from(length / 1_000_000).ok()),
+                uri: text("xesam:url"),
+            }
+        }
+
+        /// Track of the first player playing, if any
+        pub fn now_playing(&self) -> impl Future<Output = Result<Option<Track>, String>> {
+            let connection = self.connection.clone();
+            async move {
+                let bus = Proxy::new(
+                    "org.freedesktop.DBus",
+                    "/org/freedesktop/DBus",
+                    TIMEOUT,
+                    connection.clone(),
+                );
+                let (names,): (Vec<String>,) = bus
+                    .method_call("org.freedesktop.DBus", "ListNames", ())
+                    .await
+                    .map_err(|err| format!("Cannot list media players: {}", err))?;
+                for name in names.into_iter().filter(|name| name.starts_with(PREFIX)) {
+                    let player = Proxy::new(name, PATH, TIMEOUT, connection.clone());
+                    let status: Option<String> = player.get(PLAYER, "PlaybackStatus").await.ok();
+                    if status.as_deref() != Some("Playing") {
+                        continue;
+                    }
+                    if let Ok(metadata) = player.get::<PropMap>(PLAYER, "Metadata").await {
+                        return Ok(Some(Self::track(&metadata)));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "dbus"))]
+mod mpris {
+    use std::future::Future;
+
+    use super::Track;
+
+    pub struct Players;
+
+    impl Players {
+        pub fn connect() -> Result<Self, String> {
+            Err("Aparté is built without the dbus feature".to_string())
+        }
+
+        pub fn now_playing(&self) -> impl Future<Output = Result<Option<Track>, String>> {
+            async { Ok(None) }
+        }
+    }
+}
+
+pub struct TuneMod {
+    players: Option<mpris::Players>,
+    enabled: bool,
+    /// A read of the players is pending
+    polling: bool,
+    /// Track last published, None when nothing is played
+    current: Option<Track>,
+    accounts: HashSet<Account>,
+}
+
+impl TuneMod {
+    pub fn new() -> Self {
+        Self {
+            players: None,
+            enabled: false,
+            polling: false,
+            current: None,
+            accounts: HashSet::new(),
+        }
+    }
+
+    /// Start publishing, returns the read of the players to spawn if none is pending
+    fn enable(&mut self) -> Result<Option<impl Future<Output = Event>>, String> {
+        if self.enabled {
+            return Err("Playing tracks are already published".to_string());
+        }
+        if self.players.is_none() {
+            self.players = Some(mpris::Players::connect()?);
+        }
+        self.enabled = true;
+        Ok(self.poll(Duration::from_secs(0)))
+    }
+
+    /// Stop publishing, returns the connected accounts and whether a track was published
+    fn disable(&mut self) -> Result<(Vec<Account>, bool), String> {
+        if !self.enabled {
+            return Err("Playing tracks aren't published".to_string());
+        }
+        self.enabled = false;
+        let published = self.current.take().is_some();
+        Ok((self.accounts.iter().cloned().collect(), published))
+    }
+
+    fn poll(&mut self, delay: Duration) -> Option<impl Future<Output = Event>> {
+        if self.polling {
+            return None;
+        }
+        let now_playing = self.players.as_ref()?.now_playing();
+        self.polling = true;
+        Some(async move {
+            tokio::time::sleep(delay).await;
+            match now_playing.await {
+                Ok(track) => Event::NowPlaying(track),
+                Err(err) => {
+                    warn!("{}", err);
+                    Event::NowPlaying(None)
+                }
+            }
+        })
+    }
+
+    /// Publication of a track, stopping to publish when None
+    fn publish(track: Option<&Track>) -> Element {
+        let tune = match track {
+            Some(track) => track.to_element(),
+            None => Element::builder("tune", ns::TUNE).build(),
+        };
+        let publish = PubSub::Publish {
+            publish: Publish {
+                node: NodeName(String::from(ns::TUNE)),
+                items: vec![pubsub::Item(Item {
+                    id: Some(ItemId(String::from("current"))),
+                    payload: Some(tune),
+                    publisher: None,
+                })],
+            },
+            publish_options: None,
+        };
+        Iq::from_set(Uuid::new_v4().to_hyphenated().to_string(), publish).into()
+    }
+
+    fn played(&mut self, aparte: &mut Aparte, track: &Option<Track>) {
+        self.polling = false;
+        if !self.enabled {
+            return;
+        }
+        if *track != self.current {
+            self.current = track.clone();
+            for account in self.accounts.iter() {
+                aparte.send(account, Self::publish(track.as_ref()));
+            }
+            if let Some(track) = track {
+                info!("Now playing {}", track);
+            }
+        }
+        if let Some(poll) = self.poll(POLL_INTERVAL) {
+            aparte.spawn(poll);
+        }
+    }
+}
+
+impl ModTrait for TuneMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(tune::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Connected(account, _) => {
+                self.accounts.insert(account.clone());
+                if let Some(track) = &self.current {
+                    aparte.send(account, Self::publish(Some(track)));
+                }
+            }
+            Event::Disconnected(account, _) => {
+                self.accounts.remove(account);
+            }
+            Event::NowPlaying(track) => self.played(aparte, track),
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for TuneMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0118: User Tune")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+
+    #[test]
+    fn test_track_to_element() {
+        // Given
+        let track = Track {
+            artist: Some(String::from("Yes")),
+            title: Some(String::from("Heart of the Sunrise")),
+            source: None,
+            length: Some(686),
+            uri: None,
+        };
+
+        // When
+        let tune = track.to_element();
+
+        // Then
+        assert_eq!(
+            String::from(&tune),
+            "<tune xmlns=\"http://jabber.org/protocol/tune\"><artist>Yes</artist><title>Heart of the Sunrise</title><length>686</length></tune>"
+        );
+    }
+
+    #[test]
+    fn test_tracks_played_are_published_once() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            {
+                let mut tune = harness.aparte.get_mod_mut::<TuneMod>();
+                tune.enabled = true;
+            }
+            let track = Track {
+                artist: Some(String::from("Yes")),
+                title: Some(String::from("Roundabout")),
+                ..Track::default()
+            };
+
+            // When
+            for track in [Some(track.clone()), Some(track), None].iter() {
+                harness.aparte.schedule(Event::NowPlaying(track.clone()));
+                harness.settle().await;
+            }
+
+            // Then
+            let published = harness.take_sent("pubsub", ns::PUBSUB);
+            assert_eq!(published.len(), 2);
+            assert!(String::from(&published[0]).contains("<title>Roundabout</title>"));
+            assert!(String::from(&published[1])
+                .contains("<tune xmlns=\"http://jabber.org/protocol/tune\"/>"));
+        });
+    }
+}
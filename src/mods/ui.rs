@@ -2,54 +2,160 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 use backtrace::Backtrace;
+use chrono::format::{Item, StrftimeItems};
 use chrono::offset::{Local, TimeZone};
 use chrono::Local as LocalTz;
+use chrono::{DateTime, FixedOffset};
 use futures::task::{AtomicWaker, Context, Poll};
 use futures::Stream;
 use linked_hash_set::LinkedHashSet;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
+use std::fs;
 use std::hash::{Hash, Hasher};
-use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::io::ErrorKind as IoErrorKind;
 use std::io::{Read, Stdout, Write};
+use std::os::unix::io::AsRawFd;
 use std::panic;
+use std::path::PathBuf;
 use std::pin::Pin;
+use std::process;
 use std::rc::Rc;
+use std::str::FromStr;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 use termion::color;
 use termion::event::{parse_event as termion_parse_event, Event as TermionEvent, Key};
 use termion::get_tty;
 use termion::raw::IntoRawMode;
 use termion::screen::AlternateScreen;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command as ProcessCommand;
 use uuid::Uuid;
+use xmpp_parsers::presence::Show as PresenceShow;
 use xmpp_parsers::{BareJid, Jid};
 
+use crate::account::Account;
 use crate::color::id_to_rgb;
-use crate::command::Command;
+use crate::command::{Command, CommandParser};
+use crate::config::{FormatConfig, LayoutConfig, PanelPosition, PanelWidth};
 use crate::conversation::{Channel, Chat, Conversation};
 use crate::core::{Aparte, Event, ModTrait};
 use crate::cursor::Cursor;
+use crate::graphics::{render_inline, Image, Protocol as GraphicsProtocol};
 use crate::i18n;
-use crate::message::{Direction, Message, XmppMessageType};
+use crate::message::{Direction, LogMessage, Message, VersionedXmppMessage, XmppMessageType};
+use crate::mods::filter::FilterAction;
+use crate::mods::openpgp::Protection;
+use crate::mods::requests::Routing;
+use crate::mods::upload;
+use crate::qrcode::QrCode;
+use crate::sims::MediaSharing;
+use crate::template::Template;
 use crate::terminus::{
-    self, BufferedWin, Dimension, FrameLayout, Input, Layout, Layouts, LinearLayout, ListView,
-    Orientation, Screen, View, Window as _,
+    self, BufferedWin, Dimension, FrameLayout, History, Input, Layout, Layouts, LinearLayout,
+    ListView, Orientation, Screen, View, Window as _,
 };
-use crate::{contact, conversation};
+use crate::{contact, conversation, mods};
 
 enum UIEvent {
     Core(Event),
     Validate(Rc<RefCell<Option<(String, bool)>>>),
     GetInput(Rc<RefCell<Option<(String, Cursor, bool)>>>),
     AddWindow(String, Option<Box<dyn View<UIEvent, Stdout>>>),
+    /// Message of a stranger for the requests window
+    Request(Message),
+    /// Headline or server message for the announcements window
+    Announcement(Message),
+    /// Details about the selected item, shown in the status bar
+    Status(Option<String>),
+    /// Window with unread messages
+    Highlight(String),
+    /// Window whose contact requests our attention
+    Urgent(String),
+    /// Collect how far each conversation window is scrolled up
+    Scroll(Rc<RefCell<HashMap<String, usize>>>),
+    /// Enter pressed with an empty input, for the current window
+    Activate,
+    /// Select a message in its conversation window
+    SelectMessage(Message),
+    /// Show or hide a sidebar whatever the width of the terminal
+    ShowSidebar(Sidebar, bool),
+    /// Show or hide the occupants of a channel, whatever the width and the other channels
+    ShowOccupants(BareJid, bool),
+    /// Resize a sidebar
+    SidebarWidth(Sidebar, PanelWidth),
+    /// Arrow key moving in the disco window, when nothing is typed
+    Browse(Key),
+    /// Keys of the sequence being typed, shown in the status bar until it is complete
+    Chord(Option<String>),
+    /// Collect the messages and the commands validated in the input
+    GetHistory(Rc<RefCell<Option<InputHistory>>>),
+    /// Restore the messages and the commands validated in the input
+    SetHistory(Vec<String>, Vec<String>),
+    /// Name and windows of the workspace opened, the only ones listed in the window bar
+    Workspace(Option<(String, Vec<String>)>),
+}
+
+/// Messages and commands validated in the input
+type InputHistory = (Vec<String>, Vec<String>);
+
+/// Lists shown beside conversations, hidden on narrow terminals
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Sidebar {
+    Roster,
+    Occupants,
+}
+
+impl Sidebar {
+    /// Width of the terminal under which the sidebar is hidden
+    fn min_width(&self, config: &LayoutConfig) -> u16 {
+        match self {
+            Sidebar::Roster => config.roster_min_width,
+            Sidebar::Occupants => config.occupants_min_width,
+        }
+    }
+}
+
+/// Layouts of a sidebar of the given width, the one of its content by default
+fn sidebar_layouts(width: Option<PanelWidth>) -> Layouts {
+    Layouts {
+        width: match width {
+            None => Layout::wrap_content(),
+            Some(PanelWidth::Columns(columns)) => Layout::absolute(columns),
+            Some(PanelWidth::Percent(percent)) => Layout::percent(percent),
+        },
+        height: Layout::match_parent(),
+    }
+}
+
+/// Truncate a text longer than the given width, ending it with an ellipsis
+fn fit(text: &str, width: usize) -> String {
+    match terminus::term_string_visible_len(text) {
+        _ if width == 0 => String::new(),
+        len if len > width => terminus::term_string_visible_truncate(text, width, Some("…")),
+        _ => text.to_string(),
+    }
+}
+
+/// When a contact stopped interacting, as a time for today and as a day before
+fn idle_since(since: &DateTime<FixedOffset>, now: &DateTime<Local>) -> String {
+    let since = since.with_timezone(&Local);
+    match since.date_naive() == now.date_naive() {
+        true => format!("idle since {}", since.format("%H:%M")),
+        false => format!("idle since {}", since.format("%Y-%m-%d")),
+    }
 }
 
 struct TitleBar {
     name: Option<String>,
     subjects: HashMap<String, HashMap<String, String>>,
+    /// Contacts currently idle, by bare jid
+    idle: HashMap<String, DateTime<FixedOffset>>,
     dirty: bool,
 }
 
@@ -58,10 +164,21 @@ impl TitleBar {
         Self {
             name: None,
             subjects: HashMap::new(),
+            idle: HashMap::new(),
             dirty: true,
         }
     }
 
+    fn set_idle(&mut self, jid: String, idle: Option<DateTime<FixedOffset>>) {
+        if Some(&jid) == self.name.as_ref() {
+            self.dirty = true;
+        }
+        match idle {
+            Some(since) => self.idle.insert(jid, since),
+            None => self.idle.remove(&jid),
+        };
+    }
+
     fn set_name(&mut self, name: &str) {
         self.name = Some(name.to_string());
         self.subjects
@@ -107,27 +224,29 @@ where
         );
 
         if let Some(name) = &self.name {
-            let clean_name = terminus::term_string_visible_truncate(
-                name,
-                dimension.w.unwrap().into(),
-                Some("…"),
-            );
+            let mut width: usize = dimension.w.unwrap().into();
+            let avatar = BareJid::from_str(name)
+                .ok()
+                .and_then(|jid| avatar_prefix(&jid));
+            if let (Some(avatar), true) = (avatar, width > AVATAR_WIDTH + 1) {
+                vprint!(screen, "{}{}", avatar, color::Fg(color::White));
+                width -= AVATAR_WIDTH + 1;
+            }
+            let clean_name = fit(name, width);
             vprint!(screen, "{}", clean_name);
 
-            let remaining = dimension.w.unwrap()
-                - terminus::term_string_visible_len(&clean_name) as u16
-                - " – ".len() as u16;
-            if remaining > 0 {
+            let remaining = width
+                .saturating_sub(terminus::term_string_visible_len(&clean_name))
+                .saturating_sub(" — ".chars().count());
+            if remaining > 1 {
                 let subjects = self.subjects.get(name).unwrap();
                 if !subjects.is_empty() {
                     if let Some((_lang, subject)) = i18n::get_best(subjects, vec![]) {
-                        let clean_subject = terminus::term_string_visible_truncate(
-                            subject,
-                            remaining.into(),
-                            Some("…"),
-                        );
-                        vprint!(screen, " — {}", clean_subject);
+                        vprint!(screen, " — {}", fit(subject, remaining));
                     }
+                } else if let Some(since) = self.idle.get(name) {
+                    let idle = idle_since(since, &Local::now());
+                    vprint!(screen, " — {}", fit(&idle, remaining));
                 }
             }
         }
@@ -141,7 +260,6 @@ where
         );
 
         restore_cursor!(screen);
-        flush!(screen);
         self.dirty = false;
     }
 
@@ -154,6 +272,14 @@ where
             UIEvent::Core(Event::ChangeWindow(name)) => {
                 self.set_name(name);
             }
+            UIEvent::Core(Event::Avatar { contact, .. })
+                if self.name.as_deref() == Some(&contact.to_string()) =>
+            {
+                self.dirty = true;
+            }
+            UIEvent::Core(Event::ContactUpdate(_, contact)) => {
+                self.set_idle(contact.jid.to_string(), contact.idle);
+            }
             UIEvent::Core(Event::Subject(_, jid, subjects)) => {
                 let window: BareJid = jid.clone().into();
                 self.add_subjects(
@@ -178,9 +304,21 @@ where
 
 struct WinBar {
     connection: Option<String>,
+    /// Availability set with /away, /dnd or /xa
+    show: Option<&'static str>,
+    /// Last round trip to the server of the connection
+    latency: Option<Duration>,
+    /// Details about the selected item of the current window
+    status: Option<String>,
+    /// Key sequence being typed
+    chord: Option<String>,
+    /// Name and windows of the workspace opened, see /workspace
+    workspace: Option<(String, Vec<String>)>,
     windows: Vec<String>,
     current_window: Option<String>,
     highlighted: Vec<String>,
+    /// Highlighted windows requesting attention, shown first
+    urgent: Vec<String>,
     dirty: bool,
 }
 
@@ -188,9 +326,15 @@ impl WinBar {
     pub fn new() -> Self {
         Self {
             connection: None,
+            show: None,
+            latency: None,
+            status: None,
+            chord: None,
+            workspace: None,
             windows: Vec::new(),
             current_window: None,
             highlighted: Vec::new(),
+            urgent: Vec::new(),
             dirty: true,
         }
     }
@@ -203,6 +347,7 @@ impl WinBar {
     pub fn del_window(&mut self, window: &str) {
         self.windows.retain(|win| win != window);
         self.highlighted.retain(|win| win != window);
+        self.urgent.retain(|win| win != window);
         self.dirty = true;
     }
 
@@ -217,6 +362,7 @@ impl WinBar {
                 i += 1;
             }
         }
+        self.urgent.retain(|win| win != window);
         self.dirty = true;
     }
 
@@ -226,6 +372,15 @@ impl WinBar {
             self.dirty = true;
         }
     }
+
+    pub fn urgent_window(&mut self, window: &str) {
+        self.highlighted.retain(|w| w != window);
+        self.highlighted.insert(0, window.to_string());
+        if !self.urgent.iter().any(|w| w == window) {
+            self.urgent.push(window.to_string());
+        }
+        self.dirty = true;
+    }
 }
 
 impl<W> View<UIEvent, W> for WinBar
@@ -262,11 +417,52 @@ where
             vprint!(screen, " {}", connection);
             written += 1 + connection.len();
         }
+        if let Some(show) = &self.show {
+            let show = format!(" [{}]", show);
+            vprint!(screen, "{}", show);
+            written += show.len();
+        }
+        if let Some((name, _)) = &self.workspace {
+            let name = format!(" <{}>", name);
+            vprint!(screen, "{}", name);
+            written += name.len();
+        }
+        if let Some(latency) = &self.latency {
+            let latency = format!(" ({} ms)", latency.as_millis());
+            vprint!(screen, "{}", latency);
+            written += latency.len();
+        }
+        if let Some(chord) = &self.chord {
+            let chord = format!(" | {} …", chord);
+            vprint!(
+                screen,
+                "{}{}{}",
+                termion::style::Bold,
+                chord,
+                termion::style::NoBold
+            );
+            written += terminus::term_string_visible_len(&chord);
+        }
+        if let Some(status) = &self.status {
+            let width = usize::from(dimension.w.unwrap());
+            let status = fit(&format!(" | {}", status), width.saturating_sub(written));
+            vprint!(screen, "{}", status);
+            written += terminus::term_string_visible_len(&status);
+        }
 
+        // Urgent windows are listed whatever the workspace
+        let highlighted = self
+            .highlighted
+            .iter()
+            .filter(|window| match &self.workspace {
+                Some((_, windows)) => windows.contains(window) || self.urgent.contains(window),
+                None => true,
+            })
+            .collect::<Vec<_>>();
         let mut first = true;
-        let mut remaining = self.highlighted.len();
+        let mut remaining = highlighted.len();
 
-        for window in &self.highlighted {
+        for window in highlighted {
             // Keep space for at least ", +X]"
             let remaining_len = if remaining > 1 {
                 format!("{}", remaining).len() + 4
@@ -289,13 +485,26 @@ where
                 vprint!(screen, ", ");
                 written += 2;
             }
-            vprint!(
-                screen,
-                "{}{}{}",
-                termion::style::Bold,
-                window,
-                termion::style::NoBold
-            );
+            if self.urgent.contains(window) {
+                vprint!(
+                    screen,
+                    "{}{}!{}{}{}",
+                    termion::style::Bold,
+                    color::Fg(color::Red),
+                    window,
+                    color::Fg(color::White),
+                    termion::style::NoBold
+                );
+                written += 1;
+            } else {
+                vprint!(
+                    screen,
+                    "{}{}{}",
+                    termion::style::Bold,
+                    window,
+                    termion::style::NoBold
+                );
+            }
             written += window.len();
             remaining -= 1;
         }
@@ -312,7 +521,6 @@ where
         );
 
         restore_cursor!(screen);
-        flush!(screen);
         self.dirty = false;
     }
 
@@ -324,6 +532,25 @@ where
         match event {
             UIEvent::Core(Event::ChangeWindow(name)) => {
                 self.set_current_window(&terminus::clean(name));
+                self.status = None;
+            }
+            UIEvent::Status(status) => {
+                self.status = status.clone();
+                self.dirty = true;
+            }
+            UIEvent::Chord(chord) => {
+                self.chord = chord.clone();
+                self.dirty = true;
+            }
+            UIEvent::Workspace(workspace) => {
+                self.workspace = workspace.clone();
+                self.dirty = true;
+            }
+            UIEvent::Highlight(name) => {
+                self.highlight_window(&terminus::clean(name));
+            }
+            UIEvent::Urgent(name) => {
+                self.urgent_window(&terminus::clean(name));
             }
             UIEvent::AddWindow(name, _) => {
                 self.add_window(terminus::clean(name));
@@ -333,6 +560,20 @@ where
             }
             UIEvent::Core(Event::Connected(account, _)) => {
                 self.connection = Some(terminus::clean(&account.to_string()));
+                self.latency = None;
+                self.dirty = true;
+            }
+            UIEvent::Core(Event::SetPresence { show, .. }) => {
+                self.show = show
+                    .as_ref()
+                    .filter(|show| show != &&PresenceShow::Chat)
+                    .map(mods::presence::show_text);
+                self.dirty = true;
+            }
+            UIEvent::Core(Event::Latency(account, latency))
+                if self.connection == Some(terminus::clean(&account.to_string())) =>
+            {
+                self.latency = Some(*latency);
                 self.dirty = true;
             }
             UIEvent::Core(Event::Message(_, Message::Xmpp(message))) => {
@@ -360,557 +601,2525 @@ where
     }
 }
 
-impl fmt::Display for Message {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Message::Log(message) => {
-                let timestamp = Local.from_utc_datetime(&message.timestamp.naive_local());
-                for line in message.body.lines() {
-                    write!(
-                        f,
-                        "{}{} - {}\n",
-                        color::Fg(color::White),
-                        timestamp.format("%T"),
-                        line
-                    )?;
-                }
-
-                Ok(())
-            }
-            Message::Xmpp(message) => {
-                let author = terminus::clean(
-                    &match &message.type_ {
-                        XmppMessageType::Channel => match &message.from_full {
-                            Jid::Full(from) => from.resource.clone(),
-                            Jid::Bare(from) => from.to_string(),
-                        },
-                        XmppMessageType::Chat => message.from.to_string(),
-                    }
-                    .to_string(),
-                );
+/// Check a strftime like format, chrono panics when formatting a date with an invalid one
+fn check_timestamp(format: &str) -> Result<(), String> {
+    match StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+        true => Err(format!("invalid timestamp format {}", format)),
+        false => Ok(()),
+    }
+}
 
-                let timestamp =
-                    Local.from_utc_datetime(&message.get_original_timestamp().naive_local());
-                let body = message.get_last_body();
-                let me = body.starts_with("/me");
-                let padding_len = match me {
-                    true => format!("{} - {}: ", timestamp.format("%T"), author).len(),
-                    false => format!("{} - * {}", timestamp.format("%T"), author).len(),
-                };
-                let padding = " ".repeat(padding_len);
+/// Parsed version of the `format` configuration section
+struct MessageFormat {
+    timestamp: String,
+    message: Template,
+    me: Template,
+    log: Template,
+    nick_max_width: Option<usize>,
+    nick_column: Option<usize>,
+}
 
-                let (r, g, b) = id_to_rgb(&author);
+impl MessageFormat {
+    fn from_config(config: &FormatConfig) -> Result<Self, String> {
+        Ok(Self {
+            timestamp: config.timestamp.clone(),
+            message: Template::from_str(&config.message)?,
+            me: Template::from_str(&config.me)?,
+            log: Template::from_str(&config.log)?,
+            nick_max_width: config.nick_max_width,
+            nick_column: config.nick_column,
+        })
+    }
 
-                let mut attributes = "".to_string();
-                if message.has_multiple_version() {
-                    attributes.push_str("✎ ");
-                }
+    fn log_prefix(&self, message: &LogMessage) -> String {
+        let timestamp = Local.from_utc_datetime(&message.timestamp.naive_local());
+        let timestamp = timestamp.format(&self.timestamp).to_string();
+        self.log.render(|name| match name {
+            "time" => Some(timestamp.clone()),
+            _ => None,
+        })
+    }
 
-                match me {
-                    true => write!(
-                        f,
-                        "{}{} - {}* {}{}{}",
-                        color::Fg(color::White),
-                        timestamp.format("%T"),
-                        attributes,
-                        color::Fg(color::Rgb(r, g, b)),
-                        author,
-                        color::Fg(color::White)
-                    ),
-                    false => write!(
-                        f,
-                        "{}{} - {}{}{}:{} ",
-                        color::Fg(color::White),
-                        timestamp.format("%T"),
-                        attributes,
-                        color::Fg(color::Rgb(r, g, b)),
-                        author,
-                        color::Fg(color::White)
-                    ),
-                }?;
+    fn xmpp_prefix(&self, message: &VersionedXmppMessage) -> String {
+        let author = terminus::clean(
+            &match &message.type_ {
+                XmppMessageType::Channel => match &message.from_full {
+                    Jid::Full(from) => from.resource.clone(),
+                    Jid::Bare(from) => from.to_string(),
+                },
+                XmppMessageType::Chat => message.from.to_string(),
+            }
+            .to_string(),
+        );
 
-                let mut iter = match me {
-                    true => body.strip_prefix("/me").unwrap().lines(),
-                    false => body.lines(),
-                };
+        let timestamp = Local.from_utc_datetime(&message.get_original_timestamp().naive_local());
+        let timestamp = timestamp.format(&self.timestamp).to_string();
 
-                if let Some(line) = iter.next() {
-                    write!(f, "{}", terminus::clean(line))?;
-                }
-                while let Some(line) = iter.next() {
-                    write!(f, "\n{}{}", padding, terminus::clean(line))?;
-                }
+        let (r, g, b) = id_to_rgb(&author);
 
-                Ok(())
+        // In column mode nicks are right aligned in a fixed width column
+        let max_width = self.nick_column.or(self.nick_max_width);
+        let mut nick = match max_width {
+            Some(max) if max > 0 && terminus::term_string_visible_len(&author) > max => {
+                terminus::term_string_visible_truncate(&author, max, Some("…"))
+            }
+            _ => author.clone(),
+        };
+        if let Some(column) = self.nick_column {
+            let len = terminus::term_string_visible_len(&nick);
+            if len < column {
+                nick = format!("{}{}", " ".repeat(column - len), nick);
             }
         }
+
+        let mut attributes = "".to_string();
+        if message.has_multiple_version() {
+            attributes.push_str("✎ ");
+        }
+        if message.delayed {
+            attributes.push_str("↺ ");
+        }
+
+        let template = match message.get_last_body().starts_with("/me") {
+            true => &self.me,
+            false => &self.message,
+        };
+        template.render(|name| match name {
+            "time" => Some(timestamp.clone()),
+            "attributes" => Some(attributes.clone()),
+            "nick" => Some(format!(
+                "{}{}{}",
+                color::Fg(color::Rgb(r, g, b)),
+                nick,
+                color::Fg(color::White)
+            )),
+            _ => None,
+        })
     }
 }
 
-impl fmt::Display for contact::Group {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}{}{}",
-            color::Fg(color::Yellow),
-            terminus::clean(&self.0),
-            color::Fg(color::White)
-        )
+impl Default for MessageFormat {
+    fn default() -> Self {
+        // Default templates are known to be valid
+        Self::from_config(&FormatConfig::default()).unwrap()
     }
 }
 
-#[derive(Clone, Debug, Ord, PartialOrd)]
-pub enum RosterItem {
-    Contact(contact::Contact),
-    Bookmark(contact::Bookmark),
-    Window(String),
+/// Entries remembered for the messages shown, the oldest ones are forgotten first
+const REMEMBERED_LIMIT: usize = 1000;
+
+/// Values kept aside for the last messages or links shown, bounded so that long sessions don't
+/// grow them forever
+struct Remembered<V> {
+    /// Oldest first, at most REMEMBERED_LIMIT of them
+    order: VecDeque<String>,
+    values: HashMap<String, V>,
 }
 
-impl Hash for RosterItem {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        match self {
-            Self::Contact(contact) => contact.jid.hash(state),
-            Self::Bookmark(bookmark) => bookmark.jid.hash(state),
-            Self::Window(window) => window.hash(state),
-        };
+impl<V> Remembered<V> {
+    fn new() -> Self {
+        Self {
+            order: VecDeque::new(),
+            values: HashMap::new(),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&V> {
+        self.values.get(key)
+    }
+
+    /// Keeps the key where it is when it's already known, only its value is replaced
+    fn insert(&mut self, key: String, value: V) {
+        if let Some(known) = self.values.get_mut(&key) {
+            *known = value;
+            return;
+        }
+        if self.order.len() == REMEMBERED_LIMIT {
+            if let Some(oldest) = self.order.pop_front() {
+                self.values.remove(&oldest);
+            }
+        }
+        self.order.push_back(key.clone());
+        self.values.insert(key, value);
     }
 }
 
-impl PartialEq for RosterItem {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Self::Contact(a), Self::Contact(b)) => a.eq(b),
-            (Self::Bookmark(a), Self::Bookmark(b)) => a.eq(b),
-            (Self::Window(a), Self::Window(b)) => a.eq(b),
-            _ => false,
+thread_local! {
+    // fmt::Display cannot be given any context so the format is kept aside and set once the
+    // configuration is known
+    static MESSAGE_FORMAT: RefCell<MessageFormat> = RefCell::new(MessageFormat::default());
+    // Ids of messages highlighted by a filter
+    static HIGHLIGHTED_MESSAGES: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+    // Ids of spoiler messages whose body is revealed
+    static REVEALED_SPOILERS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+    // Rendered images of custom emoji, by conversation and shortname
+    static CUSTOM_EMOJI: RefCell<HashMap<BareJid, HashMap<String, String>>> =
+        RefCell::new(HashMap::new());
+    // How outgoing messages are protected, by message id
+    static PROTECTIONS: RefCell<Remembered<Protection>> = RefCell::new(Remembered::new());
+    // Rendered thumbnails of contact avatars
+    static AVATARS: RefCell<HashMap<BareJid, String>> = RefCell::new(HashMap::new());
+    // Moods and activities of contacts
+    static MOODS: RefCell<HashMap<BareJid, mods::mood::Mood>> = RefCell::new(HashMap::new());
+    static ACTIVITIES: RefCell<HashMap<BareJid, mods::mood::Activity>> =
+        RefCell::new(HashMap::new());
+    // Titles of the web pages linked in messages, by URL
+    static LINK_TITLES: RefCell<Remembered<String>> = RefCell::new(Remembered::new());
+}
+
+/// Suffix of an outgoing message telling how it's protected, discreet unless it isn't, or of a
+/// decrypted one telling why it can't be trusted
+fn protection_suffix(protection: &Protection) -> String {
+    match protection {
+        Protection::Pending => crate::color::dimmed(" [encrypting]"),
+        Protection::Encrypted(1) => crate::color::dimmed(" [encrypted]"),
+        Protection::Encrypted(count) => {
+            crate::color::dimmed(&format!(" [encrypted, {} keys]", count))
         }
+        Protection::Plaintext => format!(
+            "{}{} [NOT ENCRYPTED]{}{}",
+            termion::style::Bold,
+            color::Fg(color::Red),
+            termion::style::NoBold,
+            color::Fg(color::White)
+        ),
+        Protection::Failed => format!(
+            "{}{} [NOT SENT]{}{}",
+            termion::style::Bold,
+            color::Fg(color::Red),
+            termion::style::NoBold,
+            color::Fg(color::White)
+        ),
+        Protection::Unsigned => format!(
+            "{}{} [UNVERIFIED: NOT SIGNED]{}{}",
+            termion::style::Bold,
+            color::Fg(color::Red),
+            termion::style::NoBold,
+            color::Fg(color::White)
+        ),
+        Protection::UnknownKey => format!(
+            "{}{} [UNVERIFIED: UNKNOWN KEY]{}{}",
+            termion::style::Bold,
+            color::Fg(color::Red),
+            termion::style::NoBold,
+            color::Fg(color::White)
+        ),
     }
 }
 
-impl Eq for RosterItem {}
+/// Columns taken by a custom emoji image
+const EMOJI_WIDTH: usize = 2;
 
-impl fmt::Display for RosterItem {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self {
-            Self::Contact(contact) => {
-                match contact.presence {
-                    contact::Presence::Available | contact::Presence::Chat => {
-                        write!(f, "{}", color::Fg(color::Green))?
-                    }
-                    contact::Presence::Away
-                    | contact::Presence::Dnd
-                    | contact::Presence::Xa
-                    | contact::Presence::Unavailable => write!(f, "{}", color::Fg(color::White))?,
-                };
+/// Columns taken by an avatar thumbnail
+const AVATAR_WIDTH: usize = 2;
 
-                let disp = match &contact.name {
-                    Some(name) => format!(
-                        "{} ({})",
-                        terminus::clean(name),
-                        terminus::clean(&contact.jid.to_string()),
-                    ),
-                    None => terminus::clean(&contact.jid.to_string()),
-                };
+/// Characters of the last message shown by /switch
+const SWITCH_PREVIEW_LEN: usize = 40;
 
-                write!(f, "{}{}", disp, color::Fg(color::White))
-            }
+/// Messages and commands of the input saved with the session, each
+const HISTORY_LEN: usize = 100;
 
-            Self::Bookmark(bookmark) => {
-                let disp = match &bookmark.name {
-                    Some(name) => terminus::clean(name),
-                    None => terminus::clean(&bookmark.jid.to_string()),
-                };
+/// Window listing the entities browsed with /disco
+pub const DISCO_WINDOW: &str = "disco";
 
-                write!(f, "{}{}", disp, color::Fg(color::White))
-            }
-            Self::Window(window) => {
-                let disp = terminus::clean(window);
+/// Actions key sequences can be bound to, besides commands
+const KEY_ACTIONS: [&str; 5] = [
+    "next-window",
+    "previous-window",
+    "next-unread",
+    "toggle-roster",
+    "toggle-occupants",
+];
 
-                write!(f, "{}", disp)
-            }
+/// Modifier pressed along with a special key like an arrow, which termion doesn't report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Modifier {
+    Shift,
+    Alt,
+    Ctrl,
+}
+
+/// Key pressed, with its modifier when it is a special key
+type Keystroke = (Option<Modifier>, Key);
+
+/// Key written like `C-b`, `M-x`, `F5`, `PageUp`, `S-Up`, `M-Left` or a single character
+fn parse_key(name: &str) -> Result<Keystroke, String> {
+    let single = |name: &str| {
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ if name.eq_ignore_ascii_case("space") => Some(' '),
+            _ => None,
         }
+    };
+    if let Some(c) = name.strip_prefix("C-").and_then(single) {
+        return Ok((None, Key::Ctrl(c.to_ascii_lowercase())));
     }
+    if let Some(c) = name.strip_prefix("M-").and_then(single) {
+        return Ok((None, Key::Alt(c)));
+    }
+    if let Some(c) = single(name) {
+        return Ok((None, Key::Char(c)));
+    }
+    let (modifier, special) = match name.get(..2) {
+        Some("S-") => (Some(Modifier::Shift), &name[2..]),
+        Some("M-") => (Some(Modifier::Alt), &name[2..]),
+        Some("C-") => (Some(Modifier::Ctrl), &name[2..]),
+        _ => (None, name),
+    };
+    let key = match special.to_lowercase().as_str() {
+        "tab" if modifier.is_none() => Key::Char('\t'),
+        "enter" if modifier.is_none() => Key::Char('\n'),
+        "esc" if modifier.is_none() => Key::Esc,
+        "backspace" if modifier.is_none() => Key::Backspace,
+        "backtab" if modifier.is_none() => Key::BackTab,
+        "delete" => Key::Delete,
+        "insert" => Key::Insert,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "pageup" => Key::PageUp,
+        "pagedown" => Key::PageDown,
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        function => match function.strip_prefix('f').map(u8::from_str) {
+            Some(Ok(n)) if (1..=12).contains(&n) => Key::F(n),
+            _ => return Err(format!("Unknown key {}", name)),
+        },
+    };
+    Ok((modifier, key))
 }
 
-impl fmt::Display for conversation::Occupant {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let (r, g, b) = id_to_rgb(&self.nick);
-        let nick = self.nick.clone();
+/// Keys of a sequence separated by spaces, `<leader>` standing for the leader key
+fn parse_keys(sequence: &str, leader: Option<Keystroke>) -> Result<Vec<Keystroke>, String> {
+    sequence
+        .split_whitespace()
+        .map(|name| match name {
+            "<leader>" => leader.ok_or("No leader key set".to_string()),
+            name => parse_key(name),
+        })
+        .collect::<Result<Vec<Keystroke>, String>>()
+        .and_then(|keys| match keys.is_empty() {
+            true => Err("Empty key sequence".to_string()),
+            false => Ok(keys),
+        })
+}
 
-        write!(
-            f,
-            "{}{}{}",
+/// Key written the way it is configured
+fn key_name(keystroke: &Keystroke) -> String {
+    let modifier = match keystroke.0 {
+        Some(Modifier::Shift) => "S-",
+        Some(Modifier::Alt) => "M-",
+        Some(Modifier::Ctrl) => "C-",
+        None => "",
+    };
+    let key = match keystroke.1 {
+        Key::Ctrl(c) => format!("C-{}", c),
+        Key::Alt(' ') => String::from("M-space"),
+        Key::Alt(c) => format!("M-{}", c),
+        Key::Char(' ') => String::from("space"),
+        Key::Char('\t') => String::from("Tab"),
+        Key::Char('\n') => String::from("Enter"),
+        Key::Char(c) => c.to_string(),
+        Key::F(n) => format!("F{}", n),
+        key => format!("{:?}", key),
+    };
+    format!("{}{}", modifier, key)
+}
+
+/// Avatar image when the terminal can display it, a block colored after its hash otherwise
+fn avatar_thumbnail(avatar: &mods::avatar::Avatar, protocol: GraphicsProtocol) -> String {
+    let image = avatar
+        .image
+        .as_ref()
+        .and_then(|image| render_inline(&image.data, &image.type_, protocol, AVATAR_WIDTH));
+    image.unwrap_or_else(|| {
+        let (r, g, b) = crate::color::id_to_rgb(&avatar.hash);
+        format!(
+            "{}{}",
             color::Fg(color::Rgb(r, g, b)),
-            terminus::clean(&nick),
-            color::Fg(color::White)
+            "█".repeat(AVATAR_WIDTH)
         )
+    })
+}
+
+/// Thumbnail of the avatar of a contact followed by a space, if it has one
+fn avatar_prefix(contact: &BareJid) -> Option<String> {
+    AVATARS.with(|avatars| {
+        avatars
+            .borrow()
+            .get(contact)
+            .map(|thumbnail| format!("{} ", thumbnail))
+    })
+}
+
+/// Mood and activity of a contact shown next to them in the roster, without their text
+pub fn mood_suffix(contact: &BareJid) -> Option<String> {
+    let mood = MOODS.with(|moods| {
+        let moods = moods.borrow();
+        let mood = moods.get(contact)?;
+        Some(mood.mood.replace('_', " "))
+    });
+    let activity = ACTIVITIES.with(|activities| {
+        let activities = activities.borrow();
+        let activity = activities.get(contact)?;
+        let activity = activity.specific.as_ref().unwrap_or(&activity.general);
+        Some(activity.replace('_', " "))
+    });
+    match (mood, activity) {
+        (Some(mood), Some(activity)) => Some(format!("{}, {}", mood, activity)),
+        (mood, activity) => mood.or(activity),
     }
 }
 
-impl fmt::Display for conversation::Role {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            conversation::Role::Moderator => write!(
-                f,
-                "{}Moderators{}",
-                color::Fg(color::Yellow),
-                color::Fg(color::Yellow)
-            ),
-            conversation::Role::Participant => write!(
-                f,
-                "{}Participants{}",
-                color::Fg(color::Yellow),
-                color::Fg(color::Yellow)
-            ),
-            conversation::Role::Visitor => write!(
-                f,
-                "{}Visitors{}",
-                color::Fg(color::Yellow),
-                color::Fg(color::Yellow)
-            ),
-            conversation::Role::None => write!(
-                f,
-                "{}Others{}",
-                color::Fg(color::Yellow),
-                color::Fg(color::Yellow)
-            ),
+/// Replace the shortcodes of custom emoji having an image
+fn with_custom_emoji(conversation: &BareJid, line: String) -> String {
+    CUSTOM_EMOJI.with(|emoji| match emoji.borrow().get(conversation) {
+        Some(set) => set.iter().fold(line, |line, (name, rendered)| {
+            line.replace(&format!(":{}:", name), rendered)
+        }),
+        None => line,
+    })
+}
+
+/// Message selection in conversation windows: Ctrl-p and Ctrl-n move the selection, Ctrl-o
+/// reveals or hides the selected spoiler
+fn select_message(view: &mut BufferedWin<UIEvent, Stdout, Message>, key: &Key) {
+    match key {
+        Key::Ctrl('p') => view.select_previous(),
+        Key::Ctrl('n') => view.select_next(),
+        Key::Ctrl('o') => {
+            if let Some(Message::Xmpp(message)) = view.selected() {
+                if message.spoiler.is_some() {
+                    REVEALED_SPOILERS.with(|revealed| {
+                        let mut revealed = revealed.borrow_mut();
+                        if !revealed.remove(&message.id) {
+                            revealed.insert(message.id.clone());
+                        }
+                    });
+                    view.dirty = true;
+                }
+            }
         }
+        _ => {}
     }
 }
 
-pub struct Scheduler {
-    queue: Rc<RefCell<Vec<Event>>>,
+/// Enter pressed in a conversation window: fetch the messages missing where the selected gap
+/// placeholder is, if any
+fn activate_message(view: &mut BufferedWin<UIEvent, Stdout, Message>, scheduler: &Scheduler) {
+    let gap = view
+        .selected()
+        .filter(|message| mods::mam::is_gap(message))
+        .cloned();
+    if let Some(gap) = gap {
+        view.remove(&gap);
+        scheduler.schedule(Event::FetchGap(gap.id().to_string()));
+    }
 }
 
-impl Scheduler {
-    pub fn schedule(&self, event: Event) {
-        let mut queue = self.queue.borrow_mut();
-        queue.push(event);
+/// Select the first message sent at or after a date, or the last one
+fn select_date(view: &mut BufferedWin<UIEvent, Stdout, Message>, date: &DateTime<FixedOffset>) {
+    let message = view
+        .history
+        .iter()
+        .find(|message| message.timestamp() >= date)
+        .or_else(|| view.history.iter().last())
+        .cloned();
+    if let Some(message) = message {
+        view.select(&message);
     }
 }
 
-struct PanicHandler {
-    panic: Arc<Mutex<Option<String>>>,
-    backtrace: Arc<Mutex<Option<Backtrace>>>,
+/// Wrapped lines of messages are aligned on the message body when nicks are displayed in a column
+fn message_hanging_indent(message: &Message) -> usize {
+    MESSAGE_FORMAT.with(|format| {
+        let format = format.borrow();
+        if format.nick_column.is_none() {
+            return 0;
+        }
+
+        let prefix = match message {
+            Message::Log(message) => format.log_prefix(message),
+            Message::Xmpp(message) => format.xmpp_prefix(message),
+        };
+        terminus::term_string_visible_len(&prefix)
+    })
 }
 
-impl PanicHandler {
-    pub fn new() -> Self {
-        let panic = Arc::new(Mutex::new(None));
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        MESSAGE_FORMAT.with(|format| {
+            let format = format.borrow();
+            match self {
+                Message::Log(message) => {
+                    let prefix = format.log_prefix(message);
+                    for line in message.body.lines() {
+                        write!(f, "{}{}{}\n", color::Fg(color::White), prefix, line)?;
+                    }
+
+                    Ok(())
+                }
+                Message::Xmpp(message) => {
+                    let prefix = format.xmpp_prefix(message);
+                    let padding = " ".repeat(terminus::term_string_visible_len(&prefix));
+
+                    write!(f, "{}{}", color::Fg(color::White), prefix)?;
+
+                    if let Some(hint) = &message.spoiler {
+                        let revealed = REVEALED_SPOILERS
+                            .with(|revealed| revealed.borrow().contains(&message.id));
+                        let hint = match hint.is_empty() {
+                            true => "spoiler".to_string(),
+                            false => format!("spoiler: {}", terminus::clean(hint)),
+                        };
+                        if !revealed {
+                            write!(
+                                f,
+                                "{}",
+                                crate::color::dimmed(&format!("[{}] Ctrl-o to reveal", hint))
+                            )?;
+                            return Ok(());
+                        }
+                        write!(f, "[{}] ", hint)?;
+                    }
+
+                    let highlighted = HIGHLIGHTED_MESSAGES
+                        .with(|highlighted| highlighted.borrow().contains(&message.id));
+                    if highlighted {
+                        write!(f, "{}{}", termion::style::Bold, color::Fg(color::Yellow))?;
+                    }
+
+                    let body = message.get_last_body();
+                    let iter = match body.starts_with("/me") {
+                        true => body.strip_prefix("/me").unwrap().lines(),
+                        false => body.lines(),
+                    };
+                    let conversation = message.conversation();
+
+                    // Links to shared files are replaced by their description
+                    let in_media = |link: &str| {
+                        message
+                            .media
+                            .iter()
+                            .any(|media| media.sources.iter().any(|source| source == link))
+                    };
+                    // Files described by SIMS are shown once, with their metadata
+                    let oob = message.oob.as_ref().filter(|oob| !in_media(&oob.url));
+                    let shared = |line: &str| {
+                        in_media(line.trim()) || oob.map(|oob| oob.url == line.trim()) == Some(true)
+                    };
+                    let mut lines = iter
+                        .filter(|line| !shared(line))
+                        .map(|line| with_custom_emoji(conversation, terminus::clean(line)))
+                        .collect::<Vec<_>>();
+                    for media in &message.media {
+                        lines.extend(media_lines(media));
+                    }
+                    if let Some(oob) = oob {
+                        lines.push(format!("[{}]", terminus::clean(&oob.summary())));
+                        lines.push(crate::color::dimmed(&terminus::clean(&oob.url)));
+                    }
+
+                    let mut lines = lines.iter();
+                    if let Some(line) = lines.next() {
+                        write!(f, "{}", line)?;
+                    }
+                    for line in lines {
+                        write!(f, "\n{}{}", padding, line)?;
+                    }
+
+                    if highlighted {
+                        write!(f, "{}{}", termion::style::NoBold, color::Fg(color::White))?;
+                    }
+
+                    let protection = PROTECTIONS
+                        .with(|protections| protections.borrow().get(&message.id).cloned());
+                    if let Some(protection) = protection {
+                        write!(f, "{}", protection_suffix(&protection))?;
+                    }
+
+                    let titles = LINK_TITLES.with(|titles| {
+                        let titles = titles.borrow();
+                        mods::preview::links(body)
+                            .into_iter()
+                            .filter_map(|link| titles.get(link).cloned())
+                            .collect::<Vec<_>>()
+                    });
+                    for title in titles {
+                        let title = crate::color::dimmed(&terminus::clean(&title));
+                        write!(f, "\n{}{}", padding, title)?;
+                    }
+
+                    Ok(())
+                }
+            }
+        })
+    }
+}
+
+/// File shared in a message, its description followed by where to download it and how to check
+/// it
+fn media_lines(media: &MediaSharing) -> Vec<String> {
+    let mut lines = vec![format!("[{}]", terminus::clean(&media.summary()))];
+    for source in &media.sources {
+        lines.push(crate::color::dimmed(&terminus::clean(source)));
+    }
+    let mut details = media
+        .hashes
+        .iter()
+        .map(|(algo, hash)| format!("{}: {}", algo, hash))
+        .collect::<Vec<_>>();
+    if let Some(thumbnail) = &media.thumbnail {
+        details.push(format!("thumbnail: {}", thumbnail));
+    }
+    if !details.is_empty() {
+        lines.push(crate::color::dimmed(&terminus::clean(&details.join(", "))));
+    }
+    lines
+}
+
+/// Message found by /search
+pub struct SearchResult {
+    pub account: Account,
+    pub message: VersionedXmppMessage,
+    /// Part of the body around the searched text
+    pub snippet: String,
+}
+
+impl Hash for SearchResult {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.message.id.hash(state);
+    }
+}
+
+impl PartialEq for SearchResult {
+    fn eq(&self, other: &Self) -> bool {
+        self.message.id == other.message.id
+    }
+}
+
+impl Eq for SearchResult {}
+
+impl Ord for SearchResult {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.message.get_original_timestamp(), &self.message.id)
+            .cmp(&(other.message.get_original_timestamp(), &other.message.id))
+    }
+}
+
+impl PartialOrd for SearchResult {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for SearchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = &self.message;
+        let timestamp = Local.from_utc_datetime(&message.get_original_timestamp().naive_local());
+        let author = match (&message.type_, &message.from_full) {
+            (XmppMessageType::Channel, Jid::Full(from)) => from.resource.clone(),
+            _ => message.from.to_string(),
+        };
+        let (r, g, b) = id_to_rgb(&author);
+        write!(
+            f,
+            "{} {} {}{}{}: {}",
+            crate::color::dimmed(&timestamp.format("%Y-%m-%d %H:%M").to_string()),
+            terminus::clean(&message.conversation().to_string()),
+            color::Fg(color::Rgb(r, g, b)),
+            terminus::clean(&author),
+            color::Fg(color::White),
+            terminus::clean(&self.snippet)
+        )
+    }
+}
+
+/// Window listed by /switch, with its recent activity
+pub struct SwitchItem {
+    pub window: String,
+    /// Activity of the window drawn as a sparkline, blank for windows without messages
+    pub sparkline: String,
+    /// Last message exchanged in the window, if any
+    pub last: Option<VersionedXmppMessage>,
+}
+
+impl SwitchItem {
+    fn last_timestamp(&self) -> Option<&DateTime<FixedOffset>> {
+        self.last
+            .as_ref()
+            .map(|message| message.get_original_timestamp())
+    }
+}
+
+impl Hash for SwitchItem {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.window.hash(state);
+    }
+}
+
+impl PartialEq for SwitchItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.window == other.window
+    }
+}
+
+impl Eq for SwitchItem {}
+
+/// Windows without messages first, then by last message so that the busiest are near the input
+impl Ord for SwitchItem {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.last_timestamp(), &self.window).cmp(&(other.last_timestamp(), &other.window))
+    }
+}
+
+impl PartialOrd for SwitchItem {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for SwitchItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.sparkline, terminus::clean(&self.window))?;
+        if let Some(message) = &self.last {
+            let author = match (&message.type_, &message.from_full) {
+                (XmppMessageType::Channel, Jid::Full(from)) => from.resource.clone(),
+                _ => message.from.to_string(),
+            };
+            let body = message.get_last_body().lines().next().unwrap_or("");
+            let preview = match body.chars().count() > SWITCH_PREVIEW_LEN {
+                true => format!(
+                    "{}…",
+                    body.chars().take(SWITCH_PREVIEW_LEN).collect::<String>()
+                ),
+                false => body.to_string(),
+            };
+            let (r, g, b) = id_to_rgb(&author);
+            write!(
+                f,
+                " — {}{}{}: {}",
+                color::Fg(color::Rgb(r, g, b)),
+                terminus::clean(&author),
+                color::Fg(color::White),
+                crate::color::dimmed(&terminus::clean(&preview))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Line of the disco window, items leading to the discovery of their entity
+#[derive(Clone)]
+pub struct DiscoLine {
+    pub index: usize,
+    pub text: String,
+    /// Entity and node discovered when the line is activated
+    pub target: Option<(Jid, Option<String>)>,
+}
+
+impl Hash for DiscoLine {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+    }
+}
+
+impl PartialEq for DiscoLine {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl Eq for DiscoLine {}
+
+impl Ord for DiscoLine {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.index.cmp(&other.index)
+    }
+}
+
+impl PartialOrd for DiscoLine {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for DiscoLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", terminus::clean(&self.text))
+    }
+}
+
+/// Select the next line leading to an entity in the disco window, or the previous one
+fn select_disco_target(view: &mut BufferedWin<UIEvent, Stdout, DiscoLine>, forward: bool) {
+    let selected = view.selected().map(|line| line.index);
+    let mut targets = view.history.iter().filter(|line| line.target.is_some());
+    let line = match (forward, selected) {
+        (true, Some(selected)) => targets.find(|line| line.index > selected),
+        (true, None) => targets.next(),
+        (false, Some(selected)) => targets.rev().find(|line| line.index < selected),
+        (false, None) => targets.next_back(),
+    };
+    if let Some(line) = line.cloned() {
+        view.select(&line);
+    }
+}
+
+/// Discover an entity from the disco window
+fn disco_command(account: &Account, jid: &Jid, node: &Option<String>) -> Event {
+    let mut args = vec![String::from("disco"), jid.to_string()];
+    args.extend(node.clone());
+    Event::Command(Command {
+        account: Some(account.clone()),
+        context: String::from(DISCO_WINDOW),
+        args,
+        cursor: 0,
+    })
+}
+
+/// Line of the presence feed for a contact update, None when neither its presence nor its status
+/// changed or when it isn't in one of the followed groups
+fn presence_change(
+    known: &mut HashMap<BareJid, (contact::Presence, Option<String>)>,
+    groups: &[contact::Group],
+    contact: &contact::Contact,
+) -> Option<String> {
+    if !groups.is_empty() && !contact.groups.iter().any(|group| groups.contains(group)) {
+        return None;
+    }
+
+    let state = (contact.presence.clone(), contact.status.clone());
+    if known.get(&contact.jid) == Some(&state) {
+        return None;
+    }
+    known.insert(contact.jid.clone(), state);
+
+    let name = match &contact.name {
+        Some(name) => format!("{} ({})", name, contact.jid),
+        None => contact.jid.to_string(),
+    };
+    let change = format!("{} is {}", terminus::clean(&name), contact.presence);
+    match &contact.status {
+        Some(status) => Some(format!("{}: {}", change, terminus::clean(status))),
+        None => Some(change),
+    }
+}
+
+impl fmt::Display for contact::Group {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            color::Fg(color::Yellow),
+            terminus::clean(&self.0),
+            color::Fg(color::White)
+        )
+    }
+}
+
+impl fmt::Display for contact::Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            contact::Presence::Unavailable => write!(f, "offline"),
+            contact::Presence::Available => write!(f, "online"),
+            contact::Presence::Away => write!(f, "away"),
+            contact::Presence::Chat => write!(f, "free for chat"),
+            contact::Presence::Dnd => write!(f, "busy"),
+            contact::Presence::Xa => write!(f, "away for a while"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Ord, PartialOrd)]
+pub enum RosterItem {
+    Contact(contact::Contact),
+    Bookmark(contact::Bookmark),
+    Window(String),
+}
+
+impl Hash for RosterItem {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Self::Contact(contact) => contact.jid.hash(state),
+            Self::Bookmark(bookmark) => bookmark.jid.hash(state),
+            Self::Window(window) => window.hash(state),
+        };
+    }
+}
+
+impl PartialEq for RosterItem {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Contact(a), Self::Contact(b)) => a.eq(b),
+            (Self::Bookmark(a), Self::Bookmark(b)) => a.eq(b),
+            (Self::Window(a), Self::Window(b)) => a.eq(b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for RosterItem {}
+
+impl fmt::Display for RosterItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            Self::Contact(contact) => {
+                if let Some(avatar) = avatar_prefix(&contact.jid) {
+                    write!(f, "{}", avatar)?;
+                }
+                match contact.presence {
+                    contact::Presence::Available | contact::Presence::Chat => {
+                        write!(f, "{}", color::Fg(color::Green))?
+                    }
+                    contact::Presence::Away
+                    | contact::Presence::Dnd
+                    | contact::Presence::Xa
+                    | contact::Presence::Unavailable => write!(f, "{}", color::Fg(color::White))?,
+                };
+
+                let disp = match &contact.name {
+                    Some(name) => format!(
+                        "{} ({})",
+                        terminus::clean(name),
+                        terminus::clean(&contact.jid.to_string()),
+                    ),
+                    None => terminus::clean(&contact.jid.to_string()),
+                };
+
+                write!(f, "{}{}", disp, color::Fg(color::White))?;
+                if let Some(mood) = mood_suffix(&contact.jid) {
+                    write!(f, " {}", crate::color::dimmed(&mood))?;
+                }
+                if let Some(since) = &contact.idle {
+                    let idle = idle_since(since, &Local::now());
+                    write!(f, " {}", crate::color::dimmed(&idle))?;
+                }
+                Ok(())
+            }
+
+            Self::Bookmark(bookmark) => {
+                let disp = match &bookmark.name {
+                    Some(name) => terminus::clean(name),
+                    None => terminus::clean(&bookmark.jid.to_string()),
+                };
+
+                write!(f, "{}{}", disp, color::Fg(color::White))
+            }
+            Self::Window(window) => {
+                let disp = terminus::clean(window);
+
+                write!(f, "{}", disp)
+            }
+        }
+    }
+}
+
+/// Details about a room occupant shown in the status bar
+fn occupant_info(occupant: &conversation::Occupant) -> String {
+    let jid = match &occupant.jid {
+        Some(jid) => format!(" <{}>", jid),
+        None => String::new(),
+    };
+    let role = match occupant.role {
+        conversation::Role::Moderator => "moderator",
+        conversation::Role::Participant => "participant",
+        conversation::Role::Visitor => "visitor",
+        conversation::Role::None => "none",
+    };
+    let joined = Local.from_utc_datetime(&occupant.joined.naive_utc());
+    terminus::clean(&format!(
+        "{}{}, affiliation {}, role {}, here since {}",
+        occupant.nick,
+        jid,
+        occupant.affiliation,
+        role,
+        joined.format("%Y-%m-%d %H:%M")
+    ))
+}
+
+impl fmt::Display for conversation::Occupant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (r, g, b) = id_to_rgb(&self.nick);
+        let nick = self.nick.clone();
+        // Owners and admins are told apart within the groups of roles, like on IRC
+        let mark = match self.affiliation {
+            conversation::Affiliation::Owner => "~",
+            conversation::Affiliation::Admin => "&",
+            _ => "",
+        };
+
+        write!(
+            f,
+            "{}{}{}{}",
+            mark,
+            color::Fg(color::Rgb(r, g, b)),
+            terminus::clean(&nick),
+            color::Fg(color::White)
+        )
+    }
+}
+
+impl fmt::Display for conversation::Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            conversation::Role::Moderator => write!(
+                f,
+                "{}Moderators{}",
+                color::Fg(color::Yellow),
+                color::Fg(color::Yellow)
+            ),
+            conversation::Role::Participant => write!(
+                f,
+                "{}Participants{}",
+                color::Fg(color::Yellow),
+                color::Fg(color::Yellow)
+            ),
+            conversation::Role::Visitor => write!(
+                f,
+                "{}Visitors{}",
+                color::Fg(color::Yellow),
+                color::Fg(color::Yellow)
+            ),
+            conversation::Role::None => write!(
+                f,
+                "{}Others{}",
+                color::Fg(color::Yellow),
+                color::Fg(color::Yellow)
+            ),
+        }
+    }
+}
+
+pub struct Scheduler {
+    queue: Rc<RefCell<Vec<Event>>>,
+}
+
+impl Scheduler {
+    pub fn schedule(&self, event: Event) {
+        let mut queue = self.queue.borrow_mut();
+        queue.push(event);
+    }
+}
+
+struct PanicHandler {
+    panic: Arc<Mutex<Option<String>>>,
+    backtrace: Arc<Mutex<Option<Backtrace>>>,
+}
+
+impl PanicHandler {
+    pub fn new() -> Self {
+        let panic = Arc::new(Mutex::new(None));
         let backtrace = Arc::new(Mutex::new(None));
 
-        let panic_for_hook = panic.clone();
-        let backtrace_for_hook = backtrace.clone();
-        panic::set_hook(Box::new(move |info| {
-            let panic = format!("{}", info);
-            panic_for_hook
-                .lock()
-                .expect("cannot lock panic")
-                .replace(panic);
+        let panic_for_hook = panic.clone();
+        let backtrace_for_hook = backtrace.clone();
+        panic::set_hook(Box::new(move |info| {
+            let panic = format!("{}", info);
+            panic_for_hook
+                .lock()
+                .expect("cannot lock panic")
+                .replace(panic);
+
+            let backtrace = Backtrace::new_unresolved();
+            backtrace_for_hook
+                .lock()
+                .expect("cannot lock backtrace")
+                .replace(backtrace);
+        }));
+
+        Self { panic, backtrace }
+    }
+}
+
+impl Drop for PanicHandler {
+    fn drop(&mut self) {
+        if let Some(panic) = self.panic.lock().expect("cannot lock panic").as_ref() {
+            println!("Oops Aparté {}", panic);
+            error!("Oops Aparté {}", panic);
+            println!("This isn’t normal behavior. Please report issue.");
+            error!("This isn’t normal behavior. Please report issue.");
+            if let Some(backtrace) = self
+                .backtrace
+                .lock()
+                .expect("cannot lock backtrace")
+                .as_mut()
+            {
+                println!("Aparté is gathering more info in logfile…");
+                backtrace.resolve();
+                error!("{:?}", backtrace);
+                println!("All done.");
+                let data_dir = dirs::data_dir().unwrap();
+                let aparte_data = data_dir.join("aparte").join("aparte.log");
+                println!("Please check {}", aparte_data.to_str().unwrap());
+            }
+        }
+    }
+}
+
+command_def!(
+    graphics,
+    r#"/graphics
+
+Description:
+    Show the protocol used to display images and a test pattern. The protocol
+    is detected from the terminal and can be forced with the graphics option."#,
+    {},
+    |aparte, _command| {
+        let protocol = {
+            let ui = aparte.get_mod::<UIMod>();
+            ui.graphics()
+        };
+
+        let pattern = Image::from_fn(32, 8, |x, y| {
+            ((x * 8) as u8, (y * 32) as u8, (255 - x * 8) as u8)
+        });
+        aparte.log(format!(
+            "Images are displayed with {} graphics:\n{}",
+            protocol,
+            pattern.render(protocol).join("\n")
+        ));
+        Ok(())
+    }
+);
+
+command_def!(
+    qr,
+    r#"/qr [<text>]
+
+    text    Text to encode, like an encryption fingerprint
+
+Description:
+    Show a QR code of the xmpp: URI of the current account, or of the given
+    text, to be scanned by mobile clients.
+
+Examples:
+    /qr
+    /qr 7bf0 6e3a 2ec3 5ed4 1d9e 7e1b 5a0c 1ee3"#,
+    {
+        text: Option<String>
+    },
+    |aparte, _command| {
+        let text = match text {
+            Some(text) => text,
+            None => {
+                let account = aparte
+                    .current_account()
+                    .ok_or("No connection found".to_string())?;
+                format!("xmpp:{}", BareJid::from(Jid::Full(account)))
+            }
+        };
+
+        let qrcode = QrCode::encode(text.as_bytes())?;
+        let protocol = {
+            let ui = aparte.get_mod::<UIMod>();
+            ui.graphics()
+        };
+        aparte.log(format!(
+            "{}\n{}",
+            text,
+            qrcode.to_image().render(protocol).join("\n")
+        ));
+        Ok(())
+    }
+);
+
+command_def!(
+    presences,
+    r#"/presences [<groups>]
+
+    groups    Comma separated roster groups to follow, all contacts by default
+
+Description:
+    Open a window showing a live feed of presence changes (online, offline
+    and status text) of the roster contacts of the current account. Running
+    it again replaces the followed groups.
+
+Examples:
+    /presences
+    /presences Friends,Work"#,
+    {
+        groups: Option<String>
+    },
+    |aparte, _command| {
+        let account = aparte
+            .current_account()
+            .ok_or("No connection found".to_string())?;
+        let groups = match groups {
+            Some(groups) => groups
+                .split(',')
+                .map(|group| group.trim())
+                .filter(|group| !group.is_empty())
+                .map(|group| contact::Group(group.to_string()))
+                .collect(),
+            None => Vec::new(),
+        };
+        let mut ui = aparte.get_mod_mut::<UIMod>();
+        ui.add_presence_window(&account, groups);
+        Ok(())
+    }
+);
+
+command_def!(panel,
+r#"/panel occupants"#,
+{
+    panel: Command = {
+        children: {
+            "occupants": panel_occupants,
+        }
+    },
+});
+
+command_def!(panel_occupants,
+r#"/panel occupants toggle|width"#,
+{
+    action: Command = {
+        children: {
+            "toggle": panel_occupants_toggle,
+            "width": panel_occupants_width,
+        }
+    },
+});
+
+/// Channel of the window a command is run in
+fn command_channel(aparte: &Aparte, command: &Command) -> Result<BareJid, String> {
+    let ui = aparte.get_mod::<UIMod>();
+    match ui.conversations.get(&command.context) {
+        Some(Conversation::Channel(channel)) => Ok(channel.jid.clone()),
+        _ => Err("Not in a channel".to_string()),
+    }
+}
+
+command_def!(
+    panel_occupants_toggle,
+    r#"/panel occupants toggle
+
+Description:
+    Show the occupants of the current channel if they are hidden, and hide
+    them otherwise. Only this channel is affected, unlike with Alt-o. Channels
+    can start with hidden occupants with hidden_occupants in the layout
+    configuration."#,
+    {},
+    |aparte, command| {
+        let room = command_channel(aparte, &command)?;
+        {
+            let mut ui = aparte.get_mod_mut::<UIMod>();
+            ui.toggle_occupants(aparte, &room);
+        }
+        // Commands run once the UI handled their event, it is laid out again on the next one
+        aparte.schedule(Event::WindowChange);
+        Ok(())
+    }
+);
+
+command_def!(
+    panel_occupants_width,
+    r#"/panel occupants width <width>
+
+    width    Columns, or percentage of the terminal followed by %
+
+Description:
+    Resize the occupants of all the channels, until Aparté is restarted. The
+    width is set for good with occupants_width in the layout configuration.
+
+Examples:
+    /panel occupants width 20
+    /panel occupants width 25%"#,
+    {
+        width: PanelWidth
+    },
+    |aparte, _command| {
+        {
+            let mut ui = aparte.get_mod_mut::<UIMod>();
+            ui.resize_occupants(width);
+        }
+        aparte.schedule(Event::WindowChange);
+        Ok(())
+    }
+);
+
+pub struct UIMod {
+    screen: Screen<Stdout>,
+    windows: Vec<String>,
+    current_window: Option<String>,
+    unread_windows: LinkedHashSet<String>,
+    conversations: HashMap<String, Conversation>,
+    /// Workspace opened, whose conversations are the only ones listed in the window bar
+    workspace: Option<mods::workspace::Workspace>,
+    root: LinearLayout<UIEvent, Stdout>,
+    dimension: Option<Dimension>,
+    password_command: Option<Command>,
+    /// Command waiting for the next validated input, see Event::ReadInput
+    input_command: Option<Command>,
+    /// Message held back because of its length, waiting for confirmation
+    pending_paste: Option<String>,
+    /// Protocol used to display images
+    graphics: GraphicsProtocol,
+    /// Where the session is saved, if there is a data directory
+    session: Option<PathBuf>,
+    /// What's left to restore of the previous session
+    restored: Session,
+    /// How far restored windows were scrolled up
+    restored_scroll: HashMap<String, usize>,
+    /// Chat windows being reopened in the background
+    restoring: HashSet<String>,
+    /// Whether the terminal has the focus, unknown until it reports a change
+    focused: Option<bool>,
+    /// Sidebars shown or hidden with Alt-r and Alt-o, whatever the width of the terminal
+    sidebars: HashMap<Sidebar, bool>,
+    /// Channels whose occupants are shown or hidden with /panel
+    room_occupants: HashMap<BareJid, bool>,
+    /// Width of the occupants set with /panel, overriding the configured one
+    occupants_width: Option<PanelWidth>,
+    /// Configured key sequences with the action or command they are bound to
+    bindings: Vec<(Vec<Keystroke>, String)>,
+    /// Keys of the sequence being typed
+    chord: Vec<Keystroke>,
+    /// Sequences started so far, telling which one a timeout is for
+    chords: u64,
+    outgoing_event_queue: Rc<RefCell<Vec<Event>>>,
+    #[allow(dead_code)]
+    panic_handler: Option<PanicHandler>, // Defining panic_handler last guarantee that it will be dropped last (after terminal restoration)
+}
+
+impl UIMod {
+    pub fn new() -> Self {
+        let stdout = std::io::stdout().into_raw_mode().unwrap();
+        let screen = Screen::terminal(AlternateScreen::from(stdout));
+        let session = dirs::data_dir().map(|dir| dir.join("aparte").join("session.toml"));
+        Self::with_screen(screen, session, Some(PanicHandler::new()))
+    }
+
+    /// UI drawn in memory, without session nor panic handler, to run Aparté in tests
+    #[cfg(test)]
+    pub fn headless(width: u16, height: u16) -> Self {
+        Self::with_screen(Screen::headless(width, height), None, None)
+    }
+
+    fn with_screen(
+        mut screen: Screen<Stdout>,
+        session: Option<PathBuf>,
+        panic_handler: Option<PanicHandler>,
+    ) -> Self {
+        vprint!(
+            screen,
+            "{}{}",
+            ENABLE_BRACKETED_PASTE,
+            ENABLE_FOCUS_REPORTING
+        );
+
+        let mut layout = LinearLayout::<UIEvent, Stdout>::new(Orientation::Vertical).with_event(
+            |layout, event| {
+                for child in layout.iter_children_mut() {
+                    child.event(event);
+                }
+            },
+        );
+
+        let title_bar = TitleBar::new();
+        let frame =
+            FrameLayout::<UIEvent, Stdout, String>::new().with_event(|frame, event| match event {
+                UIEvent::Core(Event::ChangeWindow(name)) => {
+                    frame.set_current(name.to_string());
+                }
+                UIEvent::AddWindow(name, view) => {
+                    let view = view.take().unwrap();
+                    frame.insert_boxed(name.to_string(), view);
+
+                    // propagate AddWindow with name only to each subview
+                    // required at least for console view
+                    for child in frame.iter_children_mut() {
+                        child.event(&mut UIEvent::AddWindow(name.to_string(), None));
+                    }
+                }
+                UIEvent::Core(Event::Close(window)) => {
+                    frame.remove(&window);
+
+                    // propagate Close with name only to each subview
+                    // required at least for console view
+                    for child in frame.iter_children_mut() {
+                        child.event(&mut UIEvent::Core(Event::Close(window.clone())));
+                    }
+                }
+                UIEvent::Core(Event::Key(Key::PageUp))
+                | UIEvent::Core(Event::Key(Key::PageDown))
+                | UIEvent::Core(Event::Key(Key::Ctrl('p')))
+                | UIEvent::Core(Event::Key(Key::Ctrl('n')))
+                | UIEvent::Core(Event::Key(Key::Ctrl('o')))
+                | UIEvent::Browse(_)
+                | UIEvent::Activate => {
+                    if let Some(current) = frame.get_current_mut() {
+                        current.event(event);
+                    }
+                }
+                _ => {
+                    for child in frame.iter_children_mut() {
+                        child.event(event);
+                    }
+                }
+            });
+        let win_bar = WinBar::new();
+        let input = Input::new().with_event(|input, event| match event {
+            UIEvent::Core(Event::Key(Key::Char(c))) => input.key(*c),
+            UIEvent::Core(Event::Key(Key::Backspace)) => input.backspace(),
+            UIEvent::Core(Event::Key(Key::Delete)) => input.delete(),
+            UIEvent::Core(Event::Key(Key::Home)) => input.home(),
+            UIEvent::Core(Event::Key(Key::End)) => input.end(),
+            UIEvent::Core(Event::Key(Key::Up)) => input.previous(),
+            UIEvent::Core(Event::Key(Key::Down)) => input.next(),
+            UIEvent::Core(Event::ModifiedKey(Modifier::Ctrl, Key::Up)) => input.previous_command(),
+            UIEvent::Core(Event::ModifiedKey(Modifier::Ctrl, Key::Down)) => input.next_command(),
+            UIEvent::Core(Event::Key(Key::Left)) => input.left(),
+            UIEvent::Core(Event::Key(Key::Right)) => input.right(),
+            UIEvent::Core(Event::Key(Key::Ctrl('a'))) => input.home(),
+            UIEvent::Core(Event::Key(Key::Ctrl('b'))) => input.left(),
+            UIEvent::Core(Event::Key(Key::Ctrl('e'))) => input.end(),
+            UIEvent::Core(Event::Key(Key::Ctrl('f'))) => input.right(),
+            UIEvent::Core(Event::Key(Key::Ctrl('h'))) => input.backspace(),
+            UIEvent::Core(Event::Key(Key::Ctrl('w'))) => input.backward_delete_word(),
+            UIEvent::Core(Event::Key(Key::Ctrl('u'))) => input.delete_from_cursor_to_start(),
+            UIEvent::Core(Event::Key(Key::Ctrl('k'))) => input.delete_from_cursor_to_end(),
+            UIEvent::Core(Event::Key(Key::Ctrl('y'))) => input.yank(),
+            UIEvent::Core(Event::Key(Key::Alt('y'))) => input.yank_pop(),
+            UIEvent::Core(Event::Key(Key::Alt('\r')))
+            | UIEvent::Core(Event::Key(Key::Alt('\n'))) => input.key('\n'),
+            UIEvent::Validate(result) => {
+                let mut result = result.borrow_mut();
+                result.replace(input.validate());
+            }
+            UIEvent::GetInput(result) => {
+                let mut result = result.borrow_mut();
+                result.replace((input.buf.clone(), input.cursor.clone(), input.password));
+            }
+            UIEvent::Core(Event::Completed(raw_buf, cursor)) => {
+                input.buf = raw_buf.clone();
+                input.cursor = cursor.clone();
+                input.dirty = true;
+            }
+            UIEvent::Core(Event::ReadPassword(_)) => input.password(),
+            UIEvent::GetHistory(result) => {
+                let mut result = result.borrow_mut();
+                result.replace((
+                    input.history.lines.clone(),
+                    input.command_history.lines.clone(),
+                ));
+            }
+            UIEvent::SetHistory(messages, commands) => {
+                input.history = History::new(std::mem::take(messages));
+                input.command_history = History::new(std::mem::take(commands));
+            }
+            _ => {}
+        });
+
+        layout.push(title_bar);
+        layout.push(frame);
+        layout.push(win_bar);
+        layout.push(input);
+
+        Self {
+            screen,
+            root: layout,
+            dimension: None,
+            windows: Vec::new(),
+            unread_windows: LinkedHashSet::new(),
+            current_window: None,
+            conversations: HashMap::new(),
+            workspace: None,
+            password_command: None,
+            input_command: None,
+            pending_paste: None,
+            graphics: GraphicsProtocol::Blocks,
+            session,
+            restored: Session::default(),
+            restored_scroll: HashMap::new(),
+            restoring: HashSet::new(),
+            focused: None,
+            sidebars: HashMap::new(),
+            room_occupants: HashMap::new(),
+            occupants_width: None,
+            bindings: Vec::new(),
+            chord: Vec::new(),
+            chords: 0,
+            outgoing_event_queue: Rc::new(RefCell::new(Vec::new())),
+            panic_handler,
+        }
+    }
+
+    /// Hold back messages with too many lines, the user can then validate them again to send
+    /// them through HTTP upload or the configured paste command (or as is when there is none)
+    fn prepare_body(&mut self, aparte: &mut Aparte, raw_buf: String) -> Option<String> {
+        let lines = raw_buf.lines().count();
+        let config = aparte.config.paste.clone();
+        if lines <= config.max_lines {
+            self.pending_paste = None;
+            return Some(raw_buf);
+        }
+
+        if self.pending_paste.as_ref() != Some(&raw_buf) {
+            match (config.upload, &config.command) {
+                (true, _) => aparte.log(format!(
+                    "Message is {} lines long, validate it again to upload it",
+                    lines
+                )),
+                (false, Some(command)) => aparte.log(format!(
+                    "Message is {} lines long, validate it again to send it through `{}`",
+                    lines, command
+                )),
+                (false, None) => aparte.log(format!(
+                    "Message is {} lines long, validate it again to send it anyway",
+                    lines
+                )),
+            }
+            self.restore_input(&raw_buf);
+            self.pending_paste = Some(raw_buf);
+            return None;
+        }
+
+        self.pending_paste = None;
+        let window = self.current_window.clone()?;
+        match (config.upload, config.command) {
+            (true, _) => {
+                let result = match self.conversations.get(&window) {
+                    Some(Conversation::Chat(chat)) => {
+                        Ok((chat.account.clone(), chat.contact.clone()))
+                    }
+                    Some(Conversation::Channel(channel)) => {
+                        Ok((channel.account.clone(), channel.jid.clone()))
+                    }
+                    None => Err(String::from(
+                        "Messages can only be uploaded in a conversation window",
+                    )),
+                }
+                .and_then(|(account, jid)| upload::share_text(aparte, &account, jid, &raw_buf));
+                if let Err(err) = result {
+                    aparte.log(format!("Cannot upload message: {}", err));
+                    self.restore_input(&raw_buf);
+                }
+                None
+            }
+            (false, None) => Some(raw_buf),
+            (false, Some(command)) => {
+                aparte.spawn(async move {
+                    let result = paste(&command, &raw_buf).await;
+                    Event::Pasted {
+                        window,
+                        raw_buf,
+                        result,
+                    }
+                });
+                None
+            }
+        }
+    }
+
+    /// Send a message to the conversation of a window
+    fn send_body(&mut self, aparte: &mut Aparte, window: &str, body: String) {
+        let mut bodies = HashMap::new();
+        bodies.insert("".to_string(), body);
+        let id = Uuid::new_v4();
+        let timestamp = LocalTz::now().into();
+        match self.conversations.get(window) {
+            Some(Conversation::Chat(chat)) => {
+                let account = &chat.account;
+                let us = account.clone().into();
+                let from: Jid = us;
+                let to: Jid = chat.contact.clone().into();
+                let message =
+                    Message::outgoing_chat(id.to_string(), timestamp, &from, &to, &bodies);
+                aparte.schedule(Event::SendMessage(account.clone(), message));
+            }
+            Some(Conversation::Channel(channel)) => {
+                let account = &channel.account;
+                let mut us = account.clone();
+                us.resource = channel.nick.clone();
+                let from: Jid = us.into();
+                let to: Jid = channel.jid.clone().into();
+                let message =
+                    Message::outgoing_channel(id.to_string(), timestamp, &from, &to, &bodies);
+                aparte.schedule(Event::SendMessage(account.clone(), message));
+            }
+            None => {}
+        }
+    }
+
+    fn restore_input(&mut self, raw_buf: &str) {
+        let cursor = Cursor::from_index(raw_buf, raw_buf.len()).unwrap();
+        self.root.event(&mut UIEvent::Core(Event::Completed(
+            raw_buf.to_string(),
+            cursor,
+        )));
+    }
+
+    /// Frames drawn by a headless UI
+    #[cfg(test)]
+    pub fn frames(&self) -> &[Vec<u8>] {
+        self.screen.frames()
+    }
+
+    pub fn event_stream(&self) -> EventStream {
+        EventStream::new()
+    }
+
+    fn get_scheduler(&self) -> Scheduler {
+        Scheduler {
+            queue: self.outgoing_event_queue.clone(),
+        }
+    }
+
+    fn add_conversation(&mut self, aparte: &mut Aparte, conversation: Conversation) {
+        let scheduler = self.get_scheduler();
+        match &conversation {
+            Conversation::Chat(chat) => {
+                let chat_for_event = chat.clone();
+                // Date given to /goto, kept selected while history is loaded
+                let mut goto = None;
+                let chatwin = BufferedWin::<UIEvent, Stdout, Message>::new()
+                    .with_scrollbar(aparte.config.layout.scrollbar)
+                    .with_hanging_indent(message_hanging_indent)
+                    .with_event(move |view, event| {
+                        match event {
+                            UIEvent::Core(Event::Message(_, Message::Xmpp(message))) => {
+                                match message.direction {
+                                    // TODO check to == us
+                                    Direction::Incoming => {
+                                        if message.from == chat_for_event.contact {
+                                            view.insert(Message::Xmpp(message.clone()));
+                                        }
+                                    }
+                                    Direction::Outgoing => {
+                                        // TODO check from == us
+                                        if message.to == chat_for_event.contact {
+                                            view.insert(Message::Xmpp(message.clone()));
+                                        }
+                                    }
+                                }
+                                if let Some(date) = &goto {
+                                    select_date(view, date);
+                                }
+                            }
+                            UIEvent::Core(Event::Key(Key::PageUp)) => {
+                                goto = None;
+                                if view.page_up() {
+                                    let from = view.first().map(|message| message.timestamp());
+                                    scheduler.schedule(Event::LoadChatHistory {
+                                        account: chat_for_event.account.clone(),
+                                        contact: chat_for_event.contact.clone(),
+                                        from: from.cloned(),
+                                    });
+                                }
+                            }
+                            UIEvent::Core(Event::Key(Key::PageDown)) => {
+                                goto = None;
+                                view.page_down();
+                            }
+                            UIEvent::Core(Event::Key(key)) => {
+                                goto = None;
+                                select_message(view, key);
+                                if let Key::Ctrl('p') | Key::Ctrl('n') = key {
+                                    scheduler.schedule(Event::MessageSelected {
+                                        account: chat_for_event.account.clone(),
+                                        conversation: chat_for_event.contact.clone(),
+                                        message: view.selected().cloned(),
+                                    });
+                                }
+                            }
+                            UIEvent::Activate => activate_message(view, &scheduler),
+                            UIEvent::Core(Event::Notice {
+                                account,
+                                conversation,
+                                message,
+                            }) if account == &chat_for_event.account
+                                && conversation == &chat_for_event.contact =>
+                            {
+                                view.insert(message.clone());
+                            }
+                            UIEvent::Core(Event::GoTo {
+                                conversation, date, ..
+                            }) if conversation == &chat_for_event.contact => {
+                                goto = Some(*date);
+                                select_date(view, date);
+                            }
+                            UIEvent::Core(Event::CustomEmoji { conversation, .. })
+                            | UIEvent::Core(Event::LinkTitle { conversation, .. })
+                            | UIEvent::Core(Event::Protection { conversation, .. })
+                                if conversation == &chat_for_event.contact =>
+                            {
+                                view.dirty = true
+                            }
+                            UIEvent::SelectMessage(Message::Xmpp(message))
+                                if message.conversation() == &chat_for_event.contact =>
+                            {
+                                let message = Message::Xmpp(message.clone());
+                                if !view.select(&message) {
+                                    // Not loaded yet, show it along with what preceded it
+                                    view.insert(message.clone());
+                                    view.select(&message);
+                                    scheduler.schedule(Event::LoadChatHistory {
+                                        account: chat_for_event.account.clone(),
+                                        contact: chat_for_event.contact.clone(),
+                                        from: Some(*message.timestamp()),
+                                    });
+                                }
+                            }
+                            UIEvent::Scroll(scroll) => {
+                                scroll
+                                    .borrow_mut()
+                                    .insert(chat_for_event.contact.to_string(), view.view);
+                            }
+                            _ => {}
+                        }
+                    });
+                let chatwin = match self.restored_scroll.remove(&chat.contact.to_string()) {
+                    Some(view) => chatwin.with_view(view),
+                    None => chatwin,
+                };
+
+                self.add_window(chat.contact.to_string(), Box::new(chatwin));
+                self.conversations
+                    .insert(chat.contact.to_string(), conversation.clone());
+                self.update_workspace();
+            }
+            Conversation::Channel(channel) => {
+                let mut layout = LinearLayout::<UIEvent, Stdout>::new(Orientation::Horizontal)
+                    .with_event(|layout, event| {
+                        for child in layout.iter_children_mut() {
+                            child.event(event);
+                        }
+                    });
+
+                let channel_for_event = channel.clone();
+                let mut goto = None;
+                let chanwin = BufferedWin::<UIEvent, Stdout, Message>::new()
+                    .with_scrollbar(aparte.config.layout.scrollbar)
+                    .with_hanging_indent(message_hanging_indent)
+                    .with_event(move |view, event| {
+                        match event {
+                            UIEvent::Core(Event::Message(_, Message::Xmpp(message))) => {
+                                match message.direction {
+                                    // TODO check to == us
+                                    Direction::Incoming => {
+                                        if message.from == channel_for_event.jid {
+                                            view.insert(Message::Xmpp(message.clone()));
+                                        }
+                                    }
+                                    Direction::Outgoing => {
+                                        // TODO check from == us
+                                        if message.to == channel_for_event.jid {
+                                            view.insert(Message::Xmpp(message.clone()));
+                                        }
+                                    }
+                                }
+                                if let Some(date) = &goto {
+                                    select_date(view, date);
+                                }
+                            }
+                            UIEvent::Core(Event::Key(Key::PageUp)) => {
+                                goto = None;
+                                if view.page_up() {
+                                    let from = view.first().map(|message| message.timestamp());
+                                    scheduler.schedule(Event::LoadChannelHistory {
+                                        account: channel_for_event.account.clone(),
+                                        jid: channel_for_event.jid.clone(),
+                                        from: from.cloned(),
+                                    });
+                                }
+                            }
+                            UIEvent::Core(Event::Key(Key::PageDown)) => {
+                                goto = None;
+                                view.page_down();
+                            }
+                            UIEvent::Core(Event::Notice {
+                                account,
+                                conversation,
+                                message,
+                            }) if account == &channel_for_event.account
+                                && conversation == &channel_for_event.jid =>
+                            {
+                                view.insert(message.clone());
+                            }
+                            UIEvent::Activate => activate_message(view, &scheduler),
+                            UIEvent::Core(Event::GoTo {
+                                conversation, date, ..
+                            }) if conversation == &channel_for_event.jid => {
+                                goto = Some(*date);
+                                select_date(view, date);
+                            }
+                            UIEvent::Core(Event::Key(key)) => {
+                                goto = None;
+                                select_message(view, key);
+                                if let Key::Ctrl('p') | Key::Ctrl('n') = key {
+                                    scheduler.schedule(Event::MessageSelected {
+                                        account: channel_for_event.account.clone(),
+                                        conversation: channel_for_event.jid.clone(),
+                                        message: view.selected().cloned(),
+                                    });
+                                }
+                            }
+                            UIEvent::Core(Event::CustomEmoji { conversation, .. })
+                            | UIEvent::Core(Event::LinkTitle { conversation, .. })
+                                if conversation == &channel_for_event.jid =>
+                            {
+                                view.dirty = true
+                            }
+                            UIEvent::SelectMessage(Message::Xmpp(message))
+                                if message.conversation() == &channel_for_event.jid =>
+                            {
+                                let message = Message::Xmpp(message.clone());
+                                if !view.select(&message) {
+                                    // Not loaded yet, show it along with what preceded it
+                                    view.insert(message.clone());
+                                    view.select(&message);
+                                    scheduler.schedule(Event::LoadChannelHistory {
+                                        account: channel_for_event.account.clone(),
+                                        jid: channel_for_event.jid.clone(),
+                                        from: Some(*message.timestamp()),
+                                    });
+                                }
+                            }
+                            UIEvent::Scroll(scroll) => {
+                                scroll
+                                    .borrow_mut()
+                                    .insert(channel_for_event.get_name(), view.view);
+                            }
+                            _ => {}
+                        }
+                    });
+                let chanwin = match self.restored_scroll.remove(&channel.get_name()) {
+                    Some(view) => chanwin.with_view(view),
+                    None => chanwin,
+                };
+
+                let roster_jid = channel.jid.clone();
+                // Shown or hidden in this channel only, whatever Alt-o does
+                let mut pinned = self.room_occupants.get(&channel.jid).copied().or_else(|| {
+                    let hidden =
+                        aparte.config.layout.hidden_occupants.iter().any(|room| {
+                            BareJid::from_str(room).ok().as_ref() == Some(&channel.jid)
+                        });
+                    match hidden {
+                        true => Some(false),
+                        false => None,
+                    }
+                });
+                let width = self
+                    .occupants_width
+                    .or(aparte.config.layout.occupants_width);
+                let mut roster =
+                    ListView::<UIEvent, Stdout, conversation::Role, conversation::Occupant>::new()
+                        .with_layouts(sidebar_layouts(width))
+                        .with_none_group()
+                        .with_unique_item()
+                        .with_sort_item()
+                        .with_hidden_below(aparte.config.layout.occupants_min_width)
+                        .with_event(move |view, event| match event {
+                            UIEvent::Core(Event::Occupant {
+                                conversation,
+                                occupant,
+                                ..
+                            }) => {
+                                if roster_jid == *conversation {
+                                    view.insert(occupant.clone(), Some(occupant.role));
+                                }
+                            }
+                            UIEvent::ShowSidebar(Sidebar::Occupants, visible)
+                                if pinned.is_none() =>
+                            {
+                                view.set_visible(Some(*visible));
+                            }
+                            UIEvent::ShowOccupants(room, visible) if room == &roster_jid => {
+                                pinned = Some(*visible);
+                                view.set_visible(Some(*visible));
+                            }
+                            UIEvent::SidebarWidth(Sidebar::Occupants, width) => {
+                                view.set_layouts(sidebar_layouts(Some(*width)));
+                            }
+                            _ => {}
+                        });
+                match (pinned, self.sidebars.get(&Sidebar::Occupants)) {
+                    (Some(visible), _) | (None, Some(&visible)) => {
+                        roster.set_visible(Some(visible))
+                    }
+                    (None, None) => {}
+                }
+                match aparte.config.layout.occupants_position {
+                    PanelPosition::Left => {
+                        layout.push(roster);
+                        layout.push(chanwin);
+                    }
+                    PanelPosition::Right => {
+                        layout.push(chanwin);
+                        layout.push(roster);
+                    }
+                }
 
-            let backtrace = Backtrace::new_unresolved();
-            backtrace_for_hook
-                .lock()
-                .expect("cannot lock backtrace")
-                .replace(backtrace);
-        }));
+                self.add_window(channel.get_name(), Box::new(layout));
+                self.conversations
+                    .insert(channel.get_name(), conversation.clone());
+                self.update_workspace();
+            }
+        }
+    }
 
-        Self { panic, backtrace }
+    /// Tell the window bar which windows show the conversations of the workspace opened
+    fn update_workspace(&mut self) {
+        let workspace = self.workspace.as_ref().map(|workspace| {
+            let windows = self
+                .window_conversations()
+                .into_iter()
+                .filter(|(_, conversation)| match conversation {
+                    Some((_, jid)) => workspace.conversations.contains(jid),
+                    None => false,
+                })
+                .map(|(window, _)| terminus::clean(&window))
+                .collect();
+            (workspace.name.clone(), windows)
+        });
+        self.root.event(&mut UIEvent::Workspace(workspace));
     }
-}
 
-impl Drop for PanicHandler {
-    fn drop(&mut self) {
-        if let Some(panic) = self.panic.lock().expect("cannot lock panic").as_ref() {
-            println!("Oops Aparté {}", panic);
-            error!("Oops Aparté {}", panic);
-            println!("This isn’t normal behavior. Please report issue.");
-            error!("This isn’t normal behavior. Please report issue.");
-            if let Some(backtrace) = self
-                .backtrace
-                .lock()
-                .expect("cannot lock backtrace")
-                .as_mut()
-            {
-                println!("Aparté is gathering more info in logfile…");
-                backtrace.resolve();
-                error!("{:?}", backtrace);
-                println!("All done.");
-                let data_dir = dirs::data_dir().unwrap();
-                let aparte_data = data_dir.join("aparte").join("aparte.log");
-                println!("Please check {}", aparte_data.to_str().unwrap());
-            }
-        }
+    /// Show a sidebar if hidden and hide it otherwise, in every window
+    fn toggle_sidebar(&mut self, aparte: &Aparte, sidebar: Sidebar) {
+        let (width, _) = self.screen.size().unwrap();
+        let visible = match self.sidebars.get(&sidebar) {
+            Some(visible) => *visible,
+            None => width >= sidebar.min_width(&aparte.config.layout),
+        };
+        self.sidebars.insert(sidebar, !visible);
+        self.root
+            .event(&mut UIEvent::ShowSidebar(sidebar, !visible));
     }
-}
 
-pub struct UIMod {
-    screen: Screen<Stdout>,
-    windows: Vec<String>,
-    current_window: Option<String>,
-    unread_windows: LinkedHashSet<String>,
-    conversations: HashMap<String, Conversation>,
-    root: LinearLayout<UIEvent, Stdout>,
-    dimension: Option<Dimension>,
-    password_command: Option<Command>,
-    outgoing_event_queue: Rc<RefCell<Vec<Event>>>,
-    #[allow(dead_code)]
-    panic_handler: PanicHandler, // Defining panic_handler last guarantee that it will be dropped last (after terminal restoration)
-}
+    /// Show the occupants of a channel if hidden and hide them otherwise, in this channel only
+    fn toggle_occupants(&mut self, aparte: &Aparte, room: &BareJid) {
+        let (width, _) = self.screen.size().unwrap();
+        let hidden = aparte
+            .config
+            .layout
+            .hidden_occupants
+            .iter()
+            .any(|hidden| BareJid::from_str(hidden).ok().as_ref() == Some(room));
+        let visible = match (
+            self.room_occupants.get(room),
+            self.sidebars.get(&Sidebar::Occupants),
+        ) {
+            (Some(visible), _) => *visible,
+            (None, _) if hidden => false,
+            (None, Some(visible)) => *visible,
+            (None, None) => width >= aparte.config.layout.occupants_min_width,
+        };
+        self.room_occupants.insert(room.clone(), !visible);
+        self.root
+            .event(&mut UIEvent::ShowOccupants(room.clone(), !visible));
+    }
 
-impl UIMod {
-    pub fn new() -> Self {
-        let stdout = std::io::stdout().into_raw_mode().unwrap();
-        let screen = AlternateScreen::from(stdout);
+    /// Resize the occupants of every channel
+    fn resize_occupants(&mut self, width: PanelWidth) {
+        self.occupants_width = Some(width);
+        self.root
+            .event(&mut UIEvent::SidebarWidth(Sidebar::Occupants, width));
+    }
 
-        let panic_handler = PanicHandler::new();
+    /// Open the presence feed of an account, only following contacts of the given groups when
+    /// there are some
+    fn add_presence_window(&mut self, account: &Account, groups: Vec<contact::Group>) {
+        let name = format!("{} presences", BareJid::from(Jid::Full(account.clone())));
+        if self.windows.contains(&name) {
+            self.windows.retain(|win| win != &name);
+            self.root
+                .event(&mut UIEvent::Core(Event::Close(name.clone())));
+        }
 
-        let mut layout = LinearLayout::<UIEvent, Stdout>::new(Orientation::Vertical).with_event(
-            |layout, event| {
-                for child in layout.iter_children_mut() {
-                    child.event(event);
+        let account = account.clone();
+        let mut known = HashMap::new();
+        let feed = BufferedWin::<UIEvent, Stdout, Message>::new()
+            .with_hanging_indent(message_hanging_indent)
+            .with_event(move |view, event| match event {
+                UIEvent::Core(Event::ContactUpdate(contact_account, contact))
+                    if contact_account == &account =>
+                {
+                    if let Some(change) = presence_change(&mut known, &groups, contact) {
+                        view.insert(Message::log(change));
+                    }
                 }
-            },
-        );
-
-        let title_bar = TitleBar::new();
-        let frame =
-            FrameLayout::<UIEvent, Stdout, String>::new().with_event(|frame, event| match event {
-                UIEvent::Core(Event::ChangeWindow(name)) => {
-                    frame.set_current(name.to_string());
+                UIEvent::Core(Event::Key(Key::PageUp)) => {
+                    view.page_up();
                 }
-                UIEvent::AddWindow(name, view) => {
-                    let view = view.take().unwrap();
-                    frame.insert_boxed(name.to_string(), view);
+                UIEvent::Core(Event::Key(Key::PageDown)) => {
+                    view.page_down();
+                }
+                _ => {}
+            });
 
-                    // propagate AddWindow with name only to each subview
-                    // required at least for console view
-                    for child in frame.iter_children_mut() {
-                        child.event(&mut UIEvent::AddWindow(name.to_string(), None));
+        self.add_window(name.clone(), Box::new(feed));
+        self.change_window(&name);
+    }
+
+    /// List messages found by /search in the search window, Enter jumps to the selected one
+    pub fn show_search_results(&mut self, results: Vec<SearchResult>) {
+        let name = String::from("search");
+        if self.windows.contains(&name) {
+            self.windows.retain(|win| win != &name);
+            self.root
+                .event(&mut UIEvent::Core(Event::Close(name.clone())));
+        }
+
+        let scheduler = self.get_scheduler();
+        let mut list =
+            BufferedWin::<UIEvent, Stdout, SearchResult>::new().with_event(move |view, event| {
+                match event {
+                    UIEvent::Core(Event::Key(Key::PageUp)) => {
+                        view.page_up();
+                    }
+                    UIEvent::Core(Event::Key(Key::PageDown)) => {
+                        view.page_down();
+                    }
+                    UIEvent::Core(Event::Key(Key::Ctrl('p'))) => view.select_previous(),
+                    UIEvent::Core(Event::Key(Key::Ctrl('n'))) => view.select_next(),
+                    UIEvent::Activate => {
+                        if let Some(result) = view.selected() {
+                            scheduler.schedule(Event::ShowMessage {
+                                account: result.account.clone(),
+                                conversation: result.message.conversation().clone(),
+                                message: Message::Xmpp(result.message.clone()),
+                            });
+                        }
                     }
+                    _ => {}
                 }
-                UIEvent::Core(Event::Close(window)) => {
-                    frame.remove(&window);
+            });
+        for result in results {
+            list.insert(result);
+        }
 
-                    // propagate Close with name only to each subview
-                    // required at least for console view
-                    for child in frame.iter_children_mut() {
-                        child.event(&mut UIEvent::Core(Event::Close(window.clone())));
-                    }
+        self.add_window(name.clone(), Box::new(list));
+        self.change_window(&name);
+    }
+
+    /// Read the configured leader key and key sequences, ignoring invalid ones
+    fn load_bindings(&mut self, aparte: &mut Aparte) {
+        let keys = aparte.config.keys.clone();
+        let leader = match keys.leader.as_deref().map(parse_key) {
+            Some(Ok(leader)) => Some(leader),
+            Some(Err(err)) => {
+                aparte.log(format!("Invalid leader key: {}", err));
+                None
+            }
+            None => None,
+        };
+        let mut bindings = keys.bindings.into_iter().collect::<Vec<_>>();
+        bindings.sort();
+        for (sequence, action) in bindings {
+            if !action.starts_with('/') && !KEY_ACTIONS.contains(&action.as_str()) {
+                aparte.log(format!("Unknown action {} bound to {}", action, sequence));
+                continue;
+            }
+            match parse_keys(&sequence, leader) {
+                Ok(keys) => self.bindings.push((keys, action)),
+                Err(err) => aparte.log(format!("Invalid key sequence {}: {}", sequence, err)),
+            }
+        }
+    }
+
+    /// Whether the key is taken by a key sequence, which runs its action once complete. Keys that
+    /// complete no sequence end it, except the leader pressed twice which is then handled as usual.
+    fn take_chord_key(&mut self, aparte: &mut Aparte, key: Keystroke) -> bool {
+        if self.bindings.is_empty() {
+            return false;
+        }
+        let mut chord = std::mem::take(&mut self.chord);
+        let pending = !chord.is_empty();
+        chord.push(key);
+
+        if let Some((_, action)) = self.bindings.iter().find(|(keys, _)| keys == &chord) {
+            let action = action.clone();
+            self.root.event(&mut UIEvent::Chord(None));
+            self.run_key_action(aparte, &action);
+            true
+        } else if self
+            .bindings
+            .iter()
+            .any(|(keys, _)| keys.starts_with(&chord))
+        {
+            let keys = chord.iter().map(key_name).collect::<Vec<_>>().join(" ");
+            self.root.event(&mut UIEvent::Chord(Some(keys)));
+            self.chord = chord;
+            self.chords += 1;
+            let chords = self.chords;
+            let timeout = Duration::from_millis(aparte.config.keys.timeout);
+            aparte.spawn(async move {
+                tokio::time::sleep(timeout).await;
+                Event::ChordTimeout(chords)
+            });
+            true
+        } else if pending {
+            self.root.event(&mut UIEvent::Chord(None));
+            let leader = aparte.config.keys.leader.as_deref().map(parse_key);
+            !(chord.len() == 2 && Some(Ok(chord[0])) == leader && chord[1] == chord[0])
+        } else {
+            false
+        }
+    }
+
+    /// Run the action or command a key sequence is bound to
+    fn run_key_action(&mut self, aparte: &mut Aparte, action: &str) {
+        match action {
+            "next-window" => self.next_window(),
+            "previous-window" => self.prev_window(),
+            "next-unread" => {
+                if let Some(window) = self.unread_windows.pop_front() {
+                    self.change_window(&window);
                 }
-                UIEvent::Core(Event::Key(Key::PageUp))
-                | UIEvent::Core(Event::Key(Key::PageDown)) => {
-                    if let Some(current) = frame.get_current_mut() {
-                        current.event(event);
+            }
+            "toggle-roster" => self.toggle_sidebar(aparte, Sidebar::Roster),
+            "toggle-occupants" => self.toggle_sidebar(aparte, Sidebar::Occupants),
+            command => {
+                let window = self.current_window.clone().unwrap();
+                let account = match self.conversations.get(&window) {
+                    Some(Conversation::Chat(chat)) => Some(chat.account.clone()),
+                    Some(Conversation::Channel(channel)) => Some(channel.account.clone()),
+                    _ => None,
+                };
+                aparte.schedule(Event::RawCommand(account, window, command.to_string()));
+            }
+        }
+    }
+
+    /// Whether nothing is typed in the input
+    fn input_is_empty(&mut self) -> bool {
+        let result = Rc::new(RefCell::new(None));
+        self.root.event(&mut UIEvent::GetInput(Rc::clone(&result)));
+        let result = result.borrow();
+        matches!(result.as_ref(), Some((buf, _, _)) if buf.is_empty())
+    }
+
+    /// Windows with the conversation they show, if any
+    pub fn window_conversations(&self) -> Vec<(String, Option<(Account, BareJid)>)> {
+        self.windows
+            .iter()
+            .map(|window| {
+                let conversation = match self.conversations.get(window) {
+                    Some(Conversation::Chat(chat)) => {
+                        Some((chat.account.clone(), chat.contact.clone()))
+                    }
+                    Some(Conversation::Channel(channel)) => {
+                        Some((channel.account.clone(), channel.jid.clone()))
+                    }
+                    None => None,
+                };
+                (window.clone(), conversation)
+            })
+            .collect()
+    }
+
+    /// List windows with their recent activity in the switch window, Enter goes to the selected
+    /// one
+    pub fn show_switch(&mut self, items: Vec<SwitchItem>) {
+        let name = String::from("switch");
+        if self.windows.contains(&name) {
+            self.windows.retain(|win| win != &name);
+            self.root
+                .event(&mut UIEvent::Core(Event::Close(name.clone())));
+        }
+
+        let scheduler = self.get_scheduler();
+        let mut list =
+            BufferedWin::<UIEvent, Stdout, SwitchItem>::new().with_event(move |view, event| {
+                match event {
+                    UIEvent::Core(Event::Key(Key::PageUp)) => {
+                        view.page_up();
+                    }
+                    UIEvent::Core(Event::Key(Key::PageDown)) => {
+                        view.page_down();
                     }
+                    UIEvent::Core(Event::Key(Key::Ctrl('p'))) => view.select_previous(),
+                    UIEvent::Core(Event::Key(Key::Ctrl('n'))) => view.select_next(),
+                    UIEvent::Activate => {
+                        if let Some(item) = view.selected() {
+                            scheduler.schedule(Event::Win(item.window.clone()));
+                        }
+                    }
+                    _ => {}
                 }
-                _ => {
-                    for child in frame.iter_children_mut() {
-                        child.event(event);
+            });
+        for item in items.into_iter().filter(|item| item.window != name) {
+            list.insert(item);
+        }
+
+        self.add_window(name.clone(), Box::new(list));
+        self.change_window(&name);
+    }
+
+    /// List the info and items of an entity in the disco window. Up and Down select an item, Right
+    /// and Enter discover it, and Left goes back to the entity discovered before
+    pub fn show_disco(
+        &mut self,
+        account: &Account,
+        lines: Vec<DiscoLine>,
+        back: Option<(Jid, Option<String>)>,
+    ) {
+        let name = String::from(DISCO_WINDOW);
+        if self.windows.contains(&name) {
+            self.windows.retain(|win| win != &name);
+            self.root
+                .event(&mut UIEvent::Core(Event::Close(name.clone())));
+        }
+
+        let scheduler = self.get_scheduler();
+        let account = account.clone();
+        let mut list =
+            BufferedWin::<UIEvent, Stdout, DiscoLine>::new().with_event(move |view, event| {
+                match event {
+                    UIEvent::Core(Event::Key(Key::PageUp)) => {
+                        view.page_up();
+                    }
+                    UIEvent::Core(Event::Key(Key::PageDown)) => {
+                        view.page_down();
+                    }
+                    UIEvent::Core(Event::Key(Key::Ctrl('p'))) | UIEvent::Browse(Key::Up) => {
+                        select_disco_target(view, false)
+                    }
+                    UIEvent::Core(Event::Key(Key::Ctrl('n'))) | UIEvent::Browse(Key::Down) => {
+                        select_disco_target(view, true)
                     }
+                    UIEvent::Activate | UIEvent::Browse(Key::Right) => {
+                        if let Some((jid, node)) =
+                            view.selected().and_then(|line| line.target.as_ref())
+                        {
+                            scheduler.schedule(disco_command(&account, jid, node));
+                        }
+                    }
+                    UIEvent::Browse(Key::Left) => {
+                        if let Some((jid, node)) = &back {
+                            scheduler.schedule(disco_command(&account, jid, node));
+                        }
+                    }
+                    _ => {}
                 }
             });
-        let win_bar = WinBar::new();
-        let input = Input::new().with_event(|input, event| match event {
-            UIEvent::Core(Event::Key(Key::Char(c))) => input.key(*c),
-            UIEvent::Core(Event::Key(Key::Backspace)) => input.backspace(),
-            UIEvent::Core(Event::Key(Key::Delete)) => input.delete(),
-            UIEvent::Core(Event::Key(Key::Home)) => input.home(),
-            UIEvent::Core(Event::Key(Key::End)) => input.end(),
-            UIEvent::Core(Event::Key(Key::Up)) => input.previous(),
-            UIEvent::Core(Event::Key(Key::Down)) => input.next(),
-            UIEvent::Core(Event::Key(Key::Left)) => input.left(),
-            UIEvent::Core(Event::Key(Key::Right)) => input.right(),
-            UIEvent::Core(Event::Key(Key::Ctrl('a'))) => input.home(),
-            UIEvent::Core(Event::Key(Key::Ctrl('b'))) => input.left(),
-            UIEvent::Core(Event::Key(Key::Ctrl('e'))) => input.end(),
-            UIEvent::Core(Event::Key(Key::Ctrl('f'))) => input.right(),
-            UIEvent::Core(Event::Key(Key::Ctrl('h'))) => input.backspace(),
-            UIEvent::Core(Event::Key(Key::Ctrl('w'))) => input.backward_delete_word(),
-            UIEvent::Core(Event::Key(Key::Ctrl('u'))) => input.delete_from_cursor_to_start(),
-            UIEvent::Core(Event::Key(Key::Ctrl('k'))) => input.delete_from_cursor_to_end(),
-            UIEvent::Validate(result) => {
-                let mut result = result.borrow_mut();
-                result.replace(input.validate());
-            }
-            UIEvent::GetInput(result) => {
-                let mut result = result.borrow_mut();
-                result.replace((input.buf.clone(), input.cursor.clone(), input.password));
+        for line in lines {
+            list.insert(line);
+        }
+
+        self.add_window(name.clone(), Box::new(list));
+        self.change_window(&name);
+    }
+
+    /// Jump to a message in its conversation window, chats are opened when needed
+    fn show_message(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        conversation: &BareJid,
+        message: &Message,
+    ) {
+        let name = self
+            .conversations
+            .iter()
+            .find_map(|(name, open)| match open {
+                Conversation::Chat(chat) if &chat.contact == conversation => Some(name.clone()),
+                Conversation::Channel(channel) if &channel.jid == conversation => {
+                    Some(name.clone())
+                }
+                _ => None,
+            });
+        let name = match (name, message) {
+            (Some(name), _) => name,
+            (None, Message::Xmpp(xmpp)) if xmpp.type_ == XmppMessageType::Chat => {
+                self.add_conversation(
+                    aparte,
+                    Conversation::Chat(Chat {
+                        account: account.clone(),
+                        contact: conversation.clone(),
+                    }),
+                );
+                conversation.to_string()
             }
-            UIEvent::Core(Event::Completed(raw_buf, cursor)) => {
-                input.buf = raw_buf.clone();
-                input.cursor = cursor.clone();
-                input.dirty = true;
+            _ => {
+                aparte.log(format!(
+                    "Join {} to see this message in context",
+                    conversation
+                ));
+                return;
             }
-            UIEvent::Core(Event::ReadPassword(_)) => input.password(),
-            _ => {}
-        });
+        };
+
+        self.change_window(&name);
+        self.root
+            .event(&mut UIEvent::SelectMessage(message.clone()));
+    }
+
+    /// Show a message of a stranger in the requests window, opened on the first request
+    fn add_request(&mut self, aparte: &mut Aparte, message: &Message, first: bool) {
+        let name = String::from("requests");
+        if !self.windows.contains(&name) {
+            let requests = BufferedWin::<UIEvent, Stdout, Message>::new()
+                .with_scrollbar(aparte.config.layout.scrollbar)
+                .with_hanging_indent(message_hanging_indent)
+                .with_event(|view, event| match event {
+                    UIEvent::Request(message) => {
+                        view.insert(message.clone());
+                    }
+                    UIEvent::Core(Event::Key(Key::PageUp)) => {
+                        view.page_up();
+                    }
+                    UIEvent::Core(Event::Key(Key::PageDown)) => {
+                        view.page_down();
+                    }
+                    _ => {}
+                });
+            self.add_window(name.clone(), Box::new(requests));
+        }
+
+        if let (true, Message::Xmpp(xmpp)) = (first, message) {
+            let hint = format!(
+                "{} isn't in your roster, answer with /requests accept {} or /requests ignore {}",
+                xmpp.from, xmpp.from, xmpp.from
+            );
+            self.root
+                .event(&mut UIEvent::Request(Message::log(crate::color::dimmed(
+                    &hint,
+                ))));
+        }
+        self.root.event(&mut UIEvent::Request(message.clone()));
 
-        layout.push(title_bar);
-        layout.push(frame);
-        layout.push(win_bar);
-        layout.push(input);
+        if self.current_window.as_ref() != Some(&name) {
+            self.unread_windows.insert(name);
+        }
+        aparte.schedule(Event::Notification(String::from("")));
+    }
 
-        Self {
-            screen,
-            root: layout,
-            dimension: None,
-            windows: Vec::new(),
-            unread_windows: LinkedHashSet::new(),
-            current_window: None,
-            conversations: HashMap::new(),
-            password_command: None,
-            outgoing_event_queue: Rc::new(RefCell::new(Vec::new())),
-            panic_handler,
+    /// Show a headline or a server message in the announcements window, opened on the first one
+    fn add_announcement(&mut self, aparte: &mut Aparte, message: &Message) {
+        let name = String::from("announcements");
+        if !self.windows.contains(&name) {
+            let announcements = BufferedWin::<UIEvent, Stdout, Message>::new()
+                .with_scrollbar(aparte.config.layout.scrollbar)
+                .with_hanging_indent(message_hanging_indent)
+                .with_event(|view, event| match event {
+                    UIEvent::Announcement(message) => {
+                        view.insert(message.clone());
+                    }
+                    UIEvent::Core(Event::Key(Key::PageUp)) => {
+                        view.page_up();
+                    }
+                    UIEvent::Core(Event::Key(Key::PageDown)) => {
+                        view.page_down();
+                    }
+                    _ => {}
+                });
+            self.add_window(name.clone(), Box::new(announcements));
+        }
+
+        self.root.event(&mut UIEvent::Announcement(message.clone()));
+
+        if self.current_window.as_ref() != Some(&name) {
+            self.unread_windows.insert(name);
+        }
+        if aparte.config.announcements.notify {
+            aparte.schedule(Event::Notification(String::from("")));
         }
     }
 
-    pub fn event_stream(&self) -> EventStream {
-        EventStream::new()
+    fn add_window(&mut self, name: String, window: Box<dyn View<UIEvent, Stdout>>) {
+        self.windows.push(name.clone());
+        self.root
+            .event(&mut UIEvent::AddWindow(name.clone(), Some(window)));
+
+        // Put restored windows back as they were left
+        if self.restored.unread.contains(&name) {
+            self.restored.unread.retain(|unread| unread != &name);
+            self.unread_windows.insert(name.clone());
+            self.root.event(&mut UIEvent::Highlight(name.clone()));
+        }
+        if self.restored.current.as_ref() == Some(&name) {
+            self.restored.current = None;
+            self.change_window(&name);
+        }
     }
 
-    fn get_scheduler(&self) -> Scheduler {
-        Scheduler {
-            queue: self.outgoing_event_queue.clone(),
+    fn load_format(aparte: &mut Aparte) {
+        let mut config = aparte.config.format.clone();
+        if let Err(err) = check_timestamp(&config.timestamp) {
+            config.timestamp = FormatConfig::default().timestamp;
+            aparte.log(format!(
+                "Invalid format configuration: {}, {} is used instead",
+                err, config.timestamp
+            ));
+        }
+        match MessageFormat::from_config(&config) {
+            Ok(format) => {
+                MESSAGE_FORMAT.with(|current| current.replace(format));
+            }
+            Err(err) => aparte.log(format!("Invalid format configuration: {}", err)),
         }
     }
 
-    fn add_conversation(&mut self, _aparte: &mut Aparte, conversation: Conversation) {
-        let scheduler = self.get_scheduler();
-        match &conversation {
-            Conversation::Chat(chat) => {
-                let chat_for_event = chat.clone();
-                let chatwin = BufferedWin::<UIEvent, Stdout, Message>::new().with_event(
-                    move |view, event| {
-                        match event {
-                            UIEvent::Core(Event::Message(_, Message::Xmpp(message))) => {
-                                match message.direction {
-                                    // TODO check to == us
-                                    Direction::Incoming => {
-                                        if message.from == chat_for_event.contact {
-                                            view.insert(Message::Xmpp(message.clone()));
-                                        }
-                                    }
-                                    Direction::Outgoing => {
-                                        // TODO check from == us
-                                        if message.to == chat_for_event.contact {
-                                            view.insert(Message::Xmpp(message.clone()));
-                                        }
-                                    }
-                                }
-                            }
-                            UIEvent::Core(Event::Key(Key::PageUp)) => {
-                                if view.page_up() {
-                                    let from = view.first().map(|message| message.timestamp());
-                                    scheduler.schedule(Event::LoadChatHistory {
-                                        account: chat_for_event.account.clone(),
-                                        contact: chat_for_event.contact.clone(),
-                                        from: from.cloned(),
-                                    });
-                                }
-                            }
-                            UIEvent::Core(Event::Key(Key::PageDown)) => {
-                                view.page_down();
-                            }
-                            _ => {}
-                        }
-                    },
-                );
+    /// Each profile has its own session
+    fn use_profile_session(&mut self, profile: &str) {
+        self.session = self
+            .session
+            .as_ref()
+            .map(|path| path.with_file_name(format!("session.{}.toml", profile)));
+    }
 
-                self.add_window(chat.contact.to_string(), Box::new(chatwin));
-                self.conversations
-                    .insert(chat.contact.to_string(), conversation.clone());
+    /// Leave the windows of the previous profile for the ones of a new one
+    fn switch_profile(&mut self, aparte: &mut Aparte, profile: &str) {
+        self.save_session();
+        let windows: Vec<String> = self
+            .windows
+            .iter()
+            .filter(|window| self.conversations.contains_key(*window))
+            .cloned()
+            .collect();
+        for window in windows {
+            aparte.schedule(Event::Close(window));
+        }
+
+        Self::load_format(aparte);
+        self.use_profile_session(profile);
+        self.restored = Session::default();
+        self.restored_scroll.clear();
+        if aparte.config.session.restore {
+            if let Err(err) = self.load_session() {
+                aparte.log(format!("Cannot restore session of {}: {}", profile, err));
             }
-            Conversation::Channel(channel) => {
-                let mut layout = LinearLayout::<UIEvent, Stdout>::new(Orientation::Horizontal)
-                    .with_event(|layout, event| {
-                        for child in layout.iter_children_mut() {
-                            child.event(event);
-                        }
-                    });
+        }
+    }
 
-                let channel_for_event = channel.clone();
-                let chanwin = BufferedWin::<UIEvent, Stdout, Message>::new().with_event(
-                    move |view, event| {
-                        match event {
-                            UIEvent::Core(Event::Message(_, Message::Xmpp(message))) => {
-                                match message.direction {
-                                    // TODO check to == us
-                                    Direction::Incoming => {
-                                        if message.from == channel_for_event.jid {
-                                            view.insert(Message::Xmpp(message.clone()));
-                                        }
-                                    }
-                                    Direction::Outgoing => {
-                                        // TODO check from == us
-                                        if message.to == channel_for_event.jid {
-                                            view.insert(Message::Xmpp(message.clone()));
-                                        }
-                                    }
-                                }
-                            }
-                            UIEvent::Core(Event::Key(Key::PageUp)) => {
-                                if view.page_up() {
-                                    let from = view.first().map(|message| message.timestamp());
-                                    scheduler.schedule(Event::LoadChannelHistory {
-                                        account: channel_for_event.account.clone(),
-                                        jid: channel_for_event.jid.clone(),
-                                        from: from.cloned(),
-                                    });
-                                }
-                            }
-                            UIEvent::Core(Event::Key(Key::PageDown)) => {
-                                view.page_down();
-                            }
-                            _ => {}
-                        }
-                    },
-                );
-                layout.push(chanwin);
+    fn load_session(&mut self) -> Result<(), String> {
+        let path = match &self.session {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let session = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        self.restored = toml::from_str(&session).map_err(|err| err.to_string())?;
+        self.root.event(&mut UIEvent::SetHistory(
+            std::mem::take(&mut self.restored.history),
+            std::mem::take(&mut self.restored.command_history),
+        ));
+        Ok(())
+    }
 
-                let roster_jid = channel.jid.clone();
-                let roster =
-                    ListView::<UIEvent, Stdout, conversation::Role, conversation::Occupant>::new()
-                        .with_layouts(Layouts {
-                            width: Layout::wrap_content(),
-                            height: Layout::match_parent(),
-                        })
-                        .with_none_group()
-                        .with_unique_item()
-                        .with_sort_item()
-                        .with_event(move |view, event| match event {
-                            UIEvent::Core(Event::Occupant {
-                                conversation,
-                                occupant,
-                                ..
-                            }) => {
-                                if roster_jid == *conversation {
-                                    view.insert(occupant.clone(), Some(occupant.role));
-                                }
-                            }
-                            _ => {}
-                        });
-                layout.push(roster);
+    /// Reopen the windows an account had in the previous session
+    fn restore_session(&mut self, aparte: &mut Aparte, account: &Account) {
+        let bare = BareJid::from(Jid::Full(account.clone())).to_string();
+        let (windows, others) = self
+            .restored
+            .windows
+            .drain(..)
+            .partition(|window| window.account == bare);
+        self.restored.windows = others;
 
-                self.add_window(channel.get_name(), Box::new(layout));
-                self.conversations
-                    .insert(channel.get_name(), conversation.clone());
+        for window in windows {
+            let jid = match BareJid::from_str(&window.jid) {
+                Ok(jid) => jid,
+                Err(err) => {
+                    warn!("Cannot restore window {}: {}", window.jid, err);
+                    continue;
+                }
+            };
+            if window.scroll > 0 {
+                self.restored_scroll
+                    .insert(window.jid.clone(), window.scroll);
+            }
+            match window.nick {
+                None => {
+                    self.restoring.insert(window.jid.clone());
+                    aparte.schedule(Event::Chat {
+                        account: account.clone(),
+                        contact: jid,
+                    });
+                }
+                Some(nick) => aparte.schedule(Event::Join {
+                    account: account.clone(),
+                    channel: Jid::Full(jid.with_resource(nick)),
+                    user_request: false,
+                }),
             }
         }
     }
 
-    fn add_window(&mut self, name: String, window: Box<dyn View<UIEvent, Stdout>>) {
-        self.windows.push(name.clone());
-        self.root.event(&mut UIEvent::AddWindow(name, Some(window)));
+    fn save_session(&mut self) {
+        let path = match &self.session {
+            Some(path) => path,
+            None => return,
+        };
+
+        let scroll = Rc::new(RefCell::new(HashMap::new()));
+        self.root.event(&mut UIEvent::Scroll(Rc::clone(&scroll)));
+        let scroll = scroll.borrow();
+
+        let mut windows = Vec::new();
+        for name in &self.windows {
+            let window = match self.conversations.get(name) {
+                Some(Conversation::Chat(chat)) => SessionWindow {
+                    account: BareJid::from(Jid::Full(chat.account.clone())).to_string(),
+                    jid: chat.contact.to_string(),
+                    nick: None,
+                    scroll: 0,
+                },
+                Some(Conversation::Channel(channel)) => SessionWindow {
+                    account: BareJid::from(Jid::Full(channel.account.clone())).to_string(),
+                    jid: channel.jid.to_string(),
+                    nick: Some(channel.nick.clone()),
+                    scroll: 0,
+                },
+                None => continue,
+            };
+            windows.push(SessionWindow {
+                scroll: scroll.get(name).cloned().unwrap_or(0),
+                ..window
+            });
+        }
+        // Windows of accounts not connected yet are kept for later
+        windows.extend(self.restored.windows.iter().cloned());
+
+        let history = Rc::new(RefCell::new(None));
+        self.root
+            .event(&mut UIEvent::GetHistory(Rc::clone(&history)));
+        let (history, command_history) = history.borrow_mut().take().unwrap_or_default();
+        let last = |lines: Vec<String>| {
+            let skipped = lines.len().saturating_sub(HISTORY_LEN);
+            lines.into_iter().skip(skipped).collect()
+        };
+
+        let session = Session {
+            current: self.current_window.clone(),
+            unread: self.unread_windows.iter().cloned().collect(),
+            history: last(history),
+            command_history: last(command_history),
+            windows,
+        };
+        let result = toml::to_string(&session)
+            .map_err(|err| err.to_string())
+            .and_then(|session| fs::write(path, session).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            warn!("Cannot save session: {}", err);
+        }
+    }
+
+    fn schedule_autosave(&self, aparte: &mut Aparte) {
+        let interval = aparte.config.session.autosave;
+        if interval > 0 {
+            aparte.spawn(async move {
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+                Event::AutoSave
+            });
+        }
     }
 
     pub fn change_window(&mut self, window: &str) {
         self.root
             .event(&mut UIEvent::Core(Event::ChangeWindow(window.to_string())));
         self.current_window = Some(window.to_string());
+        self.unread_windows.remove(window);
+        // Let other mods know which window is being read
+        self.outgoing_event_queue
+            .borrow_mut()
+            .push(Event::ChangeWindow(window.to_string()));
     }
 
-    #[allow(unused)] // XXX Should be used when alt+arrow is fixed see https://gitlab.redox-os.org/redox-os/termion/-/issues/183
     pub fn next_window(&mut self) {
         if let Some(current) = &self.current_window {
             let index = self.windows.iter().position(|e| e == current).unwrap();
@@ -922,7 +3131,6 @@ impl UIMod {
         }
     }
 
-    #[allow(unused)] // XXX Should be used when alt+arrow is fixed see https://gitlab.redox-os.org/redox-os/termion/-/issues/183
     pub fn prev_window(&mut self) {
         if let Some(current) = &self.current_window {
             let index = self.windows.iter().position(|e| e == current).unwrap();
@@ -938,20 +3146,108 @@ impl UIMod {
         self.windows.clone()
     }
 
+    /// Protocol to use to display images in buffers
+    pub fn graphics(&self) -> GraphicsProtocol {
+        self.graphics
+    }
+
     pub fn current_window<'a>(&'a self) -> Option<&'a String> {
         self.current_window.as_ref()
     }
+
+    /// Number of windows with messages not read yet
+    #[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+    pub fn unread_count(&self) -> usize {
+        self.unread_windows.len()
+    }
+}
+
+/// Windows left open by a previous run
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Session {
+    current: Option<String>,
+    unread: Vec<String>,
+    /// Last messages validated in the input, kept apart from commands
+    history: Vec<String>,
+    /// Last commands validated in the input
+    command_history: Vec<String>,
+    windows: Vec<SessionWindow>,
+}
+
+/// Conversation window of a session, either a chat or a channel joined with `nick`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SessionWindow {
+    /// Bare jid of the account the conversation belongs to
+    account: String,
+    jid: String,
+    nick: Option<String>,
+    /// Lines scrolled up from the bottom
+    #[serde(default)]
+    scroll: usize,
+}
+
+/// Send `content` to the stdin of a shell `command` and return its trimmed stdout
+async fn paste(command: &str, content: &str) -> Result<String, String> {
+    let mut child = ProcessCommand::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(content.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    let output = child.wait_with_output().await.map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!("`{}` failed with {}", command, output.status));
+    }
+
+    let link = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    match link.is_empty() {
+        true => Err(format!("`{}` didn't output anything", command)),
+        false => Ok(link),
+    }
 }
 
 impl ModTrait for UIMod {
-    fn init(&mut self, _aparte: &mut Aparte) -> Result<(), ()> {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        Self::load_format(aparte);
+        if let Some(profile) = aparte.config.profile.clone() {
+            self.use_profile_session(&profile);
+        }
+
+        self.graphics = aparte
+            .config
+            .graphics
+            .unwrap_or_else(GraphicsProtocol::detect);
+        aparte.add_command(graphics::new());
+        aparte.add_command(qr::new());
+        aparte.add_command(presences::new());
+        aparte.add_command(panel::new());
+        self.load_bindings(aparte);
+
+        if aparte.config.session.restore {
+            if let Err(err) = self.load_session() {
+                aparte.log(format!("Cannot restore previous session: {}", err));
+            }
+        }
+
         vprint!(&mut self.screen, "{}", termion::clear::All);
 
-        let (width, height) = termion::terminal_size().unwrap();
+        let (width, height) = self.screen.size().unwrap();
         let mut dimension = Dimension::new();
         self.root.measure(&mut dimension, Some(width), Some(height));
         self.root.layout(&mut dimension, 1, 1);
         self.root.render(&dimension, &mut self.screen);
+        flush!(self.screen);
         self.dimension = Some(dimension);
 
         let mut console = LinearLayout::<UIEvent, Stdout>::new(Orientation::Horizontal).with_event(
@@ -962,18 +3258,21 @@ impl ModTrait for UIMod {
             },
         );
         console.push(
-            BufferedWin::<UIEvent, Stdout, Message>::new().with_event(|view, event| match event {
-                UIEvent::Core(Event::Message(_, Message::Log(message))) => {
-                    view.insert(Message::Log(message.clone()));
-                }
-                UIEvent::Core(Event::Key(Key::PageUp)) => {
-                    view.page_up();
-                }
-                UIEvent::Core(Event::Key(Key::PageDown)) => {
-                    view.page_down();
-                }
-                _ => {}
-            }),
+            BufferedWin::<UIEvent, Stdout, Message>::new()
+                .with_scrollbar(aparte.config.layout.scrollbar)
+                .with_hanging_indent(message_hanging_indent)
+                .with_event(|view, event| match event {
+                    UIEvent::Core(Event::Message(_, Message::Log(message))) => {
+                        view.insert(Message::Log(message.clone()));
+                    }
+                    UIEvent::Core(Event::Key(Key::PageUp)) => {
+                        view.page_up();
+                    }
+                    UIEvent::Core(Event::Key(Key::PageDown)) => {
+                        view.page_down();
+                    }
+                    _ => {}
+                }),
         );
         let roster = ListView::<UIEvent, Stdout, contact::Group, RosterItem>::new()
             .with_layouts(Layouts {
@@ -982,6 +3281,7 @@ impl ModTrait for UIMod {
             })
             .with_none_group()
             .with_sort_item()
+            .with_hidden_below(aparte.config.layout.roster_min_width)
             .with_event(|view, event| match event {
                 UIEvent::Core(Event::Connected(_, _)) => {
                     view.add_group(contact::Group(String::from("Windows")));
@@ -999,6 +3299,9 @@ impl ModTrait for UIMod {
                         view.insert(RosterItem::Contact(contact.clone()), Some(group));
                     }
                 }
+                UIEvent::Core(Event::Avatar { .. })
+                | UIEvent::Core(Event::Mood { .. })
+                | UIEvent::Core(Event::Activity { .. }) => view.dirty = true,
                 UIEvent::Core(Event::Bookmark(bookmark)) => {
                     let group = contact::Group(String::from("Bookmarks"));
                     view.insert(RosterItem::Bookmark(bookmark.clone()), Some(group));
@@ -1023,6 +3326,7 @@ impl ModTrait for UIMod {
                     let group = contact::Group(String::from("Windows"));
                     let _ = view.remove(RosterItem::Window(window.clone()), Some(group));
                 }
+                UIEvent::ShowSidebar(Sidebar::Roster, visible) => view.set_visible(Some(*visible)),
                 _ => {}
             });
         console.push(roster);
@@ -1040,75 +3344,149 @@ impl ModTrait for UIMod {
                 self.root
                     .event(&mut UIEvent::Core(Event::ReadPassword(command.clone())));
             }
+            Event::ReadInput(command) => self.input_command = Some(command.clone()),
+            Event::Pasted {
+                window,
+                raw_buf,
+                result,
+            } => match result {
+                Ok(link) => self.send_body(aparte, window, link.clone()),
+                Err(err) => {
+                    aparte.log(format!("Cannot paste message: {}", err));
+                    // Don't overwrite what the user typed meanwhile
+                    if self.current_window.as_ref() == Some(window) && self.input_is_empty() {
+                        self.restore_input(raw_buf);
+                    }
+                }
+            },
+            Event::Start => self.schedule_autosave(aparte),
+            Event::AutoSave => {
+                self.save_session();
+                self.schedule_autosave(aparte);
+            }
+            Event::Quit => self.save_session(),
+            Event::Profile(profile) => self.switch_profile(aparte, profile),
+            Event::Workspace(workspace) => {
+                self.workspace = workspace.clone();
+                self.update_workspace();
+            }
             Event::Connected(account, jid) => {
                 self.root.event(&mut UIEvent::Core(Event::Connected(
                     account.clone(),
                     jid.clone(),
                 )));
+                self.restore_session(aparte, account);
             }
             Event::Message(account, message) => {
-                match message {
-                    Message::Xmpp(message) => {
-                        let window_name = match message.direction {
-                            Direction::Incoming => message.from.to_string(),
-                            Direction::Outgoing => message.to.to_string(),
-                        };
-
-                        if !self.conversations.contains_key(&window_name) {
-                            let conversation = match message.type_ {
-                                XmppMessageType::Chat => match message.direction {
-                                    Direction::Incoming => Conversation::Chat(Chat {
-                                        account: account.clone().unwrap(),
-                                        contact: message.from.clone(),
-                                    }),
-                                    Direction::Outgoing => Conversation::Chat(Chat {
-                                        account: account.clone().unwrap(),
-                                        contact: message.to.clone(),
-                                    }),
-                                },
-                                XmppMessageType::Channel => match message.direction {
-                                    Direction::Incoming => Conversation::Channel(Channel {
-                                        account: account.clone().unwrap(),
-                                        jid: message.from.clone(),
-                                        nick: account.as_ref().unwrap().resource.clone(),
-                                        name: None,
-                                        occupants: HashMap::new(),
-                                    }),
-                                    Direction::Outgoing => Conversation::Channel(Channel {
-                                        account: account.clone().unwrap(),
-                                        jid: message.to.clone(),
-                                        nick: account.as_ref().unwrap().resource.clone(),
-                                        name: None,
-                                        occupants: HashMap::new(),
-                                    }),
-                                },
+                // Messages of contacts who moved end up in their current conversation
+                let message = {
+                    let moved = aparte.get_mod::<mods::moved::MovedMod>();
+                    moved.follow(account, message)
+                };
+                // User defined filters only apply to incoming messages
+                let action = match &message {
+                    Message::Xmpp(message) if message.direction == Direction::Incoming => {
+                        let filters = aparte.get_mod::<mods::filter::FilterMod>();
+                        filters.action(&message.from, message.get_last_body())
+                    }
+                    _ => None,
+                };
+                // Strangers wait in the requests window
+                let routing = match action {
+                    Some(FilterAction::Hide) => Routing::Drop,
+                    _ => {
+                        let mut requests = aparte.get_mod_mut::<mods::requests::RequestsMod>();
+                        requests.route(aparte, account, &message)
+                    }
+                };
+                if routing == Routing::Drop {
+                    debug!("Not displaying message {}", message.id());
+                } else if let Routing::Hold { first } = routing {
+                    self.add_request(aparte, &message, first);
+                } else {
+                    match &message {
+                        Message::Xmpp(message) => {
+                            let window_name = match message.direction {
+                                Direction::Incoming => message.from.to_string(),
+                                Direction::Outgoing => message.to.to_string(),
                             };
 
-                            self.add_conversation(aparte, conversation);
-                        }
+                            if !self.conversations.contains_key(&window_name) {
+                                let conversation = match message.type_ {
+                                    XmppMessageType::Chat => match message.direction {
+                                        Direction::Incoming => Conversation::Chat(Chat {
+                                            account: account.clone().unwrap(),
+                                            contact: message.from.clone(),
+                                        }),
+                                        Direction::Outgoing => Conversation::Chat(Chat {
+                                            account: account.clone().unwrap(),
+                                            contact: message.to.clone(),
+                                        }),
+                                    },
+                                    XmppMessageType::Channel => match message.direction {
+                                        Direction::Incoming => Conversation::Channel(Channel {
+                                            account: account.clone().unwrap(),
+                                            jid: message.from.clone(),
+                                            nick: account.as_ref().unwrap().resource.clone(),
+                                            name: None,
+                                            occupants: HashMap::new(),
+                                        }),
+                                        Direction::Outgoing => Conversation::Channel(Channel {
+                                            account: account.clone().unwrap(),
+                                            jid: message.to.clone(),
+                                            nick: account.as_ref().unwrap().resource.clone(),
+                                            name: None,
+                                            occupants: HashMap::new(),
+                                        }),
+                                    },
+                                };
 
-                        if message.direction == Direction::Incoming {
-                            let mut unread = None;
-                            for window in &self.windows {
-                                if &message.from.to_string() == window
-                                    && Some(window) != self.current_window.as_ref()
-                                {
-                                    unread = Some(window.clone());
+                                self.add_conversation(aparte, conversation);
+                            }
+
+                            if message.direction == Direction::Incoming {
+                                let mut unread = None;
+                                for window in &self.windows {
+                                    if &message.from.to_string() == window
+                                        && Some(window) != self.current_window.as_ref()
+                                    {
+                                        unread = Some(window.clone());
+                                    }
+                                }
+                                if unread.is_some() {
+                                    self.unread_windows.insert(unread.unwrap());
+                                }
+                                // Messages already in sight don't need to ring
+                                let seen = self.focused == Some(true)
+                                    && self.current_window.as_ref()
+                                        == Some(&message.from.to_string());
+                                if !seen {
+                                    aparte.schedule(Event::Notification(String::from("")));
                                 }
                             }
-                            if unread.is_some() {
-                                self.unread_windows.insert(unread.unwrap());
+
+                            match action {
+                                Some(FilterAction::Highlight) => {
+                                    HIGHLIGHTED_MESSAGES.with(|highlighted| {
+                                        highlighted.borrow_mut().insert(message.id.clone())
+                                    });
+                                }
+                                Some(FilterAction::Notify) => aparte.log(format!(
+                                    "{}: {}",
+                                    message.from,
+                                    message.get_last_body()
+                                )),
+                                _ => {}
                             }
-                            aparte.schedule(Event::Notification(String::from("")));
                         }
-                    }
-                    Message::Log(_message) => {}
-                };
+                        Message::Log(_message) => {}
+                    };
 
-                self.root.event(&mut UIEvent::Core(Event::Message(
-                    account.clone(),
-                    message.clone(),
-                )));
+                    self.root.event(&mut UIEvent::Core(Event::Message(
+                        account.clone(),
+                        message.clone(),
+                    )));
+                }
             }
             Event::Chat { account, contact } => {
                 // Should we store account association?
@@ -1122,7 +3500,46 @@ impl ModTrait for UIMod {
                         }),
                     );
                 }
-                self.change_window(&win_name);
+                // Restored windows don't steal the focus
+                if !self.restoring.remove(&win_name) {
+                    self.change_window(&win_name);
+                }
+            }
+            Event::Merge { account, old, new } => {
+                let history = {
+                    let messages = aparte.get_mod::<mods::messages::MessagesMod>();
+                    let moved = aparte.get_mod::<mods::moved::MovedMod>();
+                    messages
+                        .conversation(&Some(account.clone()), old)
+                        .iter()
+                        .map(|message| moved.follow(&Some(account.clone()), message))
+                        .collect::<Vec<Message>>()
+                };
+
+                let win_name = new.to_string();
+                if !self.windows.contains(&win_name) {
+                    self.add_conversation(
+                        aparte,
+                        Conversation::Chat(Chat {
+                            account: account.clone(),
+                            contact: new.clone(),
+                        }),
+                    );
+                }
+
+                for message in history {
+                    self.root.event(&mut UIEvent::Core(Event::Message(
+                        Some(account.clone()),
+                        message,
+                    )));
+                }
+
+                if self.windows.contains(&old.to_string()) {
+                    if self.current_window.as_ref() == Some(&old.to_string()) {
+                        self.change_window(&win_name);
+                    }
+                    aparte.schedule(Event::Close(old.to_string()));
+                }
             }
             Event::Joined {
                 account,
@@ -1147,6 +3564,38 @@ impl ModTrait for UIMod {
                     self.change_window(&win_name);
                 }
             }
+            Event::ChangeWindow(_) => {
+                // Already handled by change_window
+            }
+            Event::MessageSelected {
+                account,
+                conversation,
+                message,
+            } => {
+                let status = match message {
+                    Some(Message::Xmpp(message)) => {
+                        let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+                        match (conversations.get(account, conversation), &message.from_full) {
+                            (Some(Conversation::Channel(channel)), Jid::Full(from)) => {
+                                match channel.occupants.get(&from.resource) {
+                                    Some(occupant) => Some(occupant_info(occupant)),
+                                    None => Some(terminus::clean(&format!(
+                                        "{} isn't in the room anymore",
+                                        from.resource
+                                    ))),
+                                }
+                            }
+                            // Actions the contact can't make use of are greyed out
+                            (Some(Conversation::Chat(_)), _) => {
+                                Some(mods::disco::actions_status(aparte, account, conversation))
+                            }
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+                self.root.event(&mut UIEvent::Status(status));
+            }
             Event::Win(window) => {
                 if self.windows.contains(window) {
                     self.change_window(&window);
@@ -1155,7 +3604,7 @@ impl ModTrait for UIMod {
                 }
             }
             Event::WindowChange => {
-                let (width, height) = termion::terminal_size().unwrap();
+                let (width, height) = self.screen.size().unwrap();
                 let mut dimension = Dimension::new();
                 self.root.measure(&mut dimension, Some(width), Some(height));
                 self.root.layout(&mut dimension, 1, 1);
@@ -1176,6 +3625,11 @@ impl ModTrait for UIMod {
                         .event(&mut UIEvent::Core(Event::Close(window.clone())))
                 }
             }
+            Event::Key(key) if self.take_chord_key(aparte, (None, *key)) => {}
+            Event::ModifiedKey(modifier, key)
+                if self.take_chord_key(aparte, (Some(*modifier), *key)) => {}
+            Event::ModifiedKey(Modifier::Alt, Key::Left) => self.prev_window(),
+            Event::ModifiedKey(Modifier::Alt, Key::Right) => self.next_window(),
             Event::Key(key) => {
                 match key {
                     Key::Char('\t') => {
@@ -1212,12 +3666,14 @@ impl ModTrait for UIMod {
                         // TODO avoid direct send to root, should go back to main event loop
                         self.root.event(&mut UIEvent::Validate(Rc::clone(&result)));
 
-                        let result = result.borrow_mut();
-                        let (raw_buf, password) = result.as_ref().unwrap();
-                        let raw_buf = raw_buf.clone();
-                        if *password {
+                        let (raw_buf, password) = result.borrow_mut().take().unwrap();
+                        if password {
+                            // The command is the only copy of the password left, wiped once parsed
                             let mut command = self.password_command.take().unwrap();
-                            command.args.push(raw_buf.clone());
+                            command.args.push(raw_buf);
+                            aparte.schedule(Event::Command(command));
+                        } else if let Some(mut command) = self.input_command.take() {
+                            command.args.push(raw_buf);
                             aparte.schedule(Event::Command(command));
                         } else if raw_buf.starts_with("/") {
                             let window = self.current_window.clone().unwrap();
@@ -1230,56 +3686,14 @@ impl ModTrait for UIMod {
                             };
                             aparte.schedule(Event::RawCommand(account, window, raw_buf.clone()));
                         } else if raw_buf.len() > 0 {
-                            if let Some(current_window) = self.current_window.clone() {
-                                if let Some(conversation) = self.conversations.get(&current_window)
-                                {
-                                    match conversation {
-                                        Conversation::Chat(chat) => {
-                                            let account = &chat.account;
-                                            let us = account.clone().into();
-                                            let from: Jid = us;
-                                            let to: Jid = chat.contact.clone().into();
-                                            let id = Uuid::new_v4();
-                                            let timestamp = LocalTz::now().into();
-                                            let mut bodies = HashMap::new();
-                                            bodies.insert("".to_string(), raw_buf.clone());
-                                            let message = Message::outgoing_chat(
-                                                id.to_string(),
-                                                timestamp,
-                                                &from,
-                                                &to,
-                                                &bodies,
-                                            );
-                                            aparte.schedule(Event::SendMessage(
-                                                account.clone(),
-                                                message,
-                                            ));
-                                        }
-                                        Conversation::Channel(channel) => {
-                                            let account = &channel.account;
-                                            let mut us = account.clone();
-                                            us.resource = channel.nick.clone();
-                                            let from: Jid = us.into();
-                                            let to: Jid = channel.jid.clone().into();
-                                            let id = Uuid::new_v4();
-                                            let timestamp = LocalTz::now().into();
-                                            let mut bodies = HashMap::new();
-                                            bodies.insert("".to_string(), raw_buf.clone());
-                                            let message = Message::outgoing_channel(
-                                                id.to_string(),
-                                                timestamp,
-                                                &from,
-                                                &to,
-                                                &bodies,
-                                            );
-                                            aparte.schedule(Event::SendMessage(
-                                                account.clone(),
-                                                message,
-                                            ));
-                                        }
-                                    }
-                                }
+                            let body = self.prepare_body(aparte, raw_buf);
+                            if let (Some(body), Some(current_window)) =
+                                (body, self.current_window.clone())
+                            {
+                                self.send_body(aparte, &current_window, body);
                             }
+                        } else {
+                            self.root.event(&mut UIEvent::Activate);
                         }
                     }
                     Key::Alt('a') => {
@@ -1287,6 +3701,14 @@ impl ModTrait for UIMod {
                             self.change_window(&window);
                         }
                     }
+                    Key::Alt('r') => self.toggle_sidebar(aparte, Sidebar::Roster),
+                    Key::Alt('o') => self.toggle_sidebar(aparte, Sidebar::Occupants),
+                    Key::Up | Key::Down | Key::Left | Key::Right
+                        if self.current_window.as_deref() == Some(DISCO_WINDOW)
+                            && self.input_is_empty() =>
+                    {
+                        self.root.event(&mut UIEvent::Browse(*key));
+                    }
                     _ => {
                         aparte.schedule(Event::ResetCompletion);
                         self.root.event(&mut UIEvent::Core(Event::Key(key.clone())));
@@ -1299,17 +3721,130 @@ impl ModTrait for UIMod {
                     cursor.clone(),
                 )));
             }
+            Event::Announcement(_, message) => self.add_announcement(aparte, message),
             Event::Notification(_) => {
                 vprint!(self.screen, "\x07");
                 flush!(self.screen);
             }
+            Event::Focus(focused) => {
+                // Terminal multiplexers forget the modes of detached clients
+                if *focused {
+                    vprint!(
+                        self.screen,
+                        "{}{}",
+                        ENABLE_BRACKETED_PASTE,
+                        ENABLE_FOCUS_REPORTING
+                    );
+                    flush!(self.screen);
+                }
+                self.focused = Some(*focused);
+            }
+            Event::ShowMessage {
+                account,
+                conversation,
+                message,
+            } => self.show_message(aparte, account, conversation, message),
+            Event::CustomEmoji {
+                conversation,
+                shortname,
+                image,
+            } => {
+                let rendered = render_inline(&image.data, &image.type_, self.graphics, EMOJI_WIDTH);
+                if let Some(rendered) = rendered {
+                    CUSTOM_EMOJI.with(|emoji| {
+                        emoji
+                            .borrow_mut()
+                            .entry(conversation.clone())
+                            .or_default()
+                            .insert(shortname.clone(), rendered)
+                    });
+                    self.root.event(&mut UIEvent::Core(event.clone()));
+                }
+            }
+            Event::Attention { account, contact } => {
+                let name = contact.to_string();
+                if !self.windows.contains(&name) {
+                    self.add_conversation(
+                        aparte,
+                        Conversation::Chat(Chat {
+                            account: account.clone(),
+                            contact: contact.clone(),
+                        }),
+                    );
+                }
+                if self.current_window.as_ref() != Some(&name) {
+                    self.unread_windows.insert(name.clone());
+                    self.root.event(&mut UIEvent::Urgent(name));
+                }
+                // Rings even when the conversation is in sight, and flashes the screen
+                aparte.schedule(Event::Notification(format!(
+                    "{} requests your attention",
+                    contact
+                )));
+                vprint!(self.screen, "{}", FLASH_START);
+                flush!(self.screen);
+                aparte.spawn(async {
+                    tokio::time::sleep(FLASH_DURATION).await;
+                    Event::FlashEnd
+                });
+            }
+            Event::FlashEnd => {
+                vprint!(self.screen, "{}", FLASH_END);
+                flush!(self.screen);
+            }
+            Event::ChordTimeout(chords) if *chords == self.chords && !self.chord.is_empty() => {
+                self.chord.clear();
+                self.root.event(&mut UIEvent::Chord(None));
+            }
+            Event::Avatar { contact, avatar } => {
+                let thumbnail = avatar
+                    .as_ref()
+                    .map(|avatar| avatar_thumbnail(avatar, self.graphics));
+                AVATARS.with(|avatars| match thumbnail {
+                    Some(thumbnail) => avatars.borrow_mut().insert(contact.clone(), thumbnail),
+                    None => avatars.borrow_mut().remove(contact),
+                });
+                self.root.event(&mut UIEvent::Core(event.clone()));
+            }
+            Event::Mood { contact, mood } => {
+                MOODS.with(|moods| match mood {
+                    Some(mood) => moods.borrow_mut().insert(contact.clone(), mood.clone()),
+                    None => moods.borrow_mut().remove(contact),
+                });
+                self.root.event(&mut UIEvent::Core(event.clone()));
+            }
+            Event::Activity { contact, activity } => {
+                ACTIVITIES.with(|activities| match activity {
+                    Some(activity) => activities
+                        .borrow_mut()
+                        .insert(contact.clone(), activity.clone()),
+                    None => activities.borrow_mut().remove(contact),
+                });
+                self.root.event(&mut UIEvent::Core(event.clone()));
+            }
+            Event::LinkTitle {
+                url,
+                title: Some(title),
+                ..
+            } => {
+                LINK_TITLES.with(|titles| titles.borrow_mut().insert(url.clone(), title.clone()));
+                self.root.event(&mut UIEvent::Core(event.clone()));
+            }
+            Event::Protection { id, protection, .. } => {
+                PROTECTIONS.with(|protections| {
+                    protections
+                        .borrow_mut()
+                        .insert(id.clone(), protection.clone())
+                });
+                self.root.event(&mut UIEvent::Core(event.clone()));
+            }
             // Forward all unknown events
             event => self.root.event(&mut UIEvent::Core(event.clone())),
         }
 
         // Update rendering
         if self.root.is_layout_dirty() {
-            let (width, height) = termion::terminal_size().unwrap();
+            let (width, height) = self.screen.size().unwrap();
             let mut dimension = Dimension::new();
             self.root.measure(&mut dimension, Some(width), Some(height));
             self.root.layout(&mut dimension, 1, 1);
@@ -1319,6 +3854,8 @@ impl ModTrait for UIMod {
             let dimension: &Dimension = self.dimension.as_ref().unwrap();
             self.root.render(dimension, &mut self.screen);
         }
+        // Views only mark what they redrew, the frame reaches the terminal at once
+        flush!(self.screen);
 
         // Handle queued outgoing event
         for event in self.outgoing_event_queue.borrow_mut().drain(..) {
@@ -1327,15 +3864,135 @@ impl ModTrait for UIMod {
     }
 }
 
+impl Drop for UIMod {
+    fn drop(&mut self) {
+        vprint!(
+            self.screen,
+            "{}{}",
+            DISABLE_BRACKETED_PASTE,
+            DISABLE_FOCUS_REPORTING
+        );
+        flush!(self.screen);
+    }
+}
+
 impl fmt::Display for UIMod {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Aparté UI")
     }
 }
 
+const ENABLE_BRACKETED_PASTE: &str = "\x1b[?2004h";
+const DISABLE_BRACKETED_PASTE: &str = "\x1b[?2004l";
+const ENABLE_FOCUS_REPORTING: &str = "\x1b[?1004h";
+const DISABLE_FOCUS_REPORTING: &str = "\x1b[?1004l";
+/// Reverse video of the whole screen, for a short visual bell
+const FLASH_START: &str = "\x1b[?5h";
+const FLASH_END: &str = "\x1b[?5l";
+const FLASH_DURATION: Duration = Duration::from_millis(200);
+const FOCUS_IN: &[u8] = b"\x1b[I";
+const FOCUS_OUT: &[u8] = b"\x1b[O";
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+/// Time left to the rest of a paste marker to be read, before what was read of it is taken as
+/// keys (like a lone Esc)
+const PASTE_MARKER_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Translate bracketed pastes so that pasted new lines are inserted in the input (as Alt-Enter)
+/// instead of validating it line by line
+#[derive(Default)]
+struct BracketedPaste {
+    pasting: bool,
+    pending: Vec<u8>,
+}
+
+impl BracketedPaste {
+    /// Translate the bytes read, holding back the start of a marker until the next read
+    fn translate(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+
+        for byte in bytes {
+            self.pending.push(*byte);
+            let marker = match self.pasting {
+                true => PASTE_END,
+                false => PASTE_START,
+            };
+
+            if marker.starts_with(&self.pending) {
+                if marker.len() == self.pending.len() {
+                    self.pasting = !self.pasting;
+                    self.pending.clear();
+                }
+            } else {
+                self.flush(&mut output);
+            }
+        }
+
+        output
+    }
+
+    /// Whether the start of a marker is held back
+    fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Give back what was held back when nothing followed it, it wasn't a marker
+    fn timeout(&mut self) -> Vec<u8> {
+        let mut output = Vec::new();
+        self.flush(&mut output);
+        output
+    }
+
+    fn flush(&mut self, output: &mut Vec<u8>) {
+        for byte in self.pending.drain(..) {
+            match byte {
+                b'\r' | b'\n' if self.pasting => output.extend_from_slice(b"\x1b\n"),
+                byte => output.push(byte),
+            }
+        }
+    }
+}
+
+/// Hold back the UTF-8 sequences split across reads, so that composed characters and input
+/// methods sending multi-byte characters are never parsed from half of their bytes
+#[derive(Default)]
+struct Utf8Reassembler {
+    pending: Vec<u8>,
+}
+
+impl Utf8Reassembler {
+    fn complete(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut output = std::mem::take(&mut self.pending);
+        output.extend_from_slice(bytes);
+
+        // Look for a lead byte among the last three bytes, continuation bytes are 0b10xxxxxx
+        let tail = output
+            .iter()
+            .rev()
+            .take(3)
+            .position(|byte| byte & 0b1100_0000 != 0b1000_0000);
+        if let Some(tail) = tail {
+            let start = output.len() - tail - 1;
+            let expected = match output[start] {
+                lead if lead & 0b1110_0000 == 0b1100_0000 => 2,
+                lead if lead & 0b1111_0000 == 0b1110_0000 => 3,
+                lead if lead & 0b1111_1000 == 0b1111_0000 => 4,
+                // ASCII or invalid byte, let the parser deal with it
+                _ => 0,
+            };
+            if tail + 1 < expected {
+                self.pending = output.split_off(start);
+            }
+        }
+
+        output
+    }
+}
+
 struct TermionEventStream {
-    channel: mpsc::Receiver<Result<u8, IoError>>,
+    channel: mpsc::Receiver<Vec<u8>>,
     waker: Arc<AtomicWaker>,
+    buffer: VecDeque<u8>,
 }
 
 impl TermionEventStream {
@@ -1347,14 +4004,30 @@ impl TermionEventStream {
         thread::spawn(move || {
             let mut input = get_tty().expect("cannot get tty for stdin reading");
             let mut buf = [0u8; 256];
+            let mut paste = BracketedPaste::default();
+            let mut utf8 = Utf8Reassembler::default();
             loop {
+                // A marker split across reads is completed by the next one, shortly
+                if paste.is_pending() && !wait_input(&input, PASTE_MARKER_TIMEOUT) {
+                    let bytes = utf8.complete(&paste.timeout());
+                    if send.send(bytes).is_err() {
+                        return;
+                    }
+                    waker_for_tty.wake();
+                    continue;
+                }
+
                 match input.read(&mut buf[..]) {
+                    Ok(0) => break,
                     Ok(n) => {
-                        for byte in buf[..n].iter() {
-                            if send.send(Ok(*byte)).is_err() {
-                                // channel has been closed, get out
-                                return;
-                            }
+                        // Bytes are sent by chunk so that a character is never received in part
+                        let bytes = utf8.complete(&paste.translate(&buf[..n]));
+                        if bytes.is_empty() {
+                            continue;
+                        }
+                        if send.send(bytes).is_err() {
+                            // channel has been closed, get out
+                            return;
                         }
                         waker_for_tty.wake();
                     }
@@ -1372,27 +4045,89 @@ impl TermionEventStream {
         Self {
             channel: recv,
             waker,
+            buffer: VecDeque::new(),
         }
     }
 }
 
-struct IterWrapper<'a, T> {
-    inner: &'a mut mpsc::Receiver<T>,
+/// Wait for the tty to have something to read, false when it has nothing once `timeout` elapsed
+fn wait_input(tty: &fs::File, timeout: Duration) -> bool {
+    let mut fd = libc::pollfd {
+        fd: tty.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // Safety: a single valid pollfd is given
+    let ready = unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) };
+    // Errors are left to the read that follows
+    ready != 0
 }
 
-impl<'a, T> IterWrapper<'a, T> {
-    fn new(inner: &'a mut mpsc::Receiver<T>) -> Self {
-        Self { inner }
+/// Take a focus report starting with the given byte out of the input, termion doesn't know them
+fn focus_report(byte: u8, buffer: &mut VecDeque<u8>) -> Option<Vec<u8>> {
+    if byte != FOCUS_IN[0] || buffer.len() < 2 {
+        return None;
+    }
+    let report = [byte, buffer[0], buffer[1]];
+    match report == FOCUS_IN || report == FOCUS_OUT {
+        true => {
+            buffer.drain(..2);
+            Some(report.to_vec())
+        }
+        false => None,
     }
 }
 
-impl<'a, T> Iterator for IterWrapper<'a, T> {
-    type Item = T;
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.inner.try_recv() {
-            Ok(e) => Some(e),
-            Err(_) => None,
+/// Special key pressed with a modifier starting the input, with the length of its escape
+/// sequence. termion doesn't know them, both xterm (`ESC [ 1 ; 3 D`) and rxvt (`ESC ESC [ D`)
+/// encodings are read.
+fn modified_key(bytes: &[u8]) -> Option<(Modifier, Key, usize)> {
+    let modifier = |byte| match byte {
+        b'2' => Some(Modifier::Shift),
+        b'3' => Some(Modifier::Alt),
+        b'5' => Some(Modifier::Ctrl),
+        _ => None,
+    };
+    let arrow = |byte: u8| match byte.to_ascii_uppercase() {
+        b'A' => Some(Key::Up),
+        b'B' => Some(Key::Down),
+        b'C' => Some(Key::Right),
+        b'D' => Some(Key::Left),
+        _ => None,
+    };
+    let special = |code: &[u8]| match code {
+        b"2" => Some(Key::Insert),
+        b"3" => Some(Key::Delete),
+        b"5" => Some(Key::PageUp),
+        b"6" => Some(Key::PageDown),
+        b"15" => Some(Key::F(5)),
+        b"17" => Some(Key::F(6)),
+        b"18" => Some(Key::F(7)),
+        b"19" => Some(Key::F(8)),
+        b"20" => Some(Key::F(9)),
+        b"21" => Some(Key::F(10)),
+        b"23" => Some(Key::F(11)),
+        b"24" => Some(Key::F(12)),
+        _ => None,
+    };
+    match bytes {
+        [b'\x1b', b'[', b'1', b';', m, end, ..] => {
+            let key = match end {
+                b'H' => Key::Home,
+                b'F' => Key::End,
+                b'P'..=b'S' => Key::F(1 + end - b'P'),
+                end => arrow(*end)?,
+            };
+            Some((modifier(*m)?, key, 6))
+        }
+        [b'\x1b', b'[', code, b';', m, b'~', ..] => Some((modifier(*m)?, special(&[*code])?, 6)),
+        [b'\x1b', b'[', c1, c2, b';', m, b'~', ..] => {
+            Some((modifier(*m)?, special(&[*c1, *c2])?, 7))
         }
+        [b'\x1b', b'\x1b', b'[', end @ b'A'..=b'D', ..] => Some((Modifier::Alt, arrow(*end)?, 4)),
+        [b'\x1b', b'[', end @ b'a'..=b'd', ..] => Some((Modifier::Shift, arrow(*end)?, 3)),
+        [b'\x1b', b'O', end @ b'a'..=b'd', ..] => Some((Modifier::Ctrl, arrow(*end)?, 3)),
+        _ => None,
     }
 }
 
@@ -1400,22 +4135,47 @@ impl Stream for TermionEventStream {
     type Item = TermionEvent;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        let byte = match self.channel.try_recv() {
-            Ok(Ok(byte)) => byte,
-            Ok(Err(_)) => return Poll::Ready(None),
-            Err(mpsc::TryRecvError::Empty) => {
+        loop {
+            match self.channel.try_recv() {
+                Ok(bytes) => self.buffer.extend(bytes),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) if self.buffer.is_empty() => {
+                    return Poll::Ready(None)
+                }
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            }
+        }
+
+        let byte = match self.buffer.pop_front() {
+            Some(byte) => byte,
+            None => {
                 self.waker.register(cx.waker());
                 return Poll::Pending;
             }
-            Err(mpsc::TryRecvError::Disconnected) => return Poll::Ready(None),
         };
 
-        let mut iter = IterWrapper::new(&mut self.channel);
-        if let Ok(event) = termion_parse_event(byte, &mut iter) {
-            Poll::Ready(Some(event))
-        } else {
-            self.waker.register(cx.waker());
-            Poll::Pending
+        if let Some(report) = focus_report(byte, &mut self.buffer) {
+            return Poll::Ready(Some(TermionEvent::Unsupported(report)));
+        }
+
+        // Handed over as is, for EventStream to read it again
+        let pending = std::iter::once(byte)
+            .chain(self.buffer.iter().take(6).copied())
+            .collect::<Vec<u8>>();
+        if let Some((_, _, len)) = modified_key(&pending) {
+            self.buffer.drain(..len - 1);
+            return Poll::Ready(Some(TermionEvent::Unsupported(pending[..len].to_vec())));
+        }
+
+        let buffer = &mut self.buffer;
+        let mut iter = std::iter::from_fn(|| buffer.pop_front().map(Ok));
+        match termion_parse_event(byte, &mut iter) {
+            Ok(event) => Poll::Ready(Some(event)),
+            Err(_) => {
+                // Skip the invalid input but go on with what follows
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
         }
     }
 }
@@ -1437,50 +4197,45 @@ impl Stream for EventStream {
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         match Pin::new(&mut self.inner).poll_next(cx) {
-            Poll::Ready(Some(TermionEvent::Key(key))) => {
-                match key {
-                    //Key::Alt('\x1b') => {
-                    //    match Pin::new(&mut self.inner).poll_next(cx) {
-                    //        Poll::Ready(Some(TermionEvent::Key(Key::Char('[')))) => {
-                    //            match Pin::new(&mut self.inner).poll_next(cx) {
-                    //                Poll::Ready(Some(TermionEvent::Key(Key::Char('C')))) => Poll::Pending,
-                    //                Poll::Ready(Some(TermionEvent::Key(Key::Char('D')))) => Poll::Pending,
-                    //                Poll::Ready(Some(TermionEvent::Key(_))) => Poll::Pending,
-                    //                Poll::Ready(Some(TermionEvent::Key(_))) => Poll::Pending,
-                    //                Poll::Ready(None) => Poll::Pending,
-                    //                Poll::Pending => Poll::Pending,
-                    //            }
-                    //        },
-                    //        _ => Poll::Pending,
-                    //    };
-                    //},
-                    Key::Char(c) => Poll::Ready(Some(Event::Key(Key::Char(c)))),
-                    Key::Backspace => Poll::Ready(Some(Event::Key(Key::Backspace))),
-                    Key::Delete => Poll::Ready(Some(Event::Key(Key::Delete))),
-                    Key::Home => Poll::Ready(Some(Event::Key(Key::Home))),
-                    Key::End => Poll::Ready(Some(Event::Key(Key::End))),
-                    Key::Up => Poll::Ready(Some(Event::Key(Key::Up))),
-                    Key::Down => Poll::Ready(Some(Event::Key(Key::Down))),
-                    Key::Left => Poll::Ready(Some(Event::Key(Key::Left))),
-                    Key::Right => Poll::Ready(Some(Event::Key(Key::Right))),
-                    Key::Ctrl(c) => Poll::Ready(Some(Event::Key(Key::Ctrl(c)))),
-                    Key::Alt(c) => Poll::Ready(Some(Event::Key(Key::Alt(c)))),
-                    Key::PageUp => Poll::Ready(Some(Event::Key(Key::PageUp))),
-                    Key::PageDown => Poll::Ready(Some(Event::Key(Key::PageDown))),
-                    _ => {
-                        self.inner.waker.register(cx.waker());
-                        Poll::Pending
-                    }
+            Poll::Ready(Some(TermionEvent::Key(key))) => match key {
+                Key::Char(c) => Poll::Ready(Some(Event::Key(Key::Char(c)))),
+                Key::Backspace => Poll::Ready(Some(Event::Key(Key::Backspace))),
+                Key::Delete => Poll::Ready(Some(Event::Key(Key::Delete))),
+                Key::Home => Poll::Ready(Some(Event::Key(Key::Home))),
+                Key::End => Poll::Ready(Some(Event::Key(Key::End))),
+                Key::Up => Poll::Ready(Some(Event::Key(Key::Up))),
+                Key::Down => Poll::Ready(Some(Event::Key(Key::Down))),
+                Key::Left => Poll::Ready(Some(Event::Key(Key::Left))),
+                Key::Right => Poll::Ready(Some(Event::Key(Key::Right))),
+                Key::Ctrl(c) => Poll::Ready(Some(Event::Key(Key::Ctrl(c)))),
+                Key::Alt(c) => Poll::Ready(Some(Event::Key(Key::Alt(c)))),
+                Key::PageUp => Poll::Ready(Some(Event::Key(Key::PageUp))),
+                Key::PageDown => Poll::Ready(Some(Event::Key(Key::PageDown))),
+                Key::Insert => Poll::Ready(Some(Event::Key(Key::Insert))),
+                Key::BackTab => Poll::Ready(Some(Event::Key(Key::BackTab))),
+                Key::F(n) => Poll::Ready(Some(Event::Key(Key::F(n)))),
+                _ => {
+                    self.inner.waker.register(cx.waker());
+                    Poll::Pending
                 }
-            }
+            },
             Poll::Ready(Some(TermionEvent::Mouse(_))) => {
                 self.inner.waker.register(cx.waker());
                 Poll::Pending
             }
-            Poll::Ready(Some(TermionEvent::Unsupported(_))) => {
-                self.inner.waker.register(cx.waker());
-                Poll::Pending
+            Poll::Ready(Some(TermionEvent::Unsupported(bytes))) if bytes == FOCUS_IN => {
+                Poll::Ready(Some(Event::Focus(true)))
+            }
+            Poll::Ready(Some(TermionEvent::Unsupported(bytes))) if bytes == FOCUS_OUT => {
+                Poll::Ready(Some(Event::Focus(false)))
             }
+            Poll::Ready(Some(TermionEvent::Unsupported(bytes))) => match modified_key(&bytes) {
+                Some((modifier, key, _)) => Poll::Ready(Some(Event::ModifiedKey(modifier, key))),
+                None => {
+                    self.inner.waker.register(cx.waker());
+                    Poll::Pending
+                }
+            },
             Poll::Ready(None) => Poll::Ready(None),
             Poll::Pending => {
                 self.inner.waker.register(cx.waker());
@@ -1489,3 +4244,657 @@ impl Stream for EventStream {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminus::TestScreen;
+
+    #[test]
+    fn test_bracketed_paste_new_lines_are_inserted() {
+        // Given
+        let mut paste = BracketedPaste::default();
+
+        // When
+        let translated = paste.translate(b"a\r\x1b[200~b\rc\n\x1b[201~\r");
+
+        // Then
+        assert_eq!(translated, b"a\rb\x1b\nc\x1b\n\r".to_vec());
+    }
+
+    #[test]
+    fn test_bracketed_paste_keeps_other_escape_sequences() {
+        // Given
+        let mut paste = BracketedPaste::default();
+
+        // When
+        let translated = paste.translate(b"\x1b[A\x1b[2~\x1b");
+        let held_back = paste.is_pending();
+        let lone_escape = paste.timeout();
+
+        // Then
+        assert_eq!(translated, b"\x1b[A\x1b[2~".to_vec());
+        assert!(held_back);
+        assert_eq!(lone_escape, b"\x1b".to_vec());
+    }
+
+    #[test]
+    fn test_bracketed_paste_end_marker_split_across_reads() {
+        // Given
+        let mut paste = BracketedPaste::default();
+
+        // When
+        let first = paste.translate(b"\x1b[200~a\rb\x1b[2");
+        let second = paste.translate(b"01~\r");
+
+        // Then
+        assert_eq!(first, b"a\x1b\nb".to_vec());
+        assert_eq!(second, b"\r".to_vec());
+        assert!(!paste.is_pending());
+    }
+
+    #[test]
+    fn test_utf8_sequences_split_across_reads_are_held_back() {
+        // Given
+        let mut utf8 = Utf8Reassembler::default();
+        let text = "é漢😀".as_bytes();
+
+        // When
+        let reads = [
+            utf8.complete(&text[..1]),
+            utf8.complete(&text[1..3]),
+            utf8.complete(&text[3..6]),
+            utf8.complete(&text[6..]),
+        ];
+
+        // Then
+        assert_eq!(reads[0], b"".to_vec());
+        assert_eq!(reads[1], "é".as_bytes().to_vec());
+        assert_eq!(reads[2], "漢".as_bytes().to_vec());
+        assert_eq!(reads[3], "😀".as_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_utf8_reassembler_keeps_ascii_and_escape_sequences() {
+        // Given
+        let mut utf8 = Utf8Reassembler::default();
+
+        // When
+        let complete = utf8.complete(b"a\x1b[A\x1bb");
+
+        // Then
+        assert_eq!(complete, b"a\x1b[A\x1bb".to_vec());
+    }
+
+    #[test]
+    fn test_focus_reports_are_taken_out_of_the_input() {
+        // Given
+        let mut focus_in = b"[Ia".iter().cloned().collect::<VecDeque<u8>>();
+        let mut arrow = b"[A".iter().cloned().collect::<VecDeque<u8>>();
+
+        // When
+        let focus_in_report = focus_report(b'\x1b', &mut focus_in);
+        let arrow_report = focus_report(b'\x1b', &mut arrow);
+
+        // Then
+        assert_eq!(focus_in_report, Some(FOCUS_IN.to_vec()));
+        assert_eq!(focus_in, vec![b'a']);
+        assert_eq!(arrow_report, None);
+        assert_eq!(arrow, b"[A".to_vec());
+    }
+
+    #[test]
+    fn test_modified_keys_are_read_from_escape_sequences() {
+        // Given
+        let sequences: [&[u8]; 6] = [
+            b"\x1b[1;2Aa",
+            b"\x1b[1;3D",
+            b"\x1b[5;5~",
+            b"\x1b[15;2~",
+            b"\x1b\x1b[C",
+            b"\x1b[A",
+        ];
+
+        // When
+        let keys = sequences
+            .iter()
+            .map(|sequence| modified_key(sequence))
+            .collect::<Vec<_>>();
+
+        // Then
+        assert_eq!(
+            keys,
+            vec![
+                Some((Modifier::Shift, Key::Up, 6)),
+                Some((Modifier::Alt, Key::Left, 6)),
+                Some((Modifier::Ctrl, Key::PageUp, 6)),
+                Some((Modifier::Shift, Key::F(5), 7)),
+                Some((Modifier::Alt, Key::Right, 4)),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_title_bar_truncates_long_names_and_subjects() {
+        // Given
+        let mut screen = TestScreen::new(20, 2);
+        let mut title = TitleBar::new();
+        let mut subjects = HashMap::new();
+        subjects.insert(String::new(), String::from("Verona gossips"));
+
+        // When
+        title.set_name("room@conference.example.org");
+        screen.render_in(&mut title, 1, 1, 20, 1);
+        title.set_name("room@ex.org");
+        title.add_subjects(String::from("room@ex.org"), subjects);
+        screen.render_in(&mut title, 1, 2, 20, 1);
+
+        // Then
+        assert_eq!(
+            screen.lines(),
+            vec!["room@conference.exa…", "room@ex.org — Veron…"]
+        );
+        assert!(screen.style(1, 1).bold);
+    }
+
+    #[test]
+    fn test_title_bar_shows_since_when_contacts_are_idle() {
+        // Given
+        let mut screen = TestScreen::new(50, 1);
+        let mut title = TitleBar::new();
+        let since = Local::now();
+
+        // When
+        title.set_name("juliet@example.org");
+        title.set_idle(String::from("juliet@example.org"), Some(since.into()));
+        screen.render_in(&mut title, 1, 1, 50, 1);
+
+        // Then
+        assert_eq!(
+            screen.lines(),
+            vec![format!(
+                "juliet@example.org — idle since {}",
+                since.format("%H:%M")
+            )]
+        );
+    }
+
+    #[test]
+    fn test_status_bar_truncates_the_status() {
+        // Given
+        let mut screen = TestScreen::new(30, 2);
+        let mut bar = WinBar::new();
+        bar.connection = Some(String::from("romeo@example.org"));
+        bar.status = Some(String::from("juliet (owner, moderator)"));
+
+        // When
+        screen.render_in(&mut bar, 1, 1, 30, 1);
+
+        // Then
+        assert_eq!(screen.lines(), vec![" romeo@example.org | juliet (…", ""]);
+    }
+
+    #[test]
+    fn test_outgoing_messages_tell_how_they_are_protected() {
+        // Given
+        let from = Jid::from_str("romeo@example.org/aparte").unwrap();
+        let to = Jid::from_str("juliet@example.org").unwrap();
+        let mut bodies = HashMap::new();
+        bodies.insert(String::new(), String::from("Hi"));
+        let encrypted = Message::outgoing_chat("1", LocalTz::now().into(), &from, &to, &bodies);
+        let plaintext = Message::outgoing_chat("2", LocalTz::now().into(), &from, &to, &bodies);
+
+        // When
+        PROTECTIONS.with(|protections| {
+            let mut protections = protections.borrow_mut();
+            protections.insert(String::from("1"), Protection::Encrypted(2));
+            protections.insert(String::from("2"), Protection::Plaintext);
+        });
+
+        // Then
+        assert!(encrypted
+            .to_string()
+            .ends_with(&crate::color::dimmed(" [encrypted, 2 keys]")));
+        assert!(plaintext.to_string().contains(" [NOT ENCRYPTED]"));
+    }
+
+    #[test]
+    fn test_oldest_remembered_values_are_forgotten() {
+        // Given
+        let mut remembered = Remembered::new();
+        for i in 0..REMEMBERED_LIMIT {
+            remembered.insert(i.to_string(), i);
+        }
+
+        // When
+        remembered.insert(String::from("0"), 42);
+        remembered.insert(String::from("new"), 0);
+
+        // Then
+        assert_eq!(remembered.get("0"), None);
+        assert_eq!(remembered.get("1"), Some(&1));
+        assert_eq!(remembered.get("new"), Some(&0));
+        assert_eq!(remembered.values.len(), REMEMBERED_LIMIT);
+    }
+
+    #[test]
+    fn test_occupant_info() {
+        // Given
+        let occupant = conversation::Occupant {
+            nick: String::from("alice"),
+            jid: Some(BareJid::from_str("alice@example.org").unwrap()),
+            affiliation: conversation::Affiliation::Owner,
+            role: conversation::Role::Moderator,
+            joined: LocalTz::now().into(),
+        };
+
+        // When
+        let info = occupant_info(&occupant);
+
+        // Then
+        assert!(info.starts_with(
+            "alice <alice@example.org>, affiliation owner, role moderator, here since "
+        ));
+    }
+
+    #[test]
+    fn test_presence_feed_only_shows_changes_of_followed_groups() {
+        // Given
+        let mut known = HashMap::new();
+        let groups = vec![contact::Group(String::from("Friends"))];
+        let mut friend = contact::Contact {
+            jid: BareJid::from_str("friend@example.org").unwrap(),
+            name: Some(String::from("Friend")),
+            subscription: xmpp_parsers::roster::Subscription::Both,
+            presence: contact::Presence::Available,
+            status: None,
+            idle: None,
+            groups: groups.clone(),
+        };
+        let colleague = contact::Contact {
+            jid: BareJid::from_str("colleague@example.org").unwrap(),
+            name: None,
+            groups: vec![contact::Group(String::from("Work"))],
+            ..friend.clone()
+        };
+
+        // When
+        let online = presence_change(&mut known, &groups, &friend);
+        let repeated = presence_change(&mut known, &groups, &friend);
+        friend.presence = contact::Presence::Away;
+        friend.status = Some(String::from("Lunch"));
+        let away = presence_change(&mut known, &groups, &friend);
+
+        // Then
+        assert_eq!(
+            online,
+            Some(String::from("Friend (friend@example.org) is online"))
+        );
+        assert_eq!(repeated, None);
+        assert_eq!(
+            away,
+            Some(String::from("Friend (friend@example.org) is away: Lunch"))
+        );
+        assert_eq!(presence_change(&mut known, &groups, &colleague), None);
+        assert!(presence_change(&mut known, &[], &colleague).is_some());
+    }
+
+    #[test]
+    fn test_headlines_and_server_messages_go_to_the_announcements_window() {
+        crate::testing::run(async {
+            // Given
+            let mut harness = crate::testing::Harness::new();
+            harness.connect().await;
+
+            // When
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='headline' from='news.example.org'
+                        to='{account}'>
+                        <subject>Release</subject>
+                        <body>Aparté is out</body>
+                    </message>",
+                )
+                .await;
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' from='example.org' to='{account}'>
+                        <body>Maintenance tonight</body>
+                    </message>",
+                )
+                .await;
+
+            // Then
+            {
+                let ui = harness.aparte.get_mod::<UIMod>();
+                assert!(ui.unread_windows.contains("announcements"));
+                assert!(!ui.windows.contains(&String::from("news.example.org")));
+            }
+            harness.input("console", "/win announcements").await;
+            let screen = harness.screen();
+            assert!(screen.contains("news.example.org: Release"));
+            assert!(screen.contains("Aparté is out"));
+            assert!(screen.contains("example.org: Maintenance tonight"));
+        });
+    }
+
+    #[test]
+    fn test_out_of_band_files_are_shown_as_attachments() {
+        crate::testing::run(async {
+            // Given
+            let mut harness = crate::testing::Harness::new();
+            harness.connect().await;
+            harness.input("console", "/msg juliet@example.org").await;
+
+            // When
+            harness
+                .receive(
+                    "<message xmlns='jabber:client' type='chat' id='m1'
+                        from='juliet@example.org/balcony' to='{account}'>
+                        <body>https://download.montague.lit/summit.jpg</body>
+                        <x xmlns='jabber:x:oob'>
+                            <url>https://download.montague.lit/summit.jpg</url>
+                        </x>
+                    </message>",
+                )
+                .await;
+
+            // Then
+            let screen = harness.screen();
+            assert!(screen.contains("juliet@example.org: [summit.jpg]"));
+            assert!(!screen.contains("juliet@example.org: https://"));
+        });
+    }
+
+    /// Type a message in the input and validate it
+    fn type_message(harness: &mut crate::testing::Harness, message: &str) {
+        for c in message.chars().chain(Some('\n')) {
+            harness.aparte.schedule(Event::Key(Key::Char(c)));
+        }
+    }
+
+    #[test]
+    fn test_paste_command_gets_the_message_on_its_stdin() {
+        crate::testing::run(async {
+            // When
+            let link = paste("tr a-z A-Z", "hello\nworld").await;
+            let failed = paste("cat > /dev/null", "hello").await;
+
+            // Then
+            assert_eq!(link, Ok(String::from("HELLO\nWORLD")));
+            assert_eq!(
+                failed,
+                Err(String::from("`cat > /dev/null` didn't output anything"))
+            );
+        });
+    }
+
+    #[test]
+    fn test_long_messages_are_sent_through_the_paste_command_once_validated_again() {
+        crate::testing::run(async {
+            // Given
+            let mut config = crate::config::Config::default();
+            config.paste.max_lines = 0;
+            config.paste.command = Some(String::from("sed s,^,https://paste.example.org/,"));
+            let mut harness = crate::testing::Harness::with_config(config);
+            harness.connect().await;
+            harness.input("console", "/msg juliet@example.org").await;
+
+            // When
+            type_message(&mut harness, "hello");
+            harness.settle().await;
+            let held_back = harness.take_sent("message", "jabber:client");
+            harness.aparte.schedule(Event::Key(Key::Char('\n')));
+            let mut sent = Vec::new();
+            for _ in 0..100 {
+                harness.settle().await;
+                sent = harness.take_sent("message", "jabber:client");
+                if !sent.is_empty() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            // Then
+            assert!(held_back.is_empty());
+            assert_eq!(sent.len(), 1);
+            let body = sent[0].get_child("body", "jabber:client").unwrap();
+            assert_eq!(body.text(), "https://paste.example.org/hello");
+        });
+    }
+
+    #[test]
+    fn test_parse_key_sequences() {
+        // Given
+        let leader = parse_key("C-b").ok();
+
+        // When
+        let keys = parse_keys("<leader> M-space F5 PageUp n", leader);
+        let unknown = parse_keys("C-b Hyper", leader);
+        let no_leader = parse_keys("<leader> n", None);
+
+        // Then
+        assert_eq!(
+            keys,
+            Ok(vec![
+                (None, Key::Ctrl('b')),
+                (None, Key::Alt(' ')),
+                (None, Key::F(5)),
+                (None, Key::PageUp),
+                (None, Key::Char('n'))
+            ])
+        );
+        assert_eq!(unknown, Err(String::from("Unknown key Hyper")));
+        assert_eq!(no_leader, Err(String::from("No leader key set")));
+    }
+
+    /// Harness whose leader is Ctrl-b, bound to tmux like window switches
+    fn harness_with_leader() -> crate::testing::Harness {
+        let mut config = crate::config::Config::default();
+        config.keys.leader = Some(String::from("C-b"));
+        for (sequence, action) in [
+            ("<leader> n", "next-window"),
+            ("<leader> p", "previous-window"),
+        ] {
+            config
+                .keys
+                .bindings
+                .insert(sequence.to_string(), action.to_string());
+        }
+        crate::testing::Harness::with_config(config)
+    }
+
+    #[test]
+    fn test_key_sequence_runs_its_action() {
+        crate::testing::run(async {
+            // Given
+            let mut harness = harness_with_leader();
+            harness.connect().await;
+            harness.input("console", "/msg juliet@example.org").await;
+
+            // When
+            harness.aparte.schedule(Event::Key(Key::Ctrl('b')));
+            harness.settle().await;
+            let pending = harness.screen();
+            harness.aparte.schedule(Event::Key(Key::Char('p')));
+            harness.settle().await;
+
+            // Then
+            assert!(pending.contains("| C-b …"));
+            let ui = harness.aparte.get_mod::<UIMod>();
+            assert_eq!(ui.current_window.as_deref(), Some("console"));
+            assert!(ui.chord.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_invalid_timestamp_format_falls_back_to_the_default() {
+        crate::testing::run(async {
+            // Given
+            let mut config = crate::config::Config::default();
+            config.format.timestamp = String::from("%H:%Q");
+
+            // When
+            let mut harness = crate::testing::Harness::with_config(config);
+            harness.connect().await;
+
+            // Then
+            let screen = harness.screen();
+            assert!(screen.contains("invalid timestamp format %H:%Q"));
+            assert!(screen.contains("Connected as romeo@example.org/aparte"));
+        });
+    }
+
+    #[test]
+    fn test_alt_arrows_switch_windows_unless_bound() {
+        crate::testing::run(async {
+            // Given
+            let mut config = crate::config::Config::default();
+            config
+                .keys
+                .bindings
+                .insert(String::from("M-Right"), String::from("/win console"));
+            let mut harness = crate::testing::Harness::with_config(config);
+            harness.connect().await;
+            harness.input("console", "/msg juliet@example.org").await;
+
+            // When
+            harness
+                .aparte
+                .schedule(Event::ModifiedKey(Modifier::Alt, Key::Left));
+            harness.settle().await;
+            let left = harness.aparte.get_mod::<UIMod>().current_window.clone();
+            harness
+                .aparte
+                .schedule(Event::ModifiedKey(Modifier::Alt, Key::Right));
+            harness.settle().await;
+
+            // Then
+            assert_eq!(left.as_deref(), Some("console"));
+            let ui = harness.aparte.get_mod::<UIMod>();
+            assert_eq!(ui.current_window.as_deref(), Some("console"));
+        });
+    }
+
+    #[test]
+    fn test_unbound_key_ends_the_sequence() {
+        crate::testing::run(async {
+            // Given
+            let mut harness = harness_with_leader();
+            harness.connect().await;
+
+            // When
+            for key in [Key::Ctrl('b'), Key::Char('x')].iter() {
+                harness.aparte.schedule(Event::Key(*key));
+                harness.settle().await;
+            }
+            let mut ui = harness.aparte.get_mod_mut::<UIMod>();
+
+            // Then
+            assert!(ui.chord.is_empty());
+            assert!(ui.input_is_empty());
+        });
+    }
+
+    #[test]
+    fn test_roster_hidden_on_narrow_terminals_is_toggled() {
+        crate::testing::run(async {
+            // Given
+            let mut harness = crate::testing::Harness::new();
+            harness.connect().await;
+            let hidden = harness.screen();
+
+            // When
+            harness.aparte.schedule(Event::Key(Key::Alt('r')));
+            harness.settle().await;
+            let shown = harness.screen();
+            harness.aparte.schedule(Event::Key(Key::Alt('r')));
+            harness.settle().await;
+
+            // Then
+            assert!(!hidden.contains("Bookmarks"));
+            assert!(shown.contains("Bookmarks"));
+            assert!(!harness.screen()[shown.len()..].contains("Bookmarks"));
+        });
+    }
+
+    #[test]
+    fn test_occupants_toggled_in_one_channel_only() {
+        crate::testing::run(async {
+            // Given
+            let mut harness = crate::testing::Harness::new();
+            harness.connect().await;
+            for room in [
+                "verona@conference.example.org",
+                "mantua@conference.example.org",
+            ]
+            .iter()
+            {
+                harness.input("console", &format!("/join {}", room)).await;
+                harness
+                    .receive(&format!(
+                        "<presence xmlns='jabber:client' from='{}/benvolio' to='{{account}}'>
+                            <x xmlns='http://jabber.org/protocol/muc#user'>
+                                <item affiliation='member' role='participant'/>
+                                <status code='110'/>
+                            </x>
+                        </presence>",
+                        room
+                    ))
+                    .await;
+            }
+            let shown = harness.screen();
+
+            // When
+            harness
+                .input("mantua@conference.example.org", "/panel occupants toggle")
+                .await;
+            let toggled = harness.screen();
+            harness
+                .aparte
+                .schedule(Event::Win(String::from("verona@conference.example.org")));
+            harness.settle().await;
+
+            // Then
+            assert!(shown.contains("benvolio"));
+            assert!(!toggled[shown.len()..].contains("benvolio"));
+            assert!(harness.screen()[toggled.len()..].contains("benvolio"));
+            let ui = harness.aparte.get_mod::<UIMod>();
+            let mantua = BareJid::from_str("mantua@conference.example.org").unwrap();
+            assert_eq!(ui.room_occupants.get(&mantua), Some(&false));
+        });
+    }
+
+    #[test]
+    fn test_session_round_trip() {
+        // Given
+        let session = Session {
+            current: Some(String::from("room@conference.example.org")),
+            unread: vec![String::from("friend@example.org")],
+            history: vec![String::from("Hello\nthere")],
+            command_history: vec![String::from("/join room@conference.example.org")],
+            windows: vec![
+                SessionWindow {
+                    account: String::from("me@example.org"),
+                    jid: String::from("friend@example.org"),
+                    nick: None,
+                    scroll: 0,
+                },
+                SessionWindow {
+                    account: String::from("me@example.org"),
+                    jid: String::from("room@conference.example.org"),
+                    nick: Some(String::from("me")),
+                    scroll: 42,
+                },
+            ],
+        };
+
+        // When
+        let saved = toml::to_string(&session).unwrap();
+        let restored: Session = toml::from_str(&saved).unwrap();
+
+        // Then
+        assert_eq!(restored, session);
+    }
+}
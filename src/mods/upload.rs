@@ -0,0 +1,983 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use tokio::process::Command as ProcessCommand;
+use uuid::Uuid;
+use xmpp_parsers::disco::DiscoInfoResult;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::stanza_error::{DefinedCondition, StanzaError};
+use xmpp_parsers::{BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::message::{Message, Oob};
+use crate::mods;
+use crate::sims::{MediaSharing, SIMS};
+use crate::template::Template;
+
+const HTTP_UPLOAD: &str = "urn:xmpp:http:upload:0";
+
+command_def!(upload,
+r#"/upload [<file>]
+
+    file          Path of the file to share, the clipboard image if omitted
+
+Description:
+    Upload a file to the server and share its link in the current conversation.
+    Without argument, the image in the clipboard is shared (requires wl-paste
+    or xclip). Images too large for the server are shrunk if an upload
+    resize_command is configured.
+
+Examples:
+    /upload ~/picture.jpg
+    /upload"#,
+{
+    file: Option<String>
+},
+|aparte, _command| {
+    let account = aparte.current_account().ok_or("No connection found".to_string())?;
+    let jid = BareJid::from_str(&_command.context)
+        .map_err(|_| "Files can only be shared in a conversation window".to_string())?;
+
+    match file {
+        Some(file) => share(aparte, &account, jid, &expand(&file)?, false),
+        None => share_clipboard(aparte, &account, jid),
+    }
+});
+
+/// Path given by the user, `~/` standing for the home directory
+pub fn expand(path: &str) -> Result<PathBuf, String> {
+    match path.strip_prefix("~/") {
+        Some(relative) => Ok(dirs::home_dir()
+            .ok_or("Cannot find home directory".to_string())?
+            .join(relative)),
+        None => Ok(PathBuf::from(path)),
+    }
+}
+
+/// Upload a file and share its link in a conversation once uploaded, a `temporary` file is
+/// removed once uploaded or given up. The file is resized out of the event loop if needed.
+pub fn share(
+    aparte: &mut Aparte,
+    account: &Account,
+    conversation: BareJid,
+    path: &Path,
+    temporary: bool,
+) -> Result<(), String> {
+    let limits = match limits(aparte, account) {
+        Ok(limits) => limits,
+        Err(err) => {
+            if temporary {
+                remove_temporary(path);
+            }
+            return Err(err);
+        }
+    };
+
+    let path = path.to_path_buf();
+    spawn_upload(aparte, account, conversation, limits, async move {
+        Ok((path, temporary))
+    });
+    Ok(())
+}
+
+/// Upload the image in the clipboard, read out of the event loop, and share its link
+fn share_clipboard(
+    aparte: &mut Aparte,
+    account: &Account,
+    conversation: BareJid,
+) -> Result<(), String> {
+    let limits = limits(aparte, account)?;
+    // The clipboard image is written to a file of our own, removed once uploaded
+    spawn_upload(aparte, account, conversation, limits, async {
+        Ok((clipboard_image().await?, true))
+    });
+    Ok(())
+}
+
+/// Get the file to upload along with whether it is temporary, and prepare it in the background
+fn spawn_upload<F>(
+    aparte: &mut Aparte,
+    account: &Account,
+    conversation: BareJid,
+    limits: (Option<u64>, Option<String>),
+    file: F,
+) where
+    F: Future<Output = Result<(PathBuf, bool), String>> + 'static,
+{
+    let account = account.clone();
+    aparte.spawn(async move {
+        let result = match file.await {
+            Ok((path, temporary)) => prepare_file(path, temporary, limits).await,
+            Err(err) => Err(err),
+        };
+        Event::UploadPrepared {
+            account,
+            conversation,
+            result,
+        }
+    });
+}
+
+/// Largest file allowed by the upload service of the account, and the command to resize images
+/// too large for it
+fn limits(aparte: &Aparte, account: &Account) -> Result<(Option<u64>, Option<String>), String> {
+    let max_file_size = {
+        let upload = aparte.get_mod::<UploadMod>();
+        let service = upload
+            .services
+            .get(account)
+            .ok_or("No upload service found on server".to_string())?;
+        service.max_file_size
+    };
+    Ok((max_file_size, aparte.config.upload.resize_command.clone()))
+}
+
+/// File to upload, resized if needed. A `temporary` file is removed once given up or replaced by
+/// its resized copy.
+async fn prepare_file(
+    path: PathBuf,
+    temporary: bool,
+    (max_file_size, resize_command): (Option<u64>, Option<String>),
+) -> Result<Prepared, String> {
+    let result = prepare(&path, max_file_size, resize_command.as_deref()).await;
+    // A resized copy is ours to remove, the file it comes from is not needed anymore
+    let resized = match &result {
+        Ok((prepared, _)) => *prepared != path,
+        Err(_) => true,
+    };
+    if resized && temporary {
+        remove_temporary(&path);
+    }
+
+    result.map(|(prepared, size)| Prepared {
+        temporary: temporary || prepared != path,
+        path: prepared,
+        size,
+    })
+}
+
+/// Share a text through a temporary file, for messages too long to be sent as is
+pub fn share_text(
+    aparte: &mut Aparte,
+    account: &Account,
+    conversation: BareJid,
+    text: &str,
+) -> Result<(), String> {
+    let path = std::env::temp_dir().join(format!(
+        "aparte-paste-{}.txt",
+        Uuid::new_v4().to_hyphenated()
+    ));
+    write_private(&path, text.as_bytes())?;
+    share(aparte, account, conversation, &path, true)
+}
+
+/// Write a new file only the user can read
+fn write_private(path: &Path, content: &[u8]) -> Result<(), String> {
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+    file.write_all(content)
+        .map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+fn remove_temporary(path: &Path) {
+    if let Err(err) = std::fs::remove_file(path) {
+        warn!("Cannot remove {}: {}", path.display(), err);
+    }
+}
+
+/// Human readable size, using the same units as upload services
+pub fn human_size(size: u64) -> String {
+    match size {
+        size if size >= 1_000_000_000 => format!("{} GB", size / 1_000_000_000),
+        size if size >= 1_000_000 => format!("{} MB", size / 1_000_000),
+        size if size >= 1_000 => format!("{} kB", size / 1_000),
+        size => format!("{} B", size),
+    }
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("txt") => "text/plain",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Preferred image type among the clipboard targets
+fn image_type(types: &str) -> Option<&str> {
+    let images = types
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| line.starts_with("image/"))
+        .collect::<Vec<&str>>();
+    images
+        .iter()
+        .find(|image| **image == "image/png")
+        .or_else(|| images.first())
+        .copied()
+}
+
+async fn clipboard(command: &[&str]) -> Result<Vec<u8>, String> {
+    let output = ProcessCommand::new(command[0])
+        .args(&command[1..])
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .output()
+        .await
+        .map_err(|e| format!("Cannot run {}: {}", command[0], e))?;
+    match output.status.success() {
+        true => Ok(output.stdout),
+        false => Err(format!("Cannot read clipboard with {}", command[0])),
+    }
+}
+
+/// Write the image in the clipboard to a temporary file
+async fn clipboard_image() -> Result<PathBuf, String> {
+    let wayland = std::env::var_os("WAYLAND_DISPLAY").is_some();
+    let types = match wayland {
+        true => clipboard(&["wl-paste", "--list-types"]).await?,
+        false => {
+            clipboard(&[
+                "xclip",
+                "-selection",
+                "clipboard",
+                "-target",
+                "TARGETS",
+                "-out",
+            ])
+            .await?
+        }
+    };
+
+    let types = String::from_utf8_lossy(&types);
+    let type_ = image_type(&types).ok_or("No image in clipboard".to_string())?;
+    let image = match wayland {
+        true => clipboard(&["wl-paste", "--type", type_]).await?,
+        false => clipboard(&["xclip", "-selection", "clipboard", "-target", type_, "-out"]).await?,
+    };
+
+    let extension = match type_ {
+        "image/jpeg" => "jpg",
+        other => other.trim_start_matches("image/"),
+    };
+    let path = std::env::temp_dir().join(format!(
+        "aparte-clipboard-{}.{}",
+        Uuid::new_v4().to_hyphenated(),
+        extension
+    ));
+    write_private(&path, &image)?;
+    Ok(path)
+}
+
+/// Check that a file can be uploaded, shrinking it with `resize_command` if it is an image too
+/// large for the service. Returns the path of the file to upload and its size.
+async fn prepare(
+    path: &Path,
+    max_file_size: Option<u64>,
+    resize_command: Option<&str>,
+) -> Result<(PathBuf, u64), String> {
+    let metadata =
+        std::fs::metadata(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a file", path.display()));
+    }
+
+    let size = metadata.len();
+    let max_file_size = match max_file_size {
+        Some(max_file_size) if size > max_file_size => max_file_size,
+        _ => return Ok((path.to_path_buf(), size)),
+    };
+
+    let too_large = format!(
+        "{} is {}, server allows {}",
+        path.display(),
+        human_size(size),
+        human_size(max_file_size)
+    );
+
+    let resize_command = match resize_command {
+        Some(resize_command) if content_type(path).starts_with("image/") => resize_command,
+        _ => return Err(too_large),
+    };
+
+    let resized = resize(path, resize_command).await?;
+    let resized_size = std::fs::metadata(&resized)
+        .map_err(|e| format!("Cannot read {}: {}", resized.display(), e))?
+        .len();
+    match resized_size > max_file_size {
+        true => Err(format!("{}, even once resized", too_large)),
+        false => Ok((resized, resized_size)),
+    }
+}
+
+async fn resize(path: &Path, resize_command: &str) -> Result<PathBuf, String> {
+    let template = Template::from_str(resize_command)
+        .map_err(|e| format!("Invalid upload resize_command: {}", e))?;
+    let output = std::env::temp_dir().join(format!(
+        "aparte-{}-{}",
+        Uuid::new_v4().to_hyphenated(),
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+
+    let quote = |path: &Path| format!("'{}'", path.display().to_string().replace('\'', "'\\''"));
+    let command = template.render(|name| match name {
+        "input" => Some(quote(path)),
+        "output" => Some(quote(&output)),
+        _ => None,
+    });
+
+    let status = ProcessCommand::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .status()
+        .await
+        .map_err(|e| e.to_string())?;
+    match status.success() {
+        true => Ok(output),
+        false => Err(format!("`{}` failed with {}", command, status)),
+    }
+}
+#[derive(Debug, PartialEq)]
+struct Slot {
+    put_url: String,
+    /// Headers to send along the upload
+    headers: Vec<(String, String)>,
+    get_url: String,
+}
+
+fn parse_slot(slot: &Element) -> Result<Slot, String> {
+    let put = slot.get_child("put", HTTP_UPLOAD);
+    let get = slot.get_child("get", HTTP_UPLOAD);
+    let (put_url, get_url) = match (
+        put.and_then(|put| put.attr("url")),
+        get.and_then(|get| get.attr("url")),
+    ) {
+        (Some(put_url), Some(get_url)) => (put_url.to_string(), get_url.to_string()),
+        _ => return Err("Invalid upload slot".to_string()),
+    };
+
+    // Files would otherwise be sent, and their link shared, in clear
+    for url in [&put_url, &get_url].iter() {
+        if !url.starts_with("https://") {
+            return Err(format!("Upload slot {} is not using HTTPS", url));
+        }
+    }
+
+    let headers = put
+        .into_iter()
+        .flat_map(|put| put.children())
+        .filter(|header| header.is("header", HTTP_UPLOAD))
+        .filter_map(|header| Some((header.attr("name")?.to_string(), header.text())))
+        .collect();
+    Ok(Slot {
+        put_url,
+        headers,
+        get_url,
+    })
+}
+
+/// Why a slot was refused, from the upload specific error payloads if any
+fn slot_error(err: &StanzaError) -> String {
+    let other = err.other.as_ref().filter(|other| other.has_ns(HTTP_UPLOAD));
+    let reason = match (&err.defined_condition, other) {
+        (_, Some(other)) if other.is("file-too-large", HTTP_UPLOAD) => {
+            match other
+                .get_child("max-file-size", HTTP_UPLOAD)
+                .and_then(|max| max.text().trim().parse::<u64>().ok())
+            {
+                Some(max) => format!("file too large, server allows {}", human_size(max)),
+                None => "file too large".to_string(),
+            }
+        }
+        (_, Some(other)) if other.is("retry", HTTP_UPLOAD) => match other.attr("stamp") {
+            Some(stamp) => format!("quota reached, retry after {}", stamp),
+            None => "quota reached, retry later".to_string(),
+        },
+        (DefinedCondition::ResourceConstraint, _) => "quota reached".to_string(),
+        (DefinedCondition::NotAllowed, _) | (DefinedCondition::Forbidden, _) => {
+            "not allowed".to_string()
+        }
+        (condition, _) => format!("{:?}", condition),
+    };
+
+    match err.texts.values().next() {
+        Some(text) => format!("{} ({})", reason, text),
+        None => reason,
+    }
+}
+
+/// PUT a file to an upload slot
+async fn put_file(
+    path: PathBuf,
+    url: String,
+    headers: Vec<(String, String)>,
+) -> Result<(), String> {
+    let mut curl = ProcessCommand::new("curl");
+    curl.arg("--fail")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--upload-file")
+        .arg(&path);
+    for (name, value) in headers {
+        curl.arg("--header").arg(format!("{}: {}", name, value));
+    }
+    curl.arg(&url);
+
+    let output = curl
+        .stdin(process::Stdio::null())
+        .output()
+        .await
+        .map_err(|e| format!("Cannot run curl: {}", e))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+    }
+}
+
+struct Service {
+    jid: Jid,
+    max_file_size: Option<u64>,
+}
+
+/// File ready to be uploaded, resized if needed
+#[derive(Debug, Clone)]
+pub struct Prepared {
+    path: PathBuf,
+    size: u64,
+    /// Whether the file was written for the upload alone
+    temporary: bool,
+}
+
+struct Upload {
+    conversation: BareJid,
+    path: PathBuf,
+    /// Whether the file was written for the upload alone, removed once done
+    temporary: bool,
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if self.temporary {
+            remove_temporary(&self.path);
+        }
+    }
+}
+
+enum Query {
+    Slot(Upload),
+}
+
+pub struct UploadMod {
+    /// HTTP upload service of each account
+    services: HashMap<Account, Service>,
+    /// Pending queries by iq id
+    queries: HashMap<String, Query>,
+}
+
+impl UploadMod {
+    pub fn new() -> Self {
+        Self {
+            services: HashMap::new(),
+            queries: HashMap::new(),
+        }
+    }
+
+    /// Whether an upload service was found on the server of an account
+    pub fn available(&self, account: &Account) -> bool {
+        self.services.contains_key(account)
+    }
+
+    fn request_slot(
+        &mut self,
+        account: &Account,
+        upload: Upload,
+        size: u64,
+    ) -> Result<Element, String> {
+        let path = &upload.path;
+        let service = self
+            .services
+            .get(account)
+            .ok_or("No upload service found on server".to_string())?;
+        let filename = path
+            .file_name()
+            .ok_or(format!("Invalid file name {}", path.display()))?
+            .to_string_lossy()
+            .to_string();
+
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let request = Element::builder("request", HTTP_UPLOAD)
+            .attr("filename", filename)
+            .attr("size", size.to_string())
+            .attr("content-type", content_type(path))
+            .build();
+        let iq = Iq {
+            from: None,
+            to: Some(service.jid.clone()),
+            id: id.clone(),
+            payload: IqType::Get(request),
+        };
+        self.queries.insert(id, Query::Slot(upload));
+        Ok(iq.into())
+    }
+
+    fn upload(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        conversation: &BareJid,
+        prepared: &Prepared,
+    ) {
+        let upload = Upload {
+            conversation: conversation.clone(),
+            path: prepared.path.clone(),
+            temporary: prepared.temporary,
+        };
+        match self.request_slot(account, upload, prepared.size) {
+            Ok(request) => aparte.send(account, request),
+            Err(err) => aparte.log(err),
+        }
+    }
+
+    fn handle_service(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        jid: &Jid,
+        info: &DiscoInfoResult,
+    ) {
+        if !info
+            .features
+            .iter()
+            .any(|feature| feature.var == HTTP_UPLOAD)
+        {
+            return;
+        }
+
+        let max_file_size = info
+            .extensions
+            .iter()
+            .filter(|form| form.form_type.as_deref() == Some(HTTP_UPLOAD))
+            .flat_map(|form| form.fields.iter())
+            .filter(|field| field.var == "max-file-size")
+            .find_map(|field| field.values.first()?.parse::<u64>().ok());
+
+        aparte.log(match max_file_size {
+            Some(max_file_size) => format!(
+                "Files up to {} can be shared through {}",
+                human_size(max_file_size),
+                jid
+            ),
+            None => format!("Files can be shared through {}", jid),
+        });
+        let jid = jid.clone();
+        self.services
+            .insert(account.clone(), Service { jid, max_file_size });
+    }
+
+    fn handle_slot(&self, aparte: &mut Aparte, account: &Account, upload: Upload, slot: Element) {
+        let Slot {
+            put_url,
+            headers,
+            get_url,
+        } = match parse_slot(&slot) {
+            Ok(slot) => slot,
+            Err(err) => {
+                aparte.log(format!("Cannot upload {}: {}", upload.path.display(), err));
+                return;
+            }
+        };
+
+        let conversation = {
+            let conversations = aparte.get_mod::<mods::conversation::ConversationMod>();
+            conversations.get(account, &upload.conversation).cloned()
+        };
+        aparte.log(format!("Uploading {}", upload.path.display()));
+        let account = account.clone();
+        aparte.spawn(async move {
+            let media = MediaSharing::from_file(
+                upload.path.clone(),
+                content_type(&upload.path).to_string(),
+                get_url.clone(),
+            );
+            let (media, uploaded) =
+                tokio::join!(media, put_file(upload.path.clone(), put_url, headers));
+            if let Err(err) = uploaded {
+                return Event::Message(
+                    None,
+                    Message::log(format!("Cannot upload {}: {}", upload.path.display(), err)),
+                );
+            }
+
+            // Metadata are optional, the link alone is shared if the file cannot be read anymore
+            let media = media.map_err(|err| warn!("{}", err)).ok();
+            let oob = Oob {
+                url: get_url.clone(),
+                desc: media.as_ref().map(MediaSharing::summary),
+            };
+            let message = Message::outgoing(&account, &upload.conversation, conversation, get_url)
+                .with_oob(Some(oob))
+                .with_media(media.into_iter().collect());
+            Event::SendMessage(account, message)
+        });
+    }
+}
+
+impl ModTrait for UploadMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(upload::new());
+        let mut disco = aparte.get_mod_mut::<mods::disco::DiscoMod>();
+        disco.add_feature(SIMS)
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Connected(account, _) => {
+                self.services.remove(account);
+            }
+            Event::Service(account, jid, info) => self.handle_service(aparte, account, jid, info),
+            Event::UploadPrepared {
+                account,
+                conversation,
+                result,
+            } => match result {
+                Ok(prepared) => self.upload(aparte, account, conversation, prepared),
+                Err(err) => aparte.log(err.clone()),
+            },
+            Event::Iq(account, iq) => {
+                let query = match self.queries.remove(&iq.id) {
+                    Some(query) => query,
+                    None => return,
+                };
+
+                match (query, iq.payload.clone()) {
+                    (Query::Slot(upload), IqType::Result(Some(slot))) => {
+                        self.handle_slot(aparte, account, upload, slot)
+                    }
+                    (Query::Slot(upload), IqType::Error(err)) => aparte.log(format!(
+                        "Upload of {} refused by the server: {}",
+                        upload.path.display(),
+                        slot_error(&err)
+                    )),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for UploadMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0363: HTTP File Upload")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(24_000_000), "24 MB");
+        assert_eq!(human_size(10_485_760), "10 MB");
+    }
+
+    #[test]
+    fn test_clipboard_image_type() {
+        assert_eq!(
+            image_type("TARGETS\nimage/jpeg\nimage/png\ntext/plain"),
+            Some("image/png")
+        );
+        assert_eq!(image_type("text/html\nimage/gif\n"), Some("image/gif"));
+        assert_eq!(image_type("UTF8_STRING\ntext/plain"), None);
+    }
+
+    #[test]
+    fn test_prepare_rejects_too_large_files() {
+        crate::testing::run(async {
+            // Given
+            let path = std::env::temp_dir().join(format!("aparte-test-{}.bin", Uuid::new_v4()));
+            std::fs::write(&path, vec![0u8; 2_000]).unwrap();
+
+            // When
+            let accepted = prepare(&path, Some(10_000), None).await;
+            let refused = prepare(&path, Some(1_000), None).await;
+            std::fs::remove_file(&path).unwrap();
+
+            // Then
+            assert_eq!(accepted, Ok((path.clone(), 2_000)));
+            assert_eq!(
+                refused,
+                Err(format!("{} is 2 kB, server allows 1 kB", path.display()))
+            );
+        });
+    }
+
+    #[test]
+    fn test_large_images_are_resized() {
+        crate::testing::run(async {
+            // Given
+            let path = std::env::temp_dir().join(format!("aparte-test-{}.png", Uuid::new_v4()));
+            std::fs::write(&path, vec![0u8; 2_000]).unwrap();
+
+            // When
+            let resized = prepare(&path, Some(1_000), Some("head -c 500 {input} > {output}")).await;
+            let failed = prepare(&path, Some(1_000), Some("false")).await;
+            std::fs::remove_file(&path).unwrap();
+
+            // Then
+            let (resized, size) = resized.unwrap();
+            assert_ne!(resized, path);
+            assert_eq!(size, 500);
+            std::fs::remove_file(&resized).unwrap();
+            assert_eq!(
+                failed,
+                Err(String::from("`false` failed with exit status: 1"))
+            );
+        });
+    }
+
+    #[test]
+    fn test_slot_urls_must_use_https() {
+        // Given
+        let secure = Element::from_str(
+            "<slot xmlns='urn:xmpp:http:upload:0'>
+                <put url='https://upload.example.org/a.jpg'>
+                    <header name='Authorization'>Basic Base64String==</header>
+                </put>
+                <get url='https://download.example.org/a.jpg'/>
+            </slot>",
+        )
+        .unwrap();
+        let insecure = Element::from_str(
+            "<slot xmlns='urn:xmpp:http:upload:0'>
+                <put url='http://upload.example.org/a.jpg'/>
+                <get url='https://download.example.org/a.jpg'/>
+            </slot>",
+        )
+        .unwrap();
+
+        // When
+        let secure = parse_slot(&secure);
+        let insecure = parse_slot(&insecure);
+
+        // Then
+        assert_eq!(
+            secure,
+            Ok(Slot {
+                put_url: "https://upload.example.org/a.jpg".to_string(),
+                headers: vec![(
+                    "Authorization".to_string(),
+                    "Basic Base64String==".to_string()
+                )],
+                get_url: "https://download.example.org/a.jpg".to_string(),
+            })
+        );
+        assert_eq!(
+            insecure,
+            Err("Upload slot http://upload.example.org/a.jpg is not using HTTPS".to_string())
+        );
+    }
+
+    #[test]
+    fn test_slot_errors_explain_quota_and_size() {
+        // Given
+        let too_large = StanzaError::try_from(
+            Element::from_str(
+                "<error xmlns='jabber:client' type='modify'>
+                    <not-acceptable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+                    <file-too-large xmlns='urn:xmpp:http:upload:0'>
+                        <max-file-size>20000</max-file-size>
+                    </file-too-large>
+                </error>",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let quota = StanzaError::try_from(
+            Element::from_str(
+                "<error xmlns='jabber:client' type='wait'>
+                    <resource-constraint xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+                    <retry xmlns='urn:xmpp:http:upload:0' stamp='2017-12-03T23:42:01Z'/>
+                </error>",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        // When
+        let too_large = slot_error(&too_large);
+        let quota = slot_error(&quota);
+
+        // Then
+        assert_eq!(too_large, "file too large, server allows 20 kB");
+        assert_eq!(quota, "quota reached, retry after 2017-12-03T23:42:01Z");
+    }
+
+    /// Script the discovery of upload.example.org, allowing files up to 10 MB
+    fn reply_upload_service(harness: &mut crate::testing::Harness) {
+        harness
+            .reply(
+                "query",
+                "http://jabber.org/protocol/disco#items",
+                "<iq xmlns='jabber:client' type='result' id='{id}' from='example.org'>
+                    <query xmlns='http://jabber.org/protocol/disco#items'>
+                        <item jid='upload.example.org'/>
+                    </query>
+                </iq>",
+            )
+            .reply(
+                "query",
+                "http://jabber.org/protocol/disco#info",
+                "<iq xmlns='jabber:client' type='result' id='{id}' from='upload.example.org'>
+                    <query xmlns='http://jabber.org/protocol/disco#info'>
+                        <identity category='store' type='file'/>
+                        <feature var='http://jabber.org/protocol/disco#info'/>
+                        <feature var='urn:xmpp:http:upload:0'/>
+                        <x type='result' xmlns='jabber:x:data'>
+                            <field var='FORM_TYPE' type='hidden'>
+                                <value>urn:xmpp:http:upload:0</value>
+                            </field>
+                            <field var='max-file-size'><value>10000000</value></field>
+                        </x>
+                    </query>
+                </iq>",
+            );
+    }
+
+    #[test]
+    fn test_upload_service_is_discovered_on_connection() {
+        crate::testing::run(async {
+            // Given
+            let mut harness = crate::testing::Harness::new();
+            reply_upload_service(&mut harness);
+
+            // When
+            harness.connect().await;
+
+            // Then
+            let upload = harness.aparte.get_mod::<UploadMod>();
+            let service = &upload.services[&harness.account];
+            assert_eq!(service.jid, Jid::from_str("upload.example.org").unwrap());
+            assert_eq!(service.max_file_size, Some(10_000_000));
+        });
+    }
+
+    #[test]
+    fn test_long_messages_can_be_uploaded() {
+        crate::testing::run(async {
+            // Given
+            let mut config = crate::config::Config::default();
+            config.paste.max_lines = 0;
+            config.paste.upload = true;
+            let mut harness = crate::testing::Harness::with_config(config);
+            reply_upload_service(&mut harness);
+            harness.connect().await;
+            harness.input("console", "/msg juliet@example.org").await;
+
+            // When
+            for c in "hello\n\n".chars() {
+                harness
+                    .aparte
+                    .schedule(Event::Key(termion::event::Key::Char(c)));
+            }
+            harness.settle().await;
+
+            // Then
+            let requests = harness.take_sent("request", HTTP_UPLOAD);
+            assert_eq!(requests.len(), 1);
+            let request = requests[0].get_child("request", HTTP_UPLOAD).unwrap();
+            assert_eq!(request.attr("size"), Some("5"));
+            assert_eq!(request.attr("content-type"), Some("text/plain"));
+            assert!(harness.take_sent("message", "jabber:client").is_empty());
+        });
+    }
+
+    #[test]
+    fn test_pasted_message_file_is_private_and_removed_once_given_up() {
+        crate::testing::run(async {
+            // Given
+            let mut config = crate::config::Config::default();
+            config.paste.max_lines = 0;
+            config.paste.upload = true;
+            let mut harness = crate::testing::Harness::with_config(config);
+            reply_upload_service(&mut harness);
+            harness.connect().await;
+            harness.input("console", "/msg juliet@example.org").await;
+            for c in "hello\n\n".chars() {
+                harness
+                    .aparte
+                    .schedule(Event::Key(termion::event::Key::Char(c)));
+            }
+            harness.settle().await;
+            let requests = harness.take_sent("request", HTTP_UPLOAD);
+            let request = requests[0].get_child("request", HTTP_UPLOAD).unwrap();
+            let path = std::env::temp_dir().join(request.attr("filename").unwrap());
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+
+            // When
+            let refusal = Element::from_str(&format!(
+                "<iq xmlns='jabber:client' type='error' id='{}' from='upload.example.org'>
+                    <error type='modify'>
+                        <not-acceptable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+                    </error>
+                </iq>",
+                requests[0].attr("id").unwrap()
+            ))
+            .unwrap();
+            harness
+                .aparte
+                .schedule(Event::Stanza(harness.account.clone(), refusal));
+            harness.settle().await;
+
+            // Then
+            assert_eq!(mode & 0o777, 0o600);
+            assert!(!path.exists());
+        });
+    }
+
+    #[test]
+    fn test_private_files_are_only_readable_by_the_user() {
+        // Given
+        let path = std::env::temp_dir().join(format!("aparte-private-{}", Uuid::new_v4()));
+
+        // When
+        let written = write_private(&path, b"secret");
+        let overwritten = write_private(&path, b"other");
+
+        // Then
+        assert_eq!(written, Ok(()));
+        assert!(overwritten.is_err());
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(std::fs::read(&path).unwrap(), b"secret".to_vec());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,657 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Profiles of contacts, published as vCard4 on their PEP service (XEP-0292).
+//!
+//! vCards are fetched by /whois and pushed by the PEP service of contacts, and kept in a cache so
+//! that their birthdays and anniversaries are reminded on the day, even when they aren't online.
+use chrono::{Datelike, Duration, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::delay::Delay;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::message::Message as XmppParsersMessage;
+use xmpp_parsers::pubsub::{pubsub, NodeName, PubSub, PubSubEvent};
+use xmpp_parsers::stanza_error::DefinedCondition;
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::core::{Aparte, Event, ModTrait};
+use crate::mods;
+
+const VCARD4: &str = "urn:xmpp:vcard4";
+const VCARD4_NOTIFY: &str = "urn:xmpp:vcard4+notify";
+const VCARD4_NS: &str = "urn:ietf:params:xml:ns:vcard-4.0";
+
+command_def!(
+    whois,
+    r#"/whois <jid>
+
+    jid    Contact whose profile is shown
+
+Description:
+    Show the vCard of a contact (XEP-0292): name, nickname, email, birthday,
+    anniversary and avatar.
+
+Example:
+    /whois juliet@example.org"#,
+{
+    jid: BareJid = {
+        jid: bare
+    },
+},
+|aparte, _command| {
+    let account = _command
+        .account
+        .clone()
+        .ok_or("No connection found".to_string())?;
+    let request = {
+        let mut vcard = aparte.get_mod_mut::<VCardMod>();
+        vcard.request(&jid)
+    };
+    aparte.send(&account, request);
+    Ok(())
+});
+
+command_def!(
+    birthdays,
+    r#"/birthdays
+
+Description:
+    List the upcoming birthdays and anniversaries of the contacts whose vCard
+    is known, soonest first. They are also reminded in the console on the day
+    (see birthdays in the configuration to ring too)."#,
+    {},
+    |aparte, _command| {
+        let lines = {
+            let vcard = aparte.get_mod::<VCardMod>();
+            vcard.upcoming(Local::now().date_naive())
+        };
+        match lines.is_empty() {
+            true => aparte.log("No birthday nor anniversary known".to_string()),
+            false => aparte.log(format!(
+                "Upcoming birthdays and anniversaries:\n{}",
+                lines.join("\n")
+            )),
+        }
+        Ok(())
+    }
+);
+
+/// Date of a vCard, whose year may be unknown
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Date {
+    pub year: Option<i32>,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    /// Dates like 1996-04-15, 19960415, --04-15 or --0415, the time following them if any being
+    /// ignored
+    pub fn parse(date: &str) -> Option<Self> {
+        let date = date.trim().split('T').next()?;
+        let (digits, with_year) = match date.strip_prefix("--") {
+            Some(date) => (date.replace('-', ""), false),
+            None => (date.replace('-', ""), true),
+        };
+        if !digits.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let date = match (digits.len(), with_year) {
+            (8, true) => Self {
+                year: Some(digits[..4].parse().ok()?),
+                month: digits[4..6].parse().ok()?,
+                day: digits[6..].parse().ok()?,
+            },
+            (4, false) => Self {
+                year: None,
+                month: digits[..2].parse().ok()?,
+                day: digits[2..].parse().ok()?,
+            },
+            _ => return None,
+        };
+        // 2000 being a leap year, the 29th of February is valid
+        NaiveDate::from_ymd_opt(date.year.unwrap_or(2000), date.month, date.day)?;
+        Some(date)
+    }
+
+    /// Next time the date comes back, from the given day included. The 29th of February comes
+    /// back on the 1st of March of other years.
+    pub fn next(&self, today: NaiveDate) -> NaiveDate {
+        let on = |year| {
+            NaiveDate::from_ymd_opt(year, self.month, self.day)
+                .or_else(|| NaiveDate::from_ymd_opt(year, 3, 1))
+                .unwrap()
+        };
+        match on(today.year()) {
+            date if date >= today => date,
+            _ => on(today.year() + 1),
+        }
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.year {
+            Some(year) => write!(f, "{:04}-{:02}-{:02}", year, self.month, self.day),
+            None => write!(f, "--{:02}-{:02}", self.month, self.day),
+        }
+    }
+}
+
+/// Fields of a vCard shown by /whois
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VCard {
+    pub name: Option<String>,
+    pub nickname: Option<String>,
+    pub email: Option<String>,
+    pub birthday: Option<Date>,
+    pub anniversary: Option<Date>,
+}
+
+impl VCard {
+    /// First text value of each property, as a property may hold several
+    pub fn parse(vcard: &Element) -> Self {
+        let text = |property: &str| {
+            vcard
+                .get_child(property, VCARD4_NS)?
+                .children()
+                .find(|value| value.is("text", VCARD4_NS))
+                .map(|value| value.text().trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let date = |property: &str| {
+            vcard
+                .get_child(property, VCARD4_NS)?
+                .children()
+                .filter(|value| value.ns() == VCARD4_NS)
+                .find_map(|value| match value.name() {
+                    "date" | "date-and-or-time" | "date-time" | "text" => {
+                        Date::parse(&value.text())
+                    }
+                    _ => None,
+                })
+        };
+        Self {
+            name: text("fn"),
+            nickname: text("nickname"),
+            email: text("email"),
+            birthday: date("bday"),
+            anniversary: date("anniversary"),
+        }
+    }
+
+    /// Birthday and anniversary, for the ones known
+    fn occasions(&self) -> Vec<(&'static str, Date)> {
+        [
+            ("birthday", self.birthday),
+            ("anniversary", self.anniversary),
+        ]
+        .iter()
+        .filter_map(|(occasion, date)| Some((*occasion, (*date)?)))
+        .collect()
+    }
+}
+
+/// An occasion of a contact, with the years it marks when the year is known
+fn describe(contact: &str, vcard: &VCard, occasion: &str, date: &Date, on: NaiveDate) -> String {
+    let who = match vcard.name.as_ref().or(vcard.nickname.as_ref()) {
+        Some(name) => format!("{} ({})", name, contact),
+        None => contact.to_string(),
+    };
+    match date.year {
+        Some(year) if year < on.year() => {
+            format!("{} of {}, {} years", occasion, who, on.year() - year)
+        }
+        _ => format!("{} of {}", occasion, who),
+    }
+}
+
+pub struct VCardMod {
+    /// Pending requests, by IQ id, with the contact they are for
+    requests: HashMap<String, BareJid>,
+    /// Last vCard known of each contact, by bare jid
+    vcards: HashMap<String, VCard>,
+    cache: Option<PathBuf>,
+    /// Day of the last reminders, with the contacts and occasions already reminded that day
+    reminded: Option<(NaiveDate, HashSet<(String, &'static str)>)>,
+    /// A wake up at midnight is pending
+    waiting: bool,
+}
+
+impl VCardMod {
+    pub fn new() -> Self {
+        Self {
+            requests: HashMap::new(),
+            vcards: HashMap::new(),
+            cache: dirs::cache_dir().map(|dir| dir.join("aparte").join("vcards.toml")),
+            reminded: None,
+            waiting: false,
+        }
+    }
+
+    fn load(&mut self) -> Result<(), String> {
+        let path = match &self.cache {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let cache = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        self.vcards = toml::from_str(&cache).map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.cache {
+            let result = toml::to_string(&self.vcards)
+                .map_err(|err| err.to_string())
+                .and_then(|cache| {
+                    let dir = path.parent().unwrap();
+                    fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+                    fs::write(path, cache).map_err(|err| err.to_string())
+                });
+            if let Err(err) = result {
+                warn!("Cannot save vCards cache: {}", err);
+            }
+        }
+    }
+
+    /// Keep the last vCard of a contact, and remind of its occasions falling today
+    fn update(&mut self, aparte: &mut Aparte, contact: &BareJid, vcard: VCard) {
+        if self.vcards.get(&contact.to_string()) != Some(&vcard) {
+            self.vcards.insert(contact.to_string(), vcard);
+            self.save();
+        }
+        self.remind(aparte, Local::now().date_naive());
+    }
+
+    /// Log the occasions of the day not reminded yet
+    fn remind(&mut self, aparte: &mut Aparte, today: NaiveDate) {
+        if self.reminded.as_ref().map(|(day, _)| *day) != Some(today) {
+            self.reminded = Some((today, HashSet::new()));
+        }
+        let reminded = &mut self.reminded.as_mut().unwrap().1;
+        let mut contacts: Vec<&String> = self.vcards.keys().collect();
+        contacts.sort();
+        let mut reminders = Vec::new();
+        for contact in contacts {
+            let vcard = &self.vcards[contact];
+            for (occasion, date) in vcard.occasions() {
+                if date.next(today) == today && reminded.insert((contact.clone(), occasion)) {
+                    reminders.push(describe(contact, vcard, occasion, &date, today));
+                }
+            }
+        }
+        if reminders.is_empty() {
+            return;
+        }
+        for reminder in reminders {
+            aparte.log(format!("Today: {}", reminder));
+        }
+        if aparte.config.birthdays.notify {
+            aparte.schedule(Event::Notification(String::from("")));
+        }
+    }
+
+    /// Wake up at the next midnight to remind of the occasions of the new day
+    fn wait_midnight(&mut self, aparte: &mut Aparte) {
+        if self.waiting {
+            return;
+        }
+        self.waiting = true;
+        let now = Local::now();
+        let midnight = (now.date_naive() + Duration::days(1))
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let delay = midnight - now.naive_local();
+        aparte.spawn(async move {
+            tokio::time::sleep(delay.to_std().unwrap_or_default()).await;
+            Event::DayChanged
+        });
+    }
+
+    /// Occasions of every known vCard, soonest first
+    fn upcoming(&self, today: NaiveDate) -> Vec<String> {
+        let mut occasions: Vec<(NaiveDate, String)> = self
+            .vcards
+            .iter()
+            .flat_map(|(contact, vcard)| {
+                vcard.occasions().into_iter().map(move |(occasion, date)| {
+                    let on = date.next(today);
+                    (on, describe(contact, vcard, occasion, &date, on))
+                })
+            })
+            .collect();
+        occasions.sort();
+        occasions
+            .into_iter()
+            .map(|(on, occasion)| {
+                let when = match (on - today).num_days() {
+                    0 => String::from("today"),
+                    1 => String::from("tomorrow"),
+                    days => format!("in {} days", days),
+                };
+                format!("  {} ({}): {}", on.format("%Y-%m-%d"), when, occasion)
+            })
+            .collect()
+    }
+
+    /// vCard published in a PEP notification
+    fn get_vcard(message: &XmppParsersMessage) -> Option<VCard> {
+        message
+            .payloads
+            .iter()
+            .filter(|payload| payload.is("event", ns::PUBSUB_EVENT))
+            .find_map(|payload| match PubSubEvent::try_from(payload.clone()) {
+                Ok(PubSubEvent::PublishedItems { node, items }) if node.0 == VCARD4 => items
+                    .into_iter()
+                    .filter_map(|item| item.0.payload)
+                    .find(|payload| payload.is("vcard", VCARD4_NS))
+                    .map(|payload| VCard::parse(&payload)),
+                _ => None,
+            })
+    }
+
+    fn request(&mut self, contact: &BareJid) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let iq = Iq {
+            from: None,
+            to: Some(Jid::Bare(contact.clone())),
+            id: id.clone(),
+            payload: IqType::Get(
+                PubSub::Items(pubsub::Items {
+                    max_items: Some(1),
+                    node: NodeName(String::from(VCARD4)),
+                    subid: None,
+                    items: Vec::new(),
+                })
+                .into(),
+            ),
+        };
+        self.requests.insert(id, contact.clone());
+        iq.into()
+    }
+
+    fn render(contact: &BareJid, vcard: &VCard, avatar: Option<String>) -> String {
+        let fields = [
+            ("Name", &vcard.name),
+            ("Nickname", &vcard.nickname),
+            ("Email", &vcard.email),
+            ("Birthday", &vcard.birthday.map(|date| date.to_string())),
+            (
+                "Anniversary",
+                &vcard.anniversary.map(|date| date.to_string()),
+            ),
+            ("Avatar", &avatar),
+        ];
+        let mut lines = vec![format!("vCard of {}:", contact)];
+        lines.extend(
+            fields
+                .iter()
+                .filter_map(|(label, value)| Some(format!("  {}: {}", label, value.as_ref()?))),
+        );
+        lines.join("\n")
+    }
+
+    fn handle_result(&mut self, aparte: &mut Aparte, contact: &BareJid, payload: &Element) {
+        let vcard = match PubSub::try_from(payload.clone()) {
+            Ok(PubSub::Items(items)) => items
+                .items
+                .into_iter()
+                .filter_map(|item| item.0.payload)
+                .find(|payload| payload.is("vcard", VCARD4_NS))
+                .map(|payload| VCard::parse(&payload)),
+            _ => None,
+        };
+        let avatar = {
+            let avatars = aparte.get_mod::<mods::avatar::AvatarMod>();
+            avatars.get(contact).map(|avatar| avatar.hash)
+        };
+        match vcard {
+            Some(vcard) => {
+                aparte.log(Self::render(contact, &vcard, avatar));
+                self.update(aparte, contact, vcard);
+            }
+            None => aparte.log(format!("{} has no vCard", contact)),
+        }
+    }
+}
+
+impl ModTrait for VCardMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(whois::new());
+        aparte.add_command(birthdays::new());
+        if let Err(err) = self.load() {
+            warn!("Cannot load vCards cache: {}", err);
+        }
+        let mut disco = aparte.get_mod_mut::<mods::disco::DiscoMod>();
+        disco.add_feature(VCARD4_NOTIFY)
+    }
+
+    fn can_handle_xmpp_message(
+        &mut self,
+        _aparte: &mut Aparte,
+        _account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+    ) -> f64 {
+        match Self::get_vcard(message) {
+            Some(_) => 1f64,
+            None => 0f64,
+        }
+    }
+
+    fn handle_xmpp_message(
+        &mut self,
+        aparte: &mut Aparte,
+        account: &Account,
+        message: &XmppParsersMessage,
+        _delay: &Option<Delay>,
+    ) {
+        let contact = match &message.from {
+            Some(from) => BareJid::from(from.clone()),
+            None => BareJid::from(Jid::Full(account.clone())),
+        };
+        if let Some(vcard) = Self::get_vcard(message) {
+            self.update(aparte, &contact, vcard);
+        }
+    }
+
+    fn on_event(&mut self, aparte: &mut Aparte, event: &Event) {
+        match event {
+            Event::Connected(_, _) => {
+                self.remind(aparte, Local::now().date_naive());
+                self.wait_midnight(aparte);
+            }
+            Event::DayChanged => {
+                self.waiting = false;
+                self.remind(aparte, Local::now().date_naive());
+                self.wait_midnight(aparte);
+            }
+            Event::Iq(_, iq) if self.requests.contains_key(&iq.id) => {
+                let contact = self.requests.remove(&iq.id).unwrap();
+                match &iq.payload {
+                    IqType::Result(Some(payload)) => self.handle_result(aparte, &contact, payload),
+                    IqType::Result(None) => aparte.log(format!("{} has no vCard", contact)),
+                    IqType::Error(err)
+                        if err.defined_condition == DefinedCondition::ItemNotFound =>
+                    {
+                        aparte.log(format!("{} has no vCard", contact))
+                    }
+                    IqType::Error(err) => aparte.log(format!(
+                        "Cannot fetch vCard of {}: {:?}",
+                        contact, err.defined_condition
+                    )),
+                    _ => warn!("Unexpected response for vCard of {}", contact),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for VCardMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0292: vCard4 Over XMPP")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Harness};
+    use xmpp_parsers::ns;
+
+    #[test]
+    fn test_whois_shows_the_vcard_of_the_contact() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            harness.reply(
+                "pubsub",
+                ns::PUBSUB,
+                "<iq xmlns='jabber:client' type='result' id='{id}' from='juliet@example.org'>
+                    <pubsub xmlns='http://jabber.org/protocol/pubsub'>
+                        <items node='urn:xmpp:vcard4'>
+                            <item id='current'>
+                                <vcard xmlns='urn:ietf:params:xml:ns:vcard-4.0'>
+                                    <fn><text>Juliet Capulet</text></fn>
+                                    <nickname><text>Jule</text></nickname>
+                                    <email><text>juliet@example.org</text></email>
+                                </vcard>
+                            </item>
+                        </items>
+                    </pubsub>
+                </iq>",
+            );
+            harness.reply(
+                "pubsub",
+                ns::PUBSUB,
+                "<iq xmlns='jabber:client' type='error' id='{id}' from='tybalt@example.org'>
+                    <error type='cancel'>
+                        <item-not-found xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>
+                    </error>
+                </iq>",
+            );
+
+            // When
+            harness.input("console", "/whois juliet@example.org").await;
+            harness.input("console", "/whois tybalt@example.org").await;
+
+            // Then
+            let screen = harness.screen();
+            assert!(screen.contains("Name: Juliet Capulet"));
+            assert!(screen.contains("Nickname: Jule"));
+            assert!(screen.contains("Email: juliet@example.org"));
+            assert!(screen.contains("tybalt@example.org has no vCard"));
+        });
+    }
+
+    #[test]
+    fn test_parse_vcard_keeps_first_text_values() {
+        // Given
+        let vcard = Element::from_str(
+            "<vcard xmlns='urn:ietf:params:xml:ns:vcard-4.0'>
+                <fn><text>Juliet Capulet</text></fn>
+                <email><text>juliet@example.org</text><text>jule@example.net</text></email>
+                <nickname><text> </text></nickname>
+            </vcard>",
+        )
+        .unwrap();
+
+        // When
+        let vcard = VCard::parse(&vcard);
+
+        // Then
+        assert_eq!(
+            vcard,
+            VCard {
+                name: Some(String::from("Juliet Capulet")),
+                nickname: None,
+                email: Some(String::from("juliet@example.org")),
+                birthday: None,
+                anniversary: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_and_next_date() {
+        // Given
+        let today = NaiveDate::from_ymd_opt(2023, 6, 1).unwrap();
+
+        // When
+        let dates = [
+            "1996-04-15",
+            "19960415",
+            "--06-01",
+            "--0229",
+            "1996-04-15T10:00:00Z",
+        ]
+        .iter()
+        .map(|date| Date::parse(date))
+        .collect::<Vec<_>>();
+
+        // Then
+        assert_eq!(dates[0], dates[1]);
+        assert_eq!(dates[0], dates[4]);
+        assert_eq!(
+            dates[0].map(|date| date.next(today)),
+            NaiveDate::from_ymd_opt(2024, 4, 15)
+        );
+        assert_eq!(dates[2].map(|date| date.next(today)), Some(today));
+        assert_eq!(
+            dates[3].map(|date| date.next(today)),
+            NaiveDate::from_ymd_opt(2024, 2, 29)
+        );
+        assert_eq!(Date::parse("1996-02-30"), None);
+        assert_eq!(Date::parse("April 15th"), None);
+    }
+
+    #[test]
+    fn test_birthdays_pushed_are_reminded_on_the_day() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+            let today = Local::now().date_naive();
+
+            // When
+            harness
+                .receive(&format!(
+                    "<message xmlns='jabber:client' type='headline'
+                        from='juliet@example.org' to='{{account}}'>
+                        <event xmlns='http://jabber.org/protocol/pubsub#event'>
+                            <items node='urn:xmpp:vcard4'>
+                                <item id='current'>
+                                    <vcard xmlns='urn:ietf:params:xml:ns:vcard-4.0'>
+                                        <fn><text>Juliet</text></fn>
+                                        <bday><date>1996{}</date></bday>
+                                    </vcard>
+                                </item>
+                            </items>
+                        </event>
+                    </message>",
+                    today.format("%m%d")
+                ))
+                .await;
+            harness.input("console", "/birthdays").await;
+
+            // Then
+            let screen = harness.screen();
+            assert!(screen.contains(&format!(
+                "Today: birthday of Juliet (juliet@example.org), {} years",
+                today.year() - 1996
+            )));
+            assert!(screen.contains("(today): birthday of Juliet"));
+        });
+    }
+}
@@ -0,0 +1,281 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Workspaces grouping related channels and chats, like the ones of a recurring meeting.
+//!
+//! Opening a workspace joins its channels and opens its chats with a single command, the window
+//! bar then only listing the activity of its conversations until it is closed.
+use std::fmt;
+use std::str::FromStr;
+use xmpp_parsers::{BareJid, Jid};
+
+use crate::account::Account;
+use crate::command::{Command, CommandParser};
+use crate::config::{Config, WorkspaceConfig};
+use crate::core::{Aparte, Event, ModTrait};
+
+command_def!(workspace,
+r#"/workspace list|open|close"#,
+{
+    action: Command = {
+        children: {
+            "list": workspace_list,
+            "open": workspace_open,
+            "close": workspace_close,
+        }
+    },
+});
+
+command_def!(
+    workspace_list,
+    r#"/workspace list
+
+Description:
+    List the workspaces of the configuration, the one opened being marked with
+    a star."#,
+    {},
+    |aparte, _command| {
+        let lines = {
+            let workspace = aparte.get_mod::<WorkspaceMod>();
+            workspace.list(&aparte.config)
+        };
+        match lines.is_empty() {
+            true => aparte.log("No workspace configured".to_string()),
+            false => aparte.log(format!("Workspaces:\n{}", lines.join("\n"))),
+        }
+        Ok(())
+    }
+);
+
+command_def!(
+    workspace_open,
+    r#"/workspace open <name>
+
+    name          Workspace to open
+
+Description:
+    Join the channels and open the chats of a workspace, the window bar only
+    listing their activity until the workspace is closed.
+
+Example:
+    /workspace open standup"#,
+    {
+        name: String = {
+            completion: (|aparte, _command| {
+                aparte.config.workspaces.keys().cloned().collect()
+            })
+        },
+    },
+    |aparte, _command| {
+        let config = aparte
+            .config
+            .workspaces
+            .get(&name)
+            .cloned()
+            .ok_or(format!("Unknown workspace {}", name))?;
+        let account = workspace_account(aparte, &config)?;
+        let channels = parse_jids(&config.channels)?;
+        let chats = parse_jids(&config.chats)?;
+
+        let mut conversations = Vec::new();
+        for channel in channels {
+            conversations.push(BareJid::from(channel.clone()));
+            aparte.schedule(Event::Join {
+                account: account.clone(),
+                channel,
+                user_request: false,
+            });
+        }
+        for contact in chats {
+            let contact = BareJid::from(contact);
+            conversations.push(contact.clone());
+            aparte.schedule(Event::Chat {
+                account: account.clone(),
+                contact,
+            });
+        }
+        aparte.schedule(Event::Workspace(Some(Workspace {
+            name: name.clone(),
+            conversations,
+        })));
+        aparte.log(format!("Workspace {} opened", name));
+        Ok(())
+    }
+);
+
+command_def!(
+    workspace_close,
+    r#"/workspace close
+
+Description:
+    Stop restricting the window bar to the conversations of the workspace
+    opened, its windows are left open."#,
+    {},
+    |aparte, _command| {
+        let name = {
+            let workspace = aparte.get_mod::<WorkspaceMod>();
+            workspace.current.clone()
+        }
+        .ok_or("No workspace opened".to_string())?;
+        aparte.schedule(Event::Workspace(None));
+        aparte.log(format!("Workspace {} closed", name));
+        Ok(())
+    }
+);
+
+/// Account of a workspace, the current one unless it names another connected one
+fn workspace_account(aparte: &Aparte, config: &WorkspaceConfig) -> Result<Account, String> {
+    let name = match &config.account {
+        Some(name) => name,
+        None => {
+            return aparte
+                .current_account()
+                .ok_or("No connection found".to_string())
+        }
+    };
+    let jid = aparte
+        .config
+        .accounts
+        .get(name)
+        .and_then(|info| Jid::from_str(&info.jid).ok())
+        .map(BareJid::from)
+        .ok_or(format!("Unknown account {}", name))?;
+    aparte
+        .accounts()
+        .into_iter()
+        .find(|account| {
+            let bare: BareJid = account.clone().into();
+            bare == jid
+        })
+        .ok_or(format!("Account {} isn't connected", name))
+}
+
+fn parse_jids(jids: &[String]) -> Result<Vec<Jid>, String> {
+    jids.iter()
+        .map(|jid| Jid::from_str(jid).map_err(|err| format!("Invalid address {}: {}", jid, err)))
+        .collect()
+}
+
+/// Conversations of the workspace opened
+#[derive(Debug, Clone)]
+pub struct Workspace {
+    pub name: String,
+    pub conversations: Vec<BareJid>,
+}
+
+pub struct WorkspaceMod {
+    /// Name of the workspace opened
+    current: Option<String>,
+}
+
+impl WorkspaceMod {
+    pub fn new() -> Self {
+        Self { current: None }
+    }
+
+    fn list(&self, config: &Config) -> Vec<String> {
+        let mut names: Vec<&String> = config.workspaces.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| {
+                let workspace = &config.workspaces[name];
+                let conversations = workspace
+                    .channels
+                    .iter()
+                    .chain(workspace.chats.iter())
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                match self.current.as_ref() == Some(name) {
+                    true => format!("* {} ({})", name, conversations),
+                    false => format!("  {} ({})", name, conversations),
+                }
+            })
+            .collect()
+    }
+}
+
+impl ModTrait for WorkspaceMod {
+    fn init(&mut self, aparte: &mut Aparte) -> Result<(), ()> {
+        aparte.add_command(workspace::new());
+        Ok(())
+    }
+
+    fn on_event(&mut self, _aparte: &mut Aparte, event: &Event) {
+        if let Event::Workspace(workspace) = event {
+            self.current = workspace.as_ref().map(|workspace| workspace.name.clone());
+        }
+    }
+}
+
+impl fmt::Display for WorkspaceMod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Workspaces")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods;
+    use crate::testing::{self, Harness};
+    use xmpp_parsers::ns;
+
+    #[test]
+    fn test_opening_workspace_joins_channels_and_opens_chats() {
+        testing::run(async {
+            // Given
+            let config: Config = toml::from_str(
+                r#"
+                accounts = {}
+
+                [workspaces.standup]
+                channels = ["team@conference.example.org"]
+                chats = ["juliet@example.org"]
+                "#,
+            )
+            .unwrap();
+            let mut harness = Harness::with_config(config);
+            harness.connect().await;
+            harness.take_sent("presence", ns::DEFAULT_NS);
+
+            // When
+            harness.input("console", "/workspace open standup").await;
+
+            // Then
+            let joins = harness.take_sent("x", ns::MUC);
+            assert_eq!(joins.len(), 1);
+            assert!(joins[0]
+                .attr("to")
+                .unwrap()
+                .starts_with("team@conference.example.org/"));
+            let windows = {
+                let ui = harness.aparte.get_mod::<mods::ui::UIMod>();
+                ui.window_conversations()
+            };
+            assert!(windows
+                .iter()
+                .any(|(window, _)| window == "juliet@example.org"));
+            assert!(harness.screen().contains("<standup>"));
+            let workspace = harness.aparte.get_mod::<WorkspaceMod>();
+            assert_eq!(workspace.current.as_deref(), Some("standup"));
+        });
+    }
+
+    #[test]
+    fn test_unknown_workspace_isnt_opened() {
+        testing::run(async {
+            // Given
+            let mut harness = Harness::new();
+            harness.connect().await;
+
+            // When
+            harness.input("console", "/workspace open standup").await;
+
+            // Then
+            assert!(harness.screen().contains("Unknown workspace standup"));
+            assert!(harness.take_sent("x", ns::MUC).is_empty());
+        });
+    }
+}
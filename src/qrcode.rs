@@ -0,0 +1,503 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! QR code generation.
+//!
+//! Only what is needed to share addresses and fingerprints is supported: byte mode, medium
+//! error correction level and versions 1 to 10 (up to 213 bytes).
+use crate::graphics::Image;
+
+/// Quiet zone around the code, in modules
+const QUIET_ZONE: usize = 4;
+
+/// Error correction blocks of each version at level M: (ec codewords per block, [(blocks, data
+/// codewords per block)])
+const BLOCKS: [(usize, [(usize, usize); 2]); 10] = [
+    (10, [(1, 16), (0, 0)]),
+    (16, [(1, 28), (0, 0)]),
+    (26, [(1, 44), (0, 0)]),
+    (18, [(2, 32), (0, 0)]),
+    (24, [(2, 43), (0, 0)]),
+    (16, [(4, 27), (0, 0)]),
+    (18, [(4, 31), (0, 0)]),
+    (22, [(2, 38), (2, 39)]),
+    (22, [(3, 36), (2, 37)]),
+    (26, [(4, 43), (1, 44)]),
+];
+
+const ALIGNMENTS: [&[usize]; 10] = [
+    &[],
+    &[6, 18],
+    &[6, 22],
+    &[6, 26],
+    &[6, 30],
+    &[6, 34],
+    &[6, 22, 38],
+    &[6, 24, 42],
+    &[6, 26, 46],
+    &[6, 28, 50],
+];
+
+#[derive(Debug, Clone)]
+pub struct QrCode {
+    size: usize,
+    modules: Vec<Vec<bool>>,
+    function: Vec<Vec<bool>>,
+}
+
+impl QrCode {
+    pub fn encode(data: &[u8]) -> Result<Self, String> {
+        let version = (1..=BLOCKS.len())
+            .find(|version| capacity(*version) >= data.len())
+            .ok_or(format!(
+                "Too much data for a QR code ({} bytes)",
+                data.len()
+            ))?;
+
+        let mut qrcode = Self::new(version);
+        qrcode.draw_function_patterns(version);
+        let codewords = interleave(version, &codewords(version, data));
+        qrcode.draw_codewords(&codewords);
+
+        let best = (0..8)
+            .min_by_key(|mask| {
+                let mut masked = qrcode.clone();
+                masked.apply_mask(*mask);
+                masked.draw_format(*mask);
+                masked.penalty()
+            })
+            .unwrap();
+        qrcode.apply_mask(best);
+        qrcode.draw_format(best);
+
+        Ok(qrcode)
+    }
+
+    fn new(version: usize) -> Self {
+        let size = version * 4 + 17;
+        Self {
+            size,
+            modules: vec![vec![false; size]; size],
+            function: vec![vec![false; size]; size],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y][x]
+    }
+
+    /// Black on white image of the code, surrounded by its quiet zone
+    pub fn to_image(&self) -> Image {
+        let size = self.size() + QUIET_ZONE * 2;
+        Image::from_fn(size, size, |x, y| {
+            let dark = x >= QUIET_ZONE
+                && y >= QUIET_ZONE
+                && x < size - QUIET_ZONE
+                && y < size - QUIET_ZONE
+                && self.is_dark(x - QUIET_ZONE, y - QUIET_ZONE);
+            match dark {
+                true => (0, 0, 0),
+                false => (255, 255, 255),
+            }
+        })
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y][x] = dark;
+        self.function[y][x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        for i in 0..self.size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        let far = self.size - 4;
+        self.draw_finder(3, 3);
+        self.draw_finder(far, 3);
+        self.draw_finder(3, far);
+
+        let positions = ALIGNMENTS[version - 1];
+        for (i, x) in positions.iter().enumerate() {
+            for (j, y) in positions.iter().enumerate() {
+                let last = positions.len() - 1;
+                let corner = (i == 0 && (j == 0 || j == last)) || (i == last && j == 0);
+                if !corner {
+                    self.draw_alignment(*x, *y);
+                }
+            }
+        }
+
+        // Reserve format areas, actual bits are drawn once the mask is chosen
+        self.draw_format(0);
+        self.draw_version(version);
+    }
+
+    fn draw_finder(&mut self, cx: usize, cy: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let (x, y) = (cx as i32 + dx, cy as i32 + dy);
+                if x < 0 || y < 0 || x >= self.size as i32 || y >= self.size as i32 {
+                    continue;
+                }
+                let distance = dx.abs().max(dy.abs());
+                self.set_function(x as usize, y as usize, distance != 2 && distance != 4);
+            }
+        }
+    }
+
+    fn draw_alignment(&mut self, cx: usize, cy: usize) {
+        for dy in -2i32..=2 {
+            for dx in -2i32..=2 {
+                let (x, y) = ((cx as i32 + dx) as usize, (cy as i32 + dy) as usize);
+                self.set_function(x, y, dx.abs().max(dy.abs()) != 1);
+            }
+        }
+    }
+
+    fn draw_format(&mut self, mask: usize) {
+        let bits = format_bits(mask);
+        let bit = |i: usize| (bits >> i) & 1 == 1;
+
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+
+        for i in 0..8 {
+            self.set_function(self.size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, self.size - 15 + i, bit(i));
+        }
+        self.set_function(8, self.size - 8, true);
+    }
+
+    fn draw_version(&mut self, version: usize) {
+        if version < 7 {
+            return;
+        }
+
+        let bits = version_bits(version);
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 == 1;
+            let (a, b) = (self.size - 11 + i % 3, i / 3);
+            self.set_function(a, b, dark);
+            self.set_function(b, a, dark);
+        }
+    }
+
+    /// Place codewords in the zigzag order, two columns at a time from the bottom right corner
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let mut i = 0;
+        let mut right = self.size as i32 - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..self.size {
+                for j in 0..2 {
+                    let x = (right - j) as usize;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = match upward {
+                        true => self.size - 1 - vertical,
+                        false => vertical,
+                    };
+                    if !self.function[y][x] && i < codewords.len() * 8 {
+                        self.modules[y][x] = (codewords[i / 8] >> (7 - i % 8)) & 1 == 1;
+                        i += 1;
+                    }
+                }
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: usize) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if invert && !self.function[y][x] {
+                    self.modules[y][x] = !self.modules[y][x];
+                }
+            }
+        }
+    }
+
+    fn penalty(&self) -> usize {
+        let mut penalty = 0;
+        let lines = (0..self.size)
+            .map(|y| self.modules[y].clone())
+            .chain((0..self.size).map(|x| (0..self.size).map(|y| self.modules[y][x]).collect()))
+            .collect::<Vec<Vec<bool>>>();
+
+        for line in &lines {
+            // Runs of modules of the same color
+            let mut run = 1;
+            for i in 1..=line.len() {
+                if i < line.len() && line[i] == line[i - 1] {
+                    run += 1;
+                } else {
+                    if run >= 5 {
+                        penalty += run - 2;
+                    }
+                    run = 1;
+                }
+            }
+
+            // Patterns looking like finders
+            let finder = [true, false, true, true, true, false, true];
+            for window in line.windows(11) {
+                let light = |range: &[bool]| range.iter().all(|dark| !dark);
+                if (window[..7] == finder && light(&window[7..]))
+                    || (light(&window[..4]) && window[4..] == finder)
+                {
+                    penalty += 40;
+                }
+            }
+        }
+
+        // Blocks of modules of the same color
+        for y in 1..self.size {
+            for x in 1..self.size {
+                let color = self.modules[y][x];
+                if self.modules[y - 1][x] == color
+                    && self.modules[y][x - 1] == color
+                    && self.modules[y - 1][x - 1] == color
+                {
+                    penalty += 3;
+                }
+            }
+        }
+
+        // Balance of dark and light modules
+        let dark = self.modules.iter().flatten().filter(|dark| **dark).count();
+        let percent = dark * 100 / (self.size * self.size);
+        let deviation = match percent > 50 {
+            true => percent - 50,
+            false => 50 - percent,
+        };
+        penalty + deviation / 5 * 10
+    }
+}
+
+/// Number of bytes a version can hold
+fn capacity(version: usize) -> usize {
+    let data_bits = data_codewords(version) * 8;
+    let header_bits = 4 + count_bits(version);
+    (data_bits - header_bits) / 8
+}
+
+fn count_bits(version: usize) -> usize {
+    match version {
+        1..=9 => 8,
+        _ => 16,
+    }
+}
+
+fn data_codewords(version: usize) -> usize {
+    let (_, groups) = BLOCKS[version - 1];
+    groups.iter().map(|(blocks, size)| blocks * size).sum()
+}
+
+/// Data codewords: byte mode header, data then padding
+fn codewords(version: usize, data: &[u8]) -> Vec<u8> {
+    let mut bits = Vec::new();
+    let mut push = |value: usize, length: usize| {
+        for i in (0..length).rev() {
+            bits.push((value >> i) & 1 == 1);
+        }
+    };
+    push(0b0100, 4);
+    push(data.len(), count_bits(version));
+    for byte in data {
+        push(*byte as usize, 8);
+    }
+
+    let capacity = data_codewords(version) * 8;
+    let terminator = (capacity - bits.len()).min(4);
+    bits.extend(vec![false; terminator]);
+    while bits.len() % 8 != 0 {
+        bits.push(false);
+    }
+
+    let mut codewords = bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0u8, |acc, bit| (acc << 1) | *bit as u8))
+        .collect::<Vec<u8>>();
+    for pad in [0xec, 0x11].iter().cycle() {
+        if codewords.len() >= data_codewords(version) {
+            break;
+        }
+        codewords.push(*pad);
+    }
+
+    codewords
+}
+
+/// Split data codewords in blocks, compute their error correction and interleave them
+fn interleave(version: usize, data: &[u8]) -> Vec<u8> {
+    let (ec_size, groups) = BLOCKS[version - 1];
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+    for (count, size) in groups.iter() {
+        for _ in 0..*count {
+            blocks.push(&data[offset..offset + size]);
+            offset += size;
+        }
+    }
+
+    let ecs = blocks
+        .iter()
+        .map(|block| reed_solomon(block, ec_size))
+        .collect::<Vec<Vec<u8>>>();
+
+    let mut codewords = Vec::new();
+    let longest = blocks.iter().map(|block| block.len()).max().unwrap_or(0);
+    for i in 0..longest {
+        for block in &blocks {
+            if let Some(codeword) = block.get(i) {
+                codewords.push(*codeword);
+            }
+        }
+    }
+    for i in 0..ec_size {
+        for ec in &ecs {
+            codewords.push(ec[i]);
+        }
+    }
+
+    codewords
+}
+
+/// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    while b != 0 {
+        if b & 1 == 1 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1d;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+fn reed_solomon(data: &[u8], degree: usize) -> Vec<u8> {
+    // Generator polynomial (x - 2^0)(x - 2^1)…(x - 2^(degree-1)), leading term omitted
+    let mut generator = vec![0u8; degree];
+    generator[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            generator[j] = gf_multiply(generator[j], root);
+            if j + 1 < degree {
+                generator[j] ^= generator[j + 1];
+            }
+        }
+        root = gf_multiply(root, 2);
+    }
+
+    let mut remainder = vec![0u8; degree];
+    for byte in data {
+        let factor = byte ^ remainder.remove(0);
+        remainder.push(0);
+        for (r, g) in remainder.iter_mut().zip(generator.iter()) {
+            *r ^= gf_multiply(*g, factor);
+        }
+    }
+
+    remainder
+}
+
+/// Format information for level M with the given mask, BCH protected
+fn format_bits(mask: usize) -> usize {
+    let data = mask; // Level M is 0b00
+    let mut remainder = data;
+    for _ in 0..10 {
+        remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+    }
+    ((data << 10) | remainder) ^ 0x5412
+}
+
+fn version_bits(version: usize) -> usize {
+    let mut remainder = version;
+    for _ in 0..12 {
+        remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1f25);
+    }
+    (version << 12) | remainder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reed_solomon() {
+        // Example from ISO/IEC 18004 annex I, version 1-M
+        let data = [
+            32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17,
+        ];
+
+        assert_eq!(
+            reed_solomon(&data, 10),
+            vec![196, 35, 39, 119, 235, 215, 231, 226, 93, 23]
+        );
+    }
+
+    #[test]
+    fn test_format_and_version_bits() {
+        assert_eq!(format_bits(0), 0b101010000010010);
+        assert_eq!(format_bits(7), 0b100101010100000);
+        assert_eq!(version_bits(7), 0b000111110010010100);
+    }
+
+    #[test]
+    fn test_encode_chooses_smallest_version() {
+        // Given
+        let short = QrCode::encode(b"xmpp:me@example.org").unwrap();
+        let long = QrCode::encode(&[b'a'; 100]).unwrap();
+
+        // Then
+        assert_eq!(short.size(), 25);
+        assert_eq!(long.size(), 41);
+        assert!(QrCode::encode(&[b'a'; 214]).is_err());
+    }
+
+    #[test]
+    fn test_encode_draws_finders() {
+        // Given
+        let qrcode = QrCode::encode(b"xmpp:me@example.org").unwrap();
+        let far = qrcode.size() - 7;
+
+        // Then
+        for (x, y) in &[(0, 0), (far, 0), (0, far)] {
+            assert!(qrcode.is_dark(*x, *y));
+            assert!(!qrcode.is_dark(x + 1, y + 1));
+            assert!(qrcode.is_dark(x + 3, y + 3));
+        }
+    }
+}
@@ -0,0 +1,323 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Tolerant ingestion of incoming stanzas.
+//!
+//! Mods parse the payloads they know with `TryFrom` and silently ignore those failing to parse.
+//! Incoming stanzas are checked here first: malformed payloads are taken out of their stanza,
+//! and malformed stanzas dropped, so that mods only ever see elements that parse.
+use std::convert::TryFrom;
+use xmpp_parsers::{ns, Element};
+
+/// Payloads nested deeper are checked as a whole, without looking at their content
+const MAX_DEPTH: usize = 16;
+
+type Check = fn(&Element) -> Result<(), xmpp_parsers::Error>;
+
+/// Parsers of the elements checked, by name (any name if None) and namespace
+const CHECKS: &[(Option<&str>, &str, Check)] = &[
+    (Some("message"), ns::DEFAULT_NS, |element| {
+        xmpp_parsers::message::Message::try_from(element.clone()).map(|_| ())
+    }),
+    (Some("presence"), ns::DEFAULT_NS, |element| {
+        xmpp_parsers::presence::Presence::try_from(element.clone()).map(|_| ())
+    }),
+    (Some("iq"), ns::DEFAULT_NS, |element| {
+        xmpp_parsers::iq::Iq::try_from(element.clone()).map(|_| ())
+    }),
+    (Some("delay"), ns::DELAY, |element| {
+        xmpp_parsers::delay::Delay::try_from(element.clone()).map(|_| ())
+    }),
+    (Some("replace"), ns::MESSAGE_CORRECT, |element| {
+        xmpp_parsers::message_correct::Replace::try_from(element.clone()).map(|_| ())
+    }),
+    (Some("received"), ns::RECEIPTS, |element| {
+        xmpp_parsers::receipts::Received::try_from(element.clone()).map(|_| ())
+    }),
+    (None, ns::CHATSTATES, |element| {
+        xmpp_parsers::chatstates::ChatState::try_from(element.clone()).map(|_| ())
+    }),
+    (Some("stanza-id"), ns::SID, |element| {
+        xmpp_parsers::stanza_id::StanzaId::try_from(element.clone()).map(|_| ())
+    }),
+    (Some("origin-id"), ns::SID, |element| {
+        xmpp_parsers::stanza_id::OriginId::try_from(element.clone()).map(|_| ())
+    }),
+    (Some("forwarded"), ns::FORWARD, |element| {
+        // The parser accepts forwarded elements without stanza, which are useless to mods
+        match xmpp_parsers::forwarding::Forwarded::try_from(element.clone())?.stanza {
+            Some(_) => Ok(()),
+            None => Err(xmpp_parsers::Error::ParseError("Missing forwarded stanza.")),
+        }
+    }),
+    (Some("received"), ns::CARBONS, |element| {
+        xmpp_parsers::carbons::Received::try_from(element.clone()).map(|_| ())
+    }),
+    (Some("sent"), ns::CARBONS, |element| {
+        xmpp_parsers::carbons::Sent::try_from(element.clone()).map(|_| ())
+    }),
+    (Some("result"), ns::MAM, |element| {
+        xmpp_parsers::mam::Result_::try_from(element.clone()).map(|_| ())
+    }),
+    (Some("x"), ns::MUC_USER, |element| {
+        // The parser doesn't know invitations, left to the invite mod
+        let mut element = element.clone();
+        while element.remove_child("invite", ns::MUC_USER).is_some() {}
+        element.remove_child("decline", ns::MUC_USER);
+        element.remove_child("password", ns::MUC_USER);
+        xmpp_parsers::muc::user::MucUser::try_from(element).map(|_| ())
+    }),
+    (Some("query"), ns::ROSTER, |element| {
+        xmpp_parsers::roster::Roster::try_from(element.clone()).map(|_| ())
+    }),
+];
+
+/// Element taken out of an incoming stanza as it fails to parse
+#[derive(Debug, Clone)]
+pub struct Quarantined {
+    pub element: Element,
+    pub reason: String,
+}
+
+/// Check an incoming stanza, returning it without its malformed payloads, or None if the
+/// stanza itself is malformed, along with the elements taken out of it
+pub fn ingest(stanza: Element) -> (Option<Element>, Vec<Quarantined>) {
+    let mut quarantined = Vec::new();
+    let stanza = sanitize(&stanza, 0, &mut quarantined);
+    (stanza, quarantined)
+}
+
+/// Whether an element parses, if it is one of those checked
+pub(crate) fn check(element: &Element) -> Result<(), String> {
+    match CHECKS.iter().find(|(name, ns, _)| match name {
+        Some(name) => element.is(*name, *ns),
+        None => element.has_ns(*ns),
+    }) {
+        Some((_, _, check)) => check(element).map_err(|err| format!("{:?}", err)),
+        None => Ok(()),
+    }
+}
+
+/// Sanitize the children of an element, then the element itself
+fn sanitize(
+    element: &Element,
+    depth: usize,
+    quarantined: &mut Vec<Quarantined>,
+) -> Option<Element> {
+    let element = match depth < MAX_DEPTH && element.children().next().is_some() {
+        true => {
+            let mut builder = Element::builder(element.name(), element.ns());
+            for (name, value) in element.attrs() {
+                builder = builder.attr(name, value);
+            }
+            for node in element.nodes() {
+                match node.as_element() {
+                    Some(child) => {
+                        if let Some(child) = sanitize(child, depth + 1, quarantined) {
+                            builder = builder.append(child);
+                        }
+                    }
+                    None => builder = builder.append(node.clone()),
+                }
+            }
+            builder.build()
+        }
+        false => element.clone(),
+    };
+
+    match check(&element) {
+        Ok(()) => Some(element),
+        Err(reason) => {
+            quarantined.push(Quarantined { element, reason });
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::str::FromStr;
+
+    /// Stanzas mutated by the fuzz test
+    const CORPUS: &[&str] = &[
+        "<message xmlns='jabber:client' type='chat' id='m1' from='juliet@example.org/balcony' to='romeo@example.org'>
+            <body>Wherefore art thou</body>
+            <active xmlns='http://jabber.org/protocol/chatstates'/>
+            <delay xmlns='urn:xmpp:delay' stamp='2021-03-14T18:30:00Z'/>
+            <replace xmlns='urn:xmpp:message-correct:0' id='m0'/>
+            <origin-id xmlns='urn:xmpp:sid:0' id='o1'/>
+        </message>",
+        "<message xmlns='jabber:client' from='romeo@example.org' to='romeo@example.org/aparte'>
+            <received xmlns='urn:xmpp:carbons:2'>
+                <forwarded xmlns='urn:xmpp:forward:0'>
+                    <message xmlns='jabber:client' type='chat' id='c1' from='juliet@example.org/balcony' to='romeo@example.org/phone'>
+                        <body>Hi</body>
+                        <received xmlns='urn:xmpp:receipts' id='m1'/>
+                    </message>
+                </forwarded>
+            </received>
+        </message>",
+        "<presence xmlns='jabber:client' from='room@conference.example.org/juliet'>
+            <x xmlns='http://jabber.org/protocol/muc#user'>
+                <item affiliation='member' role='participant'/>
+                <status code='110'/>
+            </x>
+        </presence>",
+        "<iq xmlns='jabber:client' type='set' id='r1'>
+            <query xmlns='jabber:iq:roster'>
+                <item jid='juliet@example.org' subscription='both'><group>Friends</group></item>
+            </query>
+        </iq>",
+    ];
+
+    fn mutate(rng: &mut StdRng, stanza: &str) -> String {
+        let mut bytes = stanza.as_bytes().to_vec();
+        for _ in 0..rng.gen_range(1..4) {
+            let position = rng.gen_range(0..bytes.len());
+            match rng.gen_range(0..4) {
+                // Replace a character with one meaningful in XML or in attribute values
+                0 => bytes[position] = b"<>/='\"aZ0-:T"[rng.gen_range(0..12)],
+                1 => {
+                    bytes.remove(position);
+                }
+                2 => {
+                    let end = rng.gen_range(position..bytes.len());
+                    bytes.drain(position..end);
+                }
+                _ => {
+                    let end = rng.gen_range(position..bytes.len());
+                    let copy = bytes[position..end].to_vec();
+                    bytes.splice(position..position, copy);
+                }
+            }
+        }
+        String::from_utf8_lossy(&bytes).to_string()
+    }
+
+    fn assert_sane(element: &Element) {
+        assert_eq!(check(element), Ok(()), "{}", String::from(element));
+        for child in element.children() {
+            assert_sane(child);
+        }
+    }
+
+    #[test]
+    fn test_malformed_payload_is_quarantined() {
+        // Given
+        let stanza = Element::from_str(
+            "<message xmlns='jabber:client' type='chat' from='juliet@example.org/balcony'>
+                <body>Hi</body>
+                <delay xmlns='urn:xmpp:delay' stamp='yesterday'/>
+            </message>",
+        )
+        .unwrap();
+
+        // When
+        let (stanza, quarantined) = ingest(stanza);
+
+        // Then
+        let stanza = stanza.unwrap();
+        assert!(stanza.has_child("body", ns::DEFAULT_NS));
+        assert!(!stanza.has_child("delay", ns::DELAY));
+        assert_eq!(quarantined.len(), 1);
+        assert!(quarantined[0].element.is("delay", ns::DELAY));
+    }
+
+    #[test]
+    fn test_malformed_forwarded_message_quarantines_its_wrapper() {
+        // Given
+        let stanza = Element::from_str(
+            "<message xmlns='jabber:client' from='romeo@example.org'>
+                <sent xmlns='urn:xmpp:carbons:2'>
+                    <forwarded xmlns='urn:xmpp:forward:0'>
+                        <message xmlns='jabber:client'><body>Hi</body><body>Hi</body></message>
+                    </forwarded>
+                </sent>
+            </message>",
+        )
+        .unwrap();
+
+        // When
+        let (stanza, quarantined) = ingest(stanza);
+
+        // Then
+        assert!(!stanza.unwrap().has_child("sent", ns::CARBONS));
+        let names = quarantined
+            .iter()
+            .map(|quarantined| quarantined.element.name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["message", "forwarded", "sent"]);
+    }
+
+    #[test]
+    fn test_malformed_stanza_is_dropped() {
+        // Given
+        let stanza = Element::from_str("<iq xmlns='jabber:client' type='get'/>").unwrap();
+
+        // When
+        let (stanza, quarantined) = ingest(stanza);
+
+        // Then
+        assert!(stanza.is_none());
+        assert_eq!(quarantined.len(), 1);
+    }
+
+    #[test]
+    fn test_valid_stanzas_are_untouched() {
+        for stanza in CORPUS {
+            // Given
+            let stanza = Element::from_str(stanza).unwrap();
+
+            // When
+            let (ingested, quarantined) = ingest(stanza.clone());
+
+            // Then
+            assert!(quarantined.is_empty(), "{:?}", quarantined);
+            assert_eq!(ingested, Some(stanza));
+        }
+    }
+
+    #[test]
+    fn fuzz_ingested_stanzas_only_hold_parsable_elements() {
+        let mut rng = StdRng::seed_from_u64(0x61706172);
+        for _ in 0..5000 {
+            // Given
+            let seed = CORPUS[rng.gen_range(0..CORPUS.len())];
+            let stanza = mutate(&mut rng, seed);
+            let stanza = match Element::from_str(&stanza) {
+                Ok(stanza) => stanza,
+                Err(_) => continue,
+            };
+
+            // When
+            let (ingested, _) = ingest(stanza);
+
+            // Then
+            if let Some(ingested) = ingested {
+                assert_sane(&ingested);
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_deeply_nested_stanzas() {
+        // Given
+        let depth = 1000;
+        let stanza = format!(
+            "<message xmlns='jabber:client'>{}{}</message>",
+            "<x xmlns='urn:example'>".repeat(depth),
+            "</x>".repeat(depth)
+        );
+        let stanza = Element::from_str(&stanza).unwrap();
+
+        // When
+        let (ingested, quarantined) = ingest(stanza);
+
+        // Then
+        assert!(ingested.is_some());
+        assert!(quarantined.is_empty());
+    }
+}
@@ -0,0 +1,165 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Control socket letting `aparte remote <command>` run commands in the running instance
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::UnixListener;
+use tokio::sync::mpsc;
+
+use crate::command::Command;
+use crate::core::Event;
+
+/// Path of the control socket of the running instance, in a directory only the user can enter
+pub fn socket_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("aparte").join("remote").join("control.sock"))
+}
+
+/// Build the command line to run from shell arguments, the leading / being optional
+pub fn command_line(args: &[String]) -> Result<String, String> {
+    let mut args = args.to_vec();
+    match args.first_mut() {
+        Some(name) => {
+            if name.starts_with('/') {
+                name.remove(0);
+            }
+        }
+        None => return Err("Usage: aparte remote <command> [<args>...]".to_string()),
+    }
+    Ok(format!("/{}", Command::assemble_args(&args)))
+}
+
+/// Send a command to the running instance
+pub fn send(args: &[String]) -> Result<(), String> {
+    let path = socket_path().ok_or("Cannot find data directory".to_string())?;
+    let command = command_line(args)?;
+    let mut stream = StdUnixStream::connect(&path)
+        .map_err(|err| format!("Cannot reach Aparté at {}: {}", path.display(), err))?;
+    writeln!(stream, "{}", command.replace('\n', " ")).map_err(|err| err.to_string())
+}
+
+/// Control socket bound by this instance, removed once dropped
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!("Cannot remove {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Bind the control socket, unless another instance already listens on it
+pub fn bind(path: &Path) -> Result<ControlSocket, String> {
+    // The socket is never reachable by other users, even for the time it would take to change
+    // its own permissions once bound
+    let dir = path
+        .parent()
+        .ok_or(format!("Invalid socket path {}", path.display()))?;
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .map_err(|err| format!("Cannot create {}: {}", dir.display(), err))?;
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))
+        .map_err(|err| format!("Cannot restrict {}: {}", dir.display(), err))?;
+
+    if path.exists() {
+        if StdUnixStream::connect(path).is_ok() {
+            return Err(format!("{} is used by another instance", path.display()));
+        }
+        // Left over by an instance that didn't quit properly
+        std::fs::remove_file(path).map_err(|err| err.to_string())?;
+    }
+
+    let listener = UnixListener::bind(path).map_err(|err| err.to_string())?;
+    Ok(ControlSocket {
+        listener,
+        path: path.to_path_buf(),
+    })
+}
+
+/// Forward each line received on the control socket as a command run from the console
+pub async fn listen(socket: ControlSocket, tx: mpsc::Sender<Event>) {
+    loop {
+        let stream = match socket.listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                error!("Cannot accept remote connection: {}", err);
+                break;
+            }
+        };
+
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stream).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // Arguments are left out of the logs, they can be messages or passwords
+                let name = line.split_whitespace().next().unwrap_or_default();
+                info!("Remote command: {}", name);
+                let event = Event::RawCommand(None, "console".to_string(), line);
+                if let Err(err) = tx.send(event).await {
+                    error!("Cannot send remote command to internal channel: {}", err);
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_line_quotes_arguments() {
+        // Given
+        let args = vec![
+            String::from("msg"),
+            String::from("alice@example.org"),
+            String::from("on my way"),
+        ];
+
+        // When
+        let command = command_line(&args);
+
+        // Then
+        assert_eq!(
+            command,
+            Ok(String::from(r#"/msg alice@example.org "on my way""#))
+        );
+    }
+
+    #[test]
+    fn test_socket_is_bound_in_a_private_directory_and_removed_once_dropped() {
+        crate::testing::run(async {
+            // Given
+            let dir = std::env::temp_dir().join(format!("aparte-remote-{}", std::process::id()));
+            let path = dir.join("remote").join("control.sock");
+
+            // When
+            let socket = bind(&path).unwrap();
+            let mode = std::fs::metadata(path.parent().unwrap())
+                .unwrap()
+                .permissions()
+                .mode();
+            let bound = path.exists();
+            drop(socket);
+
+            // Then
+            assert_eq!(mode & 0o777, 0o700);
+            assert!(bound);
+            assert!(!path.exists());
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+}
@@ -0,0 +1,278 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Files shared with Stateless Inline Media Sharing (XEP-0385), described by references
+//! (XEP-0372) to their metadata and to the places they can be downloaded from.
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use xmpp_parsers::{ns, Element};
+
+use crate::mods::upload::human_size;
+
+pub const REFERENCE: &str = "urn:xmpp:reference:0";
+pub const SIMS: &str = "urn:xmpp:sims:1";
+const THUMBS: &str = "urn:xmpp:thumbs:1";
+/// Size of the chunks of a file hashed at once
+const CHUNK: usize = 64 * 1024;
+
+/// File shared in a message
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MediaSharing {
+    pub name: Option<String>,
+    pub media_type: Option<String>,
+    pub size: Option<u64>,
+    /// Hashes of the file (XEP-0300), as algorithm and base64 value
+    pub hashes: Vec<(String, String)>,
+    /// Uri of a thumbnail, usually a `cid:` of Bits of Binary (XEP-0231)
+    pub thumbnail: Option<String>,
+    /// Uris the file can be downloaded from
+    pub sources: Vec<String>,
+}
+
+impl MediaSharing {
+    /// File described by a reference payload, None for other references
+    pub fn from_reference(reference: &Element) -> Option<Self> {
+        if !reference.is("reference", REFERENCE) {
+            return None;
+        }
+        let sharing = reference.get_child("media-sharing", SIMS)?;
+        let file = sharing.get_child("file", ns::JINGLE_FT)?;
+        let text = |name: &str| {
+            file.get_child(name, ns::JINGLE_FT)
+                .map(|child| child.text().trim().to_string())
+        };
+        let sources = sharing
+            .get_child("sources", SIMS)?
+            .children()
+            .filter(|source| source.is("reference", REFERENCE))
+            .filter_map(|source| source.attr("uri").map(String::from))
+            .collect::<Vec<_>>();
+        if sources.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            name: text("name"),
+            media_type: text("media-type"),
+            size: text("size").and_then(|size| size.parse().ok()),
+            hashes: file
+                .children()
+                .filter(|hash| hash.is("hash", ns::HASHES))
+                .filter_map(|hash| Some((hash.attr("algo")?.to_string(), hash.text())))
+                .collect(),
+            thumbnail: file
+                .get_child("thumbnail", THUMBS)
+                .and_then(|thumbnail| thumbnail.attr("uri"))
+                .map(String::from),
+            sources,
+        })
+    }
+
+    /// Metadata of a local file to be downloaded from an uri, read and hashed out of the event
+    /// loop
+    pub async fn from_file(
+        path: PathBuf,
+        media_type: String,
+        source: String,
+    ) -> Result<Self, String> {
+        tokio::task::spawn_blocking(move || Self::read_file(&path, &media_type, source))
+            .await
+            .map_err(|e| format!("Cannot read file: {}", e))?
+    }
+
+    fn read_file(path: &Path, media_type: &str, source: String) -> Result<Self, String> {
+        let error = |e: io::Error| format!("Cannot read {}: {}", path.display(), e);
+        let mut file = File::open(path).map_err(error)?;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        let mut chunk = vec![0; CHUNK];
+        loop {
+            let read = match file.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(error(e)),
+            };
+            hasher.input(&chunk[..read]);
+            size += read as u64;
+        }
+        let mut hash = [0; 32];
+        hasher.result(&mut hash);
+
+        Ok(Self {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string()),
+            media_type: Some(media_type.to_string()),
+            size: Some(size),
+            hashes: vec![(String::from("sha-256"), base64::encode(hash))],
+            thumbnail: None,
+            sources: vec![source],
+        })
+    }
+
+    pub fn to_reference(&self) -> Element {
+        let mut file = Element::builder("file", ns::JINGLE_FT).build();
+        if let Some(media_type) = &self.media_type {
+            file.append_child(
+                Element::builder("media-type", ns::JINGLE_FT)
+                    .append(media_type.as_str())
+                    .build(),
+            );
+        }
+        if let Some(name) = &self.name {
+            file.append_child(
+                Element::builder("name", ns::JINGLE_FT)
+                    .append(name.as_str())
+                    .build(),
+            );
+        }
+        if let Some(size) = self.size {
+            file.append_child(
+                Element::builder("size", ns::JINGLE_FT)
+                    .append(size.to_string())
+                    .build(),
+            );
+        }
+        for (algo, hash) in &self.hashes {
+            file.append_child(
+                Element::builder("hash", ns::HASHES)
+                    .attr("algo", algo.as_str())
+                    .append(hash.as_str())
+                    .build(),
+            );
+        }
+        if let Some(thumbnail) = &self.thumbnail {
+            file.append_child(
+                Element::builder("thumbnail", THUMBS)
+                    .attr("uri", thumbnail.as_str())
+                    .build(),
+            );
+        }
+
+        let mut sources = Element::builder("sources", SIMS).build();
+        for source in &self.sources {
+            sources.append_child(
+                Element::builder("reference", REFERENCE)
+                    .attr("type", "data")
+                    .attr("uri", source.as_str())
+                    .build(),
+            );
+        }
+
+        Element::builder("reference", REFERENCE)
+            .attr("type", "data")
+            .append(
+                Element::builder("media-sharing", SIMS)
+                    .append(file)
+                    .append(sources)
+                    .build(),
+            )
+            .build()
+    }
+
+    /// Name, size and type of the file, as shown in place of its link
+    pub fn summary(&self) -> String {
+        let mut details = Vec::new();
+        if let Some(size) = self.size {
+            details.push(human_size(size));
+        }
+        if let Some(media_type) = &self.media_type {
+            details.push(media_type.clone());
+        }
+        let name = self
+            .name
+            .clone()
+            .unwrap_or_else(|| String::from("Unnamed file"));
+        match details.is_empty() {
+            true => name,
+            false => format!("{} ({})", name, details.join(", ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_shared_file_is_parsed_from_its_reference() {
+        // Given
+        let reference = Element::from_str(
+            "<reference xmlns='urn:xmpp:reference:0' type='data'>
+                <media-sharing xmlns='urn:xmpp:sims:1'>
+                    <file xmlns='urn:xmpp:jingle:apps:file-transfer:5'>
+                        <media-type>image/jpeg</media-type>
+                        <name>summit.jpg</name>
+                        <size>3032449</size>
+                        <hash xmlns='urn:xmpp:hashes:2' algo='sha3-256'>2XarmwTlNxDAMkvymloX3S5+VbylNrJt/l5QyPa+YoU=</hash>
+                        <thumbnail xmlns='urn:xmpp:thumbs:1' uri='cid:sha1+ffd7c8d28e9c5e82afea41f97108c6b4@bob.xmpp.org'/>
+                    </file>
+                    <sources>
+                        <reference xmlns='urn:xmpp:reference:0' type='data' uri='https://download.montague.lit/summit.jpg'/>
+                    </sources>
+                </media-sharing>
+            </reference>",
+        )
+        .unwrap();
+
+        // When
+        let sharing = MediaSharing::from_reference(&reference).unwrap();
+
+        // Then
+        assert_eq!(sharing.summary(), "summit.jpg (3 MB, image/jpeg)");
+        assert_eq!(
+            sharing.hashes,
+            vec![(
+                String::from("sha3-256"),
+                String::from("2XarmwTlNxDAMkvymloX3S5+VbylNrJt/l5QyPa+YoU=")
+            )]
+        );
+        assert_eq!(
+            sharing.thumbnail.as_deref(),
+            Some("cid:sha1+ffd7c8d28e9c5e82afea41f97108c6b4@bob.xmpp.org")
+        );
+        assert_eq!(
+            sharing.sources,
+            vec![String::from("https://download.montague.lit/summit.jpg")]
+        );
+        assert_eq!(
+            MediaSharing::from_reference(&sharing.to_reference()),
+            Some(sharing)
+        );
+    }
+
+    #[test]
+    fn test_uploaded_file_is_hashed() {
+        crate::testing::run(async {
+            // Given
+            let path =
+                std::env::temp_dir().join(format!("aparte-test-{}.txt", uuid::Uuid::new_v4()));
+            std::fs::write(&path, "hello").unwrap();
+
+            // When
+            let sharing = MediaSharing::from_file(
+                path.clone(),
+                String::from("text/plain"),
+                String::from("https://upload.example.org/hello.txt"),
+            )
+            .await;
+            std::fs::remove_file(&path).unwrap();
+
+            // Then
+            let sharing = sharing.unwrap();
+            assert_eq!(sharing.size, Some(5));
+            assert_eq!(
+                sharing.hashes,
+                vec![(
+                    String::from("sha-256"),
+                    String::from("LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=")
+                )]
+            );
+        });
+    }
+}
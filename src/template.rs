@@ -0,0 +1,220 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Minimal template language used to customize rendering.
+//!
+//! A template is a literal string in which `{name}` placeholders are substituted. A placeholder
+//! can be given a minimal width and an alignment: `{nick:>12}` right aligns the value in a 12
+//! columns wide field while `{nick:<12}` (or `{nick:12}`) left aligns it. Literal braces are
+//! written `{{` and `}}`.
+use std::str::FromStr;
+
+use crate::terminus::term_string_visible_len;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Align {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Placeholder {
+        name: String,
+        align: Align,
+        width: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Render the template, `resolve` gives the value of each placeholder. Unknown placeholders
+    /// are rendered as is.
+    pub fn render<F>(&self, resolve: F) -> String
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut output = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => output.push_str(literal),
+                Part::Placeholder { name, align, width } => {
+                    let value = match resolve(name) {
+                        Some(value) => value,
+                        None => {
+                            output.push_str(&format!("{{{}}}", name));
+                            continue;
+                        }
+                    };
+
+                    // Width is computed on visible chars so that values can embed colors
+                    let len = term_string_visible_len(&value);
+                    let padding = match width {
+                        Some(width) if *width > len => " ".repeat(width - len),
+                        _ => String::new(),
+                    };
+
+                    match align {
+                        Align::Left => {
+                            output.push_str(&value);
+                            output.push_str(&padding);
+                        }
+                        Align::Right => {
+                            output.push_str(&padding);
+                            output.push_str(&value);
+                        }
+                    }
+                }
+            }
+        }
+
+        output
+    }
+
+    fn parse_placeholder(placeholder: &str) -> Result<Part, String> {
+        let mut split = placeholder.splitn(2, ':');
+        let name = split.next().unwrap().trim().to_string();
+        if name.is_empty() {
+            return Err(format!("Empty placeholder `{{{}}}`", placeholder));
+        }
+
+        let (align, width) = match split.next() {
+            None => (Align::Left, None),
+            Some(spec) => {
+                let (align, width) = if let Some(width) = spec.strip_prefix('>') {
+                    (Align::Right, width)
+                } else if let Some(width) = spec.strip_prefix('<') {
+                    (Align::Left, width)
+                } else {
+                    (Align::Left, spec)
+                };
+                let width = usize::from_str(width)
+                    .map_err(|_| format!("Invalid width in placeholder `{{{}}}`", placeholder))?;
+                (align, Some(width))
+            }
+        };
+
+        Ok(Part::Placeholder { name, align, width })
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err(format!("Unterminated placeholder in `{}`", s)),
+                        }
+                    }
+
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(literal.clone()));
+                        literal.clear();
+                    }
+                    parts.push(Self::parse_placeholder(&placeholder)?);
+                }
+                '}' => return Err(format!("Unexpected `}}` in `{}`", s)),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Template { parts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(name: &str) -> Option<String> {
+        match name {
+            "nick" => Some("alice".to_string()),
+            "time" => Some("12:00:00".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_render_placeholders() {
+        // Given
+        let template = Template::from_str("{time} <{nick}> ").unwrap();
+
+        // When
+        let rendered = template.render(resolve);
+
+        // Then
+        assert_eq!(rendered, "12:00:00 <alice> ");
+    }
+
+    #[test]
+    fn test_render_aligned_placeholders() {
+        // Given
+        let template = Template::from_str("[{nick:>8}|{nick:<8}|{nick:2}]").unwrap();
+
+        // When
+        let rendered = template.render(resolve);
+
+        // Then
+        assert_eq!(rendered, "[   alice|alice   |alice]");
+    }
+
+    #[test]
+    fn test_render_width_ignores_colors() {
+        // Given
+        let template = Template::from_str("{nick:>6}").unwrap();
+
+        // When
+        let rendered = template.render(|_| Some("\x1b[31mbob\x1b[0m".to_string()));
+
+        // Then
+        assert_eq!(rendered, "   \x1b[31mbob\x1b[0m");
+    }
+
+    #[test]
+    fn test_render_escaped_braces_and_unknown_placeholder() {
+        // Given
+        let template = Template::from_str("{{{unknown}}}").unwrap();
+
+        // When
+        let rendered = template.render(resolve);
+
+        // Then
+        assert_eq!(rendered, "{{unknown}}");
+    }
+
+    #[test]
+    fn test_parse_invalid_templates() {
+        assert!(Template::from_str("{nick").is_err());
+        assert!(Template::from_str("nick}").is_err());
+        assert!(Template::from_str("{}").is_err());
+        assert!(Template::from_str("{nick:>abc}").is_err());
+    }
+}
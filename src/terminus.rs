@@ -8,13 +8,333 @@ use std::cmp;
 use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt;
 use std::hash::Hash;
-use std::io::Write;
+use std::io::{self, Write};
 use std::rc::Rc;
 use termion::raw::RawTerminal;
 use termion::screen::AlternateScreen;
 use unicode_segmentation::UnicodeSegmentation;
+use zeroize::Zeroize;
+
+/// Where views are drawn: the terminal, in raw mode on its alternate screen, or a buffer keeping
+/// each flushed frame when running headless.
+///
+/// Views only draw what changed, nothing reaches the terminal until the whole frame is flushed
+/// at once so that views updated together don't tear.
+pub enum Screen<W: Write> {
+    Terminal {
+        terminal: Box<AlternateScreen<RawTerminal<W>>>,
+        pending: Vec<u8>,
+    },
+    #[cfg_attr(not(test), allow(dead_code))]
+    Headless {
+        width: u16,
+        height: u16,
+        pending: Vec<u8>,
+        frames: Vec<Vec<u8>>,
+    },
+}
+
+impl<W: Write> Screen<W> {
+    pub fn terminal(terminal: AlternateScreen<RawTerminal<W>>) -> Self {
+        Screen::Terminal {
+            terminal: Box::new(terminal),
+            pending: Vec::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn headless(width: u16, height: u16) -> Self {
+        Screen::Headless {
+            width,
+            height,
+            pending: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn size(&self) -> io::Result<(u16, u16)> {
+        match self {
+            Screen::Terminal { .. } => termion::terminal_size(),
+            Screen::Headless { width, height, .. } => Ok((*width, *height)),
+        }
+    }
+
+    /// Output flushed so far by a headless screen, one frame per flush
+    #[cfg(test)]
+    pub fn frames(&self) -> &[Vec<u8>] {
+        match self {
+            Screen::Terminal { .. } => &[],
+            Screen::Headless { frames, .. } => frames,
+        }
+    }
+}
+
+impl<W: Write> Write for Screen<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Screen::Terminal { pending, .. } => pending.write(buf),
+            Screen::Headless { pending, .. } => pending.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Screen::Terminal { terminal, pending } => {
+                write_pending(terminal, pending)?;
+                terminal.flush()
+            }
+            Screen::Headless {
+                pending, frames, ..
+            } => {
+                if !pending.is_empty() {
+                    frames.push(std::mem::take(pending));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Write a frame, keeping what couldn't be written yet to be written by the next flush
+fn write_pending<W: Write>(out: &mut W, pending: &mut Vec<u8>) -> io::Result<()> {
+    while !pending.is_empty() {
+        match out.write(pending) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => {
+                pending.drain(..written);
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Style of a cell of a `TestScreen`, colors are kept as their SGR parameters (like `5;12` or
+/// `2;255;0;0` for extended colors)
+#[cfg(test)]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Style {
+    pub bold: bool,
+    pub dim: bool,
+    pub invert: bool,
+    pub fg: Option<String>,
+    pub bg: Option<String>,
+}
+
+#[cfg(test)]
+impl Style {
+    fn apply(&mut self, sgr: &str) {
+        let mut params = sgr.split(';');
+        while let Some(param) = params.next() {
+            match param {
+                "" | "0" => *self = Style::default(),
+                "1" => self.bold = true,
+                "2" => self.dim = true,
+                "21" | "22" => {
+                    self.bold = false;
+                    self.dim = false;
+                }
+                "7" => self.invert = true,
+                "27" => self.invert = false,
+                "39" => self.fg = None,
+                "49" => self.bg = None,
+                "38" | "48" => {
+                    let color = match params.next() {
+                        Some("5") => format!("5;{}", params.next().unwrap_or_default()),
+                        Some("2") => format!(
+                            "2;{}",
+                            params.by_ref().take(3).collect::<Vec<_>>().join(";")
+                        ),
+                        _ => continue,
+                    };
+                    match param {
+                        "38" => self.fg = Some(color),
+                        _ => self.bg = Some(color),
+                    }
+                }
+                color => match color.parse::<u8>() {
+                    Ok(30..=37) | Ok(90..=97) => self.fg = Some(color.to_string()),
+                    Ok(40..=47) | Ok(100..=107) => self.bg = Some(color.to_string()),
+                    _ => {}
+                },
+            }
+        }
+    }
+}
+
+/// Grid of cells filled by interpreting what views write to a headless screen, to compare
+/// renderings in tests. Each grapheme takes one cell, as for `term_string_visible_len`.
+#[cfg(test)]
+pub struct TestScreen {
+    pub width: u16,
+    pub height: u16,
+    cells: Vec<Vec<(String, Style)>>,
+    x: u16,
+    y: u16,
+    saved: (u16, u16),
+    style: Style,
+}
+
+#[cfg(test)]
+impl TestScreen {
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![vec![(String::from(" "), Style::default()); width.into()]; height.into()],
+            x: 1,
+            y: 1,
+            saved: (1, 1),
+            style: Style::default(),
+        }
+    }
+
+    /// Measure, layout and render a view on the whole screen
+    pub fn render<E, V: View<E, Vec<u8>>>(&mut self, view: &mut V) {
+        let (width, height) = (self.width, self.height);
+        self.render_in(view, 1, 1, width, height);
+    }
+
+    /// Measure, layout and render a view in an area of the screen
+    pub fn render_in<E, V: View<E, Vec<u8>>>(
+        &mut self,
+        view: &mut V,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    ) {
+        let mut screen = Screen::headless(self.width, self.height);
+        let mut dimension = Dimension::new();
+        view.measure(&mut dimension, Some(width), Some(height));
+        view.layout(&mut dimension, y, x);
+        view.render(&dimension, &mut screen);
+        screen.flush().unwrap();
+        for frame in screen.frames() {
+            self.write(frame);
+        }
+    }
+
+    /// Interpret what a terminal would receive
+    pub fn write(&mut self, bytes: &[u8]) {
+        let text = String::from_utf8_lossy(bytes);
+        let mut graphemes = text.graphemes(true).peekable();
+        while let Some(grapheme) = graphemes.next() {
+            match grapheme {
+                "\x1b" => match graphemes.next() {
+                    Some("[") => {
+                        let mut params = String::new();
+                        for grapheme in graphemes.by_ref() {
+                            match grapheme.chars().next() {
+                                Some(c @ '\x40'..='\x7e') if grapheme.len() == 1 => {
+                                    self.csi(&params, c);
+                                    break;
+                                }
+                                _ => params.push_str(grapheme),
+                            }
+                        }
+                    }
+                    // Terminal graphics and other strings end with BEL or ST
+                    Some("]") | Some("_") | Some("P") => {
+                        while let Some(grapheme) = graphemes.next() {
+                            if grapheme == "\x07" {
+                                break;
+                            }
+                            if grapheme == "\x1b" && graphemes.peek() == Some(&"\\") {
+                                graphemes.next();
+                                break;
+                            }
+                        }
+                    }
+                    Some("7") => self.saved = (self.x, self.y),
+                    Some("8") => {
+                        let (x, y) = self.saved;
+                        self.x = x;
+                        self.y = y;
+                    }
+                    _ => {}
+                },
+                "\r" => self.x = 1,
+                "\n" | "\r\n" => {
+                    self.x = 1;
+                    self.y += 1;
+                }
+                grapheme if grapheme.chars().all(char::is_control) => {}
+                grapheme => {
+                    // Writing past the last column wraps to the next line, as terminals do
+                    if self.x > self.width {
+                        self.x = 1;
+                        self.y += 1;
+                    }
+                    if (1..=self.height).contains(&self.y) {
+                        self.cells[usize::from(self.y - 1)][usize::from(self.x - 1)] =
+                            (grapheme.to_string(), self.style.clone());
+                    }
+                    self.x += 1;
+                }
+            }
+        }
+    }
 
-pub type Screen<W> = AlternateScreen<RawTerminal<W>>;
+    fn csi(&mut self, params: &str, command: char) {
+        let numbers = params
+            .split(';')
+            .map(|param| param.parse::<u16>().unwrap_or(0))
+            .collect::<Vec<u16>>();
+        match command {
+            'H' | 'f' => {
+                self.y = numbers.first().cloned().unwrap_or(1).max(1);
+                self.x = numbers.get(1).cloned().unwrap_or(1).max(1);
+            }
+            'J' if params == "2" => self.clear(1, 1, self.width, self.height),
+            'J' => {
+                let (x, y) = (self.x, self.y);
+                self.clear(x, y, self.width, y);
+                self.clear(1, y + 1, self.width, self.height);
+            }
+            'K' if params == "2" => self.clear(1, self.y, self.width, self.y),
+            'K' => self.clear(self.x, self.y, self.width, self.y),
+            'm' => self.style.apply(params),
+            's' => self.saved = (self.x, self.y),
+            'u' => {
+                let (x, y) = self.saved;
+                self.x = x;
+                self.y = y;
+            }
+            _ => {}
+        }
+    }
+
+    fn clear(&mut self, left: u16, top: u16, right: u16, bottom: u16) {
+        for y in top.max(1)..=bottom.min(self.height) {
+            for x in left.max(1)..=right.min(self.width) {
+                self.cells[usize::from(y - 1)][usize::from(x - 1)] =
+                    (String::from(" "), Style::default());
+            }
+        }
+    }
+
+    /// Text of each line, without trailing spaces
+    pub fn lines(&self) -> Vec<String> {
+        (1..=self.height).map(|y| self.line(y)).collect()
+    }
+
+    /// Text of a line, starting at 1 like terminal coordinates, without trailing spaces
+    pub fn line(&self, y: u16) -> String {
+        self.cells[usize::from(y - 1)]
+            .iter()
+            .map(|(grapheme, _)| grapheme.as_str())
+            .collect::<String>()
+            .trim_end()
+            .to_string()
+    }
+
+    /// Style of a cell, starting at 1 like terminal coordinates
+    pub fn style(&self, x: u16, y: u16) -> &Style {
+        &self.cells[usize::from(y - 1)][usize::from(x - 1)].1
+    }
+}
 
 pub fn term_string_visible_len(string: &str) -> usize {
     // Count each grapheme on a given struct but ignore invisible chars sequences like '\x1b[…'
@@ -40,6 +360,8 @@ pub fn term_string_visible_len(string: &str) -> usize {
                                 break;
                             }
                         }
+                    } else if is_string_sequence(grapheme) {
+                        skip_string_sequence(&mut iter, |_| {});
                     }
                 }
             }
@@ -52,6 +374,59 @@ pub fn term_string_visible_len(string: &str) -> usize {
     len
 }
 
+/// Whether an escape sequence starting with `grapheme` is terminated by ST (APC, DCS and OSC
+/// sequences used by terminal graphics)
+fn is_string_sequence(grapheme: &str) -> bool {
+    grapheme == "_" || grapheme == "P" || grapheme == "]"
+}
+
+/// Consume a string sequence up to its terminator, giving each consumed grapheme to `consumed`
+fn skip_string_sequence<'a, I, F>(iter: &mut I, mut consumed: F)
+where
+    I: Iterator<Item = &'a str>,
+    F: FnMut(&'a str),
+{
+    let mut escape = false;
+    for grapheme in iter {
+        consumed(grapheme);
+        match grapheme {
+            "\x07" => break,
+            "\\" if escape => break,
+            "\x1b" => escape = true,
+            _ => escape = false,
+        }
+    }
+}
+
+/// Split a line between text and string sequences, flagging the latter
+fn split_string_sequences(line: &str) -> Vec<(&str, bool)> {
+    let mut segments = Vec::new();
+    let mut rest = line;
+    while let Some(start) = rest
+        .match_indices('\x1b')
+        .map(|(index, _)| index)
+        .find(|index| is_string_sequence(rest.get(index + 1..index + 2).unwrap_or("")))
+    {
+        let sequence = &rest[start + 2..];
+        let end = match (sequence.find('\x07'), sequence.find("\x1b\\")) {
+            (Some(bel), Some(st)) if st < bel => st + 2,
+            (Some(bel), _) => bel + 1,
+            (None, Some(st)) => st + 2,
+            (None, None) => sequence.len(),
+        } + start
+            + 2;
+        if start > 0 {
+            segments.push((&rest[..start], false));
+        }
+        segments.push((&rest[start..end], true));
+        rest = &rest[end..];
+    }
+    if !rest.is_empty() {
+        segments.push((rest, false));
+    }
+    segments
+}
+
 /// Remove all terminal specific chars sequences
 pub fn clean(string: &str) -> String {
     let mut output = String::new();
@@ -72,6 +447,17 @@ pub fn clean(string: &str) -> String {
                                 }
                             }
                         }
+                        '_' | 'P' | ']' => {
+                            let mut escape = false;
+                            for c in iter.by_ref() {
+                                match c {
+                                    '\x07' => break,
+                                    '\\' if escape => break,
+                                    '\x1b' => escape = true,
+                                    _ => escape = false,
+                                }
+                            }
+                        }
                         _ => output.push(c),
                     }
                 }
@@ -98,7 +484,9 @@ pub fn term_string_visible_truncate(string: &str, max: usize, append: Option<&st
             "\x1b" => {
                 if let Some(grapheme) = iter.next() {
                     output.push_str(grapheme);
-                    if grapheme == "[" {
+                    if is_string_sequence(grapheme) {
+                        skip_string_sequence(&mut iter, |grapheme| output.push_str(grapheme));
+                    } else if grapheme == "[" {
                         while let Some(grapheme) = iter.next() {
                             output.push_str(grapheme);
                             let chars = grapheme.chars().collect::<Vec<_>>();
@@ -152,6 +540,8 @@ pub enum LayoutBehavior {
     MatchParent,
     WrapContent(LayoutConstraints),
     Absolute(u16),
+    /// Share of the parent, given by it as the spec of the view
+    Percent(u16),
 }
 
 #[derive(Debug, Clone)]
@@ -181,6 +571,12 @@ impl Layout {
         }
     }
 
+    pub fn percent(value: u16) -> Self {
+        Self {
+            behavior: LayoutBehavior::Percent(value),
+        }
+    }
+
     #[allow(dead_code)]
     pub fn with_absolute_max(mut self, value: u16) -> Self {
         if let LayoutBehavior::WrapContent(ref mut constraint) = self.behavior {
@@ -261,6 +657,11 @@ pub fn apply_constraints(value: u16, parent: Option<u16>, constraints: &LayoutCo
     cmp::min(max, value)
 }
 
+/// Percentage of a size, rounded down
+fn share(size: u16, percent: u16) -> u16 {
+    (size as u32 * cmp::min(percent, 100) as u32 / 100) as u16
+}
+
 #[derive(Debug, Clone)]
 pub struct Layouts {
     pub width: Layout,
@@ -305,7 +706,7 @@ where
     ) {
         let layout = self.get_layouts();
         dimension.w = match layout.width.behavior {
-            LayoutBehavior::MatchParent => width_spec,
+            LayoutBehavior::MatchParent | LayoutBehavior::Percent(_) => width_spec,
             LayoutBehavior::WrapContent(_) => unreachable!(),
             LayoutBehavior::Absolute(width) => match width_spec {
                 Some(width_spec) => Some(cmp::min(width, width_spec)),
@@ -314,7 +715,7 @@ where
         };
 
         dimension.h = match layout.height.behavior {
-            LayoutBehavior::MatchParent => height_spec,
+            LayoutBehavior::MatchParent | LayoutBehavior::Percent(_) => height_spec,
             LayoutBehavior::WrapContent(_) => unreachable!(),
             LayoutBehavior::Absolute(height) => match height_spec {
                 Some(height_spec) => Some(cmp::min(height, height_spec)),
@@ -353,6 +754,12 @@ where
         }
     }
 
+    /// If this view is shown given the width its parent can give to it, hidden views being
+    /// measured empty
+    fn is_visible(&self, _width_spec: Option<u16>) -> bool {
+        true
+    }
+
     /// If this view requires to be rendered
     fn is_dirty(&self) -> bool;
 
@@ -707,7 +1114,7 @@ where
          */
         let layouts = self.get_layouts();
         let max_width = match layouts.width.behavior {
-            LayoutBehavior::MatchParent => width_spec,
+            LayoutBehavior::MatchParent | LayoutBehavior::Percent(_) => width_spec,
             LayoutBehavior::WrapContent(_) => width_spec,
             LayoutBehavior::Absolute(width) => match width_spec {
                 Some(width_spec) => Some(cmp::min(width, width_spec)),
@@ -716,7 +1123,7 @@ where
         };
 
         let max_height = match layouts.height.behavior {
-            LayoutBehavior::MatchParent => height_spec,
+            LayoutBehavior::MatchParent | LayoutBehavior::Percent(_) => height_spec,
             LayoutBehavior::WrapContent(_) => height_spec,
             LayoutBehavior::Absolute(height) => match height_spec {
                 Some(height_spec) => Some(cmp::min(height, height_spec)),
@@ -727,29 +1134,32 @@ where
         let mut min_width = 0;
         let mut min_height = 0;
         for (child_dimension, child_view) in self.children.iter_mut() {
+            if !child_view.is_visible(max_width) {
+                child_dimension.w = Some(0);
+                child_dimension.h = Some(0);
+                continue;
+            }
             child_view.measure(child_dimension, None, None);
             let child_layouts = child_view.get_layouts();
-            let requested_width = match &child_layouts {
-                Layouts {
-                    width:
-                        Layout {
-                            behavior: LayoutBehavior::WrapContent(constraints),
-                            ..
-                        },
-                    ..
-                } => apply_constraints(child_dimension.w.unwrap_or(0), max_width, constraints),
+            let requested_width = match &child_layouts.width.behavior {
+                LayoutBehavior::WrapContent(constraints) => {
+                    apply_constraints(child_dimension.w.unwrap_or(0), max_width, constraints)
+                }
+                LayoutBehavior::Percent(percent) => {
+                    child_dimension.w = max_width.map(|max_width| share(max_width, *percent));
+                    child_dimension.w.unwrap_or(0)
+                }
                 _ => child_dimension.w.unwrap_or(0),
             };
 
-            let requested_height = match &child_layouts {
-                Layouts {
-                    height:
-                        Layout {
-                            behavior: LayoutBehavior::WrapContent(constraints),
-                            ..
-                        },
-                    ..
-                } => apply_constraints(child_dimension.h.unwrap_or(0), max_height, constraints),
+            let requested_height = match &child_layouts.height.behavior {
+                LayoutBehavior::WrapContent(constraints) => {
+                    apply_constraints(child_dimension.h.unwrap_or(0), max_height, constraints)
+                }
+                LayoutBehavior::Percent(percent) => {
+                    child_dimension.h = max_height.map(|max_height| share(max_height, *percent));
+                    child_dimension.h.unwrap_or(0)
+                }
                 _ => child_dimension.h.unwrap_or(0),
             };
 
@@ -807,6 +1217,10 @@ where
         dimension.h = Some(0);
 
         for (child_dimension, child_view) in self.children.iter_mut() {
+            if !child_view.is_visible(max_width) {
+                continue;
+            }
+
             let mut width_spec = match child_dimension.w {
                 Some(w) => Some(w),
                 None => splitted_width,
@@ -873,7 +1287,8 @@ where
 
     fn render(&mut self, _dimension: &Dimension, screen: &mut Screen<W>) {
         for (child_dimension, child_view) in self.children.iter_mut() {
-            if self.dirty || child_view.is_dirty() {
+            let hidden = child_dimension.w == Some(0) || child_dimension.h == Some(0);
+            if !hidden && (self.dirty || child_view.is_dirty()) {
                 child_view.render(child_dimension, screen);
             }
         }
@@ -923,12 +1338,88 @@ where
     }
 }
 
+/// Lines validated in an input, browsed back from the last one
+#[derive(Default)]
+pub struct History {
+    pub lines: Vec<String>,
+    /// Line browsed to, the length of `lines` once browsing is over
+    index: usize,
+}
+
+impl History {
+    pub fn new(lines: Vec<String>) -> Self {
+        Self {
+            index: lines.len(),
+            lines,
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        self.lines.push(line);
+        self.index = self.lines.len();
+    }
+}
+
+/// Kills kept in the kill ring, older ones being dropped
+const KILL_RING_LEN: usize = 10;
+
+/// Text deleted from an input, yanked back from the last one
+#[derive(Default)]
+pub struct KillRing {
+    kills: Vec<String>,
+    /// Kill yanked next, rotated back by yanking again over the previous one
+    index: usize,
+}
+
+impl KillRing {
+    fn push(&mut self, text: String) {
+        self.kills.push(text);
+        if self.kills.len() > KILL_RING_LEN {
+            self.kills.remove(0);
+        }
+        self.index = self.kills.len() - 1;
+    }
+
+    /// Add text to the last kill, before it when killed backward
+    fn extend(&mut self, text: String, backward: bool) {
+        match self.kills.last_mut() {
+            Some(last) if backward => last.insert_str(0, &text),
+            Some(last) => last.push_str(&text),
+            None => return self.push(text),
+        }
+        self.index = self.kills.len() - 1;
+    }
+
+    fn current(&self) -> Option<String> {
+        self.kills.get(self.index).cloned()
+    }
+
+    fn rotate(&mut self) -> Option<String> {
+        self.index = match self.index {
+            0 => self.kills.len().checked_sub(1)?,
+            index => index - 1,
+        };
+        self.current()
+    }
+}
+
+/// Last kill or yank, continued by the next one as long as the input is left as it was
+enum Edit {
+    Kill,
+    /// Yanked text, from the start given to the cursor
+    Yank(Cursor),
+}
+
 pub struct Input<E> {
     pub buf: String,
     pub tmp_buf: Option<String>,
     pub password: bool,
-    pub history: Vec<String>,
-    pub history_index: usize,
+    /// Messages validated, browsed with Up and Down
+    pub history: History,
+    /// Commands validated, browsed apart from messages
+    pub command_history: History,
+    pub kill_ring: KillRing,
+    last_edit: Option<(Edit, String, Cursor)>,
     // Used to index code points in buf (don't use it to directly index buf)
     pub cursor: Cursor,
     // start index (in code points) of the view inside the buffer
@@ -950,8 +1441,10 @@ impl<E> Input<E> {
             buf: String::new(),
             tmp_buf: None,
             password: false,
-            history: Vec::new(),
-            history_index: 0,
+            history: History::default(),
+            command_history: History::default(),
+            kill_ring: KillRing::default(),
+            last_edit: None,
             cursor: Cursor::new(0),
             view: Cursor::new(0),
             event_handler: None,
@@ -1039,27 +1532,73 @@ impl<E> Input<E> {
             word_start -= 1;
         }
 
-        self.buf.replace_range(
-            word_start.index(&self.buf)..self.cursor.index(&self.buf),
-            "",
-        );
-        self.cursor = word_start;
-        if !self.password {
-            self.dirty = true;
-        }
+        let end = self.cursor.clone();
+        self.kill(word_start, end);
     }
 
     pub fn delete_from_cursor_to_start(&mut self) {
-        self.buf.replace_range(0..self.cursor.index(&self.buf), "");
-        self.cursor = Cursor::new(0);
+        let end = self.cursor.clone();
+        self.kill(Cursor::new(0), end);
         self.view = Cursor::new(0);
+    }
+
+    pub fn delete_from_cursor_to_end(&mut self) {
+        let start = self.cursor.clone();
+        self.kill(start, Cursor::new(self.buf.graphemes(true).count()));
+    }
+
+    /// The last kill or yank, if nothing changed the input since
+    fn last_edit(&self) -> Option<&Edit> {
+        match &self.last_edit {
+            Some((edit, buf, cursor)) if buf == &self.buf && cursor == &self.cursor => Some(edit),
+            _ => None,
+        }
+    }
+
+    /// Delete text and keep it in the kill ring, successive kills making a single one. Passwords
+    /// are never kept.
+    fn kill(&mut self, start: Cursor, end: Cursor) {
+        let range = start.index(&self.buf)..end.index(&self.buf);
+        let killed = self.buf[range.clone()].to_string();
+        if !self.password && !killed.is_empty() {
+            match self.last_edit() {
+                Some(Edit::Kill) => self.kill_ring.extend(killed, self.cursor > start),
+                _ => self.kill_ring.push(killed),
+            }
+        }
+        self.buf.replace_range(range, "");
+        self.cursor = start;
+        self.last_edit = Some((Edit::Kill, self.buf.clone(), self.cursor.clone()));
         if !self.password {
             self.dirty = true;
         }
     }
 
-    pub fn delete_from_cursor_to_end(&mut self) {
-        self.buf.replace_range(self.cursor.index(&self.buf).., "");
+    /// Insert the last kill at the cursor
+    pub fn yank(&mut self) {
+        if let Some(text) = self.kill_ring.current() {
+            let start = self.cursor.clone();
+            self.insert_yank(start, &text);
+        }
+    }
+
+    /// Replace the text just yanked by the kill before it
+    pub fn yank_pop(&mut self) {
+        let start = match self.last_edit() {
+            Some(Edit::Yank(start)) => start.clone(),
+            _ => return,
+        };
+        if let Some(text) = self.kill_ring.rotate() {
+            let range = start.index(&self.buf)..self.cursor.index(&self.buf);
+            self.buf.replace_range(range, "");
+            self.insert_yank(start, &text);
+        }
+    }
+
+    fn insert_yank(&mut self, start: Cursor, text: &str) {
+        self.buf.insert_str(start.index(&self.buf), text);
+        self.cursor = &start + text.graphemes(true).count();
+        self.last_edit = Some((Edit::Yank(start), self.buf.clone(), self.cursor.clone()));
         if !self.password {
             self.dirty = true;
         }
@@ -1100,6 +1639,9 @@ impl<E> Input<E> {
     }
 
     pub fn clear(&mut self) {
+        if self.password {
+            self.buf.zeroize();
+        }
         self.buf.clear();
         self.cursor = Cursor::new(0);
         self.view = Cursor::new(0);
@@ -1132,41 +1674,64 @@ impl<E> Input<E> {
     }
 
     pub fn validate(&mut self) -> (String, bool) {
-        if !self.password {
-            self.history.push(self.buf.clone());
-            self.history_index = self.history.len();
+        if !self.password && !self.buf.is_empty() {
+            match self.buf.starts_with('/') {
+                true => self.command_history.push(self.buf.clone()),
+                false => self.history.push(self.buf.clone()),
+            }
         }
-        let buf = self.buf.clone();
+        self.history.index = self.history.lines.len();
+        self.command_history.index = self.command_history.lines.len();
+        let buf = std::mem::take(&mut self.buf);
         let password = self.password;
         self.clear();
         (buf, password)
     }
 
     pub fn previous(&mut self) {
-        if self.history_index == 0 {
-            return;
-        }
+        self.browse(false, true);
+    }
 
-        if self.tmp_buf.is_none() {
-            self.tmp_buf = Some(self.buf.clone());
-        }
+    pub fn next(&mut self) {
+        self.browse(false, false);
+    }
 
-        self.history_index -= 1;
-        self.buf = self.history[self.history_index].clone();
-        self.end();
-        self.dirty = true;
+    pub fn previous_command(&mut self) {
+        self.browse(true, true);
     }
 
-    pub fn next(&mut self) {
-        if self.history_index == self.history.len() {
-            return;
-        }
+    pub fn next_command(&mut self) {
+        self.browse(true, false);
+    }
 
-        self.history_index += 1;
-        if self.history_index == self.history.len() {
-            self.buf = self.tmp_buf.take().unwrap();
+    /// Show the previous or next line of the history of messages or commands, what was typed
+    /// before browsing coming back after the last line
+    fn browse(&mut self, commands: bool, backward: bool) {
+        let browsing = self.history.index < self.history.lines.len()
+            || self.command_history.index < self.command_history.lines.len();
+        let history = match commands {
+            true => &mut self.command_history,
+            false => &mut self.history,
+        };
+        if backward {
+            if history.index == 0 {
+                return;
+            }
+            history.index -= 1;
+            let line = history.lines[history.index].clone();
+            let typed = std::mem::replace(&mut self.buf, line);
+            if !browsing {
+                self.tmp_buf = Some(typed);
+            }
         } else {
-            self.buf = self.history[self.history_index].clone();
+            if history.index == history.lines.len() {
+                return;
+            }
+            history.index += 1;
+            self.buf = match history.lines.get(history.index) {
+                Some(line) => line.clone(),
+                None => self.tmp_buf.clone().unwrap_or_default(),
+            };
         }
         self.end();
         self.dirty = true;
@@ -1183,7 +1748,6 @@ where
             true => {
                 goto!(screen, dimension.x, dimension.y);
                 vprint!(screen, "password: ");
-                flush!(screen);
 
                 self.dirty = false;
             }
@@ -1215,11 +1779,10 @@ where
                 }
 
                 goto!(screen, dimension.x, dimension.y);
-                vprint!(screen, "{}", buf);
+                // Multi line input is displayed on a single line
+                vprint!(screen, "{}", buf.replace('\n', "↵"));
                 goto!(screen, dimension.x + cursor.get() as u16, dimension.y);
 
-                flush!(screen);
-
                 self.dirty = false;
             }
         }
@@ -1256,11 +1819,21 @@ where
     T: fmt::Display + Hash + Eq + Ord,
 {
     fn insert(&mut self, item: T);
+    #[allow(dead_code)]
     fn send_message(&self);
     /// PageUp the window, return true if top is reached
     fn page_up(&mut self) -> bool;
     /// PageDown the window, return true if bottom is reached
     fn page_down(&mut self) -> bool;
+    /// Select the item before the selected one, or the last one if none is selected
+    fn select_previous(&mut self);
+    /// Select the item after the selected one, nothing is selected after the last one
+    fn select_next(&mut self);
+    /// Select the given item, false when it isn't in the window
+    fn select(&mut self, item: &T) -> bool;
+    fn selected(&self) -> Option<&T>;
+    /// Remove an item, keeping the same item selected if it isn't the removed one
+    fn remove(&mut self, item: &T);
 }
 
 pub struct BufferedWin<E, W, I>
@@ -1272,11 +1845,29 @@ where
     pub view: usize,
     pub event_handler: Option<Rc<RefCell<Box<dyn FnMut(&mut Self, &mut E)>>>>,
     pub dirty: bool,
+    /// Indentation of wrapped lines for a given item
+    hanging_indent: Option<fn(&I) -> usize>,
+    /// Index in history of the selected item
+    selected: Option<usize>,
+    /// Draw a scrollbar in the last column
+    scrollbar: bool,
     width: usize,
     height: usize,
     layouts: Layouts,
 }
 
+/// Rows of the scrollbar thumb, from the top, for `count` lines shown `height` at a time while
+/// scrolled up by `view` lines. None when all lines fit.
+fn scrollbar_thumb(count: usize, height: usize, view: usize) -> Option<(usize, usize)> {
+    if count <= height || height == 0 {
+        return None;
+    }
+    let size = cmp::max(1, height * height / count);
+    let hidden = count - height;
+    let top = (hidden - cmp::min(view, hidden)) * (height - size) / hidden;
+    Some((top, top + size))
+}
+
 impl<E, W, I> BufferedWin<E, W, I>
 where
     I: fmt::Display + Hash + Eq + Ord,
@@ -1288,6 +1879,9 @@ where
             view: 0,
             event_handler: None,
             dirty: true,
+            hanging_indent: None,
+            selected: None,
+            scrollbar: false,
             width: 0,
             height: 0,
             layouts: Layouts {
@@ -1311,103 +1905,182 @@ where
         self
     }
 
+    /// Start scrolled up by the given number of lines, kept until there are enough of them
+    pub fn with_view(mut self, view: usize) -> Self {
+        self.view = view;
+        self
+    }
+
+    pub fn with_hanging_indent(mut self, hanging_indent: fn(&I) -> usize) -> Self {
+        self.hanging_indent = Some(hanging_indent);
+        self
+    }
+
+    /// Keep the last column for a scrollbar, drawn when there are more lines than fit
+    pub fn with_scrollbar(mut self, scrollbar: bool) -> Self {
+        self.scrollbar = scrollbar;
+        self
+    }
+
     fn get_rendered_items(&self) -> Vec<String> {
+        self.render_items().0
+    }
+
+    /// Render items as lines, along with the range of lines of the selected item
+    fn render_items(&self) -> (Vec<String>, Option<(usize, usize)>) {
         let max_len = self.width;
         let mut buffers: Vec<String> = Vec::new();
-
-        for buf in &self.history {
+        let mut selected_lines = None;
+
+        for (index, buf) in self.history.iter().enumerate() {
+            let selected = self.selected == Some(index);
+            let first_line = buffers.len();
+            let indent = match &self.hanging_indent {
+                // Keep some room for the text itself
+                Some(hanging_indent) => match hanging_indent(buf) {
+                    indent if indent < max_len / 2 => indent,
+                    _ => 0,
+                },
+                None => 0,
+            };
             let formatted = format!("{}", buf);
             for line in formatted.lines() {
-                let mut words = line.split_word_bounds();
-
                 let mut line_len = 0;
                 let mut chunk = String::new();
-                while let Some(word) = words.next() {
-                    let visible_word;
-                    let mut remaining = String::new();
-
-                    // We can safely unwrap here because split_word_bounds produce non empty words
-                    let first_char = word.chars().next().unwrap();
-
-                    if first_char == '\x1b' {
-                        // Handle Escape sequence: see https://www.ecma-international.org/publications/files/ECMA-ST/Ecma-048.pdf
-                        // First char is a word boundary
-                        //
-                        // We must ignore them for the visible length count but include them in the
-                        // final chunk that will be written to the terminal
-
-                        if let Some(word) = words.next() {
-                            match word {
-                                "[" => {
-                                    // Control Sequence Introducer are accepted and can safely be
-                                    // written to terminal
-                                    let mut escape = String::from("\x1b[");
-                                    let mut end = false;
-
-                                    while let Some(word) = words.next() {
-                                        for c in word.chars() {
-                                            // Push all char belonging to escape sequence
-                                            // but keep remaining for wrap computation
-                                            if !end {
-                                                escape.push(c);
-                                                match c {
-                                                    '\x30'..='\x3f' => {} // parameter bytes
-                                                    '\x20'..='\x2f' => {} // intermediate bytes
-                                                    '\x40'..='\x7e' => {
-                                                        // final byte
-                                                        chunk.push_str(&escape);
-                                                        end = true;
-                                                    }
-                                                    _ => {
-                                                        // Invalid escape sequence, just ignore it
-                                                        end = true;
+                // Spaces the line was wrapped on are not carried over to the next one
+                let mut wrapped = false;
+                for (segment, is_sequence) in split_string_sequences(line) {
+                    if is_sequence {
+                        // Terminal graphics take no room in the text and are written as is
+                        chunk.push_str(segment);
+                        continue;
+                    }
+
+                    let mut words = segment.split_word_bounds();
+                    while let Some(word) = words.next() {
+                        let visible_word;
+                        let mut remaining = String::new();
+
+                        // We can safely unwrap here because split_word_bounds produce non empty words
+                        let first_char = word.chars().next().unwrap();
+
+                        if first_char == '\x1b' {
+                            // Handle Escape sequence: see https://www.ecma-international.org/publications/files/ECMA-ST/Ecma-048.pdf
+                            // First char is a word boundary
+                            //
+                            // We must ignore them for the visible length count but include them in the
+                            // final chunk that will be written to the terminal
+
+                            if let Some(word) = words.next() {
+                                match word {
+                                    "[" => {
+                                        // Control Sequence Introducer are accepted and can safely be
+                                        // written to terminal
+                                        let mut escape = String::from("\x1b[");
+                                        let mut end = false;
+
+                                        while let Some(word) = words.next() {
+                                            for c in word.chars() {
+                                                // Push all char belonging to escape sequence
+                                                // but keep remaining for wrap computation
+                                                if !end {
+                                                    escape.push(c);
+                                                    match c {
+                                                        '\x30'..='\x3f' => {} // parameter bytes
+                                                        '\x20'..='\x2f' => {} // intermediate bytes
+                                                        '\x40'..='\x7e' => {
+                                                            // final byte
+                                                            chunk.push_str(&escape);
+                                                            end = true;
+                                                        }
+                                                        _ => {
+                                                            // Invalid escape sequence, just ignore it
+                                                            end = true;
+                                                        }
                                                     }
+                                                } else {
+                                                    remaining.push(c);
                                                 }
-                                            } else {
-                                                remaining.push(c);
                                             }
-                                        }
 
-                                        if end {
-                                            break;
+                                            if end {
+                                                break;
+                                            }
                                         }
                                     }
+                                    _ => {
+                                        // Other sequence are not handled and just ignored
+                                    }
                                 }
-                                _ => {
-                                    // Other sequence are not handled and just ignored
-                                }
+                            } else {
+                                // Nothing is following the escape char
+                                // We can simply ignore it
                             }
+                            visible_word = remaining.as_str();
                         } else {
-                            // Nothing is following the escape char
-                            // We can simply ignore it
+                            visible_word = word;
                         }
-                        visible_word = remaining.as_str();
-                    } else {
-                        visible_word = word;
-                    }
 
-                    if visible_word.len() == 0 {
-                        continue;
-                    }
+                        if visible_word.len() == 0 {
+                            continue;
+                        }
 
-                    let grapheme_count = visible_word.graphemes(true).count();
+                        let grapheme_count = visible_word.graphemes(true).count();
 
-                    if line_len + grapheme_count > max_len {
-                        // Wrap line
-                        buffers.push(chunk);
-                        chunk = String::new();
-                        line_len = 0;
-                    }
+                        if line_len + grapheme_count > max_len {
+                            // Wrap line
+                            buffers.push(chunk);
+                            chunk = " ".repeat(indent);
+                            line_len = indent;
+                            wrapped = true;
+                        }
+
+                        if wrapped && visible_word.trim().is_empty() {
+                            continue;
+                        }
+                        wrapped = false;
 
-                    chunk.push_str(visible_word);
-                    line_len += grapheme_count;
+                        chunk.push_str(visible_word);
+                        line_len += grapheme_count;
+                    }
                 }
 
                 buffers.push(chunk);
             }
+
+            if selected {
+                for line in &mut buffers[first_line..] {
+                    *line = format!(
+                        "{}{}{}",
+                        termion::style::Invert,
+                        line,
+                        termion::style::NoInvert
+                    );
+                }
+                selected_lines = Some((first_line, buffers.len()));
+            }
+        }
+
+        (buffers, selected_lines)
+    }
+
+    /// Scroll so that the selected item is visible
+    fn scroll_to_selected(&mut self) {
+        let (buffers, selected_lines) = self.render_items();
+        let count = buffers.len();
+        if count <= self.height {
+            self.view = 0;
+            return;
         }
 
-        buffers
+        // Visible lines are the ones from count - height - view to count - view
+        if let Some((first, last)) = selected_lines {
+            if last > count - self.view {
+                self.view = count - last;
+            } else if first + self.height + self.view < count {
+                self.view = count - self.height - first;
+            }
+        }
     }
 
     #[allow(dead_code)]
@@ -1425,14 +2098,74 @@ where
         // We don't care about rendered buffer, we avoid computation here at cost of false positive
         // (set dirty while in fact it shouldn't)
         let len = self.history.len();
-        let position = len
-            - self
-                .history
-                .iter()
-                .position(|iter| iter > &item)
-                .unwrap_or(self.history.len());
-        self.history.replace(item);
-        self.dirty |= position >= self.view && position <= self.view + self.height;
+        let index = self
+            .history
+            .iter()
+            .position(|iter| iter > &item)
+            .unwrap_or(self.history.len());
+        let position = len - index;
+        let replaced = self.history.replace(item).is_some();
+        // Keep the same item selected
+        if let Some(selected) = self.selected {
+            if !replaced && index <= selected {
+                self.selected = Some(selected + 1);
+            }
+        }
+        // The scrollbar changes whatever the position of the item
+        self.dirty |=
+            self.scrollbar || (position >= self.view && position <= self.view + self.height);
+    }
+
+    fn select_previous(&mut self) {
+        if self.history.is_empty() {
+            return;
+        }
+
+        self.selected = match self.selected {
+            Some(selected) => Some(selected.saturating_sub(1)),
+            None => Some(self.history.len() - 1),
+        };
+        self.scroll_to_selected();
+        self.dirty = true;
+    }
+
+    fn select_next(&mut self) {
+        self.selected = match self.selected {
+            Some(selected) if selected + 1 < self.history.len() => Some(selected + 1),
+            _ => None,
+        };
+        self.scroll_to_selected();
+        self.dirty = true;
+    }
+
+    fn select(&mut self, item: &I) -> bool {
+        match self.history.iter().position(|iter| iter == item) {
+            Some(index) => {
+                self.selected = Some(index);
+                self.scroll_to_selected();
+                self.dirty = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn selected(&self) -> Option<&I> {
+        self.history.iter().nth(self.selected?)
+    }
+
+    fn remove(&mut self, item: &I) {
+        let index = match self.history.iter().position(|iter| iter == item) {
+            Some(index) => index,
+            None => return,
+        };
+        self.history.remove(item);
+        self.selected = match self.selected {
+            Some(selected) if selected == index => None,
+            Some(selected) if selected > index => Some(selected - 1),
+            selected => selected,
+        };
+        self.dirty = true;
     }
 
     fn page_up(&mut self) -> bool {
@@ -1477,7 +2210,10 @@ where
 {
     fn render(&mut self, dimension: &Dimension, screen: &mut Screen<W>) {
         save_cursor!(screen);
-        self.width = dimension.w.unwrap() as usize;
+        self.width = match self.scrollbar {
+            true => (dimension.w.unwrap() as usize).saturating_sub(1),
+            false => dimension.w.unwrap() as usize,
+        };
         self.height = dimension.h.unwrap() as usize;
 
         self.next_line = 0;
@@ -1487,7 +2223,9 @@ where
         let mut iter = buffers.iter();
 
         if count > dimension.h.unwrap() as usize {
-            for _ in 0..count - dimension.h.unwrap() as usize - self.view {
+            // The view can be further than the history while it's loaded
+            let view = cmp::min(self.view, count - dimension.h.unwrap() as usize);
+            for _ in 0..count - dimension.h.unwrap() as usize - view {
                 if iter.next().is_none() {
                     break;
                 }
@@ -1507,8 +2245,21 @@ where
             }
         }
 
+        if let Some((top, bottom)) = match self.scrollbar {
+            true => scrollbar_thumb(count, self.height, self.view),
+            false => None,
+        } {
+            let x = dimension.x + dimension.w.unwrap() - 1;
+            for row in 0..self.height {
+                goto!(screen, x, dimension.y + row as u16);
+                match row >= top && row < bottom {
+                    true => vprint!(screen, "█"),
+                    false => vprint!(screen, "│"),
+                }
+            }
+        }
+
         restore_cursor!(screen);
-        flush!(screen);
 
         self.dirty = false;
     }
@@ -1541,8 +2292,14 @@ where
     #[allow(dead_code)]
     sort_group: Option<Box<dyn FnMut(&G, &G) -> cmp::Ordering>>,
     event_handler: Option<Rc<RefCell<Box<dyn FnMut(&mut Self, &mut E)>>>>,
-    dirty: bool,
+    pub dirty: bool,
     layouts: Layouts,
+    /// Width of the parent under which the list is hidden
+    hidden_below: Option<u16>,
+    /// Shown or hidden whatever the width of the parent
+    visible: Option<bool>,
+    /// Dimension changed, the parent measuring it again
+    relayout: bool,
 }
 
 impl<E, W, G, V> ListView<E, W, G, V>
@@ -1562,9 +2319,30 @@ where
                 width: Layout::match_parent(),
                 height: Layout::match_parent(),
             },
+            hidden_below: None,
+            visible: None,
+            relayout: false,
         }
     }
 
+    pub fn with_hidden_below(mut self, width: u16) -> Self {
+        self.hidden_below = Some(width);
+        self
+    }
+
+    /// Force the list to be shown or hidden, None going back to depend on the width
+    pub fn set_visible(&mut self, visible: Option<bool>) {
+        self.visible = visible;
+        self.relayout = true;
+        self.dirty = true;
+    }
+
+    pub fn set_layouts(&mut self, layouts: Layouts) {
+        self.layouts = layouts;
+        self.relayout = true;
+        self.dirty = true;
+    }
+
     pub fn with_event<F>(mut self, event_handler: F) -> Self
     where
         F: FnMut(&mut Self, &mut E) + 'static,
@@ -1679,7 +2457,7 @@ where
     ) {
         let layouts = self.get_layouts();
         dimension.w = match layouts.width.behavior {
-            LayoutBehavior::MatchParent => width_spec,
+            LayoutBehavior::MatchParent | LayoutBehavior::Percent(_) => width_spec,
             LayoutBehavior::WrapContent(_) => {
                 let mut width: u16 = 0;
                 for (group, items) in &self.items {
@@ -1713,7 +2491,7 @@ where
         };
 
         dimension.h = match layouts.height.behavior {
-            LayoutBehavior::MatchParent => height_spec,
+            LayoutBehavior::MatchParent | LayoutBehavior::Percent(_) => height_spec,
             LayoutBehavior::WrapContent(_) => {
                 let mut height: u16 = 0;
                 for (group, items) in &self.items {
@@ -1752,7 +2530,7 @@ where
         }
 
         for (group, items) in &self.items {
-            if y > dimension.y + dimension.h.unwrap() {
+            if y >= dimension.y + dimension.h.unwrap() {
                 break;
             }
 
@@ -1773,7 +2551,7 @@ where
             }
 
             for item in items {
-                if y > dimension.y + dimension.h.unwrap() {
+                if y >= dimension.y + dimension.h.unwrap() {
                     break;
                 }
 
@@ -1793,7 +2571,6 @@ where
         }
 
         restore_cursor!(screen);
-        flush!(screen);
 
         self.dirty = false;
     }
@@ -1806,6 +2583,26 @@ where
         }
     }
 
+    fn is_visible(&self, width_spec: Option<u16>) -> bool {
+        match (self.visible, self.hidden_below, width_spec) {
+            (Some(visible), _, _) => visible,
+            (None, Some(hidden_below), Some(width)) => width >= hidden_below,
+            _ => true,
+        }
+    }
+
+    fn layout(&mut self, dimension: &mut Dimension, top: u16, left: u16) {
+        dimension.x = left;
+        dimension.y = top;
+        self.relayout = false;
+    }
+
+    fn is_layout_dirty(&self) -> bool {
+        let wraps_content = matches!(self.layouts.width.behavior, LayoutBehavior::WrapContent(_))
+            || matches!(self.layouts.height.behavior, LayoutBehavior::WrapContent(_));
+        self.relayout || (wraps_content && self.dirty)
+    }
+
     fn is_dirty(&self) -> bool {
         self.dirty
     }
@@ -1885,6 +2682,57 @@ mod tests {
         assert_eq!(input.buf, "ab".to_string());
     }
 
+    #[test]
+    fn test_input_browses_commands_apart_from_messages() {
+        // Given
+        let mut input = Input::<()>::new();
+        input.width = 80;
+        for line in ["/join room@example.org", "hello", "/me waves", "bye"].iter() {
+            input.buf = line.to_string();
+            input.validate();
+        }
+        input.buf = String::from("draft");
+
+        // When
+        input.previous_command();
+        input.previous_command();
+        let command = input.buf.clone();
+        input.previous();
+        let message = input.buf.clone();
+        input.next();
+        input.next_command();
+        input.next_command();
+
+        // Then
+        assert_eq!(command, "/join room@example.org");
+        assert_eq!(message, "bye");
+        assert_eq!(input.buf, "draft");
+    }
+
+    #[test]
+    fn test_input_yanks_successive_kills_as_one() {
+        // Given
+        let mut input = Input::<()>::new();
+        input.buf = String::from("hello dear world");
+        input.cursor = Cursor::new(11);
+        input.delete_from_cursor_to_end();
+        input.backward_delete_word();
+        input.backward_delete_word();
+        input.buf.push_str("bye ");
+        input.cursor = Cursor::new(4);
+        input.delete_from_cursor_to_start();
+
+        // When
+        input.yank();
+        let yanked = input.buf.clone();
+        input.yank_pop();
+
+        // Then
+        assert_eq!(yanked, "bye ");
+        assert_eq!(input.buf, "hello dear world");
+        assert_eq!(input.cursor, Cursor::new(16));
+    }
+
     #[test]
     fn test_term_string_clean() {
         // Given
@@ -1920,4 +2768,274 @@ mod tests {
         // Then
         assert_eq!(truncated, "test …");
     }
+
+    /// Terminal accepting a few bytes at a time, failing once as if it was busy
+    struct BusyTerminal {
+        written: Vec<u8>,
+        busy: bool,
+    }
+
+    impl Write for BusyTerminal {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.len() >= 3 && !self.busy {
+                self.busy = true;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let written = cmp::min(buf.len(), 3);
+            self.written.extend_from_slice(&buf[..written]);
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_frame_not_written_is_kept_for_the_next_flush() {
+        // Given
+        let mut terminal = BusyTerminal {
+            written: Vec::new(),
+            busy: false,
+        };
+        let mut pending = b"frame".to_vec();
+
+        // When
+        let failed = write_pending(&mut terminal, &mut pending);
+        let retried = write_pending(&mut terminal, &mut pending);
+
+        // Then
+        assert!(failed.is_err());
+        assert!(retried.is_ok());
+        assert_eq!(terminal.written, b"frame".to_vec());
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_buffered_win_hanging_indent() {
+        // Given
+        let mut win = BufferedWin::<(), MockWriter, String>::new().with_hanging_indent(|_| 4);
+        win.width = 12;
+
+        // When
+        win.insert("bob: one two three".to_string());
+
+        // Then
+        assert_eq!(
+            win.get_rendered_items(),
+            vec!["bob: one two".to_string(), "    three".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_buffered_win_keeps_graphics_sequences() {
+        // Given
+        let mut win = BufferedWin::<(), MockWriter, String>::new();
+        win.width = 9;
+
+        // When
+        win.insert("one \x1b_Ga=T;AAAA\x1b\\  two three".to_string());
+
+        // Then
+        assert_eq!(
+            win.get_rendered_items(),
+            vec![
+                "one \x1b_Ga=T;AAAA\x1b\\  two".to_string(),
+                "three".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_buffered_win_selection() {
+        // Given
+        let mut win = BufferedWin::<(), MockWriter, String>::new();
+        win.width = 12;
+        win.height = 1;
+        win.insert("b".to_string());
+        win.insert("c".to_string());
+
+        // When
+        win.select_previous();
+        win.select_previous();
+        win.insert("a".to_string());
+
+        // Then
+        assert_eq!(win.selected(), Some(&"b".to_string()));
+        assert_eq!(win.view, 1);
+        assert_eq!(
+            win.get_rendered_items(),
+            vec![
+                "a".to_string(),
+                format!("{}b{}", termion::style::Invert, termion::style::NoInvert),
+                "c".to_string()
+            ]
+        );
+
+        // When
+        win.select_next();
+        win.select_next();
+
+        // Then
+        assert_eq!(win.selected(), None);
+
+        // When
+        let found = win.select(&"a".to_string());
+
+        // Then
+        assert!(found);
+        assert_eq!(win.selected(), Some(&"a".to_string()));
+        assert!(!win.select(&"z".to_string()));
+    }
+
+    #[test]
+    fn test_buffered_win_wraps_long_lines() {
+        // Given
+        let mut screen = TestScreen::new(12, 4);
+        let mut win = BufferedWin::<(), Vec<u8>, String>::new();
+
+        // When
+        win.insert(String::from("Hello wonderful world"));
+        win.insert(String::from("\x1b[1mBold\x1b[22m move"));
+        screen.render(&mut win);
+
+        // Then
+        assert_eq!(
+            screen.lines(),
+            vec!["Bold move", "Hello", "wonderful", "world"]
+        );
+        assert!(screen.style(1, 1).bold);
+        assert!(!screen.style(6, 1).bold);
+    }
+
+    #[test]
+    fn test_views_are_flushed_in_a_single_frame() {
+        // Given
+        let mut screen = Screen::<Vec<u8>>::headless(20, 2);
+        let mut layout = LinearLayout::<(), Vec<u8>>::new(Orientation::Vertical);
+        for line in ["Wherefore", "art thou"].iter() {
+            let mut win = BufferedWin::<(), Vec<u8>, String>::new();
+            win.insert(line.to_string());
+            layout.push(win);
+        }
+        let mut dimension = Dimension::new();
+        layout.measure(&mut dimension, Some(20), Some(2));
+        layout.layout(&mut dimension, 1, 1);
+
+        // When
+        layout.render(&dimension, &mut screen);
+        let rendered = screen.frames().len();
+        screen.flush().unwrap();
+
+        // Then
+        assert_eq!(rendered, 0);
+        assert_eq!(screen.frames().len(), 1);
+        let frame = String::from_utf8_lossy(&screen.frames()[0]).to_string();
+        assert!(frame.contains("Wherefore") && frame.contains("art thou"));
+    }
+
+    #[test]
+    fn test_scrollbar_thumb_follows_the_view() {
+        assert_eq!(scrollbar_thumb(4, 4, 0), None);
+        assert_eq!(scrollbar_thumb(16, 4, 0), Some((3, 4)));
+        assert_eq!(scrollbar_thumb(16, 4, 12), Some((0, 1)));
+        assert_eq!(scrollbar_thumb(8, 4, 2), Some((1, 3)));
+    }
+
+    #[test]
+    fn test_buffered_win_draws_scrollbar() {
+        // Given
+        let mut screen = TestScreen::new(8, 2);
+        let mut win = BufferedWin::<(), Vec<u8>, String>::new().with_scrollbar(true);
+        for line in ["a", "b", "c", "d"].iter() {
+            win.insert(line.to_string());
+        }
+
+        // When
+        screen.render(&mut win);
+
+        // Then
+        assert_eq!(screen.lines(), vec!["c      │", "d      █"]);
+
+        // When
+        win.page_up();
+        screen.render(&mut win);
+
+        // Then
+        assert_eq!(screen.lines(), vec!["a      █", "b      │"]);
+    }
+
+    #[test]
+    fn test_list_view_sorts_items_within_its_area() {
+        // Given
+        let mut screen = TestScreen::new(10, 4);
+        let mut list = ListView::<(), Vec<u8>, String, String>::new()
+            .with_none_group()
+            .with_sort_item();
+
+        // When
+        list.add_group(String::from("Contacts"));
+        for item in ["romeo", "juliet", "benvolio", "tybalt"].iter() {
+            list.insert(item.to_string(), Some(String::from("Contacts")));
+        }
+        screen.render_in(&mut list, 1, 1, 10, 3);
+
+        // Then
+        assert_eq!(
+            screen.lines(),
+            vec!["Contacts", "  benvolio", "  juliet", ""]
+        );
+    }
+
+    #[test]
+    fn test_linear_layout_hides_sidebar_when_narrow() {
+        // Given
+        let mut layout = LinearLayout::<(), Vec<u8>>::new(Orientation::Horizontal);
+        let mut messages = ListView::<(), Vec<u8>, String, String>::new().with_none_group();
+        messages.insert(String::from("Wherefore art thou"), None);
+        layout.push(messages);
+        let mut sidebar = ListView::<(), Vec<u8>, String, String>::new()
+            .with_layouts(Layouts {
+                width: Layout::wrap_content(),
+                height: Layout::match_parent(),
+            })
+            .with_none_group()
+            .with_hidden_below(30);
+        sidebar.insert(String::from("romeo"), None);
+        layout.push(sidebar);
+
+        // When
+        let mut wide = TestScreen::new(30, 1);
+        wide.render(&mut layout);
+        let mut narrow = TestScreen::new(20, 1);
+        narrow.render(&mut layout);
+
+        // Then
+        assert_eq!(wide.lines(), vec!["Wherefore art thou       romeo"]);
+        assert_eq!(narrow.lines(), vec!["Wherefore art thou"]);
+    }
+
+    #[test]
+    fn test_linear_layout_gives_percent_of_its_width() {
+        // Given
+        let mut layout = LinearLayout::<(), Vec<u8>>::new(Orientation::Horizontal);
+        let mut sidebar = ListView::<(), Vec<u8>, String, String>::new()
+            .with_layouts(Layouts {
+                width: Layout::percent(25),
+                height: Layout::match_parent(),
+            })
+            .with_none_group();
+        sidebar.insert(String::from("romeo"), None);
+        layout.push(sidebar);
+        let mut messages = ListView::<(), Vec<u8>, String, String>::new().with_none_group();
+        messages.insert(String::from("Wherefore art thou"), None);
+        layout.push(messages);
+
+        // When
+        let mut screen = TestScreen::new(40, 1);
+        screen.render(&mut layout);
+
+        // Then
+        assert_eq!(screen.lines(), vec!["romeo     Wherefore art thou"]);
+    }
 }
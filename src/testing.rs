@@ -0,0 +1,216 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! End-to-end testing: Aparté talks to a scripted fake server and draws on a headless screen.
+//!
+//! ```ignore
+//! testing::run(async {
+//!     let mut harness = Harness::new();
+//!     harness.reply("query", ns::ROSTER, "<iq xmlns='jabber:client' type='result' id='{id}'/>");
+//!     harness.connect().await;
+//!     harness.receive("<message xmlns='jabber:client' …/>").await;
+//!     assert!(harness.screen().contains("…"));
+//! });
+//! ```
+use std::collections::VecDeque;
+use std::future::Future;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Once;
+use tokio::sync::mpsc;
+use xmpp_parsers::{Element, FullJid, Jid};
+
+use crate::account::Account;
+use crate::config::Config;
+use crate::core::{Aparte, Event};
+use crate::graphics::Protocol as GraphicsProtocol;
+use crate::mods;
+use crate::terminus;
+
+pub const ACCOUNT: &str = "romeo@example.org/aparte";
+
+static ISOLATE: Once = Once::new();
+
+/// Run an async test the way Aparté runs: local tasks on a single thread
+pub fn run<F: Future>(test: F) -> F::Output {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    tokio::task::LocalSet::new().block_on(&rt, test)
+}
+
+/// Scripted answer of the fake server
+struct Reply {
+    name: String,
+    ns: String,
+    /// Entity answering, only stanzas sent to it are answered
+    from: Option<String>,
+    stanza: String,
+}
+
+/// Whether a stanza is, or has a direct child, named `name` in `ns`
+pub fn matches(stanza: &Element, name: &str, ns: &str) -> bool {
+    stanza.is(name, ns) || stanza.children().any(|child| child.is(name, ns))
+}
+
+pub struct Harness {
+    pub aparte: Aparte,
+    pub account: Account,
+    /// Stanzas sent by Aparté, as received by the fake server
+    server: mpsc::Receiver<Element>,
+    server_sink: mpsc::Sender<Element>,
+    /// Events of tasks spawned by Aparté
+    events: mpsc::Receiver<Event>,
+    replies: VecDeque<Reply>,
+    /// Stanzas sent by Aparté without scripted reply, oldest first
+    pub sent: Vec<Element>,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    pub fn with_config(mut config: Config) -> Self {
+        // Keep mods saving their state in the data directory away from the user's one
+        ISOLATE.call_once(|| {
+            let dir = std::env::temp_dir().join(format!("aparte-tests-{}", std::process::id()));
+            std::env::set_var("XDG_DATA_HOME", dir.join("data"));
+            std::env::set_var("XDG_CACHE_HOME", dir.join("cache"));
+        });
+
+        config.graphics.get_or_insert(GraphicsProtocol::Blocks);
+        let config_path = PathBuf::from("/dev/null");
+        let (events_sink, events) = mpsc::channel(1024);
+        let (server_sink, server) = mpsc::channel(1024);
+
+        let mut aparte = Aparte::headless(config, config_path, events_sink);
+        aparte.init().unwrap();
+
+        Self {
+            aparte,
+            account: FullJid::from_str(ACCOUNT).unwrap(),
+            server,
+            server_sink,
+            events,
+            replies: VecDeque::new(),
+            sent: Vec::new(),
+        }
+    }
+
+    /// Script the answer to the next stanza sent by Aparté that is, or has a child, named `name`
+    /// in `ns`, and is sent to the `from` of the answer if any. `{id}` is replaced by the id of
+    /// the stanza answered and `{account}` by the full jid of the account.
+    pub fn reply(&mut self, name: &str, ns: &str, stanza: &str) -> &mut Self {
+        let from = self.parse(stanza, None).attr("from").map(String::from);
+        self.replies.push_back(Reply {
+            name: name.to_string(),
+            ns: ns.to_string(),
+            from,
+            stanza: stanza.to_string(),
+        });
+        self
+    }
+
+    /// Let the fake server accept the connection of the account, again if it was disconnected
+    pub async fn connect(&mut self) {
+        if !self.aparte.accounts().contains(&self.account) {
+            self.aparte
+                .add_connection(self.account.clone(), self.server_sink.clone());
+        }
+        let jid = Jid::Full(self.account.clone());
+        self.aparte
+            .schedule(Event::Connected(self.account.clone(), jid));
+        self.settle().await;
+    }
+
+    /// Let the fake server accept the account as an external component (XEP-0114)
+    pub async fn connect_component(&mut self) {
+        self.aparte
+            .add_component_connection(self.account.clone(), self.server_sink.clone());
+        self.aparte
+            .schedule(Event::ComponentConnected(self.account.clone()));
+        self.settle().await;
+    }
+
+    /// Let the fake server send a stanza to Aparté, `{account}` is replaced by the account jid
+    pub async fn receive(&mut self, stanza: &str) {
+        let stanza = self.parse(stanza, None);
+        self.aparte
+            .schedule(Event::Stanza(self.account.clone(), stanza));
+        self.settle().await;
+    }
+
+    /// Validate a command or a message in the input of the given window
+    pub async fn input(&mut self, window: &str, buf: &str) {
+        self.aparte.schedule(Event::RawCommand(
+            Some(self.account.clone()),
+            window.to_string(),
+            buf.to_string(),
+        ));
+        self.settle().await;
+    }
+
+    /// Handle events until Aparté has nothing left to do, answering its stanzas as scripted.
+    /// Background tasks still waiting (timers, external commands) are left behind.
+    pub async fn settle(&mut self) {
+        loop {
+            let _ = self.aparte.event_loop().await;
+
+            let mut idle = true;
+            while let Ok(stanza) = self.server.try_recv() {
+                let position = self.replies.iter().position(|reply| {
+                    matches(&stanza, &reply.name, &reply.ns)
+                        && (reply.from.is_none() || reply.from.as_deref() == stanza.attr("to"))
+                });
+                match position.and_then(|position| self.replies.remove(position)) {
+                    Some(reply) => {
+                        let reply = self.parse(&reply.stanza, stanza.attr("id"));
+                        self.aparte
+                            .schedule(Event::Stanza(self.account.clone(), reply));
+                        idle = false;
+                    }
+                    None => self.sent.push(stanza),
+                }
+            }
+
+            // Give spawned tasks ready to complete a chance to do so
+            tokio::task::yield_now().await;
+            while let Ok(event) = self.events.try_recv() {
+                self.aparte.schedule(event);
+                idle = false;
+            }
+
+            if idle {
+                break;
+            }
+        }
+    }
+
+    /// Take the sent stanzas that are, or have a child, named `name` in `ns`
+    pub fn take_sent(&mut self, name: &str, ns: &str) -> Vec<Element> {
+        let (taken, kept) = std::mem::take(&mut self.sent)
+            .into_iter()
+            .partition(|stanza| matches(stanza, name, ns));
+        self.sent = kept;
+        taken
+    }
+
+    /// Everything drawn on the screen so far, without escape sequences
+    pub fn screen(&self) -> String {
+        let ui = self.aparte.get_mod::<mods::ui::UIMod>();
+        ui.frames()
+            .iter()
+            .map(|frame| terminus::clean(&String::from_utf8_lossy(frame)))
+            .collect()
+    }
+
+    fn parse(&self, stanza: &str, id: Option<&str>) -> Element {
+        let stanza = stanza
+            .replace("{id}", id.unwrap_or_default())
+            .replace("{account}", &self.account.to_string());
+        Element::from_str(&stanza)
+            .unwrap_or_else(|err| panic!("Invalid scripted stanza {}: {:?}", stanza, err))
+    }
+}
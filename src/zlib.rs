@@ -0,0 +1,219 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+//! Stream compression with zlib (XEP-0138), below the XML stream once negotiated.
+//!
+//! Each write is flushed to a byte boundary (Z_SYNC_FLUSH) so that the server can inflate a
+//! stanza as soon as it is sent, the compression context being kept along the whole stream.
+use miniz_oxide::deflate::core::{create_comp_flags_from_zip_params, CompressorOxide};
+use miniz_oxide::inflate::stream::InflateState;
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Compression level, the default one of zlib
+const LEVEL: i32 = 6;
+/// Size of the zlib window, 32 KiB
+const WINDOW_BITS: i32 = 15;
+const CHUNK: usize = 4096;
+
+struct Zlib {
+    deflate: Box<CompressorOxide>,
+    inflate: Box<InflateState>,
+    /// Compressed data read, not inflated yet
+    input: Vec<u8>,
+    /// Compressed data not written yet
+    output: Vec<u8>,
+}
+
+/// Stream compressed once `compress` is called
+pub struct ZlibStream<S> {
+    inner: S,
+    zlib: Option<Zlib>,
+}
+
+impl<S> ZlibStream<S> {
+    /// Stream left uncompressed until told otherwise
+    pub fn new(inner: S) -> Self {
+        Self { inner, zlib: None }
+    }
+
+    /// Compress what is written from now on, and inflate what is read
+    pub fn compress(&mut self) {
+        let flags = create_comp_flags_from_zip_params(LEVEL, WINDOW_BITS, 0);
+        self.zlib = Some(Zlib {
+            deflate: Box::new(CompressorOxide::new(flags)),
+            inflate: InflateState::new_boxed(DataFormat::Zlib),
+            input: Vec::new(),
+            output: Vec::new(),
+        });
+    }
+}
+
+fn invalid(err: MZError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("zlib error: {:?}", err))
+}
+
+impl<S: AsyncWrite + Unpin> ZlibStream<S> {
+    /// Write the compressed data pending
+    fn poll_output(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let zlib = match &mut self.zlib {
+            Some(zlib) => zlib,
+            None => return Poll::Ready(Ok(())),
+        };
+        while !zlib.output.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, &zlib.output) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(written)) => {
+                    zlib.output.drain(..written);
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ZlibStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let zlib = match &mut this.zlib {
+            Some(zlib) => zlib,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            if !zlib.input.is_empty() {
+                let result = miniz_oxide::inflate::stream::inflate(
+                    &mut zlib.inflate,
+                    &zlib.input,
+                    buf.initialize_unfilled(),
+                    MZFlush::None,
+                );
+                zlib.input.drain(..result.bytes_consumed);
+                buf.advance(result.bytes_written);
+                match result.status {
+                    Ok(_) | Err(MZError::Buf) => {}
+                    Err(err) => return Poll::Ready(Err(invalid(err))),
+                }
+                if result.bytes_written > 0 {
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            let mut chunk = [0u8; CHUNK];
+            let mut read = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut read) {
+                // End of the stream
+                Poll::Ready(Ok(())) if read.filled().is_empty() => return Poll::Ready(Ok(())),
+                Poll::Ready(Ok(())) => zlib.input.extend_from_slice(read.filled()),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ZlibStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.zlib.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+        // Data is only taken once the previous one is on its way
+        if this.poll_output(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+
+        let zlib = this.zlib.as_mut().unwrap();
+        let mut consumed = 0;
+        loop {
+            let mut chunk = [0u8; CHUNK];
+            let result = miniz_oxide::deflate::stream::deflate(
+                &mut zlib.deflate,
+                &data[consumed..],
+                &mut chunk,
+                MZFlush::Sync,
+            );
+            consumed += result.bytes_consumed;
+            zlib.output
+                .extend_from_slice(&chunk[..result.bytes_written]);
+            match result.status {
+                Ok(MZStatus::Ok) | Err(MZError::Buf) => {}
+                Ok(_) => break,
+                Err(err) => return Poll::Ready(Err(invalid(err))),
+            }
+            // Flushed once all the data is taken and the output had room left
+            if consumed == data.len() && result.bytes_written < CHUNK {
+                break;
+            }
+        }
+        // Written further by the next write or flush if the inner stream isn't ready
+        if let Poll::Ready(Err(err)) = this.poll_output(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.poll_output(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.poll_output(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_compressed_writes_are_read_back_one_by_one() {
+        testing::run(async {
+            // Given
+            let (client, server) = tokio::io::duplex(65536);
+            let mut client = ZlibStream::new(client);
+            let mut server = ZlibStream::new(server);
+            client.write_all(b"<compress/>").await.unwrap();
+            let mut plain = [0u8; 11];
+            server.read_exact(&mut plain).await.unwrap();
+            client.compress();
+            server.compress();
+
+            // When
+            let stanza = format!("<message>{}</message>", "hello ".repeat(1000));
+            client.write_all(stanza.as_bytes()).await.unwrap();
+            client.flush().await.unwrap();
+
+            // Then
+            let mut read = vec![0u8; stanza.len()];
+            server.read_exact(&mut read).await.unwrap();
+            assert_eq!(read, stanza.as_bytes());
+            server.write_all(b"<r/>").await.unwrap();
+            server.flush().await.unwrap();
+            let mut answer = [0u8; 4];
+            client.read_exact(&mut answer).await.unwrap();
+            assert_eq!(&answer, b"<r/>");
+        });
+    }
+}